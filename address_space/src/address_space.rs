@@ -10,6 +10,8 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 
 use util::byte_code::ByteCode;
@@ -17,8 +19,8 @@ use util::byte_code::ByteCode;
 use crate::errors::{ErrorKind, Result, ResultExt};
 use crate::region::FlatView;
 use crate::{
-    AddressRange, FlatRange, GuestAddress, Listener, ListenerReqType, Region, RegionIoEventFd,
-    RegionType,
+    page_size, AddressRange, FlatRange, GuestAddress, HostMemMapping, HostMemRef, Listener,
+    ListenerReqType, Region, RegionIoEventFd, RegionType,
 };
 
 /// Address Space of memory.
@@ -33,6 +35,11 @@ pub struct AddressSpace {
     listeners: Arc<Mutex<Vec<Box<dyn Listener>>>>,
     /// The vector buffer would help in comparison stage of topology update.
     ioeventfds: Arc<Mutex<Vec<RegionIoEventFd>>>,
+    /// Whether pages of Ram written are logged.
+    log_dirty: Arc<AtomicBool>,
+    /// Pages of Ram written by VMM since dirty log is got, which are not
+    /// logged by the listeners.
+    dirty_pages: Arc<Mutex<BTreeSet<u64>>>,
 }
 
 impl AddressSpace {
@@ -47,6 +54,8 @@ impl AddressSpace {
             flat_view: Arc::new(RwLock::new(FlatView::default())),
             listeners: Arc::new(Mutex::new(Vec::new())),
             ioeventfds: Arc::new(Mutex::new(Vec::new())),
+            log_dirty: Arc::new(AtomicBool::new(false)),
+            dirty_pages: Arc::new(Mutex::new(BTreeSet::new())),
         });
 
         root.set_belonged_address_space(&space);
@@ -147,7 +156,9 @@ impl AddressSpace {
 
             if let Some(old_r) = old_range {
                 if let Some(new_r) = new_range {
-                    if old_r.addr_range == new_r.addr_range {
+                    // the owner must also be the same region, otherwise a region replaced
+                    // by another one with the same geometry would be left registered
+                    if old_r == new_r && old_r.owner.is_same(&new_r.owner) {
                         old_idx += 1;
                         new_idx += 1;
                         continue;
@@ -228,7 +239,9 @@ impl AddressSpace {
         Ok(())
     }
 
-    /// Return the start host address of Region where the `GuestAddress` belongs to.
+    /// Return the host address which the `GuestAddress` is mapped to.
+    /// The address is only valid while the Ram region stays in the topology,
+    /// use `get_host_mem` if the memory needs to be kept mapped.
     ///
     /// # Arguments
     ///
@@ -253,6 +266,32 @@ impl AddressSpace {
         }
     }
 
+    /// Return the host memory from the `GuestAddress` to the end of the Ram range
+    /// it belongs to. The returned `HostMemRef` keeps the memory mapped, see its
+    /// safety contract.
+    /// Return `None` if the `GuestAddress` is not backed by Ram or RamDevice region.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address.
+    pub fn get_host_mem(&self, addr: GuestAddress) -> Option<HostMemRef> {
+        let view = &self.flat_view.read().unwrap().0;
+
+        let (fr, offset) = match view.binary_search_by_key(&addr, |x| x.addr_range.base) {
+            Ok(x) => (&view[x], 0),
+            Err(x) if (x > 0 && addr < view[x - 1].addr_range.end_addr()) => {
+                let fr = &view[x - 1];
+                (fr, addr.offset_from(fr.addr_range.base))
+            }
+            _ => return None,
+        };
+        HostMemRef::new(
+            fr.owner.mem_mapping()?,
+            fr.offset_in_region + offset,
+            fr.addr_range.size - offset,
+        )
+    }
+
     /// Check if the GuestAddress is in one of Ram region.
     ///
     /// # Arguments
@@ -282,29 +321,204 @@ impl AddressSpace {
             .map_or(GuestAddress(0), |fr| fr.addr_range.end_addr())
     }
 
-    /// Read memory segment to `dst`.
+    /// Return all Ram ranges in AddressSpace, sorted by base address.
+    /// Adjacent Ram ranges are merged, so every returned range is contiguous
+    /// guest memory and separated from the others by a hole or an IO region.
+    pub fn memory_ranges(&self) -> Vec<AddressRange> {
+        let view = &self.flat_view.read().unwrap().0;
+        let mut ranges: Vec<AddressRange> = Vec::new();
+        for fr in view
+            .iter()
+            .filter(|fr| fr.owner.region_type() == RegionType::Ram)
+        {
+            match ranges.last_mut() {
+                Some(last) if last.end_addr() == fr.addr_range.base => {
+                    last.size += fr.addr_range.size;
+                }
+                _ => ranges.push(fr.addr_range),
+            }
+        }
+        ranges
+    }
+
+    /// Split a guest memory range into segments of the host memory mappings,
+    /// every segment is returned as the mapping, offset within it and size.
     ///
     /// # Arguments
     ///
-    /// * `dst` - Destination the data would be written to.
     /// * `addr` - Start address.
-    /// * `count` - Size of data.
+    /// * `size` - Size of the range.
     ///
     /// # Errors
     ///
-    /// Return Error if the `addr` is a invalid GuestAddress.
-    pub fn read(&self, dst: &mut dyn std::io::Write, addr: GuestAddress, count: u64) -> Result<()> {
+    /// Return Error if any part of the range is not backed by Ram region.
+    fn ram_segments(
+        &self,
+        addr: GuestAddress,
+        size: u64,
+    ) -> Result<Vec<(Arc<HostMemMapping>, u64, u64)>> {
+        let end = addr
+            .raw_value()
+            .checked_add(size)
+            .ok_or_else(|| ErrorKind::Overflow(addr.raw_value()))?;
+        let not_ram = || ErrorKind::NotRam(addr.raw_value(), end);
         let view = &self.flat_view.read().unwrap().0;
 
+        let mut segments = Vec::new();
+        let mut cur = addr;
+        while cur.raw_value() < end {
+            let fr = match view.binary_search_by_key(&cur, |x| x.addr_range.base) {
+                Ok(x) => &view[x],
+                Err(x) if (x > 0 && cur < view[x - 1].addr_range.end_addr()) => &view[x - 1],
+                _ => return Err(not_ram().into()),
+            };
+            // Memory of devices is never returned to host.
+            if fr.owner.region_type() != RegionType::Ram {
+                return Err(not_ram().into());
+            }
+            let mapping = fr.owner.mem_mapping().ok_or_else(not_ram)?;
+            let offset = cur.offset_from(fr.addr_range.base);
+            let len = std::cmp::min(fr.addr_range.size - offset, end - cur.raw_value());
+            segments.push((mapping, fr.offset_in_region + offset, len));
+            cur = cur.unchecked_add(len);
+        }
+        Ok(segments)
+    }
+
+    /// Return the memory in a guest range to host, used by balloon device.
+    /// The range may cross Ram regions, but must be fully backed by them.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start address.
+    /// * `size` - Size of the range.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the range is not fully backed by Ram regions, or not
+    /// aligned with the page size of the backing memory. Nothing is discarded then.
+    pub fn discard_range(&self, addr: GuestAddress, size: u64) -> Result<()> {
+        let segments = self.ram_segments(addr, size)?;
+        for (mapping, offset, len) in segments.iter() {
+            mapping.check_discard_range(*offset, *len)?;
+        }
+        for (mapping, offset, len) in segments.iter() {
+            mapping
+                .discard(*offset, *len)
+                .chain_err(|| format!("Failed to discard memory at 0x{:x}", addr.raw_value()))?;
+        }
+        Ok(())
+    }
+
+    /// Mark memory in a guest range discarded before in use again, used by balloon device.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start address.
+    /// * `size` - Size of the range.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the range is not fully backed by Ram regions, or not
+    /// aligned with the page size of the backing memory.
+    pub fn undiscard_range(&self, addr: GuestAddress, size: u64) -> Result<()> {
+        let segments = self.ram_segments(addr, size)?;
+        for (mapping, offset, len) in segments.iter() {
+            mapping.check_discard_range(*offset, *len)?;
+        }
+        for (mapping, offset, len) in segments.iter() {
+            mapping.undiscard(*offset, *len)?;
+        }
+        Ok(())
+    }
+
+    /// Find the flat range which serves an access of `count` bytes at `addr`,
+    /// return the flat range and the offset of `addr` in it.
+    /// The owner region may be larger than the flat range, the rest of it is either
+    /// shadowed by other regions or already removed from the topology.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - Flat ranges of current topology, sorted by base address.
+    /// * `op` - Name of the access, used in error message.
+    /// * `addr` - Start address.
+    /// * `count` - Size of data.
+    ///
+    /// # Errors
+    ///
+    /// Return `AddrUnmapped` with the boundaries of the hole if `addr` does not
+    /// belong to any flat range, or `AccessOutOfRange` with the boundaries of the
+    /// flat range if the access crosses its end.
+    fn find_flat_range<'a>(
+        &self,
+        view: &'a [FlatRange],
+        op: &'static str,
+        addr: GuestAddress,
+        count: u64,
+    ) -> Result<(&'a FlatRange, u64)> {
         let (fr, offset) = match view.binary_search_by_key(&addr, |x| x.addr_range.base) {
             Ok(x) => (&view[x], 0),
             Err(x) if (x > 0 && addr < view[x - 1].addr_range.end_addr()) => {
                 let fr = &view[x - 1];
                 (fr, addr.offset_from(fr.addr_range.base))
             }
-            _ => return Err(ErrorKind::AddrInvalid(addr.raw_value()).into()),
+            Err(x) => {
+                let hole_start = if x > 0 {
+                    view[x - 1].addr_range.end_addr().raw_value()
+                } else {
+                    0
+                };
+                let hole_end = view
+                    .get(x)
+                    .map(|fr| fr.addr_range.base.raw_value())
+                    .unwrap_or_else(|| self.root.size());
+                return Err(ErrorKind::AddrUnmapped(
+                    op,
+                    addr.raw_value(),
+                    count,
+                    hole_start,
+                    hole_end,
+                )
+                .into());
+            }
         };
 
+        if offset
+            .checked_add(count)
+            .filter(|end| *end <= fr.addr_range.size)
+            .is_none()
+        {
+            return Err(ErrorKind::AccessOutOfRange(
+                op,
+                addr.raw_value(),
+                count,
+                fr.addr_range.base.raw_value(),
+                fr.addr_range.end_addr().raw_value(),
+            )
+            .into());
+        }
+        Ok((fr, offset))
+    }
+
+    /// Read memory segment to `dst`.
+    /// The flat view is locked during the access, so a region being deleted concurrently
+    /// is either accessed completely or not found at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - Destination the data would be written to.
+    /// * `addr` - Start address.
+    /// * `count` - Size of data.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the `addr` is a invalid GuestAddress, or the access
+    /// exceeds the region which `addr` belongs to.
+    pub fn read(&self, dst: &mut dyn std::io::Write, addr: GuestAddress, count: u64) -> Result<()> {
+        let view = &self.flat_view.read().unwrap().0;
+
+        let (fr, offset) = self.find_flat_range(view, "read", addr, count)?;
+
         fr.owner.read(
             dst,
             fr.addr_range.base.unchecked_sub(fr.offset_in_region),
@@ -323,18 +537,15 @@ impl AddressSpace {
     ///
     /// # Errors
     ///
-    /// Return Error if the `addr` is a invalid GuestAddress.
+    /// Return Error if the `addr` is a invalid GuestAddress, or the access
+    /// exceeds the region which `addr` belongs to.
     pub fn write(&self, src: &mut dyn std::io::Read, addr: GuestAddress, count: u64) -> Result<()> {
         let view = &self.flat_view.read().unwrap().0;
 
-        let (fr, offset) = match view.binary_search_by_key(&addr, |x| x.addr_range.base) {
-            Ok(x) => (&view[x], 0),
-            Err(x) if (x > 0 && addr < view[x - 1].addr_range.end_addr()) => {
-                let fr = &view[x - 1];
-                (fr, addr.offset_from(fr.addr_range.base))
-            }
-            _ => return Err(ErrorKind::AddrInvalid(addr.raw_value()).into()),
-        };
+        let (fr, offset) = self.find_flat_range(view, "write", addr, count)?;
+        if fr.owner.region_type() == RegionType::Ram {
+            self.mark_dirty(addr, count);
+        }
 
         fr.owner.write(
             src,
@@ -375,6 +586,87 @@ impl AddressSpace {
         Ok(obj)
     }
 
+    /// Start logging the pages of Ram written by guest and VMM, which are
+    /// got by `get_dirty_log`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if any listener fails to start logging, then logging is
+    /// stopped.
+    pub fn start_dirty_log(&self) -> Result<()> {
+        self.dirty_pages.lock().unwrap().clear();
+        self.log_dirty.store(true, Ordering::SeqCst);
+        let ret = self.listeners.lock().unwrap().iter().try_for_each(|ml| {
+            ml.set_dirty_log(true)
+                .chain_err(|| "Failed to start dirty log of listener")
+        });
+        if ret.is_err() {
+            if let Err(e) = self.stop_dirty_log() {
+                error!("Failed to stop dirty log: {}", e);
+            }
+        }
+        ret
+    }
+
+    /// Stop logging the pages of Ram written.
+    pub fn stop_dirty_log(&self) -> Result<()> {
+        self.log_dirty.store(false, Ordering::SeqCst);
+        self.dirty_pages.lock().unwrap().clear();
+        self.listeners.lock().unwrap().iter().try_for_each(|ml| {
+            ml.set_dirty_log(false)
+                .chain_err(|| "Failed to stop dirty log of listener")
+        })
+    }
+
+    /// Get and clear the pages of Ram written since dirty log is started or
+    /// got last time, as their page-aligned addresses.
+    pub fn get_dirty_log(&self) -> Result<BTreeSet<u64>> {
+        let page_size = page_size();
+        // Requests of devices may write to memory of devices, such as
+        // virtio-pmem, which is not guest memory.
+        let mut pages: BTreeSet<u64> = std::mem::take(&mut *self.dirty_pages.lock().unwrap())
+            .into_iter()
+            .filter(|page| self.address_in_memory(GuestAddress(*page), 0))
+            .collect();
+        for ml in self.listeners.lock().unwrap().iter() {
+            let bitmaps = ml
+                .get_dirty_log()
+                .chain_err(|| "Failed to get dirty log of listener")?;
+            for (base, bitmap) in bitmaps {
+                for (index, bits) in bitmap.iter().enumerate() {
+                    let mut bits = *bits;
+                    while bits != 0 {
+                        let page = index as u64 * 64 + u64::from(bits.trailing_zeros());
+                        pages.insert(base.raw_value() + page * page_size);
+                        bits &= bits - 1;
+                    }
+                }
+            }
+        }
+        Ok(pages)
+    }
+
+    /// Log the pages of Ram written by VMM directly through host addresses,
+    /// which `write` logs by itself. Nothing is done if dirty log is not
+    /// started.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start address of memory written.
+    /// * `count` - Size of memory written.
+    pub fn mark_dirty(&self, addr: GuestAddress, count: u64) {
+        if !self.log_dirty.load(Ordering::SeqCst) || count == 0 {
+            return;
+        }
+        let page_size = page_size();
+        let first = addr.raw_value() / page_size;
+        let last = (addr.raw_value() + count - 1) / page_size;
+        let mut pages = self.dirty_pages.lock().unwrap();
+        for page in first..=last {
+            pages.insert(page * page_size);
+        }
+    }
+
     /// Update the topology of memory.
     pub fn update_topology(&self) -> Result<()> {
         let old_fv = self.flat_view.read().unwrap();
@@ -397,7 +689,7 @@ mod test {
     use vmm_sys_util::eventfd::EventFd;
 
     use super::*;
-    use crate::{HostMemMapping, RegionOps};
+    use crate::{MemAdvice, RegionOps};
 
     #[derive(Default, Clone)]
     struct TestListener {
@@ -554,6 +846,159 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_update_topology_replaced_region() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root).unwrap();
+        let listener = TestListener::default();
+        space.register_listener(Box::new(listener.clone())).unwrap();
+
+        // two regions of the same geometry, backed by different memory
+        let flat_view = || {
+            let ram = Arc::new(
+                HostMemMapping::new(GuestAddress(1000), 1000, MemAdvice::default()).unwrap(),
+            );
+            FlatView(vec![FlatRange {
+                addr_range: AddressRange::from((1000, 1000)),
+                owner: Region::init_ram_region(ram),
+                offset_in_region: 0,
+            }])
+        };
+        let old_fv = flat_view();
+        let new_fv = flat_view();
+        assert!(old_fv.0[0] == new_fv.0[0]);
+
+        // the same region is left untouched
+        space
+            .update_topology_pass(&old_fv, &old_fv.clone(), false)
+            .unwrap();
+        space
+            .update_topology_pass(&old_fv, &old_fv.clone(), true)
+            .unwrap();
+        assert!(listener.reqs.lock().unwrap().is_empty());
+
+        // the replaced region is deleted, and the new one is added
+        space.update_topology_pass(&old_fv, &new_fv, false).unwrap();
+        space.update_topology_pass(&old_fv, &new_fv, true).unwrap();
+        assert_eq!(
+            *listener.reqs.lock().unwrap(),
+            vec![
+                (
+                    ListenerReqType::DeleteRegion,
+                    AddressRange::from((1000, 1000))
+                ),
+                (ListenerReqType::AddRegion, AddressRange::from((1000, 1000))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delete_subregion_repeatedly() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let listener = TestListener::default();
+        space.register_listener(Box::new(listener.clone())).unwrap();
+
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(1000), 1000, MemAdvice::default()).unwrap());
+        for _ in 0..10 {
+            let region = Region::init_ram_region(ram.clone());
+            root.add_subregion(region.clone(), 1000).unwrap();
+            assert!(space.read_object::<u64>(GuestAddress(1500)).is_ok());
+
+            root.delete_subregion(&region).unwrap();
+            assert!(space.flat_view.read().unwrap().0.is_empty());
+            assert!(space.read_object::<u64>(GuestAddress(1500)).is_err());
+            assert!(space.write_object(&0_u64, GuestAddress(1500)).is_err());
+        }
+
+        // every added flat-range must have been deleted afterwards
+        let reqs = listener.reqs.lock().unwrap();
+        let add_cnt = reqs
+            .iter()
+            .filter(|(t, _)| *t == ListenerReqType::AddRegion)
+            .count();
+        let del_cnt = reqs
+            .iter()
+            .filter(|(t, _)| *t == ListenerReqType::DeleteRegion)
+            .count();
+        assert_eq!(add_cnt, 10);
+        assert_eq!(del_cnt, 10);
+    }
+
+    #[test]
+    fn test_access_shadowed_region() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // region layout
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  a:    [AAAAAAAAAAAAA]
+        //  b:           [BBBBBB]
+        // access to region a must not run into the part shadowed by region b
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 2000, MemAdvice::default()).unwrap());
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+        let region_b = Region::init_io_region(1000, default_ops);
+        region_b.set_priority(1);
+        root.add_subregion(region_b, 1000).unwrap();
+
+        assert!(space.write_object(&0_u64, GuestAddress(992)).is_ok());
+        assert!(space.write_object(&0_u64, GuestAddress(996)).is_err());
+        assert!(space.read_object::<u64>(GuestAddress(996)).is_err());
+    }
+
+    #[test]
+    fn test_access_error_detail() {
+        let root = Region::init_container_region(0x8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 0x1000, MemAdvice::default()).unwrap());
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(0x4000), 0x1000, MemAdvice::default()).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram1), 0)
+            .unwrap();
+        root.add_subregion(Region::init_ram_region(ram2), 0x4000)
+            .unwrap();
+
+        let mut buf = [0_u8; 0x10];
+        let err = space
+            .write(&mut buf.as_ref(), GuestAddress(0xff8), 0x10)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "write of 0x10 bytes at GPA 0xff8 exceeds region [0x0, 0x1000)"
+        );
+
+        let err = space.read_object::<u64>(GuestAddress(0x2000)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "read of 0x8 bytes at GPA 0x2000 falls in unmapped hole [0x1000, 0x4000)"
+        );
+
+        let err = space.read_object::<u64>(GuestAddress(0x6000)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "read of 0x8 bytes at GPA 0x6000 falls in unmapped hole [0x5000, 0x8000)"
+        );
+
+        // the detail is kept when callers chain their own context.
+        let err = space
+            .write_object(&0_u64, GuestAddress(0x4ffc))
+            .chain_err(|| "Failed to write object")
+            .unwrap_err();
+        assert_eq!(
+            err.iter().last().unwrap().to_string(),
+            "write of 0x8 bytes at GPA 0x4ffc exceeds region [0x4000, 0x5000)"
+        );
+    }
+
     #[test]
     fn test_update_ioeventfd() {
         let ioeventfds = vec![RegionIoEventFd {
@@ -607,6 +1052,221 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_discard_range() {
+        let page = crate::page_size();
+        let root = Region::init_container_region(16 * page);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // region layout, in pages
+        //        0      4      8      12     16
+        //        |------|------|------|------|
+        //  ram1: [RRRRRR]
+        //  ram2:        [RRRRRRRRRRRRR]
+        //  io:                 [II]
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 4 * page, MemAdvice::default()).unwrap());
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(4 * page), 8 * page, MemAdvice::default()).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram1.clone()), 0)
+            .unwrap();
+        root.add_subregion(Region::init_ram_region(ram2.clone()), 4 * page)
+            .unwrap();
+        let region_io = Region::init_io_region(page, default_ops);
+        region_io.set_priority(1);
+        root.add_subregion(region_io, 8 * page).unwrap();
+
+        // range across the boundary of two ram regions
+        space
+            .discard_range(GuestAddress(3 * page), 2 * page)
+            .unwrap();
+        assert_eq!(ram1.discarded_bytes(), page);
+        assert_eq!(ram2.discarded_bytes(), page);
+
+        // range in the part of ram2 behind the io region
+        space
+            .discard_range(GuestAddress(9 * page), 3 * page)
+            .unwrap();
+        assert_eq!(ram2.discarded_bytes(), 4 * page);
+
+        // ranges run into io region, hole or partial pages are refused as a whole
+        assert!(space.discard_range(GuestAddress(0), 9 * page).is_err());
+        assert!(space
+            .discard_range(GuestAddress(11 * page), 2 * page)
+            .is_err());
+        assert!(space.discard_range(GuestAddress(page + 1), page).is_err());
+        assert_eq!(ram1.discarded_bytes(), page);
+        assert_eq!(ram2.discarded_bytes(), 4 * page);
+
+        space
+            .undiscard_range(GuestAddress(3 * page), 7 * page)
+            .unwrap_err();
+        space
+            .undiscard_range(GuestAddress(3 * page), 5 * page)
+            .unwrap();
+        assert_eq!(ram1.discarded_bytes(), 0);
+        assert_eq!(ram2.discarded_bytes(), 3 * page);
+    }
+
+    #[test]
+    fn test_get_host_mem() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+
+        // region layout
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  ram:  [RRRRRRRRRRRRRRRRRRRR]
+        //  io:          [IIIIII]              [IIIIII]
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 3000, MemAdvice::default()).unwrap());
+        let region_ram = Region::init_ram_region(ram.clone());
+        root.add_subregion(region_ram.clone(), 0).unwrap();
+        let region_io = Region::init_io_region(1000, default_ops.clone());
+        region_io.set_priority(1);
+        root.add_subregion(region_io, 1000).unwrap();
+        root.add_subregion(Region::init_io_region(1000, default_ops), 4000)
+            .unwrap();
+
+        let mem = space.get_host_mem(GuestAddress(500)).unwrap();
+        assert_eq!(mem.host_address(), ram.host_address() + 500);
+        assert_eq!(mem.len(), 500);
+
+        // the very end of ram regions
+        let mem = space.get_host_mem(GuestAddress(999)).unwrap();
+        assert_eq!(mem.len(), 1);
+        let mem = space.get_host_mem(GuestAddress(2999)).unwrap();
+        assert_eq!(mem.host_address(), ram.host_address() + 2999);
+        assert_eq!(mem.len(), 1);
+        assert!(space.get_host_mem(GuestAddress(3000)).is_none());
+
+        // mmio space and holes
+        assert!(space.get_host_mem(GuestAddress(1000)).is_none());
+        assert!(space.get_host_mem(GuestAddress(4500)).is_none());
+        assert!(space.get_host_mem(GuestAddress(7000)).is_none());
+
+        // memory stays mapped after the region is removed
+        let mem = space.get_host_mem(GuestAddress(2000)).unwrap();
+        root.delete_subregion(&region_ram).unwrap();
+        drop(region_ram);
+        drop(ram);
+        assert!(space.get_host_mem(GuestAddress(2000)).is_none());
+        unsafe { *mem.as_ptr() = 0xff };
+        assert_eq!(unsafe { *mem.as_ptr() }, 0xff);
+    }
+
+    #[test]
+    fn test_memory_ranges() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let default_ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        assert!(space.memory_ranges().is_empty());
+
+        // single range
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 2000, MemAdvice::default()).unwrap());
+        root.add_subregion(Region::init_ram_region(ram1), 0)
+            .unwrap();
+        assert_eq!(
+            space.memory_ranges(),
+            vec![AddressRange::new(GuestAddress(0), 2000)]
+        );
+
+        // adjacent ram regions are merged into one range
+        let ram2 =
+            Arc::new(HostMemMapping::new(GuestAddress(2000), 1000, MemAdvice::default()).unwrap());
+        root.add_subregion(Region::init_ram_region(ram2), 2000)
+            .unwrap();
+        assert_eq!(
+            space.memory_ranges(),
+            vec![AddressRange::new(GuestAddress(0), 3000)]
+        );
+
+        // region layout
+        //        0      1000   2000   3000   4000   5000   6000   7000   8000
+        //        |------|------|------|------|------|------|------|------|
+        //  ram:  [RRRRRRRRRRRRRRRRRRRR]      [RRRRRR]
+        //  io:          [IIIIII]
+        // ram hotplugged later and ram split by io region give multiple ranges
+        let ram3 =
+            Arc::new(HostMemMapping::new(GuestAddress(4000), 1000, MemAdvice::default()).unwrap());
+        root.add_subregion(Region::init_ram_region(ram3), 4000)
+            .unwrap();
+        let region_io = Region::init_io_region(1000, default_ops);
+        region_io.set_priority(1);
+        root.add_subregion(region_io, 1000).unwrap();
+        assert_eq!(
+            space.memory_ranges(),
+            vec![
+                AddressRange::new(GuestAddress(0), 1000),
+                AddressRange::new(GuestAddress(2000), 1000),
+                AddressRange::new(GuestAddress(4000), 1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ram_device_region() {
+        let page = page_size();
+        let root = Region::init_container_region(8 * page);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 4 * page, MemAdvice::default()).unwrap());
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+        let device = Arc::new(
+            HostMemMapping::new(GuestAddress(6 * page), 2 * page, MemAdvice::default()).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_device_region(device.clone()), 6 * page)
+            .unwrap();
+
+        // Memory of device is accessed like Ram.
+        space
+            .write_object(&0x1234_u64, GuestAddress(6 * page + 8))
+            .unwrap();
+        assert_eq!(
+            space
+                .read_object::<u64>(GuestAddress(6 * page + 8))
+                .unwrap(),
+            0x1234
+        );
+        assert_eq!(
+            space.get_host_address(GuestAddress(7 * page)),
+            Some(device.host_address() + page)
+        );
+        assert_eq!(
+            space.get_host_mem(GuestAddress(7 * page)).unwrap().len(),
+            page
+        );
+
+        // But it's not guest memory.
+        assert_eq!(
+            space.memory_ranges(),
+            vec![AddressRange::new(GuestAddress(0), 4 * page)]
+        );
+        assert_eq!(space.memory_end_address(), GuestAddress(4 * page));
+        assert!(!space.address_in_memory(GuestAddress(6 * page), 8));
+        assert!(space.discard_range(GuestAddress(6 * page), page).is_err());
+        assert_eq!(device.discarded_bytes(), 0);
+        space.start_dirty_log().unwrap();
+        space.write_object(&0_u64, GuestAddress(6 * page)).unwrap();
+        space.mark_dirty(GuestAddress(3 * page), 4 * page);
+        let pages: Vec<u64> = space.get_dirty_log().unwrap().into_iter().collect();
+        assert_eq!(pages, vec![3 * page]);
+        space.stop_dirty_log().unwrap();
+    }
+
     #[test]
     fn test_get_ram_info() {
         let root = Region::init_container_region(8000);
@@ -617,8 +1277,10 @@ mod test {
             write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
         };
 
-        let ram1 = Arc::new(HostMemMapping::new(GuestAddress(0), 1000, false).unwrap());
-        let ram2 = Arc::new(HostMemMapping::new(GuestAddress(2000), 1000, false).unwrap());
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1000, MemAdvice::default()).unwrap());
+        let ram2 =
+            Arc::new(HostMemMapping::new(GuestAddress(2000), 1000, MemAdvice::default()).unwrap());
         let region_a = Region::init_ram_region(ram1.clone());
         let region_b = Region::init_ram_region(ram2.clone());
         root.add_subregion(region_a, ram1.start_address().raw_value())
@@ -681,7 +1343,8 @@ mod test {
     fn test_write_and_read_object() {
         let root = Region::init_container_region(8000);
         let space = AddressSpace::new(root.clone()).unwrap();
-        let ram1 = Arc::new(HostMemMapping::new(GuestAddress(0), 1000, false).unwrap());
+        let ram1 =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1000, MemAdvice::default()).unwrap());
         let region_a = Region::init_ram_region(ram1.clone());
         root.add_subregion(region_a, ram1.start_address().raw_value())
             .unwrap();
@@ -692,4 +1355,65 @@ mod test {
         assert_eq!(data1, 10000);
         assert!(space.write_object(&data, GuestAddress(993)).is_err());
     }
+
+    #[test]
+    fn test_dirty_log() {
+        // Listener logging pages 0 and 2 written by guest once.
+        #[derive(Default)]
+        struct DirtyListener {
+            enabled: Arc<AtomicBool>,
+        }
+        impl Listener for DirtyListener {
+            fn priority(&self) -> i32 {
+                0
+            }
+
+            fn set_dirty_log(&self, enable: bool) -> std::result::Result<(), crate::errors::Error> {
+                self.enabled.store(enable, Ordering::SeqCst);
+                Ok(())
+            }
+
+            fn get_dirty_log(
+                &self,
+            ) -> std::result::Result<Vec<(GuestAddress, Vec<u64>)>, crate::errors::Error>
+            {
+                if self.enabled.swap(false, Ordering::SeqCst) {
+                    Ok(vec![(GuestAddress(0), vec![0b101])])
+                } else {
+                    Ok(Vec::new())
+                }
+            }
+        }
+
+        let page = page_size();
+        let root = Region::init_container_region(page * 8);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram =
+            Arc::new(HostMemMapping::new(GuestAddress(0), page * 4, MemAdvice::default()).unwrap());
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+        let listener = DirtyListener::default();
+        let enabled = listener.enabled.clone();
+        space.register_listener(Box::new(listener)).unwrap();
+
+        // Writes of VMM are not logged until it's started.
+        space.write_object(&0_u64, GuestAddress(page * 3)).unwrap();
+        space.start_dirty_log().unwrap();
+        assert!(enabled.load(Ordering::SeqCst));
+        // The object crosses pages 1 and 2.
+        space
+            .write_object(&1_u64, GuestAddress(page * 2 - 4))
+            .unwrap();
+        let pages: Vec<u64> = space.get_dirty_log().unwrap().into_iter().collect();
+        assert_eq!(pages, vec![0, page, page * 2]);
+        assert!(space.get_dirty_log().unwrap().is_empty());
+
+        space.mark_dirty(GuestAddress(page * 2 + 1), page);
+        let pages: Vec<u64> = space.get_dirty_log().unwrap().into_iter().collect();
+        assert_eq!(pages, vec![page * 2, page * 3]);
+
+        space.stop_dirty_log().unwrap();
+        space.write_object(&1_u64, GuestAddress(0)).unwrap();
+        space.mark_dirty(GuestAddress(0), 1);
+        assert!(space.get_dirty_log().unwrap().is_empty());
+    }
 }
@@ -10,40 +10,248 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::sync::Arc;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
 
 use crate::errors::{ErrorKind, Result};
 use crate::{AddressRange, GuestAddress};
 
+/// Default upper limit of the size of one host memory mapping, and so the
+/// size of one kvm memory slot.
+pub const DEFAULT_MAX_MAPPING_SIZE: u64 = 256 << 30;
+
+/// Split guest ram ranges into pieces no larger than `max_size`.
+/// Pieces of one range stay contiguous in guest physical address.
+///
+/// # Arguments
+///
+/// * `ranges` - The guest address ranges, as `(base, size)`.
+/// * `max_size` - Max size of one piece, must be page aligned.
+pub fn split_ram_ranges(ranges: &[(u64, u64)], max_size: u64) -> Vec<(u64, u64)> {
+    let mut pieces = Vec::new();
+    for &(base, size) in ranges.iter() {
+        let mut offset = 0;
+        while offset < size {
+            let len = std::cmp::min(max_size, size - offset);
+            pieces.push((base + offset, len));
+            offset += len;
+        }
+    }
+    pieces
+}
+
 /// Create a new HostMemMapping.
+/// Ranges larger than `max_mapping_size` are backed by several mappings.
 ///
 /// # Arguments
 ///
 /// * `ranges` - The guest address range that will be mapped.
-/// * `omit_vm_memory` - Dump guest memory in core file or not.
+/// * `backend` - File backing the ranges, which are laid out one after
+///   another from its offset. Anonymous memory is mapped if it's `None`.
+/// * `mem_advice` - Memory advice applied to every mapping.
+/// * `max_mapping_size` - Max size of one mapping.
+///
+/// # Errors
+///
+/// Return Error if `max_mapping_size` is not a non-zero multiple of page size,
+/// the file is shorter than the ranges, or fail to map memory.
 pub fn create_host_mmaps(
     ranges: &[(u64, u64)],
-    omit_vm_memory: bool,
+    backend: Option<&FileBackend>,
+    mem_advice: MemAdvice,
+    max_mapping_size: u64,
 ) -> Result<Vec<Arc<HostMemMapping>>> {
-    let mut mappings = Vec::new();
+    if max_mapping_size == 0 || max_mapping_size % crate::page_size() != 0 {
+        bail!(
+            "Max mapping size {:#x} should be a non-zero multiple of page size",
+            max_mapping_size
+        );
+    }
 
-    for range in ranges.iter() {
-        mappings.push(Arc::new(HostMemMapping::new(
+    let mut mappings = Vec::new();
+    let mut file_offset = backend.map_or(0, |backend| backend.offset);
+    for range in split_ram_ranges(ranges, max_mapping_size).iter() {
+        let backend = backend.map(|backend| FileBackend {
+            offset: file_offset,
+            ..backend.clone()
+        });
+        mappings.push(Arc::new(HostMemMapping::with_backend(
             GuestAddress(range.0),
             range.1,
-            omit_vm_memory,
+            backend,
+            mem_advice,
         )?));
+        file_offset += range.1;
     }
 
     Ok(mappings)
 }
 
+/// File backing guest ram instead of anonymous memory, such as a file on
+/// tmpfs or hugetlbfs given by `-mem-path`.
+#[derive(Clone)]
+pub struct FileBackend {
+    /// The file mapped.
+    pub file: Arc<File>,
+    /// Offset in the file where the mapping starts.
+    pub offset: u64,
+    /// Map the file shared, so that guest writes reach the file. Pages
+    /// written to a private mapping are copied, and the file is untouched.
+    pub shared: bool,
+}
+
+/// Map `size` bytes of `backend` or anonymous memory readable and writable.
+///
+/// # Arguments
+///
+/// * `fixed_addr` - Map at this host address, replacing the mapping there.
+///   Kernel chooses the address if it's null.
+/// * `size` - Size of memory to map.
+/// * `backend` - File to map, anonymous memory is mapped if it's `None`.
+fn map_memory(
+    fixed_addr: *mut libc::c_void,
+    size: u64,
+    backend: Option<&FileBackend>,
+) -> Result<*mut libc::c_void> {
+    let mut flags = libc::MAP_NORESERVE;
+    if !fixed_addr.is_null() {
+        flags |= libc::MAP_FIXED;
+    }
+    let (fd, offset) = match backend {
+        Some(backend) => {
+            let file_len = backend.file.metadata()?.len();
+            if backend
+                .offset
+                .checked_add(size)
+                .filter(|end| *end <= file_len)
+                .is_none()
+            {
+                return Err(ErrorKind::FileBackend.into());
+            }
+            flags |= if backend.shared {
+                libc::MAP_SHARED
+            } else {
+                libc::MAP_PRIVATE
+            };
+            (backend.file.as_raw_fd(), backend.offset as libc::off_t)
+        }
+        None => {
+            flags |= libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            (-1, 0)
+        }
+    };
+
+    let hva = unsafe {
+        libc::mmap(
+            fixed_addr,
+            size as libc::size_t,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            fd,
+            offset,
+        )
+    };
+    if hva == libc::MAP_FAILED {
+        return Err(ErrorKind::Mmap.into());
+    }
+    Ok(hva)
+}
+
+/// Memory advice for the host memory backing guest ram.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemAdvice {
+    /// Include guest memory in core file or not.
+    pub dump_guest_core: bool,
+    /// Let KSM merge identical pages of guest memory or not.
+    pub mem_merge: bool,
+    /// Transparent huge page policy, `None` means following host's setting.
+    pub thp: Option<bool>,
+}
+
+impl Default for MemAdvice {
+    fn default() -> Self {
+        MemAdvice {
+            dump_guest_core: true,
+            mem_merge: false,
+            thp: None,
+        }
+    }
+}
+
+impl MemAdvice {
+    /// Get the `madvise` advice values which are needed by this configuration.
+    pub fn advice_values(&self) -> Vec<libc::c_int> {
+        let mut advices = Vec::new();
+        if !self.dump_guest_core {
+            advices.push(libc::MADV_DONTDUMP);
+        }
+        if self.mem_merge {
+            advices.push(libc::MADV_MERGEABLE);
+        }
+        match self.thp {
+            Some(true) => advices.push(libc::MADV_HUGEPAGE),
+            Some(false) => advices.push(libc::MADV_NOHUGEPAGE),
+            None => {}
+        }
+        advices
+    }
+
+    /// Apply this advice to a range of host memory with the given `madvise` function.
+    /// The kernel may lack support of some advice, failures are logged but not fatal.
+    /// Return the memory advice which takes effect actually.
+    ///
+    /// # Arguments
+    ///
+    /// * `host_addr` - Start host address of the memory.
+    /// * `size` - Size of the memory.
+    /// * `madvise_fn` - Function to call `madvise`, returns negative value on failure.
+    fn apply<F>(&self, host_addr: u64, size: u64, madvise_fn: F) -> MemAdvice
+    where
+        F: Fn(u64, u64, libc::c_int) -> libc::c_int,
+    {
+        let mut applied = MemAdvice::default();
+        for advice in self.advice_values() {
+            if madvise_fn(host_addr, size, advice) < 0 {
+                error!(
+                    "madvise with advice {} failed: {}",
+                    advice,
+                    std::io::Error::last_os_error()
+                );
+                continue;
+            }
+            match advice {
+                libc::MADV_DONTDUMP => applied.dump_guest_core = false,
+                libc::MADV_MERGEABLE => applied.mem_merge = true,
+                libc::MADV_HUGEPAGE => applied.thp = Some(true),
+                libc::MADV_NOHUGEPAGE => applied.thp = Some(false),
+                _ => {}
+            }
+        }
+        applied
+    }
+}
+
+/// Call `madvise` on the host memory.
+fn host_madvise(host_addr: u64, size: u64, advice: libc::c_int) -> libc::c_int {
+    unsafe { libc::madvise(host_addr as *mut libc::c_void, size as libc::size_t, advice) }
+}
+
 /// Record information of memory mapping.
 pub struct HostMemMapping {
     /// Record the range of one memory segment.
     address_range: AddressRange,
     /// The start address of mapped memory.
     host_addr: *mut u8,
+    /// Memory advice which takes effect on this mapping.
+    mem_advice: MemAdvice,
+    /// File backing this mapping, `None` for anonymous memory.
+    backend: Mutex<Option<FileBackend>>,
+    /// Size of the pages backing this mapping, discarding works in units of it.
+    page_size: u64,
+    /// Discarded ranges of this mapping, recorded as sorted and non-overlapping
+    /// `[start, end)` offsets within the mapping.
+    discarded: Mutex<Vec<(u64, u64)>>,
 }
 
 // Send and Sync is not auto-implemented for raw pointer type
@@ -53,13 +261,13 @@ unsafe impl Send for HostMemMapping {}
 unsafe impl Sync for HostMemMapping {}
 
 impl HostMemMapping {
-    /// Construct a new HostMemMapping.
+    /// Construct a new HostMemMapping of anonymous memory.
     ///
     /// # Arguments
     ///
     /// * `guest_addr` - The start address im memory.
     /// * `size` - Size of memory that will be mapped.
-    /// * `omit_vm_memory` - Dump guest memory in core file or not.
+    /// * `mem_advice` - Memory advice applied to the mapped memory.
     ///
     /// # Errors
     ///
@@ -67,35 +275,32 @@ impl HostMemMapping {
     pub fn new(
         guest_addr: GuestAddress,
         size: u64,
-        omit_vm_memory: bool,
+        mem_advice: MemAdvice,
     ) -> Result<HostMemMapping> {
-        let host_addr = unsafe {
-            let hva = libc::mmap(
-                std::ptr::null_mut() as *mut libc::c_void,
-                size as libc::size_t,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
-                -1,
-                0,
-            );
-            if hva == libc::MAP_FAILED {
-                return Err(ErrorKind::Mmap.into());
-            }
-            hva
-        };
+        Self::with_backend(guest_addr, size, None, mem_advice)
+    }
 
-        if omit_vm_memory {
-            unsafe {
-                let madvise_res = libc::madvise(
-                    host_addr as *mut libc::c_void,
-                    size as libc::size_t,
-                    libc::MADV_DONTDUMP,
-                );
-                if madvise_res < 0 {
-                    error!("madvise with MADV_DONTDUMP failed");
-                }
-            }
-        }
+    /// Construct a new HostMemMapping backed by a file or anonymous memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_addr` - The start address im memory.
+    /// * `size` - Size of memory that will be mapped.
+    /// * `backend` - File backing the memory, anonymous memory if it's `None`.
+    /// * `mem_advice` - Memory advice applied to the mapped memory.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the file is shorter than `offset + size`, or fail to
+    /// map memory.
+    pub fn with_backend(
+        guest_addr: GuestAddress,
+        size: u64,
+        backend: Option<FileBackend>,
+        mem_advice: MemAdvice,
+    ) -> Result<HostMemMapping> {
+        let host_addr = map_memory(std::ptr::null_mut(), size, backend.as_ref())?;
+        let mem_advice = mem_advice.apply(host_addr as u64, size, host_madvise);
 
         Ok(HostMemMapping {
             address_range: AddressRange {
@@ -103,9 +308,44 @@ impl HostMemMapping {
                 size,
             },
             host_addr: host_addr as *mut u8,
+            mem_advice,
+            backend: Mutex::new(backend),
+            page_size: crate::page_size(),
+            discarded: Mutex::new(Vec::new()),
         })
     }
 
+    /// Replace the memory of this mapping with `backend` in place, so that
+    /// the host address seen by KVM and devices doesn't change. The memory
+    /// advice in effect is applied again.
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - File whose content becomes the memory.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the file is shorter than `offset + size`, or fail to
+    /// map it.
+    ///
+    /// # Notes
+    ///
+    /// The content of memory is replaced under anyone accessing it, so it's
+    /// only called when guest isn't running, such as restoring a snapshot.
+    pub fn remap(&self, backend: FileBackend) -> Result<()> {
+        let mut current = self.backend.lock().unwrap();
+        map_memory(
+            self.host_addr as *mut libc::c_void,
+            self.size(),
+            Some(&backend),
+        )?;
+        self.mem_advice
+            .apply(self.host_address(), self.size(), host_madvise);
+        *current = Some(backend);
+        self.discarded.lock().unwrap().clear();
+        Ok(())
+    }
+
     /// Get size of mapped memory.
     pub fn size(&self) -> u64 {
         self.address_range.size
@@ -121,6 +361,214 @@ impl HostMemMapping {
     pub fn host_address(&self) -> u64 {
         self.host_addr as u64
     }
+
+    /// Get the memory advice which takes effect on this mapping.
+    pub fn mem_advice(&self) -> MemAdvice {
+        self.mem_advice
+    }
+
+    /// Get the file backing this mapping, `None` for anonymous memory.
+    pub fn file_backend(&self) -> Option<FileBackend> {
+        self.backend.lock().unwrap().clone()
+    }
+
+    /// Get size of the pages backing this mapping.
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Get the total size of discarded memory, which is the memory returned to host.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(start, end)| end - start)
+            .sum()
+    }
+
+    /// Check that `[offset, offset + size)` is within this mapping and consists of whole pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset within this mapping.
+    /// * `size` - Size of memory segment.
+    pub(crate) fn check_discard_range(&self, offset: u64, size: u64) -> Result<()> {
+        if offset % self.page_size != 0 {
+            return Err(
+                ErrorKind::AddrNotAligned(self.start_address().raw_value() + offset).into(),
+            );
+        }
+        if size % self.page_size != 0 {
+            return Err(ErrorKind::AddrNotAligned(
+                self.start_address().raw_value() + offset + size,
+            )
+            .into());
+        }
+        if offset
+            .checked_add(size)
+            .filter(|end| *end <= self.size())
+            .is_none()
+        {
+            return Err(ErrorKind::Overflow(self.start_address().raw_value() + offset).into());
+        }
+        Ok(())
+    }
+
+    /// Return a segment of memory to host, the content of it is lost and it reads
+    /// as zero when the guest touches it again. Memory of a private file
+    /// mapping reads as the content of the file instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset within this mapping.
+    /// * `size` - Size of memory segment.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the segment is not page aligned, or `madvise` or
+    /// `fallocate` fails.
+    pub fn discard(&self, offset: u64, size: u64) -> Result<()> {
+        self.check_discard_range(offset, size)?;
+        let ret = match self.backend.lock().unwrap().as_ref() {
+            // Pages of shared file mapping are kept by the file, punching a
+            // hole frees them and unmaps them from the mapping.
+            Some(backend) if backend.shared => unsafe {
+                libc::fallocate(
+                    backend.file.as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    (backend.offset + offset) as libc::off_t,
+                    size as libc::off_t,
+                )
+            },
+            _ => host_madvise(self.host_address() + offset, size, libc::MADV_DONTNEED),
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        range_insert(&mut self.discarded.lock().unwrap(), offset, offset + size);
+        Ok(())
+    }
+
+    /// Mark a discarded segment of memory in use again. Pages are populated on
+    /// demand when the guest touches them, so only the accounting is updated.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset within this mapping.
+    /// * `size` - Size of memory segment.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the segment is not page aligned.
+    pub fn undiscard(&self, offset: u64, size: u64) -> Result<()> {
+        self.check_discard_range(offset, size)?;
+        range_remove(&mut self.discarded.lock().unwrap(), offset, offset + size);
+        Ok(())
+    }
+}
+
+/// Add `[start, end)` to a sorted list of non-overlapping ranges, merging
+/// the ranges it overlaps or touches.
+fn range_insert(ranges: &mut Vec<(u64, u64)>, mut start: u64, mut end: u64) {
+    if start >= end {
+        return;
+    }
+    let first = ranges
+        .iter()
+        .position(|r| r.1 >= start)
+        .unwrap_or(ranges.len());
+    let mut last = first;
+    while last < ranges.len() && ranges[last].0 <= end {
+        start = std::cmp::min(start, ranges[last].0);
+        end = std::cmp::max(end, ranges[last].1);
+        last += 1;
+    }
+    ranges.splice(first..last, std::iter::once((start, end)));
+}
+
+/// Remove `[start, end)` from a sorted list of non-overlapping ranges,
+/// ranges partially covered are cut.
+fn range_remove(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    if start >= end {
+        return;
+    }
+    let mut result = Vec::with_capacity(ranges.len() + 1);
+    for &(s, e) in ranges.iter() {
+        if e <= start || s >= end {
+            result.push((s, e));
+            continue;
+        }
+        if s < start {
+            result.push((s, start));
+        }
+        if e > end {
+            result.push((end, e));
+        }
+    }
+    *ranges = result;
+}
+
+/// A piece of host memory backing guest ram.
+///
+/// It holds a reference of the `HostMemMapping`, so the host memory stays mapped
+/// as long as the `HostMemRef` lives, even if the ram region is removed from the
+/// topology of `AddressSpace` meanwhile.
+///
+/// # Safety
+///
+/// The memory in `[host_address(), host_address() + len())` is valid to access
+/// while the `HostMemRef` lives. The guest may access the same memory concurrently,
+/// so users must not assume its content is stable. After the ram region is removed
+/// from `AddressSpace`, the memory is no longer seen by the guest.
+#[derive(Clone)]
+pub struct HostMemRef {
+    /// The mapping this memory belongs to.
+    mapping: Arc<HostMemMapping>,
+    /// Offset within the mapping.
+    offset: u64,
+    /// Length of the memory.
+    len: u64,
+}
+
+impl HostMemRef {
+    /// Create a reference of `[offset, offset + len)` within the mapping.
+    ///
+    /// # Arguments
+    ///
+    /// * `mapping` - The mapping this memory belongs to.
+    /// * `offset` - Offset within the mapping.
+    /// * `len` - Length of the memory.
+    pub(crate) fn new(mapping: Arc<HostMemMapping>, offset: u64, len: u64) -> Option<HostMemRef> {
+        offset
+            .checked_add(len)
+            .filter(|end| *end <= mapping.size())?;
+        Some(HostMemRef {
+            mapping,
+            offset,
+            len,
+        })
+    }
+
+    /// Get start `HVA` (host virtual address) of the memory.
+    pub fn host_address(&self) -> u64 {
+        self.mapping.host_address() + self.offset
+    }
+
+    /// Get start pointer of the memory.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.host_address() as *mut u8
+    }
+
+    /// Get length of the memory.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Check if the memory is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 }
 
 impl Drop for HostMemMapping {
@@ -146,9 +594,240 @@ mod test {
 
     #[test]
     fn test_ramblock_creation() {
-        let ram1 = HostMemMapping::new(GuestAddress(0), 100u64, false).unwrap();
-        let ram2 = HostMemMapping::new(GuestAddress(0), 100u64, false).unwrap();
+        let ram1 = HostMemMapping::new(GuestAddress(0), 100u64, MemAdvice::default()).unwrap();
+        let ram2 = HostMemMapping::new(GuestAddress(0), 100u64, MemAdvice::default()).unwrap();
         identify(ram1, 0, 100);
         identify(ram2, 0, 100);
     }
+
+    #[test]
+    fn test_split_ram_ranges() {
+        let ranges = [(0, 0x4000), (0x1_0000, 0x5000), (0x2_0000, 0x1000)];
+        assert_eq!(split_ram_ranges(&ranges, 0x10_0000), ranges.to_vec());
+        assert_eq!(
+            split_ram_ranges(&ranges, 0x2000),
+            vec![
+                (0, 0x2000),
+                (0x2000, 0x2000),
+                (0x1_0000, 0x2000),
+                (0x1_2000, 0x2000),
+                (0x1_4000, 0x1000),
+                (0x2_0000, 0x1000),
+            ]
+        );
+        assert!(split_ram_ranges(&[(0, 0)], 0x2000).is_empty());
+    }
+
+    #[test]
+    fn test_create_host_mmaps() {
+        let page = crate::page_size();
+        let mappings =
+            create_host_mmaps(&[(0, 5 * page)], None, MemAdvice::default(), 2 * page).unwrap();
+        assert_eq!(mappings.len(), 3);
+        assert_eq!(mappings[2].start_address(), GuestAddress(4 * page));
+        assert_eq!(mappings[2].size(), page);
+        assert!(mappings[2].file_backend().is_none());
+
+        assert!(create_host_mmaps(&[(0, 5 * page)], None, MemAdvice::default(), 0).is_err());
+        assert!(create_host_mmaps(&[(0, 5 * page)], None, MemAdvice::default(), page + 1).is_err());
+    }
+
+    fn temp_file(name: &str, size: u64) -> (std::path::PathBuf, Arc<File>) {
+        let path =
+            std::env::temp_dir().join(format!("stratovirt-test-{}-{}", std::process::id(), name));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(size).unwrap();
+        (path, Arc::new(file))
+    }
+
+    #[test]
+    fn test_file_backed_mmaps() {
+        use std::os::unix::fs::FileExt;
+
+        let page = crate::page_size();
+        let (path, file) = temp_file("shared", 4 * page);
+        let backend = FileBackend {
+            file: file.clone(),
+            offset: page,
+            shared: true,
+        };
+        let mappings = create_host_mmaps(
+            &[(0, 3 * page)],
+            Some(&backend),
+            MemAdvice::default(),
+            2 * page,
+        )
+        .unwrap();
+        assert_eq!(mappings[0].file_backend().unwrap().offset, page);
+        assert_eq!(mappings[1].file_backend().unwrap().offset, 3 * page);
+
+        // guest writes reach the file
+        unsafe { *(mappings[1].host_address() as *mut u8) = 0x5a };
+        let mut byte = [0_u8; 1];
+        file.read_exact_at(&mut byte, 3 * page).unwrap();
+        assert_eq!(byte[0], 0x5a);
+
+        // discarded pages are punched from the file
+        mappings[1].discard(0, page).unwrap();
+        file.read_exact_at(&mut byte, 3 * page).unwrap();
+        assert_eq!(byte[0], 0);
+        assert_eq!(mappings[1].discarded_bytes(), page);
+
+        // file shorter than the ranges
+        let err = create_host_mmaps(&[(0, 4 * page)], Some(&backend), MemAdvice::default(), page)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Exceed file-backend length");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_remap_file() {
+        use std::os::unix::fs::FileExt;
+
+        let page = crate::page_size();
+        let (path, file) = temp_file("private", 2 * page);
+        file.write_all_at(&[0xa5], page).unwrap();
+        let ram = HostMemMapping::new(GuestAddress(0), page, MemAdvice::default()).unwrap();
+        let host_addr = ram.host_address();
+        unsafe { *(host_addr as *mut u8) = 0xff };
+        ram.discard(0, page).unwrap();
+
+        ram.remap(FileBackend {
+            file: file.clone(),
+            offset: page,
+            shared: false,
+        })
+        .unwrap();
+        assert_eq!(ram.host_address(), host_addr);
+        assert_eq!(ram.discarded_bytes(), 0);
+        assert_eq!(unsafe { *(host_addr as *const u8) }, 0xa5);
+
+        // private mapping leaves the file untouched
+        unsafe { *(host_addr as *mut u8) = 0x5a };
+        let mut byte = [0_u8; 1];
+        file.read_exact_at(&mut byte, page).unwrap();
+        assert_eq!(byte[0], 0xa5);
+
+        let backend = FileBackend {
+            file,
+            offset: 2 * page,
+            shared: false,
+        };
+        assert!(ram.remap(backend).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_discard_ranges() {
+        let mut ranges = Vec::new();
+        range_insert(&mut ranges, 0x1000, 0x2000);
+        range_insert(&mut ranges, 0x4000, 0x5000);
+        range_insert(&mut ranges, 0x2000, 0x3000);
+        assert_eq!(ranges, vec![(0x1000, 0x3000), (0x4000, 0x5000)]);
+        range_insert(&mut ranges, 0x1000, 0x2000);
+        assert_eq!(ranges, vec![(0x1000, 0x3000), (0x4000, 0x5000)]);
+        range_insert(&mut ranges, 0x2000, 0x4800);
+        assert_eq!(ranges, vec![(0x1000, 0x5000)]);
+
+        range_remove(&mut ranges, 0x2000, 0x3000);
+        assert_eq!(ranges, vec![(0x1000, 0x2000), (0x3000, 0x5000)]);
+        range_remove(&mut ranges, 0, 0x1000);
+        assert_eq!(ranges, vec![(0x1000, 0x2000), (0x3000, 0x5000)]);
+        range_remove(&mut ranges, 0x1800, 0x4000);
+        assert_eq!(ranges, vec![(0x1000, 0x1800), (0x4000, 0x5000)]);
+    }
+
+    #[test]
+    fn test_discard_accounting() {
+        let page = crate::page_size();
+        let ram = HostMemMapping::new(GuestAddress(0), 4 * page, MemAdvice::default()).unwrap();
+        unsafe { *(ram.host_address() as *mut u8) = 0xff };
+
+        ram.discard(0, 2 * page).unwrap();
+        ram.discard(page, 2 * page).unwrap();
+        assert_eq!(ram.discarded_bytes(), 3 * page);
+        assert_eq!(unsafe { *(ram.host_address() as *const u8) }, 0);
+
+        ram.undiscard(0, page).unwrap();
+        assert_eq!(ram.discarded_bytes(), 2 * page);
+
+        // partial pages and ranges beyond the mapping are rejected
+        assert!(ram.discard(1, page).is_err());
+        assert!(ram.discard(0, page + 1).is_err());
+        assert!(ram.discard(3 * page, 2 * page).is_err());
+        assert_eq!(ram.discarded_bytes(), 2 * page);
+    }
+
+    #[test]
+    fn test_mem_advice_values() {
+        let mut advice = MemAdvice::default();
+        assert!(advice.advice_values().is_empty());
+
+        advice.dump_guest_core = false;
+        assert_eq!(advice.advice_values(), vec![libc::MADV_DONTDUMP]);
+
+        advice.mem_merge = true;
+        advice.thp = Some(true);
+        assert_eq!(
+            advice.advice_values(),
+            vec![
+                libc::MADV_DONTDUMP,
+                libc::MADV_MERGEABLE,
+                libc::MADV_HUGEPAGE
+            ]
+        );
+
+        advice.dump_guest_core = true;
+        advice.mem_merge = false;
+        advice.thp = Some(false);
+        assert_eq!(advice.advice_values(), vec![libc::MADV_NOHUGEPAGE]);
+    }
+
+    #[test]
+    fn test_mem_advice_apply() {
+        let advice = MemAdvice {
+            dump_guest_core: false,
+            mem_merge: true,
+            thp: Some(true),
+        };
+
+        let calls = std::cell::RefCell::new(Vec::new());
+        let applied = advice.apply(0x1000, 0x2000, |addr, size, adv| {
+            calls.borrow_mut().push((addr, size, adv));
+            0
+        });
+        assert_eq!(applied, advice);
+        assert_eq!(
+            calls.into_inner(),
+            vec![
+                (0x1000, 0x2000, libc::MADV_DONTDUMP),
+                (0x1000, 0x2000, libc::MADV_MERGEABLE),
+                (0x1000, 0x2000, libc::MADV_HUGEPAGE),
+            ]
+        );
+
+        // kernel without KSM support, the rest advice still takes effect
+        let applied = advice.apply(0x1000, 0x2000, |_, _, adv| {
+            if adv == libc::MADV_MERGEABLE {
+                -1
+            } else {
+                0
+            }
+        });
+        assert_eq!(
+            applied,
+            MemAdvice {
+                dump_guest_core: false,
+                mem_merge: false,
+                thp: Some(true),
+            }
+        );
+    }
 }
@@ -18,7 +18,7 @@
 //! ```rust
 //! use std::sync::{Arc, Mutex};
 //! extern crate address_space;
-//! use address_space::{AddressSpace, Region, GuestAddress, HostMemMapping, RegionOps};
+//! use address_space::{AddressSpace, Region, GuestAddress, HostMemMapping, MemAdvice, RegionOps};
 //!
 //! struct DummyDevice;
 //! impl DummyDevice {
@@ -37,7 +37,7 @@
 //!     let space = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
 //!
 //!     // 2. create an Ram-type Region, and set it's priority
-//!     let mem_mapping = Arc::new(HostMemMapping::new(GuestAddress(0), 0x1000, false).unwrap());
+//!     let mem_mapping = Arc::new(HostMemMapping::new(GuestAddress(0), 0x1000, MemAdvice::default()).unwrap());
 //!     let ram_region = Region::init_ram_region(mem_mapping.clone());
 //!     ram_region.set_priority(10);
 //!
@@ -87,7 +87,10 @@ mod region;
 
 pub use address::{AddressRange, GuestAddress};
 pub use address_space::AddressSpace;
-pub use host_mmap::{create_host_mmaps, HostMemMapping};
+pub use host_mmap::{
+    create_host_mmaps, split_ram_ranges, FileBackend, HostMemMapping, HostMemRef, MemAdvice,
+    DEFAULT_MAX_MAPPING_SIZE,
+};
 #[cfg(target_arch = "x86_64")]
 pub use listener::KvmIoListener;
 pub use listener::KvmMemoryListener;
@@ -118,9 +121,18 @@ pub mod errors {
             AddrInvalid(addr: u64) {
                 display("Failed to find matched region, addr {}", addr)
             }
+            AddrUnmapped(op: &'static str, addr: u64, count: u64, start: u64, end: u64) {
+                display("{} of {:#x} bytes at GPA {:#x} falls in unmapped hole [{:#x}, {:#x})", op, count, addr, start, end)
+            }
+            AccessOutOfRange(op: &'static str, addr: u64, count: u64, start: u64, end: u64) {
+                display("{} of {:#x} bytes at GPA {:#x} exceeds region [{:#x}, {:#x})", op, count, addr, start, end)
+            }
             Overflow(addr: u64) {
                 display("Address overflows, addr is {}", addr)
             }
+            NotRam(start: u64, end: u64) {
+                display("Range [{:#x}, {:#x}) is not fully backed by ram", start, end)
+            }
             FileBackend {
                 display("Exceed file-backend length")
             }
@@ -10,14 +10,14 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
-use kvm_bindings::kvm_userspace_memory_region;
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY};
 use kvm_ioctls::{IoEventAddress, NoDatamatch, VmFd};
 use util::num_ops::round_down;
 
-use crate::{page_size, AddressRange, FlatRange, RegionIoEventFd, RegionType};
+use crate::{page_size, AddressRange, FlatRange, GuestAddress, RegionIoEventFd, RegionType};
 
 pub mod errors {
     error_chain! {
@@ -37,7 +37,7 @@ pub mod errors {
 use self::errors::{ErrorKind, Result, ResultExt};
 
 /// Different operations of listener requests.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ListenerReqType {
     /// Add a region.
     AddRegion,
@@ -68,6 +68,23 @@ pub trait Listener: Send + Sync {
     ) -> std::result::Result<(), crate::errors::Error> {
         Ok(())
     }
+
+    /// Start or stop logging the pages of Ram written by guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `_enable` - Start logging if true, or stop it.
+    fn set_dirty_log(&self, _enable: bool) -> std::result::Result<(), crate::errors::Error> {
+        Ok(())
+    }
+
+    /// Get and clear the pages of Ram written by guest since the last call,
+    /// as bitmaps of pages following their start addresses.
+    fn get_dirty_log(
+        &self,
+    ) -> std::result::Result<Vec<(GuestAddress, Vec<u64>)>, crate::errors::Error> {
+        Ok(Vec::new())
+    }
 }
 
 /// Memory slot constructing a link between guest address and host address.
@@ -83,6 +100,9 @@ struct MemSlot {
     pub host_addr: u64,
     /// Flag.
     pub flag: u32,
+    /// Whether pages written by guest are logged when dirty log is started,
+    /// which is only true for slots of Ram.
+    pub dirty_log: bool,
 }
 
 /// Kvm memory listener.
@@ -94,6 +114,8 @@ pub struct KvmMemoryListener {
     fd: Arc<VmFd>,
     /// Record all MemSlots.
     slots: Arc<Mutex<Vec<MemSlot>>>,
+    /// Whether pages written by guest are logged in the slots of Ram.
+    log_dirty: Arc<AtomicBool>,
 }
 
 impl KvmMemoryListener {
@@ -108,6 +130,7 @@ impl KvmMemoryListener {
             as_id: Arc::new(AtomicU32::new(0)),
             fd: vmfd,
             slots: Arc::new(Mutex::new(vec![MemSlot::default(); nr_slots as usize])),
+            log_dirty: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -215,27 +238,36 @@ impl KvmMemoryListener {
     ///
     /// Return Error if fail to delete kvm_mem_slot.
     fn add_region(&self, flat_range: &FlatRange) -> Result<()> {
-        if flat_range.owner.region_type() != RegionType::Ram {
-            return Ok(());
-        }
+        let flags = match flat_range.owner.region_type() {
+            RegionType::Ram if self.log_dirty.load(Ordering::SeqCst) => KVM_MEM_LOG_DIRTY_PAGES,
+            RegionType::Ram | RegionType::RamDevice => 0,
+            // Guest writes to read-only memory slot exit to VMM as MMIO.
+            RegionType::Rom => KVM_MEM_READONLY,
+            _ => return Ok(()),
+        };
+        let dirty_log = flat_range.owner.region_type() == RegionType::Ram;
 
         let (aligned_addr, aligned_size) =
             Self::align_mem_slot(flat_range.addr_range, page_size()).map(|r| (r.base, r.size))?;
         let align_adjust = aligned_addr.raw_value() - flat_range.addr_range.base.raw_value();
 
-        // `unwrap()` won't fail because Ram-type Region definitely has hva
-        let aligned_hva = flat_range.owner.get_host_address().unwrap()
+        // `unwrap()` won't fail because Ram, Rom and RamDevice Region definitely has hva
+        let aligned_hva = flat_range.owner.backing_host_address().unwrap()
             + flat_range.offset_in_region
             + align_adjust;
 
         let slot_idx = self.get_free_slot(aligned_addr.raw_value(), aligned_size, aligned_hva)?;
+        let mut slots = self.slots.lock().unwrap();
+        slots[slot_idx as usize].flag = flags;
+        slots[slot_idx as usize].dirty_log = dirty_log;
+        drop(slots);
 
         let kvm_region = kvm_userspace_memory_region {
             slot: slot_idx | (self.as_id.load(Ordering::SeqCst) << 16),
             guest_phys_addr: aligned_addr.raw_value(),
             memory_size: aligned_size,
             userspace_addr: aligned_hva,
-            flags: 0,
+            flags,
         };
         unsafe {
             self.fd.set_user_memory_region(kvm_region).or_else(|e| {
@@ -259,8 +291,9 @@ impl KvmMemoryListener {
     ///
     /// * `flat_range` - FlatRange would be used to find the region.
     fn delete_region(&self, flat_range: &FlatRange) -> Result<()> {
-        if flat_range.owner.region_type() != RegionType::Ram {
-            return Ok(());
+        match flat_range.owner.region_type() {
+            RegionType::Ram | RegionType::Rom | RegionType::RamDevice => {}
+            _ => return Ok(()),
         }
 
         let (aligned_addr, aligned_size) =
@@ -287,6 +320,65 @@ impl KvmMemoryListener {
         Ok(())
     }
 
+    /// Set the flags of the slots of Ram, so that KVM starts or stops logging
+    /// the pages written by guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `enable` - Start logging if true, or stop it.
+    fn log_dirty_pages(&self, enable: bool) -> Result<()> {
+        let mut slots = self.slots.lock().unwrap();
+        self.log_dirty.store(enable, Ordering::SeqCst);
+        let flag = if enable { KVM_MEM_LOG_DIRTY_PAGES } else { 0 };
+        for slot in slots.iter_mut() {
+            if slot.size == 0 || !slot.dirty_log || slot.flag == flag {
+                continue;
+            }
+            let kvm_region = kvm_userspace_memory_region {
+                slot: slot.index | (self.as_id.load(Ordering::SeqCst) << 16),
+                guest_phys_addr: slot.guest_addr,
+                memory_size: slot.size,
+                userspace_addr: slot.host_addr,
+                flags: flag,
+            };
+            unsafe {
+                self.fd.set_user_memory_region(kvm_region).chain_err(|| {
+                    format!(
+                        "KVM set dirty log of memory slot failed: addr {}",
+                        slot.guest_addr
+                    )
+                })?;
+            }
+            slot.flag = flag;
+        }
+        Ok(())
+    }
+
+    /// Get and clear the dirty bitmaps of the slots logging dirty pages.
+    fn dirty_bitmaps(&self) -> Result<Vec<(GuestAddress, Vec<u64>)>> {
+        let slots = self.slots.lock().unwrap();
+        let mut bitmaps = Vec::new();
+        for slot in slots.iter() {
+            if slot.size == 0 || slot.flag & KVM_MEM_LOG_DIRTY_PAGES == 0 {
+                continue;
+            }
+            let bitmap = self
+                .fd
+                .get_dirty_log(
+                    slot.index | (self.as_id.load(Ordering::SeqCst) << 16),
+                    slot.size as usize,
+                )
+                .chain_err(|| {
+                    format!(
+                        "KVM get dirty log of memory slot failed: addr {}",
+                        slot.guest_addr
+                    )
+                })?;
+            bitmaps.push((GuestAddress(slot.guest_addr), bitmap));
+        }
+        Ok(bitmaps)
+    }
+
     /// Register a IoEvent to `/dev/kvm`.
     ///
     /// # Arguments
@@ -388,6 +480,17 @@ impl Listener for KvmMemoryListener {
         }
         Ok(())
     }
+
+    fn set_dirty_log(&self, enable: bool) -> std::result::Result<(), crate::errors::Error> {
+        self.log_dirty_pages(enable)?;
+        Ok(())
+    }
+
+    fn get_dirty_log(
+        &self,
+    ) -> std::result::Result<Vec<(GuestAddress, Vec<u64>)>, crate::errors::Error> {
+        Ok(self.dirty_bitmaps()?)
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -507,7 +610,7 @@ mod test {
     use vmm_sys_util::eventfd::EventFd;
 
     use super::*;
-    use crate::{GuestAddress, HostMemMapping, Region, RegionIoEventFd};
+    use crate::{GuestAddress, HostMemMapping, MemAdvice, Region, RegionIoEventFd};
 
     fn generate_region_ioeventfd(addr: u64, datamatch: Option<u64>) -> RegionIoEventFd {
         RegionIoEventFd {
@@ -519,7 +622,8 @@ mod test {
     }
 
     fn create_ram_range(addr: u64, size: u64, offset_in_region: u64) -> FlatRange {
-        let mem_mapping = Arc::new(HostMemMapping::new(GuestAddress(addr), size, false).unwrap());
+        let mem_mapping =
+            Arc::new(HostMemMapping::new(GuestAddress(addr), size, MemAdvice::default()).unwrap());
         FlatRange {
             addr_range: AddressRange::new(
                 mem_mapping.start_address().unchecked_add(offset_in_region),
@@ -574,6 +678,74 @@ mod test {
             .is_err());
     }
 
+    #[test]
+    fn test_add_del_ram_region_repeatedly() {
+        let kml = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => KvmMemoryListener::new(4, Arc::new(vm_fd)),
+            Err(_) => return,
+        };
+
+        // more iterations than slots, deleted slots must be reused
+        let ram_size = page_size();
+        for i in 0..16 {
+            let ram_fr = create_ram_range(i * ram_size, ram_size, 0);
+            kml.handle_request(Some(&ram_fr), None, ListenerReqType::AddRegion)
+                .unwrap();
+            assert_eq!(
+                kml.slots
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|s| s.size != 0)
+                    .count(),
+                1
+            );
+            kml.handle_request(Some(&ram_fr), None, ListenerReqType::DeleteRegion)
+                .unwrap();
+            assert!(kml.slots.lock().unwrap().iter().all(|s| s.size == 0));
+        }
+    }
+
+    #[test]
+    fn test_dirty_log_of_ram_device() {
+        let kml = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => KvmMemoryListener::new(34, Arc::new(vm_fd)),
+            Err(_) => return,
+        };
+
+        let page_size = page_size();
+        let ram_fr = create_ram_range(0, page_size, 0);
+        let mem_mapping = Arc::new(
+            HostMemMapping::new(GuestAddress(page_size), page_size, MemAdvice::default()).unwrap(),
+        );
+        let device_fr = FlatRange {
+            addr_range: AddressRange::new(GuestAddress(page_size), page_size),
+            owner: Region::init_ram_device_region(mem_mapping),
+            offset_in_region: 0,
+        };
+        kml.handle_request(Some(&ram_fr), None, ListenerReqType::AddRegion)
+            .unwrap();
+        kml.handle_request(Some(&device_fr), None, ListenerReqType::AddRegion)
+            .unwrap();
+
+        // Only the slot of Ram logs dirty pages.
+        kml.set_dirty_log(true).unwrap();
+        let flags: Vec<(u64, u32)> = kml
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.size != 0)
+            .map(|s| (s.guest_addr, s.flag))
+            .collect();
+        assert_eq!(flags, vec![(0, KVM_MEM_LOG_DIRTY_PAGES), (page_size, 0)]);
+        assert_eq!(kml.get_dirty_log().unwrap().len(), 1);
+        kml.set_dirty_log(false).unwrap();
+
+        kml.handle_request(Some(&device_fr), None, ListenerReqType::DeleteRegion)
+            .unwrap();
+    }
+
     #[test]
     fn test_add_region_align() {
         let kml = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
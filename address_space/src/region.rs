@@ -14,7 +14,7 @@ use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock, Weak};
 
 use crate::errors::{ErrorKind, Result};
-use crate::{AddressRange, AddressSpace, GuestAddress, HostMemMapping, RegionOps};
+use crate::{AddressRange, AddressSpace, GuestAddress, HostMemMapping, HostMemRef, RegionOps};
 
 /// Types of Region.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -25,6 +25,12 @@ pub enum RegionType {
     IO,
     /// Container type.
     Container,
+    /// Read-only memory type, writes from guest are dropped.
+    Rom,
+    /// Memory mapped from the backend of a device, such as virtio-pmem. It's
+    /// accessed like Ram, but it's not guest memory, so it's never reported
+    /// as memory, logged dirty or discarded.
+    RamDevice,
 }
 
 /// Represents a memory region, used by mem-mapped IO or Ram.
@@ -112,6 +118,18 @@ pub struct FlatRange {
     pub offset_in_region: u64,
 }
 
+impl FlatRange {
+    /// Get the host memory backing this flat range,
+    /// Return `None` if the owner is neither Ram nor RamDevice type.
+    pub fn host_mem(&self) -> Option<HostMemRef> {
+        HostMemRef::new(
+            self.owner.mem_mapping()?,
+            self.offset_in_region,
+            self.addr_range.size,
+        )
+    }
+}
+
 /// Contain a set of `FlatRange`.
 /// Note that flat ranges is sorted by implementing `PartialOrd` and `Ord` trait.
 #[derive(Default, Clone)]
@@ -166,6 +184,30 @@ impl Region {
         Region::init_region_internal(mem_mapping.size(), RegionType::Ram, Some(mem_mapping), None)
     }
 
+    /// Initialize Rom-type region, the content should be filled into
+    /// `mem_mapping` before the region is added to address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_mapping` - Mapped memory of this Rom region.
+    pub fn init_rom_region(mem_mapping: Arc<HostMemMapping>) -> Region {
+        Region::init_region_internal(mem_mapping.size(), RegionType::Rom, Some(mem_mapping), None)
+    }
+
+    /// Initialize RamDevice-type region.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_mapping` - Mapped memory of the device.
+    pub fn init_ram_device_region(mem_mapping: Arc<HostMemMapping>) -> Region {
+        Region::init_region_internal(
+            mem_mapping.size(),
+            RegionType::RamDevice,
+            Some(mem_mapping),
+            None,
+        )
+    }
+
     /// Initialize IO-type region.
     ///
     /// # Arguments
@@ -190,6 +232,12 @@ impl Region {
         self.region_type
     }
 
+    /// Check whether `other` is this region or a clone of it, which shares
+    /// its state, rather than another region of the same geometry.
+    pub(crate) fn is_same(&self, other: &Region) -> bool {
+        Arc::ptr_eq(&self.size, &other.size)
+    }
+
     /// Get the priority of this region.
     pub fn priority(&self) -> i32 {
         self.priority.load(Ordering::SeqCst)
@@ -226,12 +274,29 @@ impl Region {
     }
 
     /// Get the host address if this region is backed by host-memory,
-    /// Return `None` if it is not a Ram-type region.
+    /// Return `None` if it is neither Ram nor RamDevice type.
     pub fn get_host_address(&self) -> Option<u64> {
-        if self.region_type != RegionType::Ram {
-            return None;
+        self.mem_mapping().map(|r| r.host_address())
+    }
+
+    /// Get the host address of the memory backing this region, which is
+    /// registered to KVM, Return `None` if it is an IO or Container region.
+    pub(crate) fn backing_host_address(&self) -> Option<u64> {
+        match self.region_type {
+            RegionType::Ram | RegionType::Rom | RegionType::RamDevice => {
+                self.mem_mapping.as_ref().map(|r| r.host_address())
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the host memory mapping backing this region,
+    /// Return `None` if it is neither Ram nor RamDevice type.
+    pub(crate) fn mem_mapping(&self) -> Option<Arc<HostMemMapping>> {
+        match self.region_type {
+            RegionType::Ram | RegionType::RamDevice => self.mem_mapping.clone(),
+            _ => None,
         }
-        self.mem_mapping.as_ref().map(|r| r.host_address())
     }
 
     /// Return all sub-regions of this Region, the returned vector is not empty,
@@ -304,7 +369,7 @@ impl Region {
         self.check_valid_offset(offset, count)?;
 
         match self.region_type {
-            RegionType::Ram => {
+            RegionType::Ram | RegionType::Rom | RegionType::RamDevice => {
                 let host_addr = self.mem_mapping.as_ref().unwrap().host_address();
                 let slice = unsafe {
                     std::slice::from_raw_parts((host_addr + offset) as *const u8, count as usize)
@@ -342,7 +407,7 @@ impl Region {
     ///
     /// Return Error if
     /// * fail to access io region.
-    /// * the region is a container.
+    /// * the region is a container or rom.
     /// * the address overflows.
     pub fn write(
         &self,
@@ -354,7 +419,7 @@ impl Region {
         self.check_valid_offset(offset, count)?;
 
         match self.region_type {
-            RegionType::Ram => {
+            RegionType::Ram | RegionType::RamDevice => {
                 let host_addr = self.mem_mapping.as_ref().unwrap().host_address();
                 let slice = unsafe {
                     std::slice::from_raw_parts_mut((host_addr + offset) as *mut u8, count as usize)
@@ -487,7 +552,7 @@ impl Region {
         if let Some(space) = self.space.read().unwrap().upgrade() {
             space.update_topology()?;
         } else {
-            debug!("add subregion to container region, which has no belonged address-space");
+            debug!("delete subregion from container region, which has no belonged address-space");
         }
         child.del_belonged_address_space();
 
@@ -527,7 +592,7 @@ impl Region {
                     sub_r.render_region_pass(region_base, intersect, flat_view)?;
                 }
             }
-            RegionType::Ram | RegionType::IO => {
+            RegionType::Ram | RegionType::IO | RegionType::Rom | RegionType::RamDevice => {
                 self.render_terminate_region(base, addr_range, flat_view)?;
             }
         }
@@ -629,7 +694,7 @@ impl Region {
         let mut flat_view = FlatView::default();
         match self.region_type {
             RegionType::Container => self.render_region_pass(base, addr_range, &mut flat_view)?,
-            RegionType::Ram | RegionType::IO => {
+            RegionType::Ram | RegionType::IO | RegionType::Rom | RegionType::RamDevice => {
                 self.render_terminate_region(base, addr_range, &mut flat_view)?
             }
         }
@@ -645,6 +710,7 @@ mod test {
     use vmm_sys_util::eventfd::EventFd;
 
     use super::*;
+    use crate::MemAdvice;
 
     #[derive(Default)]
     struct TestDevice {
@@ -677,7 +743,8 @@ mod test {
 
     #[test]
     fn test_ram_region() {
-        let mem_mapping = Arc::new(HostMemMapping::new(GuestAddress(0), 1024u64, false).unwrap());
+        let mem_mapping =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1024u64, MemAdvice::default()).unwrap());
         let ram_region = Region::init_ram_region(mem_mapping.clone());
         let data: [u8; 10] = [10; 10];
         let mut res_data: [u8; 10] = [0; 10];
@@ -707,11 +774,38 @@ mod test {
         assert!(ram_region.check_valid_offset(100, 1000).is_err());
     }
 
+    #[test]
+    fn test_rom_region() {
+        let mem_mapping =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1024u64, MemAdvice::default()).unwrap());
+        let rom =
+            unsafe { std::slice::from_raw_parts_mut(mem_mapping.host_address() as *mut u8, 4) };
+        rom.copy_from_slice(&[0xea, 0x5b, 0xe0, 0x00]);
+        let rom_region = Region::init_rom_region(mem_mapping.clone());
+        assert_eq!(rom_region.region_type(), RegionType::Rom);
+
+        let mut res_data = [0_u8; 4];
+        assert!(rom_region
+            .read(&mut res_data.as_mut(), GuestAddress(0), 0, 4)
+            .is_ok());
+        assert_eq!(res_data, [0xea, 0x5b, 0xe0, 0x00]);
+        // rom is not writable and not exposed as guest ram
+        assert!(rom_region
+            .write(&mut [0_u8; 4].as_ref(), GuestAddress(0), 0, 4)
+            .is_err());
+        assert!(rom_region.get_host_address().is_none());
+        assert_eq!(
+            rom_region.backing_host_address(),
+            Some(mem_mapping.host_address())
+        );
+    }
+
     #[test]
     fn test_ram_region_access() {
         // the target guest address is 0~1024 (1024 not included)
         let rgn_start = GuestAddress(0);
-        let host_mmap = HostMemMapping::new(GuestAddress(0), 1024u64, false).unwrap();
+        let host_mmap =
+            HostMemMapping::new(GuestAddress(0), 1024u64, MemAdvice::default()).unwrap();
         let ram_region = Region::init_ram_region(Arc::new(host_mmap));
         let mut file = std::fs::File::create("/tmp/test_read_write_buffer.tmp").unwrap();
         let mut file_read = std::fs::File::open("/tmp/test_read_write_buffer.tmp").unwrap();
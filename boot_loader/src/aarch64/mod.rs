@@ -10,12 +10,16 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::io::{Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use self::errors::{ErrorKind, Result};
+use self::errors::{ErrorKind, Result, ResultExt};
+use crate::image::Image;
 use address_space::{AddressSpace, GuestAddress};
+use util::byte_code::ByteCode;
 use util::device_tree;
+use util::num_ops::{round_down, round_up};
 
 pub mod errors {
     use util::device_tree;
@@ -28,22 +32,124 @@ pub mod errors {
                 display(
                     "guest memory size {} should bigger than {}",
                     size,
-                    device_tree::FDT_MAX_SIZE
+                    device_tree::size_limit()
                 )
             }
-            InitrdOverflow(addr: u64, size: u32) {
+            InitrdOverflow(size: u64, available: u64) {
                 display(
-                    "Failed to allocate initrd image {} to memory {}.",
-                     size,
-                     addr
+                    "initrd of {} bytes does not fit: only {} bytes available above the kernel",
+                    size,
+                    available
                 )
             }
+            InvalidImage(reason: String) {
+                display("Invalid arm64 kernel Image: {}", reason)
+            }
+            KernelOverflow(addr: u64, size: u64) {
+                display("Kernel needs memory [{:#x}, {:#x}) out of guest ram", addr, addr + size)
+            }
+            CmdlineOverflow(len: u64, max: u64) {
+                display("Kernel cmdline of {} bytes exceeds the {} bytes allowed", len, max)
+            }
+            InvalidFirmware(reason: String) {
+                display("Invalid firmware image: {}", reason)
+            }
         }
     }
 }
 
 const DRAM_MEM_START: u64 = 0x8000_0000;
+/// Base address of the flash where firmware is mapped.
+pub const FLASH_BASE: u64 = 0;
+/// Size of the flash, firmware is mapped at its beginning.
+pub const FLASH_SIZE: u64 = 64 << 20;
 const AARCH64_KERNEL_OFFSET: u64 = 0x8_0000;
+const INITRD_ALIGN: u64 = 0x20_0000;
+/// Max length of `bootargs` without the terminating zero, arm64 kernel copies
+/// it into a buffer of `COMMAND_LINE_SIZE` (2048) bytes.
+const CMDLINE_MAX_SIZE: u64 = 2047;
+
+const ARM64_IMAGE_MAGIC: u32 = 0x644d_5241; // "ARM\x64"
+const ARM64_IMAGE_FLAG_BE: u64 = 0x1;
+const ARM64_IMAGE_FLAG_PAGE_SIZE_SHIFT: u64 = 1;
+const ARM64_IMAGE_FLAG_PAGE_SIZE_MASK: u64 = 0x3;
+
+/// Header of arm64 kernel Image, refer to
+/// https://www.kernel.org/doc/Documentation/arm64/booting.txt.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Arm64ImageHeader {
+    code0: u32,
+    code1: u32,
+    text_offset: u64,
+    image_size: u64,
+    flags: u64,
+    res2: u64,
+    res3: u64,
+    res4: u64,
+    magic: u32,
+    res5: u32,
+}
+
+impl ByteCode for Arm64ImageHeader {}
+
+impl Arm64ImageHeader {
+    /// Parse the header from the beginning of kernel Image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The beginning bytes of kernel Image.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the magic is missing or the kernel is big-endian.
+    fn from_image(image: &[u8]) -> Result<Self> {
+        let header = image
+            .get(..std::mem::size_of::<Self>())
+            .and_then(Self::from_bytes)
+            .copied()
+            .ok_or_else(|| ErrorKind::InvalidImage("image is too small".to_string()))?;
+        if header.magic != ARM64_IMAGE_MAGIC {
+            return Err(ErrorKind::InvalidImage("bad magic".to_string()).into());
+        }
+        // `image_size` and `flags` are valid since linux 3.17, they are zero before.
+        if header.image_size != 0 && header.flags & ARM64_IMAGE_FLAG_BE != 0 {
+            return Err(
+                ErrorKind::InvalidImage("big-endian kernel is not supported".to_string()).into(),
+            );
+        }
+        Ok(header)
+    }
+
+    /// Get the offset from the base of DRAM where the Image should be loaded,
+    /// Image with zero `image_size` is loaded at the default offset 0x80000.
+    fn text_offset(&self) -> u64 {
+        if self.image_size == 0 {
+            AARCH64_KERNEL_OFFSET
+        } else {
+            self.text_offset
+        }
+    }
+
+    /// Get the page size of the kernel, `None` if unspecified.
+    fn page_size(&self) -> Option<u64> {
+        match (self.flags >> ARM64_IMAGE_FLAG_PAGE_SIZE_SHIFT) & ARM64_IMAGE_FLAG_PAGE_SIZE_MASK {
+            1 => Some(4 << 10),
+            2 => Some(16 << 10),
+            3 => Some(64 << 10),
+            _ => None,
+        }
+    }
+
+    /// Get the memory the kernel occupies from its load address, including BSS.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_size` - Size of the Image file.
+    fn effective_size(&self, file_size: u64) -> u64 {
+        std::cmp::max(self.image_size, file_size)
+    }
+}
 
 /// Boot loader config used for aarch64.
 #[derive(Default, Debug)]
@@ -52,8 +158,8 @@ pub struct AArch64BootLoaderConfig {
     pub kernel: PathBuf,
     /// Path of initrd image.
     pub initrd: Option<PathBuf>,
-    /// Initrd file size, 0 means no initrd file.
-    pub initrd_size: u32,
+    /// Kernel cmdline parameters, passed by `bootargs` of fdt.
+    pub kernel_cmdline: String,
 }
 
 /// The start address for `kernel image`, `initrd image` and `dtb` in guest memory.
@@ -62,55 +168,234 @@ pub struct AArch64BootLoader {
     pub kernel_start: u64,
     /// Start address for `initrd image` in guest memory.
     pub initrd_start: u64,
+    /// Size of `initrd image` in guest memory, which is decompressed if compressed.
+    pub initrd_size: u64,
     /// Start address for `dtb` in guest memory.
     pub dtb_start: u64,
 }
 
+/// Read the header of kernel Image.
+///
+/// # Arguments
+///
+/// * `kernel` - Kernel Image.
+fn read_image_header(kernel: &mut Image) -> Result<Arm64ImageHeader> {
+    let mut image = Vec::new();
+    kernel
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| {
+            kernel
+                .by_ref()
+                .take(std::mem::size_of::<Arm64ImageHeader>() as u64)
+                .read_to_end(&mut image)
+        })
+        .chain_err(|| "Failed to read kernel image")?;
+
+    Arm64ImageHeader::from_image(&image)
+}
+
+/// Get the address of initrd, which is placed as high as possible between the
+/// kernel and dtb, aligned to 2M.
+///
+/// # Arguments
+///
+/// * `kernel_end` - End address of the memory reserved for kernel.
+/// * `dtb_addr` - Start address of dtb.
+/// * `initrd_size` - Size of initrd.
+///
+/// # Errors
+///
+/// Return Error if the initrd doesn't fit between kernel and dtb.
+fn initrd_address(kernel_end: u64, dtb_addr: u64, initrd_size: u64) -> Result<u64> {
+    let lowest = round_up(kernel_end, INITRD_ALIGN).unwrap_or(u64::max_value());
+    let available = dtb_addr.saturating_sub(lowest);
+    if initrd_size > available {
+        return Err(ErrorKind::InitrdOverflow(initrd_size, available).into());
+    }
+
+    // `lowest` is aligned, so the aligned address never goes below it.
+    Ok(round_down(dtb_addr - initrd_size, INITRD_ALIGN).unwrap())
+}
+
 pub fn linux_bootloader(
     config: &AArch64BootLoaderConfig,
+    kernel: &mut Image,
+    initrd_size: u64,
     sys_mem: &Arc<AddressSpace>,
 ) -> Result<AArch64BootLoader> {
-    let dtb_addr =
-        if sys_mem.memory_end_address().raw_value() > u64::from(device_tree::FDT_MAX_SIZE) {
-            if let Some(addr) = sys_mem
-                .memory_end_address()
-                .raw_value()
-                .checked_sub(u64::from(device_tree::FDT_MAX_SIZE))
-            {
-                if sys_mem.address_in_memory(GuestAddress(addr), 0) {
-                    addr
-                } else {
-                    DRAM_MEM_START
-                }
-            } else {
-                0
-            }
-        } else {
-            0
-        };
+    let cmdline_len = config.kernel_cmdline.len() as u64;
+    if cmdline_len > CMDLINE_MAX_SIZE {
+        return Err(ErrorKind::CmdlineOverflow(cmdline_len, CMDLINE_MAX_SIZE).into());
+    }
+
+    let header = read_image_header(kernel)?;
+    let kernel_start = DRAM_MEM_START + header.text_offset();
+    let kernel_size = header.effective_size(kernel.size());
+    if !sys_mem.address_in_memory(GuestAddress(kernel_start), kernel_size) {
+        return Err(ErrorKind::KernelOverflow(kernel_start, kernel_size).into());
+    }
+    let kernel_end = kernel_start + kernel_size;
+    info!(
+        "Kernel Image is loaded at {:#x}, occupies {:#x} bytes, page size {:?}",
+        kernel_start,
+        kernel_size,
+        header.page_size()
+    );
+
+    // Space of the size limit is reserved at the end of memory, as the fdt is
+    // generated after the layout is decided.
+    let dtb_addr = sys_mem
+        .memory_end_address()
+        .raw_value()
+        .checked_sub(u64::from(device_tree::size_limit()))
+        .and_then(|addr| round_down(addr, device_tree::FDT_ALIGN))
+        .filter(|addr| sys_mem.address_in_memory(GuestAddress(*addr), 0) && *addr >= kernel_end)
+        .unwrap_or(0);
 
     if dtb_addr == 0 {
         return Err(ErrorKind::DTBOverflow(sys_mem.memory_end_address().raw_value()).into());
     }
 
     let mut initrd_addr = 0;
-    if config.initrd_size > 0 {
-        initrd_addr = if let Some(addr) = dtb_addr.checked_sub(u64::from(config.initrd_size)) {
-            addr
-        } else {
-            return Err(ErrorKind::InitrdOverflow(dtb_addr, config.initrd_size).into());
-        };
-
-        if !sys_mem.address_in_memory(GuestAddress(initrd_addr), 0) {
-            initrd_addr = DRAM_MEM_START + u64::from(device_tree::FDT_MAX_SIZE);
-        }
+    if initrd_size > 0 {
+        initrd_addr = initrd_address(kernel_end, dtb_addr, initrd_size)?;
+        info!(
+            "Initrd of {:#x} bytes is placed at {:#x}",
+            initrd_size, initrd_addr
+        );
     } else {
         info!("No initrd image file.");
     }
 
     Ok(AArch64BootLoader {
-        kernel_start: DRAM_MEM_START + AARCH64_KERNEL_OFFSET,
+        kernel_start,
         initrd_start: initrd_addr,
+        initrd_size,
         dtb_start: dtb_addr,
     })
 }
+
+/// Map firmware to the flash, return the flash base where cpu starts
+/// executing.
+///
+/// # Arguments
+///
+/// * `image` - Content of firmware.
+/// * `sys_mem` - guest memory.
+///
+/// # Errors
+///
+/// Return Error if the firmware is empty or larger than flash, or fail to
+/// map it.
+pub fn setup_firmware(image: &[u8], sys_mem: &Arc<AddressSpace>) -> Result<u64> {
+    let size = image.len() as u64;
+    if size == 0 || size > FLASH_SIZE {
+        return Err(ErrorKind::InvalidFirmware(format!(
+            "size {:#x} is not in (0, {:#x}]",
+            size, FLASH_SIZE
+        ))
+        .into());
+    }
+
+    crate::map_rom(image, FLASH_BASE, FLASH_SIZE, 0, sys_mem)
+        .chain_err(|| "Failed to map firmware to flash")?;
+
+    Ok(FLASH_BASE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build_image(text_offset: u64, image_size: u64, flags: u64) -> Vec<u8> {
+        let header = Arm64ImageHeader {
+            code0: 0x9100_0000,
+            text_offset,
+            image_size,
+            flags,
+            magic: ARM64_IMAGE_MAGIC,
+            ..Default::default()
+        };
+        let mut image = header.as_bytes().to_vec();
+        image.resize(0x1000, 0);
+        image
+    }
+
+    #[test]
+    fn test_image_header() {
+        // zero text_offset
+        let header = Arm64ImageHeader::from_image(&build_image(0, 0x20_0000, 0xa)).unwrap();
+        assert_eq!(header.text_offset(), 0);
+        assert_eq!(header.page_size(), Some(4 << 10));
+        assert_eq!(header.effective_size(0x10_0000), 0x20_0000);
+
+        // non-zero text_offset, image_size smaller than the file
+        let header = Arm64ImageHeader::from_image(&build_image(0x8_0000, 0x1000, 0x6)).unwrap();
+        assert_eq!(header.text_offset(), 0x8_0000);
+        assert_eq!(header.page_size(), Some(64 << 10));
+        assert_eq!(header.effective_size(0x10_0000), 0x10_0000);
+
+        // kernel before 3.17 has no image_size, it's loaded at the default offset
+        let header = Arm64ImageHeader::from_image(&build_image(0x1000, 0, 0x1)).unwrap();
+        assert_eq!(header.text_offset(), AARCH64_KERNEL_OFFSET);
+        assert_eq!(header.page_size(), None);
+
+        // big-endian kernel, bad magic and truncated image are rejected
+        assert!(Arm64ImageHeader::from_image(&build_image(0, 0x20_0000, 0x1)).is_err());
+        let mut image = build_image(0, 0x20_0000, 0);
+        image[56] = 0;
+        assert!(Arm64ImageHeader::from_image(&image).is_err());
+        assert!(Arm64ImageHeader::from_image(&build_image(0, 0x20_0000, 0)[..32]).is_err());
+    }
+
+    #[test]
+    fn test_initrd_address() {
+        // 128M guest with 2M dtb at the end, kernel occupies [2G+512K, 2G+32M)
+        let kernel_end = DRAM_MEM_START + 0x200_0000;
+        let dtb_addr = DRAM_MEM_START + 0x800_0000 - u64::from(device_tree::FDT_MAX_SIZE);
+        let available = dtb_addr - kernel_end;
+
+        // exactly fits
+        assert_eq!(
+            initrd_address(kernel_end, dtb_addr, available).unwrap(),
+            kernel_end
+        );
+        // one byte too big
+        let err = initrd_address(kernel_end, dtb_addr, available + 1)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "initrd of {} bytes does not fit: only {} bytes available above the kernel",
+                available + 1,
+                available
+            )
+        );
+        // initrd is aligned down to 2M
+        assert_eq!(
+            initrd_address(kernel_end, dtb_addr, 0x10_0000).unwrap(),
+            DRAM_MEM_START + 0x7c0_0000
+        );
+        // memory above unaligned kernel end is not usable
+        assert!(initrd_address(kernel_end + 1, dtb_addr, available - 0x20_0000).is_ok());
+        assert!(initrd_address(kernel_end + 1, dtb_addr, available - 0x20_0000 + 1).is_err());
+        // dtb below kernel
+        assert!(initrd_address(kernel_end, kernel_end - 0x1000, 1).is_err());
+    }
+
+    #[test]
+    fn test_compressed_image_header() {
+        let path = PathBuf::from("/tmp/test_compressed_image_header.zst");
+        let zstd = zstd::encode_all(build_image(0, 0x20_0000, 0xa).as_slice(), 0).unwrap();
+        std::fs::write(&path, zstd).unwrap();
+
+        // header is checked on the decompressed Image
+        let mut image = Image::open(&path).unwrap();
+        assert_eq!(image.size(), 0x1000);
+        let header = read_image_header(&mut image).unwrap();
+        assert_eq!(header.text_offset(), 0);
+        assert_eq!(header.effective_size(image.size()), 0x20_0000);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use flate2::read::MultiGzDecoder;
+
+use crate::errors::{ErrorKind, Result, ResultExt};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Max size of decompressed image, which is held in memory before loading.
+const DECOMPRESSED_MAX_SIZE: u64 = 1 << 30;
+
+/// Compression format of kernel or initrd image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect the compression format by the magic at the beginning of image,
+    /// return `None` if the image is not compressed.
+    ///
+    /// # Arguments
+    ///
+    /// * `head` - The beginning bytes of image.
+    pub fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(&GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if head.starts_with(&ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reader which counts the bytes read from the compressed file, so that the
+/// position of corrupted data can be reported.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+enum ImageData {
+    File(File),
+    Decompressed(Cursor<Vec<u8>>),
+}
+
+/// Kernel or initrd image to be loaded, gzip and zstd compressed image is
+/// decompressed when opened, so that it's read as the uncompressed one.
+pub struct Image {
+    data: ImageData,
+    size: u64,
+}
+
+impl Image {
+    /// Open an image file, decompress it if it's compressed.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of image.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to open or read the file, the compressed data is
+    /// corrupted, or the decompressed image exceeds 1G.
+    pub fn open(path: &PathBuf) -> Result<Self> {
+        let mut file = File::open(path).chain_err(|| ErrorKind::BootLoaderOpenKernel)?;
+        let mut head = Vec::with_capacity(ZSTD_MAGIC.len());
+        (&mut file)
+            .take(ZSTD_MAGIC.len() as u64)
+            .read_to_end(&mut head)
+            .chain_err(|| format!("Failed to read image {:?}", path))?;
+        file.seek(SeekFrom::Start(0))
+            .chain_err(|| format!("Failed to seek image {:?}", path))?;
+
+        match Compression::detect(&head) {
+            Some(compression) => {
+                let buf = decompress(file, compression, path)?;
+                info!(
+                    "Image {:?} is decompressed from {:?} to {:#x} bytes",
+                    path,
+                    compression,
+                    buf.len()
+                );
+                Ok(Image {
+                    size: buf.len() as u64,
+                    data: ImageData::Decompressed(Cursor::new(buf)),
+                })
+            }
+            None => {
+                let size = file
+                    .metadata()
+                    .chain_err(|| format!("Failed to get size of image {:?}", path))?
+                    .len();
+                Ok(Image {
+                    data: ImageData::File(file),
+                    size,
+                })
+            }
+        }
+    }
+
+    /// Get the size of image, which is the decompressed size for compressed
+    /// image.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for Image {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.data {
+            ImageData::File(file) => file.read(buf),
+            ImageData::Decompressed(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Seek for Image {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match &mut self.data {
+            ImageData::File(file) => file.seek(pos),
+            ImageData::Decompressed(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+/// Decompress the whole file into memory.
+///
+/// # Arguments
+///
+/// * `file` - Compressed file, positioned at its beginning.
+/// * `compression` - Compression format of the file.
+/// * `path` - Path of the file, used in error message.
+fn decompress(file: File, compression: Compression, path: &PathBuf) -> Result<Vec<u8>> {
+    let mut input = CountingReader {
+        inner: BufReader::new(file),
+        count: 0,
+    };
+    let mut buf = Vec::new();
+    let ret = match compression {
+        Compression::Gzip => MultiGzDecoder::new(&mut input)
+            .take(DECOMPRESSED_MAX_SIZE + 1)
+            .read_to_end(&mut buf),
+        Compression::Zstd => zstd::stream::read::Decoder::new(&mut input).and_then(|decoder| {
+            decoder
+                .take(DECOMPRESSED_MAX_SIZE + 1)
+                .read_to_end(&mut buf)
+        }),
+    };
+    ret.chain_err(|| ErrorKind::DecompressImage(format!("{:?}", path), input.count))?;
+
+    if buf.len() as u64 > DECOMPRESSED_MAX_SIZE {
+        return Err(ErrorKind::ImageTooLarge(format!("{:?}", path), DECOMPRESSED_MAX_SIZE).into());
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    fn image_content() -> Vec<u8> {
+        (0..0x3000_u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn write_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = PathBuf::from(format!("/tmp/{}", name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn read_image(image: &mut Image) -> Vec<u8> {
+        let mut data = Vec::new();
+        image.seek(SeekFrom::Start(0)).unwrap();
+        image.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_detect_compression() {
+        assert_eq!(
+            Compression::detect(&[0x1f, 0x8b, 0x08, 0x00]),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::detect(&[0x7f, b'E', b'L', b'F']), None);
+        assert_eq!(Compression::detect(&[0x28, 0xb5]), None);
+    }
+
+    #[test]
+    fn test_open_image() {
+        let content = image_content();
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&content).unwrap();
+        let gzip = encoder.finish().unwrap();
+        let zstd = zstd::encode_all(content.as_slice(), 0).unwrap();
+
+        for (name, data) in [
+            ("test_image_raw.img", content.clone()),
+            ("test_image_gzip.img", gzip.clone()),
+            ("test_image_zstd.img", zstd),
+        ]
+        .iter()
+        {
+            let path = write_file(name, data);
+            let mut image = Image::open(&path).unwrap();
+            assert_eq!(image.size(), content.len() as u64);
+            assert_eq!(read_image(&mut image), content);
+            // image is seekable after decompression
+            let mut byte = [0_u8; 1];
+            image.seek(SeekFrom::Start(0x1000)).unwrap();
+            image.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], content[0x1000]);
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        // corrupted data is reported with file name
+        let mut corrupted = gzip;
+        let len = corrupted.len();
+        corrupted.truncate(len / 2);
+        let path = write_file("test_image_corrupted.img", &corrupted);
+        let err = Image::open(&path).err().unwrap();
+        assert!(err.to_string().contains("/tmp/test_image_corrupted.img"));
+        std::fs::remove_file(&path).unwrap();
+
+        // missing file
+        assert!(Image::open(&PathBuf::from("/tmp/test_image_missing.img")).is_err());
+    }
+}
@@ -17,9 +17,11 @@
 //! ## Design
 //!
 //! This crate offers support for:
-//! 1. Loading PE (vmlinux.bin) kernel images.
+//! 1. Loading PE (vmlinux.bin) kernel images, and bzImage or ELF vmlinux on `x86_64`.
 //! 2. Loading initrd image.
+//!    Kernel and initrd compressed by gzip or zstd are decompressed transparently.
 //! 3. Initialization for architecture related information.
+//! 4. Mapping firmware image read-only into guest memory.
 //!
 //! ## Platform Support
 //!
@@ -45,9 +47,9 @@
 //!     let bootloader_config = BootLoaderConfig {
 //!         kernel: kernel_file,
 //!         initrd: None,
-//!         initrd_size: 0,
 //!         kernel_cmdline: String::new(),
 //!         cpu_count: 0,
+//!         max_cpus: 0,
 //!     };
 //!
 //!     let layout = load_kernel(&bootloader_config, &guest_mem).unwrap();
@@ -61,7 +63,7 @@
 //!     let bootloader_config = BootLoaderConfig {
 //!         kernel: kernel_file,
 //!         initrd: None,
-//!         initrd_size: 0,
+//!         kernel_cmdline: String::new(),
 //!     };
 //!
 //!     let layout = load_kernel(&bootloader_config, &guest_mem).unwrap();
@@ -76,28 +78,41 @@ extern crate error_chain;
 
 #[cfg(target_arch = "aarch64")]
 mod aarch64;
+mod image;
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
 use std::fs;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use address_space::{AddressSpace, GuestAddress};
+use address_space::{AddressSpace, GuestAddress, HostMemMapping, MemAdvice, Region};
+use image::Image;
 
 #[cfg(target_arch = "aarch64")]
 use aarch64::linux_bootloader;
 #[cfg(target_arch = "aarch64")]
+use aarch64::setup_firmware;
+#[cfg(target_arch = "aarch64")]
 pub use aarch64::AArch64BootLoader as BootLoader;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::AArch64BootLoaderConfig as BootLoaderConfig;
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::{FLASH_BASE, FLASH_SIZE};
 
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::acpi;
 #[cfg(target_arch = "x86_64")]
 use x86_64::linux_bootloader;
 #[cfg(target_arch = "x86_64")]
+use x86_64::setup_firmware;
+#[cfg(target_arch = "x86_64")]
 pub use x86_64::X86BootLoader as BootLoader;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::X86BootLoaderConfig as BootLoaderConfig;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::RESET_VECTOR;
 
 pub mod errors {
     #[cfg(target_arch = "aarch64")]
@@ -115,43 +130,96 @@ pub mod errors {
                 description("Boot loader open kernel error")
                 display("Failed to open kernel image or initrd")
             }
+            DecompressImage(file: String, offset: u64) {
+                display("Failed to decompress {} at compressed offset {:#x}", file, offset)
+            }
+            ImageTooLarge(file: String, max: u64) {
+                display("Decompressed image {} exceeds {:#x} bytes", file, max)
+            }
         }
     }
 }
 
-use self::errors::{ErrorKind, Result};
+use self::errors::{Result, ResultExt};
 
-/// Load PE(vmlinux.bin) linux kernel to Guest Memory.
+/// A piece of image file to be loaded into guest memory.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ImageSegment {
+    /// Offset of the segment in image file.
+    pub file_offset: u64,
+    /// Size of the segment in image file.
+    pub file_size: u64,
+    /// Size of the segment in guest memory, the part beyond `file_size` is zeroed.
+    pub mem_size: u64,
+    /// Start address of the segment in guest memory.
+    pub guest_addr: u64,
+}
+
+/// Load segments of an image to Guest Memory.
 ///
 /// # Arguments
-/// * `kernel_file` - host path for kernel.
-/// * `kernel_start` - kernel start address in guest memory.
+/// * `image` - image to read segments from.
+/// * `segments` - segments to load.
 /// * `sys_mem` - guest memory.
 ///
 /// # Errors
-/// * `BootLoaderOpenKernel`: Open PE linux kernel failed.
-/// * `AddressSpace`: Write PE linux kernel to guest memory failed.
-fn load_image(kernel_file: &PathBuf, kernel_start: u64, sys_mem: &Arc<AddressSpace>) -> Result<()> {
-    debug!("Loading image {:?}", kernel_file);
-    let len = std::fs::metadata(kernel_file).unwrap().len();
-    let mut kernel_image = match fs::File::open(kernel_file) {
-        Ok(file) => file,
-        _ => return Err(ErrorKind::BootLoaderOpenKernel.into()),
-    };
+/// * `AddressSpace`: Write segments to guest memory failed.
+fn load_segments<R: Read + Seek>(
+    image: &mut R,
+    segments: &[ImageSegment],
+    sys_mem: &Arc<AddressSpace>,
+) -> Result<()> {
+    for seg in segments.iter() {
+        image
+            .seek(SeekFrom::Start(seg.file_offset))
+            .chain_err(|| format!("Failed to seek image to 0x{:x}", seg.file_offset))?;
+        sys_mem
+            .write(image, GuestAddress(seg.guest_addr), seg.file_size)
+            .chain_err(|| format!("Failed to load image segment to 0x{:x}", seg.guest_addr))?;
 
-    sys_mem.write(&mut kernel_image, GuestAddress(kernel_start), len)?;
+        if seg.mem_size > seg.file_size {
+            let zero_start = seg.guest_addr + seg.file_size;
+            sys_mem
+                .write(
+                    &mut std::io::repeat(0),
+                    GuestAddress(zero_start),
+                    seg.mem_size - seg.file_size,
+                )
+                .chain_err(|| format!("Failed to zero image segment at 0x{:x}", zero_start))?;
+        }
+    }
 
     Ok(())
 }
 
+/// Load the whole image to Guest Memory.
+///
+/// # Arguments
+/// * `image` - kernel or initrd image.
+/// * `start` - start address in guest memory.
+/// * `sys_mem` - guest memory.
+///
+/// # Errors
+/// * `AddressSpace`: Write image to guest memory failed.
+fn load_image(image: &mut Image, start: u64, sys_mem: &Arc<AddressSpace>) -> Result<()> {
+    let segment = ImageSegment {
+        file_offset: 0,
+        file_size: image.size(),
+        mem_size: image.size(),
+        guest_addr: start,
+    };
+    load_segments(image, &[segment], sys_mem)
+}
+
 /// Load PE(vmlinux.bin) linux kernel and other boot source to Guest Memory.
 ///
 /// # Steps
 ///
-/// 1. Prepare for linux kernel boot env, return guest memory layout.
-/// 2. According guest memory layout, load PE linux kernel to guest memory.
-/// 3. According guest memory layout, load initrd image to guest memory.
-/// 4. For `x86_64` arch, inject cmdline to guest memory.
+/// 1. Open kernel and initrd, which are decompressed if compressed.
+/// 2. Prepare for linux kernel boot env, return guest memory layout.
+/// 3. According guest memory layout, load PE linux kernel to guest memory.
+/// 4. According guest memory layout, load initrd image to guest memory.
+/// 5. For `x86_64` arch, inject cmdline to guest memory.
 ///
 /// # Arguments
 ///
@@ -164,18 +232,89 @@ fn load_image(kernel_file: &PathBuf, kernel_start: u64, sys_mem: &Arc<AddressSpa
 /// Load kernel, initrd or kernel cmdline to guest memory failed. Boot source
 /// is broken or guest memory is unnormal.
 pub fn load_kernel(config: &BootLoaderConfig, sys_mem: &Arc<AddressSpace>) -> Result<BootLoader> {
-    let boot_loader = linux_bootloader(config, sys_mem)?;
-
-    load_image(&config.kernel, boot_loader.kernel_start, &sys_mem)?;
-    match &config.initrd {
-        Some(initrd) => {
-            load_image(&initrd, boot_loader.initrd_start, &sys_mem)?;
+    debug!("Loading kernel {:?}", config.kernel);
+    let mut kernel = Image::open(&config.kernel)?;
+    let mut initrd = match &config.initrd {
+        Some(path) => {
+            debug!("Loading initrd {:?}", path);
+            Some(Image::open(path)?)
         }
-        None => {}
+        None => None,
     };
+    let initrd_size = initrd.as_ref().map_or(0, |rd| rd.size());
+
+    let boot_loader = linux_bootloader(config, &mut kernel, initrd_size, sys_mem)?;
+
+    #[cfg(target_arch = "x86_64")]
+    load_segments(&mut kernel, &boot_loader.kernel_segments, &sys_mem)?;
+    #[cfg(target_arch = "aarch64")]
+    load_image(&mut kernel, boot_loader.kernel_start, &sys_mem)?;
+    if let Some(initrd) = &mut initrd {
+        load_image(initrd, boot_loader.initrd_start, &sys_mem)?;
+    }
 
     #[cfg(target_arch = "x86_64")]
     x86_64::setup_kernel_cmdline(&config, sys_mem)?;
 
     Ok(boot_loader)
 }
+
+/// Map `image` as a Rom region of `size` bytes at `base` of guest memory, the
+/// part beyond the image is zeroed.
+///
+/// # Arguments
+///
+/// * `image` - Content of the Rom.
+/// * `base` - Start address of the Rom in guest memory.
+/// * `size` - Size of the Rom, not smaller than `image`.
+/// * `priority` - Priority of the Rom region, which is higher than ram if
+///                they overlap.
+/// * `sys_mem` - guest memory.
+fn map_rom(
+    image: &[u8],
+    base: u64,
+    size: u64,
+    priority: i32,
+    sys_mem: &Arc<AddressSpace>,
+) -> address_space::errors::Result<()> {
+    let mapping = Arc::new(HostMemMapping::new(
+        GuestAddress(base),
+        size,
+        MemAdvice::default(),
+    )?);
+    // Rom content is filled before guest can see it, as writes through
+    // address space are rejected afterwards.
+    let host_mem =
+        unsafe { std::slice::from_raw_parts_mut(mapping.host_address() as *mut u8, image.len()) };
+    host_mem.copy_from_slice(image);
+
+    let rom = Region::init_rom_region(mapping);
+    rom.set_priority(priority);
+    sys_mem.root().add_subregion(rom, base)
+}
+
+/// Map firmware image read-only to Guest Memory, return the address where
+/// the boot cpu starts executing.
+///
+/// # Arguments
+///
+/// * `firmware` - host path for firmware.
+/// * `sys_mem` - guest memory.
+///
+/// # Errors
+///
+/// Read firmware failed, the size of firmware is unsupported, or map it to
+/// guest memory failed.
+pub fn load_firmware(firmware: &PathBuf, sys_mem: &Arc<AddressSpace>) -> Result<u64> {
+    debug!("Loading firmware {:?}", firmware);
+    let image =
+        fs::read(firmware).chain_err(|| format!("Failed to read firmware {:?}", firmware))?;
+    let entry = setup_firmware(&image, sys_mem)?;
+    info!(
+        "Firmware of {:#x} bytes is loaded, entry {:#x}",
+        image.len(),
+        entry
+    );
+
+    Ok(entry)
+}
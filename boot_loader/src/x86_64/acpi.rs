@@ -0,0 +1,439 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use util::byte_code::ByteCode;
+use util::checksum::checksum;
+
+use super::mptable::{IOAPIC_BASE_ADDR, LAPIC_BASE_ADDR};
+
+/// Start of ACPI tables in guest memory, the kernel scans [0xe0000, 0x100000)
+/// for RSDP if the zero page doesn't point to it.
+pub const ACPI_TABLES_START: u64 = 0x000e_0000;
+/// IO port of PM1a event block, which contains PM1 status and enable registers.
+pub const PM1A_EVT_BLK: u16 = 0x600;
+/// IO port of PM1a control block.
+pub const PM1A_CNT_BLK: u16 = 0x604;
+/// Length of PM1 event block.
+pub const PM1_EVT_LEN: u8 = 4;
+/// Length of PM1 control block.
+pub const PM1_CNT_LEN: u8 = 2;
+/// Interrupt used by ACPI to notify guest of events, such as power button.
+pub const SCI_IRQ: u32 = 9;
+/// Interrupt flags of SCI: active high, level triggered.
+pub const SCI_INT_FLAGS: u16 = 0x000d;
+/// Value of `SLP_TYP` in PM1 control register which enters S5 (soft-off).
+pub const SLP_TYP_S5: u16 = 5;
+
+const OEM_ID: [u8; 6] = *b"STRATO";
+const OEM_TABLE_ID: [u8; 8] = *b"STRATOVM";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: [u8; 4] = *b"STRT";
+const CREATOR_REVISION: u32 = 1;
+const TABLE_ALIGN: usize = 16;
+
+// FADT fields, sourced from ACPI Specification 6.0, Table 5-34.
+const FADT_REVISION: u8 = 6;
+const FADT_LENGTH: usize = 276;
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_SCI_INT_OFFSET: usize = 46;
+const FADT_PM1A_EVT_BLK_OFFSET: usize = 56;
+const FADT_PM1A_CNT_BLK_OFFSET: usize = 64;
+const FADT_PM1_EVT_LEN_OFFSET: usize = 88;
+const FADT_PM1_CNT_LEN_OFFSET: usize = 89;
+const FADT_IAPC_BOOT_ARCH_OFFSET: usize = 109;
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_X_DSDT_OFFSET: usize = 140;
+// VGA is not present.
+const IAPC_BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+// WBINVD works, and no sleep button. The power button is the fixed-feature
+// one, whose status is reported in PM1 status register.
+const FADT_FLAGS: u32 = 1 | (1 << 5);
+
+const MADT_REVISION: u8 = 4;
+const MADT_PCAT_COMPAT: u32 = 1;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IOAPIC: u8 = 1;
+const MADT_INT_SOURCE_OVERRIDE: u8 = 2;
+const LAPIC_ENABLED: u32 = 1;
+
+const DSDT_REVISION: u8 = 2;
+const XSDT_REVISION: u8 = 1;
+const RSDP_REVISION: u8 = 2;
+
+/// Header shared by all system description tables.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct AcpiTableHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: [u8; 4],
+    creator_revision: u32,
+}
+
+impl ByteCode for AcpiTableHeader {}
+
+impl AcpiTableHeader {
+    fn new(signature: &[u8; 4], revision: u8) -> Self {
+        AcpiTableHeader {
+            signature: *signature,
+            length: 0,
+            revision,
+            checksum: 0,
+            oem_id: OEM_ID,
+            oem_table_id: OEM_TABLE_ID,
+            oem_revision: OEM_REVISION,
+            creator_id: CREATOR_ID,
+            creator_revision: CREATOR_REVISION,
+        }
+    }
+}
+
+/// Root System Description Pointer, refer to ACPI Specification 6.0, Table 5-27.
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+impl ByteCode for Rsdp {}
+
+// Only the first 20 bytes are covered by `checksum`, which is ACPI 1.0 RSDP.
+const RSDP_V1_LENGTH: usize = 20;
+
+impl Rsdp {
+    fn new(xsdt_address: u64) -> Self {
+        let mut rsdp = Rsdp {
+            signature: *b"RSD PTR ",
+            oem_id: OEM_ID,
+            revision: RSDP_REVISION,
+            length: std::mem::size_of::<Rsdp>() as u32,
+            xsdt_address,
+            ..Default::default()
+        };
+        rsdp.checksum = 0_u8.wrapping_sub(checksum(&rsdp.as_bytes()[..RSDP_V1_LENGTH]));
+        rsdp.extended_checksum = 0_u8.wrapping_sub(checksum(rsdp.as_bytes()));
+        rsdp
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MadtLocalApic {
+    type_: u8,
+    length: u8,
+    processor_id: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+impl ByteCode for MadtLocalApic {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MadtIoApic {
+    type_: u8,
+    length: u8,
+    ioapic_id: u8,
+    reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+
+impl ByteCode for MadtIoApic {}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MadtIntSourceOverride {
+    type_: u8,
+    length: u8,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+impl ByteCode for MadtIntSourceOverride {}
+
+impl MadtIntSourceOverride {
+    fn new(source: u8, gsi: u32, flags: u16) -> Self {
+        MadtIntSourceOverride {
+            type_: MADT_INT_SOURCE_OVERRIDE,
+            length: std::mem::size_of::<Self>() as u8,
+            bus: 0,
+            source,
+            gsi,
+            flags,
+        }
+    }
+}
+
+/// Fill `length` and `checksum` of the table, which starts with `AcpiTableHeader`.
+fn finish_table(table: &mut Vec<u8>) {
+    let length = table.len() as u32;
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    table[9] = 0;
+    table[9] = 0_u8.wrapping_sub(checksum(table));
+}
+
+fn build_madt(cpu_count: u8, max_cpus: u8) -> Vec<u8> {
+    let mut madt = AcpiTableHeader::new(b"APIC", MADT_REVISION)
+        .as_bytes()
+        .to_vec();
+    madt.extend_from_slice(&LAPIC_BASE_ADDR.to_le_bytes());
+    madt.extend_from_slice(&MADT_PCAT_COMPAT.to_le_bytes());
+
+    // Vcpus beyond `cpu_count` are offline at boot.
+    for cpu_id in 0..max_cpus {
+        let lapic = MadtLocalApic {
+            type_: MADT_LOCAL_APIC,
+            length: std::mem::size_of::<MadtLocalApic>() as u8,
+            processor_id: cpu_id,
+            apic_id: cpu_id,
+            flags: if cpu_id < cpu_count { LAPIC_ENABLED } else { 0 },
+        };
+        madt.extend_from_slice(lapic.as_bytes());
+    }
+
+    // Same as mptable, IOAPIC id follows the ids of LAPICs.
+    let ioapic = MadtIoApic {
+        type_: MADT_IOAPIC,
+        length: std::mem::size_of::<MadtIoApic>() as u8,
+        ioapic_id: max_cpus + 1,
+        reserved: 0,
+        address: IOAPIC_BASE_ADDR,
+        gsi_base: 0,
+    };
+    madt.extend_from_slice(ioapic.as_bytes());
+    // ISA IRQs are identity-mapped to IOAPIC pins by the default GSI routing
+    // of KVM, only SCI needs an override for its trigger mode.
+    madt.extend_from_slice(
+        MadtIntSourceOverride::new(SCI_IRQ as u8, SCI_IRQ, SCI_INT_FLAGS).as_bytes(),
+    );
+
+    finish_table(&mut madt);
+    madt
+}
+
+fn build_dsdt() -> Vec<u8> {
+    let mut dsdt = AcpiTableHeader::new(b"DSDT", DSDT_REVISION)
+        .as_bytes()
+        .to_vec();
+    // Name (_S5, Package (0x04) { SLP_TYP_S5, SLP_TYP_S5, Zero, Zero })
+    dsdt.extend_from_slice(&[
+        0x08, // NameOp
+        b'_',
+        b'S',
+        b'5',
+        b'_',
+        0x12, // PackageOp
+        0x08, // PkgLength
+        0x04, // NumElements
+        0x0a, // BytePrefix
+        SLP_TYP_S5 as u8,
+        0x0a, // BytePrefix
+        SLP_TYP_S5 as u8,
+        0x00, // ZeroOp
+        0x00, // ZeroOp
+    ]);
+
+    finish_table(&mut dsdt);
+    dsdt
+}
+
+fn build_fadt(dsdt_addr: u64) -> Vec<u8> {
+    let mut fadt = AcpiTableHeader::new(b"FACP", FADT_REVISION)
+        .as_bytes()
+        .to_vec();
+    fadt.resize(FADT_LENGTH, 0);
+
+    let mut set = |offset: usize, bytes: &[u8]| {
+        fadt[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    set(FADT_DSDT_OFFSET, &(dsdt_addr as u32).to_le_bytes());
+    set(FADT_X_DSDT_OFFSET, &dsdt_addr.to_le_bytes());
+    set(FADT_SCI_INT_OFFSET, &(SCI_IRQ as u16).to_le_bytes());
+    // `SMI_CMD` is zero, so the hardware is always in ACPI mode.
+    set(
+        FADT_PM1A_EVT_BLK_OFFSET,
+        &u32::from(PM1A_EVT_BLK).to_le_bytes(),
+    );
+    set(
+        FADT_PM1A_CNT_BLK_OFFSET,
+        &u32::from(PM1A_CNT_BLK).to_le_bytes(),
+    );
+    set(FADT_PM1_EVT_LEN_OFFSET, &[PM1_EVT_LEN]);
+    set(FADT_PM1_CNT_LEN_OFFSET, &[PM1_CNT_LEN]);
+    set(
+        FADT_IAPC_BOOT_ARCH_OFFSET,
+        &IAPC_BOOT_ARCH_VGA_NOT_PRESENT.to_le_bytes(),
+    );
+    set(FADT_FLAGS_OFFSET, &FADT_FLAGS.to_le_bytes());
+
+    finish_table(&mut fadt);
+    fadt
+}
+
+fn build_xsdt(entries: &[u64]) -> Vec<u8> {
+    let mut xsdt = AcpiTableHeader::new(b"XSDT", XSDT_REVISION)
+        .as_bytes()
+        .to_vec();
+    for entry in entries {
+        xsdt.extend_from_slice(&entry.to_le_bytes());
+    }
+
+    finish_table(&mut xsdt);
+    xsdt
+}
+
+/// Append `table` to `blob` at the next aligned offset, return the guest
+/// address of the table.
+fn append_table(blob: &mut Vec<u8>, base: u64, table: &[u8]) -> u64 {
+    let offset = (blob.len() + TABLE_ALIGN - 1) / TABLE_ALIGN * TABLE_ALIGN;
+    blob.resize(offset, 0);
+    blob.extend_from_slice(table);
+    base + offset as u64
+}
+
+/// Build ACPI tables: RSDP, XSDT, FADT, DSDT and MADT, which are placed one
+/// after another from `base`, and RSDP is at `base`.
+///
+/// # Arguments
+///
+/// * `base` - Guest address of the tables.
+/// * `cpu_count` - Number of vcpus online at boot.
+/// * `max_cpus` - Number of vcpus, including the offline ones.
+pub fn build_acpi_tables(base: u64, cpu_count: u8, max_cpus: u8) -> Vec<u8> {
+    // RSDP is filled after the address of XSDT is known.
+    let mut blob = vec![0_u8; std::mem::size_of::<Rsdp>()];
+
+    let dsdt_addr = append_table(&mut blob, base, &build_dsdt());
+    let fadt_addr = append_table(&mut blob, base, &build_fadt(dsdt_addr));
+    let madt_addr = append_table(&mut blob, base, &build_madt(cpu_count, max_cpus));
+    let xsdt_addr = append_table(&mut blob, base, &build_xsdt(&[fadt_addr, madt_addr]));
+
+    let rsdp = Rsdp::new(xsdt_addr);
+    blob[..std::mem::size_of::<Rsdp>()].copy_from_slice(rsdp.as_bytes());
+    blob
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table_at(blob: &[u8], offset: usize) -> &[u8] {
+        let mut length = [0_u8; 4];
+        length.copy_from_slice(&blob[offset + 4..offset + 8]);
+        &blob[offset..offset + u32::from_le_bytes(length) as usize]
+    }
+
+    #[test]
+    fn test_acpi_tables() {
+        let blob = build_acpi_tables(ACPI_TABLES_START, 2, 3);
+
+        // RSDP
+        assert_eq!(std::mem::size_of::<Rsdp>(), 36);
+        assert_eq!(
+            blob[..36].to_vec(),
+            vec![
+                0x52, 0x53, 0x44, 0x20, 0x50, 0x54, 0x52, 0x20, 0x02, 0x53, 0x54, 0x52, 0x41, 0x54,
+                0x4f, 0x02, 0x00, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0xf0, 0x01, 0x0e, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0xdd, 0x00, 0x00, 0x00
+            ]
+        );
+        assert_eq!(checksum(&blob[..20]), 0);
+        assert_eq!(checksum(&blob[..36]), 0);
+
+        // DSDT
+        let dsdt = table_at(&blob, 0x30);
+        assert_eq!(
+            dsdt.to_vec(),
+            vec![
+                0x44, 0x53, 0x44, 0x54, 0x32, 0x00, 0x00, 0x00, 0x02, 0x67, 0x53, 0x54, 0x52, 0x41,
+                0x54, 0x4f, 0x53, 0x54, 0x52, 0x41, 0x54, 0x4f, 0x56, 0x4d, 0x01, 0x00, 0x00, 0x00,
+                0x53, 0x54, 0x52, 0x54, 0x01, 0x00, 0x00, 0x00, 0x08, 0x5f, 0x53, 0x35, 0x5f, 0x12,
+                0x08, 0x04, 0x0a, 0x05, 0x0a, 0x05, 0x00, 0x00
+            ]
+        );
+
+        // FADT
+        let fadt = table_at(&blob, 0x70);
+        assert_eq!(&fadt[..4], b"FACP");
+        assert_eq!(fadt.len(), FADT_LENGTH);
+        assert_eq!(checksum(fadt), 0);
+        assert_eq!(
+            fadt[FADT_DSDT_OFFSET..FADT_DSDT_OFFSET + 4],
+            [0x30, 0, 0x0e, 0]
+        );
+        assert_eq!(fadt[FADT_SCI_INT_OFFSET..FADT_SCI_INT_OFFSET + 2], [9, 0]);
+        assert_eq!(
+            fadt[FADT_PM1A_EVT_BLK_OFFSET..FADT_PM1A_EVT_BLK_OFFSET + 4],
+            [0x00, 0x06, 0, 0]
+        );
+        assert_eq!(
+            fadt[FADT_PM1A_CNT_BLK_OFFSET..FADT_PM1A_CNT_BLK_OFFSET + 4],
+            [0x04, 0x06, 0, 0]
+        );
+
+        // MADT
+        let madt = table_at(&blob, 0x190);
+        assert_eq!(
+            madt.to_vec(),
+            vec![
+                0x41, 0x50, 0x49, 0x43, 0x5a, 0x00, 0x00, 0x00, 0x04, 0xe0, 0x53, 0x54, 0x52, 0x41,
+                0x54, 0x4f, 0x53, 0x54, 0x52, 0x41, 0x54, 0x4f, 0x56, 0x4d, 0x01, 0x00, 0x00, 0x00,
+                0x53, 0x54, 0x52, 0x54, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00,
+                0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x01, 0x01,
+                0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x0c,
+                0x04, 0x00, 0x00, 0x00, 0xc0, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a, 0x00, 0x09,
+                0x09, 0x00, 0x00, 0x00, 0x0d, 0x00
+            ]
+        );
+
+        // XSDT points to FADT and MADT
+        let xsdt = table_at(&blob, 0x1f0);
+        assert_eq!(&xsdt[..4], b"XSDT");
+        assert_eq!(checksum(xsdt), 0);
+        assert_eq!(
+            xsdt[36..].to_vec(),
+            [0x000e_0070_u64.to_le_bytes(), 0x000e_0190_u64.to_le_bytes()].concat()
+        );
+    }
+
+    #[test]
+    fn test_madt_possible_cpus() {
+        // 2 of 8 vcpus are online, the others are described as disabled.
+        let madt = build_madt(2, 8);
+        assert_eq!(checksum(&madt), 0);
+        let lapics = &madt[44..44 + 8 * 8];
+        for (cpu_id, lapic) in lapics.chunks(8).enumerate() {
+            let flags = if cpu_id < 2 { LAPIC_ENABLED } else { 0 };
+            let mut expected = vec![MADT_LOCAL_APIC, 8, cpu_id as u8, cpu_id as u8];
+            expected.extend_from_slice(&flags.to_le_bytes());
+            assert_eq!(lapic.to_vec(), expected);
+        }
+        // IOAPIC follows them with id 9.
+        assert_eq!(madt[44 + 8 * 8..44 + 8 * 8 + 3], [MADT_IOAPIC, 12, 9]);
+    }
+}
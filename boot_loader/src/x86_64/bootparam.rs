@@ -12,8 +12,29 @@
 
 use util::byte_code::ByteCode;
 
+use super::errors::{ErrorKind, Result};
+use super::{INITRD_ADDR_MAX, VMLINUX_RAM_START};
+use util::num_ops::round_up;
+
 pub const E820_RAM: u32 = 1;
 pub const E820_RESERVED: u32 = 2;
+const E820_MAX_ENTRIES: usize = 0x80;
+
+/// Offset of the setup header in bzImage and zero page.
+pub const KERNEL_HEADER_OFFSET: usize = 0x1f1;
+/// Offset of the 64-bit entry from the start of protected-mode kernel.
+pub const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
+
+const BOOT_FLAG: u16 = 0xaa55;
+const HDRS_MAGIC: u32 = 0x5372_6448; // "HdrS"
+const SECTOR_SIZE: u64 = 512;
+const DEFAULT_SETUP_SECTS: u64 = 4;
+// 64-bit boot protocol is introduced in version 2.12.
+const BOOT_PROTOCOL_64BIT: u16 = 0x020c;
+const XLF_KERNEL_64: u16 = 0x1;
+const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 0x2;
+const LOADFLAGS_CAN_USE_HEAP: u8 = 0x80;
+const UNDEFINED_LOADER: u8 = 0xff;
 
 // Structures below sourced from:
 // https://www.kernel.org/doc/html/latest/x86/boot.html
@@ -62,12 +83,15 @@ pub struct RealModeKernelHeader {
     kernel_info_offset: u32,
 }
 
+impl ByteCode for RealModeKernelHeader {}
+
 impl RealModeKernelHeader {
+    /// Synthesize a header for kernel images without setup header, such as vmlinux.bin.
     pub fn new(cmdline_ptr: u32, cmdline_size: u32, ramdisk_image: u32, ramdisk_size: u32) -> Self {
         RealModeKernelHeader {
-            boot_flag: 0xaa55,
-            header: 0x5372_6448,  // "HdrS"
-            type_of_loader: 0xff, // undefined identifier and version
+            boot_flag: BOOT_FLAG,
+            header: HDRS_MAGIC,
+            type_of_loader: UNDEFINED_LOADER,
             cmdline_ptr,
             cmdline_size,
             ramdisk_image,
@@ -75,6 +99,111 @@ impl RealModeKernelHeader {
             ..Default::default()
         }
     }
+
+    /// Parse the setup header from the start of a bzImage.
+    /// Return `None` if the image doesn't carry a setup header.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The beginning bytes of kernel image.
+    pub fn from_image(image: &[u8]) -> Option<Self> {
+        let end = KERNEL_HEADER_OFFSET + std::mem::size_of::<Self>();
+        let header = *Self::from_bytes(image.get(KERNEL_HEADER_OFFSET..end)?)?;
+        if header.boot_flag != BOOT_FLAG || header.header != HDRS_MAGIC {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// Check that the kernel can be booted with 64-bit boot protocol.
+    pub fn check_64bit_boot(&self) -> Result<()> {
+        if self.version < BOOT_PROTOCOL_64BIT {
+            return Err(ErrorKind::BootProtocolTooOld(self.version).into());
+        }
+        if self.xloadflags & XLF_KERNEL_64 == 0 {
+            return Err(ErrorKind::NoKernel64BitEntry.into());
+        }
+        Ok(())
+    }
+
+    /// Get the offset of protected-mode kernel in bzImage, which follows the setup sectors.
+    pub fn kernel_file_offset(&self) -> u64 {
+        let setup_sects = match self.setup_sects {
+            0 => DEFAULT_SETUP_SECTS,
+            n => u64::from(n),
+        };
+        (setup_sects + 1) * SECTOR_SIZE
+    }
+
+    /// Get the alignment the protected-mode kernel requires, `kernel_alignment`
+    /// is preferred and `min_alignment` is the fallback for relocatable kernel.
+    pub fn kernel_alignment(&self) -> u64 {
+        if self.kernel_alignment.is_power_of_two() {
+            u64::from(self.kernel_alignment)
+        } else if self.relocatable_kernel != 0 && self.min_alignment < 64 {
+            1 << self.min_alignment
+        } else {
+            1
+        }
+    }
+
+    /// Get the address where protected-mode kernel should be loaded,
+    /// a relocatable kernel prefers `pref_address` aligned to its alignment,
+    /// other kernels must be loaded at `code32_start`.
+    pub fn load_address(&self) -> Result<u64> {
+        if self.relocatable_kernel != 0 && self.pref_address != 0 {
+            round_up(self.pref_address, self.kernel_alignment()).ok_or_else(|| {
+                ErrorKind::KernelOverflow(self.pref_address, self.init_size()).into()
+            })
+        } else if self.code32_start != 0 {
+            Ok(u64::from(self.code32_start))
+        } else {
+            Ok(VMLINUX_RAM_START)
+        }
+    }
+
+    /// Get the highest address the initrd can occupy,
+    /// kernels before boot protocol 2.03 don't declare it.
+    pub fn initrd_addr_max(&self) -> u64 {
+        if self.initrd_addr_max == 0 {
+            INITRD_ADDR_MAX
+        } else {
+            u64::from(self.initrd_addr_max)
+        }
+    }
+
+    /// Check if kernel, boot_params, cmdline and initrd can be placed above 4G,
+    /// which requires the `ext_*` fields of zero page.
+    pub fn can_be_loaded_above_4g(&self) -> bool {
+        self.xloadflags & XLF_CAN_BE_LOADED_ABOVE_4G != 0
+    }
+
+    /// Get the size of memory the kernel needs from its load address during boot.
+    pub fn init_size(&self) -> u64 {
+        u64::from(self.init_size)
+    }
+
+    /// Get the max length of kernel cmdline declared by the kernel, without
+    /// the terminating zero.
+    pub fn cmdline_size(&self) -> u32 {
+        self.cmdline_size
+    }
+
+    /// Fill the fields owned by boot loader, the rest are kept as the kernel declares.
+    ///
+    /// # Arguments
+    ///
+    /// * `cmdline_ptr` - Address of kernel cmdline.
+    /// * `ramdisk_image` - Address of initrd.
+    /// * `ramdisk_size` - Size of initrd.
+    pub fn set_loader_fields(&mut self, cmdline_ptr: u32, ramdisk_image: u32, ramdisk_size: u32) {
+        self.cmdline_ptr = cmdline_ptr;
+        self.ramdisk_image = ramdisk_image;
+        self.ramdisk_size = ramdisk_size;
+        self.type_of_loader = UNDEFINED_LOADER;
+        // `heap_end_ptr` is not set up, the kernel should not use the heap.
+        self.loadflags &= !LOADFLAGS_CAN_USE_HEAP;
+    }
 }
 
 #[repr(C, packed)]
@@ -93,7 +222,8 @@ pub struct BootParams {
     pad1: u32,
     tboot_addr: [u8; 0x8],
     ist_info: [u8; 0x10],
-    pad2: [u8; 0x10],
+    acpi_rsdp_addr: u64,
+    pad2: [u8; 0x8],
     hd0_info: [u8; 0x10],
     hd1_info: [u8; 0x10],
     sys_desc_table: [u8; 0x10],
@@ -117,7 +247,7 @@ pub struct BootParams {
     kernel_header: RealModeKernelHeader, // offset: 0x1f1
     pad6: [u8; 0x24],
     edd_mbr_sig_buffer: [u8; 0x40],
-    e820_table: [E820Entry; 0x80],
+    e820_table: [E820Entry; E820_MAX_ENTRIES],
     pad8: [u8; 0x30],
     eddbuf: [u8; 0x1ec],
 }
@@ -138,9 +268,47 @@ impl BootParams {
         }
     }
 
-    pub fn add_e820_entry(&mut self, addr: u64, size: u64, type_: u32) {
-        self.e820_table[self.e820_entries as usize] = E820Entry { addr, size, type_ };
+    /// Append an entry to E820 table.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the table is full.
+    pub fn add_e820_entry(&mut self, addr: u64, size: u64, type_: u32) -> Result<()> {
+        let index = self.e820_entries as usize;
+        if index >= E820_MAX_ENTRIES {
+            bail!(
+                "E820 table is full, entry [{:#x}, {:#x}) is dropped",
+                addr,
+                addr + size
+            );
+        }
+        self.e820_table[index] = E820Entry { addr, size, type_ };
         self.e820_entries += 1;
+        Ok(())
+    }
+
+    /// Fill the upper 32 bits of the addresses and size filled by boot loader,
+    /// the lower 32 bits are in the setup header.
+    ///
+    /// # Arguments
+    ///
+    /// * `cmdline_ptr` - Address of kernel cmdline.
+    /// * `ramdisk_image` - Address of initrd.
+    /// * `ramdisk_size` - Size of initrd.
+    pub fn set_ext_loader_fields(
+        &mut self,
+        cmdline_ptr: u64,
+        ramdisk_image: u64,
+        ramdisk_size: u64,
+    ) {
+        self.ext_cmd_line_ptr = (cmdline_ptr >> 32) as u32;
+        self.ext_ramdisk_image = (ramdisk_image >> 32) as u32;
+        self.ext_ramdisk_size = (ramdisk_size >> 32) as u32;
+    }
+
+    /// Tell kernel the address of ACPI RSDP, it's honored since boot protocol 2.14.
+    pub fn set_acpi_rsdp_addr(&mut self, addr: u64) {
+        self.acpi_rsdp_addr = addr;
     }
 }
 
@@ -149,17 +317,111 @@ mod test {
     use std::path::PathBuf;
     use std::sync::Arc;
 
-    use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+    use address_space::{AddressSpace, GuestAddress, HostMemMapping, MemAdvice, Region};
 
-    use super::super::{setup_boot_params, X86BootLoaderConfig};
+    use super::super::{
+        setup_boot_params, X86BootLoaderConfig, ACPI_TABLES_START, CMDLINE_START, EBDA_START,
+        VGA_RAM_BEGIN, ZERO_PAGE_START,
+    };
     use super::*;
 
+    fn build_bzimage_header(version: u16, xloadflags: u16) -> Vec<u8> {
+        let mut image = vec![0_u8; 0x1000];
+        let header = RealModeKernelHeader {
+            setup_sects: 0x1b,
+            boot_flag: BOOT_FLAG,
+            header: HDRS_MAGIC,
+            version,
+            loadflags: 0x81,
+            code32_start: 0x10_0000,
+            cmdline_size: 0x7ff,
+            relocatable_kernel: 1,
+            xloadflags,
+            pref_address: 0x100_0000,
+            init_size: 0x200_0000,
+            ..Default::default()
+        };
+        let end = KERNEL_HEADER_OFFSET + std::mem::size_of::<RealModeKernelHeader>();
+        image[KERNEL_HEADER_OFFSET..end].copy_from_slice(header.as_bytes());
+        image
+    }
+
+    #[test]
+    fn test_kernel_header_constraints() {
+        let image = build_bzimage_header(0x020f, 0x1);
+        let mut header = RealModeKernelHeader::from_image(&image).unwrap();
+        assert_eq!(header.initrd_addr_max(), INITRD_ADDR_MAX);
+        header.initrd_addr_max = 0x7fff_ffff;
+        assert_eq!(header.initrd_addr_max(), 0x7fff_ffff);
+
+        // pref_address is aligned up to kernel_alignment
+        header.pref_address = 0x100_1000;
+        header.kernel_alignment = 0x20_0000;
+        assert_eq!(header.kernel_alignment(), 0x20_0000);
+        assert_eq!(header.load_address().unwrap(), 0x120_0000);
+
+        // invalid kernel_alignment falls back to min_alignment
+        header.kernel_alignment = 0x30_0000;
+        header.min_alignment = 13;
+        assert_eq!(header.kernel_alignment(), 0x2000);
+        assert_eq!(header.load_address().unwrap(), 0x100_2000);
+
+        // non-relocatable kernel is never moved
+        header.relocatable_kernel = 0;
+        assert_eq!(header.kernel_alignment(), 1);
+        assert_eq!(header.load_address().unwrap(), 0x10_0000);
+    }
+
+    #[test]
+    fn test_parse_kernel_header() {
+        let image = build_bzimage_header(0x020f, 0x7f);
+        let mut header = RealModeKernelHeader::from_image(&image).unwrap();
+        assert!(header.check_64bit_boot().is_ok());
+        assert_eq!(header.kernel_file_offset(), 0x1c * 512);
+        assert_eq!(header.load_address().unwrap(), 0x100_0000);
+        assert_eq!(header.init_size(), 0x200_0000);
+        assert_eq!(header.cmdline_size(), 0x7ff);
+
+        header.set_loader_fields(0x2_0000, 0x3000_0000, 0x1000);
+        assert_eq!({ header.cmdline_ptr }, 0x2_0000);
+        assert_eq!({ header.ramdisk_image }, 0x3000_0000);
+        assert_eq!({ header.ramdisk_size }, 0x1000);
+        assert_eq!(header.type_of_loader, UNDEFINED_LOADER);
+        assert_eq!(header.loadflags, 0x01);
+        // fields declared by kernel are kept
+        assert_eq!({ header.pref_address }, 0x100_0000);
+        assert_eq!({ header.xloadflags }, 0x7f);
+        assert!(header.can_be_loaded_above_4g());
+
+        // setup_sects 0 means 4, non-relocatable kernel is loaded at code32_start
+        header.setup_sects = 0;
+        header.relocatable_kernel = 0;
+        assert_eq!(header.kernel_file_offset(), 5 * 512);
+        assert_eq!(header.load_address().unwrap(), 0x10_0000);
+
+        // old boot protocol or kernel without 64-bit entry
+        let header = RealModeKernelHeader::from_image(&build_bzimage_header(0x020b, 0x1)).unwrap();
+        assert!(header.check_64bit_boot().is_err());
+        let header = RealModeKernelHeader::from_image(&build_bzimage_header(0x020f, 0x2)).unwrap();
+        assert!(header.check_64bit_boot().is_err());
+
+        // raw kernel images or truncated images have no setup header
+        let mut image = build_bzimage_header(0x020f, 0x7f);
+        image[0x1fe] = 0;
+        assert!(RealModeKernelHeader::from_image(&image).is_none());
+        let image = build_bzimage_header(0x020f, 0x7f);
+        assert!(RealModeKernelHeader::from_image(&image[..0x200]).is_none());
+        assert!(RealModeKernelHeader::from_image(&[0_u8; 0x1000]).is_none());
+    }
+
     #[test]
     fn test_boot_param() {
         // test setup_boot_params function
         let root = Region::init_container_region(0x2000_0000);
         let space = AddressSpace::new(root.clone()).unwrap();
-        let ram1 = Arc::new(HostMemMapping::new(GuestAddress(0), 0x1000_0000, false).unwrap());
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), 0x1000_0000, MemAdvice::default()).unwrap(),
+        );
         let region_a = Region::init_ram_region(ram1.clone());
         root.add_subregion(region_a, ram1.start_address().raw_value())
             .unwrap();
@@ -167,33 +429,109 @@ mod test {
         let config = X86BootLoaderConfig {
             kernel: PathBuf::new(),
             initrd: Some(PathBuf::new()),
-            initrd_size: 0x1_0000,
             kernel_cmdline: String::from("this_is_a_piece_of_test_string"),
             cpu_count: 2,
+            max_cpus: 2,
         };
-        let (_, initrd_addr_tmp) = setup_boot_params(&config, &space).unwrap();
+        let (_, initrd_addr_tmp) = setup_boot_params(&config, 0x1_0000, &space, None).unwrap();
         assert_eq!(initrd_addr_tmp, 0xfff_0000);
         let test_zero_page = space
-            .read_object::<BootParams>(GuestAddress(0x0000_7000))
+            .read_object::<BootParams>(GuestAddress(ZERO_PAGE_START))
             .unwrap();
-        assert_eq!(test_zero_page.e820_entries, 4);
-
-        unsafe {
-            assert_eq!(test_zero_page.e820_table[0].addr, 0);
-            assert_eq!(test_zero_page.e820_table[0].size, 0x0009_FC00);
-            assert_eq!(test_zero_page.e820_table[0].type_, 1);
+        assert_eq!({ test_zero_page.acpi_rsdp_addr }, ACPI_TABLES_START);
 
-            assert_eq!(test_zero_page.e820_table[1].addr, 0x0009_FC00);
-            assert_eq!(test_zero_page.e820_table[1].size, 0x400);
-            assert_eq!(test_zero_page.e820_table[1].type_, 2);
+        let entries: Vec<(u64, u64, u32)> = test_zero_page.e820_table
+            [..test_zero_page.e820_entries as usize]
+            .iter()
+            .map(|e| (e.addr, e.size, e.type_))
+            .collect();
+        // entries are sorted and not overlapped
+        for pair in entries.windows(2) {
+            assert!(pair[0].0 + pair[0].1 <= pair[1].0);
+        }
+        // ram entries are inside the ram ranges of address space
+        let ram_ranges = space.memory_ranges();
+        for (addr, size, _) in entries.iter().filter(|e| e.2 == E820_RAM) {
+            assert!(ram_ranges
+                .iter()
+                .any(|r| r.base.raw_value() <= *addr && addr + size <= r.end_addr().raw_value()));
+        }
+        // all ram is reported except the legacy VGA hole
+        let reported: u64 = entries.iter().map(|e| e.1).sum();
+        let total: u64 = ram_ranges.iter().map(|r| r.size).sum();
+        assert_eq!(reported, total - (ACPI_TABLES_START - VGA_RAM_BEGIN));
+        // areas used by boot loader are reserved
+        for (addr, size) in [
+            (ZERO_PAGE_START, 0x1000),
+            (CMDLINE_START, 0x1000),
+            (EBDA_START, VGA_RAM_BEGIN - EBDA_START),
+            (ACPI_TABLES_START, VMLINUX_RAM_START - ACPI_TABLES_START),
+        ]
+        .iter()
+        {
+            assert!(entries.contains(&(*addr, *size, E820_RESERVED)));
+        }
+        assert_eq!(entries.len(), 8);
 
-            assert_eq!(test_zero_page.e820_table[2].addr, 0x000F_0000);
-            assert_eq!(test_zero_page.e820_table[2].size, 0);
-            assert_eq!(test_zero_page.e820_table[2].type_, 2);
+        // E820 table can't overflow
+        let mut boot_params = BootParams::default();
+        for i in 0..E820_MAX_ENTRIES as u64 {
+            assert!(boot_params
+                .add_e820_entry(i << 12, 0x1000, E820_RAM)
+                .is_ok());
+        }
+        assert!(boot_params
+            .add_e820_entry(0x100_0000, 0x1000, E820_RAM)
+            .is_err());
+    }
 
-            assert_eq!(test_zero_page.e820_table[3].addr, 0x0010_0000);
-            assert_eq!(test_zero_page.e820_table[3].size, 0x0ff0_0000);
-            assert_eq!(test_zero_page.e820_table[3].type_, 1);
+    #[test]
+    fn test_boot_param_high_initrd() {
+        // 256M low ram and 256M high ram above 4G
+        let root = Region::init_container_region(0x2_0000_0000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        for base in [0, 0x1_0000_0000].iter() {
+            let ram = Arc::new(
+                HostMemMapping::new(GuestAddress(*base), 0x1000_0000, MemAdvice::default())
+                    .unwrap(),
+            );
+            root.add_subregion(Region::init_ram_region(ram), *base)
+                .unwrap();
         }
+
+        // initrd doesn't fit below the kernel, which occupies [16M, 48M)
+        let config = X86BootLoaderConfig {
+            kernel: PathBuf::new(),
+            initrd: Some(PathBuf::new()),
+            kernel_cmdline: String::from("console=ttyS0"),
+            cpu_count: 1,
+            max_cpus: 1,
+        };
+        let header = RealModeKernelHeader::from_image(&build_bzimage_header(0x020f, 0x3)).unwrap();
+        let (_, initrd_addr) =
+            setup_boot_params(&config, 0xf00_0000, &space, Some(header)).unwrap();
+        assert_eq!(initrd_addr, 0x1_0100_0000);
+        let zero_page = space
+            .read_object::<BootParams>(GuestAddress(0x0000_7000))
+            .unwrap();
+        assert_eq!({ zero_page.kernel_header.ramdisk_image }, 0x0100_0000);
+        assert_eq!({ zero_page.kernel_header.ramdisk_size }, 0xf00_0000);
+        assert_eq!({ zero_page.kernel_header.cmdline_ptr }, 0x2_0000);
+        assert_eq!({ zero_page.ext_ramdisk_image }, 0x1);
+        assert_eq!({ zero_page.ext_ramdisk_size }, 0);
+        assert_eq!({ zero_page.ext_cmd_line_ptr }, 0);
+
+        // kernel without XLF_CAN_BE_LOADED_ABOVE_4G can't use high memory
+        let header = RealModeKernelHeader::from_image(&build_bzimage_header(0x020f, 0x1)).unwrap();
+        assert!(setup_boot_params(&config, 0xf00_0000, &space, Some(header)).is_err());
+
+        // initrd fitting in low memory is still placed below initrd_addr_max
+        let header = RealModeKernelHeader::from_image(&build_bzimage_header(0x020f, 0x3)).unwrap();
+        let (_, initrd_addr) = setup_boot_params(&config, 0x1_0000, &space, Some(header)).unwrap();
+        assert_eq!(initrd_addr, 0xfff_0000);
+        let zero_page = space
+            .read_object::<BootParams>(GuestAddress(0x0000_7000))
+            .unwrap();
+        assert_eq!({ zero_page.ext_ramdisk_image }, 0);
     }
 }
@@ -0,0 +1,297 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use util::byte_code::ByteCode;
+
+use super::errors::{ErrorKind, Result, ResultExt};
+use crate::ImageSegment;
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+
+// Structures below sourced from:
+// https://man7.org/linux/man-pages/man5/elf.5.html
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Elf64Header {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+impl ByteCode for Elf64Header {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Elf64ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+impl ByteCode for Elf64ProgramHeader {}
+
+/// Check if the image starts with ELF magic.
+///
+/// # Arguments
+///
+/// * `image` - The beginning bytes of kernel image.
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(&ELF_MAGIC)
+}
+
+/// Parse an ELF vmlinux, return its entry address and the `PT_LOAD` segments,
+/// which are loaded at their physical addresses.
+///
+/// # Arguments
+///
+/// * `image` - The ELF kernel image.
+///
+/// # Errors
+///
+/// Return Error if the image is not a 64-bit little-endian x86_64 ELF, or fail
+/// to read its headers.
+pub fn parse_elf<R: Read + Seek>(image: &mut R) -> Result<(u64, Vec<ImageSegment>)> {
+    let mut ehdr = Elf64Header::default();
+    image
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| image.read_exact(ehdr.as_mut_bytes()))
+        .chain_err(|| "Failed to read ELF header")?;
+
+    if !is_elf(&ehdr.e_ident) {
+        return Err(ErrorKind::InvalidElf("bad magic".to_string()).into());
+    }
+    if ehdr.e_ident[EI_CLASS] != ELFCLASS64 {
+        return Err(ErrorKind::InvalidElf("only 64-bit ELF is supported".to_string()).into());
+    }
+    if ehdr.e_ident[EI_DATA] != ELFDATA2LSB {
+        return Err(ErrorKind::InvalidElf("not little-endian".to_string()).into());
+    }
+    if ehdr.e_machine != EM_X86_64 {
+        return Err(
+            ErrorKind::InvalidElf(format!("unsupported machine {}", ehdr.e_machine)).into(),
+        );
+    }
+    if usize::from(ehdr.e_phentsize) != std::mem::size_of::<Elf64ProgramHeader>() {
+        return Err(ErrorKind::InvalidElf(format!(
+            "unexpected program header size {}",
+            ehdr.e_phentsize
+        ))
+        .into());
+    }
+
+    image
+        .seek(SeekFrom::Start(ehdr.e_phoff))
+        .chain_err(|| "Failed to seek ELF program headers")?;
+    let mut segments = Vec::new();
+    for _ in 0..ehdr.e_phnum {
+        let mut phdr = Elf64ProgramHeader::default();
+        image
+            .read_exact(phdr.as_mut_bytes())
+            .chain_err(|| "Failed to read ELF program header")?;
+        if phdr.p_type != PT_LOAD || phdr.p_memsz == 0 {
+            continue;
+        }
+        if phdr.p_filesz > phdr.p_memsz {
+            return Err(ErrorKind::InvalidElf(format!(
+                "segment at 0x{:x} has file size larger than memory size",
+                phdr.p_paddr
+            ))
+            .into());
+        }
+        segments.push(ImageSegment {
+            file_offset: phdr.p_offset,
+            file_size: phdr.p_filesz,
+            mem_size: phdr.p_memsz,
+            guest_addr: phdr.p_paddr,
+        });
+    }
+
+    Ok((ehdr.e_entry, segments))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Write};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use address_space::{AddressSpace, GuestAddress, HostMemMapping, MemAdvice, Region};
+    use flate2::write::GzEncoder;
+
+    use super::*;
+    use crate::image::Image;
+    use crate::load_segments;
+
+    /// Build an ELF image with a text segment at 0x100_0000 and a data segment
+    /// with bss at 0x120_0000.
+    fn build_elf(class: u8) -> Vec<u8> {
+        let ehdr_size = std::mem::size_of::<Elf64Header>();
+        let phdr_size = std::mem::size_of::<Elf64ProgramHeader>();
+        let mut ident = [0_u8; 16];
+        ident[..4].copy_from_slice(&ELF_MAGIC);
+        ident[EI_CLASS] = class;
+        ident[EI_DATA] = ELFDATA2LSB;
+        let ehdr = Elf64Header {
+            e_ident: ident,
+            e_type: 2,
+            e_machine: EM_X86_64,
+            e_version: 1,
+            e_entry: 0x100_0000,
+            e_phoff: ehdr_size as u64,
+            e_ehsize: ehdr_size as u16,
+            e_phentsize: phdr_size as u16,
+            e_phnum: 3,
+            ..Default::default()
+        };
+        let text = Elf64ProgramHeader {
+            p_type: PT_LOAD,
+            p_offset: 0x1000,
+            p_vaddr: 0xffff_ffff_8100_0000,
+            p_paddr: 0x100_0000,
+            p_filesz: 0x10,
+            p_memsz: 0x10,
+            ..Default::default()
+        };
+        let data = Elf64ProgramHeader {
+            p_type: PT_LOAD,
+            p_offset: 0x1010,
+            p_vaddr: 0xffff_ffff_8120_0000,
+            p_paddr: 0x120_0000,
+            p_filesz: 0x8,
+            p_memsz: 0x20,
+            ..Default::default()
+        };
+        // PT_NOTE segment is skipped.
+        let note = Elf64ProgramHeader {
+            p_type: 4,
+            p_offset: 0x1000,
+            p_filesz: 0x10,
+            p_memsz: 0x10,
+            ..Default::default()
+        };
+
+        let mut image = vec![0_u8; 0x1018];
+        image[..ehdr_size].copy_from_slice(ehdr.as_bytes());
+        let mut offset = ehdr_size;
+        for phdr in [text, note, data].iter() {
+            image[offset..offset + phdr_size].copy_from_slice(phdr.as_bytes());
+            offset += phdr_size;
+        }
+        for (i, byte) in image[0x1000..0x1018].iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        image
+    }
+
+    #[test]
+    fn test_parse_elf() {
+        let image = build_elf(ELFCLASS64);
+        assert!(is_elf(&image));
+        let (entry, segments) = parse_elf(&mut Cursor::new(&image)).unwrap();
+        assert_eq!(entry, 0x100_0000);
+        assert_eq!(
+            segments,
+            vec![
+                ImageSegment {
+                    file_offset: 0x1000,
+                    file_size: 0x10,
+                    mem_size: 0x10,
+                    guest_addr: 0x100_0000,
+                },
+                ImageSegment {
+                    file_offset: 0x1010,
+                    file_size: 0x8,
+                    mem_size: 0x20,
+                    guest_addr: 0x120_0000,
+                },
+            ]
+        );
+
+        // 32-bit ELF is rejected
+        let image = build_elf(1);
+        assert!(is_elf(&image));
+        assert!(parse_elf(&mut Cursor::new(&image)).is_err());
+
+        // truncated ELF
+        let image = build_elf(ELFCLASS64);
+        assert!(parse_elf(&mut Cursor::new(&image[..0x50])).is_err());
+    }
+
+    #[test]
+    fn test_parse_compressed_elf() {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&build_elf(ELFCLASS64)).unwrap();
+        let path = PathBuf::from("/tmp/test_parse_compressed_elf.gz");
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let mut image = Image::open(&path).unwrap();
+        assert_eq!(image.size(), 0x1018);
+        let (entry, segments) = parse_elf(&mut image).unwrap();
+        assert_eq!(entry, 0x100_0000);
+        assert_eq!(segments.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_elf_segments() {
+        let root = Region::init_container_region(0x2000_0000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(GuestAddress(0), 0x1000_0000, MemAdvice::default()).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+        // stale data in the bss should be cleared
+        space
+            .write_object(&u64::max_value(), GuestAddress(0x120_0010))
+            .unwrap();
+
+        let image = build_elf(ELFCLASS64);
+        let mut cursor = Cursor::new(&image);
+        let (_, segments) = parse_elf(&mut cursor).unwrap();
+        load_segments(&mut cursor, &segments, &space).unwrap();
+
+        let mut text = [0_u8; 0x10];
+        space
+            .read(&mut text.as_mut(), GuestAddress(0x100_0000), 0x10)
+            .unwrap();
+        assert_eq!(text.to_vec(), (1..=0x10).collect::<Vec<u8>>());
+        let data = space.read_object::<u64>(GuestAddress(0x120_0000)).unwrap();
+        assert_eq!(data, u64::from_le_bytes([17, 18, 19, 20, 21, 22, 23, 24]));
+        let bss = space.read_object::<u64>(GuestAddress(0x120_0010)).unwrap();
+        assert_eq!(bss, 0);
+    }
+}
@@ -41,6 +41,9 @@
 //!   0x000a_0000   +------------------------+
 //!                 |  VGA_RAM               |
 //!                 |                        |
+//!   0x000e_0000   +------------------------+
+//!                 |  ACPI tables           |
+//!                 |                        |
 //!   0x000f_0000   +------------------------+
 //!                 |  MB_BIOS               |
 //!                 |                        |
@@ -51,31 +54,39 @@
 //!                 |  Initrd Ram            |
 //!   0x****_****   +------------------------+
 //! ```
-
-const REAL_MODE_IVT_BEGIN: u64 = 0x0000_0000;
+//!
+//! Firmware given by `-bios` is mapped read-only at the top of 4G, which
+//! covers the reset vector `0xffff_fff0`, and its last 64K at most is also
+//! mapped to `MB_BIOS`.
 
 extern crate address_space;
 
+pub mod acpi;
 mod bootparam;
+mod elf;
 mod gdt;
 mod mptable;
 
+use std::io::{Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::string::String;
 use std::sync::Arc;
 
 use kvm_bindings::kvm_segment;
 
+use self::acpi::{build_acpi_tables, ACPI_TABLES_START};
 use self::errors::{ErrorKind, Result, ResultExt};
-use address_space::{AddressSpace, GuestAddress};
-use bootparam::{BootParams, RealModeKernelHeader, E820_RAM, E820_RESERVED};
-use gdt::GdtEntry;
-use mptable::{
-    BusEntry, ConfigTableHeader, FloatingPointer, IOApicEntry, IOInterruptEntry,
-    LocalInterruptEntry, ProcessEntry, DEST_ALL_LAPIC_MASK, INTERRUPT_TYPE_EXTINT,
-    INTERRUPT_TYPE_INT, INTERRUPT_TYPE_NMI, IOAPIC_BASE_ADDR, LAPIC_BASE_ADDR,
+use crate::image::Image;
+use crate::ImageSegment;
+use address_space::{AddressRange, AddressSpace, GuestAddress};
+use bootparam::{
+    BootParams, RealModeKernelHeader, E820_RAM, E820_RESERVED, KERNEL_64BIT_ENTRY_OFFSET,
+    KERNEL_HEADER_OFFSET,
 };
-use util::checksum::obj_checksum;
+use elf::{is_elf, parse_elf};
+use gdt::GdtEntry;
+use mptable::{build_mptable, mptable_size};
+use util::num_ops::{round_down, round_up};
 
 pub mod errors {
     error_chain! {
@@ -86,6 +97,30 @@ pub mod errors {
             MaxCpus(cpus: u8) {
                 display("Configure cpu number({}) above supported max cpu numbers(254)", cpus)
             }
+            BootProtocolTooOld(version: u16) {
+                display("Boot protocol version {:#x} of bzImage is lower than 2.12", version)
+            }
+            NoKernel64BitEntry {
+                display("bzImage doesn't have 64-bit entry")
+            }
+            KernelOverflow(addr: u64, size: u64) {
+                display("Kernel needs memory [{:#x}, {:#x}) out of guest ram", addr, addr + size)
+            }
+            InvalidElf(reason: String) {
+                display("Invalid ELF kernel image: {}", reason)
+            }
+            InvalidFirmware(reason: String) {
+                display("Invalid firmware image: {}", reason)
+            }
+            EntryOutOfRam(addr: u64) {
+                display("Kernel entry {:#x} is out of guest ram", addr)
+            }
+            InitrdOverflow(size: u64, max: u64) {
+                display("No room for initrd of {:#x} bytes in guest ram below {:#x}", size, max)
+            }
+            CmdlineOverflow(len: u64, max: u64) {
+                display("Kernel cmdline of {} bytes exceeds the {} bytes allowed", len, max)
+            }
         }
     }
 }
@@ -95,6 +130,9 @@ const PML4_START: u64 = 0x0000_9000;
 const PDPTE_START: u64 = 0x0000_a000;
 const PDE_START: u64 = 0x0000_b000;
 const CMDLINE_START: u64 = 0x0002_0000;
+/// Max length of kernel cmdline without the terminating zero for kernels which
+/// don't declare it, the buffer of legacy kernels is 2048 bytes.
+const CMDLINE_MAX_SIZE_LEGACY: u64 = 2047;
 
 const EBDA_START: u64 = 0x0009_fc00;
 const VGA_RAM_BEGIN: u64 = 0x000a_0000;
@@ -104,6 +142,14 @@ const KVM_32BIT_GAP_SIZE: u64 = 0x0300 << 20; /* 768MB */
 const KVM_32BIT_MAX_MEM_SIZE: u64 = 1 << 32; /* 4GB */
 const KVM_32BIT_GAP_START: u64 = KVM_32BIT_MAX_MEM_SIZE - KVM_32BIT_GAP_SIZE;
 const INITRD_ADDR_MAX: u64 = 0x37ff_ffff;
+const INITRD_ALIGN: u64 = 0x1000;
+const PAGE_SIZE: u64 = 0x1000;
+const FIRMWARE_MAX_SIZE: u64 = 8 << 20;
+/// Max size of the firmware tail which is aliased below 1M.
+const BIOS_ALIAS_MAX_SIZE: u64 = 0x1_0000;
+/// Address of the first instruction executed by BSP after reset, which is
+/// `0xfff0` of the code segment based at `0xffff_0000`.
+pub const RESET_VECTOR: u64 = 0xffff_fff0;
 
 const VMLINUX_STARTUP: u64 = 0x0100_0000;
 const BOOT_LOADER_SP: u64 = 0x0000_8ff0;
@@ -121,19 +167,25 @@ pub struct X86BootLoaderConfig {
     pub kernel: PathBuf,
     /// Path of the initrd image.
     pub initrd: Option<PathBuf>,
-    /// Initrd image size.
-    pub initrd_size: u32,
     /// Kernel cmdline parameters.
     pub kernel_cmdline: String,
     /// VM's CPU count.
     pub cpu_count: u8,
+    /// Max CPU count of VM, the CPUs beyond `cpu_count` are offline at boot.
+    pub max_cpus: u8,
 }
 
 /// The start address for some boot source in guest memory for `x86_64`.
 pub struct X86BootLoader {
+    /// Entry address of kernel.
     pub kernel_start: u64,
+    /// Segments of kernel image file to load, the real-mode setup code of
+    /// bzImage is skipped.
+    pub kernel_segments: Vec<ImageSegment>,
     pub kernel_sp: u64,
     pub initrd_start: u64,
+    /// Size of initrd in guest memory, which is decompressed if compressed.
+    pub initrd_size: u64,
     pub boot_pml4_addr: u64,
     pub zero_page_addr: u64,
     pub segments: BootGdtSegment,
@@ -167,7 +219,7 @@ fn setup_page_table(sys_mem: &Arc<AddressSpace>) -> Result<u64> {
     let pde = boot_pde_addr | 0x03;
     sys_mem
         .write_object(&pde, GuestAddress(boot_pdpte_addr))
-        .unwrap();
+        .chain_err(|| format!("Failed to load PDPTE to 0x{:x}", boot_pdpte_addr))?;
 
     // 512 2MB entries together covering VA [0..1GB). Note we are assuming
     // CPU supports 2MB pages (/proc/cpuinfo has 'pse'). All modern CPUs do.
@@ -181,150 +233,200 @@ fn setup_page_table(sys_mem: &Arc<AddressSpace>) -> Result<u64> {
     Ok(boot_pml4_addr)
 }
 
-macro_rules! write_entry {
-    ( $d:expr, $t:ty, $m:expr, $o:expr, $s:expr ) => {
-        let entry = $d;
-        $m.write_object(&entry, GuestAddress($o))?;
-        $o += std::mem::size_of::<$t>() as u64;
-        $s = $s.wrapping_add(obj_checksum(&entry));
-    };
-}
-
-fn setup_isa_mptable(sys_mem: &Arc<AddressSpace>, start_addr: u64, num_cpus: u8) -> Result<()> {
-    const BUS_ID: u8 = 0;
-    const MPTABLE_MAX_CPUS: u32 = 254; // mptable max support 255 cpus, reserve one for ioapic id
-    const MPTABLE_IOAPIC_NR: u8 = 16;
-
-    if u32::from(num_cpus) > MPTABLE_MAX_CPUS {
-        return Err(ErrorKind::MaxCpus(num_cpus).into());
+// mptable max support 255 cpus, reserve one for ioapic id
+const MPTABLE_MAX_CPUS: u32 = 254;
+
+/// Write MP floating pointer and MP configuration table to `start_addr`.
+///
+/// # Arguments
+///
+/// * `sys_mem` - Guest memory.
+/// * `start_addr` - Guest address where the MP floating pointer is placed.
+/// * `cpu_count` - Number of vcpus online at boot.
+/// * `max_cpus` - Number of vcpus, including the offline ones.
+fn setup_isa_mptable(
+    sys_mem: &Arc<AddressSpace>,
+    start_addr: u64,
+    cpu_count: u8,
+    max_cpus: u8,
+) -> Result<()> {
+    if u32::from(max_cpus) > MPTABLE_MAX_CPUS {
+        return Err(ErrorKind::MaxCpus(max_cpus).into());
     }
 
-    let ioapic_id: u8 = num_cpus + 1;
-    let header = start_addr + std::mem::size_of::<FloatingPointer>() as u64;
-    sys_mem.write_object(
-        &FloatingPointer::new(header as u32),
-        GuestAddress(start_addr),
-    )?;
+    // Same as MADT, IOAPIC id follows the ids of all LAPICs.
+    let table = build_mptable(start_addr, cpu_count, max_cpus + 1);
+    if start_addr + table.len() as u64 > ACPI_TABLES_START {
+        bail!("MP table of {} bytes overlaps ACPI tables", table.len());
+    }
+    sys_mem
+        .write(
+            &mut table.as_slice(),
+            GuestAddress(start_addr),
+            table.len() as u64,
+        )
+        .chain_err(|| format!("Failed to load MP table to {:#x}", start_addr))?;
 
-    let mut offset = header + std::mem::size_of::<ConfigTableHeader>() as u64;
-    let mut sum = 0u8;
+    Ok(())
+}
 
-    for cpu_id in 0..num_cpus {
-        write_entry!(
-            ProcessEntry::new(cpu_id as u8, true, cpu_id == 0),
-            ProcessEntry,
-            sys_mem,
-            offset,
-            sum
+/// Write ACPI tables to `ACPI_TABLES_START`, where RSDP is placed.
+///
+/// # Arguments
+///
+/// * `sys_mem` - Guest memory.
+/// * `cpu_count` - Number of vcpus online at boot.
+/// * `max_cpus` - Number of vcpus, including the offline ones.
+fn setup_acpi_tables(sys_mem: &Arc<AddressSpace>, cpu_count: u8, max_cpus: u8) -> Result<()> {
+    if u32::from(max_cpus) > MPTABLE_MAX_CPUS {
+        return Err(ErrorKind::MaxCpus(max_cpus).into());
+    }
+    if max_cpus < cpu_count {
+        bail!(
+            "Max cpu number {} is less than cpu number {}",
+            max_cpus,
+            cpu_count
         );
     }
 
-    write_entry!(BusEntry::new(BUS_ID), BusEntry, sys_mem, offset, sum);
-
-    write_entry!(
-        IOApicEntry::new(ioapic_id, true, IOAPIC_BASE_ADDR),
-        IOApicEntry,
-        sys_mem,
-        offset,
-        sum
-    );
-
-    for i in 0..MPTABLE_IOAPIC_NR {
-        write_entry!(
-            IOInterruptEntry::new(INTERRUPT_TYPE_INT, BUS_ID, i, ioapic_id, i),
-            IOInterruptEntry,
-            sys_mem,
-            offset,
-            sum
+    let tables = build_acpi_tables(ACPI_TABLES_START, cpu_count, max_cpus);
+    if ACPI_TABLES_START + tables.len() as u64 > MB_BIOS_BEGIN {
+        bail!(
+            "ACPI tables of {} bytes exceed the reserved area",
+            tables.len()
         );
     }
+    sys_mem
+        .write(
+            &mut tables.as_slice(),
+            GuestAddress(ACPI_TABLES_START),
+            tables.len() as u64,
+        )
+        .chain_err(|| format!("Failed to load ACPI tables to {:#x}", ACPI_TABLES_START))?;
 
-    write_entry!(
-        LocalInterruptEntry::new(INTERRUPT_TYPE_EXTINT, BUS_ID, 0, ioapic_id, 0),
-        LocalInterruptEntry,
-        sys_mem,
-        offset,
-        sum
-    );
-
-    write_entry!(
-        LocalInterruptEntry::new(INTERRUPT_TYPE_NMI, BUS_ID, 0, DEST_ALL_LAPIC_MASK, 1),
-        LocalInterruptEntry,
-        sys_mem,
-        offset,
-        sum
-    );
+    Ok(())
+}
 
-    sys_mem.write_object(
-        &ConfigTableHeader::new((offset - header) as u16, sum, LAPIC_BASE_ADDR),
-        GuestAddress(header),
-    )?;
+/// Build E820 entries from the ram ranges of guest memory, sorted by address.
+///
+/// # Arguments
+///
+/// * `ram_ranges` - Ram ranges of guest memory.
+/// * `carve_outs` - Areas taken out of ram as `(start, end, type)`, sorted
+///                  and not overlapped, they are reported as `type` or
+///                  omitted if `type` is `None`.
+fn e820_entries(
+    ram_ranges: &[AddressRange],
+    carve_outs: &[(u64, u64, Option<u32>)],
+) -> Vec<(u64, u64, u32)> {
+    let mut entries = Vec::new();
+    for range in ram_ranges.iter() {
+        let mut start = range.base.raw_value();
+        let end = range.end_addr().raw_value();
+        for (carve_start, carve_end, _) in carve_outs.iter() {
+            if *carve_end <= start || *carve_start >= end {
+                continue;
+            }
+            if *carve_start > start {
+                entries.push((start, carve_start - start, E820_RAM));
+            }
+            start = std::cmp::max(start, *carve_end);
+        }
+        if start < end {
+            entries.push((start, end - start, E820_RAM));
+        }
+    }
 
-    Ok(())
+    for (carve_start, carve_end, type_) in carve_outs.iter() {
+        if let Some(type_) = type_ {
+            entries.push((*carve_start, carve_end - carve_start, *type_));
+        }
+    }
+    entries.sort_by_key(|(addr, _, _)| *addr);
+    entries
 }
 
 fn setup_boot_params(
     config: &X86BootLoaderConfig,
+    initrd_size: u64,
     sys_mem: &Arc<AddressSpace>,
+    kernel_header: Option<RealModeKernelHeader>,
 ) -> Result<(u64, u64)> {
-    let (ramdisk_size, ramdisk_image, initrd_addr) = if config.initrd_size > 0 {
-        let mut initrd_addr_max = INITRD_ADDR_MAX as u32;
-        if initrd_addr_max as u64 > sys_mem.memory_end_address().raw_value() as u64 {
-            initrd_addr_max = sys_mem.memory_end_address().raw_value() as u32;
+    let (ramdisk_size, ramdisk_image) = if initrd_size > 0 {
+        let (initrd_addr_max, kernel_range, above_4g) = match &kernel_header {
+            Some(header) => (
+                header.initrd_addr_max(),
+                Some((header.load_address()?, header.init_size())),
+                header.can_be_loaded_above_4g(),
+            ),
+            None => (INITRD_ADDR_MAX, None, false),
         };
-
-        let img = (initrd_addr_max - config.initrd_size as u32) & !0xfffu32;
-        (config.initrd_size as u32, img, img as u64)
+        let ram_ranges = sys_mem.memory_ranges();
+        let img = match initrd_address(&ram_ranges, initrd_addr_max, initrd_size, kernel_range) {
+            Ok(addr) => addr,
+            // The upper 32 bits of initrd address are in `ext_ramdisk_image`.
+            Err(_) if above_4g => {
+                initrd_address(&ram_ranges, u64::max_value(), initrd_size, kernel_range)?
+            }
+            Err(e) => return Err(e),
+        };
+        info!("Initrd of {:#x} bytes is placed at {:#x}", initrd_size, img);
+        (initrd_size, img)
     } else {
         info!("No initrd image file.");
-        (0u32, 0u32, 0u64)
+        (0u64, 0u64)
     };
 
-    let mut boot_params = BootParams::new(RealModeKernelHeader::new(
-        CMDLINE_START as u32,
-        config.kernel_cmdline.len() as u32,
-        ramdisk_image,
-        ramdisk_size,
-    ));
-
-    boot_params.add_e820_entry(
-        REAL_MODE_IVT_BEGIN,
-        EBDA_START - REAL_MODE_IVT_BEGIN,
-        E820_RAM,
-    );
-    boot_params.add_e820_entry(EBDA_START, VGA_RAM_BEGIN - EBDA_START, E820_RESERVED);
-    boot_params.add_e820_entry(MB_BIOS_BEGIN, 0, E820_RESERVED);
-
-    let high_memory_start = GuestAddress(VMLINUX_RAM_START);
-    let end_32bit_gap_start = GuestAddress(KVM_32BIT_GAP_START);
-    let first_addr_past_32bits = GuestAddress(KVM_32BIT_MAX_MEM_SIZE);
-    let mem_end = sys_mem.memory_end_address();
-    if mem_end < end_32bit_gap_start {
-        boot_params.add_e820_entry(
-            high_memory_start.raw_value() as u64,
-            mem_end.offset_from(high_memory_start) as u64,
-            E820_RAM,
-        );
-    } else {
-        boot_params.add_e820_entry(
-            high_memory_start.raw_value() as u64,
-            end_32bit_gap_start.offset_from(high_memory_start) as u64,
-            E820_RAM,
-        );
-        if mem_end > first_addr_past_32bits {
-            boot_params.add_e820_entry(
-                first_addr_past_32bits.raw_value() as u64,
-                mem_end.offset_from(first_addr_past_32bits) as u64,
-                E820_RAM,
+    let kernel_header = match kernel_header {
+        Some(mut header) => {
+            header.set_loader_fields(
+                CMDLINE_START as u32,
+                ramdisk_image as u32,
+                ramdisk_size as u32,
             );
+            header
         }
+        None => RealModeKernelHeader::new(
+            CMDLINE_START as u32,
+            config.kernel_cmdline.len() as u32,
+            ramdisk_image as u32,
+            ramdisk_size as u32,
+        ),
+    };
+    let mut boot_params = BootParams::new(kernel_header);
+    boot_params.set_ext_loader_fields(CMDLINE_START, ramdisk_image, ramdisk_size);
+
+    boot_params.set_acpi_rsdp_addr(ACPI_TABLES_START);
+
+    // MP table of many vcpus extends beyond EBDA.
+    let mptable_end = std::cmp::max(EBDA_START + mptable_size(config.cpu_count), VGA_RAM_BEGIN);
+    let cmdline_end = round_up(
+        CMDLINE_START + config.kernel_cmdline.len() as u64 + 1,
+        PAGE_SIZE,
+    )
+    .ok_or_else(|| ErrorKind::Msg("Kernel cmdline is too long".to_string()))?;
+    let carve_outs = [
+        (
+            ZERO_PAGE_START,
+            ZERO_PAGE_START + std::mem::size_of::<BootParams>() as u64,
+            Some(E820_RESERVED),
+        ),
+        (CMDLINE_START, cmdline_end, Some(E820_RESERVED)),
+        (EBDA_START, mptable_end, Some(E820_RESERVED)),
+        (mptable_end, ACPI_TABLES_START, None),
+        // ACPI tables and BIOS area.
+        (ACPI_TABLES_START, VMLINUX_RAM_START, Some(E820_RESERVED)),
+        (KVM_32BIT_GAP_START, KVM_32BIT_MAX_MEM_SIZE, None),
+    ];
+    for (addr, size, type_) in e820_entries(&sys_mem.memory_ranges(), &carve_outs) {
+        boot_params.add_e820_entry(addr, size, type_)?;
     }
 
     sys_mem
         .write_object(&boot_params, GuestAddress(ZERO_PAGE_START))
         .chain_err(|| format!("Failed to load zero page to 0x{:x}", ZERO_PAGE_START))?;
 
-    Ok((ZERO_PAGE_START, initrd_addr))
+    Ok((ZERO_PAGE_START, ramdisk_image))
 }
 
 fn write_gdt_table(table: &[u64], guest_mem: &Arc<AddressSpace>) -> Result<()> {
@@ -373,37 +475,242 @@ pub fn setup_gdt(guest_mem: &Arc<AddressSpace>) -> Result<BootGdtSegment> {
     })
 }
 
+/// Choose the address of initrd, which is as high as possible in guest ram but
+/// below `initrd_addr_max`, and doesn't overlap the kernel.
+///
+/// # Arguments
+///
+/// * `ram_ranges` - Ram ranges of guest memory, sorted by base address.
+/// * `initrd_addr_max` - The highest address initrd can occupy.
+/// * `initrd_size` - Size of initrd.
+/// * `kernel_range` - Memory used by kernel during boot, as `(base, size)`.
+fn initrd_address(
+    ram_ranges: &[AddressRange],
+    initrd_addr_max: u64,
+    initrd_size: u64,
+    kernel_range: Option<(u64, u64)>,
+) -> Result<u64> {
+    let initrd_limit = initrd_addr_max.saturating_add(1);
+    for range in ram_ranges.iter().rev() {
+        let base = range.base.raw_value();
+        let mut top = std::cmp::min(range.end_addr().raw_value(), initrd_limit);
+        if let Some((kernel_base, kernel_size)) = kernel_range {
+            // initrd is below the kernel if it can't be placed above.
+            let kernel_end = kernel_base.saturating_add(kernel_size);
+            if top > kernel_base && top.saturating_sub(initrd_size) < kernel_end {
+                top = std::cmp::min(top, kernel_base);
+            }
+        }
+        if let Some(addr) = top
+            .checked_sub(initrd_size)
+            .and_then(|addr| round_down(addr, INITRD_ALIGN))
+        {
+            if addr >= base {
+                return Ok(addr);
+            }
+        }
+    }
+    Err(ErrorKind::InitrdOverflow(initrd_size, initrd_addr_max).into())
+}
+
+/// Read the beginning bytes of kernel image, which are enough to detect the format.
+///
+/// # Arguments
+///
+/// * `kernel` - Kernel image.
+fn read_kernel_head(kernel: &mut Image) -> Result<Vec<u8>> {
+    let head_size = KERNEL_HEADER_OFFSET + std::mem::size_of::<RealModeKernelHeader>();
+    let mut image = Vec::with_capacity(head_size);
+    kernel
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| {
+            kernel
+                .by_ref()
+                .take(head_size as u64)
+                .read_to_end(&mut image)
+        })
+        .chain_err(|| "Failed to read kernel image")?;
+    Ok(image)
+}
+
+/// Get the entry and the segments to load of an ELF vmlinux.
+///
+/// # Arguments
+///
+/// * `kernel` - Kernel image.
+/// * `sys_mem` - Guest memory.
+fn elf_kernel_layout(
+    kernel: &mut Image,
+    sys_mem: &Arc<AddressSpace>,
+) -> Result<(u64, Vec<ImageSegment>)> {
+    let (entry, segments) = parse_elf(kernel)?;
+    for seg in segments.iter() {
+        if !sys_mem.address_in_memory(GuestAddress(seg.guest_addr), seg.mem_size) {
+            return Err(ErrorKind::KernelOverflow(seg.guest_addr, seg.mem_size).into());
+        }
+    }
+    if !sys_mem.address_in_memory(GuestAddress(entry), 0) {
+        return Err(ErrorKind::EntryOutOfRam(entry).into());
+    }
+    Ok((entry, segments))
+}
+
+/// Get the entry and the segments to load of a bzImage.
+///
+/// # Arguments
+///
+/// * `kernel_size` - Size of kernel image.
+/// * `header` - Setup header of the bzImage.
+/// * `sys_mem` - Guest memory.
+fn bzimage_kernel_layout(
+    kernel_size: u64,
+    header: &RealModeKernelHeader,
+    sys_mem: &Arc<AddressSpace>,
+) -> Result<(u64, Vec<ImageSegment>)> {
+    header.check_64bit_boot()?;
+    let load_addr = header.load_address()?;
+    if !sys_mem.address_in_memory(GuestAddress(load_addr), header.init_size()) {
+        return Err(ErrorKind::KernelOverflow(load_addr, header.init_size()).into());
+    }
+    info!(
+        "bzImage is loaded at {:#x}, alignment {:#x}, init size {:#x}",
+        load_addr,
+        header.kernel_alignment(),
+        header.init_size()
+    );
+
+    let file_size = kernel_size
+        .checked_sub(header.kernel_file_offset())
+        .ok_or_else(|| ErrorKind::Msg("bzImage is truncated".to_string()))?;
+    let segment = ImageSegment {
+        file_offset: header.kernel_file_offset(),
+        file_size,
+        mem_size: file_size,
+        guest_addr: load_addr,
+    };
+    Ok((load_addr + KERNEL_64BIT_ENTRY_OFFSET, vec![segment]))
+}
+
+/// Check the length of kernel cmdline against the limit declared by kernel,
+/// and the area reserved for it below EBDA.
+///
+/// # Arguments
+///
+/// * `cmdline` - Kernel cmdline, including the parameters appended by StratoVirt.
+/// * `kernel_header` - Setup header of bzImage.
+fn check_kernel_cmdline(cmdline: &str, kernel_header: Option<&RealModeKernelHeader>) -> Result<()> {
+    let max = kernel_header.map_or(CMDLINE_MAX_SIZE_LEGACY, |header| {
+        u64::from(header.cmdline_size())
+    });
+    // One byte is left for the terminating zero.
+    let max = std::cmp::min(max, EBDA_START - CMDLINE_START - 1);
+    if cmdline.len() as u64 > max {
+        return Err(ErrorKind::CmdlineOverflow(cmdline.len() as u64, max).into());
+    }
+    Ok(())
+}
+
 pub fn linux_bootloader(
     config: &X86BootLoaderConfig,
+    kernel: &mut Image,
+    initrd_size: u64,
     sys_mem: &Arc<AddressSpace>,
 ) -> Result<X86BootLoader> {
+    let kernel_head = read_kernel_head(kernel)?;
+    // ELF vmlinux and raw vmlinux.bin have no setup header, a header is synthesized for them.
+    let kernel_header = RealModeKernelHeader::from_image(&kernel_head);
+    let (kernel_start, kernel_segments) = if is_elf(&kernel_head) {
+        elf_kernel_layout(kernel, sys_mem)?
+    } else if let Some(header) = &kernel_header {
+        bzimage_kernel_layout(kernel.size(), header, sys_mem)?
+    } else {
+        let len = kernel.size();
+        let segment = ImageSegment {
+            file_offset: 0,
+            file_size: len,
+            mem_size: len,
+            guest_addr: VMLINUX_STARTUP,
+        };
+        (VMLINUX_STARTUP, vec![segment])
+    };
+    check_kernel_cmdline(&config.kernel_cmdline, kernel_header.as_ref())?;
+
     let boot_pml4 = setup_page_table(sys_mem)?;
 
-    setup_isa_mptable(sys_mem, EBDA_START, config.cpu_count)?;
+    setup_acpi_tables(sys_mem, config.cpu_count, config.max_cpus)?;
+    setup_isa_mptable(sys_mem, EBDA_START, config.cpu_count, config.max_cpus)?;
 
-    let (zero_page, initrd_addr) = setup_boot_params(&config, sys_mem)?;
+    let (zero_page, initrd_addr) = setup_boot_params(&config, initrd_size, sys_mem, kernel_header)?;
 
     let gdt_seg = setup_gdt(sys_mem)?;
 
     Ok(X86BootLoader {
-        kernel_start: VMLINUX_STARTUP,
+        kernel_start,
+        kernel_segments,
         kernel_sp: BOOT_LOADER_SP,
         initrd_start: initrd_addr,
+        initrd_size,
         boot_pml4_addr: boot_pml4,
         zero_page_addr: zero_page,
         segments: gdt_seg,
     })
 }
 
+/// Map firmware to the top of 4G, return the reset vector.
+///
+/// # Arguments
+///
+/// * `image` - Content of firmware.
+/// * `sys_mem` - guest memory.
+///
+/// # Errors
+///
+/// Return Error if the size of firmware is not page aligned or exceeds 8M,
+/// or fail to map it.
+pub fn setup_firmware(image: &[u8], sys_mem: &Arc<AddressSpace>) -> Result<u64> {
+    let size = image.len() as u64;
+    if size == 0 || size % PAGE_SIZE != 0 {
+        return Err(ErrorKind::InvalidFirmware(format!(
+            "size {:#x} is not a multiple of {:#x}",
+            size, PAGE_SIZE
+        ))
+        .into());
+    }
+    if size > FIRMWARE_MAX_SIZE {
+        return Err(ErrorKind::InvalidFirmware(format!(
+            "size {:#x} exceeds {:#x}",
+            size, FIRMWARE_MAX_SIZE
+        ))
+        .into());
+    }
+
+    crate::map_rom(image, KVM_32BIT_MAX_MEM_SIZE - size, size, 0, sys_mem)
+        .chain_err(|| "Failed to map firmware below 4G")?;
+    // Legacy firmware keeps running in real mode for a while after jumping
+    // out of the reset vector, where only the first 1M is addressable.
+    let alias_size = std::cmp::min(size, BIOS_ALIAS_MAX_SIZE);
+    let alias = &image[(size - alias_size) as usize..];
+    crate::map_rom(
+        alias,
+        VMLINUX_RAM_START - alias_size,
+        alias_size,
+        1,
+        sys_mem,
+    )
+    .chain_err(|| "Failed to map firmware below 1M")?;
+
+    Ok(RESET_VECTOR)
+}
+
 pub fn setup_kernel_cmdline(
     config: &X86BootLoaderConfig,
     sys_mem: &Arc<AddressSpace>,
 ) -> Result<()> {
-    let mut cmdline = config.kernel_cmdline.as_bytes();
+    let cmdline = [config.kernel_cmdline.as_bytes(), &[0_u8]].concat();
     sys_mem.write(
-        &mut cmdline,
+        &mut cmdline.as_slice(),
         GuestAddress(CMDLINE_START),
-        config.kernel_cmdline.len() as u64,
+        cmdline.len() as u64,
     )?;
 
     Ok(())
@@ -415,11 +722,151 @@ mod test {
     use address_space::*;
     use std::sync::Arc;
     use std::vec::Vec;
+    #[test]
+    fn test_initrd_address() {
+        let ranges = vec![
+            AddressRange::new(GuestAddress(0), 0xc000_0000),
+            AddressRange::new(GuestAddress(0x1_0000_0000), 0x4000_0000),
+        ];
+        // legacy limit
+        assert_eq!(
+            initrd_address(&ranges, INITRD_ADDR_MAX, 0x10_0000, None).unwrap(),
+            0x37f0_0000
+        );
+        // limit above ram is clamped to the end of the highest usable range
+        assert_eq!(
+            initrd_address(&ranges, 0xffff_ffff, 0x10_0800, None).unwrap(),
+            0xbfef_f000
+        );
+        assert_eq!(
+            initrd_address(&ranges, u64::max_value(), 0x10_0000, None).unwrap(),
+            0x1_3ff0_0000
+        );
+        // initrd is moved below the kernel if they overlap
+        assert_eq!(
+            initrd_address(
+                &ranges,
+                0x3fff_ffff,
+                0x10_0000,
+                Some((0x3000_0000, 0x1000_0000))
+            )
+            .unwrap(),
+            0x2ff0_0000
+        );
+        // too small memory
+        let ranges = vec![AddressRange::new(GuestAddress(0), 0x80_0000)];
+        assert!(initrd_address(&ranges, INITRD_ADDR_MAX, 0x100_0000, None).is_err());
+        assert!(initrd_address(
+            &ranges,
+            INITRD_ADDR_MAX,
+            0x40_0000,
+            Some((0x10_0000, 0x60_0000))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_e820_entries() {
+        // ram overlaps the MMIO gap and crosses 4G
+        let ranges = vec![
+            AddressRange::new(GuestAddress(0), 0xe000_0000),
+            AddressRange::new(GuestAddress(0x1_0000_0000), 0x4000_0000),
+        ];
+        let carve_outs = [
+            (0x9_fc00, 0xa_0000, Some(E820_RESERVED)),
+            (0xa_0000, 0xe_0000, None),
+            (0xe_0000, 0x10_0000, Some(E820_RESERVED)),
+            (KVM_32BIT_GAP_START, KVM_32BIT_MAX_MEM_SIZE, None),
+        ];
+        assert_eq!(
+            e820_entries(&ranges, &carve_outs),
+            vec![
+                (0, 0x9_fc00, E820_RAM),
+                (0x9_fc00, 0x400, E820_RESERVED),
+                (0xe_0000, 0x2_0000, E820_RESERVED),
+                (0x10_0000, 0xcff0_0000, E820_RAM),
+                (0x1_0000_0000, 0x4000_0000, E820_RAM),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_kernel_cmdline() {
+        let cmdline = "a".repeat(CMDLINE_MAX_SIZE_LEGACY as usize);
+        assert!(check_kernel_cmdline(&cmdline, None).is_ok());
+        let cmdline = "a".repeat(CMDLINE_MAX_SIZE_LEGACY as usize + 1);
+        assert!(check_kernel_cmdline(&cmdline, None).is_err());
+
+        // limit declared by bzImage
+        let header = RealModeKernelHeader::new(CMDLINE_START as u32, 0xfff, 0, 0);
+        assert!(check_kernel_cmdline(&"a".repeat(0xfff), Some(&header)).is_ok());
+        match check_kernel_cmdline(&"a".repeat(0x1000), Some(&header)) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "Kernel cmdline of 4096 bytes exceeds the 4095 bytes allowed"
+            ),
+            Ok(_) => panic!("Cmdline overflow is not detected"),
+        }
+    }
+
+    #[test]
+    fn test_setup_firmware() {
+        let root = Region::init_container_region(1 << 32);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(GuestAddress(0), 0x1000_0000, MemAdvice::default()).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+
+        let mut image = vec![0_u8; 0x2_0000];
+        // `jmp 0xf000:0xe05b` at reset vector
+        image[0x1_fff0..0x1_fff5].copy_from_slice(&[0xea, 0x5b, 0xe0, 0x00, 0xf0]);
+        image[0x1_0000] = 0x5a;
+        assert_eq!(setup_firmware(&image, &space).unwrap(), RESET_VECTOR);
+
+        // the alias punches a hole in ram below 1M
+        assert_eq!(
+            space.memory_ranges(),
+            vec![
+                AddressRange::new(GuestAddress(0), 0xf_0000),
+                AddressRange::new(GuestAddress(0x10_0000), 0xff0_0000),
+            ]
+        );
+
+        // the tail is visible at both the top of 4G and below 1M
+        let mut code = [0_u8; 5];
+        space
+            .read(&mut code.as_mut(), GuestAddress(RESET_VECTOR), 5)
+            .unwrap();
+        assert_eq!(code, [0xea, 0x5b, 0xe0, 0x00, 0xf0]);
+        space
+            .read(&mut code.as_mut(), GuestAddress(0xf_fff0), 5)
+            .unwrap();
+        assert_eq!(code, [0xea, 0x5b, 0xe0, 0x00, 0xf0]);
+        assert_eq!(
+            space.read_object::<u8>(GuestAddress(0xf_0000)).unwrap(),
+            0x5a
+        );
+        // ram below the alias is untouched, writes to firmware are rejected
+        assert!(space.write_object(&0_u8, GuestAddress(0xe_ffff)).is_ok());
+        assert!(space.write_object(&0_u8, GuestAddress(0xf_0000)).is_err());
+        assert!(space
+            .write_object(&0_u8, GuestAddress(RESET_VECTOR))
+            .is_err());
+
+        // unaligned, empty or oversized firmware is rejected
+        assert!(setup_firmware(&[0_u8; 0x800], &space).is_err());
+        assert!(setup_firmware(&[], &space).is_err());
+        assert!(setup_firmware(&vec![0_u8; 0x80_1000], &space).is_err());
+    }
+
     #[test]
     fn test_x86_bootloader_and_kernel_cmdline() {
         let root = Region::init_container_region(0x2000_0000);
         let space = AddressSpace::new(root.clone()).unwrap();
-        let ram1 = Arc::new(HostMemMapping::new(GuestAddress(0), 0x1000_0000, false).unwrap());
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), 0x1000_0000, MemAdvice::default()).unwrap(),
+        );
         let region_a = Region::init_ram_region(ram1.clone());
         root.add_subregion(region_a, ram1.start_address().raw_value())
             .unwrap();
@@ -446,11 +893,11 @@ mod test {
         let config = X86BootLoaderConfig {
             kernel: PathBuf::new(),
             initrd: Some(PathBuf::new()),
-            initrd_size: 0x1_0000,
             kernel_cmdline: String::from("this_is_a_piece_of_test_string"),
             cpu_count: 2,
+            max_cpus: 2,
         };
-        let (_, initrd_addr_tmp) = setup_boot_params(&config, &space).unwrap();
+        let (_, initrd_addr_tmp) = setup_boot_params(&config, 0x1_0000, &space, None).unwrap();
         assert_eq!(initrd_addr_tmp, 0xfff_0000);
 
         //test setup_gdt function
@@ -504,17 +951,17 @@ mod test {
 
         //test setup_kernel_cmdline function
         let cmd_len: u64 = config.kernel_cmdline.len() as u64;
-        let mut read_buffer: [u8; 30] = [0; 30];
-        //let mut read_buffer:Vec<u8> = Vec::with_capacity();
+        let mut read_buffer: [u8; 31] = [0xff; 31];
         assert!(setup_kernel_cmdline(&config, &space).is_ok());
         space
             .read(
                 &mut read_buffer.as_mut(),
                 GuestAddress(0x0002_0000),
-                cmd_len,
+                cmd_len + 1,
             )
             .unwrap();
-        let s = String::from_utf8(read_buffer.to_vec()).unwrap();
+        let s = String::from_utf8(read_buffer[..30].to_vec()).unwrap();
         assert_eq!(s, "this_is_a_piece_of_test_string".to_string());
+        assert_eq!(read_buffer[30], 0);
     }
 }
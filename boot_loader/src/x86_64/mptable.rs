@@ -11,7 +11,9 @@
 // See the Mulan PSL v2 for more details.
 
 use util::byte_code::ByteCode;
-use util::checksum::obj_checksum;
+use util::checksum::{checksum, obj_checksum};
+
+use super::acpi::{SCI_INT_FLAGS, SCI_IRQ};
 
 const SPEC_VERSION: u8 = 4; // version 1.4
 const APIC_VERSION: u8 = 0x14;
@@ -28,6 +30,10 @@ pub const INTERRUPT_TYPE_EXTINT: u8 = 3;
 pub const IOAPIC_BASE_ADDR: u32 = 0xfec0_0000;
 pub const LAPIC_BASE_ADDR: u32 = 0xfee0_0000;
 pub const DEST_ALL_LAPIC_MASK: u8 = 0xff;
+const INTERRUPT_FLAGS_CONFORM: u16 = 0;
+const ISA_BUS_ID: u8 = 0;
+const ISA_IRQ_NR: u8 = 16;
+const BSP_LAPIC_ID: u8 = 0;
 
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
@@ -55,8 +61,7 @@ impl FloatingPointer {
             feature2: 0,
         };
 
-        let sum = obj_checksum(&fp);
-        fp.checksum = (-(sum as i8)) as u8;
+        fp.checksum = 0_u8.wrapping_sub(obj_checksum(&fp));
 
         fp
     }
@@ -83,7 +88,7 @@ pub struct ConfigTableHeader {
 impl ByteCode for ConfigTableHeader {}
 
 impl ConfigTableHeader {
-    pub fn new(length: u16, sum: u8, lapic_addr: u32) -> Self {
+    pub fn new(length: u16, entry_count: u16, sum: u8, lapic_addr: u32) -> Self {
         let mut ct = ConfigTableHeader {
             signature: [b'P', b'C', b'M', b'P'],
             length,
@@ -95,15 +100,14 @@ impl ConfigTableHeader {
             ],
             oem_table_pointer: 0,
             oem_table_size: 0,
-            entry_count: 0,
+            entry_count,
             lapic_addr,
             ext_table_length: 0,
             ext_table_checksum: 0,
             reserved: 0,
         };
 
-        let sum = sum.wrapping_add(obj_checksum(&ct));
-        ct.checksum = (-(sum as i8)) as u8;
+        ct.checksum = 0_u8.wrapping_sub(sum.wrapping_add(obj_checksum(&ct)));
 
         ct
     }
@@ -207,6 +211,7 @@ impl ByteCode for IOInterruptEntry {}
 impl IOInterruptEntry {
     pub fn new(
         interrupt_type: u8,
+        interrupt_flags: u16,
         source_bus_id: u8,
         source_bus_irq: u8,
         dest_ioapic_id: u8,
@@ -215,7 +220,7 @@ impl IOInterruptEntry {
         IOInterruptEntry {
             type_: 3,
             interrupt_type,
-            interrupt_flags: 0, // conforms to spec of bus
+            interrupt_flags,
             source_bus_id,
             source_bus_irq,
             dest_ioapic_id,
@@ -257,3 +262,133 @@ impl LocalInterruptEntry {
         }
     }
 }
+
+/// Get the size of MP floating pointer and MP configuration table.
+///
+/// # Arguments
+///
+/// * `cpu_count` - Number of processor entries.
+pub fn mptable_size(cpu_count: u8) -> u64 {
+    let size = std::mem::size_of::<FloatingPointer>()
+        + std::mem::size_of::<ConfigTableHeader>()
+        + std::mem::size_of::<ProcessEntry>() * usize::from(cpu_count)
+        + std::mem::size_of::<BusEntry>()
+        + std::mem::size_of::<IOApicEntry>()
+        + std::mem::size_of::<IOInterruptEntry>() * usize::from(ISA_IRQ_NR)
+        + std::mem::size_of::<LocalInterruptEntry>() * 2;
+    size as u64
+}
+
+/// Build MP floating pointer followed by MP configuration table, which
+/// describes `cpu_count` processors, one ISA bus and one IOAPIC.
+///
+/// # Arguments
+///
+/// * `start_addr` - Guest address where the MP floating pointer is placed.
+/// * `cpu_count` - Number of vcpus online at boot, vcpu 0 is the BSP.
+/// * `ioapic_id` - ID of IOAPIC, which must not conflict with LAPIC ids.
+pub fn build_mptable(start_addr: u64, cpu_count: u8, ioapic_id: u8) -> Vec<u8> {
+    let header_addr = start_addr + std::mem::size_of::<FloatingPointer>() as u64;
+    let mut entries = Vec::new();
+    let mut entry_count = 0_u16;
+    let mut append_entry = |entry: &[u8]| {
+        entries.extend_from_slice(entry);
+        entry_count += 1;
+    };
+
+    for cpu_id in 0..cpu_count {
+        append_entry(ProcessEntry::new(cpu_id, true, cpu_id == BSP_LAPIC_ID).as_bytes());
+    }
+    append_entry(BusEntry::new(ISA_BUS_ID).as_bytes());
+    append_entry(IOApicEntry::new(ioapic_id, true, IOAPIC_BASE_ADDR).as_bytes());
+
+    // Same as the default GSI routing of KVM, ISA IRQs are connected to the
+    // IOAPIC pins with the same number.
+    for irq in 0..ISA_IRQ_NR {
+        let flags = if u32::from(irq) == SCI_IRQ {
+            SCI_INT_FLAGS
+        } else {
+            INTERRUPT_FLAGS_CONFORM
+        };
+        append_entry(
+            IOInterruptEntry::new(INTERRUPT_TYPE_INT, flags, ISA_BUS_ID, irq, ioapic_id, irq)
+                .as_bytes(),
+        );
+    }
+
+    // 8259 PIC is wired to LINT0 of BSP, NMI is delivered to LINT1 of all.
+    append_entry(
+        LocalInterruptEntry::new(INTERRUPT_TYPE_EXTINT, ISA_BUS_ID, 0, BSP_LAPIC_ID, 0).as_bytes(),
+    );
+    append_entry(
+        LocalInterruptEntry::new(INTERRUPT_TYPE_NMI, ISA_BUS_ID, 0, DEST_ALL_LAPIC_MASK, 1)
+            .as_bytes(),
+    );
+
+    let length = std::mem::size_of::<ConfigTableHeader>() + entries.len();
+    let header = ConfigTableHeader::new(
+        length as u16,
+        entry_count,
+        checksum(&entries),
+        LAPIC_BASE_ADDR,
+    );
+
+    let mut table = FloatingPointer::new(header_addr as u32).as_bytes().to_vec();
+    table.extend_from_slice(header.as_bytes());
+    table.extend_from_slice(&entries);
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_mptable() {
+        // 2 vcpus online, IOAPIC id follows 3 LAPICs.
+        let blob = build_mptable(0x9_fc00, 2, 4);
+        assert_eq!(blob.len() as u64, mptable_size(2));
+        assert_eq!(
+            blob,
+            vec![
+                0x5f, 0x4d, 0x50, 0x5f, 0x10, 0xfc, 0x09, 0x00, 0x01, 0x04, 0x8b, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x50, 0x43, 0x4d, 0x50, 0xf4, 0x00, 0x04, 0xff, 0x71, 0x76, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x31, 0x2e, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x16, 0x00, 0x00, 0x00, 0xe0, 0xfe,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x03, 0x00, 0x06, 0x00, 0x00, 0x01, 0x02,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x14, 0x01,
+                0x00, 0x06, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x01, 0x00, 0x49, 0x53, 0x41, 0x00, 0x00, 0x00, 0x02, 0x04, 0x14, 0x01,
+                0x00, 0x00, 0xc0, 0xfe, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x03, 0x00,
+                0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x02, 0x04, 0x02,
+                0x03, 0x00, 0x00, 0x00, 0x00, 0x03, 0x04, 0x03, 0x03, 0x00, 0x00, 0x00, 0x00, 0x04,
+                0x04, 0x04, 0x03, 0x00, 0x00, 0x00, 0x00, 0x05, 0x04, 0x05, 0x03, 0x00, 0x00, 0x00,
+                0x00, 0x06, 0x04, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x07, 0x04, 0x07, 0x03, 0x00,
+                0x00, 0x00, 0x00, 0x08, 0x04, 0x08, 0x03, 0x00, 0x0d, 0x00, 0x00, 0x09, 0x04, 0x09,
+                0x03, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x04, 0x0a, 0x03, 0x00, 0x00, 0x00, 0x00, 0x0b,
+                0x04, 0x0b, 0x03, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x0c, 0x03, 0x00, 0x00, 0x00,
+                0x00, 0x0d, 0x04, 0x0d, 0x03, 0x00, 0x00, 0x00, 0x00, 0x0e, 0x04, 0x0e, 0x03, 0x00,
+                0x00, 0x00, 0x00, 0x0f, 0x04, 0x0f, 0x04, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0xff, 0x01,
+            ]
+        );
+
+        let fp_size = std::mem::size_of::<FloatingPointer>();
+        assert_eq!(checksum(&blob[..fp_size]), 0);
+        assert_eq!(checksum(&blob[fp_size..]), 0);
+        // entry count: 2 processors, 1 bus, 1 IOAPIC, 16 IO and 2 local interrupts
+        let count_offset = fp_size + 34;
+        assert_eq!(blob[count_offset..count_offset + 2], [22, 0]);
+        // only the first processor is BSP
+        let cpu_offset = fp_size + std::mem::size_of::<ConfigTableHeader>();
+        assert_eq!(blob[cpu_offset + 3], CPU_FLAGS_ENABLE | CPU_FLAGS_BSP);
+        assert_eq!(blob[cpu_offset + 23], CPU_FLAGS_ENABLE);
+
+        // checksums stay valid with max vcpus
+        let blob = build_mptable(0x9_fc00, 254, 255);
+        assert_eq!(blob.len() as u64, mptable_size(254));
+        assert_eq!(checksum(&blob[..fp_size]), 0);
+        assert_eq!(checksum(&blob[fp_size..]), 0);
+        assert_eq!(blob[count_offset..count_offset + 2], [18, 1]);
+    }
+}
@@ -151,6 +151,11 @@ pub fn set_one_core_reg(vcpu: &Arc<VcpuFd>, reg: Arm64CoreRegs, data: u64) -> Re
     }
 }
 
+pub fn get_one_core_reg(vcpu: &Arc<VcpuFd>, reg: Arm64CoreRegs) -> Result<u64> {
+    vcpu.get_one_reg(reg.into())
+        .map_err(|e| ErrorKind::GetSysRegister(format!("{:?}", e)).into())
+}
+
 /// AArch64 CPU booting configure information
 ///
 /// Before jumping into the kernel, primary CPU general-purpose
@@ -230,6 +235,16 @@ impl CPUAArch64 {
         self.mpidr
     }
 
+    /// Get MPIDR which KVM assigns to the vcpu when it's initialized, so that
+    /// the vcpu not realized yet can be described to guest.
+    /// See: https://elixir.bootlin.com/linux/v5.6/source/arch/arm64/kvm/sys_regs.c#L622
+    pub fn default_mpidr(&self) -> u64 {
+        let aff0 = u64::from(self.vcpu_id & 0x0f);
+        let aff1 = u64::from((self.vcpu_id >> 4) & 0xff);
+        let aff2 = u64::from((self.vcpu_id >> 12) & 0xff);
+        (1 << 31) | (aff2 << 16) | (aff1 << 8) | aff0
+    }
+
     pub fn reset_vcpu(&self, vcpu: &Arc<VcpuFd>) -> Result<()> {
         // Configure PSTATE(Processor State), mask all interrupts.
         let data: u64 = PSR_D_BIT | PSR_A_BIT | PSR_I_BIT | PSR_F_BIT | PSR_MODE_EL1h;
@@ -257,4 +272,21 @@ impl CPUAArch64 {
 
         Ok(())
     }
+
+    /// Get general purpose registers in the layout of `struct user_pt_regs`,
+    /// which is `pr_reg` of `NT_PRSTATUS` note in ELF core.
+    pub fn get_elf_regs(&self, vcpu: &Arc<VcpuFd>) -> Result<Vec<u64>> {
+        let mut regs = Vec::new();
+        for index in 0..31 {
+            regs.push(get_one_core_reg(
+                vcpu,
+                Arm64CoreRegs::USER_PT_REG_REGS(index),
+            )?);
+        }
+        regs.push(get_one_core_reg(vcpu, Arm64CoreRegs::USER_PT_REG_SP)?);
+        regs.push(get_one_core_reg(vcpu, Arm64CoreRegs::USER_PT_REG_PC)?);
+        regs.push(get_one_core_reg(vcpu, Arm64CoreRegs::USER_PT_REG_PSTATE)?);
+
+        Ok(regs)
+    }
 }
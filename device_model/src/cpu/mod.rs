@@ -32,30 +32,37 @@ mod aarch64;
 mod x86_64;
 
 use std::cell::RefCell;
+use std::mem::size_of;
 use std::sync::{Arc, Barrier, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use kvm_ioctls::{VcpuExit, VcpuFd};
+use kvm_bindings::{kvm_run, KVM_SYSTEM_EVENT_RESET};
+use kvm_ioctls::{Kvm, KvmRunWrapper, VcpuExit, VcpuFd};
 use libc::{c_int, c_void, siginfo_t};
 use vmm_sys_util::signal::{register_signal_handler, Killable};
 
-#[cfg(feature = "qmp")]
-use machine_manager::{qmp::qmp_schema as schema, qmp::QmpChannel};
-
 use self::errors::{ErrorKind, Result};
 #[cfg(target_arch = "aarch64")]
+use crate::interrupt_controller::GIC_MAX_VCPUS;
+use crate::micro_vm::micro_syscall::{register_seccomp, SeccompPolicy, ThreadRole};
+#[cfg(target_arch = "aarch64")]
 pub use aarch64::errors as ArchCPUError;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::AArch64CPUBootConfig as CPUBootConfig;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::CPUAArch64 as ArchCPU;
+use machine_manager::config::MAX_NR_CPUS;
 use machine_manager::machine::MachineInterface;
+use util::boot_time::{BootMilestone, BootTimes};
+use util::kvm_ioctls_ext::get_system_event_type;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::errors as ArchCPUError;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::X86CPUBootConfig as CPUBootConfig;
 #[cfg(target_arch = "x86_64")]
+pub use x86_64::X86CPUState as ArchCPUState;
+#[cfg(target_arch = "x86_64")]
 pub use x86_64::X86CPU as ArchCPU;
 
 pub mod errors {
@@ -87,6 +94,10 @@ pub mod errors {
                 description("Destroy vcpu error!")
                 display("Failed to destroy kvm vcpu: {}!", err_info)
             }
+            NrVcpus(requested: u8, max: u8) {
+                description("Limit the number of vcpus by KVM and machine.")
+                display("Number of vcpus {} is more than the max {} allowed by KVM and machine.", requested, max)
+            }
         }
     }
 }
@@ -108,6 +119,33 @@ const VCPU_TASK_SIGNAL: i32 = 37;
 
 const UNINITIALIZED_VCPU_ID: u32 = 9999;
 
+/// Capabilities of KVM which limit the number of vcpus.
+pub trait VcpuCaps {
+    /// Recommended max number of vcpus, `KVM_CAP_NR_VCPUS`.
+    fn nr_vcpus(&self) -> usize;
+    /// Max number of vcpus, `KVM_CAP_MAX_VCPUS`.
+    fn max_vcpus(&self) -> usize;
+}
+
+impl VcpuCaps for Kvm {
+    fn nr_vcpus(&self) -> usize {
+        self.get_nr_vcpus()
+    }
+
+    fn max_vcpus(&self) -> usize {
+        self.get_max_vcpus()
+    }
+}
+
+/// Get the max number of vcpus allowed by KVM, interrupt controller and
+/// `MAX_NR_CPUS` of config.
+pub fn max_nr_vcpus(caps: &dyn VcpuCaps) -> u8 {
+    let max = caps.max_vcpus().min(usize::from(MAX_NR_CPUS));
+    #[cfg(target_arch = "aarch64")]
+    let max = max.min(GIC_MAX_VCPUS as usize);
+    max as u8
+}
+
 /// State for `CPU` lifecycle.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum CpuLifecycleState {
@@ -159,12 +197,14 @@ pub trait CPUInterface {
     /// * `cpu` - The cpu instance shared in thread.
     /// * `thread_barrier` - The cpu thread barrier.
     /// * `paused` - After started, paused vcpu or not.
-    /// * `use seccomp` - Use seccomp in vcpu thread.
+    /// * `seccomp` - Seccomp policy of vcpu thread.
+    /// * `vm_name` - Name of VM, prefixed to the name of vcpu thread.
     fn start(
         cpu: Arc<Self>,
         thread_barrier: Arc<Barrier>,
         paused: bool,
-        use_seccomp: bool,
+        seccomp: SeccompPolicy,
+        vm_name: Option<&str>,
     ) -> Result<()>
     where
         Self: std::marker::Sized;
@@ -215,6 +255,14 @@ pub struct CPU {
     tid: Arc<Mutex<Option<u64>>>,
     /// The VM combined by this VCPU.
     vm: Arc<Box<Arc<dyn MachineInterface + Send + Sync>>>,
+    /// State restored from snapshot, which is set once the registers are
+    /// reset in VCPU thread.
+    #[cfg(target_arch = "x86_64")]
+    restored_state: Mutex<Option<ArchCPUState>>,
+    /// Boot milestones of the VM, where the first entry to guest is recorded.
+    boot_times: Arc<BootTimes>,
+    /// Mapping of `kvm_run` of this VCPU, to read what `VcpuExit` doesn't carry.
+    kvm_run: KvmRunWrapper,
 }
 
 impl CPU {
@@ -226,12 +274,16 @@ impl CPU {
     /// * `id` - ID of this `CPU`.
     /// * `arch_cpu` - Architecture special `CPU` property.
     /// * `vm` - The virtual machine this `CPU` gets attached to.
+    /// * `boot_times` - Boot milestones of the virtual machine.
     pub fn new(
         vcpu_fd: Arc<VcpuFd>,
         id: u8,
         arch_cpu: Arc<Mutex<ArchCPU>>,
         vm: Arc<Box<Arc<dyn MachineInterface + Send + Sync>>>,
+        boot_times: Arc<BootTimes>,
     ) -> Result<Self> {
+        let kvm_run = KvmRunWrapper::mmap_from_fd(&*vcpu_fd, size_of::<kvm_run>())
+            .map_err(|e| ErrorKind::CreateVcpu(format!("Failed to map kvm_run: {}", e)))?;
         Ok(CPU {
             id,
             fd: vcpu_fd,
@@ -241,6 +293,10 @@ impl CPU {
             task: Arc::new(Mutex::new(None)),
             tid: Arc::new(Mutex::new(None)),
             vm,
+            #[cfg(target_arch = "x86_64")]
+            restored_state: Mutex::new(None),
+            boot_times,
+            kvm_run,
         })
     }
 
@@ -261,6 +317,33 @@ impl CPU {
         &self.arch_cpu
     }
 
+    /// Get the general purpose registers of this `CPU` in the layout of
+    /// `pr_reg` of ELF core note, the `CPU` should be paused.
+    pub fn get_elf_regs(&self) -> Result<Vec<u64>> {
+        Ok(self.arch_cpu.lock().unwrap().get_elf_regs(&self.fd)?)
+    }
+
+    /// Get the state of this `CPU` saved in snapshot, the `CPU` should be
+    /// paused.
+    #[cfg(target_arch = "x86_64")]
+    pub fn get_state(&self) -> Result<ArchCPUState> {
+        Ok(self.arch_cpu.lock().unwrap().get_state(&self.fd)?)
+    }
+
+    /// Set the state of this `CPU` restored from snapshot, which takes
+    /// effect when the `CPU` starts, instead of the registers of booting.
+    /// It's set at once if the `CPU` is started and frozen already, as VM
+    /// migrated in by `migrate-incoming` is.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_state(&self, state: ArchCPUState) -> Result<()> {
+        if self.task.lock().unwrap().is_some() {
+            self.arch_cpu.lock().unwrap().set_state(&self.fd, &state)?;
+        } else {
+            *self.restored_state.lock().unwrap() = Some(state);
+        }
+        Ok(())
+    }
+
     /// Set task the `CPU` to handle.
     pub fn set_task(&self, task: Option<thread::JoinHandle<()>>) {
         let mut data = self.task.lock().unwrap();
@@ -337,7 +420,8 @@ impl CPUInterface for CPU {
         cpu: Arc<CPU>,
         thread_barrier: Arc<Barrier>,
         paused: bool,
-        use_seccomp: bool,
+        seccomp: SeccompPolicy,
+        vm_name: Option<&str>,
     ) -> Result<()> {
         let (cpu_state, _) = &*cpu.state;
         if *cpu_state.lock().unwrap() == CpuLifecycleState::Running {
@@ -350,8 +434,12 @@ impl CPUInterface for CPU {
         }
 
         let local_cpu = cpu.clone();
+        let thread_name = match vm_name {
+            Some(name) => format!("{}:CPU {}/KVM", name, cpu.id),
+            None => format!("CPU {}/KVM", cpu.id),
+        };
         let handle = thread::Builder::new()
-            .name(format!("CPU {}/KVM", cpu.id))
+            .name(thread_name)
             .spawn(move || {
                 init_local_thread_vcpu(cpu.id);
                 if let Err(e) = CPU::init_signals() {
@@ -369,17 +457,20 @@ impl CPUInterface for CPU {
                 thread_barrier.wait();
 
                 info!("vcpu{} start running", cpu.id);
-                if use_seccomp {
-                    if let Err(e) = crate::micro_vm::micro_syscall::register_seccomp() {
-                        error!("Failed to register seccomp in cpu{} thread:{}", cpu.id, e);
-                    }
+                if let Err(e) = register_seccomp(ThreadRole::Vcpu, &seccomp) {
+                    error!("Failed to register seccomp in cpu{} thread:{}", cpu.id, e);
                 }
 
+                let mut entered = false;
                 loop {
                     if !cpu.ready_for_running() {
                         break;
                     }
 
+                    if !entered {
+                        cpu.boot_times.record(BootMilestone::FirstVcpuEntry);
+                        entered = true;
+                    }
                     if !cpu.kvm_vcpu_exec().unwrap() {
                         break;
                     }
@@ -397,7 +488,14 @@ impl CPUInterface for CPU {
     }
 
     fn reset(&self) -> Result<()> {
-        self.arch_cpu.lock().unwrap().reset_vcpu(&self.fd)?;
+        let arch_cpu = self.arch_cpu.lock().unwrap();
+        arch_cpu.reset_vcpu(&self.fd)?;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(state) = self.restored_state.lock().unwrap().take() {
+                arch_cpu.set_state(&self.fd, &state)?;
+            }
+        }
         Ok(())
     }
 
@@ -484,21 +582,28 @@ impl CPUInterface for CPU {
                     info!("Vcpu{} Received KVM_EXIT_HLT signal", self.id());
                     panic!("Hlt vpu {}", self.id());
                 }
-                VcpuExit::Shutdown | VcpuExit::SystemEvent => {
+                // Vcpu triple-faults, which is how x86 guest reboots at last.
+                VcpuExit::Shutdown => {
                     info!("Vcpu{} Received an KVM_EXIT_SHUTDOWN signal", self.id());
                     let (cpu_state, _) = &*self.state;
                     *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
-                    self.vm.destroy();
-
-                    #[cfg(feature = "qmp")]
-                    {
-                        let shutdown_msg = schema::SHUTDOWN {
-                            guest: true,
-                            reason: "guest-shutdown".to_string(),
-                        };
-                        event!(SHUTDOWN; shutdown_msg);
+                    // VM is rebooted by main loop as well.
+                    self.vm.request_guest_reset();
+                    return Ok(false);
+                }
+                VcpuExit::SystemEvent => {
+                    info!("Vcpu{} Received an KVM_EXIT_SYSTEM_EVENT signal", self.id());
+                    let (cpu_state, _) = &*self.state;
+                    *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
+                    // Such as PSCI SYSTEM_RESET and SYSTEM_OFF of aarch64 guest.
+                    let event_type = get_system_event_type(&self.kvm_run);
+                    // VM is shut down or rebooted by main loop, since destroying
+                    // VM waits for all vcpus including this one to stop.
+                    if event_type == KVM_SYSTEM_EVENT_RESET {
+                        self.vm.request_guest_reset();
+                    } else {
+                        self.vm.request_guest_shutdown();
                     }
-
                     return Ok(false);
                 }
                 VcpuExit::FailEntry => {
@@ -592,15 +697,59 @@ pub struct CpuTopology {
     pub cores: u8,
     /// Number of threads in VM.
     pub threads: u8,
-    /// Number of vcpus in VM.
+    /// Number of vcpus online at boot.
     pub nrcpus: u8,
-    /// Number of online vcpus in VM.
+    /// Number of all possible vcpus in VM.
     pub max_cpus: u8,
     /// Online mask number of all vcpus.
     pub online_mask: Arc<Mutex<Vec<u8>>>,
 }
 
 impl CpuTopology {
+    /// Create the topology of `max_cpus` vcpus with one socket each, the
+    /// first `nr_cpus` of them are online.
+    ///
+    /// # Arguments
+    ///
+    /// * `nr_cpus` - Number of vcpus online at boot.
+    /// * `max_cpus` - Number of all vcpus.
+    /// * `caps` - Capabilities of KVM limiting the number of vcpus.
+    ///
+    /// # Errors
+    ///
+    /// `NrVcpus` if `max_cpus` is more than `max_nr_vcpus`, or `nr_cpus` is
+    /// 0 or more than `max_cpus`.
+    pub fn new(nr_cpus: u8, max_cpus: u8, caps: &dyn VcpuCaps) -> Result<Self> {
+        let max_allowed = max_nr_vcpus(caps);
+        if max_cpus > max_allowed {
+            return Err(ErrorKind::NrVcpus(max_cpus, max_allowed).into());
+        }
+        if nr_cpus == 0 || nr_cpus > max_cpus {
+            bail!(
+                "Number of vcpus {} should be more than 0 and no more than max cpus {}.",
+                nr_cpus,
+                max_cpus
+            );
+        }
+        if usize::from(max_cpus) > caps.nr_vcpus() {
+            warn!(
+                "Number of vcpus {} is more than {} recommended by KVM.",
+                max_cpus,
+                caps.nr_vcpus()
+            );
+        }
+
+        let mask = (0..max_cpus).map(|id| u8::from(id < nr_cpus)).collect();
+        Ok(CpuTopology {
+            sockets: max_cpus,
+            cores: 1,
+            threads: 1,
+            nrcpus: nr_cpus,
+            max_cpus,
+            online_mask: Arc::new(Mutex::new(mask)),
+        })
+    }
+
     /// Get online mask for a cpu.
     ///
     /// # Notes
@@ -631,3 +780,59 @@ impl CpuTopology {
         (socketid, coreid, threadid)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockCaps {
+        nr_vcpus: usize,
+        max_vcpus: usize,
+    }
+
+    impl VcpuCaps for MockCaps {
+        fn nr_vcpus(&self) -> usize {
+            self.nr_vcpus
+        }
+
+        fn max_vcpus(&self) -> usize {
+            self.max_vcpus
+        }
+    }
+
+    #[test]
+    fn test_vcpu_limits() {
+        let caps = MockCaps {
+            nr_vcpus: 16,
+            max_vcpus: 64,
+        };
+        assert_eq!(max_nr_vcpus(&caps), 64);
+        let topo = CpuTopology::new(4, 8, &caps).unwrap();
+        assert_eq!((topo.nrcpus, topo.max_cpus, topo.sockets), (4, 8, 8));
+        assert_eq!(
+            *topo.online_mask.lock().unwrap(),
+            vec![1, 1, 1, 1, 0, 0, 0, 0]
+        );
+        // More than recommended but no more than max is allowed.
+        assert!(CpuTopology::new(64, 64, &caps).is_ok());
+
+        let err = CpuTopology::new(65, 65, &caps).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Number of vcpus 65 is more than the max 64 allowed by KVM and machine."
+        );
+        // Max cpus is limited as well.
+        assert!(CpuTopology::new(1, 65, &caps).is_err());
+        assert!(CpuTopology::new(0, 1, &caps).is_err());
+        assert!(CpuTopology::new(2, 1, &caps).is_err());
+
+        // Limited by config if KVM supports more.
+        let caps = MockCaps {
+            nr_vcpus: 1024,
+            max_vcpus: 4096,
+        };
+        assert_eq!(max_nr_vcpus(&caps), MAX_NR_CPUS);
+        assert!(CpuTopology::new(MAX_NR_CPUS, MAX_NR_CPUS, &caps).is_ok());
+        assert!(CpuTopology::new(1, MAX_NR_CPUS + 1, &caps).is_err());
+    }
+}
@@ -15,9 +15,11 @@ mod cpuid;
 use std::sync::Arc;
 
 use kvm_bindings::{
-    kvm_fpu, kvm_msr_entry, kvm_regs, kvm_segment, kvm_sregs, Msrs, KVM_MAX_CPUID_ENTRIES,
+    kvm_fpu, kvm_lapic_state, kvm_mp_state, kvm_msr_entry, kvm_regs, kvm_segment, kvm_sregs,
+    kvm_vcpu_events, kvm_xcrs, kvm_xsave, Msrs, KVM_MAX_CPUID_ENTRIES,
 };
 use kvm_ioctls::{Kvm, VcpuFd, VmFd};
+use util::byte_code::ByteCode;
 
 use self::errors::Result;
 use cpuid::host_cpuid;
@@ -52,7 +54,11 @@ const MSR_IA32_MISC_ENABLE: u32 = 0x01a0;
 const MSR_IA32_MISC_ENABLE_FAST_STRING: u64 = 0x1;
 
 /// AArch64 CPU booting configure information
+#[derive(Default)]
 pub struct X86CPUBootConfig {
+    /// Boot into 64-bit protected mode with the entry below, or start from
+    /// the reset vector in real mode for firmware.
+    pub prot64_mode: bool,
     /// Register %rip value
     pub boot_ip: u64,
     /// Register %rsp value
@@ -69,10 +75,32 @@ pub struct X86CPUBootConfig {
     pub pml4_start: u64,
 }
 
+/// State of vcpu kept by KVM, which is saved in snapshot.
+#[repr(C)]
+#[derive(Default, Copy, Clone)]
+pub struct X86CPUState {
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    fpu: kvm_fpu,
+    xsave: kvm_xsave,
+    xcrs: kvm_xcrs,
+    lapic: kvm_lapic_state,
+    mp_state: kvm_mp_state,
+    vcpu_events: kvm_vcpu_events,
+    /// MSRs in `MSR_LIST`, the first `nr_msrs` of them are read.
+    msrs: [kvm_msr_entry; MSR_LIST.len()],
+    nr_msrs: u32,
+}
+
+impl ByteCode for X86CPUState {}
+
+util::versioned_state!(X86CPUState, 1, 1);
+
 #[derive(Default, Copy, Clone)]
 pub struct X86CPU {
     id: u32,
     nr_vcpus: u32,
+    prot64_mode: bool,
     boot_ip: u64,
     boot_sp: u64,
     zero_page: u64,
@@ -95,6 +123,7 @@ impl X86CPU {
     }
 
     pub fn realize(&mut self, vcpu_fd: &Arc<VcpuFd>, boot_config: &X86CPUBootConfig) -> Result<()> {
+        self.prot64_mode = boot_config.prot64_mode;
         self.boot_ip = boot_config.boot_ip;
         self.boot_sp = boot_config.boot_sp;
         self.zero_page = boot_config.zero_page;
@@ -223,6 +252,11 @@ impl X86CPU {
         const X86_CR4_PAE: u64 = 0x20;
 
         let mut sregs: kvm_sregs = vcpu_fd.get_sregs()?;
+        if !self.prot64_mode {
+            Self::reset_sregs(&mut sregs);
+            vcpu_fd.set_sregs(&sregs)?;
+            return Ok(());
+        }
 
         // Init gdt table, gdt table has loaded to Guest Memory Space
         sregs.cs = self.code_segment;
@@ -254,6 +288,46 @@ impl X86CPU {
         Ok(())
     }
 
+    /// Set special registers to the state after power-on, in which cpu
+    /// fetches the first instruction from `0xffff_fff0` in real mode.
+    /// Values below sourced from Intel SDM Vol.3, 9.1.1.
+    fn reset_sregs(sregs: &mut kvm_sregs) {
+        const X86_CR0_ET: u64 = 0x10;
+        const X86_CR0_NW: u64 = 0x2000_0000;
+        const X86_CR0_CD: u64 = 0x4000_0000;
+
+        let data_seg = kvm_segment {
+            base: 0,
+            limit: 0xffff,
+            selector: 0,
+            type_: 0x3,
+            present: 1,
+            s: 1,
+            ..Default::default()
+        };
+        sregs.cs = kvm_segment {
+            base: 0xffff_0000,
+            selector: 0xf000,
+            type_: 0xb,
+            ..data_seg
+        };
+        sregs.ds = data_seg;
+        sregs.es = data_seg;
+        sregs.fs = data_seg;
+        sregs.gs = data_seg;
+        sregs.ss = data_seg;
+
+        sregs.gdt.base = 0;
+        sregs.gdt.limit = 0xffff;
+        sregs.idt.base = 0;
+        sregs.idt.limit = 0xffff;
+
+        sregs.cr0 = X86_CR0_CD | X86_CR0_NW | X86_CR0_ET;
+        sregs.cr3 = 0;
+        sregs.cr4 = 0;
+        sregs.efer = 0;
+    }
+
     #[allow(clippy::cast_ptr_alignment)]
     fn setup_lapic(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
         // Disable nmi and external interrupt before enter protected mode
@@ -314,6 +388,17 @@ impl X86CPU {
     }
 
     fn setup_regs(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
+        if !self.prot64_mode {
+            // IP of reset vector, relative to the base of CS.
+            let regs = kvm_regs {
+                rflags: 0x0002,
+                rip: 0xfff0,
+                ..Default::default()
+            };
+            vcpu_fd.set_regs(&regs)?;
+            return Ok(());
+        }
+
         let regs: kvm_regs = kvm_regs {
             rflags: 0x0002, /* Means processor has been initialized */
             rip: self.boot_ip,
@@ -377,6 +462,111 @@ impl X86CPU {
 
         Ok(())
     }
+
+    /// Get the state of vcpu, which must not be running.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - File descriptor of vcpu.
+    pub fn get_state(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<X86CPUState> {
+        let entries: Vec<kvm_msr_entry> = MSR_LIST
+            .iter()
+            .map(|index| kvm_msr_entry {
+                index: *index,
+                ..Default::default()
+            })
+            .collect();
+        let mut msrs = Msrs::from_entries(&entries);
+        // KVM stops at the first MSR it fails to read.
+        let nr_msrs = vcpu_fd.get_msrs(&mut msrs)?;
+
+        let mut state = X86CPUState {
+            regs: vcpu_fd.get_regs()?,
+            sregs: vcpu_fd.get_sregs()?,
+            fpu: vcpu_fd.get_fpu()?,
+            xsave: vcpu_fd.get_xsave()?,
+            xcrs: vcpu_fd.get_xcrs()?,
+            lapic: vcpu_fd.get_lapic()?,
+            mp_state: vcpu_fd.get_mp_state()?,
+            vcpu_events: vcpu_fd.get_vcpu_events()?,
+            nr_msrs: nr_msrs as u32,
+            ..Default::default()
+        };
+        for (dst, src) in state.msrs.iter_mut().zip(msrs.as_slice()) {
+            *dst = *src;
+        }
+
+        Ok(state)
+    }
+
+    /// Set the state of vcpu saved by `get_state`, which must not be running.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - File descriptor of vcpu.
+    /// * `state` - State of vcpu saved in snapshot.
+    pub fn set_state(&self, vcpu_fd: &Arc<VcpuFd>, state: &X86CPUState) -> Result<()> {
+        if state.nr_msrs as usize > MSR_LIST.len() {
+            bail!("Invalid number {} of MSRs in vcpu state", state.nr_msrs);
+        }
+
+        // Extended registers and MSRs are set after special registers, which
+        // enable the features they depend on. Local APIC is set after the
+        // APIC base MSR.
+        vcpu_fd.set_regs(&state.regs)?;
+        vcpu_fd.set_sregs(&state.sregs)?;
+        vcpu_fd.set_fpu(&state.fpu)?;
+        vcpu_fd.set_xsave(&state.xsave)?;
+        vcpu_fd.set_xcrs(&state.xcrs)?;
+        let msrs = Msrs::from_entries(&state.msrs[..state.nr_msrs as usize]);
+        let nr_msrs = vcpu_fd.set_msrs(&msrs)?;
+        if nr_msrs != state.nr_msrs as usize {
+            bail!("Failed to set MSR {:#x} of vcpu", state.msrs[nr_msrs].index);
+        }
+        vcpu_fd.set_lapic(&state.lapic)?;
+        vcpu_fd.set_mp_state(state.mp_state)?;
+        vcpu_fd.set_vcpu_events(&state.vcpu_events)?;
+
+        Ok(())
+    }
+
+    /// Get general purpose registers in the layout of `struct user_regs_struct`,
+    /// which is `pr_reg` of `NT_PRSTATUS` note in ELF core.
+    pub fn get_elf_regs(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<Vec<u64>> {
+        let regs = vcpu_fd.get_regs()?;
+        let sregs = vcpu_fd.get_sregs()?;
+
+        Ok(vec![
+            regs.r15,
+            regs.r14,
+            regs.r13,
+            regs.r12,
+            regs.rbp,
+            regs.rbx,
+            regs.r11,
+            regs.r10,
+            regs.r9,
+            regs.r8,
+            regs.rax,
+            regs.rcx,
+            regs.rdx,
+            regs.rsi,
+            regs.rdi,
+            // orig_rax, which is meaningless out of syscall.
+            0,
+            regs.rip,
+            u64::from(sregs.cs.selector),
+            regs.rflags,
+            regs.rsp,
+            u64::from(sregs.ss.selector),
+            sregs.fs.base,
+            sregs.gs.base,
+            u64::from(sregs.ds.selector),
+            u64::from(sregs.es.selector),
+            u64::from(sregs.fs.selector),
+            u64::from(sregs.gs.selector),
+        ])
+    }
 }
 
 #[cfg(test)]
@@ -418,6 +608,7 @@ mod test {
             padding: 0,
         };
         let cpu_config = X86CPUBootConfig {
+            prot64_mode: true,
             boot_ip: 0,
             boot_sp: 0,
             zero_page: 0x0000_7000,
@@ -483,4 +674,63 @@ mod test {
         //test setup_cpuid function
         assert!(x86_cpu.setup_cpuid(&vcpu).is_ok());
     }
+
+    #[test]
+    fn test_x86_64_cpu_snapshot() {
+        let vm = if let Ok(vm_fd) = Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Arc::new(vm_fd)
+        } else {
+            return;
+        };
+        vm.create_irq_chip().unwrap();
+        let vcpu = Arc::new(vm.create_vcpu(0).unwrap());
+        let mut x86_cpu = X86CPU::new(&vm, 0, 1);
+        let cpu_config = X86CPUBootConfig {
+            prot64_mode: false,
+            ..Default::default()
+        };
+        assert!(x86_cpu.realize(&vcpu, &cpu_config).is_ok());
+        assert!(x86_cpu.setup_sregs(&vcpu).is_ok());
+        assert!(x86_cpu.setup_regs(&vcpu).is_ok());
+        assert!(x86_cpu.setup_fpu(&vcpu).is_ok());
+        assert!(x86_cpu.setup_msrs(&vcpu).is_ok());
+
+        // the state saved in snapshot is what the vcpu holds
+        let state = x86_cpu.get_state(&vcpu).unwrap();
+        assert_eq!(state.regs, vcpu.get_regs().unwrap());
+        assert_eq!(state.sregs, vcpu.get_sregs().unwrap());
+        assert_eq!(state.fpu.fcw, 0x37f);
+        assert_eq!(state.nr_msrs as usize, MSR_LIST.len());
+        assert_eq!(state.msrs[0].index, MSR_LIST[0]);
+    }
+
+    #[test]
+    fn test_x86_64_cpu_reset_vector() {
+        let vm = if let Ok(vm_fd) = Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Arc::new(vm_fd)
+        } else {
+            return;
+        };
+        vm.create_irq_chip().unwrap();
+        let vcpu = Arc::new(vm.create_vcpu(0).unwrap());
+        let mut x86_cpu = X86CPU::new(&vm, 0, 1);
+        let cpu_config = X86CPUBootConfig {
+            prot64_mode: false,
+            ..Default::default()
+        };
+        assert!(x86_cpu.realize(&vcpu, &cpu_config).is_ok());
+
+        assert!(x86_cpu.setup_sregs(&vcpu).is_ok());
+        assert!(x86_cpu.setup_regs(&vcpu).is_ok());
+        let x86_sregs = vcpu.get_sregs().unwrap();
+        let x86_regs = vcpu.get_regs().unwrap();
+        // cpu starts from 0xffff_fff0 in real mode
+        assert_eq!(x86_sregs.cs.selector, 0xf000);
+        assert_eq!(x86_sregs.cs.base + x86_regs.rip, 0xffff_fff0);
+        assert_eq!(x86_sregs.cs.limit, 0xffff);
+        assert_eq!(x86_sregs.ds.base, 0);
+        assert_eq!(x86_sregs.cr0 & 0x8000_0001, 0);
+        assert_eq!(x86_sregs.efer & 0x500, 0);
+        assert_eq!(x86_regs.rflags, 0x0002);
+    }
 }
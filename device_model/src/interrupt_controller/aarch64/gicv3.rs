@@ -349,10 +349,8 @@ impl GICDevice for GICv3 {
 
     fn generate_fdt(&self, fdt: &mut Vec<u8>) -> errors::Result<()> {
         let gic_reg = [
-            self.dist_base,
-            self.dist_size,
-            self.redists_base,
-            self.redists_size,
+            (self.dist_base, self.dist_size),
+            (self.redists_base, self.redists_size),
         ];
         let node = "/intc";
         device_tree::add_sub_node(fdt, node)?;
@@ -363,24 +361,19 @@ impl GICDevice for GICv3 {
         device_tree::set_property_u32(fdt, node, "#address-cells", 0x2)?;
         device_tree::set_property_u32(fdt, node, "#size-cells", 0x2)?;
         device_tree::set_property_u32(fdt, node, "#redistributor-regions", 0x1)?;
-        device_tree::set_property_array_u64(fdt, node, "reg", &gic_reg)?;
-
-        let gic_intr = [
-            device_tree::GIC_FDT_IRQ_TYPE_PPI,
-            0x9,
-            device_tree::IRQ_TYPE_LEVEL_HIGH,
-        ];
-        device_tree::set_property_array_u32(fdt, node, "interrupts", &gic_intr)?;
+        device_tree::set_reg(fdt, node, &gic_reg)?;
+        // Maintenance interrupt.
+        device_tree::set_interrupts_ppi(fdt, node, 0x9, device_tree::IRQ_TYPE_LEVEL_HIGH)?;
 
         if let Some(its) = &self.its_dev {
             device_tree::set_property(fdt, node, "ranges", None)?;
-            let its_reg = [its.msi_base, its.msi_size];
+            let its_reg = [(its.msi_base, its.msi_size)];
             let node = "/intc/its";
             device_tree::add_sub_node(fdt, node)?;
             device_tree::set_property_string(fdt, node, "compatible", "arm,gic-v3-its")?;
             device_tree::set_property(fdt, node, "msi-controller", None)?;
             device_tree::set_property_u32(fdt, node, "phandle", device_tree::GIC_ITS_PHANDLE)?;
-            device_tree::set_property_array_u64(fdt, node, "reg", &its_reg)?;
+            device_tree::set_reg(fdt, node, &its_reg)?;
         }
 
         Ok(())
@@ -31,6 +31,9 @@ pub enum Error {
     EINVAL(std::string::String),
 }
 
+/// Max number of vcpus handled by GICv3.
+pub const GIC_MAX_VCPUS: u64 = 256;
+
 /// Configure a Interrupt controller.
 pub struct GICConfig {
     /// Config GIC version
@@ -51,7 +54,7 @@ impl GICConfig {
             return Err(Error::EINVAL("GIC only support GICv3".to_string()));
         };
 
-        if self.vcpu_count > 256 || self.vcpu_count == 0 {
+        if self.vcpu_count > GIC_MAX_VCPUS || self.vcpu_count == 0 {
             return Err(Error::EINVAL(
                 "GIC only support maximum 256 vcpus".to_string(),
             ));
@@ -113,6 +116,14 @@ impl InterruptController {
         })
     }
 
+    /// Constructs `InterruptController` from a created `GIC` device.
+    #[cfg(test)]
+    pub(crate) fn from_device(
+        gic: Arc<dyn GICDevice + std::marker::Send + std::marker::Sync>,
+    ) -> InterruptController {
+        InterruptController { gic }
+    }
+
     /// Change `InterruptController` lifecycle state to `Stopped`.
     pub fn stop(&self) {
         self.gic
@@ -28,6 +28,8 @@ mod aarch64;
 
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::InterruptController;
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::{GICDevice, GICError, GIC_MAX_VCPUS};
 
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::GICConfig as InterruptControllerConfig;
@@ -0,0 +1,247 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use boot_loader::acpi::{PM1A_EVT_BLK, PM1_CNT_LEN, PM1_EVT_LEN, SCI_IRQ, SLP_TYP_S5};
+use kvm_ioctls::VmFd;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::mmio::errors::{Result, ResultExt};
+
+// Registers and bits below sourced from ACPI Specification 6.0, 4.8.3.
+const PM1_STS_OFFSET: u64 = 0;
+const PM1_EN_OFFSET: u64 = 2;
+const PM1_CNT_OFFSET: u64 = 4;
+const PWRBTN_STS: u16 = 1 << 8;
+const PWRBTN_EN: u16 = 1 << 8;
+const SCI_EN: u16 = 1;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0x7;
+const SLP_EN: u16 = 1 << 13;
+
+/// ACPI fixed hardware of power management, which consists of PM1a event
+/// block and PM1a control block described by FADT.
+pub struct AcpiPm {
+    /// PM1 status register.
+    pm1_sts: u16,
+    /// PM1 enable register.
+    pm1_en: u16,
+    /// PM1 control register.
+    pm1_cnt: u16,
+    /// SCI event file descriptor.
+    interrupt_evt: Option<EventFd>,
+    /// Notified when guest enters S5.
+    shutdown_evt: EventFd,
+}
+
+impl AcpiPm {
+    /// Create a new `AcpiPm`, the hardware is always in ACPI mode.
+    pub fn new() -> Result<Self> {
+        Ok(AcpiPm {
+            pm1_sts: 0,
+            pm1_en: 0,
+            pm1_cnt: SCI_EN,
+            interrupt_evt: None,
+            shutdown_evt: EventFd::new(libc::EFD_NONBLOCK)
+                .chain_err(|| "Failed to create EventFd for ACPI shutdown")?,
+        })
+    }
+
+    /// Register SCI to VM and PM1 registers to IO address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `pm` - The `AcpiPm` to realize.
+    /// * `vm_fd` - File descriptor of VM.
+    /// * `sys_io` - IO address space.
+    pub fn realize(pm: &Arc<Mutex<Self>>, vm_fd: &VmFd, sys_io: &Arc<AddressSpace>) -> Result<()> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).chain_err(|| "Failed to create EventFd")?;
+        vm_fd
+            .register_irqfd(&evt, SCI_IRQ)
+            .chain_err(|| "Failed to register irqfd for SCI")?;
+        pm.lock().unwrap().interrupt_evt = Some(evt);
+
+        let pm_clone = pm.clone();
+        let read_ops = move |data: &mut [u8], addr: GuestAddress, offset: u64| -> bool {
+            pm_clone.lock().unwrap().read(data, addr, offset)
+        };
+        let pm_clone = pm.clone();
+        let write_ops = move |data: &[u8], addr: GuestAddress, offset: u64| -> bool {
+            pm_clone.lock().unwrap().write(data, addr, offset)
+        };
+        let region_ops = RegionOps {
+            read: Arc::new(read_ops),
+            write: Arc::new(write_ops),
+        };
+
+        let size = u64::from(PM1_EVT_LEN) + u64::from(PM1_CNT_LEN);
+        sys_io
+            .root()
+            .add_subregion(
+                Region::init_io_region(size, region_ops),
+                u64::from(PM1A_EVT_BLK),
+            )
+            .chain_err(|| "Failed to register ACPI PM registers")?;
+        Ok(())
+    }
+
+    /// Get the EventFd notified when guest enters S5.
+    pub fn shutdown_evt(&self) -> &EventFd {
+        &self.shutdown_evt
+    }
+
+    /// Press the fixed-feature power button, guest is notified by SCI if
+    /// the button is enabled.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to write interrupt EventFd.
+    pub fn press_power_button(&mut self) -> Result<()> {
+        self.pm1_sts |= PWRBTN_STS;
+        if self.pm1_en & PWRBTN_EN != 0 {
+            if let Some(evt) = &self.interrupt_evt {
+                evt.write(1).chain_err(|| "Failed to inject SCI")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the state of PM1 registers saved in snapshot, which is the
+    /// registers in the layout of IO ports.
+    pub fn get_state(&self) -> Vec<u8> {
+        self.registers().to_vec()
+    }
+
+    /// Set the state of PM1 registers saved by `get_state`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the length of `state` mismatches the registers.
+    pub fn set_state(&mut self, state: &[u8]) -> Result<()> {
+        if state.len() != self.registers().len() {
+            bail!("Invalid length {} of ACPI PM state", state.len());
+        }
+        self.pm1_sts = u16::from_le_bytes([state[0], state[1]]);
+        self.pm1_en = u16::from_le_bytes([state[2], state[3]]);
+        self.pm1_cnt = u16::from_le_bytes([state[4], state[5]]) | SCI_EN;
+        Ok(())
+    }
+
+    fn registers(&self) -> [u8; 6] {
+        let mut regs = [0_u8; 6];
+        regs[0..2].copy_from_slice(&self.pm1_sts.to_le_bytes());
+        regs[2..4].copy_from_slice(&self.pm1_en.to_le_bytes());
+        regs[4..6].copy_from_slice(&self.pm1_cnt.to_le_bytes());
+        regs
+    }
+
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+        let regs = self.registers();
+        let start = offset as usize;
+        match regs.get(start..start + data.len()) {
+            Some(bytes) => {
+                data.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+        if offset as usize + data.len() > self.registers().len() {
+            return false;
+        }
+
+        // Every byte is applied to the 16-bit register it belongs to, so that
+        // both the byte and word accesses work.
+        for (i, byte) in data.iter().enumerate() {
+            let pos = offset + i as u64;
+            let shift = (pos % 2) * 8;
+            let val = u16::from(*byte) << shift;
+            let mask = 0xff_u16 << shift;
+            match pos - pos % 2 {
+                // Status bits are cleared by writing 1.
+                PM1_STS_OFFSET => self.pm1_sts &= !val,
+                PM1_EN_OFFSET => self.pm1_en = (self.pm1_en & !mask) | val,
+                PM1_CNT_OFFSET => self.pm1_cnt = (self.pm1_cnt & !mask) | val | SCI_EN,
+                _ => return false,
+            }
+        }
+
+        if self.pm1_cnt & SLP_EN != 0 {
+            // SLP_EN is write-only.
+            self.pm1_cnt &= !SLP_EN;
+            let slp_typ = (self.pm1_cnt >> SLP_TYP_SHIFT) & SLP_TYP_MASK;
+            if slp_typ == SLP_TYP_S5 {
+                info!("Guest enters ACPI S5 state");
+                if let Err(e) = self.shutdown_evt.write(1) {
+                    error!("Failed to notify ACPI shutdown: {}", e);
+                }
+            } else {
+                warn!("Unsupported sleep type {} is ignored", slp_typ);
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_acpi_pm_registers() {
+        let mut pm = AcpiPm::new().unwrap();
+        let base = GuestAddress(u64::from(PM1A_EVT_BLK));
+
+        // SCI_EN is always set
+        let mut data = [0_u8; 2];
+        assert!(pm.read(&mut data, base, PM1_CNT_OFFSET));
+        assert_eq!(u16::from_le_bytes(data), SCI_EN);
+
+        // power button status is cleared by writing 1
+        pm.interrupt_evt = Some(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        assert!(pm.write(&PWRBTN_EN.to_le_bytes(), base, PM1_EN_OFFSET));
+        pm.press_power_button().unwrap();
+        assert_eq!(pm.interrupt_evt.as_ref().unwrap().read().unwrap(), 1);
+        let mut data = [0_u8; 4];
+        assert!(pm.read(&mut data, base, PM1_STS_OFFSET));
+        assert_eq!(data, [0x00, 0x01, 0x00, 0x01]);
+        assert!(pm.write(&[0x00, 0x01], base, PM1_STS_OFFSET));
+        assert_eq!(pm.pm1_sts, 0);
+        assert_eq!(pm.pm1_en, PWRBTN_EN);
+
+        // sleep type other than S5 doesn't shut down
+        let cnt = (1 << SLP_TYP_SHIFT) | SLP_EN;
+        assert!(pm.write(&cnt.to_le_bytes(), base, PM1_CNT_OFFSET));
+        assert!(pm.shutdown_evt().read().is_err());
+
+        // S5 written by byte access
+        let cnt: u16 = (SLP_TYP_S5 << SLP_TYP_SHIFT) | SLP_EN;
+        assert!(pm.write(&cnt.to_le_bytes()[1..], base, PM1_CNT_OFFSET + 1));
+        assert_eq!(pm.shutdown_evt().read().unwrap(), 1);
+        assert_eq!(pm.pm1_cnt & SLP_EN, 0);
+
+        // out of range
+        assert!(!pm.read(&mut data, base, 4));
+        assert!(!pm.write(&[0; 4], base, 4));
+
+        // the registers are restored from state
+        let mut restored = AcpiPm::new().unwrap();
+        restored.set_state(&pm.get_state()).unwrap();
+        assert_eq!(restored.get_state(), pm.get_state());
+        assert!(restored.set_state(&[0; 4]).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::Arc;
+
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use util::boot_time::{BootMilestone, BootTimes};
+
+use super::super::mmio::errors::{Result, ResultExt};
+
+/// IO port of boot marker, the same as the one of Firecracker, so that
+/// guests integrated with it work unchanged.
+pub const BOOT_MARKER_PORT: u64 = 0x3f0;
+/// Byte written by guest to mark that it has booted.
+pub const BOOT_MARKER_MAGIC: u8 = 123;
+
+/// Write-only IO port which guest writes `BOOT_MARKER_MAGIC` to when it has
+/// booted, such as by init in userspace, and the time is recorded as the
+/// milestone `guest-ready`.
+pub struct BootMarker {
+    /// Boot milestones of VM.
+    boot_times: Arc<BootTimes>,
+}
+
+impl BootMarker {
+    /// Create a new `BootMarker`.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot_times` - Boot milestones of VM, where `guest-ready` is recorded.
+    pub fn new(boot_times: Arc<BootTimes>) -> Self {
+        BootMarker { boot_times }
+    }
+
+    /// Register the boot marker port to IO address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `marker` - The `BootMarker` to realize.
+    /// * `sys_io` - IO address space.
+    pub fn realize(marker: Arc<Self>, sys_io: &Arc<AddressSpace>) -> Result<()> {
+        let read_ops = move |_data: &mut [u8], _addr: GuestAddress, _offset: u64| -> bool { false };
+        let write_ops =
+            move |data: &[u8], _addr: GuestAddress, _offset: u64| -> bool { marker.write(data) };
+        let region_ops = RegionOps {
+            read: Arc::new(read_ops),
+            write: Arc::new(write_ops),
+        };
+
+        sys_io
+            .root()
+            .add_subregion(Region::init_io_region(1, region_ops), BOOT_MARKER_PORT)
+            .chain_err(|| "Failed to register boot marker port")?;
+        Ok(())
+    }
+
+    fn write(&self, data: &[u8]) -> bool {
+        if data != [BOOT_MARKER_MAGIC] {
+            return false;
+        }
+        self.boot_times.record(BootMilestone::GuestReady);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_boot_marker() {
+        let boot_times = Arc::new(BootTimes::new());
+        let marker = BootMarker::new(boot_times.clone());
+
+        // Other values are ignored.
+        assert!(!marker.write(&[0]));
+        assert!(!marker.write(&[BOOT_MARKER_MAGIC, 0]));
+        assert!(boot_times.snapshot().is_empty());
+
+        assert!(marker.write(&[BOOT_MARKER_MAGIC]));
+        let milestones = boot_times.snapshot();
+        assert_eq!(milestones.len(), 1);
+        assert_eq!(milestones[0].milestone, BootMilestone::GuestReady);
+
+        // The time of first write is kept.
+        assert!(marker.write(&[BOOT_MARKER_MAGIC]));
+        assert_eq!(boot_times.snapshot(), milestones);
+    }
+}
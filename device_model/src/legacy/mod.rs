@@ -12,13 +12,17 @@
 
 //! # Legacy
 //!
-//! This mod emulate legacy devices include RTC and Serial.
+//! This mod emulate legacy devices include RTC, GPIO, Serial and ACPI power management.
 //!
 //! ## Design
 //!
 //! This module offers support for:
 //! 1. Pl031 device, Arm PrimeCell Real Time Clock.
 //! 2. Serial device, Serial UART.
+//! 3. ACPI PM1 registers, used for guest shutdown and power button on x86_64.
+//! 4. Pl061 device, Arm PrimeCell GPIO, used for power button on aarch64.
+//! 5. Reset control register and i8042 reset line, used for guest reboot on x86_64.
+//! 6. Boot marker, the IO port guest writes when it has booted on x86_64.
 //!
 //! ## Platform Support
 //!
@@ -27,7 +31,23 @@
 mod serial;
 pub use self::serial::Serial;
 
+#[cfg(target_arch = "x86_64")]
+mod acpi_pm;
+#[cfg(target_arch = "x86_64")]
+mod boot_marker;
+#[cfg(target_arch = "x86_64")]
+pub use self::acpi_pm::AcpiPm;
+#[cfg(target_arch = "x86_64")]
+pub use self::boot_marker::{BootMarker, BOOT_MARKER_MAGIC, BOOT_MARKER_PORT};
+#[cfg(target_arch = "x86_64")]
+mod reset_ctrl;
+#[cfg(target_arch = "x86_64")]
+pub use self::reset_ctrl::ResetCtrl;
 #[cfg(target_arch = "aarch64")]
 mod pl031;
 #[cfg(target_arch = "aarch64")]
-pub use self::pl031::PL031;
+pub use self::pl031::{PL031, RTC_CHANGE_INTERVAL};
+#[cfg(target_arch = "aarch64")]
+mod pl061;
+#[cfg(target_arch = "aarch64")]
+pub use self::pl061::{GPIO_POWER_BUTTON_PIN, PL061};
@@ -10,7 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use address_space::GuestAddress;
 use byteorder::{ByteOrder, LittleEndian};
@@ -40,6 +40,21 @@ const RTC_ICR: u64 = 0x1c;
 /// Peripheral ID registers, default value.
 const RTC_PERIPHERAL_ID: [u8; 8] = [0x31, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
 
+/// Minimum interval between two notifications of RTC change.
+pub const RTC_CHANGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Callback notified of the offset in seconds of guest RTC from host clock,
+/// when guest sets the RTC.
+pub type RtcChangeCallback = dyn Fn(i64) + Send;
+
+/// Get the seconds of host clock since 1970-01-01 00:00:00.
+fn host_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time wrong")
+        .as_secs() as i64
+}
+
 /// Pl032 structure.
 pub struct PL031 {
     /// Match register value.
@@ -50,12 +65,16 @@ pub struct PL031 {
     imsr: u32,
     /// Raw Interrupt Status register value.
     risr: u32,
-    /// The duplicate of Load register value.
-    tick_offset: u32,
-    /// Record the real time.
-    base_time: Instant,
+    /// Offset in seconds of guest RTC from host clock, set by guest.
+    offset: i64,
     /// Interrupt eventfd.
     interrupt_evt: Option<EventFd>,
+    /// Callback notified when guest sets the RTC.
+    change_cb: Option<Box<RtcChangeCallback>>,
+    /// Time of the last notification of RTC change.
+    last_change: Option<Instant>,
+    /// Offset set by guest but not notified yet because of rate limit.
+    pending_change: Option<i64>,
 }
 
 impl PL031 {
@@ -65,15 +84,55 @@ impl PL031 {
             lr: 0,
             imsr: 0,
             risr: 0,
-            tick_offset: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("time wrong")
-                .as_secs() as u32, // since 1970-01-01 00:00:00,it never cause overflow.
-            base_time: Instant::now(),
+            offset: 0,
             interrupt_evt: None,
+            change_cb: None,
+            last_change: None,
+            pending_change: None,
         }
     }
 
+    /// Set the callback notified when guest sets the RTC, the notifications
+    /// are limited to one per `RTC_CHANGE_INTERVAL`.
+    ///
+    /// # Arguments
+    ///
+    /// * `cb` - Callback called with the offset of guest RTC from host clock.
+    pub fn set_change_cb(&mut self, cb: Box<RtcChangeCallback>) {
+        self.change_cb = Some(cb);
+    }
+
+    /// Notify the RTC change held by rate limit, if `RTC_CHANGE_INTERVAL`
+    /// has passed since the last notification.
+    pub fn flush_rtc_change(&mut self) {
+        self.flush_rtc_change_at(Instant::now());
+    }
+
+    fn flush_rtc_change_at(&mut self, now: Instant) {
+        if let Some(last) = self.last_change {
+            if now.saturating_duration_since(last) < RTC_CHANGE_INTERVAL {
+                return;
+            }
+        }
+        if let Some(offset) = self.pending_change.take() {
+            if let Some(cb) = &self.change_cb {
+                cb(offset);
+            }
+            self.last_change = Some(now);
+        }
+    }
+
+    /// Set the RTC by guest, it's kept as the offset from host clock.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Seconds since 1970-01-01 00:00:00 set by guest.
+    fn set_time(&mut self, value: u32) {
+        self.offset = i64::from(value) - host_time();
+        self.pending_change = Some(self.offset);
+        self.flush_rtc_change();
+    }
+
     /// Send interrupt to guest.
     fn interrupt(&self) {
         if let Some(evt) = &self.interrupt_evt {
@@ -81,9 +140,9 @@ impl PL031 {
         }
     }
 
-    /// Get current clock value.
+    /// Get current clock value, the register is 32 bits so it wraps.
     fn get_current_value(&self) -> u32 {
-        self.base_time.elapsed().as_secs() as u32 + self.tick_offset
+        (host_time() + self.offset) as u32
     }
 }
 
@@ -137,8 +196,7 @@ impl DeviceOps for PL031 {
             }
             RTC_LR => {
                 self.lr = value;
-                self.tick_offset = value;
-                self.base_time = Instant::now();
+                self.set_time(value);
             }
             RTC_IMSC => {
                 self.imsr = value & 1;
@@ -176,3 +234,70 @@ impl MmioDeviceOps for PL031 {
         DeviceType::RTC
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn read_time(rtc: &mut PL031) -> i64 {
+        let mut data = [0_u8; 4];
+        assert!(rtc.read(&mut data, GuestAddress(0), RTC_DR));
+        i64::from(LittleEndian::read_u32(&data))
+    }
+
+    fn write_time(rtc: &mut PL031, value: i64) {
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, value as u32);
+        assert!(rtc.write(&data, GuestAddress(0), RTC_LR));
+    }
+
+    #[test]
+    fn test_pl031_offset() {
+        let mut rtc = PL031::new();
+        assert!((read_time(&mut rtc) - host_time()).abs() <= 1);
+
+        // Guest's setting sticks across reads.
+        write_time(&mut rtc, host_time() + 3600);
+        assert!((rtc.offset - 3600).abs() <= 1);
+        assert!((read_time(&mut rtc) - host_time() - 3600).abs() <= 1);
+        assert!((read_time(&mut rtc) - host_time() - 3600).abs() <= 1);
+
+        write_time(&mut rtc, host_time() - 7200);
+        assert!((rtc.offset + 7200).abs() <= 1);
+        assert!((read_time(&mut rtc) - host_time() + 7200).abs() <= 1);
+    }
+
+    #[test]
+    fn test_pl031_change_event() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_clone = changes.clone();
+        let mut rtc = PL031::new();
+        rtc.set_change_cb(Box::new(move |offset| {
+            changes_clone.lock().unwrap().push(offset);
+        }));
+
+        // The first change is notified at once.
+        write_time(&mut rtc, host_time() + 100);
+        assert_eq!(changes.lock().unwrap().len(), 1);
+        assert!((changes.lock().unwrap()[0] - 100).abs() <= 1);
+
+        // Changes hammered by guest are held, only the latest one is notified
+        // after the interval.
+        for i in 1..10 {
+            write_time(&mut rtc, host_time() + 100 + i);
+        }
+        assert_eq!(changes.lock().unwrap().len(), 1);
+        let last = rtc.last_change.unwrap();
+        rtc.flush_rtc_change_at(last + RTC_CHANGE_INTERVAL / 2);
+        assert_eq!(changes.lock().unwrap().len(), 1);
+        rtc.flush_rtc_change_at(last + RTC_CHANGE_INTERVAL);
+        assert_eq!(changes.lock().unwrap().len(), 2);
+        assert!((changes.lock().unwrap()[1] - 109).abs() <= 1);
+
+        // Nothing is notified if there is no pending change.
+        rtc.flush_rtc_change_at(last + RTC_CHANGE_INTERVAL * 3);
+        assert_eq!(changes.lock().unwrap().len(), 2);
+    }
+}
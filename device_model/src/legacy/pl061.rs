@@ -0,0 +1,244 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use address_space::GuestAddress;
+use byteorder::{ByteOrder, LittleEndian};
+use kvm_ioctls::VmFd;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::mmio::errors::{Result, ResultExt};
+use super::super::mmio::{DeviceOps, DeviceResource, DeviceType, MmioDeviceOps};
+
+/// Registers for pl061 from ARM PrimeCell General Purpose Input/Output Technical
+/// Reference Manual.
+/// Data Register, bits [9:2] of the offset mask the accessed pins.
+const GPIO_DATA_END: u64 = 0x400;
+/// Direction Register.
+const GPIO_DIR: u64 = 0x400;
+/// Interrupt Sense Register.
+const GPIO_IS: u64 = 0x404;
+/// Interrupt Both Edges Register.
+const GPIO_IBE: u64 = 0x408;
+/// Interrupt Event Register.
+const GPIO_IEV: u64 = 0x40c;
+/// Interrupt Mask Register.
+const GPIO_IE: u64 = 0x410;
+/// Raw Interrupt Status Register.
+const GPIO_RIS: u64 = 0x414;
+/// Masked Interrupt Status Register.
+const GPIO_MIS: u64 = 0x418;
+/// Interrupt Clear Register.
+const GPIO_IC: u64 = 0x41c;
+/// Mode Control Select Register.
+const GPIO_AFSEL: u64 = 0x420;
+/// Peripheral ID registers, default value.
+const GPIO_PERIPHERAL_ID: [u8; 8] = [0x61, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// Pin of the power button, described by `gpio-keys` in device tree.
+pub const GPIO_POWER_BUTTON_PIN: u8 = 3;
+
+/// Pl061 structure, it's used to model the power button for guest.
+#[derive(Default)]
+pub struct PL061 {
+    /// Data register value of the output pins.
+    data: u8,
+    /// Level of the input pins.
+    input: u8,
+    /// Direction register value, 1 means output.
+    dir: u8,
+    /// Interrupt Sense register value, 1 means level sensitive.
+    is: u8,
+    /// Interrupt Both Edges register value.
+    ibe: u8,
+    /// Interrupt Event register value, 1 means rising edge or high level.
+    iev: u8,
+    /// Interrupt Mask register value.
+    ie: u8,
+    /// Raw Interrupt Status register value.
+    ris: u8,
+    /// Mode Control Select register value.
+    afsel: u8,
+    /// Interrupt eventfd.
+    interrupt_evt: Option<EventFd>,
+}
+
+impl PL061 {
+    pub fn new() -> Self {
+        PL061::default()
+    }
+
+    /// Set the level of an input pin, which raises interrupt to guest if the
+    /// change is an enabled interrupt event of the pin.
+    ///
+    /// # Arguments
+    ///
+    /// * `pin` - Index of pin, from 0 to 7.
+    /// * `level` - High or low level of pin.
+    pub fn set_input(&mut self, pin: u8, level: bool) {
+        let mask = 1_u8 << pin;
+        let old = self.input & mask != 0;
+        if level {
+            self.input |= mask;
+        } else {
+            self.input &= !mask;
+        }
+
+        let triggered = if self.is & mask != 0 {
+            level == (self.iev & mask != 0)
+        } else if self.ibe & mask != 0 {
+            old != level
+        } else {
+            old != level && level == (self.iev & mask != 0)
+        };
+        if triggered && self.dir & mask == 0 {
+            self.ris |= mask;
+            self.update_interrupt();
+        }
+    }
+
+    /// Send interrupt to guest if any enabled interrupt is pending.
+    fn update_interrupt(&self) {
+        if self.ris & self.ie == 0 {
+            return;
+        }
+        if let Some(evt) = &self.interrupt_evt {
+            let _ = evt.write(1);
+        }
+    }
+
+    /// Level of pins, output pins come from data register.
+    fn pins(&self) -> u8 {
+        (self.data & self.dir) | (self.input & !self.dir)
+    }
+}
+
+impl DeviceOps for PL061 {
+    /// Read data from registers by guest.
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+        if data.is_empty() {
+            return false;
+        }
+        if offset >= 0xFE0 && offset < 0x1000 {
+            data[0] = GPIO_PERIPHERAL_ID[((offset - 0xFE0) >> 2) as usize];
+            return true;
+        }
+
+        let value = match offset {
+            o if o < GPIO_DATA_END => self.pins() & ((o >> 2) as u8),
+            GPIO_DIR => self.dir,
+            GPIO_IS => self.is,
+            GPIO_IBE => self.ibe,
+            GPIO_IEV => self.iev,
+            GPIO_IE => self.ie,
+            GPIO_RIS => self.ris,
+            GPIO_MIS => self.ris & self.ie,
+            GPIO_AFSEL => self.afsel,
+            _ => 0,
+        };
+        data[0] = value;
+        for byte in data.iter_mut().skip(1) {
+            *byte = 0;
+        }
+
+        true
+    }
+
+    /// Write data to registers by guest.
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+        let value = match data.len() {
+            0 => return false,
+            1 => data[0],
+            _ => LittleEndian::read_u16(data) as u8,
+        };
+
+        match offset {
+            o if o < GPIO_DATA_END => {
+                let mask = ((o >> 2) as u8) & self.dir;
+                self.data = (self.data & !mask) | (value & mask);
+            }
+            GPIO_DIR => self.dir = value,
+            GPIO_IS => self.is = value,
+            GPIO_IBE => self.ibe = value,
+            GPIO_IEV => self.iev = value,
+            GPIO_IE => {
+                self.ie = value;
+                self.update_interrupt();
+            }
+            GPIO_IC => self.ris &= !value,
+            GPIO_AFSEL => self.afsel = value,
+            _ => {}
+        }
+
+        true
+    }
+}
+
+impl MmioDeviceOps for PL061 {
+    /// Realize GPIO device when VM starting.
+    fn realize(&mut self, vm_fd: &VmFd, resource: DeviceResource) -> Result<()> {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).chain_err(|| "Failed to create EventFd")?;
+        vm_fd
+            .register_irqfd(&evt, resource.irq)
+            .chain_err(|| "Failed to register irqfd")?;
+        self.interrupt_evt = Some(evt);
+        Ok(())
+    }
+
+    /// Get device type.
+    fn get_type(&self) -> DeviceType {
+        DeviceType::GPIO
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pl061_power_button() {
+        let mut gpio = PL061::new();
+        gpio.interrupt_evt = Some(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let base = GuestAddress(0);
+        let pin_mask = 1_u8 << GPIO_POWER_BUTTON_PIN;
+
+        // Guest enables the both edges interrupt of the pin, as gpio-keys
+        // driver does.
+        assert!(gpio.write(&[pin_mask], base, GPIO_IBE));
+        assert!(gpio.write(&[pin_mask], base, GPIO_IE));
+        assert!(gpio.interrupt_evt.as_ref().unwrap().read().is_err());
+
+        gpio.set_input(GPIO_POWER_BUTTON_PIN, true);
+        assert_eq!(gpio.interrupt_evt.as_ref().unwrap().read().unwrap(), 1);
+        let mut data = [0_u8; 4];
+        assert!(gpio.read(&mut data, base, GPIO_MIS));
+        assert_eq!(data[0], pin_mask);
+        // Data register is read by the pin mask in address.
+        assert!(gpio.read(&mut data, base, u64::from(pin_mask) << 2));
+        assert_eq!(data[0], pin_mask);
+        assert!(gpio.read(&mut data, base, 0x3fc & !(u64::from(pin_mask) << 2)));
+        assert_eq!(data[0], 0);
+
+        assert!(gpio.write(&[pin_mask], base, GPIO_IC));
+        assert!(gpio.read(&mut data, base, GPIO_RIS));
+        assert_eq!(data[0], 0);
+
+        // Masked interrupt isn't sent to guest.
+        assert!(gpio.write(&[0], base, GPIO_IE));
+        gpio.set_input(GPIO_POWER_BUTTON_PIN, false);
+        assert!(gpio.interrupt_evt.as_ref().unwrap().read().is_err());
+        assert!(gpio.read(&mut data, base, GPIO_RIS));
+        assert_eq!(data[0], pin_mask);
+
+        assert!(gpio.read(&mut data, base, 0xFE0));
+        assert_eq!(data[0], 0x61);
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::mmio::errors::{Result, ResultExt};
+
+/// Reset control register of PIIX3 and ICH9 chipsets.
+const RST_CNT_PORT: u64 = 0xcf9;
+/// Command port of i8042 keyboard controller.
+const I8042_CMD_PORT: u64 = 0x64;
+// Bits of reset control register, sourced from Intel ICH9 datasheet, 13.7.5.
+const SYS_RST: u8 = 1 << 1;
+const RST_CPU: u8 = 1 << 2;
+// i8042 commands 0xf0~0xff pulse the output lines whose bits are clear, and
+// bit 0 is the line resetting CPU.
+const I8042_CMD_PULSE: u8 = 0xf0;
+const I8042_RESET_LINE: u8 = 1;
+
+/// Decode the byte written by guest to `port`.
+///
+/// Return true if guest requests to reset the machine.
+fn is_reset_request(port: u64, value: u8) -> bool {
+    match port {
+        RST_CNT_PORT => value & RST_CPU != 0,
+        I8042_CMD_PORT => {
+            value & I8042_CMD_PULSE == I8042_CMD_PULSE && value & I8042_RESET_LINE == 0
+        }
+        _ => false,
+    }
+}
+
+/// IO ports which guest writes to reboot on x86_64, which are the reset
+/// control register at 0xcf9 and the reset line of i8042 at 0x64. The i8042
+/// isn't emulated otherwise, it always reports an empty input buffer so that
+/// guest doesn't wait for it.
+pub struct ResetCtrl {
+    /// Reset control register, only `SYS_RST` is latched.
+    rst_cnt: u8,
+    /// Notified when guest requests to reset.
+    reset_evt: EventFd,
+}
+
+impl ResetCtrl {
+    /// Create a new `ResetCtrl`.
+    pub fn new() -> Result<Self> {
+        Ok(ResetCtrl {
+            rst_cnt: 0,
+            reset_evt: EventFd::new(libc::EFD_NONBLOCK)
+                .chain_err(|| "Failed to create EventFd for guest reset")?,
+        })
+    }
+
+    /// Register the reset control register and the i8042 command port to
+    /// IO address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctrl` - The `ResetCtrl` to realize.
+    /// * `sys_io` - IO address space.
+    pub fn realize(ctrl: &Arc<Mutex<Self>>, sys_io: &Arc<AddressSpace>) -> Result<()> {
+        for port in [RST_CNT_PORT, I8042_CMD_PORT].iter() {
+            let port = *port;
+            let ctrl_clone = ctrl.clone();
+            let read_ops = move |data: &mut [u8], _addr: GuestAddress, _offset: u64| -> bool {
+                ctrl_clone.lock().unwrap().read(port, data)
+            };
+            let ctrl_clone = ctrl.clone();
+            let write_ops = move |data: &[u8], _addr: GuestAddress, _offset: u64| -> bool {
+                ctrl_clone.lock().unwrap().write(port, data)
+            };
+            let region_ops = RegionOps {
+                read: Arc::new(read_ops),
+                write: Arc::new(write_ops),
+            };
+
+            sys_io
+                .root()
+                .add_subregion(Region::init_io_region(1, region_ops), port)
+                .chain_err(|| format!("Failed to register reset port {:#x}", port))?;
+        }
+        Ok(())
+    }
+
+    /// Get the EventFd notified when guest requests to reset.
+    pub fn reset_evt(&self) -> &EventFd {
+        &self.reset_evt
+    }
+
+    fn read(&mut self, port: u64, data: &mut [u8]) -> bool {
+        if data.len() != 1 {
+            return false;
+        }
+        data[0] = match port {
+            RST_CNT_PORT => self.rst_cnt,
+            // Status of i8042: both input and output buffers are empty.
+            _ => 0,
+        };
+        true
+    }
+
+    fn write(&mut self, port: u64, data: &[u8]) -> bool {
+        if data.len() != 1 {
+            return false;
+        }
+        if port == RST_CNT_PORT {
+            self.rst_cnt = data[0] & SYS_RST;
+        }
+        if is_reset_request(port, data[0]) {
+            info!(
+                "Guest requests to reset by writing {:#x} to port {:#x}",
+                data[0], port
+            );
+            if let Err(e) = self.reset_evt.write(1) {
+                error!("Failed to notify guest reset: {}", e);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reset_request_decoding() {
+        // Reset control register resets CPU, with or without SYS_RST.
+        assert!(is_reset_request(RST_CNT_PORT, 0x04));
+        assert!(is_reset_request(RST_CNT_PORT, 0x06));
+        assert!(is_reset_request(RST_CNT_PORT, 0x0e));
+        assert!(!is_reset_request(RST_CNT_PORT, 0x00));
+        assert!(!is_reset_request(RST_CNT_PORT, 0x02));
+
+        // Only pulsing the reset line of i8042 resets.
+        assert!(is_reset_request(I8042_CMD_PORT, 0xfe));
+        assert!(is_reset_request(I8042_CMD_PORT, 0xf0));
+        assert!(!is_reset_request(I8042_CMD_PORT, 0xff));
+        assert!(!is_reset_request(I8042_CMD_PORT, 0xae));
+        assert!(!is_reset_request(I8042_CMD_PORT, 0x04));
+
+        // Other ports never reset.
+        assert!(!is_reset_request(0x60, 0xfe));
+        assert!(!is_reset_request(0xcf8, 0x04));
+    }
+
+    #[test]
+    fn test_reset_ctrl_ports() {
+        let mut ctrl = ResetCtrl::new().unwrap();
+        let mut data = [0xff_u8; 1];
+
+        // SYS_RST is latched, and writing it alone doesn't reset.
+        assert!(ctrl.write(RST_CNT_PORT, &[SYS_RST]));
+        assert!(ctrl.reset_evt().read().is_err());
+        assert!(ctrl.read(RST_CNT_PORT, &mut data));
+        assert_eq!(data[0], SYS_RST);
+
+        // RST_CPU is write-only.
+        assert!(ctrl.write(RST_CNT_PORT, &[SYS_RST | RST_CPU]));
+        assert_eq!(ctrl.reset_evt().read().unwrap(), 1);
+        assert!(ctrl.read(RST_CNT_PORT, &mut data));
+        assert_eq!(data[0], SYS_RST);
+
+        // i8042 is always ready for commands.
+        assert!(ctrl.read(I8042_CMD_PORT, &mut data));
+        assert_eq!(data[0], 0);
+        assert!(ctrl.write(I8042_CMD_PORT, &[0xad]));
+        assert!(ctrl.reset_evt().read().is_err());
+        assert!(ctrl.write(I8042_CMD_PORT, &[0xfe]));
+        assert_eq!(ctrl.reset_evt().read().unwrap(), 1);
+
+        // Only byte access is accepted.
+        assert!(!ctrl.read(RST_CNT_PORT, &mut [0_u8; 2]));
+        assert!(!ctrl.write(I8042_CMD_PORT, &[0xfe, 0x00]));
+        assert!(ctrl.reset_evt().read().is_err());
+    }
+}
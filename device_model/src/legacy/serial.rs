@@ -12,13 +12,13 @@
 
 use std::collections::VecDeque;
 use std::io;
-use std::os::unix::io::RawFd;
 use std::sync::{Arc, Mutex};
 
 use address_space::GuestAddress;
 use kvm_ioctls::VmFd;
-use util::epoll_context::{EventNotifier, EventNotifierHelper, NotifierOperation};
-use vmm_sys_util::{epoll::EventSet, eventfd::EventFd, terminal::Terminal};
+use machine_manager::chardev::{Chardev, ChardevWriter};
+use util::byte_code::ByteCode;
+use vmm_sys_util::eventfd::EventFd;
 
 use super::super::mmio::errors::{Result, ResultExt};
 use super::super::mmio::{DeviceOps, DeviceResource, DeviceType, MmioDeviceOps};
@@ -73,6 +73,26 @@ pub struct Serial {
     output: Option<Box<dyn io::Write + Send + Sync>>,
 }
 
+/// State of serial saved in snapshot, which is followed by the bytes in
+/// receiver buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SerialState {
+    ier: u8,
+    iir: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    scr: u8,
+    div: u16,
+    thr_pending: u32,
+    /// Number of bytes in receiver buffer.
+    rbr_len: u32,
+}
+
+impl ByteCode for SerialState {}
+
 impl Serial {
     /// Create a new `Serial` instance with default parameters.
     pub fn new() -> Self {
@@ -92,6 +112,29 @@ impl Serial {
         }
     }
 
+    /// Use `chardev` as the backend of serial, what guest writes is output
+    /// to it, and its input is received by serial.
+    ///
+    /// # Arguments
+    ///
+    /// * `serial` - Serial instance.
+    /// * `chardev` - Chardev as the backend.
+    pub fn set_chardev(serial: &Arc<Mutex<Self>>, chardev: &Arc<Mutex<Chardev>>) {
+        let cloned_serial = serial.clone();
+        chardev
+            .lock()
+            .unwrap()
+            .set_receiver(Arc::new(move |data: &[u8]| {
+                if let Err(e) = cloned_serial.lock().unwrap().receive(data) {
+                    error!(
+                        "Failed to receive input of serial: {}",
+                        error_chain::ChainedError::display_chain(&e)
+                    );
+                }
+            }));
+        serial.lock().unwrap().output = Some(Box::new(ChardevWriter::new(chardev.clone())));
+    }
+
     /// Set EventFd for serial.
     ///
     /// # Errors
@@ -326,7 +369,7 @@ impl DeviceOps for Serial {
 }
 impl MmioDeviceOps for Serial {
     /// Realize a serial for VM.
-    /// * Create a new output component.
+    /// * Create the output to stdout if serial has no chardev.
     /// * Register DeviceResource IRQ to VM.
     /// * Set interrupt_evt component.
     ///
@@ -341,7 +384,9 @@ impl MmioDeviceOps for Serial {
     /// * fail to register.
     /// * fail to create a new EventFd.
     fn realize(&mut self, vm_fd: &VmFd, resource: DeviceResource) -> Result<()> {
-        self.output = Some(Box::new(std::io::stdout()));
+        if self.output.is_none() {
+            self.output = Some(Box::new(std::io::stdout()));
+        }
 
         match EventFd::new(libc::EFD_NONBLOCK) {
             Ok(evt) => {
@@ -360,45 +405,60 @@ impl MmioDeviceOps for Serial {
     fn get_type(&self) -> DeviceType {
         DeviceType::SERIAL
     }
-}
 
-impl EventNotifierHelper for Serial {
-    /// Add serial to `EventNotifier`.
-    ///
-    /// # Arguments
-    ///
-    /// * `serial` - Serial instance.
-    fn internal_notifiers(serial: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
-        let mut notifiers = Vec::new();
-
-        let mut handlers = Vec::new();
-        let handler: Box<dyn Fn(EventSet, RawFd) -> Option<Vec<EventNotifier>>> =
-            Box::new(move |_, _| {
-                let mut out = [0_u8; 64];
-                if let Ok(count) = std::io::stdin().lock().read_raw(&mut out) {
-                    let _ = serial.lock().unwrap().receive(&out[..count]);
-                }
-                None
-            });
-
-        handlers.push(Arc::new(Mutex::new(handler)));
+    /// Get the state of registers and receiver buffer.
+    fn get_state(&self) -> Result<Vec<u8>> {
+        let state = SerialState {
+            ier: self.ier,
+            iir: self.iir,
+            lcr: self.lcr,
+            mcr: self.mcr,
+            lsr: self.lsr,
+            msr: self.msr,
+            scr: self.scr,
+            div: self.div,
+            thr_pending: self.thr_pending,
+            rbr_len: self.rbr.len() as u32,
+        };
+        let mut bytes = state.as_bytes().to_vec();
+        bytes.extend(self.rbr.iter());
+        Ok(bytes)
+    }
 
-        let notifier = EventNotifier::new(
-            NotifierOperation::AddShared,
-            libc::STDIN_FILENO,
-            None,
-            EventSet::IN,
-            handlers,
-        );
+    /// Set the state of registers and receiver buffer.
+    fn set_state(&mut self, bytes: &[u8]) -> Result<()> {
+        let len = std::mem::size_of::<SerialState>();
+        if bytes.len() < len {
+            bail!("Invalid length {} of serial state", bytes.len());
+        }
+        let mut state = SerialState::default();
+        state.as_mut_bytes().copy_from_slice(&bytes[..len]);
+        if bytes.len() - len != state.rbr_len as usize {
+            bail!(
+                "Serial state has {} bytes in receiver buffer, but {} bytes are found",
+                state.rbr_len,
+                bytes.len() - len
+            );
+        }
 
-        notifiers.push(notifier);
-        notifiers
+        self.ier = state.ier;
+        self.iir = state.iir;
+        self.lcr = state.lcr;
+        self.mcr = state.mcr;
+        self.lsr = state.lsr;
+        self.msr = state.msr;
+        self.scr = state.scr;
+        self.div = state.div;
+        self.thr_pending = state.thr_pending;
+        self.rbr = bytes[len..].iter().copied().collect();
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use machine_manager::config::{ChardevConfig, ChardevType};
 
     #[test]
     fn test_methods_of_serial() {
@@ -455,4 +515,55 @@ mod test {
         assert_eq!(usart.read_internal(5), 0x60);
         assert_eq!(usart.read_internal(6), 0xf0);
     }
+
+    #[test]
+    fn test_serial_snapshot() {
+        let mut usart = Serial::new();
+        usart.interrupt_evt = Some(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+
+        // test state, which is followed by bytes received
+        usart.receive(&[0x04, 0x05]).unwrap();
+        let bytes = usart.get_state().unwrap();
+        let len = std::mem::size_of::<SerialState>();
+        let state = SerialState::from_bytes(&bytes[..len]).unwrap();
+        assert_eq!(state.div, usart.div);
+        assert_eq!(state.rbr_len, 2);
+        assert_eq!(&bytes[len..], &[0x04, 0x05]);
+
+        // the state is restored by a new serial
+        let mut restored = Serial::new();
+        restored.set_state(&bytes).unwrap();
+        assert_eq!(restored.get_state().unwrap(), bytes);
+        assert_eq!(restored.rbr, usart.rbr);
+        assert_eq!(
+            restored
+                .set_state(&bytes[..len + 1])
+                .unwrap_err()
+                .to_string(),
+            "Serial state has 2 bytes in receiver buffer, but 1 bytes are found"
+        );
+    }
+
+    #[test]
+    fn test_serial_chardev() {
+        let path = "test_serial_chardev.log";
+        let _ = std::fs::remove_file(path);
+        let chardev = Arc::new(Mutex::new(Chardev::new(&ChardevConfig {
+            id: "chr0".to_string(),
+            backend: ChardevType::File {
+                path: path.to_string(),
+            },
+        })));
+        chardev.lock().unwrap().realize().unwrap();
+        let serial = Arc::new(Mutex::new(Serial::new()));
+        Serial::set_chardev(&serial, &chardev);
+
+        // What guest writes to THR is output to chardev.
+        for byte in b"hello\n".iter() {
+            serial.lock().unwrap().write_internal(0, *byte).unwrap();
+        }
+        chardev.lock().unwrap().cleanup();
+        assert_eq!(std::fs::read(path).unwrap(), b"hello\n");
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -46,7 +46,12 @@ mod mmio;
 mod virtio;
 
 pub use error_chain::*;
-pub use micro_vm::{cmdline, main_loop::MainLoop, micro_syscall::register_seccomp, LightMachine};
+pub use micro_vm::{
+    cmdline,
+    main_loop::MainLoop,
+    micro_syscall::{register_seccomp, SeccompFragment, SeccompMode, SeccompPolicy, ThreadRole},
+    LightMachine,
+};
 
 use address_space::GuestAddress;
 /// Basic device operations
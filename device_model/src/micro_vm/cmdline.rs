@@ -12,12 +12,16 @@
 
 use std::fs::File;
 use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
 
 use error_chain::bail;
-use machine_manager::config::VmConfig;
-use machine_manager::socket::SocketType;
+use machine_manager::config::{ChardevType, VmConfig};
+use machine_manager::machine::RebootAction;
+use machine_manager::socket::{AccessPolicy, SocketType};
 use util::arg_parser::{Arg, ArgMatches, ArgParser};
 
+use super::migration::parse_uri;
+use super::snapshot::read_config;
 use crate::errors::{Result, ResultExt};
 
 // Read the programe version in `Cargo.toml`.
@@ -78,15 +82,22 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         .arg(
             Arg::with_name("name")
                 .long("name")
-                .value_name("vm_name")
+                .value_name("[guest=]vm_name")
                 .help("set the name of the guest.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("uuid")
+                .long("uuid")
+                .value_name("uuid[,expose=cmdline]")
+                .help("set the uuid of the guest, a random one is generated if it's not set.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("smp")
                 .long("smp")
-                .value_name("[cpus=]n")
-                .help("set the number of CPUs to 'n' (default: 1)")
+                .value_name("[cpus=]n[,maxcpus=m]")
+                .help("set the number of CPUs to 'n' (default: 1), and the max number of possible CPUs to 'm' (default: n)")
                 .takes_value(true),
         )
         .arg(
@@ -103,6 +114,13 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                 .help("Sets a config file for vmm.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("dump-config")
+                .long("dump-config")
+                .help("print the config merged from config file and cmdline as json, then exit")
+                .takes_value(false)
+                .required(false),
+        )
         .arg(
             Arg::with_name("kernel")
                 .long("kernel")
@@ -115,7 +133,7 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                 .multiple(true)
                 .long("append")
                 .value_name("command-line parameters")
-                .help("use 'cmdline' as kernel command line")
+                .help("use 'cmdline' as kernel command line, fragments of repeated -append are concatenated")
                 .takes_values(true),
         )
         .arg(
@@ -125,19 +143,36 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                 .help("use 'initrd-file' as initial ram disk")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("dtb")
+                .long("dtb")
+                .value_name("dtb_path")
+                .help("use 'dtb_path' as device tree blob instead of the generated one (aarch64 only)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bios")
+                .long("bios")
+                .value_name("bios_path")
+                .help("boot from firmware 'bios_path', kernel is optional with it")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("api-channel")
                 .long("api-channel")
-                .value_name("unix:PATH")
-                .help("set api-channel's unixsocket path")
-                .takes_value(true)
-                .required(true),
+                .value_name(
+                    "unix:PATH[,allow-uid=UID][,allow-gid=GID]|tcp:HOST:PORT|chardev:ID",
+                )
+                .help("set api-channel's unixsocket path, tcp address or socket chardev")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("drive")
                 .multiple(true)
                 .long("drive")
-                .value_name("[file=path][,id=str][,readonly=][,direct=]")
+                .value_name(
+                    "[file=path][,id=str][,readonly=][,direct=][,cache=none|writeback|unsafe][,aio=threads|io_uring][,format=raw][,serial=str][,iothread=id]",
+                )
                 .help("use 'file' as a drive image")
                 .takes_values(true),
         )
@@ -145,31 +180,66 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             Arg::with_name("netdev")
                 .multiple(true)
                 .long("netdev")
-                .value_name("tap[,id=str][,netdev=hostname][,mac=addr]")
+                .value_name("tap[,id=str][,netdev=hostname][,mac=addr][,iothread=id]")
                 .help("configure a host TAP network with ID 'str'")
                 .takes_values(true),
         )
+        .arg(
+            Arg::with_name("iothread")
+                .multiple(true)
+                .long("iothread")
+                .value_name("id=str")
+                .help("create an iothread with ID 'str' to handle device events")
+                .takes_values(true),
+        )
         .arg(
             Arg::with_name("chardev")
                 .multiple(true)
                 .long("chardev")
-                .value_name("chartype[,id=str][,path=socket_path]")
-                .help("set char device for vm")
+                .value_name(
+                    "backend,id=str[,path=path][,host=host,port=port] | id=console_id,path=socket_path[,max_ports=num]",
+                )
+                .help("set char device for vm, backend is stdio, socket, pty or file")
                 .takes_values(true),
         )
         .arg(
             Arg::with_name("device")
                 .multiple(true)
                 .long("device")
-                .value_name("device_type[,prop1=value1,...]")
+                .value_name(
+                    "device_type[,prop1=value1,...], device_type is vsock, virtio-console, virtio-balloon or virtio-pmem",
+                )
                 .help("add device (based on driver) and sets driver properties")
                 .takes_values(true),
         )
+        .arg(
+            Arg::with_name("balloon")
+                .long("balloon")
+                .value_name("[deflate-on-oom=bool][,free-page-reporting=bool]")
+                .help("add a virtio balloon device")
+                .can_no_value(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rng")
+                .long("rng")
+                .value_name("[random_file=path][,bytes_per_sec=num]")
+                .help("add a virtio rng device reading random bytes from 'path'")
+                .can_no_value(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pmem")
+                .long("pmem")
+                .value_name("path=path,size=size")
+                .help("add a virtio pmem device backed by file 'path'")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("serial")
                 .long("serial")
-                .value_name("[stdio]")
-                .help("add serial and set stdio or not")
+                .value_name("[stdio|chardev:id]")
+                .help("add serial and connect it to stdio, a chardev or nothing")
                 .can_no_value(true)
                 .takes_value(true),
         )
@@ -181,6 +251,14 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                 .takes_value(true)
                 .can_no_value(true),
         )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("error|warn|info|debug|trace")
+                .help("set level of log, it overrides env STRATOVIRT_LOG_LEVEL")
+                .possible_values(vec!["error", "warn", "info", "debug", "trace"])
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("pidfile")
                 .long("pidfile")
@@ -194,25 +272,91 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                 .takes_value(false)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("seccomp")
+                .long("seccomp")
+                .value_name("enforce|log|off|dump")
+                .help("set mode of seccomp sandbox, denied syscalls are only logged in log mode (default enforce), dump prints the allowlists and exits")
+                .possible_values(vec!["enforce", "log", "off", "dump"])
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("disable-seccomp")
                 .long("disable-seccomp")
-                .help("not use seccomp sandbox for StratoVirt")
+                .help("not use seccomp sandbox for StratoVirt, same as -seccomp off")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("shutdown-timeout")
+                .long("shutdown-timeout")
+                .value_name("seconds")
+                .help("force to exit if VM is not shut down in 'seconds' after SIGTERM or SIGINT (default: 10)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-reboot")
+                .long("no-reboot")
+                .help("shut down VM instead of rebooting it when guest reboots, same as -action reboot=shutdown")
                 .takes_value(false)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("action")
+                .long("action")
+                .value_name("reboot=reset|shutdown")
+                .help("set the action taken when guest reboots, reset falls back to shutdown for now (default: shutdown)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("qmp-event-buffer")
+                .long("qmp-event-buffer")
+                .value_name("depth")
+                .help("buffer at most 'depth' QMP events while no client is connected (default: 128)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("qmp-max-msg-len")
+                .long("qmp-max-msg-len")
+                .value_name("bytes")
+                .help("limit the length of QMP request and pending output to 'bytes' (default: 1048576)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("freeze_cpu")
                 .short("S")
                 .long("freeze")
-                .help("Freeze CPU at startup")
+                .help("Freeze CPU at startup, use QMP 'cont' to start")
                 .takes_value(false)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("incoming")
+                .long("incoming")
+                .value_name("snapshot:PATH|tcp:HOST:PORT|defer")
+                .help("Restore VM from snapshot or wait for migration instead of booting, use QMP 'cont' to start. 'defer' waits for QMP 'migrate-incoming' to listen")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("machine")
+                .long("machine")
+                .value_name(
+                    "[type=]microvm[,dump-guest-core=on|off][,mem-merge=on|off][,thp=on|off|auto][,max-slot-size=size[M|G]][,earlycon=auto|off][,boot-marker=on|off]",
+                )
+                .help("selects emulated machine and sets machine properties")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mem-path")
+                .long("mem-path")
+                .value_name("FILE")
+                .help("back guest memory by FILE mapped shared, such as a file on tmpfs or hugetlbfs")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("omit_vm_memory")
                 .long("omit_vm_memory")
-                .help("not dump guest memory in core file")
+                .help("not dump guest memory in core file, deprecated by -machine dump-guest-core=off")
                 .takes_value(false)
                 .required(false),
         )
@@ -232,13 +376,6 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                 .takes_value(true)
                 .hidden(true),
         )
-        .arg(
-            Arg::with_name("machine")
-                .long("machine")
-                .help("selects emulated machine")
-                .takes_value(true)
-                .hidden(true),
-        )
         .arg(
             Arg::with_name("global_property")
                 .long("global")
@@ -318,14 +455,189 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
 ///
 /// Input arguments is illegal for `VmConfig` or `VmConfig`'s health check
 /// failed -- with this unhealthy `VmConfig`, VM will not boot successfully.
-#[allow(unused_parens)]
 pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
+    let vm_cfg = merge_vmconfig(args)?;
+
+    match parse_incoming(args)? {
+        Some(Incoming::Snapshot(path)) => {
+            let saved = VmConfig::create_from_value(read_config(&path)?)
+                .chain_err(|| format!("Failed to parse config in snapshot {}", path))?;
+            vm_cfg
+                .check_restore(&saved, args.is_present("daemonize"))
+                .chain_err(|| format!("VmConfig is incompatible with snapshot {}", path))?;
+            return Ok(vm_cfg);
+        }
+        // Checked against the config of source when it connects.
+        Some(Incoming::Tcp(_)) | Some(Incoming::Defer) => {
+            vm_cfg
+                .check_incoming(args.is_present("daemonize"))
+                .chain_err(|| "Precheck failed, VmConfig is unhealthy, stop running")?;
+            return Ok(vm_cfg);
+        }
+        None => (),
+    }
+
+    // Check the mini-set for Vm to start is ok
+    vm_cfg
+        .check_vmconfig(args.is_present("daemonize"))
+        .chain_err(|| "Precheck failed, VmConfig is unhealthy, stop running")?;
+
+    Ok(vm_cfg)
+}
+
+/// Where the state of VM comes from, instead of booting it.
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    /// Snapshot file saved by QMP `snapshot-save`, its path is absolute.
+    Snapshot(String),
+    /// `HOST:PORT` listened for the source of migration.
+    Tcp(String),
+    /// Migration whose address is given later by QMP `migrate-incoming`.
+    Defer,
+}
+
+/// Parse the `-incoming` argument.
+///
+/// # Arguments
+///
+/// - * `args` - The structure accepted input cmdline arguments.
+///
+/// # Errors
+///
+/// The source of `-incoming` is unknown, or a snapshot is given with
+/// `-config`.
+pub fn parse_incoming(args: &ArgMatches) -> Result<Option<Incoming>> {
+    let incoming = match args.value_of("incoming") {
+        Some(incoming) => parse_incoming_source(&incoming)?,
+        None => return Ok(None),
+    };
+    if let Incoming::Snapshot(_) = incoming {
+        if args.value_of("config-file").is_some() {
+            bail!("-incoming can't be used with -config, the config is in snapshot");
+        }
+    }
+    Ok(Some(incoming))
+}
+
+/// This function is to parse the source of `-incoming`.
+///
+/// # Arguments
+///
+/// * `incoming` - The source `String` would be parsed.
+///
+/// # Errors
+///
+/// The source is unknown, or its path or address is invalid.
+fn parse_incoming_source(incoming: &str) -> Result<Incoming> {
+    if incoming.starts_with("snapshot:") && incoming.len() > "snapshot:".len() {
+        // Working directory is changed by daemonize before it's restored.
+        let path = std::env::current_dir()?.join(&incoming["snapshot:".len()..]);
+        Ok(Incoming::Snapshot(path.to_string_lossy().to_string()))
+    } else if incoming.starts_with("tcp:") {
+        Ok(Incoming::Tcp(parse_uri(incoming)?))
+    } else if incoming == "defer" {
+        Ok(Incoming::Defer)
+    } else {
+        bail!(
+            "Unknown incoming {}, it should be snapshot:PATH, tcp:HOST:PORT or defer",
+            incoming
+        )
+    }
+}
+
+/// Parse the action taken when guest reboots from `-action` and `-no-reboot`.
+///
+/// # Arguments
+///
+/// - * `args` - The structure accepted input cmdline arguments.
+///
+/// # Errors
+///
+/// The action is unknown, or `-no-reboot` conflicts with `-action`.
+///
+/// # Notes
+///
+/// VM can't be reset yet, so the `reset` action falls back to shutdown
+/// when guest reboots, and a warning is logged at startup.
+pub fn parse_reboot_action(args: &ArgMatches) -> Result<RebootAction> {
+    let no_reboot = args.is_present("no-reboot");
+    let action = match args.value_of("action") {
+        Some(action) => parse_action(&action)?,
+        None if no_reboot => return Ok(RebootAction::Shutdown),
+        None => return Ok(RebootAction::default()),
+    };
+    if no_reboot && action != RebootAction::Shutdown {
+        bail!("-no-reboot conflicts with -action reboot=reset");
+    }
+    if action == RebootAction::Reset {
+        warn!("VM can't be reset yet, it's shut down instead when guest reboots");
+    }
+    Ok(action)
+}
+
+/// This function is to parse the value of `-action`.
+///
+/// # Arguments
+///
+/// * `action` - The action `String` would be parsed.
+///
+/// # Errors
+///
+/// The event or the action of it is unknown.
+fn parse_action(action: &str) -> Result<RebootAction> {
+    match action {
+        "reboot=reset" => Ok(RebootAction::Reset),
+        "reboot=shutdown" => Ok(RebootAction::Shutdown),
+        _ => bail!(
+            "Unknown action {}, it should be reboot=reset or reboot=shutdown",
+            action
+        ),
+    }
+}
+
+/// Get the json of `VmConfig` merged from config file and cmdline, which is
+/// printed by `-dump-config` and can be given to `-config` again.
+///
+/// # Arguments
+///
+/// - * `args` - The structure accepted input cmdline arguments.
+///
+/// # Notes
+///
+/// The config is not checked, so that a config which fails to boot can
+/// still be dumped.
+pub fn dump_vmconfig(args: &ArgMatches) -> Result<String> {
+    let vm_cfg = merge_vmconfig(args)?;
+    Ok(serde_json::to_string_pretty(&vm_cfg.to_value())?)
+}
+
+/// Merge `VmConfig` from config file or snapshot and cmdline, the cmdline
+/// arguments override the fields of config file, and add devices to it.
+/// Devices of snapshot are overridden in place by those of the same id, so
+/// that only their backends are changed.
+#[allow(unused_parens)]
+fn merge_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
     // Parse config-file json.
     // VmConfig can be transformed by json file which described VmConfig
     // directly.
     let mut vm_cfg = VmConfig::default();
-    if let Some(config_file) = args.value_of("config-file") {
-        let config_value = match File::open(&config_file) {
+    let mut config_value = None;
+    let incoming = parse_incoming(args)?;
+    if let Some(Incoming::Snapshot(path)) = &incoming {
+        let mut value = read_config(path)?;
+        // The memory file of the VM saved may still be in use, guest memory
+        // is backed by a file only if `-mem-path` is given again.
+        if let Some(machine) = value
+            .get_mut("machine-config")
+            .and_then(serde_json::Value::as_object_mut)
+        {
+            machine.remove("mem_path");
+        }
+        vm_cfg = VmConfig::create_from_value(value.clone())
+            .chain_err(|| format!("Failed to parse config in snapshot {}", path))?;
+        config_value = Some((value, "snapshot"));
+    } else if let Some(config_file) = args.value_of("config-file") {
+        let value: serde_json::Value = match File::open(&config_file) {
             Ok(mut f) => {
                 let mut data = String::new();
                 f.read_to_string(&mut data)
@@ -340,27 +652,37 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
                 bail!("Failed to open config file by: {}", e);
             }
         };
-        vm_cfg = VmConfig::create_from_value(config_value)
-            .chain_err(|| "Failed to parse config file to VmConfig")?;
+        vm_cfg = VmConfig::create_from_value(value.clone())
+            .chain_err(|| format!("Failed to parse config file {}", &config_file))?;
+        config_value = Some((value, "config file"));
     }
 
     // Parse cmdline args which need to set in VmConfig
     update_args_to_config!((args.value_of("name")), vm_cfg, update_name);
+    update_args_to_config!((args.value_of("uuid")), vm_cfg, update_uuid);
+    update_args_to_config!((args.value_of("machine")), vm_cfg, update_machine);
     update_args_to_config!((args.value_of("memory")), vm_cfg, update_memory);
+    update_args_to_config!((args.value_of("mem-path")), vm_cfg, update_mem_path);
     update_args_to_config!((args.value_of("smp")), vm_cfg, update_cpu);
     update_args_to_config!((args.value_of("kernel")), vm_cfg, update_kernel);
     update_args_to_config!((args.value_of("initrd-file")), vm_cfg, update_initrd);
+    update_args_to_config!((args.value_of("dtb")), vm_cfg, update_dtb);
+    update_args_to_config!((args.value_of("bios")), vm_cfg, update_bios);
     update_args_to_config!((args.value_of("serial")), vm_cfg, update_serial);
+    update_args_to_config!((args.value_of("balloon")), vm_cfg, update_balloon);
+    update_args_to_config!((args.value_of("rng")), vm_cfg, update_rng);
+    update_args_to_config!((args.value_of("pmem")), vm_cfg, update_pmem);
     update_args_to_config!(
         (args.values_of("kernel-cmdline")),
         vm_cfg,
         update_kernel_cmdline,
         vec
     );
+    update_args_to_config_multi!((args.values_of("iothread")), vm_cfg, update_iothread);
     update_args_to_config_multi!((args.values_of("drive")), vm_cfg, update_drive);
-    update_args_to_config_multi!((args.values_of("device")), vm_cfg, update_vsock);
+    update_args_to_config_multi!((args.values_of("device")), vm_cfg, update_device);
     update_args_to_config_multi!((args.values_of("netdev")), vm_cfg, update_net);
-    update_args_to_config_multi!((args.values_of("chardev")), vm_cfg, update_console);
+    update_args_to_config_multi!((args.values_of("chardev")), vm_cfg, update_chardev);
     update_args_to_config!(
         (args.is_present("omit_vm_memory")),
         vm_cfg,
@@ -368,10 +690,14 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
         bool
     );
 
-    // Check the mini-set for Vm to start is ok
-    vm_cfg
-        .check_vmconfig(args.is_present("daemonize"))
-        .chain_err(|| "Precheck failed, VmConfig is unhealthy, stop running")?;
+    if let Some(Incoming::Snapshot(_)) = incoming {
+        vm_cfg.override_devices();
+    }
+    if let Some((value, source)) = config_value {
+        for field in vm_cfg.overridden_fields(&value) {
+            warn!("'{}' of {} is overridden by cmdline", field, source);
+        }
+    }
 
     Ok(vm_cfg)
 }
@@ -381,16 +707,25 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
 /// # Arguments
 ///
 /// * `args` - The structure accepted input cmdline arguments.
+/// * `vm_config` - The config of VM, where chardev of `chardev:ID` is.
 ///
 /// # Errors
 ///
 /// The value of `api-channel` is illegel.
-pub fn check_api_channel(args: &ArgMatches) -> Result<(String, SocketType)> {
+pub fn check_api_channel(
+    args: &ArgMatches,
+    vm_config: &VmConfig,
+) -> Result<(String, SocketType, AccessPolicy)> {
     if let Some(api) = args.value_of("api-channel") {
-        let (api_path, api_type) = parse_path(&api)
-            .map(|(path, type_)| (path, type_))
-            .chain_err(|| "Failed to parse api-channel socket path")?;
-        Ok((api_path, api_type))
+        let (api_path, api_type) = if api.starts_with("chardev:") {
+            parse_chardev_path(&api, vm_config)
+        } else {
+            parse_path(&api)
+        }
+        .chain_err(|| "Failed to parse api-channel socket path")?;
+        let access = parse_access_policy(&api, api_type)
+            .chain_err(|| "Failed to parse api-channel access policy")?;
+        Ok((api_path, api_type, access))
     } else {
         bail!("Please use \'-api-channel\' to give a api-channel path for Unix socket");
     }
@@ -408,23 +743,169 @@ pub fn check_api_channel(args: &ArgMatches) -> Result<(String, SocketType)> {
 fn parse_path(args_str: &str) -> Result<(String, SocketType)> {
     let arg: Vec<&str> = args_str.split(',').collect();
     let item = arg[0].to_string();
-    let path_vec: Vec<&str> = item.split(':').collect();
+    let path_vec: Vec<&str> = item.splitn(2, ':').collect();
     if path_vec.len() > 1 {
-        if path_vec[0] == "unix" {
-            let unix_path = String::from(path_vec[1]);
-            Ok((unix_path, SocketType::Unix))
-        } else {
-            bail!("{} type is not support yet!", path_vec[0]);
+        match path_vec[0] {
+            "unix" => {
+                let unix_path = String::from(path_vec[1]);
+                Ok((unix_path, SocketType::Unix))
+            }
+            "tcp" => {
+                let insecure = arg[1..].contains(&"insecure");
+                let addr = parse_tcp_addr(path_vec[1], insecure)?;
+                Ok((addr.to_string(), SocketType::Tcp))
+            }
+            _ => bail!("{} type is not support yet!", path_vec[0]),
         }
     } else {
         bail!("Failed to parse path: {}", args_str);
     }
 }
 
+/// This function is to get socket path string and socket type from the
+/// chardev of `chardev:ID`.
+///
+/// # Arguments
+///
+/// * `args_str` - The arguments `String` would be parsed.
+/// * `vm_config` - The config of VM, where the chardev is.
+///
+/// # Errors
+///
+/// The chardev is not found, or its backend is neither unix socket nor tcp.
+fn parse_chardev_path(args_str: &str, vm_config: &VmConfig) -> Result<(String, SocketType)> {
+    let arg: Vec<&str> = args_str.split(',').collect();
+    let id = &arg[0]["chardev:".len()..];
+    let chardev = match vm_config.get_chardev(id) {
+        Some(chardev) => chardev,
+        None => bail!("Chardev '{}' not found", id),
+    };
+    match &chardev.backend {
+        ChardevType::Socket { path } => Ok((path.clone(), SocketType::Unix)),
+        ChardevType::Tcp { host, port } => {
+            let insecure = arg[1..].contains(&"insecure");
+            // IPv6 address is bracketed before port.
+            let addr = if host.contains(':') {
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            };
+            let addr = parse_tcp_addr(&addr, insecure)?;
+            Ok((addr.to_string(), SocketType::Tcp))
+        }
+        _ => bail!("Chardev '{}' of api-channel must be socket or tcp", id),
+    }
+}
+
+/// This function is to parse the `allow-uid=UID` and `allow-gid=GID`
+/// options of api-channel, which can be repeated to allow more peers.
+///
+/// # Arguments
+///
+/// * `args_str` - The arguments `String` of api-channel.
+/// * `sock_type` - The type of api-channel.
+///
+/// # Errors
+///
+/// The id is not a number, or the options are given to TCP-type api-channel,
+/// whose peer has no credentials.
+fn parse_access_policy(args_str: &str, sock_type: SocketType) -> Result<AccessPolicy> {
+    let mut access = AccessPolicy::default();
+    for option in args_str.split(',').skip(1) {
+        let (ids, id) = if option.starts_with("allow-uid=") {
+            (&mut access.allow_uids, &option["allow-uid=".len()..])
+        } else if option.starts_with("allow-gid=") {
+            (&mut access.allow_gids, &option["allow-gid=".len()..])
+        } else {
+            continue;
+        };
+        let id = id
+            .parse::<u32>()
+            .chain_err(|| format!("Invalid id in {}", option))?;
+        ids.push(id);
+    }
+    if access.is_restricted() && sock_type != SocketType::Unix {
+        bail!("allow-uid and allow-gid are only supported by unix socket");
+    }
+    Ok(access)
+}
+
+/// This function is to resolve the `HOST:PORT` address of tcp socket.
+///
+/// # Arguments
+///
+/// * `addr` - The `HOST:PORT` address.
+/// * `insecure` - Whether binding to non-loopback address is allowed.
+///
+/// # Errors
+///
+/// The address can't be resolved, or it's not a loopback address without
+/// `insecure`, since anyone reaching it can control the VM.
+fn parse_tcp_addr(addr: &str, insecure: bool) -> Result<SocketAddr> {
+    let socket_addr = addr
+        .to_socket_addrs()
+        .chain_err(|| format!("Failed to resolve tcp address: {}", addr))?
+        .next();
+    match socket_addr {
+        Some(socket_addr) if socket_addr.ip().is_loopback() || insecure => Ok(socket_addr),
+        Some(socket_addr) => bail!(
+            "Refuse to bind api-channel to non-loopback address {}, add 'insecure' to allow it",
+            socket_addr
+        ),
+        None => bail!("Failed to resolve tcp address: {}", addr),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_incoming_source() {
+        assert_eq!(
+            parse_incoming_source("snapshot:/tmp/vm.snap").unwrap(),
+            Incoming::Snapshot("/tmp/vm.snap".to_string())
+        );
+        let path = std::env::current_dir().unwrap().join("vm.snap");
+        assert_eq!(
+            parse_incoming_source("snapshot:vm.snap").unwrap(),
+            Incoming::Snapshot(path.to_string_lossy().to_string())
+        );
+        assert_eq!(
+            parse_incoming_source("tcp:0.0.0.0:4444").unwrap(),
+            Incoming::Tcp("0.0.0.0:4444".to_string())
+        );
+        assert!(parse_incoming_source("tcp:0.0.0.0").is_err());
+        assert_eq!(parse_incoming_source("defer").unwrap(), Incoming::Defer);
+        for incoming in ["snapshot:", "file:/tmp/vm.snap", "/tmp/vm.snap", "deferred"].iter() {
+            assert_eq!(
+                parse_incoming_source(incoming).unwrap_err().to_string(),
+                format!(
+                    "Unknown incoming {}, it should be snapshot:PATH, tcp:HOST:PORT or defer",
+                    incoming
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(parse_action("reboot=reset").unwrap(), RebootAction::Reset);
+        assert_eq!(
+            parse_action("reboot=shutdown").unwrap(),
+            RebootAction::Shutdown
+        );
+        for action in ["reboot", "reboot=", "reboot=exit", "shutdown=reset", ""].iter() {
+            assert_eq!(
+                parse_action(action).unwrap_err().to_string(),
+                format!(
+                    "Unknown action {}, it should be reboot=reset or reboot=shutdown",
+                    action
+                )
+            );
+        }
+    }
+
     #[test]
     fn test_parse_path() {
         let test_path = "unix:/tmp/stratovirt.sock";
@@ -440,9 +921,79 @@ mod tests {
         );
 
         let test_path = "tcp:127.0.0.1:8080,nowait,server";
+        assert_eq!(
+            parse_path(test_path).unwrap(),
+            ("127.0.0.1:8080".to_string(), SocketType::Tcp)
+        );
+
+        let test_path = "tcp:[::1]:8080,server,nowait";
+        assert_eq!(
+            parse_path(test_path).unwrap(),
+            ("[::1]:8080".to_string(), SocketType::Tcp)
+        );
+
+        // Non-loopback address needs explicit `insecure`.
+        let test_path = "tcp:0.0.0.0:4444,server,nowait";
+        assert!(parse_path(test_path).is_err());
+        let test_path = "tcp:0.0.0.0:4444,server,nowait,insecure";
+        assert_eq!(
+            parse_path(test_path).unwrap(),
+            ("0.0.0.0:4444".to_string(), SocketType::Tcp)
+        );
+
+        let test_path = "tcp:127.0.0.1";
         assert!(parse_path(test_path).is_err());
 
         let test_path = "file:/tmp/stratovirt-file";
         assert!(parse_path(test_path).is_err());
     }
+
+    #[test]
+    fn test_parse_chardev_path() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_chardev("socket,id=qmp0,path=/tmp/stratovirt.sock".to_string());
+        vm_config.update_chardev("socket,id=qmp1,host=127.0.0.1,port=8080".to_string());
+        vm_config.update_chardev("socket,id=qmp2,host=0.0.0.0,port=4444".to_string());
+        vm_config.update_chardev("pty,id=pty0".to_string());
+
+        assert_eq!(
+            parse_chardev_path("chardev:qmp0", &vm_config).unwrap(),
+            ("/tmp/stratovirt.sock".to_string(), SocketType::Unix)
+        );
+        assert_eq!(
+            parse_chardev_path("chardev:qmp1", &vm_config).unwrap(),
+            ("127.0.0.1:8080".to_string(), SocketType::Tcp)
+        );
+        assert!(parse_chardev_path("chardev:qmp2", &vm_config).is_err());
+        assert_eq!(
+            parse_chardev_path("chardev:qmp2,insecure", &vm_config).unwrap(),
+            ("0.0.0.0:4444".to_string(), SocketType::Tcp)
+        );
+        assert!(parse_chardev_path("chardev:pty0", &vm_config).is_err());
+        assert!(parse_chardev_path("chardev:qmp3", &vm_config).is_err());
+    }
+
+    #[test]
+    fn test_parse_access_policy() {
+        let test_path = "unix:/tmp/stratovirt.sock";
+        let access = parse_access_policy(test_path, SocketType::Unix).unwrap();
+        assert!(!access.is_restricted());
+
+        let test_path = "unix:/tmp/stratovirt.sock,allow-uid=107,allow-gid=991,allow-uid=0";
+        assert_eq!(
+            parse_access_policy(test_path, SocketType::Unix).unwrap(),
+            AccessPolicy {
+                allow_uids: vec![107, 0],
+                allow_gids: vec![991],
+            }
+        );
+
+        let test_path = "unix:/tmp/stratovirt.sock,allow-uid=qemu";
+        assert!(parse_access_policy(test_path, SocketType::Unix).is_err());
+        let test_path = "unix:/tmp/stratovirt.sock,allow-gid=-1";
+        assert!(parse_access_policy(test_path, SocketType::Unix).is_err());
+
+        let test_path = "tcp:127.0.0.1:8080,server,nowait,allow-uid=107";
+        assert!(parse_access_policy(test_path, SocketType::Tcp).is_err());
+    }
 }
@@ -0,0 +1,378 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Dump guest memory as an ELF core file, which can be analyzed by `crash`
+//! or `gdb`.
+//!
+//! The file is laid out as:
+//! 1. ELF header.
+//! 2. One `PT_NOTE` program header, and one `PT_LOAD` program header for each
+//!    range of guest memory, whose `p_paddr` is the guest physical address.
+//! 3. One `NT_PRSTATUS` note for each vcpu, holding its general purpose
+//!    registers.
+//! 4. Contents of guest memory, in the order of `PT_LOAD` program headers.
+
+use std::cmp::{max, min};
+use std::io::Write;
+
+use crate::errors::Result;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+#[cfg(target_arch = "x86_64")]
+const EM_MACHINE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_MACHINE: u16 = 183;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+// PF_X | PF_W | PF_R
+const PF_RWX: u32 = 7;
+const NT_PRSTATUS: u32 = 1;
+const NOTE_NAME: &[u8] = b"CORE\0";
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+/// Offset of `pr_pid` in `struct elf_prstatus`.
+const PRSTATUS_PID_OFFSET: usize = 32;
+/// Offset of `pr_reg` in `struct elf_prstatus`, `pr_fpvalid` and padding
+/// follow `pr_reg`, 8 bytes in total.
+const PRSTATUS_REG_OFFSET: usize = 112;
+
+/// Size of chunk in which guest memory is written.
+pub const DUMP_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Range of guest memory to dump, it's described by a `PT_LOAD` segment.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DumpRange {
+    /// Start guest physical address.
+    pub addr: u64,
+    /// Size of range.
+    pub size: u64,
+}
+
+/// Get the parts of `ranges` in the window `[begin, begin + length)`.
+///
+/// # Arguments
+///
+/// * `ranges` - Ranges of guest memory.
+/// * `begin` - Start guest physical address of window.
+/// * `length` - Size of window.
+pub fn clip_ranges(ranges: &[DumpRange], begin: u64, length: u64) -> Vec<DumpRange> {
+    let end = begin.saturating_add(length);
+    ranges
+        .iter()
+        .filter_map(|range| {
+            let start = max(range.addr, begin);
+            let stop = min(range.addr + range.size, end);
+            if start < stop {
+                Some(DumpRange {
+                    addr: start,
+                    size: stop - start,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Build a `NT_PRSTATUS` note, whose descriptor is `struct elf_prstatus`.
+///
+/// # Arguments
+///
+/// * `pid` - `pr_pid` of note, `crash` takes it as the vcpu.
+/// * `regs` - Registers in the layout of `pr_reg`.
+fn prstatus_note(pid: u32, regs: &[u64]) -> Vec<u8> {
+    let desc_size = PRSTATUS_REG_OFFSET + regs.len() * 8 + 8;
+    let mut desc = vec![0_u8; desc_size];
+    desc[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4].copy_from_slice(&pid.to_le_bytes());
+    for (index, reg) in regs.iter().enumerate() {
+        let offset = PRSTATUS_REG_OFFSET + index * 8;
+        desc[offset..offset + 8].copy_from_slice(&reg.to_le_bytes());
+    }
+
+    let mut note = Vec::new();
+    note.extend_from_slice(&(NOTE_NAME.len() as u32).to_le_bytes());
+    note.extend_from_slice(&(desc_size as u32).to_le_bytes());
+    note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    note.extend_from_slice(NOTE_NAME);
+    note.resize(align4(note.len()), 0);
+    note.extend_from_slice(&desc);
+    note.resize(align4(note.len()), 0);
+    note
+}
+
+fn push_program_header(
+    buf: &mut Vec<u8>,
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    paddr: u64,
+    size: u64,
+) {
+    buf.extend_from_slice(&p_type.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    // p_vaddr, guest virtual address is unknown without paging.
+    buf.extend_from_slice(&0_u64.to_le_bytes());
+    buf.extend_from_slice(&paddr.to_le_bytes());
+    // p_filesz and p_memsz
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    // p_align
+    buf.extend_from_slice(&0_u64.to_le_bytes());
+}
+
+/// ELF core file of guest.
+pub struct ElfCore {
+    /// Ranges of guest memory to dump.
+    ranges: Vec<DumpRange>,
+    /// Content of `PT_NOTE` segment.
+    notes: Vec<u8>,
+}
+
+impl ElfCore {
+    /// Create the ELF core of guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `ranges` - Ranges of guest memory to dump.
+    /// * `cpu_regs` - Registers of each vcpu, in the layout of `pr_reg`.
+    pub fn new(ranges: Vec<DumpRange>, cpu_regs: &[Vec<u64>]) -> Self {
+        let notes = cpu_regs
+            .iter()
+            .enumerate()
+            .flat_map(|(index, regs)| prstatus_note(index as u32 + 1, regs))
+            .collect();
+        ElfCore { ranges, notes }
+    }
+
+    fn notes_offset(&self) -> u64 {
+        ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * (self.ranges.len() as u64 + 1)
+    }
+
+    /// Get the ELF header, program headers and notes, which precede the
+    /// contents of guest memory.
+    pub fn headers(&self) -> Vec<u8> {
+        let notes_offset = self.notes_offset();
+        let mut buf = Vec::new();
+
+        buf.extend_from_slice(&ELF_MAGIC);
+        buf.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+        // EI_OSABI, EI_ABIVERSION and padding of e_ident.
+        buf.resize(16, 0);
+        buf.extend_from_slice(&ET_CORE.to_le_bytes());
+        buf.extend_from_slice(&EM_MACHINE.to_le_bytes());
+        buf.extend_from_slice(&u32::from(EV_CURRENT).to_le_bytes());
+        // e_entry
+        buf.extend_from_slice(&0_u64.to_le_bytes());
+        // e_phoff
+        buf.extend_from_slice(&ELF_HEADER_SIZE.to_le_bytes());
+        // e_shoff and e_flags
+        buf.extend_from_slice(&0_u64.to_le_bytes());
+        buf.extend_from_slice(&0_u32.to_le_bytes());
+        buf.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+        buf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        buf.extend_from_slice(&(self.ranges.len() as u16 + 1).to_le_bytes());
+        // e_shentsize, e_shnum and e_shstrndx
+        buf.extend_from_slice(&[0_u8; 6]);
+
+        let notes_size = self.notes.len() as u64;
+        push_program_header(&mut buf, PT_NOTE, 0, notes_offset, 0, notes_size);
+        let mut offset = notes_offset + notes_size;
+        for range in self.ranges.iter() {
+            push_program_header(&mut buf, PT_LOAD, PF_RWX, offset, range.addr, range.size);
+            offset += range.size;
+        }
+
+        buf.extend_from_slice(&self.notes);
+        buf
+    }
+
+    /// Write the ELF core, and return the size written.
+    ///
+    /// # Arguments
+    ///
+    /// * `dst` - Destination the core is written to.
+    /// * `read_mem` - Write guest memory of `(addr, size)` to destination,
+    ///   it's called for chunks no larger than `DUMP_CHUNK_SIZE`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to write destination or read guest memory.
+    pub fn write(
+        &self,
+        dst: &mut dyn Write,
+        read_mem: &mut dyn FnMut(&mut dyn Write, u64, u64) -> Result<()>,
+    ) -> Result<u64> {
+        let headers = self.headers();
+        dst.write_all(&headers)?;
+        let mut written = headers.len() as u64;
+
+        for range in self.ranges.iter() {
+            let mut addr = range.addr;
+            let end = range.addr + range.size;
+            while addr < end {
+                let count = min(end - addr, DUMP_CHUNK_SIZE);
+                read_mem(dst, addr, count)?;
+                addr += count;
+                written += count;
+            }
+        }
+        dst.flush()?;
+
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn u16_at(buf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_clip_ranges() {
+        let ranges = vec![
+            DumpRange {
+                addr: 0,
+                size: 0x1000,
+            },
+            DumpRange {
+                addr: 0x4000,
+                size: 0x2000,
+            },
+        ];
+        assert_eq!(clip_ranges(&ranges, 0, u64::MAX), ranges);
+        assert_eq!(
+            clip_ranges(&ranges, 0x800, 0x4000),
+            vec![
+                DumpRange {
+                    addr: 0x800,
+                    size: 0x800,
+                },
+                DumpRange {
+                    addr: 0x4000,
+                    size: 0x800,
+                },
+            ]
+        );
+        assert!(clip_ranges(&ranges, 0x1000, 0x3000).is_empty());
+    }
+
+    #[test]
+    fn test_elf_core() {
+        // A small VM with 2 vcpus and 2 ranges of memory, the second range is
+        // written in 2 chunks.
+        let ranges = vec![
+            DumpRange {
+                addr: 0,
+                size: 0x1000,
+            },
+            DumpRange {
+                addr: 0x10_0000,
+                size: DUMP_CHUNK_SIZE + 0x1000,
+            },
+        ];
+        let nr_regs = if cfg!(target_arch = "x86_64") { 27 } else { 34 };
+        let cpu_regs: Vec<Vec<u64>> = (0..2_u64)
+            .map(|cpu| (0..nr_regs).map(|reg| (cpu << 32) | reg).collect())
+            .collect();
+        let core = ElfCore::new(ranges.clone(), &cpu_regs);
+
+        let mut chunks = Vec::new();
+        let mut file: Vec<u8> = Vec::new();
+        let size = core
+            .write(&mut file, &mut |dst, addr, count| {
+                chunks.push((addr, count));
+                dst.write_all(&vec![(addr >> 20) as u8 + 1; count as usize])?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(size, file.len() as u64);
+        assert_eq!(
+            chunks,
+            vec![
+                (0, 0x1000),
+                (0x10_0000, DUMP_CHUNK_SIZE),
+                (0x10_0000 + DUMP_CHUNK_SIZE, 0x1000)
+            ]
+        );
+
+        // ELF header.
+        assert_eq!(&file[0..7], &[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+        assert_eq!(u16_at(&file, 16), ET_CORE);
+        assert_eq!(u16_at(&file, 18), EM_MACHINE);
+        assert_eq!(u64_at(&file, 32), 64);
+        assert_eq!(u16_at(&file, 52), 64);
+        assert_eq!(u16_at(&file, 54), 56);
+        assert_eq!(u16_at(&file, 56), 3);
+
+        // Note segment comes first, the load segments follow it in order.
+        let phdr = |index: usize| 64 + index * 56;
+        assert_eq!(u32_at(&file, phdr(0)), PT_NOTE);
+        let notes_offset = u64_at(&file, phdr(0) + 8) as usize;
+        let notes_size = u64_at(&file, phdr(0) + 32) as usize;
+        assert_eq!(notes_offset, 64 + 3 * 56);
+        let mut offset = notes_offset + notes_size;
+        for (index, range) in ranges.iter().enumerate() {
+            let phdr = phdr(index + 1);
+            assert_eq!(u32_at(&file, phdr), PT_LOAD);
+            assert_eq!(u64_at(&file, phdr + 8), offset as u64);
+            assert_eq!(u64_at(&file, phdr + 24), range.addr);
+            assert_eq!(u64_at(&file, phdr + 32), range.size);
+            assert_eq!(u64_at(&file, phdr + 40), range.size);
+            assert_eq!(file[offset], (range.addr >> 20) as u8 + 1);
+            offset += range.size as usize;
+        }
+        assert_eq!(offset, file.len());
+
+        // One NT_PRSTATUS note for each vcpu.
+        let desc_size = PRSTATUS_REG_OFFSET + nr_regs as usize * 8 + 8;
+        let mut note = notes_offset;
+        for cpu in 0..2_u64 {
+            assert_eq!(u32_at(&file, note), 5);
+            assert_eq!(u32_at(&file, note + 4) as usize, desc_size);
+            assert_eq!(u32_at(&file, note + 8), NT_PRSTATUS);
+            assert_eq!(&file[note + 12..note + 17], b"CORE\0");
+            let desc = note + 20;
+            assert_eq!(u32_at(&file, desc + PRSTATUS_PID_OFFSET) as u64, cpu + 1);
+            let regs = desc + PRSTATUS_REG_OFFSET;
+            assert_eq!(u64_at(&file, regs), cpu << 32);
+            assert_eq!(
+                u64_at(&file, regs + (nr_regs as usize - 1) * 8),
+                (cpu << 32) | (nr_regs - 1)
+            );
+            note = desc + desc_size;
+        }
+        assert_eq!(note, notes_offset + notes_size);
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Capabilities of host KVM, probed once VM is created.
+//!
+//! The capabilities required by micro VM are checked before anything else,
+//! so that an old or restricted host fails with the missing ones named,
+//! instead of an errno from the first ioctl depending on them. The optional
+//! ones are recorded in `KvmCaps`, and the features depending on them
+//! consult it instead of probing again.
+
+#[cfg(target_arch = "aarch64")]
+use kvm_bindings::{
+    kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, KVM_CAP_ARM_PSCI_0_2, KVM_CAP_DEVICE_CTRL,
+};
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{KVM_CAP_EXT_CPUID, KVM_CAP_IRQCHIP, KVM_CAP_PIT2, KVM_CAP_SET_TSS_ADDR};
+use kvm_bindings::{
+    KVM_CAP_IOEVENTFD, KVM_CAP_IRQFD, KVM_CAP_READONLY_MEM, KVM_CAP_SIGNAL_MSI, KVM_CAP_USER_MEMORY,
+};
+use kvm_ioctls::{Kvm, VmFd};
+use util::kvm_ioctls_ext::{check_device_type, check_extension};
+
+use crate::errors::Result;
+
+/// Probes of host KVM, separated from `Kvm` so that they can be mocked.
+pub trait KvmProbe {
+    /// Check whether extension `cap`, one of `KVM_CAP_*`, is supported.
+    fn check_extension(&self, cap: u32) -> bool;
+    /// Check whether device of `device_type`, one of `KVM_DEV_TYPE_*`, can
+    /// be created in VM.
+    fn check_device(&self, device_type: u32) -> bool;
+}
+
+/// Host KVM with the VM created in it.
+pub struct KvmHost<'a> {
+    pub kvm: &'a Kvm,
+    pub vm_fd: &'a VmFd,
+}
+
+impl KvmProbe for KvmHost<'_> {
+    fn check_extension(&self, cap: u32) -> bool {
+        // Extensions are checked on `/dev/kvm`, which VM fd doesn't
+        // support before Linux 4.0.
+        check_extension(self.kvm, cap)
+    }
+
+    fn check_device(&self, device_type: u32) -> bool {
+        check_device_type(self.vm_fd, device_type)
+    }
+}
+
+/// How a capability is probed.
+#[derive(Clone, Copy)]
+enum Probe {
+    /// Extension checked by `KVM_CHECK_EXTENSION`.
+    Extension(u32),
+    /// Device type checked by `KVM_CREATE_DEVICE`.
+    #[cfg(target_arch = "aarch64")]
+    Device(u32),
+}
+
+/// Capability without which micro VM can't run.
+struct RequiredCap {
+    name: &'static str,
+    probe: Probe,
+    /// Version of Linux which introduced the capability.
+    since: &'static str,
+}
+
+const COMMON_CAPS: [RequiredCap; 3] = [
+    RequiredCap {
+        name: "KVM_CAP_USER_MEMORY",
+        probe: Probe::Extension(KVM_CAP_USER_MEMORY),
+        since: "2.6.24",
+    },
+    // All interrupts of devices are injected by irqfd.
+    RequiredCap {
+        name: "KVM_CAP_IRQFD",
+        probe: Probe::Extension(KVM_CAP_IRQFD),
+        since: "2.6.32",
+    },
+    RequiredCap {
+        name: "KVM_CAP_IOEVENTFD",
+        probe: Probe::Extension(KVM_CAP_IOEVENTFD),
+        since: "2.6.32",
+    },
+];
+
+#[cfg(target_arch = "x86_64")]
+const ARCH_CAPS: [RequiredCap; 4] = [
+    RequiredCap {
+        name: "KVM_CAP_IRQCHIP",
+        probe: Probe::Extension(KVM_CAP_IRQCHIP),
+        since: "2.6.24",
+    },
+    RequiredCap {
+        name: "KVM_CAP_SET_TSS_ADDR",
+        probe: Probe::Extension(KVM_CAP_SET_TSS_ADDR),
+        since: "2.6.24",
+    },
+    RequiredCap {
+        name: "KVM_CAP_EXT_CPUID",
+        probe: Probe::Extension(KVM_CAP_EXT_CPUID),
+        since: "2.6.25",
+    },
+    RequiredCap {
+        name: "KVM_CAP_PIT2",
+        probe: Probe::Extension(KVM_CAP_PIT2),
+        since: "2.6.31",
+    },
+];
+
+#[cfg(target_arch = "aarch64")]
+const ARCH_CAPS: [RequiredCap; 3] = [
+    RequiredCap {
+        name: "KVM_CAP_DEVICE_CTRL",
+        probe: Probe::Extension(KVM_CAP_DEVICE_CTRL),
+        since: "3.10",
+    },
+    RequiredCap {
+        name: "KVM_CAP_ARM_PSCI_0_2",
+        probe: Probe::Extension(KVM_CAP_ARM_PSCI_0_2),
+        since: "3.18",
+    },
+    RequiredCap {
+        name: "KVM_DEV_TYPE_ARM_VGIC_V3",
+        probe: Probe::Device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3),
+        since: "3.19",
+    },
+];
+
+/// A capability probed, reported by `query-kvm-caps`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbedCap {
+    /// Name of capability, such as `KVM_CAP_IRQFD`.
+    pub name: &'static str,
+    /// Whether micro VM can't run without it.
+    pub required: bool,
+    /// Whether it's supported by host.
+    pub supported: bool,
+}
+
+/// Capabilities of host KVM.
+#[derive(Default)]
+pub struct KvmCaps {
+    /// `KVM_CAP_SIGNAL_MSI`, MSI can be injected, which enables ITS of GICv3.
+    pub signal_msi: bool,
+    /// `KVM_CAP_READONLY_MEM`, memory slots can be read-only, which is
+    /// needed by firmware.
+    pub readonly_mem: bool,
+    /// All capabilities probed, required ones first.
+    probed: Vec<ProbedCap>,
+}
+
+impl KvmCaps {
+    /// Probe the capabilities of host KVM.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - Host KVM to probe.
+    ///
+    /// # Errors
+    ///
+    /// Any required capability isn't supported, all the missing ones are
+    /// listed with the versions of Linux introducing them.
+    pub fn probe(host: &dyn KvmProbe) -> Result<Self> {
+        let mut probed = Vec::new();
+        let mut missing = Vec::new();
+        for cap in COMMON_CAPS.iter().chain(ARCH_CAPS.iter()) {
+            let supported = match cap.probe {
+                Probe::Extension(ext) => host.check_extension(ext),
+                #[cfg(target_arch = "aarch64")]
+                Probe::Device(device_type) => host.check_device(device_type),
+            };
+            if !supported {
+                missing.push(format!("{} (introduced in Linux {})", cap.name, cap.since));
+            }
+            probed.push(ProbedCap {
+                name: cap.name,
+                required: true,
+                supported,
+            });
+        }
+        if !missing.is_empty() {
+            bail!(
+                "Host KVM doesn't support the capabilities required: {}",
+                missing.join(", ")
+            );
+        }
+
+        let signal_msi = host.check_extension(KVM_CAP_SIGNAL_MSI);
+        let readonly_mem = host.check_extension(KVM_CAP_READONLY_MEM);
+        for &(name, supported) in [
+            ("KVM_CAP_SIGNAL_MSI", signal_msi),
+            ("KVM_CAP_READONLY_MEM", readonly_mem),
+        ]
+        .iter()
+        {
+            if !supported {
+                info!("Optional capability {} isn't supported by host KVM", name);
+            }
+            probed.push(ProbedCap {
+                name,
+                required: false,
+                supported,
+            });
+        }
+
+        Ok(KvmCaps {
+            signal_msi,
+            readonly_mem,
+            probed,
+        })
+    }
+
+    /// Check that firmware can be loaded, which is in read-only memory.
+    pub fn check_firmware(&self) -> Result<()> {
+        if !self.readonly_mem {
+            bail!("Firmware needs KVM_CAP_READONLY_MEM, which isn't supported by host KVM");
+        }
+        Ok(())
+    }
+
+    /// Get all capabilities probed, required ones first.
+    pub fn probed(&self) -> &[ProbedCap] {
+        &self.probed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Host KVM without the extensions and device types listed.
+    struct MockProbe {
+        missing_extensions: Vec<u32>,
+        missing_devices: Vec<u32>,
+    }
+
+    impl KvmProbe for MockProbe {
+        fn check_extension(&self, cap: u32) -> bool {
+            !self.missing_extensions.contains(&cap)
+        }
+
+        fn check_device(&self, device_type: u32) -> bool {
+            !self.missing_devices.contains(&device_type)
+        }
+    }
+
+    #[test]
+    fn test_probe_caps() {
+        let host = MockProbe {
+            missing_extensions: Vec::new(),
+            missing_devices: Vec::new(),
+        };
+        let caps = KvmCaps::probe(&host).unwrap();
+        assert!(caps.signal_msi && caps.readonly_mem);
+        assert!(caps.check_firmware().is_ok());
+        let required = caps.probed().iter().filter(|cap| cap.required).count();
+        assert_eq!(required, COMMON_CAPS.len() + ARCH_CAPS.len());
+        assert_eq!(caps.probed().len(), required + 2);
+        assert!(caps.probed().iter().all(|cap| cap.supported));
+        // Required ones are listed first.
+        assert!(caps.probed()[..required].iter().all(|cap| cap.required));
+    }
+
+    #[test]
+    fn test_missing_required_caps() {
+        #[cfg(target_arch = "x86_64")]
+        let (arch_ext, arch_device, arch_msg) = (
+            KVM_CAP_PIT2,
+            None,
+            "KVM_CAP_PIT2 (introduced in Linux 2.6.31)",
+        );
+        #[cfg(target_arch = "aarch64")]
+        let (arch_ext, arch_device, arch_msg) = (
+            KVM_CAP_ARM_PSCI_0_2,
+            Some(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3),
+            "KVM_CAP_ARM_PSCI_0_2 (introduced in Linux 3.18), \
+             KVM_DEV_TYPE_ARM_VGIC_V3 (introduced in Linux 3.19)",
+        );
+        let host = MockProbe {
+            missing_extensions: vec![KVM_CAP_IOEVENTFD, arch_ext, KVM_CAP_SIGNAL_MSI],
+            missing_devices: arch_device.into_iter().collect(),
+        };
+        let err = KvmCaps::probe(&host).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Host KVM doesn't support the capabilities required: \
+                 KVM_CAP_IOEVENTFD (introduced in Linux 2.6.32), {}",
+                arch_msg
+            )
+        );
+    }
+
+    #[test]
+    fn test_missing_optional_caps() {
+        let host = MockProbe {
+            missing_extensions: vec![KVM_CAP_SIGNAL_MSI, KVM_CAP_READONLY_MEM],
+            missing_devices: Vec::new(),
+        };
+        let caps = KvmCaps::probe(&host).unwrap();
+        // MSI of GICv3 is disabled, and firmware is rejected.
+        assert!(!caps.signal_msi);
+        assert!(!caps.readonly_mem);
+        assert_eq!(
+            caps.check_firmware().err().unwrap().to_string(),
+            "Firmware needs KVM_CAP_READONLY_MEM, which isn't supported by host KVM"
+        );
+        let optional: Vec<(&str, bool)> = caps
+            .probed()
+            .iter()
+            .filter(|cap| !cap.required)
+            .map(|cap| (cap.name, cap.supported))
+            .collect();
+        assert_eq!(
+            optional,
+            vec![
+                ("KVM_CAP_SIGNAL_MSI", false),
+                ("KVM_CAP_READONLY_MEM", false)
+            ]
+        );
+    }
+}
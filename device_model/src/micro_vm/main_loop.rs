@@ -12,11 +12,40 @@
 
 extern crate util;
 
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use util::epoll_context::{EventNotifier, MainLoopContext, MainLoopManager};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use util::epoll_context::{
+    read_fd, EventNotifier, IdleCallback, MainLoopContext, MainLoopManager, NotifierCallback,
+    NotifierOperation,
+};
+use util::loop_stats::LoopStats;
+use util::timer::{TimerCallback, TimerHandle, TimerMode};
+
+use super::micro_syscall::{register_seccomp, SeccompPolicy, ThreadRole};
 
 static mut CURRENT_MAINLOOP: Option<MainLoopContext> = None;
+/// Iothreads indexed by id, each of them is boxed so that its
+/// `MainLoopContext` never moves while the iothread is running.
+static mut IOTHREADS: Option<BTreeMap<String, Box<IoThread>>> = None;
+
+/// Thread running its own `MainLoopContext`, so that events of devices bound
+/// to it are handled apart from `CURRENT_MAINLOOP`.
+struct IoThread {
+    /// Event loop context of the iothread.
+    context: MainLoopContext,
+    /// Eventfd to wake the iothread up, so that it sees the exit advice
+    /// of `MainLoopManager`.
+    exit_evt: EventFd,
+    /// Handle to join the iothread.
+    handle: Option<JoinHandle<()>>,
+}
 
 /// The struct `MainLoop` is the only struct can handle Global variable
 /// `CURRENT_MAINLOOP`. It can manage events add and adjust or start to
@@ -54,6 +83,245 @@ impl MainLoop {
         Self::locked_inner().update_events(notifiers)
     }
 
+    /// Add a timer to `CURRENT_MAINLOOP`, its callback is called in the
+    /// thread running `CURRENT_MAINLOOP`. Timers used by other event loops
+    /// should be added by `MainLoopContext::add_timer` of that loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - Duration before the timer fires, and the interval of
+    ///   periodic timer.
+    /// * `mode` - One-shot or periodic.
+    /// * `callback` - Called when the timer fires.
+    ///
+    /// # Errors
+    ///
+    /// Create timer failed.
+    pub fn add_timer(
+        duration: Duration,
+        mode: TimerMode,
+        callback: Box<TimerCallback>,
+    ) -> util::errors::Result<TimerHandle> {
+        Self::locked_inner().add_timer(duration, mode, callback)
+    }
+
+    /// Add a callback to `CURRENT_MAINLOOP`, which is called when no event is
+    /// ready before the wait timeout, return its id used to remove it.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - Minimum interval between two calls.
+    /// * `callback` - Called when `CURRENT_MAINLOOP` is idle.
+    pub fn add_idle(interval: Duration, callback: Box<IdleCallback>) -> u64 {
+        Self::locked_inner().add_idle(interval, callback)
+    }
+
+    /// Remove the idle callback from `CURRENT_MAINLOOP`, return `false` if it
+    /// doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id returned by `add_idle`.
+    pub fn rm_idle(id: u64) -> bool {
+        Self::locked_inner().rm_idle(id)
+    }
+
+    /// Set the max time of `CURRENT_MAINLOOP` to wait for events, `None`
+    /// means waiting forever unless there are idle callbacks.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The max time to wait for events.
+    pub fn set_wait_timeout(timeout: Option<Duration>) {
+        Self::locked_inner().set_wait_timeout(timeout);
+    }
+
+    /// Update event notifiers to the event loop of iothread `iothread`, or
+    /// `CURRENT_MAINLOOP` if it's `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `iothread` - Id of iothread where events are handled.
+    /// * `notifiers` - The wrapper of events will be handled in the event loop.
+    ///
+    /// # Errors
+    ///
+    /// Iothread not found or update event failed.
+    pub fn update_event_in(
+        iothread: Option<&str>,
+        notifiers: Vec<EventNotifier>,
+    ) -> util::errors::Result<()> {
+        match iothread {
+            None => Self::update_event(notifiers),
+            Some(id) => Self::iothread_inner(id)?.context.update_events(notifiers),
+        }
+    }
+
+    /// Create the event loop of an iothread, it starts to handle events after
+    /// `start_iothreads` is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the iothread.
+    /// * `manager` - The main part to manager the event loop of iothread.
+    ///
+    /// # Errors
+    ///
+    /// The id is used by another iothread, or fail to create its event loop.
+    pub fn add_iothread(id: &str, manager: Arc<dyn MainLoopManager>) -> util::errors::Result<()> {
+        let iothreads = unsafe { IOTHREADS.get_or_insert_with(BTreeMap::new) };
+        if iothreads.contains_key(id) {
+            bail!("Iothread {} already exists.", id);
+        }
+
+        let mut context = MainLoopContext::new();
+        context.set_manager(manager);
+        let exit_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let handler: Box<NotifierCallback> = Box::new(|_, fd| {
+            read_fd(fd);
+            None
+        });
+        context.update_events(vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            exit_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        )])?;
+
+        iothreads.insert(
+            id.to_string(),
+            Box::new(IoThread {
+                context,
+                exit_evt,
+                handle: None,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Spawn threads for iothreads which are not running yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `seccomp` - Seccomp policy of iothreads.
+    ///
+    /// # Errors
+    ///
+    /// Spawn thread failed.
+    pub fn start_iothreads(seccomp: &SeccompPolicy) -> util::errors::Result<()> {
+        let iothreads = unsafe {
+            match &mut IOTHREADS {
+                Some(iothreads) => iothreads,
+                None => return Ok(()),
+            }
+        };
+
+        for (id, iothread) in iothreads.iter_mut() {
+            if iothread.handle.is_some() {
+                continue;
+            }
+
+            let thread_id = id.clone();
+            let seccomp = seccomp.clone();
+            let handle = thread::Builder::new()
+                .name(format!("iothread-{}", id))
+                .spawn(move || {
+                    if let Err(e) = register_seccomp(ThreadRole::Iothread, &seccomp) {
+                        error!(
+                            "Failed to register seccomp in iothread {}: {}",
+                            thread_id, e
+                        );
+                    }
+                    Self::run_iothread(&thread_id);
+                })?;
+            iothread.handle = Some(handle);
+        }
+        Ok(())
+    }
+
+    /// Wake up all iothreads and wait for them to exit, events already
+    /// pending in iothreads are handled before exiting. It's called after
+    /// `MainLoopManager` advises to exit.
+    ///
+    /// # Errors
+    ///
+    /// Fail to wake up or join iothread.
+    pub fn stop_iothreads() -> util::errors::Result<()> {
+        let iothreads = unsafe { IOTHREADS.take() };
+        for (id, mut iothread) in iothreads.into_iter().flatten() {
+            let handle = match iothread.handle.take() {
+                Some(handle) => handle,
+                None => continue,
+            };
+            iothread.exit_evt.write(1)?;
+            if handle.join().is_err() {
+                bail!("Iothread {} panicked.", id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get statistics of `CURRENT_MAINLOOP` and iothreads, the former is
+    /// named `main` and the latter are named by their ids.
+    ///
+    /// # Arguments
+    ///
+    /// * `reset` - Zero the counters after they are read.
+    pub fn stats(reset: bool) -> Vec<(String, LoopStats)> {
+        let mut handles = vec![("main".to_string(), Self::locked_inner().stats_handle())];
+        if let Some(iothreads) = unsafe { IOTHREADS.as_ref() } {
+            for (id, iothread) in iothreads.iter() {
+                handles.push((id.clone(), iothread.context.stats_handle()));
+            }
+        }
+
+        handles
+            .into_iter()
+            .map(|(name, handle)| {
+                let stats = handle.snapshot();
+                if reset {
+                    handle.reset();
+                }
+                (name, stats)
+            })
+            .collect()
+    }
+
+    fn run_iothread(id: &str) {
+        let context = match Self::iothread_inner(id) {
+            Ok(iothread) => &mut iothread.context,
+            Err(_) => return,
+        };
+        info!("iothread {} start running", id);
+        loop {
+            match context.run() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    error!(
+                        "Iothread {} exits unexpectedly: {}",
+                        id,
+                        error_chain::ChainedError::display_chain(&e)
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    fn iothread_inner(id: &str) -> util::errors::Result<&'static mut IoThread> {
+        unsafe {
+            match IOTHREADS
+                .as_mut()
+                .and_then(|iothreads| iothreads.get_mut(id))
+            {
+                Some(iothread) => Ok(iothread),
+                None => bail!("Iothread {} not found.", id),
+            }
+        }
+    }
+
     /// Start to run `CURRENT_MAINLOOP` according `epoll`.
     ///
     /// # Notes
@@ -75,3 +343,80 @@ impl MainLoop {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::channel;
+
+    use super::super::micro_syscall::SeccompMode;
+    use super::*;
+
+    struct TestManager {
+        exit: AtomicBool,
+    }
+
+    impl MainLoopManager for TestManager {
+        fn main_loop_should_exit(&self) -> bool {
+            self.exit.load(Ordering::SeqCst)
+        }
+
+        fn main_loop_cleanup(&self) -> util::errors::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_iothread() {
+        let manager = Arc::new(TestManager {
+            exit: AtomicBool::new(false),
+        });
+        MainLoop::add_iothread("test_iothread", manager.clone()).unwrap();
+        assert!(MainLoop::add_iothread("test_iothread", manager.clone()).is_err());
+        let seccomp = SeccompPolicy::new(SeccompMode::Off, Default::default());
+        MainLoop::start_iothreads(&seccomp).unwrap();
+
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (sender, receiver) = channel();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd| {
+            read_fd(fd);
+            sender
+                .send(thread::current().name().map(String::from))
+                .unwrap();
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        );
+        MainLoop::update_event_in(Some("test_iothread"), vec![notifier]).unwrap();
+        assert!(MainLoop::update_event_in(Some("test_unknown"), Vec::new()).is_err());
+
+        // Events are handled in the iothread.
+        evt.write(1).unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Some("iothread-test_iothread".to_string())
+        );
+
+        // Statistics are reported for each loop.
+        MainLoop::object_init();
+        let stats = MainLoop::stats(false);
+        assert_eq!(stats[0].0, "main");
+        let (_, iothread_stats) = stats
+            .iter()
+            .find(|(name, _)| name == "test_iothread")
+            .unwrap();
+        assert!(iothread_stats.events >= 1);
+        assert!(iothread_stats
+            .notifiers
+            .contains(&(evt.as_raw_fd(), iothread_stats.events)));
+
+        manager.exit.store(true, Ordering::SeqCst);
+        MainLoop::stop_iothreads().unwrap();
+        assert!(MainLoop::update_event_in(Some("test_iothread"), Vec::new()).is_err());
+    }
+}
@@ -10,10 +10,37 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+//! Seccomp allowlists of StratoVirt threads.
+//!
+//! Each thread is confined by the allowlist of its role, which is installed
+//! by the thread itself when it starts:
+//! - vcpu threads run guest, and activate virtio devices when guest driver
+//!   is ready.
+//! - main thread handles QMP, console and events of devices.
+//! - iothreads handle events of devices bound to them.
+//! - migration thread sends VM to the destination of live migration.
+//!
+//! Filters are installed after the setup of each thread, such as binding
+//! the QMP socket in main thread, and checked by a representative syscall
+//! of the role at once, so that a filter installed too early or a wrong
+//! allowlist fails StratoVirt at startup.
+//!
+//! Syscalls only needed by some features, such as io_uring of drives, are
+//! given by fragments, which are composed with the allowlists when the VM
+//! uses these features.
+
 extern crate libc;
 
-use crate::errors::Result;
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use libc::{c_int, c_void, siginfo_t};
+
+use crate::errors::{Error, Result, ResultExt};
 use crate::virtio::vhost::kernel::*;
+use machine_manager::config::{AioEngine, VmConfig};
+use util::aio::{__NR_IO_URING_ENTER, __NR_IO_URING_REGISTER, __NR_IO_URING_SETUP};
 use util::kvm_ioctls_ext::{KVM_GET_DEVICE_ATTR, KVM_HAS_DEVICE_ATTR};
 use util::seccomp::{BpfRule, SeccompCmpOpt, SeccompOpt, SyscallFilter};
 use util::tap::{TUNSETIFF, TUNSETOFFLOAD, TUNSETVNETHDRSZ};
@@ -46,51 +73,184 @@ const FIONBIO: u32 = 0x5421;
 const KVM_RUN: u32 = 0xae80;
 
 // See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/asm-generic/kvm.h
+/// Clone a file by sharing its extents, see ioctl_ficlone(2).
+const FICLONE: u32 = 0x4004_9409;
 const KVM_SET_DEVICE_ATTR: u32 = 0x4018_aee1;
+const KVM_GET_DIRTY_LOG: u32 = 0x4010_ae42;
+const KVM_SET_USER_MEMORY_REGION: u32 = 0x4020_ae46;
+
+/// Syscalls whose numbers or existence differ between architectures. A
+/// function of libc may issue any syscall in its table, depending on libc
+/// and its version.
+///
+/// # Notes
+/// aarch64 only has the generic syscalls, `open`, `unlink` and `epoll_wait`
+/// are replaced by `openat`, `unlinkat` and `epoll_pwait`.
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    /// epoll_wait(2): glibc uses `epoll_wait`, musl uses `epoll_pwait`.
+    pub const EPOLL_WAIT: &[i64] = &[libc::SYS_epoll_wait, libc::SYS_epoll_pwait];
+    /// open(2): musl uses `open`, glibc 2.26+ uses `openat`.
+    pub const OPEN: &[i64] = &[libc::SYS_open, libc::SYS_openat];
+    /// unlink(2).
+    pub const UNLINK: &[i64] = &[libc::SYS_unlink];
+    /// fstat(2): glibc 2.33+ uses `newfstatat`, metadata of Rust std uses
+    /// `statx` first.
+    pub const FSTAT: &[i64] = &[libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx];
+    /// ioctls reading state of vcpus and in-kernel devices, issued in main
+    /// thread by `dump-guest-memory` and `x-snapshot-save`.
+    /// See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/linux/kvm.h
+    pub const STATE_IOCTLS: &[u32] = &[
+        0x8090_ae81, // KVM_GET_REGS
+        0x8138_ae83, // KVM_GET_SREGS
+        0xc008_ae88, // KVM_GET_MSRS
+        0x81a0_ae8c, // KVM_GET_FPU
+        0x8400_ae8e, // KVM_GET_LAPIC
+        0x8004_ae98, // KVM_GET_MP_STATE
+        0x8040_ae9f, // KVM_GET_VCPU_EVENTS
+        0x9000_aea4, // KVM_GET_XSAVE
+        0x8188_aea6, // KVM_GET_XCRS
+        0xc208_ae62, // KVM_GET_IRQCHIP
+        0x8030_ae7c, // KVM_GET_CLOCK
+        0x8070_ae9f, // KVM_GET_PIT2
+    ];
+    /// ioctls setting state of vcpus and in-kernel devices, issued in main
+    /// thread by VM migrated in by `migrate-incoming`.
+    pub const RESTORE_IOCTLS: &[u32] = &[
+        0x4090_ae82, // KVM_SET_REGS
+        0x4138_ae84, // KVM_SET_SREGS
+        0x4008_ae89, // KVM_SET_MSRS
+        0x41a0_ae8d, // KVM_SET_FPU
+        0x4400_ae8f, // KVM_SET_LAPIC
+        0x4004_ae99, // KVM_SET_MP_STATE
+        0x4040_aea0, // KVM_SET_VCPU_EVENTS
+        0x5000_aea5, // KVM_SET_XSAVE
+        0x4188_aea7, // KVM_SET_XCRS
+        0x8208_ae63, // KVM_SET_IRQCHIP
+        0x4030_ae7b, // KVM_SET_CLOCK
+        0x4070_aea0, // KVM_SET_PIT2
+    ];
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    /// epoll_wait(2).
+    pub const EPOLL_WAIT: &[i64] = &[libc::SYS_epoll_pwait];
+    /// open(2).
+    pub const OPEN: &[i64] = &[libc::SYS_openat];
+    /// unlink(2).
+    pub const UNLINK: &[i64] = &[libc::SYS_unlinkat];
+    /// fstat(2): glibc 2.33+ uses `newfstatat`, metadata of Rust std uses
+    /// `statx` first.
+    pub const FSTAT: &[i64] = &[libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx];
+    /// ioctls reading state of vcpus, issued in main thread by
+    /// `dump-guest-memory`.
+    pub const STATE_IOCTLS: &[u32] = &[
+        0x4010_aeab, // KVM_GET_ONE_REG
+    ];
+    /// Migration is not supported on aarch64 yet.
+    pub const RESTORE_IOCTLS: &[u32] = &[];
+}
+
+// An unknown architecture would run with allowlists never audited.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("Seccomp allowlists are only given for x86_64 and aarch64");
+
+/// Role of a thread confined by seccomp, which decides its allowlist.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ThreadRole {
+    /// Thread running a vcpu.
+    Vcpu,
+    /// Main thread running `MainLoop` and QMP.
+    Main,
+    /// Iothread running its own event loop.
+    Iothread,
+    /// Thread running live migration started by QMP.
+    Migration,
+}
+
+impl ThreadRole {
+    /// Name of the role, used in logs.
+    pub fn name(self) -> &'static str {
+        match self {
+            ThreadRole::Vcpu => "vcpu",
+            ThreadRole::Main => "main",
+            ThreadRole::Iothread => "iothread",
+            ThreadRole::Migration => "migration",
+        }
+    }
+}
+
+/// Mode of seccomp sandbox, given by `-seccomp`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SeccompMode {
+    /// Syscalls not allowed are trapped, and StratoVirt exits.
+    Enforce,
+    /// Syscalls not allowed are logged, so that allowlists can be debugged.
+    Log,
+    /// No filter is installed.
+    Off,
+}
 
-/// Create a syscall allowlist for seccomp.
+impl FromStr for SeccompMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "enforce" => Ok(SeccompMode::Enforce),
+            "log" => Ok(SeccompMode::Log),
+            "off" => Ok(SeccompMode::Off),
+            _ => bail!(
+                "Invalid seccomp mode '{}', it should be enforce|log|off",
+                mode
+            ),
+        }
+    }
+}
+
+thread_local! {
+    /// Role of the filter installed in the current thread.
+    static INSTALLED_ROLE: Cell<Option<ThreadRole>> = Cell::new(None);
+}
+
+/// Get the role of the seccomp filter installed in the current thread.
+pub fn installed_role() -> Option<ThreadRole> {
+    INSTALLED_ROLE.with(|role| role.get())
+}
+
+/// Create the syscall allowlist shared by all thread roles, which covers
+/// memory, threads, signals, and fds given by other threads.
 ///
 /// # Notes
-/// This allowlist limit syscall with:
-/// * x86_64-unknown-gnu: 35 syscalls
-/// * x86_64-unknown-musl: 34 syscalls
-/// * aarch64-unknown-gnu: 34 syscalls
-/// * aarch64-unknown-musl: 33 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
-fn syscall_allow_list() -> Vec<BpfRule> {
-    vec![
+fn base_allow_list(ioctl_rule: BpfRule) -> Vec<BpfRule> {
+    let mut rules = vec![
         BpfRule::new(libc::SYS_read),
         BpfRule::new(libc::SYS_write),
-        ioctl_allow_list(),
-        #[cfg(not(all(target_env = "gnu", target_arch = "x86_64")))]
-        BpfRule::new(libc::SYS_epoll_pwait),
-        #[cfg(all(target_env = "gnu", target_arch = "x86_64"))]
-        BpfRule::new(libc::SYS_epoll_wait),
-        BpfRule::new(libc::SYS_io_getevents),
-        BpfRule::new(libc::SYS_io_submit),
+        ioctl_rule,
         BpfRule::new(libc::SYS_dup),
         BpfRule::new(libc::SYS_close),
         BpfRule::new(libc::SYS_eventfd2),
         BpfRule::new(libc::SYS_epoll_ctl),
-        BpfRule::new(libc::SYS_fdatasync),
-        BpfRule::new(libc::SYS_recvmsg),
-        BpfRule::new(libc::SYS_sendmsg),
-        BpfRule::new(libc::SYS_recvfrom),
+        BpfRule::new(libc::SYS_timerfd_create),
+        BpfRule::new(libc::SYS_timerfd_settime),
+        BpfRule::new(libc::SYS_timerfd_gettime),
         BpfRule::new(libc::SYS_mremap),
-        BpfRule::new(libc::SYS_io_setup),
         BpfRule::new(libc::SYS_brk),
         BpfRule::new(libc::SYS_fcntl)
             .add_constraint(SeccompCmpOpt::Eq, 1, F_DUPFD_CLOEXEC)
             .add_constraint(SeccompCmpOpt::Eq, 1, F_SETFD)
             .add_constraint(SeccompCmpOpt::Eq, 1, F_GETFD),
         BpfRule::new(libc::SYS_rt_sigprocmask),
-        #[cfg(target_arch = "x86_64")]
-        BpfRule::new(libc::SYS_open),
-        BpfRule::new(libc::SYS_openat),
         BpfRule::new(libc::SYS_sigaltstack),
         BpfRule::new(libc::SYS_mmap),
         BpfRule::new(libc::SYS_munmap),
-        BpfRule::new(libc::SYS_accept4),
+        // Heaps of malloc arenas in threads are grown by mprotect(2).
+        BpfRule::new(libc::SYS_mprotect).add_constraint(
+            SeccompCmpOpt::Eq,
+            2,
+            (libc::PROT_READ | libc::PROT_WRITE) as u32,
+        ),
         BpfRule::new(libc::SYS_lseek),
         BpfRule::new(libc::SYS_futex)
             .add_constraint(SeccompCmpOpt::Eq, 1, FUTEX_WAKE_PRIVATE)
@@ -107,36 +267,160 @@ fn syscall_allow_list() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_tgkill),
         BpfRule::new(libc::SYS_gettid),
         BpfRule::new(libc::SYS_getpid),
-        BpfRule::new(libc::SYS_fstat),
-        BpfRule::new(libc::SYS_pread64),
-        BpfRule::new(libc::SYS_pwrite64),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_madvise).add_constraint(
             SeccompCmpOpt::Eq,
             2,
             libc::MADV_DONTNEED as u32,
         ),
-    ]
+        // Called when vDSO can't read the clock.
+        BpfRule::new(libc::SYS_clock_gettime),
+        // Called when `HashMap` is first created in a thread.
+        BpfRule::new(libc::SYS_getrandom),
+    ];
+    rules.extend(rules_of(arch::FSTAT));
+    rules
 }
 
-/// Create a syscall bpf rule for syscall `ioctl`.
-fn ioctl_allow_list() -> BpfRule {
-    BpfRule::new(libc::SYS_ioctl)
-        .add_constraint(SeccompCmpOpt::Eq, 1, TCGETS)
+/// Create the syscall allowlist of vcpu threads. Besides running guest,
+/// virtio devices are activated in vcpu threads, which sets up vhost
+/// backends and the aio contexts of drives.
+fn vcpu_allow_list() -> Vec<BpfRule> {
+    let mut rules = base_allow_list(vhost_ioctl_rules(
+        tty_ioctl_rules(BpfRule::new(libc::SYS_ioctl))
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_RUN)
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_DEVICE_ATTR)
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_DEVICE_ATTR() as u32)
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_HAS_DEVICE_ATTR() as u32),
+    ));
+    rules.push(BpfRule::new(libc::SYS_io_setup));
+    rules
+}
+
+/// Create the syscall allowlist of main thread, which accepts and talks to
+/// QMP clients, and handles IO of devices not bound to iothreads.
+fn main_allow_list() -> Vec<BpfRule> {
+    let state_ioctls = arch::STATE_IOCTLS.iter().chain(arch::RESTORE_IOCTLS.iter());
+    let ioctl_rule = state_ioctls.fold(
+        tty_ioctl_rules(BpfRule::new(libc::SYS_ioctl))
+            // The file backing guest memory is cloned by `x-snapshot-save`.
+            .add_constraint(SeccompCmpOpt::Eq, 1, FICLONE)
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_DEVICE_ATTR)
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_DEVICE_ATTR() as u32)
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_HAS_DEVICE_ATTR() as u32),
+        |rule, ioctl| rule.add_constraint(SeccompCmpOpt::Eq, 1, *ioctl),
+    );
+    let mut rules = base_allow_list(vhost_ioctl_rules(ioctl_rule));
+    rules.extend(rules_of(arch::EPOLL_WAIT));
+    rules.extend(vec![
+        BpfRule::new(libc::SYS_io_getevents),
+        BpfRule::new(libc::SYS_io_submit),
+        BpfRule::new(libc::SYS_recvmsg),
+        BpfRule::new(libc::SYS_sendmsg),
+        BpfRule::new(libc::SYS_recvfrom),
+        BpfRule::new(libc::SYS_sendto),
+        BpfRule::new(libc::SYS_fdatasync),
+        BpfRule::new(libc::SYS_fallocate),
+        BpfRule::new(libc::SYS_pread64),
+        BpfRule::new(libc::SYS_pwrite64),
+        BpfRule::new(libc::SYS_accept4),
+        // `migrate_cancel` shuts down the stream of migration.
+        BpfRule::new(libc::SYS_shutdown),
+        // Listener of `migrate-incoming` is bound with `SO_REUSEADDR`.
+        BpfRule::new(libc::SYS_setsockopt)
+            .add_constraint(SeccompCmpOpt::Eq, 2, libc::TCP_NODELAY as u32)
+            .add_constraint(SeccompCmpOpt::Eq, 2, libc::SO_REUSEADDR as u32),
+        // Drives hot-plugged by QMP set up their aio contexts.
+        BpfRule::new(libc::SYS_io_setup),
+        // IO of devices is checked at intervals until it's quiesced.
+        BpfRule::new(libc::SYS_nanosleep),
+        BpfRule::new(libc::SYS_clock_nanosleep),
+    ]);
+    rules.extend(rules_of(arch::OPEN));
+    rules.extend(rules_of(arch::UNLINK));
+    rules
+}
+
+/// Create the syscall allowlist of iothreads, which only submit and reap
+/// IO of drives bound to them.
+fn iothread_allow_list() -> Vec<BpfRule> {
+    let mut rules = base_allow_list(tty_ioctl_rules(BpfRule::new(libc::SYS_ioctl)));
+    rules.extend(rules_of(arch::EPOLL_WAIT));
+    rules.extend(vec![
+        BpfRule::new(libc::SYS_io_getevents),
+        BpfRule::new(libc::SYS_io_submit),
+        BpfRule::new(libc::SYS_fdatasync),
+        BpfRule::new(libc::SYS_fallocate),
+        BpfRule::new(libc::SYS_pread64),
+        BpfRule::new(libc::SYS_pwrite64),
+    ]);
+    rules
+}
+
+/// Create the syscall allowlist of migration thread, which connects to the
+/// destination, logs dirty pages of guest memory, pauses VM and reads the
+/// state of vcpus and in-kernel devices.
+fn migration_allow_list() -> Vec<BpfRule> {
+    let ioctl_rule = arch::STATE_IOCTLS.iter().fold(
+        tty_ioctl_rules(BpfRule::new(libc::SYS_ioctl))
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_DIRTY_LOG)
+            .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_USER_MEMORY_REGION)
+            // Vhost backends are stopped while VM is paused.
+            .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_NET_SET_BACKEND() as u32)
+            .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_VSOCK_SET_RUNNING() as u32),
+        |rule, ioctl| rule.add_constraint(SeccompCmpOpt::Eq, 1, *ioctl),
+    );
+    let mut rules = base_allow_list(ioctl_rule);
+    rules.extend(vec![
+        BpfRule::new(libc::SYS_sendto),
+        BpfRule::new(libc::SYS_recvfrom),
+        BpfRule::new(libc::SYS_socket),
+        BpfRule::new(libc::SYS_connect),
+        BpfRule::new(libc::SYS_setsockopt).add_constraint(
+            SeccompCmpOpt::Eq,
+            2,
+            libc::TCP_NODELAY as u32,
+        ),
+        // Bandwidth is limited by sleeping.
+        BpfRule::new(libc::SYS_nanosleep),
+        BpfRule::new(libc::SYS_clock_nanosleep),
+    ]);
+    rules
+}
+
+/// Create the syscall allowlist of thread role `role`.
+fn allow_list(role: ThreadRole) -> Vec<BpfRule> {
+    match role {
+        ThreadRole::Vcpu => vcpu_allow_list(),
+        ThreadRole::Main => main_allow_list(),
+        ThreadRole::Iothread => iothread_allow_list(),
+        ThreadRole::Migration => migration_allow_list(),
+    }
+}
+
+/// Create rules allowing `syscalls` without constraints.
+fn rules_of(syscalls: &[i64]) -> Vec<BpfRule> {
+    syscalls.iter().map(|nr| BpfRule::new(*nr)).collect()
+}
+
+/// Add constraints of terminal and fd ioctls to `rule` of syscall `ioctl`.
+fn tty_ioctl_rules(rule: BpfRule) -> BpfRule {
+    rule.add_constraint(SeccompCmpOpt::Eq, 1, TCGETS)
         .add_constraint(SeccompCmpOpt::Eq, 1, TCSETS)
         .add_constraint(SeccompCmpOpt::Eq, 1, TIOCGWINSZ)
         .add_constraint(SeccompCmpOpt::Eq, 1, FIOCLEX)
         .add_constraint(SeccompCmpOpt::Eq, 1, FIONBIO)
-        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_RUN)
-        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_DEVICE_ATTR)
-        .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_VSOCK_SET_GUEST_CID() as u32)
+}
+
+/// Add constraints of vhost and tap ioctls to `rule` of syscall `ioctl`.
+fn vhost_ioctl_rules(rule: BpfRule) -> BpfRule {
+    rule.add_constraint(SeccompCmpOpt::Eq, 1, VHOST_VSOCK_SET_GUEST_CID() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_VSOCK_SET_RUNNING() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_CALL() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_NUM() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_ADDR() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_BASE() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_KICK() as u32)
-        .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_VRING_CALL() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_OWNER() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_FEATURES() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_MEM_TABLE() as u32)
@@ -144,20 +428,1059 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETIFF() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETOFFLOAD() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETVNETHDRSZ() as u32)
-        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_DEVICE_ATTR() as u32)
-        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_HAS_DEVICE_ATTR() as u32)
 }
 
-/// Register seccomp rules in syscall allowlist to seccomp.
-pub fn register_seccomp() -> Result<()> {
-    let mut seccomp_filter = SyscallFilter::new(SeccompOpt::Trap);
+/// Fragment of allowlists, which is only allowed when the VM uses the
+/// feature needing it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SeccompFragment {
+    /// io_uring of drives. Rings are set up when drives are activated in
+    /// vcpu threads or hot-plugged in main thread, and entered by the
+    /// threads submitting IO.
+    Uring,
+    /// Pages freed by balloon are discarded by madvise(2) in main thread.
+    Balloon,
+    /// Guest memory backed by `-mem-path`, which is copied to snapshot by
+    /// copy_file_range(2) in main thread if the file system can't clone it.
+    MemFile,
+    /// Migration in deferred by `-incoming defer`, whose listener is created
+    /// by `migrate-incoming` in main thread.
+    Incoming,
+}
 
-    let mut bpf_rules = syscall_allow_list();
-    for bpf_rule in &mut bpf_rules {
-        seccomp_filter.push(bpf_rule);
+impl SeccompFragment {
+    /// Name of the fragment, used in logs and dumps.
+    pub fn name(self) -> &'static str {
+        match self {
+            SeccompFragment::Uring => "uring",
+            SeccompFragment::Balloon => "balloon",
+            SeccompFragment::MemFile => "mem-file",
+            SeccompFragment::Incoming => "incoming",
+        }
     }
 
-    seccomp_filter.realize()?;
+    /// Get the fragments needed by the devices of `vm_config`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - Config of VM, whose devices are realized at boot.
+    pub fn from_config(vm_config: &VmConfig) -> BTreeSet<SeccompFragment> {
+        let mut fragments = BTreeSet::new();
+        if vm_config
+            .drives
+            .iter()
+            .flatten()
+            .any(|drive| drive.aio_engine() == AioEngine::IoUring)
+        {
+            fragments.insert(SeccompFragment::Uring);
+        }
+        if vm_config.balloon.is_some() {
+            fragments.insert(SeccompFragment::Balloon);
+        }
+        if vm_config.machine_config.mem_path.is_some() {
+            fragments.insert(SeccompFragment::MemFile);
+        }
+        fragments
+    }
 
+    /// Create the syscall rules of the fragment for thread role `role`.
+    fn rules(self, role: ThreadRole) -> Vec<BpfRule> {
+        match (self, role) {
+            (SeccompFragment::Uring, ThreadRole::Vcpu) => vec![
+                BpfRule::new(__NR_IO_URING_SETUP),
+                BpfRule::new(__NR_IO_URING_REGISTER),
+            ],
+            (SeccompFragment::Uring, ThreadRole::Main) => vec![
+                BpfRule::new(__NR_IO_URING_ENTER),
+                BpfRule::new(__NR_IO_URING_SETUP),
+                BpfRule::new(__NR_IO_URING_REGISTER),
+            ],
+            (SeccompFragment::Uring, ThreadRole::Iothread) => {
+                vec![BpfRule::new(__NR_IO_URING_ENTER)]
+            }
+            (SeccompFragment::Uring, ThreadRole::Migration) => Vec::new(),
+            (SeccompFragment::Balloon, ThreadRole::Main) => {
+                vec![BpfRule::new(libc::SYS_madvise).add_constraint(
+                    SeccompCmpOpt::Eq,
+                    2,
+                    libc::MADV_DONTNEED as u32,
+                )]
+            }
+            (SeccompFragment::Balloon, _) => Vec::new(),
+            (SeccompFragment::MemFile, ThreadRole::Main) => {
+                vec![BpfRule::new(libc::SYS_copy_file_range)]
+            }
+            (SeccompFragment::MemFile, _) => Vec::new(),
+            (SeccompFragment::Incoming, ThreadRole::Main) => vec![
+                BpfRule::new(libc::SYS_socket),
+                BpfRule::new(libc::SYS_bind),
+                BpfRule::new(libc::SYS_listen),
+            ],
+            (SeccompFragment::Incoming, _) => Vec::new(),
+        }
+    }
+}
+
+/// Builder of the seccomp filter of a thread, which starts from the
+/// allowlist of the thread role.
+pub struct SeccompBuilder {
+    /// Role of the thread to confine.
+    role: ThreadRole,
+    /// Syscall rules allowed.
+    rules: Vec<BpfRule>,
+    /// Operation for syscalls not allowed.
+    opt: SeccompOpt,
+}
+
+impl SeccompBuilder {
+    /// Create a builder with the allowlist of `role`, syscalls not allowed
+    /// are trapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - Role of the thread to confine.
+    pub fn new(role: ThreadRole) -> Self {
+        SeccompBuilder {
+            role,
+            rules: allow_list(role),
+            opt: SeccompOpt::Trap,
+        }
+    }
+
+    /// Allow more syscalls besides the allowlist of the role.
+    ///
+    /// # Arguments
+    ///
+    /// * `rules` - Syscall rules to allow.
+    pub fn allow(mut self, rules: Vec<BpfRule>) -> Self {
+        self.rules.extend(rules);
+        self
+    }
+
+    /// Compose `fragment` with the allowlist. Rules of syscalls already
+    /// allowed are skipped, so a fragment never loosens their constraints.
+    ///
+    /// # Arguments
+    ///
+    /// * `fragment` - Fragment needed by the VM.
+    pub fn fragment(mut self, fragment: SeccompFragment) -> Self {
+        for rule in fragment.rules(self.role) {
+            let nr = rule.syscall_num();
+            if self.rules.iter().all(|allowed| allowed.syscall_num() != nr) {
+                self.rules.push(rule);
+            }
+        }
+        self
+    }
+
+    /// Get the syscalls allowed, in the order they are checked.
+    pub fn syscalls(&self) -> Vec<i64> {
+        self.rules.iter().map(|rule| rule.syscall_num()).collect()
+    }
+
+    /// Set the operation for syscalls not allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `opt` - Operation for syscalls not allowed.
+    pub fn opt(mut self, opt: SeccompOpt) -> Self {
+        self.opt = opt;
+        self
+    }
+
+    /// Install the filter to the current thread, `no_new_privs` is set
+    /// before it. The filter can't be removed later.
+    ///
+    /// # Errors
+    ///
+    /// A filter is already installed in the current thread by this builder,
+    /// or prctl(2) fails.
+    pub fn install(self) -> Result<()> {
+        if let Some(role) = installed_role() {
+            bail!(
+                "Seccomp filter of role {} is already installed in this thread",
+                role.name()
+            );
+        }
+
+        let role = self.role;
+        let mut seccomp_filter = SyscallFilter::new(self.opt);
+        let mut bpf_rules = self.rules;
+        for bpf_rule in &mut bpf_rules {
+            seccomp_filter.push(bpf_rule);
+        }
+        seccomp_filter.realize()?;
+
+        INSTALLED_ROLE.with(|installed| installed.set(Some(role)));
+        Ok(())
+    }
+}
+
+/// Head of `siginfo_t` of `SIGSYS`, which carries the syscall trapped.
+///
+/// See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/asm-generic/siginfo.h#L105
+#[repr(C)]
+struct SigsysInfo {
+    _si_signo: i32,
+    _si_errno: i32,
+    _si_code: i32,
+    _pad: i32,
+    _call_addr: u64,
+    syscall: i32,
+    _arch: u32,
+}
+
+/// Head of `ucontext_t` on aarch64, where registers of the trapped syscall
+/// are saved. It's the layout of kernel, shared by glibc and musl.
+///
+/// See: https://elixir.bootlin.com/linux/v4.19.123/source/arch/arm64/include/asm/ucontext.h#L21
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+struct Aarch64Ucontext {
+    _uc_flags: u64,
+    _uc_link: u64,
+    _uc_stack: [u64; 3],
+    _uc_sigmask: [u64; 16],
+    /// `uc_mcontext` is 16 bytes aligned.
+    _pad: u64,
+    _fault_address: u64,
+    regs: [u64; 31],
+}
+
+/// Buffer of the line logged in signal handler, which never allocates.
+struct SignalSafeBuf {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl SignalSafeBuf {
+    fn new() -> Self {
+        SignalSafeBuf {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            if self.len == self.buf.len() {
+                return;
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_num(&mut self, mut num: u64, radix: u64) {
+        let mut digits = [0_u8; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b"0123456789abcdef"[(num % radix) as usize];
+            num /= radix;
+            if num == 0 {
+                break;
+            }
+        }
+        if radix == 16 {
+            self.push_str("0x");
+        }
+        // It's safe because only ascii digits are pushed.
+        self.push_str(unsafe { std::str::from_utf8_unchecked(&digits[start..]) });
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Get the arguments of the trapped syscall from `ucontext`, and make the
+/// syscall fail with `ENOSYS` after the handler returns.
+///
+/// # Arguments
+///
+/// * `ucontext` - The third argument of `SA_SIGINFO` handler.
+unsafe fn trapped_syscall_args(ucontext: *mut c_void) -> [u64; 6] {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let gregs = &mut (*(ucontext as *mut libc::ucontext_t)).uc_mcontext.gregs;
+        let args = [
+            gregs[libc::REG_RDI as usize] as u64,
+            gregs[libc::REG_RSI as usize] as u64,
+            gregs[libc::REG_RDX as usize] as u64,
+            gregs[libc::REG_R10 as usize] as u64,
+            gregs[libc::REG_R8 as usize] as u64,
+            gregs[libc::REG_R9 as usize] as u64,
+        ];
+        gregs[libc::REG_RAX as usize] = -i64::from(libc::ENOSYS);
+        args
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        let regs = &mut (*(ucontext as *mut Aarch64Ucontext)).regs;
+        let args = [regs[0], regs[1], regs[2], regs[3], regs[4], regs[5]];
+        regs[0] = -i64::from(libc::ENOSYS) as u64;
+        args
+    }
+}
+
+/// Handler of `SIGSYS` in log mode when kernel lacks `SECCOMP_RET_LOG`. The
+/// syscall trapped is logged to stderr, and fails with `ENOSYS`. Only
+/// async-signal-safe operations are done: the line is formatted in a buffer
+/// on stack, and written by write(2) directly.
+extern "C" fn handle_sigsys(_: c_int, info: *mut siginfo_t, ucontext: *mut c_void) {
+    let info = unsafe { &*(info as *const SigsysInfo) };
+    let args = unsafe { trapped_syscall_args(ucontext) };
+    // Role has no destructor, so it's never torn down in thread exiting.
+    let role = INSTALLED_ROLE
+        .try_with(|role| role.get())
+        .ok()
+        .flatten()
+        .map_or("unknown", |role| role.name());
+
+    let mut line = SignalSafeBuf::new();
+    line.push_str("Seccomp: syscall ");
+    line.push_num(info.syscall as u32 as u64, 10);
+    line.push_str(" (");
+    for (index, arg) in args.iter().enumerate() {
+        if index != 0 {
+            line.push_str(", ");
+        }
+        line.push_num(*arg, 16);
+    }
+    line.push_str(") is not allowed in ");
+    line.push_str(role);
+    line.push_str(" thread\n");
+    let bytes = line.as_bytes();
+    unsafe {
+        libc::write(
+            libc::STDERR_FILENO,
+            bytes.as_ptr() as *const c_void,
+            bytes.len(),
+        )
+    };
+}
+
+/// Register `handle_sigsys` as the handler of `SIGSYS`. It's called before
+/// the filter is installed, which forbids sigaction(2).
+fn register_sigsys_handler() -> Result<()> {
+    // It's safe because `sigaction` is initialized by zero and `sigemptyset`.
+    let mut sigaction: libc::sigaction = unsafe { std::mem::zeroed() };
+    sigaction.sa_sigaction = handle_sigsys as *const () as usize;
+    sigaction.sa_flags = libc::SA_SIGINFO;
+    unsafe { libc::sigemptyset(&mut sigaction.sa_mask) };
+    let ret = unsafe { libc::sigaction(libc::SIGSYS, &sigaction, std::ptr::null_mut()) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+            .chain_err(|| "Failed to register handler of SIGSYS");
+    }
     Ok(())
 }
+
+/// Get the operation of log mode. `SECCOMP_RET_LOG` is preferred, otherwise
+/// syscalls not allowed are trapped and logged by the handler of `SIGSYS`.
+fn log_opt() -> Result<SeccompOpt> {
+    if SeccompOpt::Log.is_available() {
+        return Ok(SeccompOpt::Log);
+    }
+    register_sigsys_handler()?;
+    Ok(SeccompOpt::Trap)
+}
+
+/// Seccomp policy of VM, which is the mode of sandbox and the fragments
+/// composed with the allowlists of all thread roles.
+#[derive(Debug, Clone)]
+pub struct SeccompPolicy {
+    /// Mode of seccomp sandbox.
+    mode: SeccompMode,
+    /// Fragments needed by the VM, ordered so that the composition is
+    /// deterministic.
+    fragments: BTreeSet<SeccompFragment>,
+}
+
+impl SeccompPolicy {
+    /// Create a seccomp policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - Mode of seccomp sandbox.
+    /// * `fragments` - Fragments needed by the VM.
+    pub fn new(mode: SeccompMode, fragments: BTreeSet<SeccompFragment>) -> Self {
+        SeccompPolicy { mode, fragments }
+    }
+
+    /// Get the mode of seccomp sandbox.
+    pub fn mode(&self) -> SeccompMode {
+        self.mode
+    }
+
+    /// Check whether `fragment` is composed with the allowlists.
+    pub fn has_fragment(&self, fragment: SeccompFragment) -> bool {
+        self.fragments.contains(&fragment)
+    }
+
+    /// Create the builder of the filter of thread role `role`, with all
+    /// fragments composed.
+    pub fn builder(&self, role: ThreadRole) -> SeccompBuilder {
+        self.fragments
+            .iter()
+            .fold(SeccompBuilder::new(role), |builder, fragment| {
+                builder.fragment(*fragment)
+            })
+    }
+
+    /// Dump the final allowlists of all thread roles, which is printed by
+    /// `-seccomp dump`. Syscalls are given by numbers of the host
+    /// architecture, in the order they are checked.
+    pub fn dump(&self) -> String {
+        let fragments: Vec<&str> = self.fragments.iter().map(|f| f.name()).collect();
+        let mut dump = format!(
+            "# Seccomp allowlists of {}, fragments: {}\n",
+            std::env::consts::ARCH,
+            if fragments.is_empty() {
+                "none".to_string()
+            } else {
+                fragments.join(",")
+            }
+        );
+        for role in [
+            ThreadRole::Vcpu,
+            ThreadRole::Main,
+            ThreadRole::Iothread,
+            ThreadRole::Migration,
+        ]
+        .iter()
+        {
+            let syscalls: Vec<String> = self
+                .builder(*role)
+                .syscalls()
+                .iter()
+                .map(|nr| nr.to_string())
+                .collect();
+            dump.push_str(&format!("{}: {}\n", role.name(), syscalls.join(" ")));
+        }
+        dump
+    }
+}
+
+/// Check the filter installed in the current thread by a representative
+/// syscall of `role`, which is given an invalid fd so that it has no side
+/// effect, and fails with `EBADF` if the filter allows it.
+///
+/// # Arguments
+///
+/// * `role` - Role of the current thread.
+///
+/// # Errors
+///
+/// The filter of `role` is not installed in the current thread, or the
+/// syscall is not allowed. In enforce mode, StratoVirt is trapped by the
+/// syscall not allowed instead.
+pub fn self_check(role: ThreadRole) -> Result<()> {
+    if installed_role() != Some(role) {
+        bail!(
+            "Seccomp filter of role {} is not installed in this thread",
+            role.name()
+        );
+    }
+
+    let (syscall, ret) = match role {
+        ThreadRole::Vcpu => ("ioctl(KVM_RUN)", unsafe {
+            libc::ioctl(-1, KVM_RUN as _, 0)
+        }),
+        ThreadRole::Main => ("accept4", unsafe {
+            libc::accept4(
+                -1,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        }),
+        ThreadRole::Iothread => {
+            let mut event = libc::epoll_event { events: 0, u64: 0 };
+            ("epoll_wait", unsafe {
+                libc::epoll_wait(-1, &mut event, 1, 0)
+            })
+        }
+        ThreadRole::Migration => ("ioctl(KVM_GET_DIRTY_LOG)", unsafe {
+            libc::ioctl(-1, KVM_GET_DIRTY_LOG as _, 0)
+        }),
+    };
+    let err = std::io::Error::last_os_error();
+    if ret != -1 || err.raw_os_error() != Some(libc::EBADF) {
+        bail!(
+            "Seccomp self-check of role {} failed, {} is not allowed: {}",
+            role.name(),
+            syscall,
+            err
+        );
+    }
+    Ok(())
+}
+
+/// Register seccomp rules in the allowlist of thread role `role`, composed
+/// with the fragments of `policy`, to the current thread, and check them by
+/// `self_check`.
+///
+/// # Arguments
+///
+/// * `role` - Role of the current thread.
+/// * `policy` - Seccomp policy of VM, nothing is installed if it's off.
+///
+/// # Notes
+///
+/// It's called at the end of the setup of the thread, syscalls only needed
+/// by setup are not allowed after it.
+pub fn register_seccomp(role: ThreadRole, policy: &SeccompPolicy) -> Result<()> {
+    let opt = match policy.mode {
+        SeccompMode::Enforce => SeccompOpt::Trap,
+        SeccompMode::Log => log_opt()?,
+        SeccompMode::Off => return Ok(()),
+    };
+    policy.builder(role).opt(opt).install()?;
+    self_check(role)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syscalls(rules: &[BpfRule]) -> Vec<i64> {
+        rules.iter().map(|rule| rule.syscall_num()).collect()
+    }
+
+    #[test]
+    fn test_allow_lists() {
+        let base = syscalls(&base_allow_list(BpfRule::new(libc::SYS_ioctl)));
+        for role in [
+            ThreadRole::Vcpu,
+            ThreadRole::Main,
+            ThreadRole::Iothread,
+            ThreadRole::Migration,
+        ]
+        .iter()
+        {
+            let list = syscalls(&allow_list(*role));
+            for nr in base.iter() {
+                assert!(list.contains(nr), "{} lacks {}", role.name(), nr);
+            }
+            let mut dedup = list.clone();
+            dedup.sort();
+            dedup.dedup();
+            assert_eq!(dedup.len(), list.len(), "{}", role.name());
+        }
+        for nr in [
+            libc::SYS_read,
+            libc::SYS_write,
+            libc::SYS_ioctl,
+            libc::SYS_mmap,
+        ]
+        .iter()
+        {
+            assert!(base.contains(nr));
+        }
+        assert!(base.contains(&libc::SYS_futex));
+
+        // Only main thread talks to QMP clients.
+        let vcpu = syscalls(&vcpu_allow_list());
+        let main = syscalls(&main_allow_list());
+        let iothread = syscalls(&iothread_allow_list());
+        for nr in [libc::SYS_accept4, libc::SYS_recvmsg, libc::SYS_sendmsg].iter() {
+            assert!(main.contains(nr));
+            assert!(!vcpu.contains(nr));
+            assert!(!iothread.contains(nr));
+        }
+        for nr in arch::EPOLL_WAIT.iter() {
+            assert!(main.contains(nr));
+            assert!(iothread.contains(nr));
+            assert!(!vcpu.contains(nr));
+        }
+
+        // Iothreads submit IO, but never set up contexts or open files.
+        for nr in [libc::SYS_pread64, libc::SYS_pwrite64, libc::SYS_io_submit].iter() {
+            assert!(iothread.contains(nr));
+            assert!(!vcpu.contains(nr));
+        }
+        for nr in [libc::SYS_io_setup, __NR_IO_URING_SETUP].iter() {
+            assert!(!iothread.contains(nr));
+        }
+
+        // Only migration thread connects out, and it never accepts or
+        // opens files.
+        let migration = syscalls(&migration_allow_list());
+        for nr in [libc::SYS_socket, libc::SYS_connect].iter() {
+            assert!(migration.contains(nr));
+            assert!(!main.contains(nr));
+            assert!(!vcpu.contains(nr));
+            assert!(!iothread.contains(nr));
+        }
+        for nr in [libc::SYS_accept4, libc::SYS_io_submit, libc::SYS_pwrite64]
+            .iter()
+            .chain(arch::OPEN.iter())
+            .chain(arch::EPOLL_WAIT.iter())
+        {
+            assert!(!migration.contains(nr));
+        }
+        for nr in arch::OPEN.iter().chain(arch::UNLINK.iter()) {
+            assert!(main.contains(nr));
+            assert!(!iothread.contains(nr));
+        }
+        assert!(vcpu.contains(&libc::SYS_io_setup));
+
+        // io_uring is only allowed by its fragment.
+        for list in [&vcpu, &main, &iothread].iter() {
+            for nr in [
+                __NR_IO_URING_SETUP,
+                __NR_IO_URING_ENTER,
+                __NR_IO_URING_REGISTER,
+            ]
+            .iter()
+            {
+                assert!(!list.contains(nr));
+            }
+        }
+    }
+
+    #[test]
+    fn test_fragments() {
+        use machine_manager::config::{BalloonConfig, DriveConfig};
+
+        // Drives without io_uring and no balloon.
+        let mut vm_config = VmConfig::default();
+        assert!(SeccompFragment::from_config(&vm_config).is_empty());
+        vm_config.drives = Some(vec![
+            DriveConfig {
+                aio: Some(AioEngine::Native),
+                ..Default::default()
+            },
+            DriveConfig {
+                direct: false,
+                ..Default::default()
+            },
+        ]);
+        assert!(SeccompFragment::from_config(&vm_config).is_empty());
+        let policy = SeccompPolicy::new(
+            SeccompMode::Enforce,
+            SeccompFragment::from_config(&vm_config),
+        );
+        for role in [ThreadRole::Vcpu, ThreadRole::Main, ThreadRole::Iothread].iter() {
+            let list = policy.builder(*role).syscalls();
+            assert!(!list.contains(&__NR_IO_URING_SETUP));
+            assert!(!list.contains(&__NR_IO_URING_ENTER));
+        }
+
+        // The filter rejects io_uring_setup, it fails with `EPERM` instead
+        // of being trapped.
+        let builder = policy.builder(ThreadRole::Main);
+        std::thread::spawn(move || {
+            builder
+                .opt(SeccompOpt::Errno(libc::EPERM as u32))
+                .install()
+                .unwrap();
+            let ret = unsafe { libc::syscall(__NR_IO_URING_SETUP, 1, std::ptr::null::<u8>()) };
+            assert_eq!(ret, -1);
+            assert_eq!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::EPERM)
+            );
+        })
+        .join()
+        .unwrap();
+
+        // A drive with io_uring, and balloon.
+        vm_config.drives.as_mut().unwrap().push(DriveConfig {
+            aio: None,
+            direct: true,
+            ..Default::default()
+        });
+        vm_config.balloon = Some(BalloonConfig::default());
+        let fragments = SeccompFragment::from_config(&vm_config);
+        assert_eq!(
+            fragments.iter().cloned().collect::<Vec<_>>(),
+            vec![SeccompFragment::Uring, SeccompFragment::Balloon]
+        );
+        let policy = SeccompPolicy::new(SeccompMode::Enforce, fragments);
+        assert!(policy.has_fragment(SeccompFragment::Uring));
+        let vcpu = policy.builder(ThreadRole::Vcpu).syscalls();
+        let main = policy.builder(ThreadRole::Main).syscalls();
+        let iothread = policy.builder(ThreadRole::Iothread).syscalls();
+        assert!(vcpu.contains(&__NR_IO_URING_SETUP));
+        assert!(!vcpu.contains(&__NR_IO_URING_ENTER));
+        assert!(main.contains(&__NR_IO_URING_SETUP));
+        assert!(main.contains(&__NR_IO_URING_ENTER));
+        assert!(iothread.contains(&__NR_IO_URING_ENTER));
+        assert!(!iothread.contains(&__NR_IO_URING_SETUP));
+        assert!(main.contains(&libc::SYS_madvise));
+        assert!(main.contains(&libc::SYS_nanosleep));
+        for list in [&vcpu, &main, &iothread].iter() {
+            let mut dedup = list.to_vec();
+            dedup.sort();
+            dedup.dedup();
+            assert_eq!(dedup.len(), list.len());
+        }
+
+        // Dump is the same however the fragments are inserted.
+        let mut reversed = BTreeSet::new();
+        reversed.insert(SeccompFragment::Balloon);
+        reversed.insert(SeccompFragment::Uring);
+        let dump = policy.dump();
+        assert_eq!(SeccompPolicy::new(SeccompMode::Log, reversed).dump(), dump);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].ends_with("fragments: uring,balloon"));
+        assert!(lines[2].starts_with(&format!("main: {} {} ", libc::SYS_read, libc::SYS_write)));
+        assert!(lines[3].contains(&format!(" {}", __NR_IO_URING_ENTER)));
+        assert!(lines[4].starts_with("migration: "));
+        assert!(!lines[4].contains(&format!(" {}", __NR_IO_URING_ENTER)));
+        assert!(SeccompPolicy::new(SeccompMode::Enforce, BTreeSet::new())
+            .dump()
+            .starts_with(&format!(
+                "# Seccomp allowlists of {}, fragments: none\n",
+                std::env::consts::ARCH
+            )));
+
+        // Guest memory backed by file.
+        vm_config.machine_config.mem_path = Some("/dev/shm/vm.mem".to_string());
+        let fragments = SeccompFragment::from_config(&vm_config);
+        assert!(fragments.contains(&SeccompFragment::MemFile));
+        let policy = SeccompPolicy::new(SeccompMode::Enforce, fragments);
+        let main = policy.builder(ThreadRole::Main).syscalls();
+        let vcpu = policy.builder(ThreadRole::Vcpu).syscalls();
+        assert!(main.contains(&libc::SYS_copy_file_range));
+        assert!(!vcpu.contains(&libc::SYS_copy_file_range));
+
+        // Listener of `migrate-incoming` is only allowed in main thread of
+        // VM deferred by `-incoming defer`.
+        let main = SeccompBuilder::new(ThreadRole::Main).syscalls();
+        assert!(!main.contains(&libc::SYS_bind));
+        let mut fragments = BTreeSet::new();
+        fragments.insert(SeccompFragment::Incoming);
+        let policy = SeccompPolicy::new(SeccompMode::Enforce, fragments);
+        let main = policy.builder(ThreadRole::Main).syscalls();
+        let migration = policy.builder(ThreadRole::Migration).syscalls();
+        for nr in [libc::SYS_socket, libc::SYS_bind, libc::SYS_listen].iter() {
+            assert!(main.contains(nr));
+        }
+        assert!(!migration.contains(&libc::SYS_bind));
+        assert!(policy
+            .dump()
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with("fragments: incoming"));
+    }
+
+    #[test]
+    fn test_install_once() {
+        // Syscalls not allowed are allowed too, so that the test thread
+        // isn't trapped.
+        let handle = std::thread::spawn(|| {
+            assert_eq!(installed_role(), None);
+            SeccompBuilder::new(ThreadRole::Iothread)
+                .opt(SeccompOpt::Allow)
+                .install()
+                .unwrap();
+            assert_eq!(installed_role(), Some(ThreadRole::Iothread));
+            let err = SeccompBuilder::new(ThreadRole::Main)
+                .opt(SeccompOpt::Allow)
+                .install()
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Seccomp filter of role iothread is already installed in this thread"
+            );
+            assert_eq!(installed_role(), Some(ThreadRole::Iothread));
+        });
+        handle.join().unwrap();
+
+        // Installed per thread.
+        assert_eq!(installed_role(), None);
+        let handle = std::thread::spawn(|| {
+            SeccompBuilder::new(ThreadRole::Vcpu)
+                .allow(vec![BpfRule::new(libc::SYS_getppid)])
+                .opt(SeccompOpt::Allow)
+                .install()
+                .unwrap();
+            assert_eq!(installed_role(), Some(ThreadRole::Vcpu));
+        });
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_seccomp_mode() {
+        assert_eq!(
+            "enforce".parse::<SeccompMode>().unwrap(),
+            SeccompMode::Enforce
+        );
+        assert_eq!("log".parse::<SeccompMode>().unwrap(), SeccompMode::Log);
+        assert_eq!("off".parse::<SeccompMode>().unwrap(), SeccompMode::Off);
+        assert_eq!(
+            "trap".parse::<SeccompMode>().unwrap_err().to_string(),
+            "Invalid seccomp mode 'trap', it should be enforce|log|off"
+        );
+
+        let mut line = SignalSafeBuf::new();
+        line.push_num(0, 10);
+        line.push_str(" ");
+        line.push_num(u64::max_value(), 10);
+        line.push_str(" ");
+        line.push_num(0xdead_beef, 16);
+        assert_eq!(line.as_bytes(), b"0 18446744073709551615 0xdeadbeef");
+        for _ in 0..100 {
+            line.push_num(u64::max_value(), 16);
+        }
+        assert_eq!(line.as_bytes().len(), 256);
+    }
+
+    #[test]
+    fn test_log_mode() {
+        // Syscalls not allowed are logged, and the thread survives.
+        let handle = std::thread::spawn(|| {
+            let policy = SeccompPolicy::new(SeccompMode::Log, BTreeSet::new());
+            register_seccomp(ThreadRole::Iothread, &policy).unwrap();
+            assert!(unsafe { libc::syscall(libc::SYS_getppid) } > 0);
+        });
+        handle.join().unwrap();
+
+        // Kernel lacks `SECCOMP_RET_LOG`, they fail with `ENOSYS` instead.
+        let handle = std::thread::spawn(|| {
+            register_sigsys_handler().unwrap();
+            SeccompBuilder::new(ThreadRole::Iothread)
+                .opt(SeccompOpt::Trap)
+                .install()
+                .unwrap();
+            assert_eq!(unsafe { libc::syscall(libc::SYS_getppid) }, -1);
+            assert_eq!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::ENOSYS)
+            );
+        });
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_self_check() {
+        let roles = [
+            ThreadRole::Vcpu,
+            ThreadRole::Main,
+            ThreadRole::Iothread,
+            ThreadRole::Migration,
+        ];
+        for role in roles.iter().cloned() {
+            std::thread::spawn(move || {
+                assert_eq!(
+                    self_check(role).unwrap_err().to_string(),
+                    format!(
+                        "Seccomp filter of role {} is not installed in this thread",
+                        role.name()
+                    )
+                );
+                SeccompBuilder::new(role)
+                    .opt(SeccompOpt::Errno(libc::EPERM as u32))
+                    .install()
+                    .unwrap();
+                self_check(role).unwrap();
+
+                // Setup of main thread, such as binding QMP socket, fails
+                // after the filter is installed.
+                let path = std::env::temp_dir().join("stratovirt-seccomp-early.sock");
+                let err = std::os::unix::net::UnixListener::bind(&path).unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+            })
+            .join()
+            .unwrap();
+        }
+
+        // The representative syscall of main thread is not allowed for vcpu.
+        std::thread::spawn(|| {
+            SeccompBuilder::new(ThreadRole::Vcpu)
+                .opt(SeccompOpt::Errno(libc::EPERM as u32))
+                .install()
+                .unwrap();
+            INSTALLED_ROLE.with(|installed| installed.set(Some(ThreadRole::Main)));
+            let err = self_check(ThreadRole::Main).unwrap_err().to_string();
+            assert!(
+                err.starts_with("Seccomp self-check of role main failed, accept4 is not allowed"),
+                "{}",
+                err
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_daemonize() {
+        use std::fs::File;
+        use std::io::{Read, Write};
+        use std::os::unix::io::FromRawFd;
+        use util::daemonize::daemonize;
+
+        // Daemonize in a child process of test like StratoVirt, the daemon
+        // reports whether the filter of main thread is installed through a
+        // pipe. Return the exit code of the process waiting for the daemon,
+        // and the report.
+        fn run_daemon(filter_first: bool) -> (i32, Vec<u8>) {
+            let mut fds = [-1; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            let (mut reader, mut writer) =
+                unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+            let pid = unsafe { libc::fork() };
+            assert!(pid >= 0);
+            if pid == 0 {
+                // The forked processes never return to the test harness.
+                drop(reader);
+                if let Ok(notifier) = daemonize() {
+                    let policy = SeccompPolicy::new(SeccompMode::Enforce, BTreeSet::new());
+                    let ret = if filter_first {
+                        register_seccomp(ThreadRole::Main, &policy)
+                            .and_then(|_| notifier.notify_ready().map_err(Error::from))
+                    } else {
+                        notifier
+                            .notify_ready()
+                            .map_err(Error::from)
+                            .and_then(|_| register_seccomp(ThreadRole::Main, &policy))
+                    };
+                    let _ = writer.write_all(&[ret.is_ok() as u8]);
+                }
+                unsafe { libc::_exit(0) };
+            }
+            drop(writer);
+
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            assert!(libc::WIFEXITED(status));
+            let mut report = Vec::new();
+            reader.read_to_end(&mut report).unwrap();
+            (libc::WEXITSTATUS(status), report)
+        }
+
+        // The daemon is ready before the filter is installed, which forbids
+        // changing working directory.
+        assert_eq!(run_daemon(false), (0, vec![1]));
+        // Otherwise, the daemon is trapped and fails to start.
+        assert_eq!(run_daemon(true), (1, vec![]));
+    }
+
+    #[test]
+    fn test_arch_tables() {
+        // Numbers of kernel, see arch/x86/entry/syscalls/syscall_64.tbl and
+        // include/uapi/asm-generic/unistd.h.
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(arch::EPOLL_WAIT, &[232, 281]);
+            assert_eq!(arch::OPEN, &[2, 257]);
+            assert_eq!(arch::UNLINK, &[87]);
+            assert_eq!(arch::FSTAT, &[5, 262, 332]);
+            assert_eq!(libc::SYS_clock_gettime, 228);
+            assert_eq!(libc::SYS_fcntl, 72);
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            assert_eq!(arch::EPOLL_WAIT, &[22]);
+            assert_eq!(arch::OPEN, &[56]);
+            assert_eq!(arch::UNLINK, &[35]);
+            assert_eq!(arch::FSTAT, &[80, 79, 291]);
+            assert_eq!(libc::SYS_clock_gettime, 113);
+            assert_eq!(libc::SYS_fcntl, 25);
+        }
+        assert_eq!(__NR_IO_URING_SETUP, 425);
+        assert_eq!(__NR_IO_URING_ENTER, 426);
+        assert_eq!(__NR_IO_URING_REGISTER, 427);
+
+        for role in [ThreadRole::Vcpu, ThreadRole::Main, ThreadRole::Iothread].iter() {
+            let list = syscalls(&allow_list(*role));
+            for nr in arch::FSTAT.iter() {
+                assert!(list.contains(nr), "{} lacks {}", role.name(), nr);
+            }
+            assert!(list.contains(&libc::SYS_clock_gettime));
+        }
+    }
+
+    #[test]
+    fn test_filters_run() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        // Syscalls not allowed fail with `EPERM` instead of being trapped,
+        // so that a syscall lacked by the allowlists fails the test.
+        let opt = SeccompOpt::Errno(libc::EPERM as u32);
+        let path = std::env::temp_dir().join(format!("stratovirt-seccomp-{}", std::process::id()));
+        std::fs::write(&path, b"seccomp").unwrap();
+
+        // A slice of event loop and drive IO in iothread.
+        let file = std::fs::File::open(&path).unwrap();
+        let epoll_fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        let evt_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK) };
+        assert!(epoll_fd >= 0 && evt_fd >= 0);
+        std::thread::spawn(move || {
+            SeccompBuilder::new(ThreadRole::Iothread)
+                .opt(opt)
+                .install()
+                .unwrap();
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: 0,
+            };
+            let ret = unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, evt_fd, &mut event) };
+            assert_eq!(ret, 0);
+            let value = 1_u64;
+            let ret = unsafe { libc::write(evt_fd, &value as *const u64 as *const c_void, 8) };
+            assert_eq!(ret, 8);
+            let ret = unsafe { libc::epoll_wait(epoll_fd, &mut event, 1, 1000) };
+            assert_eq!(ret, 1);
+            let mut value = 0_u64;
+            let ret = unsafe { libc::read(evt_fd, &mut value as *mut u64 as *mut c_void, 8) };
+            assert_eq!(ret, 8);
+            let timer_fd =
+                unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_NONBLOCK) };
+            assert!(timer_fd >= 0);
+
+            let mut buf = [0_u8; 7];
+            let ret =
+                unsafe { libc::pread(file.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, 7, 0) };
+            assert_eq!(ret, 7);
+            assert_eq!(&buf, b"seccomp");
+            assert_eq!(unsafe { libc::fdatasync(file.as_raw_fd()) }, 0);
+
+            let _ = std::time::Instant::now();
+            let _ = std::collections::HashMap::<u32, u32>::new();
+            let memory = vec![1_u8; 1 << 24];
+            assert_eq!(memory[(1 << 24) - 1], 1);
+            for fd in [timer_fd, evt_fd, epoll_fd].iter() {
+                assert_eq!(unsafe { libc::close(*fd) }, 0);
+            }
+        })
+        .join()
+        .unwrap();
+
+        // A slice of QMP and file operations in main thread.
+        let sock_path = path.with_extension("sock");
+        let _ = std::fs::remove_file(&sock_path);
+        let listener = UnixListener::bind(&sock_path).unwrap();
+        let mut client = UnixStream::connect(&sock_path).unwrap();
+        let handle = std::thread::spawn(move || {
+            SeccompBuilder::new(ThreadRole::Main)
+                .opt(opt)
+                .install()
+                .unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_nonblocking(true).unwrap();
+            let mut stream = stream.try_clone().unwrap();
+            std::io::Write::write_all(&mut stream, b"{\"QMP\":{}}").unwrap();
+
+            let mut file = std::fs::File::open(&path).unwrap();
+            assert_eq!(file.metadata().unwrap().len(), 7);
+            let mut content = String::new();
+            file.read_to_string(&mut content).unwrap();
+            assert_eq!(content, "seccomp");
+            std::fs::remove_file(&path).unwrap();
+            std::fs::remove_file(&sock_path).unwrap();
+        });
+        handle.join().unwrap();
+        let mut greeting = String::new();
+        client.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "{\"QMP\":{}}");
+    }
+}
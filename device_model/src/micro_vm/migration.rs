@@ -0,0 +1,1267 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Live migration of VM over a stream, such as a TCP connection.
+//!
+//! The source sends a migration header followed by messages, each of which
+//! is a message header with its kind and length, and the payload:
+//! 1. `config` - `VmConfig` in json, the destination replies to it after
+//!    it's checked.
+//! 2. `ram` - Guest memory at an address. All of the non-zero pages are
+//!    sent first, then the pages written by guest while they were being
+//!    sent, in passes until the rest can be sent within the downtime limit.
+//! 3. `state` - Snapshot of devices and vcpus without `ram` section, sent
+//!    with the last dirty pages after VM is paused.
+//! 4. `end` - The destination replies to it after the state is loaded, then
+//!    VM is handed off to the destination.
+//!
+//! The destination replies `ack`, or `error` with the reason of failure.
+//! All the integers are little endian.
+//!
+//! Migration out is driven by `Migration`, which runs it in the migration
+//! thread and reports its status, as QMP `migrate` and `query-migrate` do.
+
+use std::cmp::{max, min};
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use util::byte_code::ByteCode;
+
+use crate::errors::{Result, ResultExt};
+
+const MIGRATION_MAGIC: [u8; 8] = *b"SVMIGR\0\0";
+/// Version of migration stream.
+pub const MIGRATION_VERSION: u32 = 1;
+#[cfg(target_arch = "x86_64")]
+const MIGRATION_ARCH: u32 = 1;
+#[cfg(target_arch = "aarch64")]
+const MIGRATION_ARCH: u32 = 2;
+
+const MSG_CONFIG: u32 = 1;
+const MSG_RAM: u32 = 2;
+const MSG_STATE: u32 = 3;
+const MSG_END: u32 = 4;
+const MSG_ACK: u32 = 5;
+const MSG_ERROR: u32 = 6;
+
+/// Size of page whose content is checked to be zero in the first pass.
+const RAM_PAGE_SIZE: u64 = 4096;
+/// Guest memory is sent in chunks of 1 MiB.
+const RAM_CHUNK_SIZE: u64 = 1 << 20;
+/// Size of the address preceding the data of a `ram` message.
+const RAM_ADDR_SIZE: u64 = 8;
+/// Max size of the payload of messages other than `ram`.
+const MAX_PAYLOAD_SIZE: u64 = 64 << 20;
+/// Max number of passes of dirty pages before VM is paused, so that
+/// migration completes even if guest dirties memory faster than it's sent.
+const MAX_ITERATIONS: u64 = 30;
+/// Bytes allowed by the bandwidth limit are counted in periods.
+const THROTTLE_PERIOD: Duration = Duration::from_millis(100);
+/// Max downtime limit in milliseconds, the same as Qemu.
+const MAX_DOWNTIME_LIMIT: u64 = 2_000_000;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct MigrationHeader {
+    magic: [u8; 8],
+    version: u32,
+    arch: u32,
+}
+
+impl ByteCode for MigrationHeader {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct MessageHeader {
+    kind: u32,
+    reserved: u32,
+    /// Length of the payload following the header.
+    len: u64,
+}
+
+impl ByteCode for MessageHeader {}
+
+fn message_name(kind: u32) -> String {
+    match kind {
+        MSG_CONFIG => "config".to_string(),
+        MSG_RAM => "ram".to_string(),
+        MSG_STATE => "state".to_string(),
+        MSG_END => "end".to_string(),
+        MSG_ACK => "ack".to_string(),
+        MSG_ERROR => "error".to_string(),
+        _ => format!("unknown({})", kind),
+    }
+}
+
+/// Parameters of migration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MigrationParams {
+    /// Max bytes sent per second before VM is paused, unlimited if it's 0.
+    pub max_bandwidth: u64,
+    /// Max milliseconds VM is allowed to be paused for the last pass.
+    pub downtime_limit: u64,
+}
+
+impl Default for MigrationParams {
+    fn default() -> Self {
+        MigrationParams {
+            max_bandwidth: 32 << 20,
+            downtime_limit: 300,
+        }
+    }
+}
+
+/// Progress of migration, which is updated while it's running and can be
+/// cancelled from another thread.
+#[derive(Default)]
+pub struct MigrationProgress {
+    /// Bytes sent to the destination.
+    pub transferred: AtomicU64,
+    /// Bytes of guest memory.
+    pub total: AtomicU64,
+    /// Bytes of dirty pages left to send.
+    pub remaining: AtomicU64,
+    /// Pages dirtied by guest per second in the last pass.
+    pub dirty_pages_rate: AtomicU64,
+    /// Number of times dirty pages are synced.
+    pub dirty_sync_count: AtomicU64,
+    /// Milliseconds VM is paused for the last pass.
+    pub downtime: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl MigrationProgress {
+    /// Cancel migration, which fails at its next step.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether migration is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Status of migration out, the same as `MigrationStatus` of Qemu.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MigrationStatus {
+    /// No migration is started.
+    None,
+    /// Connecting to the destination.
+    Setup,
+    /// Guest memory and state are being sent.
+    Active,
+    /// Migration is cancelled, but not stopped yet.
+    Cancelling,
+    /// VM is handed off to the destination.
+    Completed,
+    /// Migration failed, and VM keeps running if it was.
+    Failed,
+    /// Migration is stopped after cancelled, and VM keeps running if it was.
+    Cancelled,
+}
+
+impl MigrationStatus {
+    /// Name of the status, reported by `query-migrate` and `MIGRATION`.
+    pub fn name(self) -> &'static str {
+        match self {
+            MigrationStatus::None => "none",
+            MigrationStatus::Setup => "setup",
+            MigrationStatus::Active => "active",
+            MigrationStatus::Cancelling => "cancelling",
+            MigrationStatus::Completed => "completed",
+            MigrationStatus::Failed => "failed",
+            MigrationStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether migration is started but not stopped.
+    pub fn is_running(self) -> bool {
+        matches!(
+            self,
+            MigrationStatus::Setup | MigrationStatus::Active | MigrationStatus::Cancelling
+        )
+    }
+}
+
+/// Information of the last migration out, reported by `query-migrate`.
+pub struct MigrationInfo {
+    /// Status of migration.
+    pub status: MigrationStatus,
+    /// Progress of migration.
+    pub progress: Arc<MigrationProgress>,
+    /// Milliseconds since migration started, until it stopped.
+    pub total_time: u64,
+    /// Reason of failure if migration failed.
+    pub error: Option<String>,
+}
+
+struct MigrationState {
+    status: MigrationStatus,
+    progress: Arc<MigrationProgress>,
+    start: Instant,
+    end: Option<Instant>,
+    error: Option<String>,
+    /// Fd of stream to the destination, which is shut down when migration
+    /// is cancelled, so that it doesn't block on the stream.
+    stream_fd: Option<RawFd>,
+}
+
+/// Controller of migration out, which keeps its parameters and status.
+/// Migration is started by `start`, then run by `run` in another thread,
+/// and each change of status is notified.
+pub struct Migration {
+    params: Mutex<MigrationParams>,
+    state: Mutex<MigrationState>,
+    /// Called with the new status when status changes.
+    notify: Box<dyn Fn(MigrationStatus) + Send + Sync>,
+}
+
+impl Migration {
+    /// Create the controller with default parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `notify` - Called with the new status when status changes, such as
+    ///   emitting QMP event `MIGRATION`.
+    pub fn new(notify: Box<dyn Fn(MigrationStatus) + Send + Sync>) -> Self {
+        Migration {
+            params: Mutex::new(MigrationParams::default()),
+            state: Mutex::new(MigrationState {
+                status: MigrationStatus::None,
+                progress: Arc::new(MigrationProgress::default()),
+                start: Instant::now(),
+                end: None,
+                error: None,
+                stream_fd: None,
+            }),
+            notify,
+        }
+    }
+
+    /// Get the parameters of migration.
+    pub fn params(&self) -> MigrationParams {
+        *self.params.lock().unwrap()
+    }
+
+    /// Set the parameters of the next migration.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if migration is running, or `downtime_limit` is out of
+    /// range.
+    pub fn set_params(&self, params: MigrationParams) -> Result<()> {
+        if params.downtime_limit > MAX_DOWNTIME_LIMIT {
+            bail!(
+                "Parameter 'downtime-limit' expects an integer in the range of 0 to {} milliseconds",
+                MAX_DOWNTIME_LIMIT
+            );
+        }
+        // Parameters are taken by migration when it starts.
+        let state = self.state.lock().unwrap();
+        if state.status.is_running() {
+            bail!("Parameters of migration can't be changed while it's running");
+        }
+        *self.params.lock().unwrap() = params;
+        Ok(())
+    }
+
+    /// Get the status of migration.
+    pub fn status(&self) -> MigrationStatus {
+        self.state.lock().unwrap().status
+    }
+
+    /// Get the information of the last migration.
+    pub fn info(&self) -> MigrationInfo {
+        let state = self.state.lock().unwrap();
+        let end = state.end.unwrap_or_else(Instant::now);
+        MigrationInfo {
+            status: state.status,
+            progress: state.progress.clone(),
+            total_time: end.duration_since(state.start).as_millis() as u64,
+            error: state.error.clone(),
+        }
+    }
+
+    fn set_status(&self, state: &mut MigrationState, status: MigrationStatus) {
+        state.status = status;
+        (self.notify)(status);
+    }
+
+    /// Start migration in `setup` status, which is run by `run` later.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if another migration is running.
+    pub fn start(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.status.is_running() {
+            bail!("There's a migration process in progress");
+        }
+        state.progress = Arc::new(MigrationProgress::default());
+        state.start = Instant::now();
+        state.end = None;
+        state.error = None;
+        self.set_status(&mut state, MigrationStatus::Setup);
+        Ok(())
+    }
+
+    /// Run migration started by `start`, it's `active` once connected to
+    /// the destination, until VM is handed off or migration fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `connect` - Connect to the destination.
+    /// * `vm` - VM migrated out.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to connect, or `migrate_out` fails.
+    pub fn run<S, V, C>(&self, connect: C, vm: &V) -> Result<()>
+    where
+        S: Read + Write + AsRawFd,
+        V: MigrationSource + ?Sized,
+        C: FnOnce() -> Result<S>,
+    {
+        let params = self.params();
+        let progress = self.state.lock().unwrap().progress.clone();
+        let result = connect().and_then(|mut stream| {
+            let mut state = self.state.lock().unwrap();
+            if progress.is_cancelled() {
+                bail!("Migration is cancelled");
+            }
+            state.stream_fd = Some(stream.as_raw_fd());
+            self.set_status(&mut state, MigrationStatus::Active);
+            drop(state);
+
+            let result = migrate_out(&mut stream, vm, &params, &progress);
+            // The fd is closed with stream.
+            self.state.lock().unwrap().stream_fd = None;
+            result
+        });
+        self.finish(&result);
+        result
+    }
+
+    /// Stop migration with `result`, which is called by `run`, or by the
+    /// caller of `start` if it fails to run migration.
+    pub fn finish(&self, result: &Result<()>) {
+        let mut state = self.state.lock().unwrap();
+        state.end = Some(Instant::now());
+        let status = match result {
+            Ok(()) => MigrationStatus::Completed,
+            Err(_) if state.progress.is_cancelled() => MigrationStatus::Cancelled,
+            Err(e) => {
+                state.error = Some(
+                    e.iter()
+                        .map(|cause| cause.to_string())
+                        .collect::<Vec<String>>()
+                        .join(": "),
+                );
+                MigrationStatus::Failed
+            }
+        };
+        self.set_status(&mut state, status);
+    }
+
+    /// Cancel the running migration, it's a no-op if no migration is
+    /// running. Migration is `cancelling` until it stops in `run`.
+    pub fn cancel(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(
+            state.status,
+            MigrationStatus::Setup | MigrationStatus::Active
+        ) {
+            return;
+        }
+        state.progress.cancel();
+        if let Some(fd) = state.stream_fd {
+            // Safe because the fd is not closed until it's cleared from
+            // state, which is locked.
+            unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+        }
+        self.set_status(&mut state, MigrationStatus::Cancelling);
+    }
+}
+
+/// VM migrated out.
+pub trait MigrationSource {
+    /// `VmConfig` of VM in json.
+    fn config(&self) -> Result<Vec<u8>>;
+
+    /// `(addr, size)` of guest memory, aligned to 4 KiB.
+    fn ram_ranges(&self) -> Vec<(u64, u64)>;
+
+    /// Read guest memory at `addr` to `buf`.
+    fn read_ram(&self, addr: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Size of the pages whose addresses are given by `dirty_pages`.
+    fn page_size(&self) -> u64;
+
+    /// Start logging pages of guest memory written.
+    fn start_dirty_log(&self) -> Result<()>;
+
+    /// Stop logging pages of guest memory written.
+    fn stop_dirty_log(&self) -> Result<()>;
+
+    /// Get and clear the addresses of pages written since dirty log is
+    /// started or got last time.
+    fn dirty_pages(&self) -> Result<BTreeSet<u64>>;
+
+    /// Whether vcpus of VM are running.
+    fn is_running(&self) -> bool;
+
+    /// Pause vcpus of VM if it's running, and wait for the IO of devices to
+    /// be quiesced.
+    fn pause_vm(&self) -> Result<()>;
+
+    /// Resume vcpus of VM.
+    fn resume_vm(&self) -> Result<()>;
+
+    /// Called when VM is handed off to the destination, VM is left paused.
+    fn handed_off(&self) {}
+
+    /// State of devices and vcpus of paused VM.
+    fn state(&self) -> Result<Vec<u8>>;
+}
+
+/// VM migrated in, which is realized with its config but not started.
+pub trait MigrationTarget {
+    /// Check that `config` of the source is compatible with VM.
+    fn check_config(&self, config: &[u8]) -> Result<()>;
+
+    /// Write `data` to guest memory at `addr`.
+    fn write_ram(&self, addr: u64, data: &[u8]) -> Result<()>;
+
+    /// Set the state of devices and vcpus realized.
+    fn load_state(&self, state: &[u8]) -> Result<()>;
+}
+
+/// Parse the `tcp:HOST:PORT` uri of migration, and return `HOST:PORT`.
+///
+/// # Errors
+///
+/// Return Error if the uri isn't of tcp, or its host or port is missing.
+pub fn parse_uri(uri: &str) -> Result<String> {
+    let mut parts = uri.splitn(2, ':');
+    let addr = match (parts.next(), parts.next()) {
+        (Some("tcp"), Some(addr)) => addr,
+        _ => bail!("Unknown migration uri {}, it should be tcp:HOST:PORT", uri),
+    };
+    match addr.rfind(':') {
+        Some(pos) if pos > 0 && addr[pos + 1..].parse::<u16>().is_ok() => Ok(addr.to_string()),
+        _ => bail!(
+            "Invalid address {} of migration uri, it should be HOST:PORT",
+            addr
+        ),
+    }
+}
+
+/// Stream to the destination, limiting the bandwidth and counting bytes
+/// sent in `progress`.
+struct Channel<'a, S: Read + Write> {
+    stream: &'a mut S,
+    progress: &'a MigrationProgress,
+    /// Bytes allowed per second, unlimited if it's 0.
+    max_bandwidth: u64,
+    period_start: Instant,
+    period_bytes: u64,
+}
+
+impl<'a, S: Read + Write> Channel<'a, S> {
+    fn check_cancelled(&self) -> Result<()> {
+        if self.progress.is_cancelled() {
+            bail!("Migration is cancelled");
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.check_cancelled()?;
+        self.stream
+            .write_all(data)
+            .chain_err(|| "Failed to send to the destination")?;
+        self.progress
+            .transferred
+            .fetch_add(data.len() as u64, Ordering::SeqCst);
+        self.throttle(data.len() as u64);
+        Ok(())
+    }
+
+    /// Sleep until the bytes sent in this period are allowed by bandwidth.
+    fn throttle(&mut self, len: u64) {
+        if self.max_bandwidth == 0 {
+            return;
+        }
+        self.period_bytes += len;
+        let allowed = Duration::from_nanos(
+            (u128::from(self.period_bytes) * 1_000_000_000 / u128::from(self.max_bandwidth)) as u64,
+        );
+        let elapsed = self.period_start.elapsed();
+        if allowed > elapsed {
+            std::thread::sleep(allowed - elapsed);
+        }
+        if self.period_start.elapsed() >= THROTTLE_PERIOD {
+            self.period_start = Instant::now();
+            self.period_bytes = 0;
+        }
+    }
+
+    fn send(&mut self, kind: u32, payload: &[u8]) -> Result<()> {
+        let header = MessageHeader {
+            kind,
+            len: payload.len() as u64,
+            ..Default::default()
+        };
+        self.write(header.as_bytes())?;
+        self.write(payload)
+    }
+
+    fn send_ram(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        let header = MessageHeader {
+            kind: MSG_RAM,
+            len: RAM_ADDR_SIZE + data.len() as u64,
+            ..Default::default()
+        };
+        self.write(header.as_bytes())?;
+        self.write(&addr.to_le_bytes())?;
+        self.write(data)
+    }
+
+    /// Wait for the destination to reply to the message `kind`.
+    fn wait_ack(&mut self, kind: u32) -> Result<()> {
+        self.check_cancelled()?;
+        let (reply, payload) = recv_message(self.stream)
+            .chain_err(|| format!("Failed to receive reply to '{}'", message_name(kind)))?;
+        match reply {
+            MSG_ACK => Ok(()),
+            MSG_ERROR => bail!(
+                "Destination failed to handle '{}': {}",
+                message_name(kind),
+                String::from_utf8_lossy(&payload)
+            ),
+            _ => bail!(
+                "Unexpected reply '{}' to '{}'",
+                message_name(reply),
+                message_name(kind)
+            ),
+        }
+    }
+}
+
+/// Receive a message, and return its kind and payload.
+fn recv_message<S: Read>(stream: &mut S) -> Result<(u32, Vec<u8>)> {
+    let mut header = MessageHeader::default();
+    stream
+        .read_exact(header.as_mut_bytes())
+        .chain_err(|| "Message header is truncated")?;
+    let max_len = if header.kind == MSG_RAM {
+        RAM_ADDR_SIZE + RAM_CHUNK_SIZE
+    } else {
+        MAX_PAYLOAD_SIZE
+    };
+    if header.len > max_len {
+        bail!(
+            "Message '{}' of {} bytes is too large",
+            message_name(header.kind),
+            header.len
+        );
+    }
+    let mut payload = vec![0_u8; header.len as usize];
+    stream.read_exact(&mut payload).chain_err(|| {
+        format!(
+            "Message '{}' of {} bytes is truncated",
+            message_name(header.kind),
+            header.len
+        )
+    })?;
+    Ok((header.kind, payload))
+}
+
+/// Check whether the page at the start of `data` is zero.
+fn is_zero_page(data: &[u8]) -> bool {
+    data[..min(data.len(), RAM_PAGE_SIZE as usize)]
+        .iter()
+        .all(|b| *b == 0)
+}
+
+/// Send the non-zero pages of guest memory, the memory of destination is
+/// zero before it's migrated in.
+fn send_all_ram<S, V>(channel: &mut Channel<S>, vm: &V) -> Result<()>
+where
+    S: Read + Write,
+    V: MigrationSource + ?Sized,
+{
+    let mut buf = vec![0_u8; RAM_CHUNK_SIZE as usize];
+    for (start, size) in vm.ram_ranges() {
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            let count = min(end - addr, RAM_CHUNK_SIZE);
+            let chunk = &mut buf[..count as usize];
+            vm.read_ram(addr, chunk)?;
+            let mut offset = 0;
+            while offset < count {
+                // Runs of non-zero pages are sent in one message.
+                let page = &chunk[offset as usize..];
+                if is_zero_page(page) {
+                    offset += RAM_PAGE_SIZE;
+                    continue;
+                }
+                let mut run_end = offset + RAM_PAGE_SIZE;
+                while run_end < count && !is_zero_page(&chunk[run_end as usize..]) {
+                    run_end += RAM_PAGE_SIZE;
+                }
+                let run_end = min(run_end, count);
+                channel.send_ram(addr + offset, &chunk[offset as usize..run_end as usize])?;
+                offset = run_end;
+            }
+            addr += count;
+        }
+    }
+    Ok(())
+}
+
+/// Send the dirty pages at `pages`, including the zero ones which may be
+/// non-zero in the destination.
+fn send_pages<S, V>(channel: &mut Channel<S>, vm: &V, pages: &BTreeSet<u64>) -> Result<()>
+where
+    S: Read + Write,
+    V: MigrationSource + ?Sized,
+{
+    let page_size = vm.page_size();
+    let mut buf = vec![0_u8; RAM_CHUNK_SIZE as usize];
+    let mut iter = pages.iter().peekable();
+    while let Some(start) = iter.next() {
+        // Adjacent pages are sent in one message.
+        let mut end = start + page_size;
+        while end - start < RAM_CHUNK_SIZE && iter.peek() == Some(&&end) {
+            iter.next();
+            end += page_size;
+        }
+        let chunk = &mut buf[..(end - start) as usize];
+        vm.read_ram(*start, chunk)?;
+        channel.send_ram(*start, chunk)?;
+    }
+    Ok(())
+}
+
+/// Migrate VM out to the destination on `stream`. VM keeps running while
+/// guest memory is sent, and is paused for the last dirty pages and the
+/// state of devices and vcpus. VM is left paused after it's handed off.
+///
+/// # Arguments
+///
+/// * `stream` - Stream connected to the destination.
+/// * `vm` - VM migrated out.
+/// * `params` - Bandwidth and downtime limit of migration.
+/// * `progress` - Progress updated while migrating, which cancels it.
+///
+/// # Errors
+///
+/// Return Error if fail to send or the destination fails, then VM keeps
+/// running if it was.
+pub fn migrate_out<S, V>(
+    stream: &mut S,
+    vm: &V,
+    params: &MigrationParams,
+    progress: &MigrationProgress,
+) -> Result<()>
+where
+    S: Read + Write,
+    V: MigrationSource + ?Sized,
+{
+    let running = vm.is_running();
+    let total: u64 = vm.ram_ranges().iter().map(|(_, size)| size).sum();
+    progress.total.store(total, Ordering::SeqCst);
+    progress.remaining.store(total, Ordering::SeqCst);
+
+    let mut channel = Channel {
+        stream,
+        progress,
+        max_bandwidth: params.max_bandwidth,
+        period_start: Instant::now(),
+        period_bytes: 0,
+    };
+    let result = vm
+        .start_dirty_log()
+        .chain_err(|| "Failed to start dirty log")
+        .and_then(|_| {
+            let result = send_vm(&mut channel, vm, params);
+            if let Err(e) = vm.stop_dirty_log() {
+                error!("Failed to stop dirty log: {}", e);
+            }
+            result
+        });
+    match result {
+        Ok(()) => vm.handed_off(),
+        Err(_) if running && !vm.is_running() => {
+            if let Err(e) = vm.resume_vm() {
+                error!("Failed to resume VM after migration failed: {}", e);
+            }
+        }
+        Err(_) => (),
+    }
+    result
+}
+
+fn send_vm<S, V>(channel: &mut Channel<S>, vm: &V, params: &MigrationParams) -> Result<()>
+where
+    S: Read + Write,
+    V: MigrationSource + ?Sized,
+{
+    let progress = channel.progress;
+    let header = MigrationHeader {
+        magic: MIGRATION_MAGIC,
+        version: MIGRATION_VERSION,
+        arch: MIGRATION_ARCH,
+    };
+    channel.write(header.as_bytes())?;
+    channel.send(MSG_CONFIG, &vm.config()?)?;
+    channel.wait_ack(MSG_CONFIG)?;
+
+    let mut pass_start = Instant::now();
+    let mut pass_bytes = progress.transferred.load(Ordering::SeqCst);
+    send_all_ram(channel, vm)?;
+    let page_size = vm.page_size();
+    let mut pages = BTreeSet::new();
+    for iteration in 1..=MAX_ITERATIONS {
+        pages.append(&mut vm.dirty_pages()?);
+        let elapsed = max(pass_start.elapsed().as_millis() as u64, 1);
+        progress.dirty_sync_count.store(iteration, Ordering::SeqCst);
+        progress
+            .dirty_pages_rate
+            .store(pages.len() as u64 * 1000 / elapsed, Ordering::SeqCst);
+        let remaining = pages.len() as u64 * page_size;
+        progress.remaining.store(remaining, Ordering::SeqCst);
+
+        // Bandwidth is estimated by the bytes sent in the last pass.
+        let sent = progress.transferred.load(Ordering::SeqCst);
+        let bandwidth = max((sent - pass_bytes) * 1000 / elapsed, 1);
+        if remaining * 1000 / bandwidth <= params.downtime_limit {
+            break;
+        }
+        pass_start = Instant::now();
+        pass_bytes = sent;
+        send_pages(channel, vm, &pages)?;
+        pages.clear();
+    }
+
+    let pause_start = Instant::now();
+    vm.pause_vm().chain_err(|| "Failed to pause VM")?;
+    // VM is paused, the rest is sent as fast as possible.
+    channel.max_bandwidth = 0;
+    pages.append(&mut vm.dirty_pages()?);
+    send_pages(channel, vm, &pages)?;
+    progress.remaining.store(0, Ordering::SeqCst);
+    channel.send(MSG_STATE, &vm.state()?)?;
+    channel.send(MSG_END, &[])?;
+    channel.stream.flush()?;
+    channel.wait_ack(MSG_END)?;
+    progress
+        .downtime
+        .store(pause_start.elapsed().as_millis() as u64, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Migrate VM in from the source on `stream`, until the state of VM is
+/// loaded. VM is left paused.
+///
+/// # Arguments
+///
+/// * `stream` - Stream connected to the source.
+/// * `vm` - VM migrated in, which is realized but not started.
+///
+/// # Errors
+///
+/// Return Error if the stream is broken or invalid, or fail to load it to
+/// VM, the reason is replied to the source.
+pub fn migrate_in<S, V>(stream: &mut S, vm: &V) -> Result<()>
+where
+    S: Read + Write,
+    V: MigrationTarget + ?Sized,
+{
+    let result = recv_vm(stream, vm);
+    if let Err(ref e) = result {
+        let reason = e
+            .iter()
+            .map(|cause| cause.to_string())
+            .collect::<Vec<String>>()
+            .join(": ");
+        let header = MessageHeader {
+            kind: MSG_ERROR,
+            len: reason.len() as u64,
+            ..Default::default()
+        };
+        // The source may be gone, which is the cause of failure.
+        let _ = stream
+            .write_all(header.as_bytes())
+            .and_then(|_| stream.write_all(reason.as_bytes()));
+    }
+    result
+}
+
+fn reply_ack<S: Write>(stream: &mut S) -> Result<()> {
+    let header = MessageHeader {
+        kind: MSG_ACK,
+        ..Default::default()
+    };
+    stream
+        .write_all(header.as_bytes())
+        .and_then(|_| stream.flush())
+        .chain_err(|| "Failed to reply to the source")
+}
+
+fn recv_vm<S, V>(stream: &mut S, vm: &V) -> Result<()>
+where
+    S: Read + Write,
+    V: MigrationTarget + ?Sized,
+{
+    let mut header = MigrationHeader::default();
+    stream
+        .read_exact(header.as_mut_bytes())
+        .chain_err(|| "Migration header is truncated")?;
+    if header.magic != MIGRATION_MAGIC {
+        bail!("Invalid magic of migration stream");
+    }
+    if header.version != MIGRATION_VERSION {
+        bail!(
+            "Migration stream has version {}, expected {}",
+            header.version,
+            MIGRATION_VERSION
+        );
+    }
+    if header.arch != MIGRATION_ARCH {
+        bail!("Migration stream is of another architecture");
+    }
+
+    let mut expected = vec![MSG_CONFIG];
+    loop {
+        let (kind, payload) = recv_message(stream)?;
+        if !expected.contains(&kind) {
+            bail!("Unexpected message '{}'", message_name(kind));
+        }
+        match kind {
+            MSG_CONFIG => {
+                vm.check_config(&payload)
+                    .chain_err(|| "Config of source is incompatible")?;
+                reply_ack(stream)?;
+                expected = vec![MSG_RAM, MSG_STATE];
+            }
+            MSG_RAM => {
+                if payload.len() < RAM_ADDR_SIZE as usize {
+                    bail!("Message 'ram' of {} bytes is truncated", payload.len());
+                }
+                let (addr, data) = payload.split_at(RAM_ADDR_SIZE as usize);
+                let addr = u64::from_le_bytes(addr.try_into().unwrap());
+                vm.write_ram(addr, data)
+                    .chain_err(|| format!("Failed to write guest memory at {:#x}", addr))?;
+            }
+            MSG_STATE => {
+                vm.load_state(&payload)
+                    .chain_err(|| "Failed to load state of source")?;
+                expected = vec![MSG_END];
+            }
+            _ => {
+                reply_ack(stream)?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Mutex;
+
+    const MOCK_PAGE_SIZE: u64 = 4096;
+    const MOCK_RAM_SIZE: u64 = MOCK_PAGE_SIZE * 64;
+
+    /// VM whose guest writes a page each time dirty pages are synced.
+    #[derive(Default)]
+    struct MockVm {
+        ram: Mutex<Vec<u8>>,
+        running: AtomicBool,
+        logging: AtomicBool,
+        dirty: Mutex<BTreeSet<u64>>,
+        syncs: AtomicU64,
+        config: Vec<u8>,
+        state: Mutex<Vec<u8>>,
+    }
+
+    impl MockVm {
+        fn new(config: &str) -> Self {
+            MockVm {
+                ram: Mutex::new(vec![0_u8; MOCK_RAM_SIZE as usize]),
+                config: config.as_bytes().to_vec(),
+                ..Default::default()
+            }
+        }
+
+        fn guest_write(&self, addr: u64, value: u8) {
+            assert!(self.running.load(Ordering::SeqCst));
+            self.ram.lock().unwrap()[addr as usize] = value;
+            if self.logging.load(Ordering::SeqCst) {
+                self.dirty
+                    .lock()
+                    .unwrap()
+                    .insert(addr / MOCK_PAGE_SIZE * MOCK_PAGE_SIZE);
+            }
+        }
+    }
+
+    impl MigrationSource for MockVm {
+        fn config(&self) -> Result<Vec<u8>> {
+            Ok(self.config.clone())
+        }
+
+        fn ram_ranges(&self) -> Vec<(u64, u64)> {
+            vec![(0, MOCK_RAM_SIZE)]
+        }
+
+        fn read_ram(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+            let ram = self.ram.lock().unwrap();
+            buf.copy_from_slice(&ram[addr as usize..addr as usize + buf.len()]);
+            Ok(())
+        }
+
+        fn page_size(&self) -> u64 {
+            MOCK_PAGE_SIZE
+        }
+
+        fn start_dirty_log(&self) -> Result<()> {
+            self.logging.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn stop_dirty_log(&self) -> Result<()> {
+            self.logging.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn dirty_pages(&self) -> Result<BTreeSet<u64>> {
+            if self.is_running() {
+                // Page 3 is cleared, and one of pages 8..16 is written.
+                let syncs = self.syncs.fetch_add(1, Ordering::SeqCst);
+                self.guest_write(3 * MOCK_PAGE_SIZE, 0);
+                self.guest_write((8 + syncs % 8) * MOCK_PAGE_SIZE + 1, syncs as u8 + 1);
+            }
+            Ok(std::mem::take(&mut *self.dirty.lock().unwrap()))
+        }
+
+        fn is_running(&self) -> bool {
+            self.running.load(Ordering::SeqCst)
+        }
+
+        fn pause_vm(&self) -> Result<()> {
+            self.running.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn resume_vm(&self) -> Result<()> {
+            self.running.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn state(&self) -> Result<Vec<u8>> {
+            assert!(!self.is_running());
+            Ok(self.state.lock().unwrap().clone())
+        }
+    }
+
+    impl MigrationTarget for MockVm {
+        fn check_config(&self, config: &[u8]) -> Result<()> {
+            if config != self.config.as_slice() {
+                bail!("Config mismatches");
+            }
+            Ok(())
+        }
+
+        fn write_ram(&self, addr: u64, data: &[u8]) -> Result<()> {
+            let mut ram = self.ram.lock().unwrap();
+            if addr + data.len() as u64 > ram.len() as u64 {
+                bail!("Out of ram");
+            }
+            ram[addr as usize..addr as usize + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn load_state(&self, state: &[u8]) -> Result<()> {
+            *self.state.lock().unwrap() = state.to_vec();
+            Ok(())
+        }
+    }
+
+    fn migrate(
+        src: &MockVm,
+        dst: MockVm,
+        params: &MigrationParams,
+        progress: &MigrationProgress,
+    ) -> (Result<()>, Result<()>, MockVm) {
+        let (mut src_stream, mut dst_stream) = UnixStream::pair().unwrap();
+        let dst_thread = std::thread::spawn(move || {
+            let result = migrate_in(&mut dst_stream, &dst);
+            (result, dst)
+        });
+        let result = migrate_out(&mut src_stream, src, params, progress);
+        drop(src_stream);
+        let (dst_result, dst) = dst_thread.join().unwrap();
+        (result, dst_result, dst)
+    }
+
+    #[test]
+    fn test_parse_uri() {
+        assert_eq!(parse_uri("tcp:127.0.0.1:4444").unwrap(), "127.0.0.1:4444");
+        assert_eq!(parse_uri("tcp:[::1]:4444").unwrap(), "[::1]:4444");
+        assert_eq!(
+            parse_uri("unix:/tmp/migrate.sock").unwrap_err().to_string(),
+            "Unknown migration uri unix:/tmp/migrate.sock, it should be tcp:HOST:PORT"
+        );
+        for addr in [":4444", "localhost", "localhost:", "localhost:port"].iter() {
+            assert_eq!(
+                parse_uri(&format!("tcp:{}", addr)).unwrap_err().to_string(),
+                format!(
+                    "Invalid address {} of migration uri, it should be HOST:PORT",
+                    addr
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        let src = MockVm::new("config");
+        src.resume_vm().unwrap();
+        src.guest_write(0, 1);
+        src.guest_write(3 * MOCK_PAGE_SIZE, 3);
+        src.guest_write(MOCK_RAM_SIZE - 1, 0xff);
+        *src.state.lock().unwrap() = b"state".to_vec();
+
+        let progress = MigrationProgress::default();
+        let (result, dst_result, dst) = migrate(
+            &src,
+            MockVm::new("config"),
+            &MigrationParams::default(),
+            &progress,
+        );
+        result.unwrap();
+        dst_result.unwrap();
+        assert!(!src.is_running());
+        assert!(!src.logging.load(Ordering::SeqCst));
+        assert!(*src.ram.lock().unwrap() == *dst.ram.lock().unwrap());
+        assert_eq!(dst.state.lock().unwrap().as_slice(), b"state");
+        assert!(progress.dirty_sync_count.load(Ordering::SeqCst) >= 1);
+        assert_eq!(progress.total.load(Ordering::SeqCst), MOCK_RAM_SIZE);
+        assert_eq!(progress.remaining.load(Ordering::SeqCst), 0);
+        // Only the non-zero pages are sent in the first pass.
+        assert!(progress.transferred.load(Ordering::SeqCst) < MOCK_RAM_SIZE / 2);
+
+        // Guest dirties memory faster than the bandwidth, which is limited
+        // by the number of passes.
+        src.resume_vm().unwrap();
+        let params = MigrationParams {
+            max_bandwidth: 1 << 20,
+            downtime_limit: 0,
+        };
+        let progress = MigrationProgress::default();
+        let (result, dst_result, dst) = migrate(&src, MockVm::new("config"), &params, &progress);
+        result.unwrap();
+        dst_result.unwrap();
+        assert_eq!(
+            progress.dirty_sync_count.load(Ordering::SeqCst),
+            MAX_ITERATIONS
+        );
+        assert!(*src.ram.lock().unwrap() == *dst.ram.lock().unwrap());
+    }
+
+    #[test]
+    fn test_migrate_failed() {
+        // VM keeps running if the destination rejects it.
+        let src = MockVm::new("config");
+        src.resume_vm().unwrap();
+        let progress = MigrationProgress::default();
+        let (result, dst_result, _) = migrate(
+            &src,
+            MockVm::new("other"),
+            &MigrationParams::default(),
+            &progress,
+        );
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Destination failed to handle 'config': Config of source is incompatible: Config mismatches"
+        );
+        assert!(dst_result.is_err());
+        assert!(src.is_running());
+        assert!(!src.logging.load(Ordering::SeqCst));
+
+        // Cancelled migration breaks the stream of destination.
+        let progress = MigrationProgress::default();
+        progress.cancel();
+        let (result, dst_result, _) = migrate(
+            &src,
+            MockVm::new("config"),
+            &MigrationParams::default(),
+            &progress,
+        );
+        assert_eq!(result.unwrap_err().to_string(), "Migration is cancelled");
+        assert_eq!(
+            dst_result.unwrap_err().to_string(),
+            "Migration header is truncated"
+        );
+        assert!(src.is_running());
+
+        // The destination checks the stream.
+        let (mut src_stream, mut dst_stream) = UnixStream::pair().unwrap();
+        let header = MigrationHeader {
+            magic: MIGRATION_MAGIC,
+            version: MIGRATION_VERSION,
+            arch: MIGRATION_ARCH,
+        };
+        src_stream.write_all(header.as_bytes()).unwrap();
+        let message = MessageHeader {
+            kind: MSG_STATE,
+            ..Default::default()
+        };
+        src_stream.write_all(message.as_bytes()).unwrap();
+        let dst = MockVm::new("config");
+        assert_eq!(
+            migrate_in(&mut dst_stream, &dst).unwrap_err().to_string(),
+            "Unexpected message 'state'"
+        );
+        let (kind, reason) = recv_message(&mut src_stream).unwrap();
+        assert_eq!(kind, MSG_ERROR);
+        assert_eq!(
+            String::from_utf8(reason).unwrap(),
+            "Unexpected message 'state'"
+        );
+    }
+
+    #[test]
+    fn test_migration_status() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let cloned_events = events.clone();
+        let migration = Arc::new(Migration::new(Box::new(move |status| {
+            cloned_events.lock().unwrap().push(status)
+        })));
+        assert_eq!(migration.status(), MigrationStatus::None);
+        assert_eq!(
+            migration
+                .set_params(MigrationParams {
+                    downtime_limit: MAX_DOWNTIME_LIMIT + 1,
+                    ..Default::default()
+                })
+                .unwrap_err()
+                .to_string(),
+            "Parameter 'downtime-limit' expects an integer in the range of 0 to 2000000 milliseconds"
+        );
+        let params = MigrationParams {
+            max_bandwidth: 0,
+            downtime_limit: 100,
+        };
+        migration.set_params(params).unwrap();
+        assert_eq!(migration.params(), params);
+
+        // Completed, and parameters are fixed while it's running.
+        let src = Arc::new(MockVm::new("config"));
+        src.resume_vm().unwrap();
+        src.guest_write(0, 1);
+        migration.start().unwrap();
+        assert_eq!(
+            migration.start().unwrap_err().to_string(),
+            "There's a migration process in progress"
+        );
+        assert_eq!(
+            migration.set_params(params).unwrap_err().to_string(),
+            "Parameters of migration can't be changed while it's running"
+        );
+        let (src_stream, mut dst_stream) = UnixStream::pair().unwrap();
+        let dst_thread = std::thread::spawn(move || {
+            let dst = MockVm::new("config");
+            migrate_in(&mut dst_stream, &dst).map(|_| dst)
+        });
+        migration.run(|| Ok(src_stream), src.as_ref()).unwrap();
+        let dst = dst_thread.join().unwrap().unwrap();
+        assert!(*src.ram.lock().unwrap() == *dst.ram.lock().unwrap());
+        let info = migration.info();
+        assert_eq!(info.status, MigrationStatus::Completed);
+        assert_eq!(info.progress.remaining.load(Ordering::SeqCst), 0);
+        assert!(info.progress.transferred.load(Ordering::SeqCst) > MOCK_PAGE_SIZE);
+        assert!(info.error.is_none());
+        assert_eq!(
+            std::mem::take(&mut *events.lock().unwrap()),
+            vec![
+                MigrationStatus::Setup,
+                MigrationStatus::Active,
+                MigrationStatus::Completed
+            ]
+        );
+
+        // Failed to connect.
+        src.resume_vm().unwrap();
+        migration.start().unwrap();
+        assert!(migration.info().progress.transferred.load(Ordering::SeqCst) == 0);
+        migration
+            .run(
+                || -> Result<UnixStream> { bail!("Connection refused") },
+                src.as_ref(),
+            )
+            .unwrap_err();
+        let info = migration.info();
+        assert_eq!(info.status, MigrationStatus::Failed);
+        assert_eq!(info.error.unwrap(), "Connection refused");
+        assert_eq!(
+            std::mem::take(&mut *events.lock().unwrap()),
+            vec![MigrationStatus::Setup, MigrationStatus::Failed]
+        );
+
+        // Cancelled while waiting for the destination, which never replies.
+        migration.start().unwrap();
+        let (src_stream, dst_stream) = UnixStream::pair().unwrap();
+        let (cloned_migration, cloned_src) = (migration.clone(), src.clone());
+        let src_thread = std::thread::spawn(move || {
+            cloned_migration.run(|| Ok(src_stream), cloned_src.as_ref())
+        });
+        while migration.status() != MigrationStatus::Active {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        migration.cancel();
+        assert_eq!(migration.status(), MigrationStatus::Cancelling);
+        src_thread.join().unwrap().unwrap_err();
+        let info = migration.info();
+        assert_eq!(info.status, MigrationStatus::Cancelled);
+        assert!(info.error.is_none());
+        assert!(src.is_running());
+        assert!(!src.logging.load(Ordering::SeqCst));
+        drop(dst_stream);
+
+        // Nothing to cancel.
+        migration.cancel();
+        assert_eq!(migration.status(), MigrationStatus::Cancelled);
+        assert_eq!(
+            std::mem::take(&mut *events.lock().unwrap()),
+            vec![
+                MigrationStatus::Setup,
+                MigrationStatus::Active,
+                MigrationStatus::Cancelling,
+                MigrationStatus::Cancelled
+            ]
+        );
+    }
+}
@@ -35,11 +35,39 @@ extern crate util;
 pub mod cmdline;
 pub mod main_loop;
 pub mod micro_syscall;
+pub mod migration;
 
+#[cfg(feature = "qmp")]
+mod dump;
+mod kvm_caps;
+#[cfg(feature = "qmp")]
+mod qom;
+mod snapshot;
+
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+use std::io::BufWriter;
+#[cfg(target_arch = "x86_64")]
+use std::io::{BufReader, Cursor};
 use std::marker::{Send, Sync};
+#[cfg(feature = "qmp")]
+use std::net::SocketAddr;
+#[cfg(target_arch = "x86_64")]
+use std::net::TcpListener;
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+use std::net::TcpStream;
 use std::ops::Deref;
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(feature = "qmp")]
+use std::os::unix::io::FromRawFd;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+use std::sync::Weak;
 use std::sync::{Arc, Barrier, Condvar, Mutex};
+use std::time::Duration;
 use std::vec::Vec;
 
 #[cfg(target_arch = "x86_64")]
@@ -47,22 +75,39 @@ use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
 use kvm_ioctls::{Kvm, VmFd};
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
-use vmm_sys_util::terminal::Terminal;
 
+#[cfg(target_arch = "aarch64")]
+use address_space::AddressRange;
 #[cfg(target_arch = "x86_64")]
 use address_space::KvmIoListener;
-use address_space::{create_host_mmaps, AddressSpace, GuestAddress, KvmMemoryListener, Region};
-use boot_loader::{load_kernel, BootLoaderConfig};
+use address_space::{
+    create_host_mmaps, split_ram_ranges, AddressSpace, FileBackend, GuestAddress, HostMemMapping,
+    KvmMemoryListener, MemAdvice, Region, DEFAULT_MAX_MAPPING_SIZE,
+};
+use boot_loader::{load_firmware, load_kernel, BootLoaderConfig};
+#[cfg(target_arch = "aarch64")]
+use boot_loader::{FLASH_BASE, FLASH_SIZE};
+use machine_manager::chardev::Chardev;
+use machine_manager::config::Param;
+#[cfg(feature = "qmp")]
+use machine_manager::config::{implicit_chardev_id, ChardevType};
 use machine_manager::config::{
-    BootSource, ConsoleConfig, DriveConfig, NetworkInterfaceConfig, SerialConfig, VmConfig,
-    VsockConfig,
+    BalloonConfig, BootSource, ChardevConfig, DriveConfig, NetworkInterfaceConfig, PmemConfig,
+    RngConfig, VmConfig, VsockConfig, PMEM_ALIGN,
 };
+#[cfg(feature = "qmp")]
+use machine_manager::config::{MachineConfig, MACHINE_PROPS};
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+use machine_manager::machine::check_migrate_incoming;
 use machine_manager::machine::{
-    DeviceInterface, KvmVmState, MachineAddressInterface, MachineExternalInterface,
-    MachineInterface, MachineLifecycle,
+    check_transition, reboot_dispatch, DeviceInterface, KvmVmState, MachineAddressInterface,
+    MachineExternalInterface, MachineInterface, MachineLifecycle, RebootAction,
 };
 #[cfg(feature = "qmp")]
 use machine_manager::{qmp, qmp::qmp_schema as schema, qmp::QmpChannel};
+use util::boot_time::{BootMilestone, BootTimes};
+#[cfg(target_arch = "x86_64")]
+use util::byte_code::ByteCode;
 #[cfg(target_arch = "aarch64")]
 use util::device_tree;
 #[cfg(target_arch = "aarch64")]
@@ -70,33 +115,75 @@ use util::device_tree::CompileFDT;
 use util::epoll_context::{
     EventNotifier, EventNotifierHelper, MainLoopManager, NotifierCallback, NotifierOperation,
 };
+#[cfg(feature = "qmp")]
+use util::loop_stats::CALLBACK_TIME_BUCKETS_US;
+use util::num_ops::round_up;
+use util::signal::SignalFd;
+#[cfg(target_arch = "x86_64")]
+use util::state::{StateFn, StateRegistry, StateVersion, VersionedState};
+#[cfg(target_arch = "aarch64")]
+use util::timer::TimerMode;
 
+#[cfg(feature = "qmp")]
+use self::dump::{DumpRange, ElfCore};
+use self::kvm_caps::{KvmCaps, KvmHost};
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+use self::micro_syscall::{register_seccomp, ThreadRole};
+use self::micro_syscall::{SeccompFragment, SeccompMode, SeccompPolicy};
+#[cfg(target_arch = "x86_64")]
+use self::migration::{migrate_in, MigrationSource, MigrationTarget};
+#[cfg(feature = "qmp")]
+use self::migration::{Migration, MigrationStatus};
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+use self::snapshot::RamFileRange;
+#[cfg(target_arch = "x86_64")]
+use self::snapshot::{KvmState, RamFile, SnapshotReader, SnapshotWriter};
+#[cfg(target_arch = "x86_64")]
+use crate::cpu::ArchCPUState;
 use crate::cpu::{ArchCPU, CPUBootConfig, CPUInterface, CpuTopology, CPU};
 use crate::errors::{Result, ResultExt};
 #[cfg(target_arch = "aarch64")]
 use crate::interrupt_controller::{InterruptController, InterruptControllerConfig};
+#[cfg(target_arch = "x86_64")]
+use crate::legacy::{AcpiPm, BootMarker, ResetCtrl};
 #[cfg(target_arch = "aarch64")]
-use crate::legacy::PL031;
-#[cfg(target_arch = "aarch64")]
+use crate::legacy::{GPIO_POWER_BUTTON_PIN, PL031, PL061, RTC_CHANGE_INTERVAL};
+#[cfg(any(target_arch = "aarch64", feature = "qmp"))]
 use crate::mmio::DeviceResource;
 use crate::MainLoop;
 use crate::{
     legacy::Serial,
     mmio::{Bus, DeviceType, VirtioMmioDevice},
-    virtio::{vhost, Console},
+    virtio::{vhost, Balloon, Console, Pmem, Rng},
 };
 
+/// Time to wait for the IO of devices to be quiesced after VM is paused.
+const IO_QUIESCE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Layout of aarch64
 #[cfg(target_arch = "aarch64")]
 pub const DRAM_BASE: u64 = 1 << 31;
+/// Baud rate of serial given in `stdout-path`.
+#[cfg(target_arch = "aarch64")]
+const SERIAL_BAUD_RATE: u32 = 115_200;
+/// Key code of power button in linux input event codes.
+#[cfg(target_arch = "aarch64")]
+const KEY_POWER: u32 = 116;
 #[cfg(target_arch = "aarch64")]
 pub const MEM_MAPPED_IO_BASE: u64 = 1 << 30;
+/// Persistent memory is placed above RAM, and no lower than this.
+#[cfg(target_arch = "aarch64")]
+const PMEM_MIN_BASE: u64 = DRAM_BASE;
 
 /// Layout of x86_64
 #[cfg(target_arch = "x86_64")]
 pub const MEM_MAPPED_IO_BASE: u64 = (1 << 32) - MEM_MAPPED_IO_SIZE;
 #[cfg(target_arch = "x86_64")]
 pub const MEM_MAPPED_IO_SIZE: u64 = 768 << 20;
+/// Persistent memory is placed above RAM, and no lower than this, so that
+/// it never falls in the MMIO gap below 4G.
+#[cfg(target_arch = "x86_64")]
+const PMEM_MIN_BASE: u64 = 1 << 32;
 
 /// Every type of devices depends on this configure-related trait to perform
 /// initialization.
@@ -120,6 +207,8 @@ impl ConfigDevBuilder for DriveConfig {
 impl ConfigDevBuilder for NetworkInterfaceConfig {
     fn build_dev(&self, sys_mem: Arc<AddressSpace>, bus: &mut Bus) -> Result<()> {
         if self.vhost_type.is_some() {
+            bus.register_id(&self.iface_id)
+                .chain_err(|| "build dev from config failed")?;
             let net = Arc::new(Mutex::new(vhost::kernel::Net::new(
                 self.clone(),
                 sys_mem.clone(),
@@ -135,66 +224,153 @@ impl ConfigDevBuilder for NetworkInterfaceConfig {
     }
 }
 
-impl ConfigDevBuilder for ConsoleConfig {
+impl ConfigDevBuilder for VsockConfig {
     fn build_dev(&self, sys_mem: Arc<AddressSpace>, bus: &mut Bus) -> Result<()> {
-        let console = Arc::new(Mutex::new(Console::new(self.clone())));
-        let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, console)));
+        bus.register_id(&self.vsock_id)
+            .chain_err(|| "build dev from config failed")?;
+        let vsock = Arc::new(Mutex::new(vhost::kernel::Vsock::new(
+            self.clone(),
+            sys_mem.clone(),
+        )));
+        let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, vsock)));
         bus.attach_device(device)
             .chain_err(|| "build dev from config failed")?;
         Ok(())
     }
 }
 
-impl ConfigDevBuilder for VsockConfig {
+impl ConfigDevBuilder for BalloonConfig {
     fn build_dev(&self, sys_mem: Arc<AddressSpace>, bus: &mut Bus) -> Result<()> {
-        let vsock = Arc::new(Mutex::new(vhost::kernel::Vsock::new(
-            self.clone(),
-            sys_mem.clone(),
-        )));
-        let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, vsock)));
+        let balloon = Arc::new(Mutex::new(Balloon::new(self.clone())));
+        let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, balloon)));
         bus.attach_device(device)
             .chain_err(|| "build dev from config failed")?;
         Ok(())
     }
 }
 
-impl ConfigDevBuilder for SerialConfig {
-    fn build_dev(&self, _sys_mem: Arc<AddressSpace>, bus: &mut Bus) -> Result<()> {
-        let serial = Arc::new(Mutex::new(Serial::new()));
-        bus.attach_device(serial.clone())
+impl ConfigDevBuilder for RngConfig {
+    fn build_dev(&self, sys_mem: Arc<AddressSpace>, bus: &mut Bus) -> Result<()> {
+        let rng = Arc::new(Mutex::new(Rng::new(self.clone())));
+        let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, rng)));
+        bus.attach_device(device)
             .chain_err(|| "build dev from config failed")?;
+        Ok(())
+    }
+}
 
-        if self.stdio {
-            MainLoop::update_event(EventNotifierHelper::internal_notifiers(serial))?;
-        }
+impl ConfigDevBuilder for PmemConfig {
+    fn build_dev(&self, sys_mem: Arc<AddressSpace>, bus: &mut Bus) -> Result<()> {
+        let base = pmem_base(sys_mem.memory_end_address().raw_value())?;
+        let pmem = Arc::new(Mutex::new(Pmem::new(self.clone(), sys_mem.clone(), base)));
+        let device = Arc::new(Mutex::new(VirtioMmioDevice::new(sys_mem, pmem)));
+        bus.attach_device(device)
+            .chain_err(|| "build dev from config failed")?;
         Ok(())
     }
 }
 
+/// Get the guest physical address of persistent memory, the first address
+/// aligned to `PMEM_ALIGN` above RAM and `PMEM_MIN_BASE`.
+///
+/// # Arguments
+///
+/// * `ram_end` - End address of RAM.
+fn pmem_base(ram_end: u64) -> Result<u64> {
+    match round_up(std::cmp::max(ram_end, PMEM_MIN_BASE), PMEM_ALIGN) {
+        Some(base) => Ok(base),
+        None => bail!("No space for pmem above RAM end {:#x}", ram_end),
+    }
+}
+
 /// A wrapper around creating and using a kvm-based micro VM.
 pub struct LightMachine {
     /// KVM VM file descriptor, represent VM entry in kvm module.
     vm_fd: Arc<VmFd>,
     /// `vCPU` topology, support sockets, cores, threads.
     cpu_topo: CpuTopology,
-    /// `vCPU` devices.
+    /// `vCPU` devices of all possible vcpus, only the online ones in
+    /// `cpu_topo` are realized and started.
     cpus: Arc<Mutex<Vec<Arc<CPU>>>>,
     /// Interrupt controller device.
     #[cfg(target_arch = "aarch64")]
     irq_chip: Arc<InterruptController>,
     /// Memory address space.
     sys_mem: Arc<AddressSpace>,
+    /// Host memory mappings of guest ram.
+    ram_mappings: Vec<Arc<HostMemMapping>>,
     /// IO address space.
     #[cfg(target_arch = "x86_64")]
     sys_io: Arc<AddressSpace>,
     /// Mmio bus.
     bus: Bus,
+    /// Virtio consoles with their ids, ports are hot added to them.
+    consoles: Vec<(String, Arc<Mutex<Console>>)>,
+    /// Chardevs not used by any device at boot, left for hot added ports.
+    chardevs: Vec<ChardevConfig>,
     /// VM running state.
     vm_state: Arc<(Mutex<KvmVmState>, Condvar)>,
+    /// Name of VM, set by `-name`.
+    name: Option<String>,
+    /// UUID of VM, set by `-uuid` or generated.
+    uuid: String,
+    /// Machine config with the effective max slot size, reported by
+    /// `query-machine-properties`.
+    #[cfg(feature = "qmp")]
+    machine_config: MachineConfig,
+    /// Config of VM, which is saved in snapshot and checked by the
+    /// destination of migration.
+    #[cfg(any(feature = "qmp", target_arch = "x86_64"))]
+    vm_config: VmConfig,
+    /// Capabilities of host KVM, reported by `query-kvm-caps`.
+    #[cfg(feature = "qmp")]
+    kvm_caps: KvmCaps,
     /// Vm boot_source config.
     boot_source: Arc<Mutex<BootSource>>,
     /// VM power button, handle VM `Shutdown` event.
     power_button: EventFd,
+    /// Whether guest requested to shut down, the request is handled by
+    /// main loop when it's woken up by `power_button`.
+    guest_shutdown: AtomicBool,
+    /// Whether the shutdown requested by guest is for reboot.
+    guest_reset: AtomicBool,
+    /// Whether vcpus are started frozen by `vm_start` and not resumed yet.
+    start_frozen: AtomicBool,
+    /// Action taken when guest requests to reboot.
+    reboot_action: RebootAction,
+    /// Time of boot milestones, reported by `query-boot-times`.
+    boot_times: Arc<BootTimes>,
+    /// ACPI power management registers, handle guest shutdown request.
+    #[cfg(target_arch = "x86_64")]
+    acpi_pm: Arc<Mutex<AcpiPm>>,
+    /// Reset ports, handle guest reboot request.
+    #[cfg(target_arch = "x86_64")]
+    reset_ctrl: Arc<Mutex<ResetCtrl>>,
+    /// GPIO controller, the power button of guest is attached to it.
+    #[cfg(target_arch = "aarch64")]
+    gpio: Arc<Mutex<PL061>>,
+    /// Seccomp fragments needed by the devices realized at boot.
+    seccomp_fragments: BTreeSet<SeccompFragment>,
+    /// Migration out started by QMP `migrate`.
+    #[cfg(feature = "qmp")]
+    migration: Migration,
+    /// Sender of the destinations of migrations to migration thread, which
+    /// is set when the thread is started.
+    #[cfg(feature = "qmp")]
+    migration_jobs: Mutex<Option<mpsc::Sender<SocketAddr>>>,
+    /// Migration in deferred by `-incoming defer`, which is listened for by
+    /// QMP `migrate-incoming`.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    incoming: Mutex<Option<DeferredIncoming>>,
+}
+
+/// Migration in deferred by `-incoming defer`.
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+struct DeferredIncoming {
+    /// VM migrated in, which is referred weakly by the listener in main loop.
+    vm: Weak<LightMachine>,
+    /// Uri given by `migrate-incoming`, it's `None` before the command.
+    uri: Option<String>,
 }
 
 impl LightMachine {
@@ -203,12 +379,22 @@ impl LightMachine {
     /// # Arguments
     ///
     /// * `vm_config` - Represents the configuration for VM.
-    pub fn new(vm_config: VmConfig) -> Result<Arc<LightMachine>> {
+    /// * `reboot_action` - Action taken when guest requests to reboot.
+    pub fn new(mut vm_config: VmConfig, reboot_action: RebootAction) -> Result<Arc<LightMachine>> {
+        let boot_times = Arc::new(BootTimes::new());
         let kvm = Kvm::new().chain_err(|| "Failed to open /dev/kvm.")?;
         let vm_fd = Arc::new(
             kvm.create_vm()
                 .chain_err(|| "KVM: failed to create VM fd failed")?,
         );
+        boot_times.record(BootMilestone::VmCreated);
+        let kvm_caps = KvmCaps::probe(&KvmHost {
+            kvm: &kvm,
+            vm_fd: &vm_fd,
+        })?;
+        if vm_config.boot_source.bios.is_some() {
+            kvm_caps.check_firmware()?;
+        }
 
         let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value()))?;
         let nr_slots = kvm.get_nr_memslots();
@@ -228,32 +414,66 @@ impl LightMachine {
         // Init guest-memory
         // Define ram-region ranges according to architectures
         let ram_ranges = Self::arch_ram_ranges(vm_config.machine_config.mem_size);
-        let mem_mappings = create_host_mmaps(&ram_ranges, vm_config.machine_config.omit_vm_memory)?;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some((start, size)) = ram_ranges.get(1) {
+                warn!(
+                    "Memory size {:#x} is beyond the MMIO gap at {:#x}, the last {:#x} of memory is placed at {:#x}",
+                    vm_config.machine_config.mem_size, MEM_MAPPED_IO_BASE, size, start
+                );
+            }
+        }
+        let mem_advice = MemAdvice {
+            dump_guest_core: !vm_config.machine_config.omit_vm_memory,
+            mem_merge: vm_config.machine_config.mem_merge,
+            thp: vm_config.machine_config.thp,
+        };
+        let max_slot_size = vm_config
+            .machine_config
+            .max_slot_size
+            .unwrap_or(DEFAULT_MAX_MAPPING_SIZE);
+        // Persistent memory takes a slot of its own.
+        let nr_needed = split_ram_ranges(&ram_ranges, max_slot_size).len()
+            + vm_config.pmem.as_ref().map_or(0, |_| 1);
+        if nr_needed > nr_slots {
+            bail!(
+                "Guest memory needs {} kvm memory slots with max slot size {:#x}, but only {} available",
+                nr_needed,
+                max_slot_size,
+                nr_slots
+            );
+        }
+        let backend = match &vm_config.machine_config.mem_path {
+            Some(path) => Some(FileBackend {
+                file: Arc::new(open_mem_file(path, vm_config.machine_config.mem_size)?),
+                offset: 0,
+                shared: true,
+            }),
+            None => None,
+        };
+        let mem_mappings =
+            create_host_mmaps(&ram_ranges, backend.as_ref(), mem_advice, max_slot_size)?;
         for mmap in mem_mappings.iter() {
             sys_mem.root().add_subregion(
                 Region::init_ram_region(mmap.clone()),
                 mmap.start_address().raw_value(),
             )?;
         }
+        boot_times.record(BootMilestone::MemoryReady);
+
+        // Pre init vcpu and cpu topology, the number of vcpus is checked
+        // against KVM here and used by the others through `cpu_topo`.
+        let cpu_topo = CpuTopology::new(
+            vm_config.machine_config.nr_cpus,
+            vm_config.machine_config.vm_max_cpus(),
+            &kvm,
+        )?;
 
-        // Pre init vcpu and cpu topology
-        let mut mask: Vec<u8> = Vec::with_capacity(vm_config.machine_config.nr_cpus as usize);
-        for _i in 0..vm_config.machine_config.nr_cpus {
-            mask.push(1)
-        }
-
-        let cpu_topo = CpuTopology {
-            sockets: vm_config.machine_config.nr_cpus,
-            cores: 1,
-            threads: 1,
-            nrcpus: vm_config.machine_config.nr_cpus,
-            max_cpus: vm_config.machine_config.nr_cpus,
-            online_mask: Arc::new(Mutex::new(mask)),
-        };
-
-        let nrcpus = vm_config.machine_config.nr_cpus;
+        // All possible vcpus are created before the interrupt controller,
+        // only the online ones are realized and started.
+        let max_cpus = cpu_topo.max_cpus;
         let mut vcpu_fds = vec![];
-        for cpu_id in 0..nrcpus {
+        for cpu_id in 0..max_cpus {
             vcpu_fds.push(Arc::new(vm_fd.create_vcpu(cpu_id)?));
         }
 
@@ -262,9 +482,9 @@ impl LightMachine {
         let intc_conf = InterruptControllerConfig {
             version: kvm_bindings::kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
             map_region: 1 << 30,
-            vcpu_count: u64::from(vm_config.machine_config.nr_cpus),
+            vcpu_count: u64::from(cpu_topo.max_cpus),
             max_irq: 192,
-            msi: true,
+            msi: kvm_caps.signal_msi,
         };
         #[cfg(target_arch = "aarch64")]
         let irq_chip = InterruptController::new(vm_fd.clone(), &intc_conf)?;
@@ -272,6 +492,19 @@ impl LightMachine {
         // Machine state init
         let vm_state = Arc::new((Mutex::new(KvmVmState::Created), Condvar::new()));
 
+        let uuid = vm_config
+            .machine_config
+            .vm_uuid()
+            .chain_err(|| "Failed to get uuid of VM")?;
+        #[cfg(target_arch = "aarch64")]
+        let boot_source = with_earlycon(
+            vm_config.boot_source.clone(),
+            vm_config.machine_config.earlycon,
+        );
+        #[cfg(target_arch = "x86_64")]
+        let boot_source = vm_config.boot_source.clone();
+        let boot_source = with_uuid(boot_source, &uuid, vm_config.machine_config.uuid_on_cmdline);
+
         // Create vm object
         let mut vm = LightMachine {
             cpu_topo,
@@ -279,36 +512,79 @@ impl LightMachine {
             #[cfg(target_arch = "aarch64")]
             irq_chip: Arc::new(irq_chip),
             sys_mem: sys_mem.clone(),
+            ram_mappings: mem_mappings,
             #[cfg(target_arch = "x86_64")]
             sys_io,
             bus: Bus::new(sys_mem),
-            boot_source: Arc::new(Mutex::new(vm_config.clone().boot_source)),
+            consoles: Vec::new(),
+            chardevs: Vec::new(),
+            boot_source: Arc::new(Mutex::new(boot_source)),
             vm_fd: vm_fd.clone(),
             vm_state,
+            name: vm_config.machine_config.vm_name().map(String::from),
+            uuid,
+            #[cfg(feature = "qmp")]
+            machine_config: MachineConfig {
+                max_slot_size: Some(max_slot_size),
+                ..vm_config.machine_config.clone()
+            },
+            #[cfg(any(feature = "qmp", target_arch = "x86_64"))]
+            vm_config: vm_config.clone(),
+            #[cfg(feature = "qmp")]
+            kvm_caps,
             power_button: EventFd::new(libc::EFD_NONBLOCK)
                 .chain_err(|| "Create EventFd for power-button failed.")?,
+            guest_shutdown: AtomicBool::new(false),
+            guest_reset: AtomicBool::new(false),
+            start_frozen: AtomicBool::new(false),
+            reboot_action,
+            boot_times: boot_times.clone(),
+            #[cfg(target_arch = "x86_64")]
+            acpi_pm: Arc::new(Mutex::new(AcpiPm::new()?)),
+            #[cfg(target_arch = "x86_64")]
+            reset_ctrl: Arc::new(Mutex::new(ResetCtrl::new()?)),
+            #[cfg(target_arch = "aarch64")]
+            gpio: Arc::new(Mutex::new(PL061::new())),
+            seccomp_fragments: SeccompFragment::from_config(&vm_config),
+            #[cfg(feature = "qmp")]
+            migration: Migration::new(Box::new(|status: MigrationStatus| {
+                event!(MIGRATION; schema::MIGRATION {
+                    status: status.name().to_string()
+                });
+            })),
+            #[cfg(feature = "qmp")]
+            migration_jobs: Mutex::new(None),
+            #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+            incoming: Mutex::new(None),
         };
 
+        let iothreads = vm_config.iothreads.clone();
         // Add mmio devices
         vm.add_devices(vm_config)?;
 
         let vm = Arc::new(vm);
 
+        // Create event loops of iothreads, which are referred by devices
+        for iothread in iothreads.iter().flatten() {
+            MainLoop::add_iothread(&iothread.id, vm.clone())?;
+        }
+
         // Add vcpu object to vm
         let cpu_vm: Arc<Box<Arc<dyn MachineInterface + Send + Sync>>> =
             Arc::new(Box::new(vm.clone()));
-        for vcpu_id in 0..nrcpus {
+        for vcpu_id in 0..max_cpus {
             #[cfg(target_arch = "aarch64")]
             let arch_cpu = ArchCPU::new(&vm_fd, u32::from(vcpu_id));
 
             #[cfg(target_arch = "x86_64")]
-            let arch_cpu = ArchCPU::new(&vm_fd, u32::from(vcpu_id), u32::from(nrcpus));
+            let arch_cpu = ArchCPU::new(&vm_fd, u32::from(vcpu_id), u32::from(max_cpus));
 
             let cpu = CPU::new(
                 vcpu_fds[vcpu_id as usize].clone(),
                 vcpu_id,
                 Arc::new(Mutex::new(arch_cpu)),
                 cpu_vm.clone(),
+                boot_times.clone(),
             )?;
 
             let mut vcpus = vm.cpus.lock().unwrap();
@@ -316,6 +592,12 @@ impl LightMachine {
             vcpus.push(newcpu.clone());
         }
 
+        LightMachine::register_power_event(&vm)?;
+        #[cfg(target_arch = "x86_64")]
+        LightMachine::register_acpi_shutdown_event(&vm)?;
+        #[cfg(target_arch = "x86_64")]
+        LightMachine::register_reset_event(&vm)?;
+
         Ok(vm)
     }
 
@@ -366,47 +648,78 @@ impl LightMachine {
     pub fn realize(&self) -> Result<()> {
         self.bus
             .realize_devices(&self.vm_fd, &self.boot_source, &self.sys_mem)?;
+        self.boot_times.record(BootMilestone::DevicesRealized);
 
-        let boot_source = self.boot_source.lock().unwrap();
+        let mut boot_source = self.boot_source.lock().unwrap();
 
-        let (initrd, initrd_size) = match &boot_source.initrd {
-            Some(rd) => (Some(rd.initrd_file.clone()), rd.initrd_size),
-            None => (None, 0),
+        let firmware_entry = match &boot_source.bios {
+            Some(bios) => Some(load_firmware(bios, &self.sys_mem)?),
+            None => None,
         };
 
-        let bootloader_config = BootLoaderConfig {
-            kernel: boot_source.kernel_file.clone(),
-            initrd,
-            initrd_size: initrd_size as u32,
-        };
+        // Without kernel, firmware gets the fdt at the base of ram.
+        let (fdt_addr, kernel_addr) = if boot_source.has_kernel() {
+            let bootloader_config = BootLoaderConfig {
+                kernel: boot_source.kernel_file.clone(),
+                initrd: boot_source.initrd.as_ref().map(|rd| rd.initrd_file.clone()),
+                kernel_cmdline: boot_source.kernel_cmdline.to_string(),
+            };
 
-        let layout = load_kernel(&bootloader_config, &self.sys_mem)?;
-        if let Some(rd) = &boot_source.initrd {
-            *rd.initrd_addr.lock().unwrap() = layout.initrd_start;
-        }
+            let layout = load_kernel(&bootloader_config, &self.sys_mem)?;
+            self.boot_times.record(BootMilestone::KernelLoaded);
+            // Initrd in guest memory may be larger than the file if it's compressed.
+            if let Some(rd) = &mut boot_source.initrd {
+                *rd.initrd_addr.lock().unwrap() = layout.initrd_start;
+                rd.initrd_size = layout.initrd_size;
+            }
+            (layout.dtb_start, layout.kernel_start)
+        } else {
+            (DRAM_BASE, 0)
+        };
+        let dtb = boot_source.dtb.clone();
 
         // need to release lock here, as generate_fdt_node will acquire it later
         drop(boot_source);
 
         let boot_config = CPUBootConfig {
-            fdt_addr: layout.dtb_start,
-            kernel_addr: layout.kernel_start,
+            fdt_addr,
+            kernel_addr: firmware_entry.unwrap_or(kernel_addr),
         };
 
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            self.cpus.lock().unwrap()[cpu_index as usize].realize(&boot_config)?;
+        for cpu in self.online_cpus() {
+            cpu.realize(&boot_config)?;
         }
 
-        let mut fdt = vec![0; device_tree::FDT_MAX_SIZE as usize];
-        self.generate_fdt_node(&mut fdt)?;
-
-        self.sys_mem.write(
-            &mut fdt.as_slice(),
-            GuestAddress(boot_config.fdt_addr as u64),
-            fdt.len() as u64,
-        )?;
+        let mut fdt = match dtb {
+            Some(path) => {
+                let blob = std::fs::read(&path)
+                    .chain_err(|| format!("Failed to read dtb file {:?}", path))?;
+                let mut fdt = device_tree::load_device_tree(&blob)
+                    .chain_err(|| format!("Invalid dtb file {:?}", path))?;
+                // Only the parts depending on the configuration of VM are patched.
+                self.generate_memory_node(&mut fdt)?;
+                self.generate_chosen_node(&mut fdt)?;
+                fdt
+            }
+            None => {
+                let mut fdt = Vec::new();
+                self.generate_fdt_node(&mut fdt)?;
+                fdt
+            }
+        };
+        let initrd = self.boot_source.lock().unwrap().initrd.as_ref().map(|rd| {
+            let start = *rd.initrd_addr.lock().unwrap();
+            (start, rd.initrd_size)
+        });
+        reserve_boot_ranges(&mut fdt, boot_config.fdt_addr, initrd)?;
 
-        self.register_power_event()?;
+        self.sys_mem
+            .write(
+                &mut fdt.as_slice(),
+                GuestAddress(boot_config.fdt_addr as u64),
+                fdt.len() as u64,
+            )
+            .chain_err(|| format!("Failed to load fdt to 0x{:x}", boot_config.fdt_addr))?;
 
         Ok(())
     }
@@ -420,77 +733,151 @@ impl LightMachine {
             &self.sys_mem,
             self.sys_io.clone(),
         )?;
+        self.boot_times.record(BootMilestone::DevicesRealized);
 
         let boot_source = self.boot_source.lock().unwrap();
 
-        // Load kernel image
-        let (initrd, initrd_size) = match &boot_source.initrd {
-            Some(rd) => (Some(rd.initrd_file.clone()), rd.initrd_size),
-            None => (None, 0),
-        };
-        let bootloader_config = BootLoaderConfig {
-            kernel: boot_source.kernel_file.clone(),
-            initrd,
-            initrd_size: initrd_size as u32,
-            kernel_cmdline: boot_source.kernel_cmdline.to_string(),
-            cpu_count: self.cpu_topo.nrcpus,
-        };
+        // Firmware starts from the reset vector, kernel is still loaded for
+        // it if given.
+        if let Some(bios) = &boot_source.bios {
+            load_firmware(bios, &self.sys_mem)?;
+        }
+        let prot64_mode = boot_source.bios.is_none();
+
+        let boot_config = if boot_source.has_kernel() {
+            // Load kernel image
+            let bootloader_config = BootLoaderConfig {
+                kernel: boot_source.kernel_file.clone(),
+                initrd: boot_source.initrd.as_ref().map(|rd| rd.initrd_file.clone()),
+                kernel_cmdline: boot_source.kernel_cmdline.to_string(),
+                cpu_count: self.cpu_topo.nrcpus,
+                max_cpus: self.cpu_topo.max_cpus,
+            };
 
-        let layout = load_kernel(&bootloader_config, &self.sys_mem)?;
-        let boot_config = CPUBootConfig {
-            boot_ip: layout.kernel_start,
-            boot_sp: layout.kernel_sp,
-            zero_page: layout.zero_page_addr,
-            code_segment: layout.segments.code_segment,
-            data_segment: layout.segments.data_segment,
-            gdt_base: layout.segments.gdt_base,
-            gdt_size: layout.segments.gdt_limit,
-            idt_base: layout.segments.idt_base,
-            idt_size: layout.segments.idt_limit,
-            pml4_start: layout.boot_pml4_addr,
+            let layout = load_kernel(&bootloader_config, &self.sys_mem)?;
+            self.boot_times.record(BootMilestone::KernelLoaded);
+            CPUBootConfig {
+                prot64_mode,
+                boot_ip: layout.kernel_start,
+                boot_sp: layout.kernel_sp,
+                zero_page: layout.zero_page_addr,
+                code_segment: layout.segments.code_segment,
+                data_segment: layout.segments.data_segment,
+                gdt_base: layout.segments.gdt_base,
+                gdt_size: layout.segments.gdt_limit,
+                idt_base: layout.segments.idt_base,
+                idt_size: layout.segments.idt_limit,
+                pml4_start: layout.boot_pml4_addr,
+            }
+        } else {
+            CPUBootConfig {
+                prot64_mode,
+                ..Default::default()
+            }
         };
 
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            self.cpus.lock().unwrap()[cpu_index as usize].realize(&boot_config)?;
+        for cpu in self.online_cpus() {
+            cpu.realize(&boot_config)?;
         }
 
-        self.register_power_event()?;
+        AcpiPm::realize(&self.acpi_pm, &self.vm_fd, &self.sys_io)?;
+        ResetCtrl::realize(&self.reset_ctrl, &self.sys_io)?;
+        self.realize_boot_marker()?;
 
         Ok(())
     }
 
-    /// Start VM, changed `LightMachine`'s `vmstate` to `Paused` or
-    /// `Running`.
+    /// Register the boot marker port if it's enabled by
+    /// `-machine boot-marker=on`.
+    #[cfg(target_arch = "x86_64")]
+    fn realize_boot_marker(&self) -> Result<()> {
+        if self.vm_config.machine_config.boot_marker {
+            let marker = Arc::new(BootMarker::new(self.boot_times.clone()));
+            BootMarker::realize(marker, &self.sys_io)?;
+        }
+        Ok(())
+    }
+
+    /// Start VM, changed `LightMachine`'s `vmstate` to `Running`, or keep it
+    /// `Created` if vcpus are frozen, which are started by `cont` later.
     ///
     /// # Arguments
     ///
-    /// * `paused` - After started, paused all vcpu or not.
-    /// * `use_seccomp` - If use seccomp sandbox or not.
-    pub fn vm_start(&self, paused: bool, use_seccomp: bool) -> Result<()> {
-        let cpus_thread_barrier = Arc::new(Barrier::new((self.cpu_topo.max_cpus + 1) as usize));
+    /// * `paused` - After started, paused all vcpu or not, as `-S` does. No
+    ///   `STOP` event is emitted for it.
+    /// * `seccomp` - Seccomp policy of vcpu threads and iothreads.
+    pub fn vm_start(&self, paused: bool, seccomp: &SeccompPolicy) -> Result<()> {
+        MainLoop::start_iothreads(seccomp)?;
 
-        for cpu_index in 0..self.cpu_topo.max_cpus {
+        let cpus = self.online_cpus();
+        let cpus_thread_barrier = Arc::new(Barrier::new(cpus.len() + 1));
+
+        for cpu in cpus {
             let cpu_thread_barrier = cpus_thread_barrier.clone();
-            let cpu = self.cpus.lock().unwrap()[cpu_index as usize].clone();
-            CPU::start(cpu, cpu_thread_barrier, paused, use_seccomp)?;
+            CPU::start(
+                cpu,
+                cpu_thread_barrier,
+                paused,
+                seccomp.clone(),
+                self.name.as_deref(),
+            )?;
         }
 
-        let mut vmstate = self.vm_state.deref().0.lock().unwrap();
         if paused {
-            *vmstate = KvmVmState::Paused;
+            self.start_frozen.store(true, Ordering::SeqCst);
         } else {
-            *vmstate = KvmVmState::Running;
+            *self.vm_state.deref().0.lock().unwrap() = KvmVmState::Running;
         }
         cpus_thread_barrier.wait();
 
         Ok(())
     }
 
-    /// Pause VM, sleepy all vcpu thread. Changed `LightMachine`'s `vmstate`
-    /// from `Running` to `Paused`.
+    /// Get the seccomp policy of VM, the allowlists are composed with the
+    /// fragments needed by the devices realized at boot.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - Mode of seccomp sandbox.
+    pub fn seccomp_policy(&self, mode: SeccompMode) -> SeccompPolicy {
+        let mut fragments = self.seccomp_fragments.clone();
+        if self.is_incoming_deferred() {
+            fragments.insert(SeccompFragment::Incoming);
+        }
+        SeccompPolicy::new(mode, fragments)
+    }
+
+    /// Get the online vcpus, which are realized and started.
+    fn online_cpus(&self) -> Vec<Arc<CPU>> {
+        self.cpus
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| self.cpu_topo.get_mask(*index) == 1)
+            .map(|(_, cpu)| cpu.clone())
+            .collect()
+    }
+
+    /// Pause VM, sleepy all vcpu thread and quiesce the IO of devices.
+    /// Changed `LightMachine`'s `vmstate` from `Running` to `Paused`.
     fn vm_pause(&self) -> Result<()> {
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            self.cpus.lock().unwrap()[cpu_index as usize].pause()?;
+        for cpu in self.online_cpus() {
+            cpu.pause()?;
+        }
+
+        // Devices are quiesced before the GIC is stopped, so that the
+        // interrupts of IO completed meanwhile are kept in it. VM keeps
+        // running if any device fails to stop. IO in flight may still
+        // complete after it, see `wait_io_quiesced`.
+        if let Err(e) = self.bus.pause_io() {
+            if let Err(e) = self.bus.resume_io() {
+                error!("Failed to restart device IO: {}", e);
+            }
+            for cpu in self.online_cpus() {
+                cpu.resume()?;
+            }
+            return Err(e).chain_err(|| "Failed to quiesce device IO");
         }
 
         #[cfg(target_arch = "aarch64")]
@@ -502,11 +889,30 @@ impl LightMachine {
         Ok(())
     }
 
-    /// Resume VM, awaken all vcpu thread. Changed `LightMachine`'s `vmstate`
-    /// from `Paused` to `Running`.
+    /// Wait for the IO of devices to be quiesced after VM is paused, so that
+    /// guest memory and the state of devices don't change any more.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if IO is still in flight after `IO_QUIESCE_TIMEOUT`, or
+    /// it can't be reaped by the current thread, which runs the event loop
+    /// of the device.
+    fn wait_io_quiesced(&self) -> Result<()> {
+        self.bus
+            .wait_io_quiesced(IO_QUIESCE_TIMEOUT)
+            .chain_err(|| "Device IO is still in flight while VM is paused")
+    }
+
+    /// Resume VM, restart the IO of devices and awaken all vcpu thread.
+    /// Changed `LightMachine`'s `vmstate` from `Paused` or `Created` to
+    /// `Running`.
     fn vm_resume(&self) -> Result<()> {
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            self.cpus.lock().unwrap()[cpu_index as usize].resume()?;
+        self.bus
+            .resume_io()
+            .chain_err(|| "Failed to restart device IO")?;
+
+        for cpu in self.online_cpus() {
+            cpu.resume()?;
         }
 
         let mut vmstate = self.vm_state.deref().0.lock().unwrap();
@@ -515,106 +921,1263 @@ impl LightMachine {
         Ok(())
     }
 
-    /// Destroy VM, kill all vcpu thread. Changed `LightMachine`'s `vmstate`
-    /// to `KVM_VMSTATE_DESTROY`.
-    fn vm_destroy(&self) -> Result<()> {
-        let mut vmstate = self.vm_state.deref().0.lock().unwrap();
-        *vmstate = KvmVmState::Shutdown;
-
-        let mut cpus = self.cpus.lock().unwrap();
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            cpus[cpu_index as usize].destroy()?;
+    /// Transform VM to `target` for `command`, which is `stop` or `cont`,
+    /// the `STOP` or `RESUME` event is emitted once it's done.
+    ///
+    /// Return false if VM is in `target` already.
+    ///
+    /// # Errors
+    ///
+    /// Return the message of `check_transition` if the transformation is
+    /// illegal in the state it's applied to, or VM fails to transform.
+    fn vm_transform(&self, command: &str, target: KvmVmState) -> std::result::Result<bool, String> {
+        let old = *self.vm_state.deref().0.lock().unwrap();
+        if !check_transition(command, old, target)? {
+            return Ok(false);
+        }
+        if !self.notify_lifecycle(old, target) {
+            // VM may be transformed by others meanwhile, which is checked
+            // again against the state it's in now.
+            let state = *self.vm_state.deref().0.lock().unwrap();
+            if state != old && !check_transition(command, state, target)? {
+                return Ok(false);
+            }
+            return Err(match target {
+                KvmVmState::Paused => "Failed to pause VM".to_string(),
+                _ => "Failed to resume VM".to_string(),
+            });
         }
-        cpus.clear();
 
-        Ok(())
-    }
+        #[cfg(feature = "qmp")]
+        match target {
+            KvmVmState::Paused => event!(STOP),
+            _ => event!(RESUME),
+        }
 
-    fn register_device<T: ConfigDevBuilder>(&mut self, dev_builder_ops: &T) -> Result<()> {
-        dev_builder_ops.build_dev(self.sys_mem.clone(), &mut self.bus)
+        Ok(true)
     }
 
-    fn add_devices(&mut self, vm_config: VmConfig) -> Result<()> {
-        #[cfg(target_arch = "aarch64")]
-        {
-            let rtc = Arc::new(Mutex::new(PL031::new()));
-            self.bus
-                .attach_device(rtc)
-                .chain_err(|| "add rtc to bus failed")?;
+    /// Dump guest memory and vcpu registers as an ELF core file. VM is
+    /// paused while dumping if it's running.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocol` - `file:PATH` or `fd:NAME`, where the core is written.
+    /// * `window` - `(begin, length)` of guest memory to dump, all of guest
+    ///   memory is dumped if it's `None`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if `protocol` or `window` is invalid, or fail to write
+    /// the core.
+    #[cfg(feature = "qmp")]
+    fn dump_guest_core(&self, protocol: &str, window: Option<(u64, u64)>) -> Result<()> {
+        let mut ranges: Vec<DumpRange> = self
+            .ram_mappings
+            .iter()
+            .map(|mapping| DumpRange {
+                addr: mapping.start_address().raw_value(),
+                size: mapping.size(),
+            })
+            .collect();
+        if let Some((begin, length)) = window {
+            ranges = dump::clip_ranges(&ranges, begin, length);
+            if ranges.is_empty() {
+                bail!(
+                    "No guest memory in range [{:#x}, {:#x})",
+                    begin,
+                    begin.saturating_add(length)
+                );
+            }
         }
+        let mut file = open_dump_file(protocol)?;
 
-        if let Some(serial) = vm_config.serial {
-            self.register_device(&serial)?;
+        // Registers and memory must not change while dumping.
+        let running = *self.vm_state.deref().0.lock().unwrap() == KvmVmState::Running;
+        if running && !self.pause() {
+            bail!("Failed to pause VM before dumping");
         }
-
-        if let Some(vsock) = vm_config.vsock {
-            self.register_device(&vsock)?;
+        let result = self
+            .wait_io_quiesced()
+            .and_then(|_| self.write_guest_core(&mut file, ranges));
+        if running && !self.resume() {
+            error!("Failed to resume VM after dumping");
         }
 
-        if let Some(drives) = vm_config.drives {
-            for drive in drives {
-                self.register_device(&drive)?;
-            }
-        }
+        let size = result?;
+        info!("Dumped {} bytes of guest core to {}", size, protocol);
+        Ok(())
+    }
 
-        if let Some(nets) = vm_config.nets {
-            for net in nets {
-                self.register_device(&net)?;
-            }
+    #[cfg(feature = "qmp")]
+    fn write_guest_core(&self, file: &mut File, ranges: Vec<DumpRange>) -> Result<u64> {
+        let mut cpu_regs = Vec::new();
+        for cpu in self.online_cpus() {
+            cpu_regs.push(cpu.get_elf_regs()?);
         }
 
-        if let Some(consoles) = vm_config.consoles {
-            for console in consoles {
-                self.register_device(&console)?;
-            }
+        let core = ElfCore::new(ranges, &cpu_regs);
+        core.write(file, &mut |dst, addr, count| {
+            self.sys_mem
+                .read(dst, GuestAddress(addr), count)
+                .chain_err(|| format!("Failed to read guest memory at {:#x}", addr))
+        })
+    }
+
+    /// Save the state of VM to a snapshot file, whose layout is described
+    /// in `snapshot`. VM is paused while saving if it's running. Guest
+    /// memory backed by `-mem-path` is cloned to the ram file beside it.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of snapshot file, which must not exist.
+    /// * `paused` - Leave VM paused after it's saved.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to save any state, then the incomplete files are
+    /// removed and VM keeps running if it was.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn save_snapshot(&self, path: &str, paused: bool) -> Result<()> {
+        self.check_boot_devices()?;
+        let file = create_snapshot_file(path)?;
+        let mut created = vec![path.to_string()];
+        let ram_path = snapshot::ram_file_path(path);
+        let ram = match self.mem_file() {
+            Some(mem_file) => match create_snapshot_file(&ram_path) {
+                Ok(ram) => {
+                    created.push(ram_path.clone());
+                    Some((mem_file, ram))
+                }
+                Err(e) => {
+                    remove_incomplete_snapshot(&created);
+                    return Err(e);
+                }
+            },
+            None => None,
+        };
+
+        // State of vcpus, devices and memory must be consistent.
+        let running = *self.vm_state.deref().0.lock().unwrap() == KvmVmState::Running;
+        let result = if running && !self.pause() {
+            Err("Failed to pause VM before saving snapshot".into())
+        } else {
+            let ram = ram
+                .as_ref()
+                .map(|(mem_file, ram)| (mem_file.as_ref(), ram, ram_path.as_str()));
+            self.wait_io_quiesced()
+                .and_then(|_| self.write_snapshot(file, ram))
+        };
+        if result.is_err() {
+            remove_incomplete_snapshot(&created);
+        }
+        if running && (result.is_err() || !paused) && !self.resume() {
+            error!("Failed to resume VM after saving snapshot");
         }
 
+        result?;
+        info!("Saved snapshot of VM to {}", path);
         Ok(())
     }
 
-    fn register_power_event(&self) -> Result<()> {
-        let power_button = self.power_button.try_clone().unwrap();
-        let button_fd = power_button.as_raw_fd();
-        let power_button_handler: Arc<Mutex<Box<NotifierCallback>>> =
-            Arc::new(Mutex::new(Box::new(move |_, _| {
-                let _ret = power_button.read().unwrap();
-                None
-            })));
-
-        let notifier = EventNotifier::new(
-            NotifierOperation::AddShared,
-            button_fd,
-            None,
-            EventSet::IN,
-            vec![power_button_handler],
-        );
+    #[cfg(all(feature = "qmp", target_arch = "aarch64"))]
+    fn save_snapshot(&self, _path: &str, _paused: bool) -> Result<()> {
+        bail!("Snapshot is not supported on aarch64 yet")
+    }
 
-        MainLoop::update_event(vec![notifier])?;
+    /// Check that the replaceable devices are the ones configured at boot,
+    /// as VM is restored or migrated in with the config of boot, which
+    /// doesn't have the devices hot plugged or unplugged.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn check_boot_devices(&self) -> Result<()> {
+        let mut plugged: Vec<String> = self
+            .bus
+            .get_replaceable_info()
+            .into_iter()
+            .filter(|info| info.resource.is_some())
+            .map(|info| info.id)
+            .collect();
+        let drives = self.vm_config.drives.iter().flatten();
+        let nets = self.vm_config.nets.iter().flatten();
+        let mut configured: Vec<String> = drives
+            .map(|drive| drive.drive_id.clone())
+            .chain(
+                nets.filter(|net| net.vhost_type.is_none())
+                    .map(|net| net.iface_id.clone()),
+            )
+            .collect();
+        plugged.sort();
+        configured.sort();
+        if plugged != configured {
+            bail!(
+                "Devices {:?} are plugged, but {:?} are configured at boot, hot plugged devices are not supported",
+                plugged,
+                configured
+            );
+        }
         Ok(())
     }
 
-    #[cfg(target_arch = "aarch64")]
-    fn generate_serial_device_node(
-        &self,
-        dev_info: &DeviceResource,
-        fdt: &mut Vec<u8>,
-    ) -> util::errors::Result<()> {
-        let node = format!("/uart@{:x}", dev_info.addr);
-        device_tree::add_sub_node(fdt, &node)?;
-        device_tree::set_property_string(fdt, &node, "compatible", "ns16550a")?;
-        device_tree::set_property_string(fdt, &node, "clock-names", "apb_pclk")?;
-        device_tree::set_property_u32(fdt, &node, "clocks", device_tree::CLK_PHANDLE)?;
-        device_tree::set_property_array_u64(fdt, &node, "reg", &[dev_info.addr, dev_info.size])?;
-        device_tree::set_property_array_u32(
-            fdt,
-            &node,
-            "interrupts",
-            &[
-                device_tree::GIC_FDT_IRQ_TYPE_SPI,
-                dev_info.irq,
-                device_tree::IRQ_TYPE_EDGE_RISING,
-            ],
-        )?;
+    /// Get the type, address and irq of MMIO devices on bus, which is saved
+    /// in snapshot to check the bus restored.
+    #[cfg(target_arch = "x86_64")]
+    fn bus_layout(&self) -> serde_json::Value {
+        let devices = self
+            .bus
+            .get_devices_info()
+            .iter()
+            .map(|res| {
+                serde_json::json!({
+                    "type": mmio_device_name(res.dev_type),
+                    "addr": res.addr,
+                    "size": res.size,
+                    "irq": res.irq,
+                })
+            })
+            .collect();
+        serde_json::Value::Array(devices)
+    }
+
+    /// Restore `LightMachine` from the snapshot at `path` instead of booting
+    /// it. Devices and vcpus are realized, then set to the state saved in
+    /// the order of memory, devices, interrupt controller and vcpus. The
+    /// vcpus take their state when they are started.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of snapshot file, whose config is checked to be
+    ///   compatible with the config of VM.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the section which is missing, corrupted or
+    /// mismatches the VM.
+    #[cfg(target_arch = "x86_64")]
+    pub fn restore(&self, path: &str) -> Result<()> {
+        let file =
+            File::open(path).chain_err(|| format!("Failed to open snapshot file {}", path))?;
+        let mut reader = SnapshotReader::new(BufReader::new(file))
+            .chain_err(|| format!("Invalid snapshot file {}", path))?;
+
+        self.realize_restored()?;
+        if reader.has_section("ram-file") {
+            let ram_file = RamFile::from_section(&reader.read_section("ram-file", 1)?)?;
+            self.load_ram_file(&ram_file, path)?;
+        } else {
+            reader.read_section_with("ram", 1, |src, size| {
+                snapshot::load_ram(src, size, &mut |addr, buf| self.write_ram(addr, buf))
+            })?;
+        }
+        self.load_vm_state(&mut reader)?;
+
+        info!("Restored VM from snapshot {}", path);
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn restore(&self, _path: &str) -> Result<()> {
+        bail!("Snapshot is not supported on aarch64 yet")
+    }
+
+    /// Realize devices and vcpus of `LightMachine` whose state is restored
+    /// or migrated in, instead of booting it.
+    #[cfg(target_arch = "x86_64")]
+    fn realize_restored(&self) -> Result<()> {
+        self.bus.realize_devices(
+            &self.vm_fd,
+            &self.boot_source,
+            &self.sys_mem,
+            self.sys_io.clone(),
+        )?;
+        self.boot_times.record(BootMilestone::DevicesRealized);
+        // Registers of booting are overridden by the state restored.
+        for cpu in self.online_cpus() {
+            cpu.realize(&CPUBootConfig::default())?;
+        }
+        AcpiPm::realize(&self.acpi_pm, &self.vm_fd, &self.sys_io)?;
+        ResetCtrl::realize(&self.reset_ctrl, &self.sys_io)?;
+        self.realize_boot_marker()?;
+        Ok(())
+    }
+
+    /// Register the state of devices, interrupt controller and vcpus, which
+    /// are the sections from `mmio<N>` to `cpu<N>` of snapshot, in the order
+    /// they are restored.
+    #[cfg(target_arch = "x86_64")]
+    fn state_registry(&self) -> Result<StateRegistry> {
+        let mut registry = StateRegistry::new();
+        for index in 0..self.bus.get_devices_info().len() {
+            let section = StateFn::new(
+                StateVersion::new(1, 1),
+                move || self.bus.get_device_state(index).map_err(state_error),
+                move |_, state| self.bus.set_device_state(index, state).map_err(state_error),
+            );
+            registry.register(&format!("mmio{}", index), Box::new(section))?;
+        }
+        let section = StateFn::new(
+            StateVersion::new(1, 1),
+            move || Ok(self.acpi_pm.lock().unwrap().get_state()),
+            move |_, state| {
+                let mut acpi_pm = self.acpi_pm.lock().unwrap();
+                acpi_pm.set_state(state).map_err(state_error)
+            },
+        );
+        registry.register("acpi_pm", Box::new(section))?;
+
+        let section = StateFn::new(
+            KvmState::VERSION,
+            move || {
+                let state = KvmState::save(&self.vm_fd).map_err(state_error)?;
+                Ok(state.as_bytes().to_vec())
+            },
+            move |version, state| {
+                let state = KvmState::from_versioned_bytes(state, version)?;
+                state.restore(&self.vm_fd).map_err(state_error)
+            },
+        );
+        registry.register("kvm", Box::new(section))?;
+        for (index, cpu) in self.online_cpus().into_iter().enumerate() {
+            let cpu_clone = cpu.clone();
+            let section = StateFn::new(
+                ArchCPUState::VERSION,
+                move || {
+                    let state = cpu.get_state().map_err(state_error)?;
+                    Ok(state.as_bytes().to_vec())
+                },
+                move |version, state| {
+                    let state = ArchCPUState::from_versioned_bytes(state, version)?;
+                    cpu_clone.set_state(state).map_err(state_error)
+                },
+            );
+            registry.register(&format!("cpu{}", index), Box::new(section))?;
+        }
+        Ok(registry)
+    }
+
+    /// Write the state of devices, interrupt controller and vcpus of paused
+    /// VM, which is the sections from `bus` to `cpu<N>` of snapshot.
+    #[cfg(target_arch = "x86_64")]
+    fn write_vm_state<W: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut SnapshotWriter<W>,
+    ) -> Result<()> {
+        writer.add_section("bus", 1, &serde_json::to_vec(&self.bus_layout())?)?;
+        for state in self.state_registry()?.save()?.iter() {
+            writer.add_state(state)?;
+        }
+        Ok(())
+    }
+
+    /// Set devices, interrupt controller and vcpus realized by
+    /// `realize_restored` to the state written by `write_vm_state`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the section which is missing, corrupted or
+    /// mismatches the VM.
+    #[cfg(target_arch = "x86_64")]
+    fn load_vm_state<R: std::io::Read + std::io::Seek>(
+        &self,
+        reader: &mut SnapshotReader<R>,
+    ) -> Result<()> {
+        let saved_bus: serde_json::Value = serde_json::from_slice(&reader.read_section("bus", 1)?)
+            .chain_err(|| "Invalid section 'bus' in snapshot")?;
+        let bus = self.bus_layout();
+        if saved_bus != bus {
+            bail!(
+                "Section 'bus' mismatches the devices configured, {} in snapshot, but {} configured",
+                saved_bus,
+                bus
+            );
+        }
+
+        let states = reader.read_states(&["config", "bus", "ram", "ram-file"])?;
+        self.state_registry()?.load(&states)?;
+        Ok(())
+    }
+
+    /// Write the snapshot of paused VM to `file`.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - Snapshot file created.
+    /// * `ram` - The file backing guest memory, the ram file created and its
+    ///   path, which are given if guest memory is backed by `-mem-path`.
+    ///   Guest memory is written in section `ram` if it's `None`.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn write_snapshot(&self, file: File, ram: Option<(&File, &File, &str)>) -> Result<()> {
+        let mut writer = SnapshotWriter::new(BufWriter::new(file))?;
+
+        writer.add_section("config", 1, &self.config()?)?;
+        self.write_vm_state(&mut writer)?;
+        match ram {
+            Some((mem_file, ram, ram_path)) => {
+                let ram_file = self.save_ram_file(mem_file, ram, ram_path)?;
+                writer.add_section("ram-file", 1, &serde_json::to_vec(&ram_file)?)?;
+            }
+            None => {
+                writer.begin_section("ram", 1)?;
+                snapshot::write_ram(&mut writer, &self.ram_ranges(), &mut |addr, buf| {
+                    self.read_ram(addr, buf)
+                })?;
+                writer.end_section()?;
+            }
+        }
+
+        let file = writer.finish()?;
+        file.get_ref()
+            .sync_data()
+            .chain_err(|| "Failed to sync snapshot file")?;
+        Ok(())
+    }
+
+    /// Get the file of `-mem-path` which guest memory is mapped shared from,
+    /// `None` if guest memory is anonymous or mapped private from the ram
+    /// file of snapshot restored.
+    #[cfg(target_arch = "x86_64")]
+    fn mem_file(&self) -> Option<Arc<File>> {
+        match self.ram_mappings.first()?.file_backend() {
+            Some(backend) if backend.shared => Some(backend.file),
+            _ => None,
+        }
+    }
+
+    /// Clone the file of `-mem-path` to the ram file, instead of copying
+    /// guest memory to snapshot, and describe it by section `ram-file`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_file` - The file backing guest memory.
+    /// * `ram` - The ram file created beside snapshot.
+    /// * `ram_path` - Path of the ram file.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn save_ram_file(&self, mem_file: &File, ram: &File, ram_path: &str) -> Result<RamFile> {
+        // Pages written by guest are flushed, so that they are cloned.
+        mem_file
+            .sync_data()
+            .chain_err(|| "Failed to sync memory backend file")?;
+        let size = mem_file.metadata()?.len();
+        snapshot::clone_file(mem_file, ram, size)?;
+        ram.sync_data().chain_err(|| "Failed to sync ram file")?;
+
+        let ranges = self
+            .ram_mappings
+            .iter()
+            .map(|mapping| RamFileRange {
+                addr: mapping.start_address().raw_value(),
+                size: mapping.size(),
+                offset: mapping.file_backend().map_or(0, |backend| backend.offset),
+            })
+            .collect();
+        let path = std::path::Path::new(ram_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(RamFile { path, size, ranges })
+    }
+
+    /// Load guest memory from the ram file beside the snapshot at `path`.
+    /// It's cloned to the file of `-mem-path` if it's given. Otherwise guest
+    /// memory is mapped private from it, whose pages are read on demand and
+    /// never written back.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the ram file is missing or truncated, or its ranges
+    /// mismatch guest memory.
+    #[cfg(target_arch = "x86_64")]
+    fn load_ram_file(&self, ram_file: &RamFile, path: &str) -> Result<()> {
+        let ram_path = ram_file.resolve(path);
+        let ram = File::open(&ram_path)
+            .chain_err(|| format!("Failed to open ram file {}", ram_path.display()))?;
+        let size = ram.metadata()?.len();
+        if size < ram_file.size {
+            bail!(
+                "Ram file {} is truncated, its size is {:#x}, expected {:#x}",
+                ram_path.display(),
+                size,
+                ram_file.size
+            );
+        }
+        let mut offsets = Vec::new();
+        for mapping in self.ram_mappings.iter() {
+            offsets
+                .push(ram_file.file_offset(mapping.start_address().raw_value(), mapping.size())?);
+        }
+
+        if let Some(mem_file) = self.mem_file() {
+            let same_layout =
+                self.ram_mappings
+                    .iter()
+                    .zip(offsets.iter())
+                    .all(|(mapping, offset)| {
+                        mapping.file_backend().map(|backend| backend.offset) == Some(*offset)
+                    });
+            if !same_layout {
+                bail!("Layout of ram file mismatches the memory backend file");
+            }
+            return snapshot::clone_file(&ram, &mem_file, ram_file.size);
+        }
+        let ram = Arc::new(ram);
+        for (mapping, offset) in self.ram_mappings.iter().zip(offsets.into_iter()) {
+            mapping.remap(FileBackend {
+                file: ram.clone(),
+                offset,
+                shared: false,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Check that VM can be migrated out to `uri`, and get the address of
+    /// the destination, which is started with `-incoming`.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - `tcp:IP:PORT` of the destination.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if `uri` is invalid, or VM has devices not supported by
+    /// migration.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn migration_dest(&self, uri: &str) -> Result<SocketAddr> {
+        self.check_boot_devices()?;
+        // Guest memory written by vhost backends is not logged dirty.
+        let vhost_net = self
+            .vm_config
+            .nets
+            .iter()
+            .flatten()
+            .any(|net| net.vhost_type.is_some());
+        if vhost_net || self.vm_config.vsock.is_some() {
+            bail!("Migration doesn't support vhost devices, such as vhost-net and vsock");
+        }
+        let addr = migration::parse_uri(uri)?;
+        // Host names are not resolved, which needs syscalls not allowed in
+        // migration thread.
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => Ok(addr),
+            Err(_) => bail!("Host of migration address {} must be an IP address", addr),
+        }
+    }
+
+    #[cfg(all(feature = "qmp", target_arch = "aarch64"))]
+    fn migration_dest(&self, _uri: &str) -> Result<SocketAddr> {
+        bail!("Migration is not supported on aarch64 yet")
+    }
+
+    /// Start migrating VM out to `uri` in migration thread.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if VM can't be migrated to `uri`, or another migration
+    /// is running.
+    #[cfg(feature = "qmp")]
+    fn start_migration(&self, uri: &str) -> Result<()> {
+        let addr = self.migration_dest(uri)?;
+        let jobs = self.migration_jobs.lock().unwrap();
+        let jobs = match jobs.as_ref() {
+            Some(jobs) => jobs,
+            None => bail!("Migration thread is not running"),
+        };
+        self.migration.start()?;
+        if jobs.send(addr).is_err() {
+            let result = Err("Migration thread exited".into());
+            self.migration.finish(&result);
+            return result;
+        }
+        Ok(())
+    }
+
+    /// Spawn migration thread, which runs the migrations started by QMP
+    /// `migrate` one by one. It refers to VM weakly, and exits when VM is
+    /// dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The VM migrated out.
+    /// * `seccomp` - Seccomp policy of migration thread.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to spawn the thread.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    pub fn start_migration_thread(vm: &Arc<Self>, seccomp: &SeccompPolicy) -> Result<()> {
+        let (sender, receiver) = mpsc::channel::<SocketAddr>();
+        let vm_weak = Arc::downgrade(vm);
+        let seccomp = seccomp.clone();
+        std::thread::Builder::new()
+            .name("migration".to_string())
+            .spawn(move || {
+                if let Err(e) = register_seccomp(ThreadRole::Migration, &seccomp) {
+                    error!("Failed to register seccomp in migration thread: {}", e);
+                }
+                for addr in receiver {
+                    match vm_weak.upgrade() {
+                        Some(vm) => vm.run_migration(addr),
+                        None => break,
+                    }
+                }
+            })
+            .chain_err(|| "Failed to spawn migration thread")?;
+        *vm.migration_jobs.lock().unwrap() = Some(sender);
+        Ok(())
+    }
+
+    /// Migration is not supported on aarch64 yet, no thread is spawned.
+    #[cfg(all(feature = "qmp", target_arch = "aarch64"))]
+    pub fn start_migration_thread(_vm: &Arc<Self>, _seccomp: &SeccompPolicy) -> Result<()> {
+        Ok(())
+    }
+
+    /// Run the migration to `addr` started by `start_migration`. VM keeps
+    /// running until the last pass of dirty pages, and is left in
+    /// `postmigrate` state after it's handed off.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn run_migration(&self, addr: SocketAddr) {
+        let connect = || -> Result<TcpStream> {
+            let stream = TcpStream::connect(addr)
+                .chain_err(|| format!("Failed to connect to destination {}", addr))?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        };
+        match self.migration.run(connect, self) {
+            Ok(()) => info!("Migrated VM out to {}", addr),
+            Err(e) => error!(
+                "Failed to migrate VM out to {}: {}",
+                addr,
+                error_chain::ChainedError::display_chain(&e)
+            ),
+        }
+    }
+
+    /// Wait for VM to be migrated in from the source connecting to `addr`,
+    /// instead of booting it. VM is paused until `cont`.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - `HOST:PORT` listened for the source.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to listen, or the migration from source fails.
+    #[cfg(target_arch = "x86_64")]
+    pub fn migrate_incoming(&self, addr: &str) -> Result<()> {
+        self.realize_restored()?;
+        let listener =
+            TcpListener::bind(addr).chain_err(|| format!("Failed to listen on {}", addr))?;
+        info!("Waiting for migration on tcp {}", addr);
+        let (mut stream, source) = listener
+            .accept()
+            .chain_err(|| format!("Failed to accept migration on {}", addr))?;
+        // Only one source is accepted.
+        drop(listener);
+        info!("Migration from {} is accepted", source);
+        migrate_in(&mut stream, self)?;
+        info!("Migrated VM in from {}", source);
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn migrate_incoming(&self, _addr: &str) -> Result<()> {
+        bail!("Migration is not supported on aarch64 yet")
+    }
+
+    /// Defer migration in of VM until QMP `migrate-incoming` gives the
+    /// address to listen on. Devices and vcpus are realized but not started,
+    /// and VM waits in `inmigrate` state.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The VM migrated in, which is referred weakly by the listener.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to realize devices or vcpus.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    pub fn defer_incoming(vm: &Arc<Self>) -> Result<()> {
+        vm.realize_restored()?;
+        *vm.incoming.lock().unwrap() = Some(DeferredIncoming {
+            vm: Arc::downgrade(vm),
+            uri: None,
+        });
+        *vm.vm_state.deref().0.lock().unwrap() = KvmVmState::InMigrating;
+        info!("Waiting for QMP migrate-incoming");
+        Ok(())
+    }
+
+    #[cfg(all(not(feature = "qmp"), target_arch = "x86_64"))]
+    pub fn defer_incoming(_vm: &Arc<Self>) -> Result<()> {
+        bail!("-incoming defer needs QMP migrate-incoming, which is not built in")
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn defer_incoming(_vm: &Arc<Self>) -> Result<()> {
+        bail!("Migration is not supported on aarch64 yet")
+    }
+
+    /// Whether migration in of VM is deferred by `-incoming defer`.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn is_incoming_deferred(&self) -> bool {
+        self.incoming.lock().unwrap().is_some()
+    }
+
+    #[cfg(not(all(feature = "qmp", target_arch = "x86_64")))]
+    fn is_incoming_deferred(&self) -> bool {
+        false
+    }
+
+    /// Listen on `uri` for the source of VM deferred by `-incoming defer`,
+    /// the migration is accepted and received in main loop.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if VM isn't waiting for `migrate-incoming`, or fail to
+    /// listen on `uri`.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn start_incoming(&self, uri: &str) -> Result<()> {
+        let mut incoming = self.incoming.lock().unwrap();
+        let state = *self.vm_state.deref().0.lock().unwrap();
+        let listened = incoming.as_ref().and_then(|deferred| deferred.uri.clone());
+        check_migrate_incoming(state, listened.as_deref())?;
+        let deferred = match incoming.as_mut() {
+            Some(deferred) => deferred,
+            None => bail!("VM is not started with '-incoming defer'"),
+        };
+
+        let addr = self.migration_dest(uri)?;
+        let listener =
+            TcpListener::bind(addr).chain_err(|| format!("Failed to listen on {}", addr))?;
+        // Main loop may be woken up without any connection pending.
+        listener.set_nonblocking(true)?;
+        MainLoop::update_event(Self::incoming_notifiers(deferred.vm.clone(), listener))?;
+        deferred.uri = Some(uri.to_string());
+        info!("Waiting for migration on tcp {}", addr);
+        Ok(())
+    }
+
+    #[cfg(all(feature = "qmp", target_arch = "aarch64"))]
+    fn start_incoming(&self, _uri: &str) -> Result<()> {
+        bail!("Migration is not supported on aarch64 yet")
+    }
+
+    /// Notifiers of the listener created by `migrate-incoming`. Only one
+    /// source is accepted, whose migration is received in main loop, and the
+    /// listener is removed then.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn incoming_notifiers(vm: Weak<LightMachine>, listener: TcpListener) -> Vec<EventNotifier> {
+        let listener_fd = listener.as_raw_fd();
+        let handler: Box<NotifierCallback> = Box::new(move |_, _| {
+            let (mut stream, source) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return None,
+                Err(e) => {
+                    error!("Failed to accept migration: {}", e);
+                    return None;
+                }
+            };
+            if let Some(vm) = vm.upgrade() {
+                vm.receive_incoming(&mut stream, source);
+            }
+            Some(vec![EventNotifier::new(
+                NotifierOperation::Delete,
+                listener_fd,
+                None,
+                EventSet::IN,
+                Vec::new(),
+            )])
+        });
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            listener_fd,
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        )]
+    }
+
+    /// Receive VM migrated in from `source`, VM is left paused until `cont`.
+    /// VM is shut down if the migration fails, whose state is broken.
+    #[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+    fn receive_incoming(&self, stream: &mut TcpStream, source: SocketAddr) {
+        let notify = |status: MigrationStatus| {
+            event!(MIGRATION; schema::MIGRATION {
+                status: status.name().to_string()
+            });
+        };
+        info!("Migration from {} is accepted", source);
+        notify(MigrationStatus::Active);
+        if let Err(e) = migrate_in(stream, self) {
+            error!(
+                "Failed to migrate VM in from {}: {}",
+                source,
+                error_chain::ChainedError::display_chain(&e)
+            );
+            notify(MigrationStatus::Failed);
+            self.destroy();
+            return;
+        }
+
+        info!("Migrated VM in from {}", source);
+        notify(MigrationStatus::Completed);
+        if !self.notify_lifecycle(KvmVmState::InMigrating, KvmVmState::Paused) {
+            error!("Failed to pause VM migrated in");
+        }
+    }
+
+    /// Add a port of `virtconsole` or `virtserialport` to the multiport
+    /// console named by `bus`, which can be omitted if there is only one.
+    #[cfg(feature = "qmp")]
+    fn add_console_port(&self, args: schema::device_add) -> std::result::Result<(), String> {
+        let chardev = match args.chardev {
+            Some(chardev) => chardev,
+            None => {
+                return Err(format!(
+                    "Parameter 'chardev' is missing for {}",
+                    args.driver
+                ))
+            }
+        };
+        let console = match args.bus.as_ref() {
+            Some(bus) => self
+                .consoles
+                .iter()
+                .find(|(id, _)| id == bus)
+                .map(|(_, console)| console)
+                .ok_or_else(|| format!("Bus '{}' not found", bus))?,
+            None => {
+                let mut multiport = self
+                    .consoles
+                    .iter()
+                    .filter(|(_, console)| console.lock().unwrap().is_multiport());
+                match (multiport.next(), multiport.next()) {
+                    (Some((_, console)), None) => console,
+                    (None, _) => return Err("No multiport console device found".to_string()),
+                    _ => {
+                        return Err("Parameter 'bus' is required with multiple consoles".to_string())
+                    }
+                }
+            }
+        };
+
+        // Socket path is accepted as the shorthand of a unix socket chardev.
+        let chardev_cfg = if chardev.contains('/') {
+            ChardevConfig {
+                id: implicit_chardev_id(&args.id),
+                backend: ChardevType::Socket { path: chardev },
+            }
+        } else {
+            self.chardevs
+                .iter()
+                .find(|cfg| cfg.id == chardev)
+                .cloned()
+                .ok_or_else(|| format!("Chardev '{}' not found", chardev))?
+        };
+
+        self.bus.register_id(&args.id).map_err(|e| e.to_string())?;
+        let is_console = args.driver == "virtconsole";
+        let result =
+            console
+                .lock()
+                .unwrap()
+                .add_port(&args.id, args.name, &chardev_cfg, is_console);
+        let nr = match result {
+            Ok(nr) => nr,
+            Err(e) => {
+                self.bus.release_id(&args.id);
+                return Err(e.to_string());
+            }
+        };
+        info!("Port {} of console is added as {}", nr, args.id);
+        Ok(())
+    }
+
+    /// Destroy VM, kill all vcpu thread and join iothreads. Changed
+    /// `LightMachine`'s `vmstate` to `KVM_VMSTATE_DESTROY`.
+    fn vm_destroy(&self) -> Result<()> {
+        let mut vmstate = self.vm_state.deref().0.lock().unwrap();
+        *vmstate = KvmVmState::Shutdown;
+
+        let mut cpus = self.cpus.lock().unwrap();
+        for cpu_index in 0..self.cpu_topo.max_cpus {
+            cpus[cpu_index as usize].destroy()?;
+        }
+        cpus.clear();
+        drop(cpus);
+
+        // Iothreads check `vmstate` before exiting.
+        drop(vmstate);
+        MainLoop::stop_iothreads()?;
+
+        Ok(())
+    }
+
+    fn register_device<T: ConfigDevBuilder>(&mut self, dev_builder_ops: &T) -> Result<()> {
+        dev_builder_ops.build_dev(self.sys_mem.clone(), &mut self.bus)
+    }
+
+    fn add_devices(&mut self, vm_config: VmConfig) -> Result<()> {
+        #[cfg(target_arch = "aarch64")]
+        {
+            let rtc = Arc::new(Mutex::new(PL031::new()));
+            #[cfg(feature = "qmp")]
+            rtc.lock().unwrap().set_change_cb(Box::new(|offset| {
+                event!(RTC_CHANGE; schema::RTC_CHANGE { offset });
+            }));
+            // Notify the RTC change held by rate limit.
+            let cloned_rtc = rtc.clone();
+            MainLoop::add_timer(
+                RTC_CHANGE_INTERVAL,
+                TimerMode::Periodic,
+                Box::new(move |_| cloned_rtc.lock().unwrap().flush_rtc_change()),
+            )
+            .chain_err(|| "Failed to add timer of rtc")?;
+            self.bus
+                .attach_device(rtc)
+                .chain_err(|| "add rtc to bus failed")?;
+            self.bus
+                .attach_device(self.gpio.clone())
+                .chain_err(|| "add gpio to bus failed")?;
+        }
+
+        let mut chardevs = vm_config.chardevs.unwrap_or_default();
+        let mut take_chardev = |id: &str| -> Result<ChardevConfig> {
+            match chardevs.iter().position(|cfg| cfg.id == id) {
+                Some(index) => Ok(chardevs.remove(index)),
+                None => bail!("Chardev '{}' not found", id),
+            }
+        };
+
+        if let Some(serial_cfg) = vm_config.serial {
+            let serial = Arc::new(Mutex::new(Serial::new()));
+            if let Some(id) = serial_cfg.chardev.as_ref() {
+                let chardev = Arc::new(Mutex::new(Chardev::new(&take_chardev(id)?)));
+                chardev
+                    .lock()
+                    .unwrap()
+                    .realize()
+                    .chain_err(|| "Failed to realize chardev of serial")?;
+                Serial::set_chardev(&serial, &chardev);
+                MainLoop::update_event(EventNotifierHelper::internal_notifiers(chardev))?;
+            }
+            self.bus
+                .attach_device(serial)
+                .chain_err(|| "add serial to bus failed")?;
+        }
+
+        if let Some(vsock) = vm_config.vsock {
+            self.register_device(&vsock)?;
+        }
+
+        if let Some(drives) = vm_config.drives {
+            for drive in drives {
+                self.register_device(&drive)?;
+            }
+        }
+
+        if let Some(nets) = vm_config.nets {
+            for net in nets {
+                self.register_device(&net)?;
+            }
+        }
+
+        if let Some(balloon) = vm_config.balloon {
+            self.register_device(&balloon)?;
+        }
+
+        if let Some(rng) = vm_config.rng {
+            self.register_device(&rng)?;
+        }
+
+        if let Some(pmem) = vm_config.pmem {
+            self.register_device(&pmem)?;
+        }
+
+        if let Some(consoles) = vm_config.consoles {
+            for console_cfg in consoles {
+                let id = console_cfg.console_id.clone();
+                self.bus
+                    .register_id(&id)
+                    .chain_err(|| "add console to bus failed")?;
+                let chardev_cfg = take_chardev(&console_cfg.chardev)?;
+                let console = Arc::new(Mutex::new(Console::new(console_cfg, &chardev_cfg)));
+                let device = Arc::new(Mutex::new(VirtioMmioDevice::new(
+                    self.sys_mem.clone(),
+                    console.clone(),
+                )));
+                self.bus
+                    .attach_device(device)
+                    .chain_err(|| "add console to bus failed")?;
+                self.consoles.push((id, console));
+            }
+        }
+        self.chardevs = chardevs;
+
+        Ok(())
+    }
+
+    fn register_power_event(vm: &Arc<LightMachine>) -> Result<()> {
+        MainLoop::update_event(Self::power_event_notifiers(vm)?)?;
+        Ok(())
+    }
+
+    /// Notifiers of power button, which wakes main loop up to shut down VM
+    /// on request of guest, or to check whether VM is shut down.
+    fn power_event_notifiers(vm: &Arc<LightMachine>) -> Result<Vec<EventNotifier>> {
+        let button_fd = vm.power_button.as_raw_fd();
+        let vm = vm.clone();
+        let power_button_handler: Arc<Mutex<Box<NotifierCallback>>> =
+            Arc::new(Mutex::new(Box::new(move |_, _| {
+                vm.handle_power_button();
+                None
+            })));
+
+        Ok(vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            button_fd,
+            None,
+            EventSet::IN,
+            vec![power_button_handler],
+        )])
+    }
+
+    /// Handle the wake-up of power button in main loop. VM is shut down if
+    /// guest requested, and `SHUTDOWN` event is emitted for it only once.
+    ///
+    /// Return true if VM is shut down by guest in this call.
+    fn handle_power_button(&self) -> bool {
+        // The counter may have been read by the previous wake-up.
+        let _ret = self.power_button.read();
+
+        // Host may have shut down VM before the request is handled, then
+        // `destroy` fails and the loop exits as well.
+        if !self.guest_shutdown.load(Ordering::SeqCst) || !self.destroy() {
+            return false;
+        }
+
+        #[cfg(feature = "qmp")]
+        {
+            let shutdown_msg = schema::SHUTDOWN {
+                guest: true,
+                reason: self.guest_shutdown_reason().to_string(),
+            };
+            event!(SHUTDOWN; shutdown_msg);
+        }
+        true
+    }
+
+    /// Wake up main loop to handle the shutdown requested by guest, only
+    /// the first request counts.
+    ///
+    /// # Arguments
+    ///
+    /// * `reset` - Whether guest requested to reboot.
+    fn wake_guest_shutdown(&self, reset: bool) -> bool {
+        if self.guest_shutdown.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        info!("Guest requested to shut down VM");
+        self.guest_reset.store(reset, Ordering::SeqCst);
+        if let Err(e) = self.power_button.write(1) {
+            error!("Failed to wake up main loop for guest shutdown: {}", e);
+            return false;
+        }
+        true
+    }
+
+    /// Get the reason of `SHUTDOWN` event for the shutdown requested by
+    /// guest.
+    fn guest_shutdown_reason(&self) -> &'static str {
+        if self.guest_reset.load(Ordering::SeqCst) {
+            "guest-reset"
+        } else {
+            "guest-shutdown"
+        }
+    }
+
+    /// Shut down VM gracefully when the process receives SIGTERM or SIGINT.
+    /// If VM is not shut down within `grace`, the process is forced to exit.
+    /// SIGHUP reopens the log file for log rotation instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The VM to shut down.
+    /// * `signal_fd` - Signalfd receiving the termination signals.
+    /// * `grace` - Timeout of graceful shutdown.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to spawn watchdog thread or add event to main loop.
+    pub fn register_signal_event(
+        vm: &Arc<LightMachine>,
+        signal_fd: SignalFd,
+        grace: Duration,
+    ) -> Result<()> {
+        // Spawn the watchdog now, because creating thread is not allowed
+        // after seccomp is enabled.
+        let (shutdown_tx, shutdown_rx) = mpsc::channel::<i32>();
+        std::thread::Builder::new()
+            .name("shutdown watchdog".to_string())
+            .spawn(move || {
+                if let Ok(signal) = shutdown_rx.recv() {
+                    if let Err(RecvTimeoutError::Timeout) = shutdown_rx.recv_timeout(grace) {
+                        error!(
+                            "VM is not shut down in {:?} after signal {}, force to exit",
+                            grace, signal
+                        );
+                        util::cleanup::run_cleanup_hooks();
+                        std::process::exit(1);
+                    }
+                }
+            })
+            .chain_err(|| "Failed to spawn shutdown watchdog thread")?;
+
+        let signal_raw_fd = signal_fd.as_raw_fd();
+        let vm = vm.clone();
+        let shutting_down = AtomicBool::new(false);
+        let signal_handler: Arc<Mutex<Box<NotifierCallback>>> =
+            Arc::new(Mutex::new(Box::new(move |_, _| {
+                while let Some(signal) = signal_fd.read() {
+                    if signal == libc::SIGHUP {
+                        info!("Received signal {}, reopen log file", signal);
+                        if let Err(e) = util::logger::reopen_log_file() {
+                            error!("Failed to reopen log file: {}", e);
+                        }
+                        continue;
+                    }
+                    if shutting_down.swap(true, Ordering::SeqCst) {
+                        info!("Signal {} ignored, VM is shutting down", signal);
+                        continue;
+                    }
+
+                    info!("Received signal {}, shut down VM", signal);
+                    let _ret = shutdown_tx.send(signal);
+                    vm.destroy();
+
+                    #[cfg(feature = "qmp")]
+                    {
+                        let shutdown_msg = schema::SHUTDOWN {
+                            guest: false,
+                            reason: "host-signal".to_string(),
+                        };
+                        event!(SHUTDOWN; shutdown_msg);
+                    }
+                }
+                None
+            })));
+
+        MainLoop::update_event(vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            signal_raw_fd,
+            None,
+            EventSet::IN,
+            vec![signal_handler],
+        )])?;
+        Ok(())
+    }
+
+    /// Press the power button of guest, by ACPI fixed power button on x86_64
+    /// and by the `gpio-keys` pin of pl061 on aarch64.
+    #[cfg(feature = "qmp")]
+    fn press_power_button(&self) -> Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        self.acpi_pm
+            .lock()
+            .unwrap()
+            .press_power_button()
+            .chain_err(|| "Failed to press ACPI power button")?;
+        #[cfg(target_arch = "aarch64")]
+        self.gpio
+            .lock()
+            .unwrap()
+            .set_input(GPIO_POWER_BUTTON_PIN, true);
+        Ok(())
+    }
+
+    /// Shut down VM when guest enters ACPI S5 state.
+    #[cfg(target_arch = "x86_64")]
+    fn register_acpi_shutdown_event(vm: &Arc<LightMachine>) -> Result<()> {
+        MainLoop::update_event(Self::acpi_shutdown_notifiers(vm)?)?;
+        Ok(())
+    }
+
+    /// Notifiers of ACPI shutdown request from guest.
+    #[cfg(target_arch = "x86_64")]
+    fn acpi_shutdown_notifiers(vm: &Arc<LightMachine>) -> Result<Vec<EventNotifier>> {
+        let shutdown_evt = vm
+            .acpi_pm
+            .lock()
+            .unwrap()
+            .shutdown_evt()
+            .try_clone()
+            .chain_err(|| "Failed to clone ACPI shutdown EventFd")?;
+        let shutdown_fd = shutdown_evt.as_raw_fd();
+        let vm = vm.clone();
+        let shutdown_handler: Arc<Mutex<Box<NotifierCallback>>> =
+            Arc::new(Mutex::new(Box::new(move |_, _| {
+                let _ret = shutdown_evt.read();
+                vm.request_guest_shutdown();
+                None
+            })));
+
+        Ok(vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            shutdown_fd,
+            None,
+            EventSet::IN,
+            vec![shutdown_handler],
+        )])
+    }
+
+    /// Reboot VM when guest writes the reset ports.
+    #[cfg(target_arch = "x86_64")]
+    fn register_reset_event(vm: &Arc<LightMachine>) -> Result<()> {
+        let reset_evt = vm
+            .reset_ctrl
+            .lock()
+            .unwrap()
+            .reset_evt()
+            .try_clone()
+            .chain_err(|| "Failed to clone guest reset EventFd")?;
+        let reset_fd = reset_evt.as_raw_fd();
+        let vm = vm.clone();
+        let reset_handler: Arc<Mutex<Box<NotifierCallback>>> =
+            Arc::new(Mutex::new(Box::new(move |_, _| {
+                let _ret = reset_evt.read();
+                vm.request_guest_reset();
+                None
+            })));
+
+        MainLoop::update_event(vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            reset_fd,
+            None,
+            EventSet::IN,
+            vec![reset_handler],
+        )])?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn generate_serial_device_node(
+        &self,
+        dev_info: &DeviceResource,
+        fdt: &mut Vec<u8>,
+    ) -> util::errors::Result<()> {
+        let node = serial_node_path(dev_info.addr);
+        device_tree::add_sub_node(fdt, &node)?;
+        device_tree::set_property_string(fdt, &node, "compatible", "ns16550a")?;
+        device_tree::set_property_string(fdt, &node, "clock-names", "apb_pclk")?;
+        device_tree::set_property_u32(fdt, &node, "clocks", device_tree::CLK_PHANDLE)?;
+        device_tree::set_reg(fdt, &node, &[(dev_info.addr, dev_info.size)])?;
+        device_tree::set_interrupts_spi(
+            fdt,
+            &node,
+            dev_info.irq,
+            device_tree::IRQ_TYPE_EDGE_RISING,
+        )?;
 
         Ok(())
     }
@@ -630,15 +2193,59 @@ impl LightMachine {
         device_tree::set_property_string(fdt, &node, "compatible", "arm,pl031\0arm,primecell\0")?;
         device_tree::set_property_string(fdt, &node, "clock-names", "apb_pclk")?;
         device_tree::set_property_u32(fdt, &node, "clocks", device_tree::CLK_PHANDLE)?;
-        device_tree::set_property_array_u64(fdt, &node, "reg", &[dev_info.addr, dev_info.size])?;
-        device_tree::set_property_array_u32(
+        device_tree::set_reg(fdt, &node, &[(dev_info.addr, dev_info.size)])?;
+        device_tree::set_interrupts_spi(
+            fdt,
+            &node,
+            dev_info.irq,
+            device_tree::IRQ_TYPE_LEVEL_HIGH,
+        )?;
+
+        Ok(())
+    }
+
+    /// Generate the node of pl061 and the `gpio-keys` node of power button
+    /// attached to it.
+    #[cfg(target_arch = "aarch64")]
+    fn generate_gpio_device_node(
+        &self,
+        dev_info: &DeviceResource,
+        fdt: &mut Vec<u8>,
+    ) -> util::errors::Result<()> {
+        let node = format!("/pl061@{:x}", dev_info.addr);
+        device_tree::add_sub_node(fdt, &node)?;
+        device_tree::set_property_string(fdt, &node, "compatible", "arm,pl061\0arm,primecell\0")?;
+        device_tree::set_property_string(fdt, &node, "clock-names", "apb_pclk")?;
+        device_tree::set_property_u32(fdt, &node, "clocks", device_tree::CLK_PHANDLE)?;
+        device_tree::set_reg(fdt, &node, &[(dev_info.addr, dev_info.size)])?;
+        device_tree::set_interrupts_spi(
             fdt,
             &node,
-            "interrupts",
+            dev_info.irq,
+            device_tree::IRQ_TYPE_LEVEL_HIGH,
+        )?;
+        device_tree::set_property(fdt, &node, "gpio-controller", None)?;
+        device_tree::set_property_u32(fdt, &node, "#gpio-cells", 2)?;
+        device_tree::set_property_u32(fdt, &node, "phandle", device_tree::GPIO_PHANDLE)?;
+
+        let node = "/gpio-keys";
+        device_tree::add_sub_node(fdt, node)?;
+        device_tree::set_property_string(fdt, node, "compatible", "gpio-keys")?;
+        device_tree::set_property_u32(fdt, node, "#size-cells", 0)?;
+        device_tree::set_property_u32(fdt, node, "#address-cells", 1)?;
+
+        let node = "/gpio-keys/poweroff";
+        device_tree::add_sub_node(fdt, node)?;
+        device_tree::set_property_string(fdt, node, "label", "GPIO Key Poweroff")?;
+        device_tree::set_property_u32(fdt, node, "linux,code", KEY_POWER)?;
+        device_tree::set_property_array_u32(
+            fdt,
+            node,
+            "gpios",
             &[
-                device_tree::GIC_FDT_IRQ_TYPE_SPI,
-                dev_info.irq,
-                device_tree::IRQ_TYPE_LEVEL_HIGH,
+                device_tree::GPIO_PHANDLE,
+                u32::from(GPIO_POWER_BUTTON_PIN),
+                0,
             ],
         )?;
 
@@ -655,43 +2262,118 @@ impl LightMachine {
         device_tree::add_sub_node(fdt, &node)?;
         device_tree::set_property_string(fdt, &node, "compatible", "virtio,mmio")?;
         device_tree::set_property_u32(fdt, &node, "interrupt-parent", device_tree::GIC_PHANDLE)?;
-        device_tree::set_property_array_u64(fdt, &node, "reg", &[dev_info.addr, dev_info.size])?;
-        device_tree::set_property_array_u32(
+        device_tree::set_reg(fdt, &node, &[(dev_info.addr, dev_info.size)])?;
+        device_tree::set_interrupts_spi(
             fdt,
             &node,
-            "interrupts",
-            &[
-                device_tree::GIC_FDT_IRQ_TYPE_SPI,
-                dev_info.irq,
-                device_tree::IRQ_TYPE_EDGE_RISING,
-            ],
+            dev_info.irq,
+            device_tree::IRQ_TYPE_EDGE_RISING,
         )?;
 
         Ok(())
     }
 }
 
-impl MachineLifecycle for LightMachine {
-    fn pause(&self) -> bool {
-        if self.notify_lifecycle(KvmVmState::Running, KvmVmState::Paused) {
-            #[cfg(feature = "qmp")]
-            event!(STOP);
+#[cfg(target_arch = "x86_64")]
+impl MigrationSource for LightMachine {
+    fn config(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&self.vm_config.to_value())?)
+    }
 
-            true
-        } else {
-            false
+    fn ram_ranges(&self) -> Vec<(u64, u64)> {
+        self.ram_mappings
+            .iter()
+            .map(|mapping| (mapping.start_address().raw_value(), mapping.size()))
+            .collect()
+    }
+
+    fn read_ram(&self, addr: u64, mut buf: &mut [u8]) -> Result<()> {
+        let count = buf.len() as u64;
+        self.sys_mem
+            .read(&mut buf, GuestAddress(addr), count)
+            .chain_err(|| format!("Failed to read guest memory at {:#x}", addr))
+    }
+
+    fn page_size(&self) -> u64 {
+        address_space::page_size()
+    }
+
+    fn start_dirty_log(&self) -> Result<()> {
+        Ok(self.sys_mem.start_dirty_log()?)
+    }
+
+    fn stop_dirty_log(&self) -> Result<()> {
+        Ok(self.sys_mem.stop_dirty_log()?)
+    }
+
+    fn dirty_pages(&self) -> Result<BTreeSet<u64>> {
+        Ok(self.sys_mem.get_dirty_log()?)
+    }
+
+    fn is_running(&self) -> bool {
+        *self.vm_state.deref().0.lock().unwrap() == KvmVmState::Running
+    }
+
+    fn pause_vm(&self) -> Result<()> {
+        if self.is_running() && !self.pause() {
+            bail!("Failed to pause VM");
         }
+        self.wait_io_quiesced()
     }
 
-    fn resume(&self) -> bool {
-        if !self.notify_lifecycle(KvmVmState::Paused, KvmVmState::Running) {
-            return false;
+    fn resume_vm(&self) -> Result<()> {
+        if !self.resume() {
+            bail!("Failed to resume VM");
         }
+        Ok(())
+    }
 
-        #[cfg(feature = "qmp")]
-        event!(RESUME);
+    fn handed_off(&self) {
+        // VM can't be resumed, which would run the same guest as the
+        // destination.
+        *self.vm_state.deref().0.lock().unwrap() = KvmVmState::Migrated;
+    }
 
-        true
+    fn state(&self) -> Result<Vec<u8>> {
+        let mut writer = SnapshotWriter::new(Cursor::new(Vec::new()))?;
+        self.write_vm_state(&mut writer)?;
+        Ok(writer.finish()?.into_inner())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl MigrationTarget for LightMachine {
+    fn check_config(&self, config: &[u8]) -> Result<()> {
+        let value = serde_json::from_slice(config).chain_err(|| "Invalid config of source")?;
+        let source =
+            VmConfig::create_from_value(value).chain_err(|| "Failed to parse config of source")?;
+        self.vm_config
+            .check_migration(&source)
+            .chain_err(|| "Config of source doesn't match")?;
+        Ok(())
+    }
+
+    fn write_ram(&self, addr: u64, data: &[u8]) -> Result<()> {
+        let mut src = data;
+        self.sys_mem
+            .write(&mut src, GuestAddress(addr), data.len() as u64)
+            .chain_err(|| format!("Failed to write guest memory at {:#x}", addr))
+    }
+
+    fn load_state(&self, state: &[u8]) -> Result<()> {
+        let mut reader = SnapshotReader::new(Cursor::new(state))?;
+        self.load_vm_state(&mut reader)
+    }
+}
+
+impl MachineLifecycle for LightMachine {
+    fn pause(&self) -> bool {
+        self.vm_transform("stop", KvmVmState::Paused) == Ok(true)
+    }
+
+    fn resume(&self) -> bool {
+        // Vcpus frozen at startup by `-S` are resumed from `Created` state.
+        self.vm_transform("cont", KvmVmState::Running) == Ok(true)
     }
 
     fn destroy(&self) -> bool {
@@ -707,6 +2389,21 @@ impl MachineLifecycle for LightMachine {
         true
     }
 
+    fn request_guest_shutdown(&self) -> bool {
+        self.wake_guest_shutdown(false)
+    }
+
+    fn request_guest_reset(&self) -> bool {
+        info!(
+            "Guest requested to reboot VM, action is {:?}",
+            self.reboot_action
+        );
+        if !reboot_dispatch(self.reboot_action, || self.reset()) {
+            return true;
+        }
+        self.wake_guest_shutdown(true)
+    }
+
     fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
         use KvmVmState::*;
 
@@ -718,10 +2415,17 @@ impl MachineLifecycle for LightMachine {
         drop(vmstate);
 
         match (old, new) {
+            // Vcpus started by `vm_start` run at once, only those frozen by
+            // it are resumed here.
             (Created, Running) => {
-                if let Err(e) = self.vm_start(false, false) {
-                    error!("Vm lifecycle error:{}", e);
-                };
+                if !self.start_frozen.load(Ordering::SeqCst) {
+                    error!("Vm lifecycle error: vcpus are not started.");
+                    return false;
+                }
+                match self.vm_resume() {
+                    Ok(()) => self.start_frozen.store(false, Ordering::SeqCst),
+                    Err(e) => error!("Vm lifecycle error:{}", e),
+                }
             }
             (Running, Paused) => {
                 if let Err(e) = self.vm_pause() {
@@ -733,6 +2437,15 @@ impl MachineLifecycle for LightMachine {
                     error!("Vm lifecycle error:{}", e);
                 };
             }
+            // Vcpus of VM migrated in by `migrate-incoming` are still frozen
+            // since `vm_start`.
+            (InMigrating, Paused) => {
+                *self.vm_state.deref().0.lock().unwrap() = Paused;
+            }
+            (Shutdown, Shutdown) => {
+                info!("Vm lifecycle: VM is shut down already.");
+                return false;
+            }
             (_, Shutdown) => {
                 if let Err(e) = self.vm_destroy() {
                     error!("Vm lifecycle error:{}", e);
@@ -797,22 +2510,48 @@ impl MachineAddressInterface for LightMachine {
 impl DeviceInterface for LightMachine {
     #[cfg(feature = "qmp")]
     fn query_status(&self) -> qmp::Response {
-        let vmstate = self.vm_state.deref().0.lock().unwrap();
-        let qmp_state = match *vmstate {
-            KvmVmState::Running => schema::StatusInfo {
-                singlestep: false,
-                running: true,
-                status: schema::RunState::running,
-            },
-            KvmVmState::Paused => schema::StatusInfo {
-                singlestep: false,
-                running: true,
-                status: schema::RunState::paused,
-            },
-            _ => Default::default(),
+        let qmp_state = self.vm_state.deref().0.lock().unwrap().status_info();
+        qmp::Response::create_response(serde_json::to_value(&qmp_state).unwrap(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn stop(&self) -> std::result::Result<(), String> {
+        self.vm_transform("stop", KvmVmState::Paused).map(|_| ())
+    }
+
+    #[cfg(feature = "qmp")]
+    fn cont(&self) -> std::result::Result<(), String> {
+        self.vm_transform("cont", KvmVmState::Running).map(|_| ())
+    }
+
+    #[cfg(feature = "qmp")]
+    fn system_powerdown(&self) -> qmp::Response {
+        if let Err(e) = self.press_power_button() {
+            error!("Failed to press power button: {}", e);
+            return qmp::Response::create_error_response(
+                schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            )
+            .unwrap();
+        }
+        event!(POWERDOWN);
+        qmp::Response::create_empty_response()
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_name(&self) -> qmp::Response {
+        let name_info = schema::NameInfo {
+            name: self.name.clone(),
         };
+        qmp::Response::create_response(serde_json::to_value(&name_info).unwrap(), None)
+    }
 
-        qmp::Response::create_response(serde_json::to_value(&qmp_state).unwrap(), None)
+    #[cfg(feature = "qmp")]
+    fn query_uuid(&self) -> qmp::Response {
+        let uuid_info = schema::UuidInfo {
+            uuid: self.uuid.clone(),
+        };
+        qmp::Response::create_response(serde_json::to_value(&uuid_info).unwrap(), None)
     }
 
     #[cfg(feature = "qmp")]
@@ -905,84 +2644,482 @@ impl DeviceInterface for LightMachine {
                             + &"]".to_string(),
                     ),
                 };
-                hotplug_vec.push(serde_json::to_value(hotpluggable_cpu).unwrap());
+                hotplug_vec.push(serde_json::to_value(hotpluggable_cpu).unwrap());
+            }
+        }
+        qmp::Response::create_response(hotplug_vec.into(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_memory_advice(&self) -> qmp::Response {
+        let mut advice_vec: Vec<serde_json::Value> = Vec::new();
+        for mapping in self.ram_mappings.iter() {
+            let mem_advice = mapping.mem_advice();
+            let thp = match mem_advice.thp {
+                Some(true) => "on",
+                Some(false) => "off",
+                None => "auto",
+            };
+            let advice_info = schema::MemoryAdviceInfo {
+                base: mapping.start_address().raw_value(),
+                size: mapping.size(),
+                dump_guest_core: mem_advice.dump_guest_core,
+                mem_merge: mem_advice.mem_merge,
+                thp: thp.to_string(),
+            };
+            advice_vec.push(serde_json::to_value(advice_info).unwrap());
+        }
+        qmp::Response::create_response(advice_vec.into(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_machines(&self) -> qmp::Response {
+        // Vcpus of micro VM are all created at boot, none is hotpluggable.
+        let machine_info = schema::MachineInfo {
+            name: "microvm".to_string(),
+            alias: None,
+            is_default: Some(true),
+            cpu_max: machine_manager::config::MAX_NR_CPUS as isize,
+            hotpluggable_cpus: false,
+        };
+        qmp::Response::create_response(serde_json::to_value(vec![machine_info]).unwrap(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_machine_properties(&self) -> qmp::Response {
+        let props: Vec<schema::MachinePropertyInfo> = MACHINE_PROPS
+            .iter()
+            .map(|prop| schema::MachinePropertyInfo {
+                name: prop.name.to_string(),
+                prop_type: prop.prop_type.name().to_string(),
+                value: self
+                    .machine_config
+                    .machine_prop(prop.name)
+                    .unwrap_or_default(),
+                default: prop.default.map(String::from),
+            })
+            .collect();
+        qmp::Response::create_response(serde_json::to_value(props).unwrap(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_eventloop_stats(&self, reset: Option<bool>) -> qmp::Response {
+        let mut stats_vec: Vec<serde_json::Value> = Vec::new();
+        for (name, stats) in MainLoop::stats(reset.unwrap_or(false)) {
+            let callback_time = stats.callback_time.map(|histogram| {
+                histogram
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, count)| schema::HistogramBucket {
+                        upper_bound_us: CALLBACK_TIME_BUCKETS_US.get(index).copied(),
+                        count,
+                    })
+                    .collect()
+            });
+            let stats_info = schema::EventLoopStats {
+                name,
+                iterations: stats.iterations,
+                wakeups: stats.wakeups,
+                events: stats.events,
+                max_events: stats.max_events,
+                notifiers: stats
+                    .notifiers
+                    .into_iter()
+                    .map(|(fd, count)| schema::NotifierStats { fd, count })
+                    .collect(),
+                callback_time,
+            };
+            stats_vec.push(serde_json::to_value(stats_info).unwrap());
+        }
+        qmp::Response::create_response(stats_vec.into(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_boot_times(&self) -> qmp::Response {
+        let boot_times: Vec<schema::BootTimeInfo> = self
+            .boot_times
+            .snapshot()
+            .into_iter()
+            .map(|boot_time| schema::BootTimeInfo {
+                name: boot_time.milestone.name().to_string(),
+                time_ms: boot_time.time.as_micros() as f64 / 1000.0,
+                delta_ms: boot_time.delta.as_micros() as f64 / 1000.0,
+            })
+            .collect();
+        qmp::Response::create_response(serde_json::to_value(boot_times).unwrap(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_kvm_caps(&self) -> qmp::Response {
+        let caps: Vec<schema::KvmCapInfo> = self
+            .kvm_caps
+            .probed()
+            .iter()
+            .map(|cap| schema::KvmCapInfo {
+                name: cap.name.to_string(),
+                required: cap.required,
+                supported: cap.supported,
+            })
+            .collect();
+        qmp::Response::create_response(serde_json::to_value(caps).unwrap(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn dump_guest_memory(
+        &self,
+        protocol: String,
+        paging: bool,
+        detach: Option<bool>,
+        begin: Option<u64>,
+        length: Option<u64>,
+    ) -> qmp::Response {
+        let window = match (begin, length) {
+            (None, None) => Ok(None),
+            (Some(_), Some(0)) => Err("Parameter 'length' must not be zero".to_string()),
+            (Some(begin), Some(length)) => Ok(Some((begin, length))),
+            _ => Err("Parameters 'begin' and 'length' must be given together".to_string()),
+        };
+        let result = if paging {
+            Err("Dump with paging is not supported".to_string())
+        } else if detach == Some(true) {
+            Err("Detached dump is not supported".to_string())
+        } else {
+            window.and_then(|window| {
+                self.dump_guest_core(&protocol, window)
+                    .map_err(|e| e.to_string())
+            })
+        };
+
+        if let Err(e) = result {
+            error!("Failed to dump guest memory: {}", e);
+            let err_resp = schema::QmpErrorClass::GenericError(e);
+            return qmp::Response::create_error_response(err_resp, None).unwrap();
+        }
+        qmp::Response::create_empty_response()
+    }
+
+    #[cfg(feature = "qmp")]
+    fn x_snapshot_save(&self, path: String, paused: Option<bool>) -> qmp::Response {
+        if let Err(e) = self.save_snapshot(&path, paused.unwrap_or(false)) {
+            // Causes are reported too, such as the device which can't be saved.
+            let msg = e
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(": ");
+            error!("Failed to save snapshot: {}", msg);
+            let err_resp = schema::QmpErrorClass::GenericError(msg);
+            return qmp::Response::create_error_response(err_resp, None).unwrap();
+        }
+        qmp::Response::create_empty_response()
+    }
+
+    #[cfg(feature = "qmp")]
+    fn migrate(&self, args: schema::migrate) -> qmp::Response {
+        let result = if args.blk == Some(true) || args.inc == Some(true) {
+            Err("Migration of drive images is not supported".into())
+        } else {
+            self.start_migration(&args.uri)
+        };
+        if let Err(e) = result {
+            let msg = e
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(": ");
+            error!("Failed to start migration: {}", msg);
+            let err_resp = schema::QmpErrorClass::GenericError(msg);
+            return qmp::Response::create_error_response(err_resp, None).unwrap();
+        }
+        qmp::Response::create_empty_response()
+    }
+
+    #[cfg(feature = "qmp")]
+    fn migrate_cancel(&self) -> qmp::Response {
+        self.migration.cancel();
+        qmp::Response::create_empty_response()
+    }
+
+    #[cfg(feature = "qmp")]
+    fn migrate_incoming(&self, uri: String) -> qmp::Response {
+        if let Err(e) = self.start_incoming(&uri) {
+            let msg = e
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(": ");
+            error!("Failed to start migration in: {}", msg);
+            let err_resp = schema::QmpErrorClass::GenericError(msg);
+            return qmp::Response::create_error_response(err_resp, None).unwrap();
+        }
+        qmp::Response::create_empty_response()
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_migrate(&self) -> qmp::Response {
+        let info = self.migration.info();
+        let progress = &info.progress;
+        let mut migration_info = schema::MigrationInfo::default();
+        if info.status != MigrationStatus::None {
+            migration_info.status = Some(info.status.name().to_string());
+            migration_info.total_time = Some(info.total_time);
+        }
+        match info.status {
+            MigrationStatus::Active | MigrationStatus::Cancelling | MigrationStatus::Completed => {
+                migration_info.ram = Some(schema::MigrationStats {
+                    transferred: progress.transferred.load(Ordering::SeqCst),
+                    remaining: progress.remaining.load(Ordering::SeqCst),
+                    total: progress.total.load(Ordering::SeqCst),
+                    dirty_pages_rate: progress.dirty_pages_rate.load(Ordering::SeqCst),
+                    dirty_sync_count: progress.dirty_sync_count.load(Ordering::SeqCst),
+                });
+            }
+            _ => (),
+        }
+        if info.status == MigrationStatus::Completed {
+            migration_info.downtime = Some(progress.downtime.load(Ordering::SeqCst));
+        }
+        migration_info.error_desc = info.error;
+        qmp::Response::create_response(serde_json::to_value(migration_info).unwrap(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn migrate_set_parameters(
+        &self,
+        max_bandwidth: Option<u64>,
+        downtime_limit: Option<u64>,
+    ) -> qmp::Response {
+        let mut params = self.migration.params();
+        if let Some(max_bandwidth) = max_bandwidth {
+            params.max_bandwidth = max_bandwidth;
+        }
+        if let Some(downtime_limit) = downtime_limit {
+            params.downtime_limit = downtime_limit;
+        }
+        if let Err(e) = self.migration.set_params(params) {
+            let err_resp = schema::QmpErrorClass::GenericError(e.to_string());
+            return qmp::Response::create_error_response(err_resp, None).unwrap();
+        }
+        qmp::Response::create_empty_response()
+    }
+
+    #[cfg(feature = "qmp")]
+    fn qom_list(&self, path: String) -> qmp::Response {
+        match qom::qom_list(self, &path) {
+            Ok(props) => qmp::Response::create_response(serde_json::to_value(props).unwrap(), None),
+            Err(err_resp) => qmp::Response::create_error_response(err_resp, None).unwrap(),
+        }
+    }
+
+    #[cfg(feature = "qmp")]
+    fn qom_get(&self, path: String, property: String) -> qmp::Response {
+        match qom::qom_get(self, &path, &property) {
+            Ok(value) => qmp::Response::create_response(value, None),
+            Err(err_resp) => qmp::Response::create_error_response(err_resp, None).unwrap(),
+        }
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_block(&self) -> qmp::Response {
+        let mut block_vec: Vec<serde_json::Value> = Vec::new();
+        for info in self.bus.get_replaceable_info() {
+            if let Some(drive) = info.dev_config.as_any().downcast_ref::<DriveConfig>() {
+                let block_info = schema::BlockInfo {
+                    device: info.id,
+                    file: drive.path_on_host.clone(),
+                    read_only: drive.read_only,
+                    direct: drive.direct,
+                    attached_to: info.resource.map(|res| mmio_device_path(&res)),
+                    removing: info.removing,
+                };
+                block_vec.push(serde_json::to_value(block_info).unwrap());
             }
         }
-        qmp::Response::create_response(hotplug_vec.into(), None)
+        qmp::Response::create_response(block_vec.into(), None)
     }
 
-    fn device_add(
-        &self,
-        id: String,
-        driver: String,
-        addr: Option<String>,
-        lun: Option<usize>,
-    ) -> bool {
-        // get slot of bus by addr or lun
-        let mut slot = 0;
-        if let Some(addr) = addr {
-            let slot_str = addr.as_str().trim_start_matches("0x");
-
-            if let Ok(n) = usize::from_str_radix(slot_str, 16) {
-                slot = n;
+    #[cfg(feature = "qmp")]
+    fn query_network(&self) -> qmp::Response {
+        let mut net_vec: Vec<serde_json::Value> = Vec::new();
+        for info in self.bus.get_replaceable_info() {
+            if let Some(net) = info
+                .dev_config
+                .as_any()
+                .downcast_ref::<NetworkInterfaceConfig>()
+            {
+                let net_info = schema::NetInfo {
+                    id: info.id,
+                    ifname: Some(net.host_dev_name.clone()).filter(|name| !name.is_empty()),
+                    fd: net.tap_fd,
+                    mac: net.mac.clone(),
+                    vhost: net.vhost_type.is_some(),
+                    attached_to: info.resource.map(|res| mmio_device_path(&res)),
+                    removing: info.removing,
+                };
+                net_vec.push(serde_json::to_value(net_info).unwrap());
             }
-        } else if let Some(lun) = lun {
-            slot = lun + 1;
+        }
+        qmp::Response::create_response(net_vec.into(), None)
+    }
+
+    #[cfg(feature = "qmp")]
+    fn query_mtree(&self) -> qmp::Response {
+        let mut region_vec: Vec<serde_json::Value> = Vec::new();
+        for mapping in self.ram_mappings.iter() {
+            let region_info = schema::MemoryRegionInfo {
+                address_space: "memory".to_string(),
+                name: "ram".to_string(),
+                addr: mapping.start_address().raw_value(),
+                size: mapping.size(),
+                ram: true,
+            };
+            region_vec.push(serde_json::to_value(region_info).unwrap());
         }
 
-        self.bus.add_replaceable_device(&id, &driver, slot).is_ok()
+        // Serial of x86_64 is in the I/O address space, which is listed last.
+        let (pio_devs, mmio_devs): (Vec<_>, Vec<_>) =
+            self.bus.get_devices_info().into_iter().partition(|res| {
+                cfg!(target_arch = "x86_64") && res.dev_type == DeviceType::SERIAL
+            });
+        for (address_space, res) in mmio_devs
+            .iter()
+            .map(|res| ("memory", res))
+            .chain(pio_devs.iter().map(|res| ("I/O", res)))
+        {
+            let region_info = schema::MemoryRegionInfo {
+                address_space: address_space.to_string(),
+                name: mmio_device_name(res.dev_type).to_string(),
+                addr: res.addr,
+                size: res.size,
+                ram: false,
+            };
+            region_vec.push(serde_json::to_value(region_info).unwrap());
+        }
+        qmp::Response::create_response(region_vec.into(), None)
     }
 
-    fn device_del(&self, device_id: String) -> bool {
-        match self.bus.del_replaceable_device(&device_id) {
-            Ok(path) => {
-                #[cfg(feature = "qmp")]
-                {
-                    let block_del_event = schema::DEVICE_DELETED {
-                        device: Some(device_id),
-                        path,
-                    };
-                    event!(DEVICE_DELETED; block_del_event);
+    #[cfg(feature = "qmp")]
+    fn device_add(&self, args: schema::device_add) -> qmp::Response {
+        let id = args.id.clone();
+        let result = if args.driver == "virtconsole" || args.driver == "virtserialport" {
+            self.add_console_port(args)
+        } else {
+            // get slot of bus by addr or lun
+            let mut slot = 0;
+            if let Some(addr) = args.addr {
+                let slot_str = addr.as_str().trim_start_matches("0x");
+
+                if let Ok(n) = usize::from_str_radix(slot_str, 16) {
+                    slot = n;
                 }
+            } else if let Some(lun) = args.lun {
+                slot = lun + 1;
+            }
+
+            self.bus
+                .add_replaceable_device(&args.id, &args.driver, slot)
+                .map_err(|e| e.to_string())
+        };
 
-                true
+        match result {
+            Ok(()) => qmp::Response::create_empty_response(),
+            Err(e) => {
+                error!("Failed to add device {}: {}", id, e);
+                qmp::Response::create_error_response(schema::QmpErrorClass::GenericError(e), None)
+                    .unwrap()
             }
-            _ => false,
         }
     }
 
-    fn blockdev_add(
-        &self,
-        node_name: String,
-        file: schema::FileOptions,
-        cache: Option<schema::CacheOptions>,
-        read_only: Option<bool>,
-    ) -> bool {
-        let read_only = if let Some(ro) = read_only { ro } else { false };
-
-        let direct = if let Some(cache) = cache {
-            match cache.direct {
-                Some(direct) => direct,
-                _ => true,
-            }
-        } else {
-            true
+    #[cfg(feature = "qmp")]
+    fn device_del(&self, device_id: String) -> qmp::Response {
+        let block_del_event = schema::DEVICE_DELETED {
+            device: Some(device_id.clone()),
+            path: device_id.clone(),
         };
 
-        let config = DriveConfig {
-            drive_id: node_name.clone(),
-            path_on_host: file.filename,
-            read_only,
-            direct,
-            serial_num: None,
-        };
+        let console = self
+            .consoles
+            .iter()
+            .find(|(_, console)| console.lock().unwrap().has_port(&device_id));
+        if let Some((_, console)) = console {
+            return match console.lock().unwrap().del_port(&device_id) {
+                Ok(()) => {
+                    self.bus.release_id(&device_id);
+                    event!(DEVICE_DELETED; block_del_event);
+                    qmp::Response::create_empty_response()
+                }
+                Err(e) => {
+                    error!("Failed to delete device {}: {}", device_id, e);
+                    qmp::Response::create_error_response(
+                        schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    )
+                    .unwrap()
+                }
+            };
+        }
 
-        self.bus
-            .add_replaceable_config(node_name, Arc::new(config))
-            .is_ok()
+        let done = Box::new(move || {
+            event!(DEVICE_DELETED; block_del_event);
+        });
+        match self.bus.del_replaceable_device(&device_id, done) {
+            Ok(_) => qmp::Response::create_empty_response(),
+            Err(e) => {
+                error!("Failed to delete device {}: {}", device_id, e);
+                qmp::Response::create_error_response(
+                    schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+                .unwrap()
+            }
+        }
+    }
+
+    #[cfg(feature = "qmp")]
+    fn blockdev_add(&self, args: schema::blockdev_add) -> qmp::Response {
+        let node_name = args.node_name.clone();
+        let result = DriveConfig::from_blockdev_add(args)
+            .map_err(|e| e.to_string())
+            .and_then(|config| {
+                // It's checked here, so that the failure is reported before
+                // the device is realized by `device_add`.
+                let aio = config.aio_engine();
+                if aio == machine_manager::config::AioEngine::IoUring {
+                    if !util::aio::uring_supported() {
+                        return Err("aio=io_uring is not available on this host".to_string());
+                    }
+                    // Seccomp allows io_uring only if a drive used it at boot.
+                    if micro_syscall::installed_role().is_some()
+                        && !self.seccomp_fragments.contains(&SeccompFragment::Uring)
+                    {
+                        return Err(
+                            "aio=io_uring is not allowed by seccomp, no drive used it at boot"
+                                .to_string(),
+                        );
+                    }
+                }
+                self.bus
+                    .add_replaceable_config(node_name.clone(), Arc::new(config))
+                    .map_err(|e| e.to_string())
+            });
+
+        match result {
+            Ok(()) => qmp::Response::create_empty_response(),
+            Err(e) => {
+                error!("Failed to add block device {}: {}", node_name, e);
+                qmp::Response::create_error_response(schema::QmpErrorClass::GenericError(e), None)
+                    .unwrap()
+            }
+        }
     }
 
-    fn netdev_add(&self, id: String, if_name: Option<String>, fds: Option<String>) -> bool {
+    fn netdev_add(
+        &self,
+        id: String,
+        if_name: Option<String>,
+        fds: Option<String>,
+    ) -> std::result::Result<(), String> {
         let mut config = NetworkInterfaceConfig {
             iface_id: id.clone(),
             host_dev_name: "".to_string(),
@@ -990,54 +3127,49 @@ impl DeviceInterface for LightMachine {
             tap_fd: None,
             vhost_type: None,
             vhost_fd: None,
+            iothread: None,
         };
 
-        if let Some(fds) = fds {
-            let netdev_fd = if fds.contains(':') {
-                let col: Vec<_> = fds.split(':').collect();
-                String::from(col[col.len() - 1])
-            } else {
-                String::from(&fds)
-            };
-
+        if let Some(fds) = fds.as_ref() {
             #[cfg(feature = "qmp")]
             {
-                if let Some(fd_num) = QmpChannel::get_fd(&netdev_fd) {
-                    config.tap_fd = Some(fd_num);
-                } else {
-                    // try to convert string to RawFd
-                    let fd_num = match netdev_fd.parse::<i32>() {
-                        Ok(fd) => fd,
-                        _ => {
-                            error!(
-                                "Add netdev error: failed to convert {} to RawFd.",
-                                netdev_fd
-                            );
-                            return false;
-                        }
-                    };
-
-                    config.tap_fd = Some(fd_num);
+                let tap_fds = QmpChannel::get_fds(fds).map_err(|e| e.to_string())?;
+                if tap_fds.len() > 1 {
+                    warn!(
+                        "Multiqueue is not supported, only the first of fds {} is used",
+                        fds
+                    );
                 }
+                config.tap_fd = Some(tap_fds[0]);
             }
         } else if let Some(if_name) = if_name {
             config.host_dev_name = if_name;
         }
 
         self.bus
-            .add_replaceable_config(id, Arc::new(config))
-            .is_ok()
+            .add_replaceable_config(id.clone(), Arc::new(config))
+            .map_err(|e| e.to_string())?;
+
+        // The tap fd is closed by the device from now on.
+        #[cfg(feature = "qmp")]
+        {
+            if let Some(fd_name) = fds.as_ref().and_then(|fds| fds.split(':').next()) {
+                QmpChannel::claim_fd(fd_name, &id);
+            }
+        }
+        Ok(())
     }
 
     #[cfg(feature = "qmp")]
-    fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> qmp::Response {
-        if let Some(fd) = if_fd {
-            QmpChannel::set_fd(fd_name, fd);
-            qmp::Response::create_empty_response()
-        } else {
+    fn getfd(&self, fd_name: String, fds: Vec<RawFd>) -> qmp::Response {
+        if fds.is_empty() {
             let err_resp = schema::QmpErrorClass::GenericError("Invalid SCM message".to_string());
-            qmp::Response::create_error_response(err_resp, None).unwrap()
+            return qmp::Response::create_error_response(err_resp, None).unwrap();
         }
+
+        let names = QmpChannel::set_fds(&fd_name, fds);
+        info!("Receive fds {:?}", names);
+        qmp::Response::create_empty_response()
     }
 }
 
@@ -1051,13 +3183,7 @@ impl MainLoopManager for LightMachine {
     }
 
     fn main_loop_cleanup(&self) -> util::errors::Result<()> {
-        if let Err(e) = std::io::stdin().lock().set_canon_mode() {
-            error!(
-                "destroy virtual machine: reset stdin to canonical mode failed, {}",
-                e
-            );
-        }
-
+        util::cleanup::run_cleanup_hooks();
         Ok(())
     }
 }
@@ -1130,13 +3256,17 @@ impl CompileFDTHelper for LightMachine {
             }
         }
 
+        // The vcpus not online at boot are described as disabled, so that
+        // guest reserves them as possible cpus.
         let cpu_list = self.cpus.lock().unwrap();
         for cpu_index in 0..self.cpu_topo.max_cpus {
-            let mpidr = cpu_list[cpu_index as usize]
-                .arch()
-                .lock()
-                .unwrap()
-                .get_mpidr(cpu_list[cpu_index as usize].fd());
+            let cpu = &cpu_list[cpu_index as usize];
+            let online = self.cpu_topo.get_mask(cpu_index as usize) == 1;
+            let mpidr = if online {
+                cpu.arch().lock().unwrap().get_mpidr(cpu.fd())
+            } else {
+                cpu.arch().lock().unwrap().default_mpidr()
+            };
 
             let node = format!("/cpus/cpu@{:x}", mpidr);
             device_tree::add_sub_node(fdt, &node)?;
@@ -1152,19 +3282,16 @@ impl CompileFDTHelper for LightMachine {
                 device_tree::set_property_string(fdt, &node, "enable-method", "psci")?;
             }
             device_tree::set_property_u64(fdt, &node, "reg", mpidr & 0x007F_FFFF)?;
+            if !online {
+                device_tree::set_property_string(fdt, &node, "status", "disabled")?;
+            }
         }
 
         Ok(())
     }
 
     fn generate_memory_node(&self, fdt: &mut Vec<u8>) -> util::errors::Result<()> {
-        let mem_size = self.sys_mem.memory_end_address().raw_value() - 0x8000_0000;
-        let node = "/memory";
-        device_tree::add_sub_node(fdt, node)?;
-        device_tree::set_property_string(fdt, node, "device_type", "memory")?;
-        device_tree::set_property_array_u64(fdt, node, "reg", &[0x8000_0000, mem_size as u64])?;
-
-        Ok(())
+        set_memory_node(fdt, &self.sys_mem.memory_ranges())
     }
 
     fn generate_devices_node(&self, fdt: &mut Vec<u8>) -> util::errors::Result<()> {
@@ -1204,6 +3331,9 @@ impl CompileFDTHelper for LightMachine {
                 DeviceType::RTC => {
                     self.generate_rtc_device_node(dev_info, fdt)?;
                 }
+                DeviceType::GPIO => {
+                    self.generate_gpio_device_node(dev_info, fdt)?;
+                }
                 _ => {
                     self.generate_virtio_devices_node(dev_info, fdt)?;
                 }
@@ -1214,34 +3344,276 @@ impl CompileFDTHelper for LightMachine {
     }
 
     fn generate_chosen_node(&self, fdt: &mut Vec<u8>) -> util::errors::Result<()> {
-        let node = "/chosen";
-
         let boot_source = self.boot_source.lock().unwrap();
 
-        device_tree::add_sub_node(fdt, node)?;
-        let cmdline = &boot_source.kernel_cmdline.to_string();
-        device_tree::set_property_string(fdt, node, "bootargs", cmdline.as_str())?;
-
-        match &boot_source.initrd {
-            Some(initrd) => {
-                device_tree::set_property_u64(
-                    fdt,
-                    node,
-                    "linux,initrd-start",
-                    *initrd.initrd_addr.lock().unwrap(),
-                )?;
-                device_tree::set_property_u64(
-                    fdt,
-                    node,
-                    "linux,initrd-end",
-                    *initrd.initrd_addr.lock().unwrap() + initrd.initrd_size,
-                )?;
+        let cmdline = boot_source.kernel_cmdline.to_string();
+        let initrd = boot_source.initrd.as_ref().map(|initrd| {
+            let start = *initrd.initrd_addr.lock().unwrap();
+            (start, start + initrd.initrd_size)
+        });
+        let stdout = self
+            .bus
+            .get_devices_info()
+            .iter()
+            .find(|dev_info| dev_info.dev_type == DeviceType::SERIAL)
+            .map(|dev_info| serial_node_path(dev_info.addr));
+
+        set_chosen_node(fdt, &cmdline, initrd, stdout.as_deref())?;
+        device_tree::set_property_string(fdt, "/chosen", "stratovirt,uuid", &self.uuid)
+    }
+}
+
+/// Replace all memory nodes in fdt with one node covering `ranges`, one `reg`
+/// pair is emitted for every contiguous range of ram.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt to be modified.
+/// * `ranges` - Ram ranges registered in `sys_mem`, start from `DRAM_BASE`.
+#[cfg(target_arch = "aarch64")]
+fn set_memory_node(fdt: &mut Vec<u8>, ranges: &[AddressRange]) -> util::errors::Result<()> {
+    device_tree::delete_nodes_by_prop_string(fdt, "device_type", "memory")?;
+
+    let base = ranges
+        .first()
+        .map_or(DRAM_BASE, |range| range.base.raw_value());
+    let mut reg = Vec::new();
+    for range in ranges.iter() {
+        reg.push(range.base.raw_value());
+        reg.push(range.size);
+    }
+
+    let node = format!("/memory@{:x}", base);
+    device_tree::add_sub_node(fdt, &node)?;
+    device_tree::set_property_string(fdt, &node, "device_type", "memory")?;
+    device_tree::set_property_array_u64(fdt, &node, "reg", &reg)?;
+
+    Ok(())
+}
+
+/// Add the cfi flash node where firmware is mapped.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt to be modified.
+#[cfg(target_arch = "aarch64")]
+fn generate_flash_node(fdt: &mut Vec<u8>) -> util::errors::Result<()> {
+    let node = format!("/flash@{:x}", FLASH_BASE);
+    device_tree::add_sub_node(fdt, &node)?;
+    device_tree::set_property_string(fdt, &node, "compatible", "cfi-flash")?;
+    device_tree::set_property_array_u64(fdt, &node, "reg", &[FLASH_BASE, FLASH_SIZE])?;
+    device_tree::set_property_u32(fdt, &node, "bank-width", 4)?;
+
+    Ok(())
+}
+
+/// Get the fdt node path of serial device, which is referred by
+/// `stdout-path` of `/chosen` node.
+#[cfg(target_arch = "aarch64")]
+fn serial_node_path(addr: u64) -> String {
+    format!("/uart@{:x}", addr)
+}
+
+/// Open the destination of `dump-guest-memory`, the file descriptor named
+/// by `fd:NAME` is consumed.
+#[cfg(feature = "qmp")]
+fn open_dump_file(protocol: &str) -> Result<File> {
+    let mut parts = protocol.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("file"), Some(path)) => OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .chain_err(|| format!("Failed to open dump file {}", path)),
+        (Some("fd"), Some(fd_name)) => {
+            let fd = match QmpChannel::get_fd(fd_name) {
+                Some(fd) => fd,
+                None => bail!("File descriptor named '{}' not found", fd_name),
+            };
+            // It's safe because the duplicated fd is owned by the file only.
+            let new_fd = unsafe { libc::dup(fd) };
+            if new_fd < 0 {
+                return Err(std::io::Error::last_os_error().into());
             }
-            None => {}
+            let file = unsafe { File::from_raw_fd(new_fd) };
+            QmpChannel::close_fd(fd_name)?;
+            Ok(file)
         }
+        _ => bail!(
+            "Invalid dump protocol {}, expected file:PATH or fd:NAME",
+            protocol
+        ),
+    }
+}
 
-        Ok(())
+/// Create the snapshot file or the ram file beside it, which must not exist.
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+fn create_snapshot_file(path: &str) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .chain_err(|| format!("Failed to create snapshot file {}", path))
+}
+
+/// Remove the files of snapshot which fails to be saved.
+#[cfg(all(feature = "qmp", target_arch = "x86_64"))]
+fn remove_incomplete_snapshot(paths: &[String]) {
+    for path in paths.iter() {
+        if let Err(e) = std::fs::remove_file(path) {
+            error!("Failed to remove incomplete snapshot {}: {}", path, e);
+        }
+    }
+}
+
+/// Open the file of `-mem-path` backing guest memory of `size` bytes. It's
+/// created if it doesn't exist, and truncated so that guest memory starts
+/// as zero.
+fn open_mem_file(path: &str, size: u64) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .chain_err(|| format!("Failed to open memory backend file {}", path))?;
+    file.set_len(size)
+        .chain_err(|| format!("Failed to resize memory backend file {}", path))?;
+    Ok(file)
+}
+
+/// Convert the error of saving or restoring a device to the error of
+/// `StateRegistry`, keeping the messages of its chain.
+#[cfg(target_arch = "x86_64")]
+fn state_error<E: Into<crate::errors::Error>>(e: E) -> util::errors::Error {
+    let e: crate::errors::Error = e.into();
+    e.iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+        .into()
+}
+
+/// Get the name of MMIO device reported by query commands.
+#[cfg(any(feature = "qmp", target_arch = "x86_64"))]
+fn mmio_device_name(dev_type: DeviceType) -> &'static str {
+    match dev_type {
+        DeviceType::NET => "virtio-mmio-net",
+        DeviceType::BLK => "virtio-mmio-blk",
+        DeviceType::SERIAL => "serial",
+        #[cfg(target_arch = "aarch64")]
+        DeviceType::RTC => "pl031",
+        #[cfg(target_arch = "aarch64")]
+        DeviceType::GPIO => "pl061",
+        DeviceType::OTHER => "virtio-mmio",
+    }
+}
+
+/// Get the path of MMIO device which a replaceable device is attached to.
+#[cfg(feature = "qmp")]
+fn mmio_device_path(res: &DeviceResource) -> String {
+    format!("virtio-mmio@{:#010x}", res.addr)
+}
+
+/// Append `earlycon` to kernel cmdline if it's enabled and the user doesn't
+/// give one, guest finds the early console by `stdout-path`.
+///
+/// # Arguments
+///
+/// * `boot_source` - Boot source config of VM.
+/// * `earlycon` - Whether `earlycon=auto` is set for machine.
+#[cfg(target_arch = "aarch64")]
+fn with_earlycon(mut boot_source: BootSource, earlycon: bool) -> BootSource {
+    if earlycon {
+        boot_source.kernel_cmdline.push_vmm_param(Param {
+            param_type: String::new(),
+            value: "earlycon".to_string(),
+        });
+    }
+    boot_source
+}
+
+/// Append `stratovirt.uuid=<uuid>` to kernel cmdline if UUID is exposed to
+/// guest by cmdline.
+///
+/// # Arguments
+///
+/// * `boot_source` - Boot source config of VM.
+/// * `uuid` - UUID of VM.
+/// * `on_cmdline` - Whether `expose=cmdline` is set for uuid.
+fn with_uuid(mut boot_source: BootSource, uuid: &str, on_cmdline: bool) -> BootSource {
+    if on_cmdline {
+        boot_source.kernel_cmdline.push_vmm_param(Param {
+            param_type: "stratovirt.uuid".to_string(),
+            value: uuid.to_string(),
+        });
+    }
+    boot_source
+}
+
+/// Reserve the ranges of fdt and initrd in memory reservation block, so that
+/// guest kernel doesn't reclaim them before using them, and finish the fdt.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt to be modified.
+/// * `fdt_addr` - Address of fdt in guest memory.
+/// * `initrd` - Start address and size of initrd in guest memory.
+#[cfg(target_arch = "aarch64")]
+fn reserve_boot_ranges(
+    fdt: &mut Vec<u8>,
+    fdt_addr: u64,
+    initrd: Option<(u64, u64)>,
+) -> util::errors::Result<()> {
+    if let Some((start, size)) = initrd.filter(|(_, size)| *size > 0) {
+        device_tree::add_mem_reserve(fdt, start, size)?;
+    }
+    // The entry of fdt itself grows the packed fdt by one entry.
+    device_tree::finish_device_tree(fdt)?;
+    let fdt_size = fdt.len() as u64 + device_tree::FDT_MEM_RSV_ENTRY_SIZE;
+    device_tree::add_mem_reserve(fdt, fdt_addr, fdt_size)?;
+    device_tree::finish_device_tree(fdt)
+}
+
+/// Set `bootargs`, the initrd range and `stdout-path` of `/chosen` node, the
+/// node is created if absent and other properties in it are kept.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt to be modified.
+/// * `cmdline` - Kernel command line.
+/// * `initrd` - Start and end address of initrd in guest memory.
+/// * `stdout` - Node path of serial device, it's referred only if the node
+///   exists in fdt.
+#[cfg(target_arch = "aarch64")]
+fn set_chosen_node(
+    fdt: &mut Vec<u8>,
+    cmdline: &str,
+    initrd: Option<(u64, u64)>,
+    stdout: Option<&str>,
+) -> util::errors::Result<()> {
+    let node = "/chosen";
+
+    if !device_tree::node_exists(fdt, node) {
+        device_tree::add_sub_node(fdt, node)?;
+    }
+    device_tree::set_property_string(fdt, node, "bootargs", cmdline)?;
+
+    if let Some((start, end)) = initrd {
+        device_tree::set_property_u64(fdt, node, "linux,initrd-start", start)?;
+        device_tree::set_property_u64(fdt, node, "linux,initrd-end", end)?;
+    }
+
+    if let Some(path) = stdout.filter(|path| device_tree::node_exists(fdt, path)) {
+        let stdout_path = format!("{}:{}", path, SERIAL_BAUD_RATE);
+        device_tree::set_property_string(fdt, node, "stdout-path", &stdout_path)?;
     }
+
+    Ok(())
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -1259,7 +3631,587 @@ impl device_tree::CompileFDT for LightMachine {
         self.generate_devices_node(fdt)?;
         self.generate_chosen_node(fdt)?;
         self.irq_chip.generate_fdt_node(fdt)?;
+        if self.boot_source.lock().unwrap().bios.is_some() {
+            generate_flash_node(fdt)?;
+        }
 
         Ok(())
     }
 }
+
+#[cfg(all(test, target_arch = "aarch64"))]
+mod test {
+    use super::*;
+    use crate::interrupt_controller::{GICDevice, GICError};
+    use machine_manager::config::{KernelParams, ParamOperation};
+
+    /// GIC which only generates its fdt node, so that fdt of `LightMachine`
+    /// can be generated without kvm irqchip.
+    struct MockGic;
+
+    impl MachineLifecycle for MockGic {
+        fn notify_lifecycle(&self, _old: KvmVmState, _new: KvmVmState) -> bool {
+            true
+        }
+    }
+
+    impl GICDevice for MockGic {
+        fn create_device(
+            _vm: &Arc<VmFd>,
+            _gic_conf: &InterruptControllerConfig,
+        ) -> std::result::Result<Arc<dyn GICDevice + Send + Sync>, GICError> {
+            Ok(Arc::new(MockGic))
+        }
+
+        fn generate_fdt(&self, fdt: &mut Vec<u8>) -> util::errors::Result<()> {
+            let node = "/intc";
+            device_tree::add_sub_node(fdt, node)?;
+            device_tree::set_property_string(fdt, node, "compatible", "arm,gic-v3")?;
+            device_tree::set_property(fdt, node, "interrupt-controller", None)?;
+            device_tree::set_property_u32(fdt, node, "#interrupt-cells", 0x3)?;
+            device_tree::set_property_u32(fdt, node, "phandle", device_tree::GIC_PHANDLE)?;
+            device_tree::set_reg(
+                fdt,
+                node,
+                &[(0x0800_0000, 0x1_0000), (0x080a_0000, 0xf6_0000)],
+            )
+        }
+    }
+
+    /// Build a `LightMachine` with 128M memory and `max_cpus` vcpus, the
+    /// first `nr_cpus` of them are online and initialized.
+    fn build_light_machine(vm_fd: Arc<VmFd>, nr_cpus: u8, max_cpus: u8) -> Arc<LightMachine> {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let ram_ranges = LightMachine::arch_ram_ranges(128 << 20);
+        let mem_mappings = create_host_mmaps(
+            &ram_ranges,
+            None,
+            MemAdvice::default(),
+            DEFAULT_MAX_MAPPING_SIZE,
+        )
+        .unwrap();
+        for mmap in mem_mappings.iter() {
+            sys_mem
+                .root()
+                .add_subregion(
+                    Region::init_ram_region(mmap.clone()),
+                    mmap.start_address().raw_value(),
+                )
+                .unwrap();
+        }
+
+        let mut boot_source = BootSource::default();
+        boot_source.kernel_cmdline = KernelParams::from_str("console=ttyS0".to_string());
+        let vm = Arc::new(LightMachine {
+            vm_fd: vm_fd.clone(),
+            cpu_topo: CpuTopology {
+                sockets: max_cpus,
+                cores: 1,
+                threads: 1,
+                nrcpus: nr_cpus,
+                max_cpus,
+                online_mask: Arc::new(Mutex::new(
+                    (0..max_cpus).map(|id| u8::from(id < nr_cpus)).collect(),
+                )),
+            },
+            cpus: Arc::new(Mutex::new(Vec::new())),
+            irq_chip: Arc::new(InterruptController::from_device(Arc::new(MockGic))),
+            sys_mem: sys_mem.clone(),
+            ram_mappings: mem_mappings,
+            bus: Bus::new(sys_mem),
+            consoles: Vec::new(),
+            chardevs: Vec::new(),
+            vm_state: Arc::new((Mutex::new(KvmVmState::Created), Condvar::new())),
+            name: None,
+            uuid: "6ba7b810-9dad-11d1-80b4-00c04fd430c8".to_string(),
+            #[cfg(feature = "qmp")]
+            machine_config: Default::default(),
+            #[cfg(any(feature = "qmp", target_arch = "x86_64"))]
+            vm_config: VmConfig::default(),
+            #[cfg(feature = "qmp")]
+            kvm_caps: KvmCaps::default(),
+            boot_source: Arc::new(Mutex::new(boot_source)),
+            power_button: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            guest_shutdown: AtomicBool::new(false),
+            guest_reset: AtomicBool::new(false),
+            start_frozen: AtomicBool::new(false),
+            reboot_action: RebootAction::Shutdown,
+            boot_times: Arc::new(BootTimes::new()),
+            gpio: Arc::new(Mutex::new(PL061::new())),
+            seccomp_fragments: BTreeSet::new(),
+            #[cfg(feature = "qmp")]
+            migration: Migration::new(Box::new(|_| {})),
+            #[cfg(feature = "qmp")]
+            migration_jobs: Mutex::new(None),
+        });
+
+        let cpu_vm: Arc<Box<Arc<dyn MachineInterface + Send + Sync>>> =
+            Arc::new(Box::new(vm.clone()));
+        let boot_config = CPUBootConfig {
+            fdt_addr: 0,
+            kernel_addr: 0,
+        };
+        for vcpu_id in 0..max_cpus {
+            let fd = Arc::new(vm_fd.create_vcpu(vcpu_id).unwrap());
+            let arch_cpu = Arc::new(Mutex::new(ArchCPU::new(&vm_fd, u32::from(vcpu_id))));
+            if vcpu_id < nr_cpus {
+                arch_cpu.lock().unwrap().realize(&fd, &boot_config).unwrap();
+            }
+            let cpu =
+                CPU::new(fd, vcpu_id, arch_cpu, cpu_vm.clone(), vm.boot_times.clone()).unwrap();
+            vm.cpus.lock().unwrap().push(Arc::new(cpu));
+        }
+        vm
+    }
+
+    /// Build a dtb as provided by user, with its own chosen and memory nodes.
+    fn build_fixture_dtb() -> Vec<u8> {
+        let mut fdt = Vec::new();
+        device_tree::create_device_tree(&mut fdt).unwrap();
+        device_tree::set_property_u32(&mut fdt, "/", "#address-cells", 0x2).unwrap();
+        device_tree::set_property_u32(&mut fdt, "/", "#size-cells", 0x2).unwrap();
+        device_tree::add_sub_node(&mut fdt, "/chosen").unwrap();
+        device_tree::set_property_string(&mut fdt, "/chosen", "bootargs", "console=ttyAMA0")
+            .unwrap();
+        device_tree::set_property_string(&mut fdt, "/chosen", "stdout-path", "/uart").unwrap();
+        device_tree::add_sub_node(&mut fdt, "/memory").unwrap();
+        device_tree::set_property_string(&mut fdt, "/memory", "device_type", "memory").unwrap();
+        device_tree::set_property_array_u64(&mut fdt, "/memory", "reg", &[DRAM_BASE, 0x1000])
+            .unwrap();
+        device_tree::add_sub_node(&mut fdt, "/reserved-memory").unwrap();
+        fdt
+    }
+
+    #[test]
+    fn test_patch_user_dtb() {
+        let blob = build_fixture_dtb();
+        let mut fdt = device_tree::load_device_tree(&blob).unwrap();
+
+        let ranges = [AddressRange::from((DRAM_BASE, 0x4000_0000))];
+        set_memory_node(&mut fdt, &ranges).unwrap();
+        set_chosen_node(
+            &mut fdt,
+            "console=ttyS0 panic=1",
+            Some((0x8800_0000, 0x8810_0000)),
+            Some(&serial_node_path(0x900_0000)),
+        )
+        .unwrap();
+
+        assert_eq!(
+            device_tree::get_property(&fdt, "/chosen", "bootargs").unwrap(),
+            b"console=ttyS0 panic=1\0".to_vec()
+        );
+        assert_eq!(
+            device_tree::get_property(&fdt, "/chosen", "linux,initrd-start").unwrap(),
+            0x8800_0000_u64.to_be_bytes().to_vec()
+        );
+        assert_eq!(
+            device_tree::get_property(&fdt, "/chosen", "linux,initrd-end").unwrap(),
+            0x8810_0000_u64.to_be_bytes().to_vec()
+        );
+        // properties not generated by StratoVirt are kept
+        assert_eq!(
+            device_tree::get_property(&fdt, "/chosen", "stdout-path").unwrap(),
+            b"/uart\0".to_vec()
+        );
+        assert!(device_tree::node_exists(&fdt, "/reserved-memory"));
+
+        // memory node of user is replaced
+        assert!(!device_tree::node_exists(&fdt, "/memory"));
+        let mut reg = DRAM_BASE.to_be_bytes().to_vec();
+        reg.extend_from_slice(&0x4000_0000_u64.to_be_bytes());
+        assert_eq!(
+            device_tree::get_property(&fdt, "/memory@80000000", "reg").unwrap(),
+            reg
+        );
+
+        // invalid magic and truncated blob are rejected
+        let mut bad_magic = blob.clone();
+        bad_magic[0] = 0;
+        assert!(device_tree::load_device_tree(&bad_magic).is_err());
+        assert!(device_tree::load_device_tree(&blob[..0x40]).is_err());
+    }
+
+    #[test]
+    fn test_flash_node() {
+        let mut fdt = build_fixture_dtb();
+        generate_flash_node(&mut fdt).unwrap();
+
+        assert_eq!(
+            device_tree::get_property(&fdt, "/flash@0", "compatible").unwrap(),
+            b"cfi-flash\0".to_vec()
+        );
+        let mut reg = FLASH_BASE.to_be_bytes().to_vec();
+        reg.extend_from_slice(&FLASH_SIZE.to_be_bytes());
+        assert_eq!(
+            device_tree::get_property(&fdt, "/flash@0", "reg").unwrap(),
+            reg
+        );
+        assert_eq!(
+            device_tree::get_property(&fdt, "/flash@0", "bank-width").unwrap(),
+            4_u32.to_be_bytes().to_vec()
+        );
+    }
+
+    #[test]
+    fn test_chosen_stdout_path() {
+        let mut fdt = Vec::new();
+        device_tree::create_device_tree(&mut fdt).unwrap();
+        let node = serial_node_path(0x900_0000);
+        device_tree::add_sub_node(&mut fdt, &node).unwrap();
+        set_chosen_node(&mut fdt, "console=ttyS0", None, Some(&node)).unwrap();
+
+        let stdout = device_tree::get_property(&fdt, "/chosen", "stdout-path").unwrap();
+        assert_eq!(stdout, b"/uart@9000000:115200\0".to_vec());
+        // `stdout-path` refers to the serial node without the options
+        let stdout = String::from_utf8(stdout).unwrap();
+        let path = stdout.trim_end_matches('\0').split(':').next().unwrap();
+        assert!(device_tree::node_exists(&fdt, path));
+
+        // absent serial node isn't referred
+        let mut fdt = Vec::new();
+        device_tree::create_device_tree(&mut fdt).unwrap();
+        set_chosen_node(&mut fdt, "console=ttyS0", None, Some(&node)).unwrap();
+        assert!(device_tree::get_property(&fdt, "/chosen", "stdout-path").is_none());
+    }
+
+    #[test]
+    fn test_reserve_boot_ranges() {
+        let mut fdt = build_fixture_dtb();
+        let fdt_addr = 0x8fe0_0000;
+        reserve_boot_ranges(&mut fdt, fdt_addr, Some((0x8800_0000, 0x10_0000))).unwrap();
+
+        let mut off = [0_u8; 4];
+        off.copy_from_slice(&fdt[16..20]);
+        let off = u32::from_be_bytes(off) as usize;
+        let mut expected = Vec::new();
+        for val in [0x8800_0000, 0x10_0000, fdt_addr, fdt.len() as u64, 0, 0].iter() {
+            expected.extend_from_slice(&val.to_be_bytes());
+        }
+        // the reserved size of fdt is exactly the finished fdt
+        assert_eq!(&fdt[off..off + expected.len()], expected.as_slice());
+
+        // no initrd
+        let mut fdt = build_fixture_dtb();
+        reserve_boot_ranges(&mut fdt, fdt_addr, None).unwrap();
+        let mut expected = Vec::new();
+        for val in [fdt_addr, fdt.len() as u64, 0, 0].iter() {
+            expected.extend_from_slice(&val.to_be_bytes());
+        }
+        assert_eq!(&fdt[off..off + expected.len()], expected.as_slice());
+    }
+
+    #[test]
+    fn test_pmem_base() {
+        // Persistent memory follows RAM, aligned up to 2M.
+        let ram_end = DRAM_BASE + (1 << 30);
+        assert_eq!(pmem_base(ram_end).unwrap(), ram_end);
+        assert_eq!(
+            pmem_base(DRAM_BASE + 0x10_1000).unwrap(),
+            DRAM_BASE + PMEM_ALIGN
+        );
+        // It's never placed below DRAM, where MMIO devices are.
+        assert_eq!(pmem_base(0).unwrap(), DRAM_BASE);
+        assert_eq!(
+            pmem_base(u64::max_value() - 1).unwrap_err().to_string(),
+            format!(
+                "No space for pmem above RAM end {:#x}",
+                u64::max_value() - 1
+            )
+        );
+    }
+
+    #[test]
+    fn test_light_machine_fdt() {
+        let vm_fd = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => Arc::new(vm_fd),
+            Err(_) => return,
+        };
+        let vm = build_light_machine(vm_fd, 2, 2);
+        let mut fdt = Vec::new();
+        vm.generate_fdt_node(&mut fdt).unwrap();
+        device_tree::finish_device_tree(&mut fdt).unwrap();
+        let tree = device_tree::Fdt::parse(&fdt).unwrap();
+
+        // memory
+        let memory = tree.node(&format!("/memory@{:x}", DRAM_BASE)).unwrap();
+        assert_eq!(memory.property_str("device_type"), Some("memory"));
+        let mut reg = DRAM_BASE.to_be_bytes().to_vec();
+        reg.extend_from_slice(&(128_u64 << 20).to_be_bytes());
+        assert_eq!(memory.property("reg"), Some(reg.as_slice()));
+
+        // cpus
+        let cpus: Vec<&device_tree::FdtNode> = tree
+            .node("/cpus")
+            .unwrap()
+            .children
+            .iter()
+            .filter(|node| node.name.starts_with("cpu@"))
+            .collect();
+        assert_eq!(cpus.len(), 2);
+        for cpu in cpus {
+            assert_eq!(cpu.property_str("device_type"), Some("cpu"));
+            assert_eq!(cpu.property_str("enable-method"), Some("psci"));
+        }
+
+        // interrupt parent refers to GIC
+        let intc = tree.node("/intc").unwrap();
+        assert_eq!(intc.property_u32("phandle"), Some(device_tree::GIC_PHANDLE));
+        assert_eq!(
+            tree.root.property_u32("interrupt-parent"),
+            Some(device_tree::GIC_PHANDLE)
+        );
+
+        // virtio devices with edge-triggered SPI
+        let devices = vm.bus.get_devices_info();
+        assert!(!devices.is_empty());
+        for dev_info in devices.iter() {
+            let node = tree
+                .node(&format!("/virtio_mmio@{:x}", dev_info.addr))
+                .unwrap();
+            assert_eq!(
+                node.property_u32("interrupt-parent"),
+                Some(device_tree::GIC_PHANDLE)
+            );
+            assert_eq!(
+                node.property_cells("interrupts"),
+                Some(vec![
+                    device_tree::GIC_FDT_IRQ_TYPE_SPI,
+                    dev_info.irq,
+                    device_tree::IRQ_TYPE_EDGE_RISING
+                ])
+            );
+        }
+
+        assert_eq!(
+            tree.node("/chosen").unwrap().property_str("bootargs"),
+            Some("console=ttyS0")
+        );
+    }
+
+    #[test]
+    fn test_earlycon() {
+        let mut boot_source = BootSource::default();
+        boot_source.kernel_cmdline = KernelParams::from_str("console=ttyS0".to_string());
+        let cmdline = with_earlycon(boot_source.clone(), false).kernel_cmdline;
+        assert_eq!(cmdline.to_string(), "console=ttyS0");
+        let cmdline = with_earlycon(boot_source, true).kernel_cmdline;
+        assert_eq!(cmdline.to_string(), "console=ttyS0 earlycon");
+
+        // earlycon given by user is kept
+        for given in ["earlycon", "earlycon=pl011,0x9000000"].iter() {
+            let mut boot_source = BootSource::default();
+            boot_source.kernel_cmdline = KernelParams::from_str(given.to_string());
+            let cmdline = with_earlycon(boot_source, true).kernel_cmdline;
+            assert_eq!(cmdline.to_string(), *given);
+        }
+    }
+
+    #[test]
+    fn test_qom_tree() {
+        let vm_fd = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => Arc::new(vm_fd),
+            Err(_) => return,
+        };
+        let vm = build_light_machine(vm_fd, 2, 2);
+        let drive = DriveConfig {
+            drive_id: "drive-0".to_string(),
+            path_on_host: "/path/to/rootfs".to_string(),
+            ..Default::default()
+        };
+        vm.bus
+            .add_replaceable_config("drive-0".to_string(), Arc::new(drive))
+            .unwrap();
+        vm.bus
+            .add_replaceable_device("drive-0", "virtio-blk-mmio", 0)
+            .unwrap();
+
+        // 2 vcpus are followed by the replaceable devices of bus.
+        let props = qom::qom_list(&vm, "/machine/unattached/").unwrap();
+        assert_eq!(props[0].name, "type");
+        assert_eq!(props[1].name, "device[0]");
+        assert_eq!(props[1].prop_type, "child<host-arm-cpu>");
+        assert_eq!(props[3].name, "device[2]");
+        assert_eq!(props[3].prop_type, "child<virtio-mmio-blk>");
+        assert_eq!(
+            qom::qom_get(&vm, "/machine/unattached", "device[2]").unwrap(),
+            "/machine/unattached/device[2]"
+        );
+
+        let cpu = "/machine/unattached/device[1]";
+        assert_eq!(qom::qom_get(&vm, cpu, "type").unwrap(), "host-arm-cpu");
+        assert_eq!(qom::qom_get(&vm, cpu, "halted").unwrap(), false);
+        let block = "/machine/unattached/device[2]";
+        assert_eq!(qom::qom_get(&vm, block, "drive").unwrap(), "drive-0");
+        assert_eq!(qom::qom_get(&vm, block, "file").unwrap(), "/path/to/rootfs");
+
+        // Unknown path and property.
+        assert!(matches!(
+            qom::qom_list(&vm, "/machine/unattached/device[99]"),
+            Err(schema::QmpErrorClass::DeviceNotFound(_))
+        ));
+        assert!(matches!(
+            qom::qom_get(&vm, cpu, "file"),
+            Err(schema::QmpErrorClass::GenericError(_))
+        ));
+    }
+
+    #[test]
+    fn test_possible_cpus() {
+        let vm_fd = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => Arc::new(vm_fd),
+            Err(_) => return,
+        };
+        let vm = build_light_machine(vm_fd, 2, 8);
+        assert_eq!(vm.cpus.lock().unwrap().len(), 8);
+        let online: Vec<u8> = vm.online_cpus().iter().map(|cpu| cpu.id()).collect();
+        assert_eq!(online, vec![0, 1]);
+
+        // All possible vcpus are in fdt, the offline ones are disabled.
+        let mut fdt = Vec::new();
+        vm.generate_fdt_node(&mut fdt).unwrap();
+        device_tree::finish_device_tree(&mut fdt).unwrap();
+        let tree = device_tree::Fdt::parse(&fdt).unwrap();
+        for (index, cpu) in vm.cpus.lock().unwrap().iter().enumerate() {
+            let mpidr = cpu.arch().lock().unwrap().default_mpidr();
+            if index < 2 {
+                assert_eq!(cpu.arch().lock().unwrap().get_mpidr(cpu.fd()), mpidr);
+            }
+            let node = tree.node(&format!("/cpus/cpu@{:x}", mpidr)).unwrap();
+            assert_eq!(node.property_str("enable-method"), Some("psci"));
+            let status = if index < 2 { None } else { Some("disabled") };
+            assert_eq!(node.property_str("status"), status);
+        }
+
+        // The absent vcpus have no qom path, and the numbers are taken.
+        let cpus = serde_json::to_value(vm.query_hotpluggable_cpus()).unwrap();
+        let cpus = cpus["return"].as_array().unwrap();
+        assert_eq!(cpus.len(), 8);
+        for (index, cpu) in cpus.iter().enumerate() {
+            assert_eq!(cpu["props"]["socket-id"], index);
+            assert_eq!(cpu["qom-path"].is_string(), index < 2);
+        }
+        let props = qom::qom_list(&vm, "/machine/unattached/").unwrap();
+        assert_eq!(props[2].name, "device[1]");
+        assert_eq!(props[3].name, "device[8]");
+        assert!(qom::qom_list(&vm, "/machine/unattached/device[2]").is_err());
+    }
+
+    #[test]
+    fn test_guest_shutdown() {
+        let vm_fd = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => Arc::new(vm_fd),
+            Err(_) => return,
+        };
+        let vm = build_light_machine(vm_fd, 0, 0);
+        *vm.vm_state.0.lock().unwrap() = KvmVmState::Running;
+
+        // Every vcpu reports shutdown, only the first wakes main loop up.
+        assert!(vm.request_guest_shutdown());
+        assert!(!vm.request_guest_shutdown());
+        assert_eq!(vm.power_button.read().unwrap(), 1);
+        assert!(!vm.main_loop_should_exit());
+
+        // Handler shuts down VM, and main loop exits after it returns.
+        assert!(vm.handle_power_button());
+        assert!(vm.main_loop_should_exit());
+        assert_eq!(vm.power_button.read().unwrap(), 1);
+
+        // Woken up again, no more SHUTDOWN event is emitted.
+        assert!(!vm.handle_power_button());
+        assert!(vm.main_loop_should_exit());
+        assert_eq!(vm.guest_shutdown_reason(), "guest-shutdown");
+    }
+
+    #[test]
+    fn test_guest_reset() {
+        let vm_fd = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => Arc::new(vm_fd),
+            Err(_) => return,
+        };
+        let vm = build_light_machine(vm_fd, 0, 0);
+        *vm.vm_state.0.lock().unwrap() = KvmVmState::Running;
+
+        // Reboot is taken as shutdown, and the later shutdown request
+        // doesn't change the reason.
+        assert!(vm.request_guest_reset());
+        assert!(!vm.request_guest_shutdown());
+        assert!(!vm.request_guest_reset());
+        assert_eq!(vm.power_button.read().unwrap(), 1);
+        assert_eq!(vm.guest_shutdown_reason(), "guest-reset");
+        assert!(vm.handle_power_button());
+        assert!(vm.main_loop_should_exit());
+
+        // VM can't be reset yet, the reset action falls back to shutdown.
+        assert!(!vm.reset());
+        assert!(reboot_dispatch(RebootAction::Reset, || vm.reset()));
+    }
+
+    #[test]
+    fn test_start_frozen() {
+        let vm_fd = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => Arc::new(vm_fd),
+            Err(_) => return,
+        };
+        let vm = build_light_machine(vm_fd, 0, 0);
+
+        // Vcpus aren't resumed before they're started.
+        assert!(!vm.resume());
+
+        // VM frozen by `-S` stays in `Created` state, `stop` does nothing.
+        vm.vm_start(true, &vm.seccomp_policy(SeccompMode::Off))
+            .unwrap();
+        let state = *vm.vm_state.0.lock().unwrap();
+        assert_eq!(state.name(), "prelaunch");
+        assert_eq!(
+            check_transition("stop", state, KvmVmState::Paused),
+            Ok(false)
+        );
+
+        // `cont` starts it.
+        assert_eq!(
+            check_transition("cont", state, KvmVmState::Running),
+            Ok(true)
+        );
+        assert!(vm.resume());
+        assert_eq!(vm.vm_state.0.lock().unwrap().name(), "running");
+        assert!(vm.pause());
+        assert_eq!(vm.vm_state.0.lock().unwrap().name(), "paused");
+        assert!(vm.resume());
+        assert_eq!(vm.vm_state.0.lock().unwrap().name(), "running");
+    }
+
+    #[cfg(feature = "qmp")]
+    #[test]
+    fn test_stop_cont() {
+        let vm_fd = match Kvm::new().and_then(|kvm| kvm.create_vm()) {
+            Ok(vm_fd) => Arc::new(vm_fd),
+            Err(_) => return,
+        };
+        let vm = build_light_machine(vm_fd, 0, 0);
+        vm.vm_start(false, &vm.seccomp_policy(SeccompMode::Off))
+            .unwrap();
+
+        // Transformation to the state VM is in already is a no-op.
+        assert_eq!(vm.cont(), Ok(()));
+        assert_eq!(vm.stop(), Ok(()));
+        assert_eq!(vm.vm_state.0.lock().unwrap().name(), "paused");
+        assert_eq!(vm.stop(), Ok(()));
+        assert_eq!(vm.cont(), Ok(()));
+        assert_eq!(vm.vm_state.0.lock().unwrap().name(), "running");
+
+        // Illegal transformation is reported by `stop` and `cont` as
+        // `check_transition` does.
+        *vm.vm_state.0.lock().unwrap() = KvmVmState::Shutdown;
+        assert_eq!(
+            vm.stop().unwrap_err(),
+            check_transition("stop", KvmVmState::Shutdown, KvmVmState::Paused).unwrap_err()
+        );
+        assert_eq!(
+            vm.cont().unwrap_err(),
+            check_transition("cont", KvmVmState::Shutdown, KvmVmState::Running).unwrap_err()
+        );
+        assert!(!vm.pause());
+        assert!(!vm.resume());
+    }
+}
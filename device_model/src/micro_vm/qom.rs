@@ -0,0 +1,210 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Minimal QOM tree of micro VM for `qom-list` and `qom-get`.
+//!
+//! The tree is derived from vcpus and MMIO bus on every query:
+//!
+//! ```text
+//! /
+//! └── machine
+//!     └── unattached
+//!         ├── device[0]           vcpu 0
+//!         ├── ...
+//!         ├── device[max_cpus]    the first device of MMIO bus
+//!         └── ...
+//! ```
+//!
+//! vcpus are numbered the same as `qom_path` of `query-cpus`, only the online
+//! ones are in the tree, while the numbers of all possible ones are taken.
+
+use machine_manager::config::{DriveConfig, NetworkInterfaceConfig};
+use machine_manager::qmp::qmp_schema as schema;
+use serde_json::Value;
+
+use super::{mmio_device_name, LightMachine};
+
+#[cfg(target_arch = "x86_64")]
+const CPU_TYPE: &str = "host-x86_64-cpu";
+#[cfg(target_arch = "aarch64")]
+const CPU_TYPE: &str = "host-arm-cpu";
+
+/// Object in QOM tree.
+enum QomObject {
+    Root,
+    Machine,
+    Unattached,
+    /// vcpu with its index.
+    Cpu(usize),
+    /// Device with its index in MMIO bus.
+    Device(usize),
+}
+
+/// Property of object, the value of `child<>` property is the path of child.
+struct QomProperty {
+    name: String,
+    prop_type: String,
+    value: Value,
+}
+
+impl QomProperty {
+    fn new<T: Into<Value>>(name: &str, prop_type: &str, value: T) -> Self {
+        QomProperty {
+            name: name.to_string(),
+            prop_type: prop_type.to_string(),
+            value: value.into(),
+        }
+    }
+
+    fn child(name: String, child_type: &str, path: &str) -> Self {
+        QomProperty {
+            value: format!("{}/{}", path, name).into(),
+            name,
+            prop_type: format!("child<{}>", child_type),
+        }
+    }
+}
+
+fn resolve(vm: &LightMachine, path: &str) -> Option<QomObject> {
+    let max_cpus = usize::from(vm.cpu_topo.max_cpus);
+    let nr_devices = vm.bus.get_devices_info().len();
+
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    match parts.as_slice() {
+        [] => Some(QomObject::Root),
+        ["machine"] => Some(QomObject::Machine),
+        ["machine", "unattached"] => Some(QomObject::Unattached),
+        ["machine", "unattached", name] => {
+            if !name.starts_with("device[") || !name.ends_with(']') {
+                return None;
+            }
+            let index = name["device[".len()..name.len() - 1]
+                .parse::<usize>()
+                .ok()?;
+            if index < max_cpus {
+                if vm.cpu_topo.get_mask(index) == 1 {
+                    Some(QomObject::Cpu(index))
+                } else {
+                    None
+                }
+            } else if index < max_cpus + nr_devices {
+                Some(QomObject::Device(index - max_cpus))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn properties(vm: &LightMachine, object: &QomObject) -> Vec<QomProperty> {
+    match object {
+        QomObject::Root => vec![
+            QomProperty::new("type", "string", "container"),
+            QomProperty::child("machine".to_string(), "microvm-machine", ""),
+        ],
+        QomObject::Machine => vec![
+            QomProperty::new("type", "string", "microvm-machine"),
+            QomProperty::child("unattached".to_string(), "container", "/machine"),
+        ],
+        QomObject::Unattached => {
+            let max_cpus = usize::from(vm.cpu_topo.max_cpus);
+            let mut props = vec![QomProperty::new("type", "string", "container")];
+            for index in (0..max_cpus).filter(|index| vm.cpu_topo.get_mask(*index) == 1) {
+                let name = format!("device[{}]", index);
+                props.push(QomProperty::child(name, CPU_TYPE, "/machine/unattached"));
+            }
+            for (index, res) in vm.bus.get_devices_info().iter().enumerate() {
+                let name = format!("device[{}]", max_cpus + index);
+                let dev_type = mmio_device_name(res.dev_type);
+                props.push(QomProperty::child(name, dev_type, "/machine/unattached"));
+            }
+            props
+        }
+        QomObject::Cpu(index) => {
+            let thread_id = vm.cpus.lock().unwrap()[*index].tid();
+            vec![
+                QomProperty::new("type", "string", CPU_TYPE),
+                QomProperty::new("realized", "bool", true),
+                QomProperty::new("thread-id", "int", thread_id),
+                // Same as `halted` of `query-cpus`.
+                QomProperty::new("halted", "bool", false),
+            ]
+        }
+        QomObject::Device(index) => {
+            let res = vm.bus.get_devices_info()[*index];
+            let mut props = vec![
+                QomProperty::new("type", "string", mmio_device_name(res.dev_type)),
+                QomProperty::new("realized", "bool", true),
+            ];
+            let attached = vm
+                .bus
+                .get_replaceable_info()
+                .into_iter()
+                .find(|info| info.resource.map(|r| r.addr) == Some(res.addr));
+            if let Some(info) = attached {
+                let config = info.dev_config.as_any();
+                if let Some(drive) = config.downcast_ref::<DriveConfig>() {
+                    props.push(QomProperty::new("drive", "str", info.id));
+                    props.push(QomProperty::new("file", "str", drive.path_on_host.clone()));
+                } else if config.is::<NetworkInterfaceConfig>() {
+                    props.push(QomProperty::new("netdev", "str", info.id));
+                }
+            }
+            props
+        }
+    }
+}
+
+fn device_not_found(path: &str) -> schema::QmpErrorClass {
+    schema::QmpErrorClass::DeviceNotFound(format!("Device '{}' not found", path))
+}
+
+/// List the properties of object at `path`, including its children.
+///
+/// # Errors
+///
+/// Return `DeviceNotFound` if there is no object at `path`.
+pub fn qom_list(
+    vm: &LightMachine,
+    path: &str,
+) -> std::result::Result<Vec<schema::ObjectPropertyInfo>, schema::QmpErrorClass> {
+    let object = resolve(vm, path).ok_or_else(|| device_not_found(path))?;
+    Ok(properties(vm, &object)
+        .into_iter()
+        .map(|prop| schema::ObjectPropertyInfo {
+            name: prop.name,
+            prop_type: prop.prop_type,
+        })
+        .collect())
+}
+
+/// Get the value of `property` of object at `path`.
+///
+/// # Errors
+///
+/// Return `DeviceNotFound` if there is no object at `path`, and
+/// `GenericError` if the object has no such property.
+pub fn qom_get(
+    vm: &LightMachine,
+    path: &str,
+    property: &str,
+) -> std::result::Result<Value, schema::QmpErrorClass> {
+    let object = resolve(vm, path).ok_or_else(|| device_not_found(path))?;
+    properties(vm, &object)
+        .into_iter()
+        .find(|prop| prop.name == property)
+        .map(|prop| prop.value)
+        .ok_or_else(|| {
+            schema::QmpErrorClass::GenericError(format!("Property '{}' not found", property))
+        })
+}
@@ -0,0 +1,1281 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Snapshot of VM state, which a VM can be restored from.
+//!
+//! The file is laid out as:
+//! 1. Snapshot header, holding the offset of section table.
+//! 2. Sections, each of which is an entry of section table followed by its
+//!    data, so that the section cut by a truncated file can be found.
+//! 3. Section table, listing the name, version, flags, position and CRC32
+//!    of sections.
+//!
+//! A snapshot of micro VM has the sections in order:
+//! * `config` - `VmConfig` in json.
+//! * `bus` - Type, address and irq of MMIO devices in json.
+//! * `mmio<N>` - State of the Nth MMIO device on bus.
+//! * `acpi_pm` - ACPI power management registers.
+//! * `kvm` - Interrupt controller, PIT and clock of KVM.
+//! * `cpu<N>` - State of the Nth vcpu.
+//! * `ram` - Non-zero pages of guest memory, see `write_ram`.
+//!
+//! If guest memory is backed by a file, the file is cloned beside the
+//! snapshot, and section `ram-file` describing it is saved instead of `ram`,
+//! see `RamFile`.
+//!
+//! The sections of `config`, `bus`, `ram` and `ram-file` are read by name, and the rest
+//! are the state of devices loaded by `StateRegistry`, so that a section
+//! unknown to the loader is rejected unless it's optional.
+//!
+//! The state of VM sent by live migration is a snapshot with the sections
+//! from `bus` to `cpu<N>`.
+//!
+//! All the integers are little endian.
+
+use std::cmp::min;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{kvm_clock_data, kvm_irqchip, kvm_pit_state2};
+#[cfg(target_arch = "x86_64")]
+use kvm_ioctls::VmFd;
+use serde::{Deserialize, Serialize};
+use util::byte_code::ByteCode;
+use util::checksum::{crc32, Crc32};
+use util::state::SavedState;
+use vmm_sys_util::ioctl::ioctl_with_val;
+
+use crate::errors::{Result, ResultExt};
+
+const SNAPSHOT_MAGIC: [u8; 8] = *b"SVSNAP\0\0";
+/// Version of snapshot container, sections have their own versions.
+pub const SNAPSHOT_VERSION: u32 = 1;
+#[cfg(target_arch = "x86_64")]
+const SNAPSHOT_ARCH: u32 = 1;
+#[cfg(target_arch = "aarch64")]
+const SNAPSHOT_ARCH: u32 = 2;
+/// Max length of section name.
+const SECTION_NAME_SIZE: usize = 16;
+/// Flag of section which can be skipped by the loader who doesn't know it.
+const SECTION_F_OPTIONAL: u16 = 1;
+/// Size of page whose content is checked to be zero.
+const RAM_PAGE_SIZE: u64 = 4096;
+/// Guest memory is read in chunks of 1 MiB.
+const RAM_CHUNK_SIZE: u64 = 1 << 20;
+/// Size of the address and length preceding the data of a ram record.
+const RAM_RECORD_HEADER_SIZE: u64 = 16;
+
+// Clone a file by sharing its extents, see ioctl_ficlone(2).
+ioctl_iow_nr!(FICLONE, 0x94, 9, libc::c_int);
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SnapshotHeader {
+    magic: [u8; 8],
+    version: u32,
+    arch: u32,
+    nr_sections: u32,
+    /// CRC32 of section table.
+    table_crc: u32,
+    table_offset: u64,
+    /// CRC32 of header, computed with this field set to 0.
+    header_crc: u32,
+    reserved: u32,
+}
+
+impl ByteCode for SnapshotHeader {}
+
+impl SnapshotHeader {
+    fn crc(&self) -> u32 {
+        let mut header = *self;
+        header.header_crc = 0;
+        crc32(header.as_bytes())
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct SectionEntry {
+    /// Name of section, padded with zero.
+    name: [u8; SECTION_NAME_SIZE],
+    /// Offset of the data of section in file.
+    offset: u64,
+    size: u64,
+    version: u16,
+    /// Bits of `SECTION_F_*`, which are zero in the snapshots whose version
+    /// of section takes 32 bits.
+    flags: u16,
+    /// CRC32 of the data of section.
+    crc: u32,
+}
+
+impl ByteCode for SectionEntry {}
+
+impl SectionEntry {
+    fn name(&self) -> String {
+        let len = self
+            .name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(SECTION_NAME_SIZE);
+        String::from_utf8_lossy(&self.name[..len]).to_string()
+    }
+}
+
+/// Section being written.
+struct OpenSection {
+    entry: SectionEntry,
+    /// Offset of the entry preceding the data.
+    entry_offset: u64,
+    crc: Crc32,
+}
+
+/// Writer of snapshot, the header is written when it's finished.
+pub struct SnapshotWriter<W: Write + Seek> {
+    dst: W,
+    sections: Vec<SectionEntry>,
+    current: Option<OpenSection>,
+}
+
+impl<W: Write + Seek> SnapshotWriter<W> {
+    /// Create a snapshot on `dst`, which is at the start of an empty file.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to write `dst`.
+    pub fn new(mut dst: W) -> Result<Self> {
+        // The header is zero until snapshot is finished, so that an
+        // incomplete snapshot is never taken as a valid one.
+        dst.write_all(SnapshotHeader::default().as_bytes())?;
+        Ok(SnapshotWriter {
+            dst,
+            sections: Vec::new(),
+            current: None,
+        })
+    }
+
+    /// Begin a section, whose data is given by `write` until `end_section`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of section, no longer than 16 bytes.
+    /// * `version` - Version of the state in section.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the name is invalid or used, or another section is
+    /// not ended.
+    pub fn begin_section(&mut self, name: &str, version: u16) -> Result<()> {
+        self.begin_section_with_flags(name, version, 0)
+    }
+
+    fn begin_section_with_flags(&mut self, name: &str, version: u16, flags: u16) -> Result<()> {
+        if let Some(section) = self.current.as_ref() {
+            bail!("Section '{}' is not ended", section.entry.name());
+        }
+        if name.is_empty() || name.len() > SECTION_NAME_SIZE || name.contains('\0') {
+            bail!("Invalid section name '{}'", name);
+        }
+        if self.sections.iter().any(|entry| entry.name() == name) {
+            bail!("Section '{}' is written twice", name);
+        }
+
+        let entry_offset = self.dst.seek(SeekFrom::End(0))?;
+        let mut entry = SectionEntry {
+            offset: entry_offset + size_of::<SectionEntry>() as u64,
+            version,
+            flags,
+            ..Default::default()
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.dst.write_all(entry.as_bytes())?;
+        self.current = Some(OpenSection {
+            entry,
+            entry_offset,
+            crc: Crc32::new(),
+        });
+        Ok(())
+    }
+
+    /// Write data of the current section.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if no section is begun, or fail to write `dst`.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let section = match self.current.as_mut() {
+            Some(section) => section,
+            None => bail!("No section is begun"),
+        };
+        self.dst
+            .write_all(data)
+            .chain_err(|| format!("Failed to write section '{}'", section.entry.name()))?;
+        section.entry.size += data.len() as u64;
+        section.crc.update(data);
+        Ok(())
+    }
+
+    /// End the current section, and fill its entry preceding the data.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if no section is begun, or fail to write `dst`.
+    pub fn end_section(&mut self) -> Result<()> {
+        let mut section = match self.current.take() {
+            Some(section) => section,
+            None => bail!("No section is begun"),
+        };
+        section.entry.crc = section.crc.value();
+        self.dst.seek(SeekFrom::Start(section.entry_offset))?;
+        self.dst.write_all(section.entry.as_bytes())?;
+        self.dst.seek(SeekFrom::End(0))?;
+        self.sections.push(section.entry);
+        Ok(())
+    }
+
+    /// Write a section with all of its data.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the name is invalid or used, or fail to write `dst`.
+    pub fn add_section(&mut self, name: &str, version: u16, data: &[u8]) -> Result<()> {
+        self.begin_section(name, version)?;
+        self.write(data)?;
+        self.end_section()
+    }
+
+    /// Write a section with the state saved by `StateRegistry`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the name is invalid or used, or fail to write `dst`.
+    pub fn add_state(&mut self, state: &SavedState) -> Result<()> {
+        let flags = if state.optional {
+            SECTION_F_OPTIONAL
+        } else {
+            0
+        };
+        self.begin_section_with_flags(&state.name, state.version, flags)?;
+        self.write(&state.data)?;
+        self.end_section()
+    }
+
+    /// Write section table and header, and return the destination.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if a section is not ended, or fail to write `dst`.
+    pub fn finish(mut self) -> Result<W> {
+        if let Some(section) = self.current.as_ref() {
+            bail!("Section '{}' is not ended", section.entry.name());
+        }
+
+        let table: Vec<u8> = self
+            .sections
+            .iter()
+            .flat_map(|entry| entry.as_bytes().to_vec())
+            .collect();
+        let table_offset = self.dst.seek(SeekFrom::End(0))?;
+        self.dst.write_all(&table)?;
+
+        let mut header = SnapshotHeader {
+            magic: SNAPSHOT_MAGIC,
+            version: SNAPSHOT_VERSION,
+            arch: SNAPSHOT_ARCH,
+            nr_sections: self.sections.len() as u32,
+            table_crc: crc32(&table),
+            table_offset,
+            ..Default::default()
+        };
+        header.header_crc = header.crc();
+        self.dst.seek(SeekFrom::Start(0))?;
+        self.dst.write_all(header.as_bytes())?;
+        self.dst.flush()?;
+        Ok(self.dst)
+    }
+}
+
+/// Reader of the data of a section, which computes CRC32 of what is read.
+struct SectionData<'a, R: Read> {
+    src: std::io::Take<&'a mut R>,
+    crc: Crc32,
+}
+
+impl<'a, R: Read> Read for SectionData<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.src.read(buf)?;
+        self.crc.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Reader of snapshot, whose header and section table are checked when
+/// it's created.
+pub struct SnapshotReader<R: Read + Seek> {
+    src: R,
+    sections: Vec<SectionEntry>,
+}
+
+impl<R: Read + Seek> SnapshotReader<R> {
+    /// Open the snapshot in `src`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if it's not a snapshot of this version and architecture,
+    /// or its header or section table is corrupted or truncated.
+    pub fn new(mut src: R) -> Result<Self> {
+        let file_size = src.seek(SeekFrom::End(0))?;
+        let mut header = SnapshotHeader::default();
+        src.seek(SeekFrom::Start(0))?;
+        src.read_exact(header.as_mut_bytes())
+            .chain_err(|| "Snapshot header is truncated")?;
+        if header.magic != SNAPSHOT_MAGIC {
+            bail!("Not a snapshot file, or the snapshot is incomplete");
+        }
+        if header.header_crc != header.crc() {
+            bail!("Checksum of snapshot header mismatches, the snapshot is corrupted");
+        }
+        if header.version != SNAPSHOT_VERSION {
+            bail!(
+                "Snapshot version {} is not supported, expected {}",
+                header.version,
+                SNAPSHOT_VERSION
+            );
+        }
+        if header.arch != SNAPSHOT_ARCH {
+            bail!(
+                "Snapshot is taken on another architecture (id {}), expected {}",
+                header.arch,
+                SNAPSHOT_ARCH
+            );
+        }
+
+        let table_size = u64::from(header.nr_sections) * size_of::<SectionEntry>() as u64;
+        if header.table_offset.saturating_add(table_size) > file_size {
+            return Err(Self::find_truncated(&mut src, file_size));
+        }
+        let mut table = vec![0_u8; table_size as usize];
+        src.seek(SeekFrom::Start(header.table_offset))?;
+        src.read_exact(&mut table)?;
+        if crc32(&table) != header.table_crc {
+            bail!("Checksum of section table mismatches, the snapshot is corrupted");
+        }
+
+        let mut sections = Vec::new();
+        for data in table.chunks(size_of::<SectionEntry>()) {
+            let mut entry = SectionEntry::default();
+            entry.as_mut_bytes().copy_from_slice(data);
+            if entry.offset.saturating_add(entry.size) > header.table_offset {
+                bail!("Section '{}' is out of snapshot", entry.name());
+            }
+            sections.push(entry);
+        }
+        Ok(SnapshotReader { src, sections })
+    }
+
+    /// Walk the sections from the start of snapshot to find the one cut by
+    /// the end of file.
+    fn find_truncated(src: &mut R, file_size: u64) -> crate::errors::Error {
+        let mut offset = size_of::<SnapshotHeader>() as u64;
+        let mut last = None;
+        loop {
+            let mut entry = SectionEntry::default();
+            let read = src
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| src.read_exact(entry.as_mut_bytes()));
+            if read.is_err() || entry.offset != offset + size_of::<SectionEntry>() as u64 {
+                break;
+            }
+            if entry.offset.saturating_add(entry.size) > file_size {
+                return format!(
+                    "Section '{}' is truncated, {} bytes expected at {:#x}, but the snapshot ends at {:#x}",
+                    entry.name(),
+                    entry.size,
+                    entry.offset,
+                    file_size
+                )
+                .into();
+            }
+            offset = entry.offset + entry.size;
+            last = Some(entry.name());
+        }
+        match last {
+            Some(name) => format!(
+                "Snapshot is truncated after section '{}', section table is lost",
+                name
+            )
+            .into(),
+            None => "Snapshot is truncated, no section is found".into(),
+        }
+    }
+
+    /// Read a section by `read`, which is given the reader of its data and
+    /// its size. Checksum of the section is checked after it's read.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of section.
+    /// * `version` - Version of the state expected.
+    /// * `read` - Read the data of section.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the section, if it's missing, of another version,
+    /// corrupted, or fails to be read.
+    pub fn read_section_with<T, F>(&mut self, name: &str, version: u16, read: F) -> Result<T>
+    where
+        F: FnOnce(&mut dyn Read, u64) -> Result<T>,
+    {
+        let entry = match self.sections.iter().find(|entry| entry.name() == name) {
+            Some(entry) => *entry,
+            None => bail!("Section '{}' is not found in snapshot", name),
+        };
+        if entry.version != version {
+            bail!(
+                "Section '{}' has version {}, expected {}",
+                name,
+                entry.version,
+                version
+            );
+        }
+        self.read_entry_with(entry, read)
+    }
+
+    fn read_entry_with<T, F>(&mut self, entry: SectionEntry, read: F) -> Result<T>
+    where
+        F: FnOnce(&mut dyn Read, u64) -> Result<T>,
+    {
+        let name = entry.name();
+        self.src.seek(SeekFrom::Start(entry.offset))?;
+        let mut data = SectionData {
+            src: (&mut self.src).take(entry.size),
+            crc: Crc32::new(),
+        };
+        let value = read(&mut data, entry.size)
+            .chain_err(|| format!("Failed to read section '{}'", name))?;
+        // Drain the rest, so that the whole section is checked.
+        std::io::copy(&mut data, &mut std::io::sink())?;
+        if data.crc.value() != entry.crc {
+            bail!(
+                "Checksum of section '{}' mismatches, the snapshot is corrupted",
+                name
+            );
+        }
+        Ok(value)
+    }
+
+    /// Check whether the snapshot has a section named `name`.
+    pub fn has_section(&self, name: &str) -> bool {
+        self.sections.iter().any(|entry| entry.name() == name)
+    }
+
+    /// Read all of the data of a section.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the section, if it's missing, of another version
+    /// or corrupted.
+    pub fn read_section(&mut self, name: &str, version: u16) -> Result<Vec<u8>> {
+        self.read_section_with(name, version, read_data)
+    }
+
+    /// Read the sections of state to load by `StateRegistry`, which are all
+    /// the sections except `reserved` ones, in the order they are written.
+    ///
+    /// # Arguments
+    ///
+    /// * `reserved` - Sections read by name, which are not state of devices.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the section which is corrupted.
+    pub fn read_states(&mut self, reserved: &[&str]) -> Result<Vec<SavedState>> {
+        let entries: Vec<SectionEntry> = self
+            .sections
+            .iter()
+            .filter(|entry| !reserved.contains(&entry.name().as_str()))
+            .copied()
+            .collect();
+        let mut states = Vec::new();
+        for entry in entries {
+            states.push(SavedState {
+                name: entry.name(),
+                version: entry.version,
+                optional: entry.flags & SECTION_F_OPTIONAL != 0,
+                data: self.read_entry_with(entry, read_data)?,
+            });
+        }
+        Ok(states)
+    }
+}
+
+fn read_data(src: &mut dyn Read, size: u64) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size as usize);
+    src.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Read the `VmConfig` in json saved in the snapshot file at `path`.
+///
+/// # Errors
+///
+/// Return Error if the file isn't a valid snapshot, or its `config` section
+/// can't be read.
+pub fn read_config(path: &str) -> Result<serde_json::Value> {
+    let file = File::open(path).chain_err(|| format!("Failed to open snapshot file {}", path))?;
+    let mut reader = SnapshotReader::new(BufReader::new(file))
+        .chain_err(|| format!("Invalid snapshot file {}", path))?;
+    let config = reader.read_section("config", 1)?;
+    let value =
+        serde_json::from_slice(&config).chain_err(|| "Invalid section 'config' in snapshot")?;
+    Ok(value)
+}
+
+/// Write the non-zero pages of guest memory in the current section of
+/// `writer`, as records of address, length and data:
+///
+/// ```text
+/// | gpa: u64 | len: u64 | data: [u8; len] | gpa: u64 | ...
+/// ```
+///
+/// Pages missing in records are zero.
+///
+/// # Arguments
+///
+/// * `writer` - Writer of snapshot, whose current section is `ram`.
+/// * `ranges` - `(addr, size)` of guest memory, aligned to 4 KiB.
+/// * `read_mem` - Read guest memory at the address to the buffer, it's
+///   called for chunks no larger than 1 MiB.
+///
+/// # Errors
+///
+/// Return Error if fail to read guest memory or write snapshot.
+pub fn write_ram<W: Write + Seek>(
+    writer: &mut SnapshotWriter<W>,
+    ranges: &[(u64, u64)],
+    read_mem: &mut dyn FnMut(u64, &mut [u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buf = vec![0_u8; RAM_CHUNK_SIZE as usize];
+    for (start, size) in ranges.iter() {
+        let mut addr = *start;
+        let end = start + size;
+        while addr < end {
+            let count = min(end - addr, RAM_CHUNK_SIZE);
+            let chunk = &mut buf[..count as usize];
+            read_mem(addr, chunk)?;
+            for (gpa, data) in non_zero_runs(addr, chunk) {
+                writer.write(&gpa.to_le_bytes())?;
+                writer.write(&(data.len() as u64).to_le_bytes())?;
+                writer.write(data)?;
+            }
+            addr += count;
+        }
+    }
+    Ok(())
+}
+
+/// Split a chunk of guest memory at `addr` into runs of non-zero pages.
+fn non_zero_runs(addr: u64, chunk: &[u8]) -> Vec<(u64, &[u8])> {
+    let mut runs = Vec::new();
+    let mut run_start = None;
+    let pages = chunk.chunks(RAM_PAGE_SIZE as usize).enumerate();
+    for (index, page) in pages {
+        let offset = index * RAM_PAGE_SIZE as usize;
+        let zero = page.iter().all(|b| *b == 0);
+        match (run_start, zero) {
+            (None, false) => run_start = Some(offset),
+            (Some(start), true) => {
+                runs.push((addr + start as u64, &chunk[start..offset]));
+                run_start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(start) = run_start {
+        runs.push((addr + start as u64, &chunk[start..]));
+    }
+    runs
+}
+
+/// Load the records written by `write_ram` to guest memory.
+///
+/// # Arguments
+///
+/// * `src` - Data of the `ram` section.
+/// * `size` - Size of the `ram` section.
+/// * `write_mem` - Write the buffer to guest memory at the address, it's
+///   called for chunks no larger than 1 MiB.
+///
+/// # Errors
+///
+/// Return Error if a record is truncated, or fail to write guest memory.
+pub fn load_ram(
+    src: &mut dyn Read,
+    size: u64,
+    write_mem: &mut dyn FnMut(u64, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buf = vec![0_u8; RAM_CHUNK_SIZE as usize];
+    let mut offset = 0;
+    while offset < size {
+        let mut header = [0_u8; RAM_RECORD_HEADER_SIZE as usize];
+        src.read_exact(&mut header)
+            .chain_err(|| format!("Ram record at {:#x} is truncated", offset))?;
+        let mut gpa = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+        if size - offset < RAM_RECORD_HEADER_SIZE || len > size - offset - RAM_RECORD_HEADER_SIZE {
+            bail!("Ram record at {:#x} is truncated", offset);
+        }
+
+        let mut left = len;
+        while left > 0 {
+            let count = min(left, RAM_CHUNK_SIZE);
+            let chunk = &mut buf[..count as usize];
+            src.read_exact(chunk)?;
+            write_mem(gpa, chunk)?;
+            gpa += count;
+            left -= count;
+        }
+        offset += RAM_RECORD_HEADER_SIZE + len;
+    }
+    Ok(())
+}
+
+/// A range of guest memory in the file of `RamFile`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RamFileRange {
+    /// Guest physical address of the range.
+    pub addr: u64,
+    pub size: u64,
+    /// Offset of the range in the file.
+    pub offset: u64,
+}
+
+/// Section `ram-file` in json, which describes the file holding guest
+/// memory beside the snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RamFile {
+    /// Name of the file, which is in the same directory as the snapshot.
+    pub path: String,
+    /// Size of the file.
+    pub size: u64,
+    /// Ranges of guest memory in the file.
+    pub ranges: Vec<RamFileRange>,
+}
+
+impl RamFile {
+    /// Parse the data of section `ram-file`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if it's not valid json, the path isn't a file name, or
+    /// any range exceeds the file.
+    pub fn from_section(data: &[u8]) -> Result<Self> {
+        let ram_file: RamFile =
+            serde_json::from_slice(data).chain_err(|| "Invalid section 'ram-file' in snapshot")?;
+        if matches!(ram_file.path.as_str(), "" | "." | "..") || ram_file.path.contains('/') {
+            bail!("Path '{}' of ram file should be a file name", ram_file.path);
+        }
+        for range in ram_file.ranges.iter() {
+            if range
+                .offset
+                .checked_add(range.size)
+                .filter(|end| *end <= ram_file.size)
+                .is_none()
+            {
+                bail!(
+                    "Guest memory at {:#x} exceeds ram file of size {:#x}",
+                    range.addr,
+                    ram_file.size
+                );
+            }
+        }
+        Ok(ram_file)
+    }
+
+    /// Get the path of the file beside the snapshot at `snapshot`.
+    pub fn resolve(&self, snapshot: &str) -> PathBuf {
+        Path::new(snapshot).with_file_name(&self.path)
+    }
+
+    /// Get the offset in the file of guest memory `[addr, addr + size)`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the memory isn't within one range of the file.
+    pub fn file_offset(&self, addr: u64, size: u64) -> Result<u64> {
+        self.ranges
+            .iter()
+            .find(|range| {
+                addr >= range.addr
+                    && (addr - range.addr)
+                        .checked_add(size)
+                        .filter(|end| *end <= range.size)
+                        .is_some()
+            })
+            .map(|range| range.offset + addr - range.addr)
+            .ok_or_else(|| {
+                format!(
+                    "Guest memory [{:#x}, {:#x}) is not in ram file",
+                    addr,
+                    addr + size
+                )
+                .into()
+            })
+    }
+}
+
+/// Get the path of the file holding guest memory of the snapshot at
+/// `snapshot`.
+pub fn ram_file_path(snapshot: &str) -> String {
+    format!("{}.ram", snapshot)
+}
+
+/// Copy the first `len` bytes of `src` to `dst`. They share
+/// the extents if the file system supports reflink, such as btrfs and xfs,
+/// which costs neither time nor space until either of them is written.
+/// Otherwise it's copied by copy_file_range(2).
+///
+/// # Errors
+///
+/// Return Error if fail to clone or copy the file.
+pub fn clone_file(src: &File, dst: &File, len: u64) -> Result<()> {
+    clone_file_with(src, dst, len, ficlone)
+}
+
+/// Clone `src` to `dst` with `reflink`, and copy it if reflink is not
+/// supported.
+fn clone_file_with<F>(src: &File, dst: &File, len: u64, reflink: F) -> Result<()>
+where
+    F: FnOnce(&File, &File) -> std::io::Result<()>,
+{
+    match reflink(src, dst) {
+        Ok(()) => return Ok(()),
+        Err(e) if copy_fallback(&e) => {
+            info!("Reflink is not supported ({}), ram file is copied", e);
+        }
+        Err(e) => return Err(e).chain_err(|| "Failed to clone ram file"),
+    }
+    copy_file(src, dst, len).chain_err(|| "Failed to copy ram file")
+}
+
+fn ficlone(src: &File, dst: &File) -> std::io::Result<()> {
+    // It's safe because only the fds are passed.
+    let ret = unsafe { ioctl_with_val(dst, FICLONE(), src.as_raw_fd() as libc::c_ulong) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether a failed clone or copy can be done by a slower way instead. The
+/// errors are reported when the file system doesn't support it, or the
+/// files are on different file systems.
+fn copy_fallback(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EOPNOTSUPP)
+            | Some(libc::ENOTTY)
+            | Some(libc::EXDEV)
+            | Some(libc::EINVAL)
+            | Some(libc::ENOSYS)
+    )
+}
+
+/// Copy the first `len` bytes of `src` to `dst` by copy_file_range(2), or
+/// by reading and writing if the kernel can't copy them.
+fn copy_file(src: &File, dst: &File, len: u64) -> std::io::Result<()> {
+    let mut offset: u64 = 0;
+    while offset < len {
+        let mut off_in = offset as libc::loff_t;
+        let mut off_out = offset as libc::loff_t;
+        // It's safe because the offsets outlive the syscall.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                src.as_raw_fd(),
+                &mut off_in,
+                dst.as_raw_fd(),
+                &mut off_out,
+                (len - offset) as libc::size_t,
+                0,
+            )
+        };
+        if ret < 0 {
+            let e = std::io::Error::last_os_error();
+            if copy_fallback(&e) {
+                return copy_file_by_chunks(src, dst, offset, len);
+            }
+            return Err(e);
+        }
+        if ret == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        offset += ret as u64;
+    }
+    Ok(())
+}
+
+/// Copy `[offset, len)` of `src` to `dst` in chunks of 1 MiB.
+fn copy_file_by_chunks(src: &File, dst: &File, mut offset: u64, len: u64) -> std::io::Result<()> {
+    let mut buf = vec![0_u8; RAM_CHUNK_SIZE as usize];
+    while offset < len {
+        let chunk = &mut buf[..min(len - offset, RAM_CHUNK_SIZE) as usize];
+        src.read_exact_at(chunk, offset)?;
+        dst.write_all_at(chunk, offset)?;
+        offset += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// State of the in-kernel interrupt controller, PIT and clock of KVM.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct KvmState {
+    pic_master: kvm_irqchip,
+    pic_slave: kvm_irqchip,
+    ioapic: kvm_irqchip,
+    pit: kvm_pit_state2,
+    clock: kvm_clock_data,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl ByteCode for KvmState {}
+
+#[cfg(target_arch = "x86_64")]
+util::versioned_state!(KvmState, 1, 1);
+
+#[cfg(target_arch = "x86_64")]
+impl KvmState {
+    /// Get the state of in-kernel devices of `vm_fd`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to get the state from KVM.
+    pub fn save(vm_fd: &VmFd) -> Result<Self> {
+        let mut state = KvmState::default();
+        for (chip_id, chip) in [
+            &mut state.pic_master,
+            &mut state.pic_slave,
+            &mut state.ioapic,
+        ]
+        .iter_mut()
+        .enumerate()
+        {
+            chip.chip_id = chip_id as u32;
+            vm_fd
+                .get_irqchip(chip)
+                .chain_err(|| format!("Failed to get state of irqchip {}", chip_id))?;
+        }
+        state.pit = vm_fd
+            .get_pit2()
+            .chain_err(|| "Failed to get state of PIT")?;
+        state.clock = vm_fd.get_clock().chain_err(|| "Failed to get KVM clock")?;
+        Ok(state)
+    }
+
+    /// Set the state of in-kernel devices of `vm_fd`, which are created
+    /// before.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to set the state to KVM.
+    pub fn restore(&self, vm_fd: &VmFd) -> Result<()> {
+        for chip in [&self.pic_master, &self.pic_slave, &self.ioapic].iter() {
+            vm_fd
+                .set_irqchip(chip)
+                .chain_err(|| format!("Failed to set state of irqchip {}", chip.chip_id))?;
+        }
+        vm_fd
+            .set_pit2(&self.pit)
+            .chain_err(|| "Failed to set state of PIT")?;
+        // Flags of clock are read-only.
+        let clock = kvm_clock_data {
+            clock: self.clock.clock,
+            ..Default::default()
+        };
+        vm_fd
+            .set_clock(&clock)
+            .chain_err(|| "Failed to set KVM clock")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn sample_snapshot() -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.add_section("config", 1, b"{}").unwrap();
+        writer.begin_section("mmio0", 2).unwrap();
+        writer.write(&[1, 2, 3]).unwrap();
+        writer.write(&[4, 5]).unwrap();
+        writer.end_section().unwrap();
+        writer.add_section("empty", 1, &[]).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_snapshot_container() {
+        let file = sample_snapshot();
+        let mut reader = SnapshotReader::new(Cursor::new(file.clone())).unwrap();
+        assert_eq!(
+            reader.read_section("mmio0", 2).unwrap(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(reader.read_section("config", 1).unwrap(), b"{}".to_vec());
+        assert!(reader.read_section("empty", 1).unwrap().is_empty());
+
+        // Errors name the section.
+        let errors = [
+            ("cpu0", 1, "Section 'cpu0' is not found in snapshot"),
+            ("mmio0", 1, "Section 'mmio0' has version 2, expected 1"),
+        ];
+        for (name, version, error) in errors.iter() {
+            assert_eq!(
+                reader.read_section(name, *version).unwrap_err().to_string(),
+                *error
+            );
+        }
+        let err = reader
+            .read_section_with("config", 1, |_, _| -> Result<()> { bail!("Bad json") })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Failed to read section 'config'");
+
+        // Sections of state are read with their versions and flags, except
+        // the reserved ones.
+        let states = reader.read_states(&["config", "empty"]).unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].name, "mmio0");
+        assert_eq!((states[0].version, states[0].optional), (2, false));
+        assert_eq!(states[0].data, vec![1, 2, 3, 4, 5]);
+        let mut writer = SnapshotWriter::new(Cursor::new(Vec::new())).unwrap();
+        let state = SavedState {
+            name: "cpu0".to_string(),
+            version: 3,
+            optional: true,
+            data: vec![6, 7],
+        };
+        writer.add_state(&state).unwrap();
+        let state_file = writer.finish().unwrap().into_inner();
+        let mut reader = SnapshotReader::new(Cursor::new(state_file)).unwrap();
+        assert_eq!(reader.read_states(&[]).unwrap(), vec![state]);
+
+        // The data of mmio0 follows the entries of config and itself.
+        let mut corrupted = file.clone();
+        let offset = size_of::<SnapshotHeader>() + size_of::<SectionEntry>() * 2 + 2;
+        assert_eq!(corrupted[offset], 1);
+        corrupted[offset] = 0xff;
+        let mut reader = SnapshotReader::new(Cursor::new(corrupted)).unwrap();
+        assert!(reader.read_section("config", 1).is_ok());
+        assert_eq!(
+            reader.read_section("mmio0", 2).unwrap_err().to_string(),
+            "Checksum of section 'mmio0' mismatches, the snapshot is corrupted"
+        );
+
+        // Writer rejects bad names.
+        let mut writer = SnapshotWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.add_section("ram", 1, &[]).unwrap();
+        assert!(writer.add_section("ram", 1, &[]).is_err());
+        assert!(writer.begin_section("", 1).is_err());
+        assert!(writer.begin_section("a_very_long_section", 1).is_err());
+        assert!(writer.write(&[0]).is_err());
+        writer.begin_section("cpu0", 1).unwrap();
+        assert!(writer.begin_section("cpu1", 1).is_err());
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_snapshot_header_errors() {
+        let file = sample_snapshot();
+
+        // Incomplete snapshot has zero header.
+        let mut bad = file.clone();
+        for b in bad[..size_of::<SnapshotHeader>()].iter_mut() {
+            *b = 0;
+        }
+        let err = SnapshotReader::new(Cursor::new(bad)).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Not a snapshot file, or the snapshot is incomplete"
+        );
+
+        let mut bad = file.clone();
+        bad[8] = 2;
+        let err = SnapshotReader::new(Cursor::new(bad)).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Checksum of snapshot header mismatches, the snapshot is corrupted"
+        );
+
+        let mut header = SnapshotHeader::default();
+        header.as_mut_bytes().copy_from_slice(&file[..40]);
+        header.version = 2;
+        header.header_crc = header.crc();
+        let mut bad = file.clone();
+        bad[..40].copy_from_slice(header.as_bytes());
+        let err = SnapshotReader::new(Cursor::new(bad)).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Snapshot version 2 is not supported, expected 1"
+        );
+
+        // Truncated in the data of mmio0, or in section table.
+        let cut = size_of::<SnapshotHeader>() + size_of::<SectionEntry>() * 2 + 4;
+        let err = SnapshotReader::new(Cursor::new(file[..cut].to_vec()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Section 'mmio0' is truncated, 5 bytes expected at 0x7a, but the snapshot ends at 0x7c"
+        );
+        let err = SnapshotReader::new(Cursor::new(file[..file.len() - 1].to_vec()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Snapshot is truncated after section 'empty', section table is lost"
+        );
+    }
+
+    #[test]
+    fn test_read_config() {
+        let path = std::env::temp_dir()
+            .join(format!("stratovirt-test-{}.snap", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(&path, sample_snapshot()).unwrap();
+        assert_eq!(read_config(&path).unwrap(), serde_json::json!({}));
+
+        // Snapshot cut in section table.
+        let file = sample_snapshot();
+        std::fs::write(&path, &file[..file.len() - 1]).unwrap();
+        assert_eq!(
+            read_config(&path).unwrap_err().to_string(),
+            format!("Invalid snapshot file {}", path)
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            read_config(&path).unwrap_err().to_string(),
+            format!("Failed to open snapshot file {}", path)
+        );
+    }
+
+    #[test]
+    fn test_sparse_ram() {
+        // Two ranges, the first one has non-zero pages on both sides of the
+        // chunk boundary, which are split into records of each chunk. The
+        // second one is all zero.
+        let ranges = [(0, RAM_CHUNK_SIZE * 2), (0x1000_0000, 0x4000)];
+        let mut mem = vec![0_u8; (RAM_CHUNK_SIZE * 2) as usize];
+        let page = RAM_PAGE_SIZE as usize;
+        mem[0] = 1;
+        mem[RAM_CHUNK_SIZE as usize - 1] = 2;
+        mem[RAM_CHUNK_SIZE as usize + page - 1] = 3;
+        mem[RAM_CHUNK_SIZE as usize + page * 3] = 4;
+
+        let mut writer = SnapshotWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.begin_section("ram", 1).unwrap();
+        write_ram(&mut writer, &ranges, &mut |addr, buf| {
+            if addr < RAM_CHUNK_SIZE * 2 {
+                let start = addr as usize;
+                buf.copy_from_slice(&mem[start..start + buf.len()]);
+            } else {
+                buf.iter_mut().for_each(|b| *b = 0);
+            }
+            Ok(())
+        })
+        .unwrap();
+        writer.end_section().unwrap();
+        let file = writer.finish().unwrap().into_inner();
+
+        // 4 records of one non-zero page each.
+        let mut reader = SnapshotReader::new(Cursor::new(file)).unwrap();
+        let data = reader.read_section("ram", 1).unwrap();
+        assert_eq!(data.len(), 4 * (16 + page));
+
+        let mut loaded = vec![0_u8; mem.len()];
+        let mut written = Vec::new();
+        load_ram(&mut data.as_slice(), data.len() as u64, &mut |addr, buf| {
+            written.push((addr, buf.len()));
+            let start = addr as usize;
+            loaded[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            written,
+            vec![
+                (0, page),
+                (RAM_CHUNK_SIZE - RAM_PAGE_SIZE, page),
+                (RAM_CHUNK_SIZE, page),
+                (RAM_CHUNK_SIZE + RAM_PAGE_SIZE * 3, page),
+            ]
+        );
+        assert!(loaded == mem);
+
+        // Truncated record.
+        let err = load_ram(&mut &data[..20], 20, &mut |_, _| Ok(())).unwrap_err();
+        assert_eq!(err.to_string(), "Ram record at 0x0 is truncated");
+    }
+
+    #[test]
+    fn test_ram_file_section() {
+        let ram_file = RamFile {
+            path: "vm.snap.ram".to_string(),
+            size: 0x3000_0000,
+            ranges: vec![
+                RamFileRange {
+                    addr: 0,
+                    size: 0x1000_0000,
+                    offset: 0,
+                },
+                RamFileRange {
+                    addr: 0x1_0000_0000,
+                    size: 0x2000_0000,
+                    offset: 0x1000_0000,
+                },
+            ],
+        };
+        let data = serde_json::to_vec(&ram_file).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "path": "vm.snap.ram",
+                "size": 0x3000_0000_u64,
+                "ranges": [
+                    { "addr": 0, "size": 0x1000_0000_u64, "offset": 0 },
+                    { "addr": 0x1_0000_0000_u64, "size": 0x2000_0000_u64, "offset": 0x1000_0000_u64 },
+                ],
+            })
+        );
+
+        let mut writer = SnapshotWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.add_section("ram-file", 1, &data).unwrap();
+        let file = writer.finish().unwrap().into_inner();
+        let mut reader = SnapshotReader::new(Cursor::new(file)).unwrap();
+        assert!(reader.has_section("ram-file"));
+        assert!(!reader.has_section("ram"));
+        let loaded = RamFile::from_section(&reader.read_section("ram-file", 1).unwrap()).unwrap();
+        assert_eq!(loaded, ram_file);
+
+        assert_eq!(
+            loaded.resolve("/var/run/vm.snap"),
+            PathBuf::from("/var/run/vm.snap.ram")
+        );
+        assert_eq!(ram_file_path("/var/run/vm.snap"), "/var/run/vm.snap.ram");
+        assert_eq!(
+            loaded.file_offset(0x800_0000, 0x800_0000).unwrap(),
+            0x800_0000
+        );
+        assert_eq!(
+            loaded.file_offset(0x1_1000_0000, 0x1000_0000).unwrap(),
+            0x2000_0000
+        );
+        assert_eq!(
+            loaded
+                .file_offset(0x800_0000, 0x1000_0000)
+                .unwrap_err()
+                .to_string(),
+            "Guest memory [0x8000000, 0x18000000) is not in ram file"
+        );
+
+        let mut invalid = ram_file.clone();
+        invalid.path = "../vm.ram".to_string();
+        let err = RamFile::from_section(&serde_json::to_vec(&invalid).unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Path '../vm.ram' of ram file should be a file name"
+        );
+        let mut invalid = ram_file;
+        invalid.size = 0x2000_0000;
+        let err = RamFile::from_section(&serde_json::to_vec(&invalid).unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Guest memory at 0x100000000 exceeds ram file of size 0x20000000"
+        );
+        let err = RamFile::from_section(b"[]").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid section 'ram-file' in snapshot");
+    }
+
+    #[test]
+    fn test_clone_file_fallback() {
+        let dir = std::env::temp_dir();
+        let open = |name: &str| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(dir.join(format!("stratovirt-test-{}-{}", std::process::id(), name)))
+                .unwrap()
+        };
+        let len = RAM_CHUNK_SIZE * 2 + RAM_PAGE_SIZE;
+        let src = open("clone-src");
+        src.set_len(len).unwrap();
+        src.write_all_at(b"head", 0).unwrap();
+        src.write_all_at(b"tail", len - 4).unwrap();
+        let check = |dst: &File| {
+            let mut buf = [0_u8; 4];
+            dst.read_exact_at(&mut buf, 0).unwrap();
+            assert_eq!(&buf, b"head");
+            dst.read_exact_at(&mut buf, len - 4).unwrap();
+            assert_eq!(&buf, b"tail");
+        };
+
+        // Reflink not supported, the file is copied.
+        for errno in [libc::EOPNOTSUPP, libc::EXDEV, libc::ENOTTY].iter() {
+            let dst = open("clone-dst");
+            clone_file_with(&src, &dst, len, |_, _| {
+                Err(std::io::Error::from_raw_os_error(*errno))
+            })
+            .unwrap();
+            check(&dst);
+        }
+        let dst = open("clone-dst");
+        copy_file_by_chunks(&src, &dst, 0, len).unwrap();
+        check(&dst);
+
+        // Other errors are not hidden.
+        let dst = open("clone-dst");
+        let err = clone_file_with(&src, &dst, len, |_, _| {
+            Err(std::io::Error::from_raw_os_error(libc::EIO))
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Failed to clone ram file");
+        assert_eq!(dst.metadata().unwrap().len(), 0);
+
+        // Either cloned or copied, depending on the file system.
+        let dst = open("clone-dst");
+        clone_file(&src, &dst, len).unwrap();
+        check(&dst);
+
+        for name in ["clone-src", "clone-dst"].iter() {
+            std::fs::remove_file(dir.join(format!(
+                "stratovirt-test-{}-{}",
+                std::process::id(),
+                name
+            )))
+            .unwrap();
+        }
+    }
+}
@@ -10,15 +10,18 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use address_space::AddressSpace;
 use kvm_ioctls::VmFd;
-use machine_manager::config::{BootSource, ConfigCheck};
+use machine_manager::config::{check_id, BootSource, ConfigCheck};
 
-use super::super::virtio::{Block, Net};
+use super::super::virtio::{Block, Net, UnplugDone};
 use super::{
-    errors::Result, DeviceResource, DeviceType, MmioDevice, MmioDeviceOps, VirtioMmioDevice,
+    errors::{Result, ResultExt},
+    DeviceResource, DeviceType, MmioDevice, MmioDeviceOps, VirtioMmioDevice,
 };
 use crate::micro_vm::MEM_MAPPED_IO_BASE;
 
@@ -30,6 +33,8 @@ const IRQ_RANGE: (u32, u32) = (5, 15);
 const MMIO_SERIAL_IRQ: u32 = 4;
 const MMIO_SERIAL_ADDR: u64 = 0x3f8;
 const MMIO_LEN: u64 = 0x1000;
+/// Interval of checking whether the IO of devices is quiesced.
+const IO_QUIESCE_INTERVAL: Duration = Duration::from_millis(1);
 
 /// The replaceable block device maximum count.
 pub const MMIO_REPLACEABLE_BLK_NR: usize = 6;
@@ -52,6 +57,8 @@ struct MmioReplaceableDevInfo {
     id: String,
     /// Identify if this device is be used.
     used: bool,
+    /// Identify if this device is being removed.
+    removing: bool,
 }
 
 /// The gather of config, info and count of all replaceable devices.
@@ -77,12 +84,27 @@ impl MmioReplaceableInfo {
     }
 }
 
+/// The information of replaceable device, which is reported by query commands.
+pub struct ReplaceableInfo {
+    /// Device id.
+    pub id: String,
+    /// The dev_config of the related backend device.
+    pub dev_config: Arc<dyn ConfigCheck>,
+    /// The resource of MMIO device which the config is attached to, `None`
+    /// if it's not attached yet.
+    pub resource: Option<DeviceResource>,
+    /// Identify if this device is being removed.
+    pub removing: bool,
+}
+
 /// MMIO Bus.
 pub struct Bus {
     /// The devices inserted in bus.
     devices: Vec<MmioDevice>,
     /// All replaceable device information.
     replaceable_info: MmioReplaceableInfo,
+    /// Ids of all devices and backends, which share one namespace.
+    ids: Arc<Mutex<HashSet<String>>>,
 }
 
 impl Bus {
@@ -100,6 +122,7 @@ impl Bus {
         let mut bus = Bus {
             devices: Vec::new(),
             replaceable_info: MmioReplaceableInfo::new(),
+            ids: Arc::new(Mutex::new(HashSet::new())),
         };
 
         for _ in 0..MMIO_REPLACEABLE_BLK_NR {
@@ -114,6 +137,7 @@ impl Bus {
                         device: dev,
                         id: "".to_string(),
                         used: false,
+                        removing: false,
                     });
             }
         }
@@ -130,6 +154,7 @@ impl Bus {
                         device: dev,
                         id: "".to_string(),
                         used: false,
+                        removing: false,
                     });
             }
         }
@@ -180,7 +205,6 @@ impl Bus {
     }
 
     /// Get the information of all devices inserted in bus.
-    #[cfg(target_arch = "aarch64")]
     pub fn get_devices_info(&self) -> Vec<DeviceResource> {
         let mut infos = Vec::new();
 
@@ -191,6 +215,181 @@ impl Bus {
         infos
     }
 
+    /// Get the state of a device inserted in bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of device in `get_devices_info`.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the device is not found, or doesn't support to save
+    /// state.
+    pub fn get_device_state(&self, index: usize) -> Result<Vec<u8>> {
+        let dev = match self.devices.get(index) {
+            Some(dev) => dev,
+            None => bail!("No MMIO device {} on bus", index),
+        };
+        dev.get_state().chain_err(|| {
+            format!(
+                "Failed to get state of MMIO device at {:#x}",
+                dev.get_resource().addr
+            )
+        })
+    }
+
+    /// Set the state of a device inserted in bus, which is saved by
+    /// `get_device_state`.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of device in `get_devices_info`.
+    /// * `state` - State of the device.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if there is no such device, or it fails to restore its
+    /// state.
+    pub fn set_device_state(&self, index: usize, state: &[u8]) -> Result<()> {
+        let dev = match self.devices.get(index) {
+            Some(dev) => dev,
+            None => bail!("No MMIO device {} on bus", index),
+        };
+        dev.set_state(state).chain_err(|| {
+            format!(
+                "Failed to set state of MMIO device at {:#x}",
+                dev.get_resource().addr
+            )
+        })
+    }
+
+    /// Quiesce the IO of all devices inserted in bus, which is called after
+    /// vcpus are paused.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if any device fails to quiesce, the devices before it
+    /// are kept quiesced.
+    pub fn pause_io(&self) -> Result<()> {
+        for dev in self.devices.iter() {
+            dev.pause_io().chain_err(|| {
+                format!(
+                    "Failed to pause IO of MMIO device at {:#x}",
+                    dev.get_resource().addr
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the IO of all devices inserted in bus to be quiesced after
+    /// `pause_io`, such as the IO in flight of virtio-blk is reaped.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Max time to wait.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if any device isn't quiesced within `timeout`, or can't
+    /// be quiesced by waiting on the current thread.
+    pub fn wait_io_quiesced(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        for dev in self.devices.iter() {
+            let addr = dev.get_resource().addr;
+            while !dev
+                .io_quiesced()
+                .chain_err(|| format!("Failed to quiesce IO of MMIO device at {:#x}", addr))?
+            {
+                if start.elapsed() >= timeout {
+                    bail!(
+                        "IO of MMIO device at {:#x} is not quiesced after {}s",
+                        addr,
+                        timeout.as_secs()
+                    );
+                }
+                std::thread::sleep(IO_QUIESCE_INTERVAL);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restart the IO of all devices inserted in bus quiesced by `pause_io`,
+    /// which is called before vcpus are resumed.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if any device fails to restart, the others are restarted
+    /// anyway.
+    pub fn resume_io(&self) -> Result<()> {
+        let mut failed = Vec::new();
+        for dev in self.devices.iter() {
+            let addr = dev.get_resource().addr;
+            if let Err(e) = dev.resume_io() {
+                error!(
+                    "Failed to resume IO of MMIO device at {:#x}: {}",
+                    addr,
+                    error_chain::ChainedError::display_chain(&e)
+                );
+                failed.push(format!("{:#x}", addr));
+            }
+        }
+        if !failed.is_empty() {
+            bail!(
+                "Failed to resume IO of MMIO devices at {}",
+                failed.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Register `id` of a device or backend, ids of all devices and backends
+    /// share one namespace. The id is released by `release_id` once the
+    /// device or backend is removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device or backend id.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error if the id is illegal or already used.
+    pub fn register_id(&self, id: &str) -> Result<()> {
+        check_id(id).map_err(|e| e.to_string())?;
+        if !self.ids.lock().unwrap().insert(id.to_string()) {
+            bail!("Duplicate ID '{}' for device", id);
+        }
+        Ok(())
+    }
+
+    /// Release `id` of a removed device or backend, so that it can be used
+    /// again.
+    pub fn release_id(&self, id: &str) {
+        self.ids.lock().unwrap().remove(id);
+    }
+
+    /// Get the information of all replaceable configs, and the devices they
+    /// are attached to.
+    pub fn get_replaceable_info(&self) -> Vec<ReplaceableInfo> {
+        let configs_lock = self.replaceable_info.configs.lock().unwrap();
+        let devices_lock = self.replaceable_info.devices.lock().unwrap();
+
+        configs_lock
+            .iter()
+            .map(|config| {
+                let device_info = devices_lock
+                    .iter()
+                    .find(|device_info| device_info.used && device_info.id == config.id);
+                ReplaceableInfo {
+                    id: config.id.clone(),
+                    dev_config: config.dev_config.clone(),
+                    resource: device_info.map(|info| info.device.get_resource()),
+                    removing: device_info.map_or(false, |info| info.removing),
+                }
+            })
+            .collect()
+    }
+
     /// Get an unused entry of replaceable_info, then fill the fields and mark it as `used`.
     ///
     /// # Arguments
@@ -262,11 +461,7 @@ impl Bus {
             bail!("Replaceable configs size extend the max size.");
         }
 
-        for config in configs_lock.iter() {
-            if config.id == id {
-                bail!("Add the id {} repeatedly", id);
-            }
-        }
+        self.register_id(&id)?;
 
         let config = MmioReplaceableConfig { id, dev_config };
         configs_lock.push(config);
@@ -329,33 +524,72 @@ impl Bus {
         Ok(())
     }
 
-    /// Find the entry of replaceable_info which is specified by `id`,
-    /// then update the fields and mark it as `unused`.
+    /// Find the entry of replaceable_info which is specified by `id`, and
+    /// start removing its backend. Once the removal is complete, the config
+    /// is removed, the entry is marked as `unused` and `done` is called.
     ///
     /// # Arguments
     ///
     /// * `id` - Device id.
-    pub fn del_replaceable_device(&self, id: &str) -> Result<String> {
-        // find the index of configuration by name and remove it
-        let mut configs_lock = self.replaceable_info.configs.lock().unwrap();
-        for (index, config) in configs_lock.iter().enumerate() {
-            if config.id == id {
+    /// * `done` - Callback called when the removal is complete.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error if the device is already being removed, or fail to
+    /// start removing it.
+    pub fn del_replaceable_device(&self, id: &str, done: Box<UnplugDone>) -> Result<()> {
+        let configs = self.replaceable_info.configs.clone();
+        let devices = self.replaceable_info.devices.clone();
+        let ids = self.ids.clone();
+        let remove_config = move |id: &str| {
+            let mut configs_lock = configs.lock().unwrap();
+            if let Some(index) = configs_lock.iter().position(|config| config.id == id) {
                 configs_lock.remove(index);
-                break;
+                ids.lock().unwrap().remove(id);
             }
-        }
+        };
 
-        // set the status of the device to 'unused'
         let mut replaceable_devices = self.replaceable_info.devices.lock().unwrap();
-        for device_info in replaceable_devices.iter_mut() {
-            if device_info.id == id {
-                device_info.id = "".to_string();
-                device_info.used = false;
-                device_info.device.update_config(None)?;
+        let index = match replaceable_devices
+            .iter()
+            .position(|device_info| device_info.id == id)
+        {
+            Some(index) => index,
+            None => {
+                // Only the config is added, there is no backend to remove.
+                drop(replaceable_devices);
+                remove_config(id);
+                done();
+                return Ok(());
             }
+        };
+        if replaceable_devices[index].removing {
+            bail!("Device {} is already being removed", id);
+        }
+        replaceable_devices[index].removing = true;
+        let device = replaceable_devices[index].device.clone();
+        // The removal may complete synchronously, so the lock is released
+        // before unplugging.
+        drop(replaceable_devices);
+
+        let dev_id = id.to_string();
+        let unplug_done = Box::new(move || {
+            remove_config(&dev_id);
+            // set the status of the device to 'unused'
+            let mut replaceable_devices = devices.lock().unwrap();
+            let device_info = &mut replaceable_devices[index];
+            device_info.id = "".to_string();
+            device_info.used = false;
+            device_info.removing = false;
+            drop(replaceable_devices);
+            done();
+        });
+        if let Err(e) = device.unplug(unplug_done) {
+            self.replaceable_info.devices.lock().unwrap()[index].removing = false;
+            return Err(e);
         }
 
-        Ok(id.to_string())
+        Ok(())
     }
 
     /// Realize all the devices inserted in this Bus.
@@ -385,3 +619,165 @@ impl Bus {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use address_space::Region;
+    use machine_manager::config::{BalloonConfig, DriveConfig, NetworkInterfaceConfig, RngConfig};
+
+    use super::*;
+    use crate::micro_vm::ConfigDevBuilder;
+
+    #[test]
+    fn test_build_balloon() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let mut bus = Bus::new(sys_mem.clone());
+        let nr_devices = bus.get_devices_info().len();
+
+        let balloon = BalloonConfig {
+            deflate_on_oom: true,
+            free_page_reporting: false,
+        };
+        balloon.build_dev(sys_mem, &mut bus).unwrap();
+        let infos = bus.get_devices_info();
+        assert_eq!(infos.len(), nr_devices + 1);
+        let resource = infos[nr_devices];
+        assert!(resource.dev_type == DeviceType::OTHER);
+        assert_eq!(
+            resource.addr,
+            MEM_MAPPED_IO_BASE + nr_devices as u64 * MMIO_LEN
+        );
+    }
+
+    #[test]
+    fn test_build_rng() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let mut bus = Bus::new(sys_mem.clone());
+        let nr_devices = bus.get_devices_info().len();
+
+        let rng = RngConfig {
+            random_file: "/dev/urandom".to_string(),
+            bytes_per_sec: Some(1024),
+        };
+        rng.build_dev(sys_mem, &mut bus).unwrap();
+        let infos = bus.get_devices_info();
+        assert_eq!(infos.len(), nr_devices + 1);
+        let resource = infos[nr_devices];
+        assert!(resource.dev_type == DeviceType::OTHER);
+        assert_eq!(
+            resource.addr,
+            MEM_MAPPED_IO_BASE + nr_devices as u64 * MMIO_LEN
+        );
+
+        // Unused replaceable slots are saved as well.
+        for index in 0..=nr_devices {
+            assert!(bus.get_device_state(index).is_ok());
+        }
+        assert!(bus.get_device_state(nr_devices + 1).is_err());
+    }
+
+    #[test]
+    fn test_del_replaceable_device() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let bus = Bus::new(sys_mem);
+        let drive = DriveConfig {
+            drive_id: "drive-0".to_string(),
+            ..Default::default()
+        };
+        bus.add_replaceable_config("drive-0".to_string(), Arc::new(drive))
+            .unwrap();
+        bus.add_replaceable_device("drive-0", "virtio-blk-mmio", 1)
+            .unwrap();
+        let infos = bus.get_replaceable_info();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].id, "drive-0");
+        assert!(infos[0].dev_config.as_any().is::<DriveConfig>());
+        let resource = infos[0].resource.unwrap();
+        assert_eq!(resource.addr, MEM_MAPPED_IO_BASE + MMIO_LEN);
+        assert!(resource.dev_type == DeviceType::BLK);
+
+        // Removal in progress can't be started again.
+        bus.replaceable_info.devices.lock().unwrap()[1].removing = true;
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        let deleted_clone = deleted.clone();
+        let err = bus
+            .del_replaceable_device(
+                "drive-0",
+                Box::new(move || deleted_clone.lock().unwrap().push("drive-0")),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("already being removed"));
+        assert!(deleted.lock().unwrap().is_empty());
+        bus.replaceable_info.devices.lock().unwrap()[1].removing = false;
+
+        // The entry is released before `done` is called.
+        let devices = bus.replaceable_info.devices.clone();
+        let deleted_clone = deleted.clone();
+        bus.del_replaceable_device(
+            "drive-0",
+            Box::new(move || {
+                let device_info = &devices.lock().unwrap()[1];
+                assert!(!device_info.used && !device_info.removing);
+                deleted_clone.lock().unwrap().push("drive-0");
+            }),
+        )
+        .unwrap();
+        assert_eq!(*deleted.lock().unwrap(), vec!["drive-0"]);
+        assert!(bus.replaceable_info.configs.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_register_id() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let mut bus = Bus::new(sys_mem.clone());
+        let drive = |id: &str| {
+            Arc::new(DriveConfig {
+                drive_id: id.to_string(),
+                ..Default::default()
+            })
+        };
+        let net = |id: &str| {
+            Arc::new(NetworkInterfaceConfig {
+                iface_id: id.to_string(),
+                ..Default::default()
+            })
+        };
+
+        // Cold plugged devices.
+        drive("rootfs").build_dev(sys_mem, &mut bus).unwrap();
+        bus.register_id("console0").unwrap();
+
+        // Cold/hot, the backend added later can't shadow them.
+        let err = bus
+            .add_replaceable_config("rootfs".to_string(), net("rootfs"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Duplicate ID 'rootfs' for device");
+        assert!(bus
+            .add_replaceable_config("console0".to_string(), drive("console0"))
+            .is_err());
+
+        // Hot/hot, a port of console can't have the id of a backend either.
+        bus.add_replaceable_config("drive-0".to_string(), drive("drive-0"))
+            .unwrap();
+        assert!(bus
+            .add_replaceable_config("drive-0".to_string(), net("drive-0"))
+            .is_err());
+        assert_eq!(
+            bus.register_id("drive-0").unwrap_err().to_string(),
+            "Duplicate ID 'drive-0' for device"
+        );
+        let err = bus
+            .add_replaceable_config("drive 1".to_string(), drive("drive 1"))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Id 'drive 1' is illegal"));
+        assert_eq!(bus.get_replaceable_info().len(), 2);
+
+        // Removal releases the id for reuse.
+        bus.del_replaceable_device("drive-0", Box::new(|| ()))
+            .unwrap();
+        bus.add_replaceable_config("drive-0".to_string(), net("drive-0"))
+            .unwrap();
+        bus.release_id("console0");
+        bus.register_id("console0").unwrap();
+    }
+}
@@ -31,13 +31,15 @@ use std::sync::{Arc, Mutex};
 mod bus;
 mod virtio_mmio;
 
-pub use self::bus::Bus;
+pub use self::bus::{Bus, ReplaceableInfo};
 pub use self::virtio_mmio::VirtioMmioDevice;
 
 use address_space::{AddressSpace, GuestAddress, Region, RegionIoEventFd, RegionOps};
 use error_chain::bail;
 use machine_manager::config::{BootSource, ConfigCheck, Param};
 
+use crate::virtio::UnplugDone;
+
 pub mod errors {
     error_chain! {
         links {
@@ -64,6 +66,8 @@ pub enum DeviceType {
     SERIAL,
     #[cfg(target_arch = "aarch64")]
     RTC,
+    #[cfg(target_arch = "aarch64")]
+    GPIO,
     OTHER,
 }
 
@@ -150,13 +154,13 @@ impl MmioDevice {
         let cmdline = &mut bs.lock().unwrap().kernel_cmdline;
         if let DeviceType::SERIAL = self.resource.dev_type {
             #[cfg(target_arch = "aarch64")]
-            cmdline.push(Param {
+            cmdline.push_vmm_param(Param {
                 param_type: "earlycon".to_string(),
                 value: format!("uart,mmio,0x{:08x}", self.resource.addr),
             });
         } else {
             #[cfg(target_arch = "x86_64")]
-            cmdline.push(Param {
+            cmdline.push_vmm_param(Param {
                 param_type: "virtio_mmio.device".to_string(),
                 value: format!(
                     "{}K@0x{:08x}:{}",
@@ -171,7 +175,6 @@ impl MmioDevice {
     }
 
     /// Get the resource requirement of MMIO device.
-    #[cfg(target_arch = "aarch64")]
     pub fn get_resource(&self) -> DeviceResource {
         *self.resource
     }
@@ -184,6 +187,44 @@ impl MmioDevice {
     pub fn update_config(&self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
         self.device.lock().unwrap().update_config(dev_config)
     }
+
+    /// Remove the backend of MMIO device.
+    ///
+    /// # Arguments
+    ///
+    /// * `done` - Callback called when the removal is complete.
+    pub fn unplug(&self, done: Box<UnplugDone>) -> Result<()> {
+        self.device.lock().unwrap().unplug(done)
+    }
+
+    /// Get the state of MMIO device, which is saved in snapshot.
+    pub fn get_state(&self) -> Result<Vec<u8>> {
+        self.device.lock().unwrap().get_state()
+    }
+
+    /// Set the state of MMIO device saved in snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - State of MMIO device saved by `get_state`.
+    pub fn set_state(&self, state: &[u8]) -> Result<()> {
+        self.device.lock().unwrap().set_state(state)
+    }
+
+    /// Quiesce the IO of MMIO device while VM is paused.
+    pub fn pause_io(&self) -> Result<()> {
+        self.device.lock().unwrap().pause_io()
+    }
+
+    /// Check whether the IO of MMIO device is quiesced by `pause_io`.
+    pub fn io_quiesced(&self) -> Result<bool> {
+        self.device.lock().unwrap().io_quiesced()
+    }
+
+    /// Restart the IO of MMIO device quiesced by `pause_io`.
+    pub fn resume_io(&self) -> Result<()> {
+        self.device.lock().unwrap().resume_io()
+    }
 }
 
 /// Trait for MMIO device.
@@ -199,10 +240,47 @@ pub trait MmioDeviceOps: Send + DeviceOps {
         bail!("Unsupported to update configuration");
     }
 
+    /// Remove the backend of MMIO device, `done` is called once the removal
+    /// is complete.
+    fn unplug(&mut self, done: Box<UnplugDone>) -> Result<()> {
+        self.update_config(None)?;
+        done();
+        Ok(())
+    }
+
     /// Get IoEventFds of MMIO device.
     fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
         Vec::new()
     }
+
+    /// Get the state of MMIO device as bytes, which is saved in snapshot
+    /// while VM is paused.
+    fn get_state(&self) -> Result<Vec<u8>> {
+        bail!("Device doesn't support to save state");
+    }
+
+    /// Set the state of MMIO device from the bytes saved by `get_state`,
+    /// which is called after the device is realized and before VM runs.
+    fn set_state(&mut self, _state: &[u8]) -> Result<()> {
+        bail!("Device doesn't support to restore state");
+    }
+
+    /// Quiesce the IO of MMIO device after vcpus are paused, so that guest
+    /// memory and device state are frozen until `resume_io`.
+    fn pause_io(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Check whether the IO of MMIO device is quiesced, it may complete
+    /// after `pause_io` returns.
+    fn io_quiesced(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Restart the IO of MMIO device before vcpus are resumed.
+    fn resume_io(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub trait DeviceOps: Send {
@@ -17,12 +17,13 @@ use address_space::{AddressRange, AddressSpace, GuestAddress, RegionIoEventFd};
 use byteorder::{ByteOrder, LittleEndian};
 use kvm_ioctls::VmFd;
 use machine_manager::config::ConfigCheck;
+use util::byte_code::ByteCode;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::super::virtio::{
-    virtio_has_feature, Queue, QueueConfig, VirtioDevice, NOTIFY_REG_OFFSET,
-    QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING, VIRTIO_F_RING_PACKED, VIRTIO_TYPE_BLOCK,
-    VIRTIO_TYPE_NET,
+    virtio_has_feature, Queue, QueueConfig, UnplugDone, VirtioDevice, VringIndices,
+    NOTIFY_REG_OFFSET, QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING, VIRTIO_F_RING_PACKED,
+    VIRTIO_TYPE_BLOCK, VIRTIO_TYPE_NET,
 };
 
 use super::errors::{ErrorKind, Result, ResultExt};
@@ -286,6 +287,46 @@ impl VirtioMmioCommonConfig {
     }
 }
 
+/// State of virtio-mmio transport saved in snapshot, which is followed by
+/// the state of each queue, and then the state of device.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VirtioMmioState {
+    /// Type of device, refer to Virtio Spec.
+    device_type: u32,
+    /// Whether the device is activated by frontend driver.
+    activated: u32,
+    features_select: u32,
+    acked_features_select: u32,
+    interrupt_status: u32,
+    device_status: u32,
+    config_generation: u32,
+    queue_select: u32,
+    queue_type: u16,
+    /// Number of queue states following.
+    nr_queues: u16,
+}
+
+impl ByteCode for VirtioMmioState {}
+
+/// State of virtqueue saved in snapshot.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct VirtQueueState {
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    max_size: u16,
+    size: u16,
+    ready: u16,
+    /// Indices kept by device, which are zero if device is not activated.
+    next_avail: u16,
+    next_used: u16,
+    last_signal_used: u16,
+}
+
+impl ByteCode for VirtQueueState {}
+
 /// virtio-mmio device structure.
 pub struct VirtioMmioDevice {
     /// The entity of low level device.
@@ -300,6 +341,9 @@ pub struct VirtioMmioDevice {
     common_config: VirtioMmioCommonConfig,
     /// System address space.
     mem_space: Arc<AddressSpace>,
+    /// Virtqueues given to device when it's activated, kept to save their
+    /// indices.
+    queues: Vec<Arc<Mutex<Queue>>>,
 }
 
 impl VirtioMmioDevice {
@@ -314,6 +358,7 @@ impl VirtioMmioDevice {
             host_notify_info: HostNotifyInfo::new(queue_num),
             common_config: VirtioMmioCommonConfig::new(&device_clone),
             mem_space,
+            queues: Vec::new(),
         }
     }
 
@@ -345,9 +390,10 @@ impl VirtioMmioDevice {
             self.mem_space.clone(),
             self.interrupt_evt.try_clone().unwrap(),
             self.common_config.interrupt_status.clone(),
-            queues,
+            queues.clone(),
             queue_evts,
         )?;
+        self.queues = queues;
 
         Ok(())
     }
@@ -490,6 +536,190 @@ impl MmioDeviceOps for VirtioMmioDevice {
         Ok(())
     }
 
+    /// Remove the backend of MMIO device.
+    fn unplug(&mut self, done: Box<UnplugDone>) -> Result<()> {
+        self.device
+            .lock()
+            .unwrap()
+            .unplug(done)
+            .chain_err(|| "Failed to unplug device")?;
+        Ok(())
+    }
+
+    /// Get the state of transport, queues and device.
+    fn get_state(&self) -> Result<Vec<u8>> {
+        let locked_device = self.device.lock().unwrap();
+        let config = &self.common_config;
+        let state = VirtioMmioState {
+            device_type: locked_device.device_type(),
+            activated: self.device_activated as u32,
+            features_select: config.features_select,
+            acked_features_select: config.acked_features_select,
+            interrupt_status: config.interrupt_status.load(Ordering::SeqCst),
+            device_status: config.device_status,
+            config_generation: config.config_generation,
+            queue_select: config.queue_select,
+            queue_type: config.queue_type,
+            nr_queues: config.queues_config.len() as u16,
+        };
+        let mut bytes = state.as_bytes().to_vec();
+
+        for (index, q_config) in config.queues_config.iter().enumerate() {
+            let indices = self
+                .queues
+                .get(index)
+                .map(|queue| queue.lock().unwrap().vring.get_indices())
+                .unwrap_or_default();
+            let queue_state = VirtQueueState {
+                desc_table: q_config.desc_table.raw_value(),
+                avail_ring: q_config.avail_ring.raw_value(),
+                used_ring: q_config.used_ring.raw_value(),
+                max_size: q_config.max_size,
+                size: q_config.size,
+                ready: q_config.ready as u16,
+                next_avail: indices.next_avail,
+                next_used: indices.next_used,
+                last_signal_used: indices.last_signal_used,
+            };
+            bytes.extend_from_slice(queue_state.as_bytes());
+        }
+
+        let device_state = locked_device.get_state_vec().chain_err(|| {
+            format!(
+                "Failed to get state of virtio device, type {}",
+                state.device_type
+            )
+        })?;
+        bytes.extend(device_state);
+        Ok(bytes)
+    }
+
+    /// Set the state of transport, queues and device, the device is
+    /// activated again if it's activated in snapshot.
+    fn set_state(&mut self, bytes: &[u8]) -> Result<()> {
+        let header_len = std::mem::size_of::<VirtioMmioState>();
+        let queue_len = std::mem::size_of::<VirtQueueState>();
+        if bytes.len() < header_len {
+            bail!("Invalid length {} of virtio mmio state", bytes.len());
+        }
+        let mut state = VirtioMmioState::default();
+        state.as_mut_bytes().copy_from_slice(&bytes[..header_len]);
+        let device_type = self.device.lock().unwrap().device_type();
+        if state.device_type != device_type {
+            bail!(
+                "Virtio device has type {} in snapshot, but type {} is configured",
+                state.device_type,
+                device_type
+            );
+        }
+        let nr_queues = self.common_config.queues_config.len();
+        if state.nr_queues as usize != nr_queues {
+            bail!(
+                "Virtio device has {} queues in snapshot, but {} queues are configured",
+                state.nr_queues,
+                nr_queues
+            );
+        }
+        let queue_end = header_len + nr_queues * queue_len;
+        if bytes.len() < queue_end {
+            bail!("Invalid length {} of virtio mmio state", bytes.len());
+        }
+
+        let config = &mut self.common_config;
+        config.features_select = state.features_select;
+        config.acked_features_select = state.acked_features_select;
+        config
+            .interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        config.device_status = state.device_status;
+        config.config_generation = state.config_generation;
+        config.queue_select = state.queue_select;
+        config.queue_type = state.queue_type;
+        let mut indices = Vec::with_capacity(nr_queues);
+        for (index, q_config) in config.queues_config.iter_mut().enumerate() {
+            let offset = header_len + index * queue_len;
+            let mut queue_state = VirtQueueState::default();
+            queue_state
+                .as_mut_bytes()
+                .copy_from_slice(&bytes[offset..offset + queue_len]);
+            q_config.desc_table = GuestAddress(queue_state.desc_table);
+            q_config.avail_ring = GuestAddress(queue_state.avail_ring);
+            q_config.used_ring = GuestAddress(queue_state.used_ring);
+            q_config.max_size = queue_state.max_size;
+            q_config.size = queue_state.size;
+            q_config.ready = queue_state.ready != 0;
+            indices.push(VringIndices {
+                next_avail: queue_state.next_avail,
+                next_used: queue_state.next_used,
+                last_signal_used: queue_state.last_signal_used,
+            });
+        }
+
+        self.device
+            .lock()
+            .unwrap()
+            .set_state_mut(&bytes[queue_end..])
+            .chain_err(|| {
+                format!(
+                    "Failed to set state of virtio device, type {}",
+                    state.device_type
+                )
+            })?;
+
+        if state.activated != 0 {
+            self.activate()
+                .chain_err(|| "Failed to activate restored virtio device")?;
+            for (queue, indices) in self.queues.iter().zip(indices.into_iter()) {
+                queue.lock().unwrap().vring.set_indices(indices);
+            }
+            self.device_activated = true;
+            // Requests made available before the snapshot are handled at once.
+            for evt in self.host_notify_info.events.iter() {
+                evt.write(1)
+                    .chain_err(|| "Failed to notify restored virtio device")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Quiesce the backend of virtio device, which has no IO before it's
+    /// activated.
+    fn pause_io(&mut self) -> Result<()> {
+        if !self.device_activated {
+            return Ok(());
+        }
+        self.device
+            .lock()
+            .unwrap()
+            .pause_io()
+            .chain_err(|| "Failed to pause IO of virtio device")?;
+        Ok(())
+    }
+
+    fn io_quiesced(&self) -> Result<bool> {
+        if !self.device_activated {
+            return Ok(true);
+        }
+        self.device
+            .lock()
+            .unwrap()
+            .io_quiesced()
+            .chain_err(|| "Failed to quiesce IO of virtio device")
+    }
+
+    /// Restart the backend of virtio device quiesced by `pause_io`.
+    fn resume_io(&mut self) -> Result<()> {
+        if !self.device_activated {
+            return Ok(());
+        }
+        self.device
+            .lock()
+            .unwrap()
+            .resume_io()
+            .chain_err(|| "Failed to resume IO of virtio device")?;
+        Ok(())
+    }
+
     fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
         let mut ret = Vec::new();
         for (index, eventfd) in self.host_notify_info.events.iter().enumerate() {
@@ -517,17 +747,19 @@ impl MmioDeviceOps for VirtioMmioDevice {
 mod tests {
     use std::io::Write;
 
-    use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+    use address_space::{AddressSpace, GuestAddress, HostMemMapping, MemAdvice, Region};
     use util::num_ops::{read_u32, write_u32};
 
+    use super::super::super::virtio::StateTransfer;
     use super::*;
     type VirtioResult<T> = std::result::Result<T, super::super::super::virtio::Error>;
 
     fn address_space_init() -> Arc<AddressSpace> {
         let root = Region::init_container_region(1 << 36);
         let sys_space = AddressSpace::new(root).unwrap();
-        let host_mmap =
-            Arc::new(HostMemMapping::new(GuestAddress(0), SYSTEM_SPACE_SIZE, false).unwrap());
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), SYSTEM_SPACE_SIZE, MemAdvice::default()).unwrap(),
+        );
         sys_space
             .root()
             .add_subregion(
@@ -568,6 +800,17 @@ mod tests {
         }
     }
 
+    impl StateTransfer for VirtioDeviceTest {
+        fn get_state_vec(&self) -> VirtioResult<Vec<u8>> {
+            Ok(self.config_space.clone())
+        }
+
+        fn set_state_mut(&mut self, state: &[u8]) -> VirtioResult<()> {
+            self.config_space = state.to_vec();
+            Ok(())
+        }
+    }
+
     impl VirtioDevice for VirtioDeviceTest {
         fn realize(&mut self) -> VirtioResult<()> {
             self.b_realized = true;
@@ -1208,4 +1451,102 @@ mod tests {
                 | CONFIG_STATUS_FEATURES_OK
         );
     }
+
+    #[test]
+    fn test_virtio_mmio_device_snapshot() {
+        let virtio_device = Arc::new(Mutex::new(VirtioDeviceTest::new()));
+        let virtio_device_clone = virtio_device.clone();
+        let sys_space = address_space_init();
+        let mut virtio_mmio_device = VirtioMmioDevice::new(sys_space.clone(), virtio_device);
+        let addr = GuestAddress(0);
+
+        virtio_mmio_device.common_config.queue_select = 0;
+        virtio_mmio_device.common_config.device_status = CONFIG_STATUS_FEATURES_OK;
+        if let Ok(config) = virtio_mmio_device.common_config.get_mut_queue_config() {
+            config.desc_table = GuestAddress(0);
+            config.avail_ring = GuestAddress((QUEUE_SIZE as u64) * 16);
+            config.used_ring = GuestAddress(align(
+                (QUEUE_SIZE as u64) * 16 + 8 + 2 * (QUEUE_SIZE as u64),
+                4096,
+            ));
+            config.size = QUEUE_SIZE;
+            config.ready = true;
+        }
+        virtio_mmio_device.common_config.queue_select = 1;
+        if let Ok(config) = virtio_mmio_device.common_config.get_mut_queue_config() {
+            config.desc_table = GuestAddress(0);
+            config.avail_ring = GuestAddress((QUEUE_SIZE as u64) * 16);
+            config.used_ring = GuestAddress(align(
+                (QUEUE_SIZE as u64) * 16 + 8 + 2 * (QUEUE_SIZE as u64),
+                4096,
+            ));
+            config.size = QUEUE_SIZE / 2;
+            config.ready = true;
+        }
+
+        let mut buf: Vec<u8> = vec![0xff, 0xff, 0xff, 0xff];
+        LittleEndian::write_u32(
+            &mut buf[..],
+            CONFIG_STATUS_ACKNOWLEDGE
+                | CONFIG_STATUS_DRIVER
+                | CONFIG_STATUS_DRIVER_OK
+                | CONFIG_STATUS_FEATURES_OK,
+        );
+        assert_eq!(virtio_mmio_device.write(&buf[..], addr, STATUS_REG), true);
+        assert_eq!(virtio_mmio_device.device_activated, true);
+
+        // the state holds transport, queues and device in order
+        let indices = VringIndices {
+            next_avail: 2,
+            next_used: 1,
+            last_signal_used: 1,
+        };
+        virtio_mmio_device.queues[0]
+            .lock()
+            .unwrap()
+            .vring
+            .set_indices(indices);
+        let state = virtio_mmio_device.get_state().unwrap();
+        let header_len = std::mem::size_of::<VirtioMmioState>();
+        let queue_len = std::mem::size_of::<VirtQueueState>();
+        let header = VirtioMmioState::from_bytes(&state[..header_len]).unwrap();
+        assert_eq!(header.device_type, DeviceType::BLK as u32);
+        assert_eq!(header.activated, 1);
+        assert_eq!(header.nr_queues, QUEUE_NUM as u16);
+        let queue_end = header_len + QUEUE_NUM * queue_len;
+        let queue = VirtQueueState::from_bytes(&state[queue_end - queue_len..queue_end]).unwrap();
+        assert_eq!(queue.size, QUEUE_SIZE / 2);
+        assert_eq!(queue.ready, 1);
+        assert_eq!(queue.next_avail, 0);
+        assert_eq!(
+            &state[queue_end..],
+            &virtio_device_clone.lock().unwrap().config_space[..]
+        );
+
+        // the state is restored by a new device, which is activated again
+        let restored_device = Arc::new(Mutex::new(VirtioDeviceTest::new()));
+        restored_device.lock().unwrap().config_space = Vec::new();
+        let mut restored = VirtioMmioDevice::new(sys_space.clone(), restored_device.clone());
+        restored.set_state(&state).unwrap();
+        assert_eq!(restored.device_activated, true);
+        assert_eq!(restored_device.lock().unwrap().b_active, true);
+        assert_eq!(restored.get_state().unwrap(), state);
+        assert_eq!(
+            restored.queues[0].lock().unwrap().vring.get_indices(),
+            indices
+        );
+
+        let mut mismatched = state.clone();
+        mismatched[0] = VIRTIO_TYPE_BLOCK as u8;
+        let mut restored =
+            VirtioMmioDevice::new(sys_space, Arc::new(Mutex::new(VirtioDeviceTest::new())));
+        assert_eq!(
+            restored.set_state(&mismatched).unwrap_err().to_string(),
+            format!(
+                "Virtio device has type {} in snapshot, but type {} is configured",
+                VIRTIO_TYPE_BLOCK,
+                DeviceType::BLK as u32
+            )
+        );
+    }
 }
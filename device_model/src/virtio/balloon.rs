@@ -0,0 +1,406 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::io::Write;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress};
+use machine_manager::config::BalloonConfig;
+use util::byte_code::ByteCode;
+use util::epoll_context::{read_fd, EventNotifier, EventNotifierHelper, NotifierOperation};
+use util::num_ops::{read_u32, write_u32};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::micro_vm::main_loop::MainLoop;
+use super::errors::{ErrorKind, Result, ResultExt};
+use super::{
+    check_restored_features, state_from_bytes, Element, Queue, StateTransfer, VirtioDevice,
+    VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_BALLOON_F_REPORTING, VIRTIO_F_VERSION_1,
+    VIRTIO_MMIO_INT_VRING, VIRTIO_TYPE_BALLOON,
+};
+
+/// Size of virtqueue.
+const QUEUE_SIZE_BALLOON: u16 = 256;
+/// Index of virtqueues, the reporting virtqueue follows the deflate one if
+/// free page reporting is enabled.
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+const REPORTING_QUEUE: usize = 2;
+/// Pages in inflate and deflate virtqueues are given by their PFNs of 4K,
+/// whatever the page size of guest is.
+const BALLOON_PAGE_SHIFT: u64 = 12;
+const BALLOON_PAGE_SIZE: u64 = 1 << BALLOON_PAGE_SHIFT;
+/// Offset of `actual` in configuration space, it's the only field written
+/// by driver.
+const ACTUAL_OFFSET: u64 = 4;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioBalloonConfig {
+    /// Number of pages host wants guest to give up.
+    num_pages: u32,
+    /// Number of pages guest has given up.
+    actual: u32,
+}
+
+impl ByteCode for VirtioBalloonConfig {}
+
+/// Balloon device's IO handle context.
+struct BalloonHandler {
+    /// Inflate, deflate and reporting virtqueues with their eventfds.
+    queues: Vec<(Arc<Mutex<Queue>>, EventFd)>,
+    /// The address space to which the balloon device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for triggering interrupts.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+}
+
+impl BalloonHandler {
+    /// Get the guest memory ranges given by a request of virtqueue `index`.
+    /// Inflate and deflate requests are arrays of PFNs, reporting requests
+    /// are the free ranges themselves.
+    fn request_ranges(&self, index: usize, elem: &Element) -> Result<Vec<(GuestAddress, u64)>> {
+        if index == REPORTING_QUEUE {
+            return Ok(elem
+                .in_iovec
+                .iter()
+                .map(|iov| (iov.addr, u64::from(iov.len)))
+                .collect());
+        }
+
+        let mut ranges = Vec::new();
+        for iov in elem.out_iovec.iter() {
+            let nr_pfns = u64::from(iov.len) / size_of::<u32>() as u64;
+            for i in 0..nr_pfns {
+                let pfn_addr = iov
+                    .addr
+                    .checked_add(i * size_of::<u32>() as u64)
+                    .ok_or(ErrorKind::QueueDescInvalid)?;
+                let pfn = self
+                    .mem_space
+                    .read_object::<u32>(pfn_addr)
+                    .chain_err(|| "Failed to read PFN of balloon")?;
+                ranges.push((
+                    GuestAddress(u64::from(pfn) << BALLOON_PAGE_SHIFT),
+                    BALLOON_PAGE_SIZE,
+                ));
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Handle the requests of virtqueue `index`. Pages of inflate and
+    /// reporting requests are returned to host, and the ones of deflate
+    /// requests are in use again.
+    fn process_queue(&self, index: usize) -> Result<()> {
+        let mut queue = self.queues[index].0.lock().unwrap();
+        while let Ok(elem) = queue.vring.pop_avail(&self.mem_space, self.driver_features) {
+            let ranges = self.request_ranges(index, &elem)?;
+            let mut failed = 0;
+            for (addr, size) in ranges.iter() {
+                let ret = match index {
+                    INFLATE_QUEUE | REPORTING_QUEUE => self.mem_space.discard_range(*addr, *size),
+                    _ => self.mem_space.undiscard_range(*addr, *size),
+                };
+                if ret.is_err() {
+                    failed += 1;
+                }
+            }
+            // Memory backed by huge pages can't be returned by 4K pages.
+            if failed != 0 {
+                warn!(
+                    "{} of {} memory ranges given by balloon are not handled",
+                    failed,
+                    ranges.len()
+                );
+            }
+
+            queue
+                .vring
+                .add_used(&self.mem_space, elem.index, 0)
+                .chain_err(|| format!("Failed to add used ring {}", elem.index))?;
+        }
+
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+        self.interrupt_evt
+            .write(1)
+            .chain_err(|| ErrorKind::EventFdWrite)?;
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for BalloonHandler {
+    fn internal_notifiers(balloon_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+        let locked_handler = balloon_handler.lock().unwrap();
+        for (index, (_, queue_evt)) in locked_handler.queues.iter().enumerate() {
+            let cloned_handler = balloon_handler.clone();
+            let handler = Box::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                if let Err(e) = cloned_handler.lock().unwrap().process_queue(index) {
+                    error!(
+                        "Failed to handle virtqueue {} of balloon: {}",
+                        index,
+                        error_chain::ChainedError::display_chain(&e)
+                    );
+                }
+                None as Option<Vec<EventNotifier>>
+            });
+            notifiers.push(EventNotifier::new(
+                NotifierOperation::AddShared,
+                queue_evt.as_raw_fd(),
+                None,
+                EventSet::IN,
+                vec![Arc::new(Mutex::new(handler))],
+            ));
+        }
+        notifiers
+    }
+}
+
+/// Virtio balloon device, guest gives its memory back to host by it.
+pub struct Balloon {
+    /// Configuration of the balloon device.
+    balloon_cfg: BalloonConfig,
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Virtio balloon configuration space.
+    config: VirtioBalloonConfig,
+}
+
+impl Balloon {
+    /// Create a virtio balloon device.
+    ///
+    /// # Arguments
+    ///
+    /// * `balloon_cfg` - Configuration of the balloon device.
+    pub fn new(balloon_cfg: BalloonConfig) -> Self {
+        Balloon {
+            balloon_cfg,
+            device_features: 0,
+            driver_features: 0,
+            config: VirtioBalloonConfig::default(),
+        }
+    }
+}
+
+/// State of balloon device saved in snapshot.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct BalloonState {
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Virtio balloon configuration space.
+    config: VirtioBalloonConfig,
+}
+
+impl ByteCode for BalloonState {}
+
+impl StateTransfer for Balloon {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        let state = BalloonState {
+            device_features: self.device_features,
+            driver_features: self.driver_features,
+            config: self.config,
+        };
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        let state: BalloonState = state_from_bytes(state)?;
+        check_restored_features(state.device_features, self.device_features)?;
+        self.device_features = state.device_features;
+        self.driver_features = state.driver_features;
+        self.config = state.config;
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Balloon {
+    /// Realize virtio balloon device.
+    fn realize(&mut self) -> Result<()> {
+        self.device_features = 1_u64 << VIRTIO_F_VERSION_1;
+        if self.balloon_cfg.deflate_on_oom {
+            self.device_features |= 1_u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+        if self.balloon_cfg.free_page_reporting {
+            self.device_features |= 1_u64 << VIRTIO_BALLOON_F_REPORTING;
+        }
+
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_BALLOON
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        if self.balloon_cfg.free_page_reporting {
+            REPORTING_QUEUE + 1
+        } else {
+            DEFLATE_QUEUE + 1
+        }
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_BALLOON
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        let mut v = write_u32(value, page);
+        let unrequested_features = v & !self.device_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request with unknown feature.");
+            v &= !unrequested_features;
+        }
+        self.driver_features |= v;
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
+        let config_slice = self.config.as_bytes();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            return Err(ErrorKind::DevConfigOverflow(offset, config_len).into());
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write data to config from guest, only `actual` is writable.
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let config_len = size_of::<VirtioBalloonConfig>() as u64;
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if offset >= ACTUAL_OFFSET && end <= config_len => {
+                self.config.as_mut_bytes()[offset as usize..end as usize].copy_from_slice(data);
+                Ok(())
+            }
+            _ => Err(ErrorKind::DevConfigOverflow(offset, config_len).into()),
+        }
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_evt: EventFd,
+        interrupt_status: Arc<AtomicU32>,
+        queues: Vec<Arc<Mutex<Queue>>>,
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        if queues.len() != self.queue_num() || queue_evts.len() != queues.len() {
+            bail!(
+                "Balloon needs {} virtqueues, but {} are given",
+                self.queue_num(),
+                queues.len()
+            );
+        }
+
+        let handler = Arc::new(Mutex::new(BalloonHandler {
+            queues: queues.into_iter().zip(queue_evts).collect(),
+            mem_space,
+            interrupt_evt,
+            interrupt_status,
+            driver_features: self.driver_features,
+        }));
+        MainLoop::update_event(EventNotifierHelper::internal_notifiers(handler))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balloon_features() {
+        let mut balloon = Balloon::new(BalloonConfig::default());
+        balloon.realize().unwrap();
+        assert_eq!(balloon.device_type(), VIRTIO_TYPE_BALLOON);
+        assert_eq!(balloon.queue_num(), 2);
+        assert_eq!(balloon.get_device_features(0), 0);
+        assert_eq!(balloon.get_device_features(1), 1);
+
+        let mut balloon = Balloon::new(BalloonConfig {
+            deflate_on_oom: true,
+            free_page_reporting: true,
+        });
+        balloon.realize().unwrap();
+        assert_eq!(balloon.queue_num(), 3);
+        assert_eq!(
+            balloon.get_device_features(0),
+            1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM | 1 << VIRTIO_BALLOON_F_REPORTING
+        );
+
+        // Features not offered are not negotiated.
+        let mut balloon = Balloon::new(BalloonConfig {
+            deflate_on_oom: true,
+            free_page_reporting: false,
+        });
+        balloon.realize().unwrap();
+        balloon.set_driver_features(
+            0,
+            1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM | 1 << VIRTIO_BALLOON_F_REPORTING,
+        );
+        assert_eq!(
+            balloon.driver_features,
+            1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM
+        );
+    }
+
+    #[test]
+    fn test_balloon_config() {
+        let mut balloon = Balloon::new(BalloonConfig::default());
+        balloon.config.num_pages = 0x100;
+
+        let mut data = vec![0_u8; 8];
+        balloon.read_config(0, &mut data).unwrap();
+        assert_eq!(data, vec![0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(balloon.read_config(8, &mut data).is_err());
+
+        // Driver updates `actual`, but can't change `num_pages`.
+        balloon.write_config(4, &[0x80, 0, 0, 0]).unwrap();
+        assert_eq!(balloon.config.actual, 0x80);
+        assert!(balloon.write_config(0, &[0, 0, 0, 0]).is_err());
+        assert!(balloon.write_config(6, &[0, 0, 0, 0]).is_err());
+        assert_eq!(balloon.config.num_pages, 0x100);
+    }
+}
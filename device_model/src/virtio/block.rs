@@ -23,9 +23,10 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
 
 use address_space::{AddressSpace, GuestAddress};
-use machine_manager::config::{ConfigCheck, DriveConfig};
+use machine_manager::config::{AioEngine, ConfigCheck, DetectZeroes, DriveConfig};
 use util::aio::{Aio, AioCb, AioCompleteFunc, IoCmd, Iovec, UringCmd};
 use util::byte_code::ByteCode;
 use util::epoll_context::{
@@ -37,11 +38,11 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 use super::super::micro_vm::main_loop::MainLoop;
 use super::errors::{ErrorKind, Result, ResultExt};
 use super::{
-    Element, Queue, VirtioDevice, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX,
-    VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_FLUSH,
-    VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_F_RING_EVENT_IDX,
-    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
-    VIRTIO_TYPE_BLOCK,
+    check_restored_features, state_from_bytes, Element, Queue, StateTransfer, UnplugDone,
+    VirtioDevice, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX,
+    VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
+    VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING, VIRTIO_TYPE_BLOCK,
 };
 
 /// Number of virtqueues.
@@ -57,7 +58,13 @@ const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 /// Size of the dummy block device.
 const DUMMY_IMG_SIZE: u64 = 0;
 
-type SenderConfig = (Option<File>, u64, Option<String>, bool);
+type SenderConfig = (
+    Option<File>,
+    u64,
+    Option<String>,
+    IoOptions,
+    Option<Box<UnplugDone>>,
+);
 type VirtioBlockInterrupt = Box<dyn Fn(u32) -> Result<()> + Send + Sync>;
 
 fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
@@ -69,6 +76,39 @@ fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
     id_bytes
 }
 
+/// Options of the IO handler, taken from `DriveConfig`.
+#[derive(Clone, Copy)]
+pub struct IoOptions {
+    /// Submit reads and writes by io_uring, otherwise they're done
+    /// synchronously.
+    pub uring: bool,
+    /// Complete flush requests without syncing the image.
+    pub no_flush: bool,
+    /// How to handle writes of zeroes.
+    pub detect_zeroes: DetectZeroes,
+}
+
+impl IoOptions {
+    fn new(blk_cfg: &DriveConfig) -> Self {
+        IoOptions {
+            uring: blk_cfg.aio_engine() == AioEngine::IoUring,
+            no_flush: blk_cfg.no_flush,
+            detect_zeroes: blk_cfg.detect_zeroes,
+        }
+    }
+}
+
+/// Check whether the data of all the buffers is zero.
+fn iovec_is_zero(iovec: &[Iovec]) -> bool {
+    iovec.iter().all(|iov| {
+        // It's safe because the buffers are checked to be in guest memory
+        // when the request is built.
+        let buf =
+            unsafe { std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len as usize) };
+        buf.iter().all(|byte| *byte == 0)
+    })
+}
+
 /// Write data to memory at specified address.
 ///
 /// # Arguments
@@ -133,6 +173,9 @@ pub struct AioCompleteCb {
     pub interrupt_cb: Option<Arc<VirtioBlockInterrupt>>,
     /// Bit mask of features negotiated by the backend and the frontend.
     pub driver_features: u64,
+    /// Guest memory written by the request through host addresses, which
+    /// is logged dirty when it's complete.
+    pub written: Vec<(GuestAddress, u64)>,
 }
 
 impl AioCompleteCb {
@@ -163,6 +206,7 @@ impl AioCompleteCb {
             req_status_addr,
             interrupt_cb,
             driver_features,
+            written: Vec::new(),
         }
     }
 }
@@ -180,6 +224,8 @@ struct Request {
     /// The address of header(in_header) which is writable, and this header
     /// should be written with the result of handling the request.
     in_header: GuestAddress,
+    /// Guest memory of the IO vector written by the request.
+    written: Vec<(GuestAddress, u64)>,
 }
 
 impl Request {
@@ -205,7 +251,7 @@ impl Request {
 
         let out_header = mem_space
             .read_object::<RequestOutHeader>(out_iov_elem.addr)
-            .chain_err(|| format!("Failed to read from memory, addr {}", out_iov_elem.addr.0))?;
+            .chain_err(|| "Failed to read request header from memory")?;
 
         if !out_header.is_valid() {
             bail!("Unsupported request type");
@@ -223,6 +269,7 @@ impl Request {
             iovec: Vec::with_capacity(elem.desc_num as usize),
             data_len: 0,
             in_header: in_iov_elem.addr,
+            written: Vec::new(),
         };
 
         match out_header.request_type {
@@ -231,13 +278,22 @@ impl Request {
                     if index == elem.in_iovec.len() - 1 {
                         break;
                     }
-                    if let Some(hva) = mem_space.get_host_address(elem_iov.addr) {
+                    if let Some(host_mem) = mem_space.get_host_mem(elem_iov.addr) {
+                        if host_mem.len() < u64::from(elem_iov.len) {
+                            bail!(
+                                "Request buffer at 0x{:x} crosses the end of guest ram",
+                                elem_iov.addr.0
+                            );
+                        }
                         let iov = Iovec {
-                            iov_base: hva,
+                            iov_base: host_mem.host_address(),
                             iov_len: u64::from(elem_iov.len),
                         };
                         request.iovec.push(iov);
                         request.data_len += u64::from(elem_iov.len);
+                        request
+                            .written
+                            .push((elem_iov.addr, u64::from(elem_iov.len)));
                     }
                 }
             }
@@ -246,9 +302,15 @@ impl Request {
                     if index == 0 {
                         continue;
                     }
-                    if let Some(hva) = mem_space.get_host_address(elem_iov.addr) {
+                    if let Some(host_mem) = mem_space.get_host_mem(elem_iov.addr) {
+                        if host_mem.len() < u64::from(elem_iov.len) {
+                            bail!(
+                                "Request buffer at 0x{:x} crosses the end of guest ram",
+                                elem_iov.addr.0
+                            );
+                        }
                         let iov = Iovec {
-                            iov_base: hva,
+                            iov_base: host_mem.host_address(),
                             iov_len: u64::from(elem_iov.len),
                         };
                         request.iovec.push(iov);
@@ -270,7 +332,7 @@ impl Request {
         disk: &mut File,
         disk_sectors: u64,
         serial_num: &Option<String>,
-        direct: bool,
+        io_opts: IoOptions,
         last_aio: bool,
         iocompletecb: AioCompleteCb,
     ) -> Result<u32> {
@@ -307,9 +369,9 @@ impl Request {
         }
 
         match self.out_header.request_type {
-            VIRTIO_BLK_T_IN => { 
+            VIRTIO_BLK_T_IN => {
                 aiocb.opcode = UringCmd::IORING_OP_READV;
-                if direct {
+                if io_opts.uring {
                     (*aio).as_mut().rw_aio(aiocb)?;
                 } else {
                     (*aio).as_mut().rw_sync(aiocb)?;
@@ -317,14 +379,21 @@ impl Request {
             }
             VIRTIO_BLK_T_OUT => {
                 aiocb.opcode = UringCmd::IORING_OP_WRITEV;
-                if direct {
+                if io_opts.detect_zeroes != DetectZeroes::Off && iovec_is_zero(&aiocb.iovec) {
+                    let unmap = io_opts.detect_zeroes == DetectZeroes::Unmap;
+                    (*aio).as_mut().write_zeroes(aiocb, unmap)?;
+                } else if io_opts.uring {
                     (*aio).as_mut().rw_aio(aiocb)?;
                 } else {
                     (*aio).as_mut().rw_sync(aiocb)?;
                 }
             }
             VIRTIO_BLK_T_FLUSH => {
-                aiocb.opcode = UringCmd::IORING_OP_FSYNC;
+                aiocb.opcode = if io_opts.no_flush {
+                    UringCmd::IORING_OP_NOP
+                } else {
+                    UringCmd::IORING_OP_FSYNC
+                };
                 (*aio).as_mut().rw_sync(aiocb)?;
             }
             VIRTIO_BLK_T_GET_ID => {
@@ -352,6 +421,152 @@ impl Request {
     }
 }
 
+/// State of the IO handler shared with `Block`, which quiesces it while VM
+/// is paused. Completions of aio held while VM is paused are published to
+/// the used ring when VM is resumed.
+#[derive(Default)]
+struct AioHold {
+    /// Whether VM is paused.
+    paused: bool,
+    /// Whether the IO handler is processing the queue, it stops popping
+    /// requests only after that.
+    processing: bool,
+    /// Number of IO requests submitted to the image and not reaped yet.
+    inflight: usize,
+    /// Thread running the event loop of the IO handler, it's known once
+    /// the queue is processed.
+    thread: Option<ThreadId>,
+    /// Requests completed while VM is paused, with their results.
+    completions: Vec<(AioCompleteCb, i64)>,
+}
+
+impl AioHold {
+    /// Check whether the IO handler is quiesced, that's VM is paused, and
+    /// no request is being popped or in flight.
+    ///
+    /// # Errors
+    ///
+    /// IO is in flight, and the IO handler runs on the current thread, which
+    /// can't reap it while waiting.
+    fn quiesced(&self) -> Result<bool> {
+        if !self.paused {
+            return Ok(false);
+        }
+        if !self.processing && self.inflight == 0 {
+            return Ok(true);
+        }
+        if self.thread == Some(thread::current().id()) {
+            bail!(
+                "{} block IO requests in flight can't be reaped by the thread waiting for them",
+                self.inflight
+            );
+        }
+        Ok(false)
+    }
+}
+
+/// Handle the completion of an aio request, the guest memory written by it
+/// is logged dirty at once, but it's held from the used ring while VM is
+/// paused.
+///
+/// # Arguments
+///
+/// * `hold` - Completions held while VM is paused.
+/// * `complete_cb` - The completed request.
+/// * `ret` - Result of the request.
+fn complete_aio(hold: &Mutex<AioHold>, complete_cb: &AioCompleteCb, ret: i64) {
+    for (addr, len) in complete_cb.written.iter() {
+        complete_cb.mem_space.mark_dirty(*addr, *len);
+    }
+
+    let mut locked_hold = hold.lock().unwrap();
+    if locked_hold.paused {
+        locked_hold.completions.push((complete_cb.clone(), ret));
+        return;
+    }
+    drop(locked_hold);
+    publish_aio(complete_cb, ret);
+}
+
+/// Write the status of a completed aio request, put it in the used ring and
+/// notify guest if needed.
+///
+/// # Arguments
+///
+/// * `complete_cb` - The completed request.
+/// * `ret` - Result of the request.
+fn publish_aio(complete_cb: &AioCompleteCb, ret: i64) {
+    let status = if ret < 0 {
+        ret
+    } else {
+        i64::from(VIRTIO_BLK_S_OK)
+    };
+    if let Err(e) = complete_cb
+        .mem_space
+        .write_object(&status, complete_cb.req_status_addr)
+    {
+        error!(
+            "Failed to write object(aio completion): {}",
+            error_chain::ChainedError::display_chain(&e)
+        );
+        return;
+    }
+
+    let mut queue_lock = complete_cb.queue.lock().unwrap();
+    if queue_lock
+        .vring
+        .add_used(
+            &complete_cb.mem_space,
+            complete_cb.desc_index,
+            complete_cb.rw_len,
+        )
+        .is_err()
+    {
+        error!(
+            "Failed to add used ring(aio completion), index {}, len {}",
+            complete_cb.desc_index, complete_cb.rw_len
+        );
+        return;
+    }
+
+    let trigger_interrupt_status = queue_lock
+        .vring
+        .should_notify(&complete_cb.mem_space, complete_cb.driver_features);
+    if trigger_interrupt_status
+        && (*complete_cb.interrupt_cb.as_ref().unwrap())(VIRTIO_MMIO_INT_VRING).is_err()
+    {
+        error!("Failed to trigger interrupt(aio completion)");
+    }
+}
+
+/// Image removed from the block device, it's kept open until the IO
+/// submitted to it is complete.
+struct PendingUnplug {
+    /// The removed image file.
+    disk_image: Option<File>,
+    /// Callback called when the removal is complete.
+    done: Option<Box<UnplugDone>>,
+}
+
+impl PendingUnplug {
+    /// Close the image and call `done` if there is no IO in flight, return
+    /// whether the removal is complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `inflight` - Number of IO requests not completed yet.
+    fn try_complete(&mut self, inflight: usize) -> bool {
+        if inflight > 0 {
+            return false;
+        }
+        self.disk_image = None;
+        if let Some(done) = self.done.take() {
+            done();
+        }
+        true
+    }
+}
+
 /// Control block of Block IO.
 pub struct BlockIoHandler {
     /// The virtqueue.
@@ -366,8 +581,8 @@ pub struct BlockIoHandler {
     pub disk_sectors: u64,
     /// Serial number of the block device.
     pub serial_num: Option<String>,
-    /// if use direct access io.
-    pub direct: bool,
+    /// Options of IO to the image.
+    pub io_opts: IoOptions,
     /// Aio context.
     pub aio: Option<Box<Aio<AioCompleteCb>>>,
     /// Bit mask of features negotiated by the backend and the frontend.
@@ -378,12 +593,35 @@ pub struct BlockIoHandler {
     update_evt: RawFd,
     /// Callback to trigger an interrupt.
     pub interrupt_cb: Arc<VirtioBlockInterrupt>,
+    /// Removal of image waiting for the IO in flight.
+    pending_unplug: Option<PendingUnplug>,
+    /// Completions of aio held while VM is paused.
+    hold: Arc<Mutex<AioHold>>,
 }
 
 impl BlockIoHandler {
     /// Build IO requests if there are elements in virtqueue needed to be finished,
-    /// and execute them. If required, an interrupt is sent to the guest.
+    /// and execute them. If required, an interrupt is sent to the guest. No
+    /// request is popped while VM is paused.
     pub fn process_queue(&mut self) -> Result<()> {
+        {
+            let mut hold = self.hold.lock().unwrap();
+            if hold.paused {
+                return Ok(());
+            }
+            hold.processing = true;
+            hold.thread = Some(thread::current().id());
+        }
+
+        let result = self.process_requests();
+        let inflight = self.inflight();
+        let mut hold = self.hold.lock().unwrap();
+        hold.processing = false;
+        hold.inflight = inflight;
+        result
+    }
+
+    fn process_requests(&mut self) -> Result<()> {
         let mut req_queue = Vec::new();
         let mut req_index = 0;
         let mut last_aio_req_index = 0;
@@ -408,8 +646,10 @@ impl BlockIoHandler {
                     req_index += 1;
                 }
                 Err(e) => {
+                    // Go on popping, queue_evt is edge-triggered and no more
+                    // notification comes for the remaining requests.
                     error!("failed to create request, err {:#?}", e);
-                    break;
+                    continue;
                 }
             };
         }
@@ -424,7 +664,7 @@ impl BlockIoHandler {
                         _ => 0u32,
                     };
 
-                    let aiocompletecb = AioCompleteCb::new(
+                    let mut aiocompletecb = AioCompleteCb::new(
                         self.queue.clone(),
                         self.mem_space.clone(),
                         req.desc_index,
@@ -433,19 +673,23 @@ impl BlockIoHandler {
                         Some(self.interrupt_cb.clone()),
                         self.driver_features,
                     );
+                    aiocompletecb.written = req.written.clone();
 
                     match req.execute(
                         aio,
                         disk_img,
                         self.disk_sectors,
                         &self.serial_num,
-                        self.direct,
+                        self.io_opts,
                         last_aio_req_index == req_index,
                         aiocompletecb,
                     ) {
                         Ok(v) => {
                             if v == 1 {
                                 // get device id
+                                for (addr, len) in req.written.iter() {
+                                    self.mem_space.mark_dirty(*addr, *len);
+                                }
                                 self.mem_space
                                     .write_object(&VIRTIO_BLK_S_OK, req.in_header)?;
                                 self.queue.lock().unwrap().vring.add_used(
@@ -492,93 +736,80 @@ impl BlockIoHandler {
 
     /// Build an aio context.
     pub fn build_aio(&self) -> Result<Box<Aio<AioCompleteCb>>> {
+        let hold = self.hold.clone();
         let complete_func = Arc::new(Box::new(move |aiocb: &AioCb<AioCompleteCb>, ret: i64| {
-            let status = if ret < 0 {
-                ret
-            } else {
-                i64::from(VIRTIO_BLK_S_OK)
-            };
-            let complete_cb = &aiocb.iocompletecb;
-
-            if complete_cb
-                .mem_space
-                .write_object(&status, complete_cb.req_status_addr)
-                .is_err()
-            {
-                error!("Failed to write object(aio completion)");
-                return;
-            }
-
-            let mut queue_lock = complete_cb.queue.lock().unwrap();
-            if queue_lock
-                .vring
-                .add_used(
-                    &complete_cb.mem_space,
-                    complete_cb.desc_index,
-                    complete_cb.rw_len,
-                )
-                .is_err()
-            {
-                error!(
-                    "Failed to add used ring(aio completion), index {}, len {}",
-                    complete_cb.desc_index, complete_cb.rw_len
-                );
-                return;
-            }
-
-            let trigger_interrupt_status = queue_lock
-                .vring
-                .should_notify(&complete_cb.mem_space, complete_cb.driver_features);
-            if trigger_interrupt_status
-                && (*complete_cb.interrupt_cb.as_ref().unwrap())(VIRTIO_MMIO_INT_VRING).is_err()
-            {
-                error!("Failed to trigger interrupt(aio completion)");
-            }
+            complete_aio(&hold, &aiocb.iocompletecb, ret);
         }) as AioCompleteFunc<AioCompleteCb>);
 
         Ok(Box::new(Aio::new(complete_func)?))
     }
 
-    fn add_event_notifiers(mut self) -> Result<()> {
+    fn add_event_notifiers(mut self, iothread: Option<&str>) -> Result<()> {
         self.aio = Some(self.build_aio()?);
-        MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
-            Mutex::new(self),
-        )))?;
+        MainLoop::update_event_in(
+            iothread,
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(self))),
+        )?;
 
         Ok(())
     }
 
+    /// Number of IO requests submitted to the image and not reaped yet.
+    fn inflight(&self) -> usize {
+        self.aio
+            .as_ref()
+            .map_or(0, |aio| aio.aio_in_queue.len + aio.aio_in_flight.len)
+    }
+
+    /// Record the number of IO in flight after it's reaped, and complete
+    /// the pending removal of image once all the IO submitted to it is
+    /// complete.
+    fn complete_unplug(&mut self) {
+        let inflight = self.inflight();
+        self.hold.lock().unwrap().inflight = inflight;
+        if let Some(unplug) = self.pending_unplug.as_mut() {
+            if unplug.try_complete(inflight) {
+                self.pending_unplug = None;
+            }
+        }
+    }
+
     fn update_evt_handler(&mut self) {
         match self.receiver.recv() {
-            Ok((image, disk_sectors, serial_num, direct)) => {
+            Ok((image, disk_sectors, serial_num, io_opts, unplug_done)) => {
+                if let Some(done) = unplug_done {
+                    self.pending_unplug = Some(PendingUnplug {
+                        disk_image: self.disk_image.take(),
+                        done: Some(done),
+                    });
+                }
                 self.disk_sectors = disk_sectors;
                 self.disk_image = image;
                 self.serial_num = serial_num;
-                self.direct = direct;
+                self.io_opts = io_opts;
             }
             Err(_) => {
                 self.disk_sectors = 0;
                 self.disk_image = None;
                 self.serial_num = None;
-                self.direct = true;
+                self.io_opts = IoOptions::new(&DriveConfig::default());
             }
         };
 
         self.process_queue()
             .unwrap_or_else(|_| error!("Failed to handle block IO."));
+        self.complete_unplug();
     }
 }
 
-fn build_event_notifier(fd: RawFd, handler: Box<NotifierCallback>) -> EventNotifier {
+fn build_event_notifier(
+    fd: RawFd,
+    event: EventSet,
+    handler: Box<NotifierCallback>,
+) -> EventNotifier {
     let mut handlers = Vec::new();
     handlers.push(Arc::new(Mutex::new(handler)));
-    EventNotifier::new(
-        NotifierOperation::AddShared,
-        fd,
-        None,
-        EventSet::IN,
-        handlers,
-    )
+    EventNotifier::new(NotifierOperation::AddShared, fd, None, event, handlers)
 }
 
 impl EventNotifierHelper for BlockIoHandler {
@@ -593,9 +824,15 @@ impl EventNotifierHelper for BlockIoHandler {
             cloned_block_io.lock().unwrap().update_evt_handler();
             None
         });
-        notifiers.push(build_event_notifier(locked_block_io.update_evt, handler));
+        notifiers.push(build_event_notifier(
+            locked_block_io.update_evt,
+            EventSet::IN,
+            handler,
+        ));
 
-        // Register event notifier for queue_evt.
+        // Register edge-triggered event notifier for queue_evt, the eventfd
+        // is drained before processing the whole avail ring, so a kick during
+        // processing wakes the handler up again.
         let cloned_block_io = block_io.clone();
         let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
             read_fd(fd);
@@ -608,6 +845,7 @@ impl EventNotifierHelper for BlockIoHandler {
         });
         notifiers.push(build_event_notifier(
             locked_block_io.queue_evt.as_raw_fd(),
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
             handler,
         ));
 
@@ -617,14 +855,20 @@ impl EventNotifierHelper for BlockIoHandler {
             let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
                 read_fd(fd);
 
-                if let Some(aio) = &mut cloned_block_io.lock().unwrap().aio {
+                let mut locked_block_io = cloned_block_io.lock().unwrap();
+                if let Some(aio) = &mut locked_block_io.aio {
                     aio.handle()
                         .map_err(|e| error!("Failed to handle aio, {}", e))
                         .ok();
                 }
+                locked_block_io.complete_unplug();
                 None
             });
-            notifiers.push(build_event_notifier(aio.fd.as_raw_fd(), handler));
+            notifiers.push(build_event_notifier(
+                aio.fd.as_raw_fd(),
+                EventSet::IN,
+                handler,
+            ));
         }
 
         notifiers
@@ -651,6 +895,11 @@ pub struct Block {
     sender: Option<Sender<SenderConfig>>,
     /// Eventfd for config space update.
     update_evt: EventFd,
+    /// State shared with the IO handler of the activated device.
+    io_hold: Option<Arc<Mutex<AioHold>>>,
+    /// Eventfd of the virtqueue, kicked to handle the requests made
+    /// available while VM is paused.
+    queue_evt: Option<EventFd>,
 }
 
 impl Block {
@@ -670,6 +919,8 @@ impl Block {
             interrupt_cb: None,
             sender: None,
             update_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            io_hold: None,
+            queue_evt: None,
         }
     }
 
@@ -692,6 +943,75 @@ impl Block {
 
         Ok(())
     }
+
+    /// Send the realized image to the IO handler if the device is activated,
+    /// and notify guest of the config change.
+    ///
+    /// # Arguments
+    ///
+    /// * `unplug_done` - Callback called when the removal of image is
+    ///   complete, if the image is removed.
+    fn notify_config_update(&mut self, unplug_done: Option<Box<UnplugDone>>) -> Result<()> {
+        if let Some(sender) = &self.sender {
+            sender
+                .send((
+                    self.disk_image.take(),
+                    self.disk_sectors,
+                    self.blk_cfg.serial_num.clone(),
+                    IoOptions::new(&self.blk_cfg),
+                    unplug_done,
+                ))
+                .chain_err(|| ErrorKind::ChannelSend("image fd".to_string()))?;
+
+            self.update_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb(VIRTIO_MMIO_INT_CONFIG).chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// State of block device saved in snapshot.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct BlockState {
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Config space of the block device.
+    config_space: [u8; CONFIG_SPACE_SIZE],
+}
+
+impl ByteCode for BlockState {}
+
+impl StateTransfer for Block {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        let mut state = BlockState {
+            device_features: self.device_features,
+            driver_features: self.driver_features,
+            ..Default::default()
+        };
+        // Config space is built when the device is realized.
+        let len = cmp::min(self.config_space.len(), CONFIG_SPACE_SIZE);
+        state.config_space[..len].copy_from_slice(&self.config_space[..len]);
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        let state: BlockState = state_from_bytes(state)?;
+        check_restored_features(state.device_features, self.device_features)?;
+        self.device_features = state.device_features;
+        self.driver_features = state.driver_features;
+        let len = cmp::min(self.config_space.len(), CONFIG_SPACE_SIZE);
+        self.config_space[..len].copy_from_slice(&state.config_space[..len]);
+        Ok(())
+    }
 }
 
 impl VirtioDevice for Block {
@@ -714,24 +1034,20 @@ impl VirtioDevice for Block {
         if self.blk_cfg.path_on_host != "" {
             self.disk_image = None;
 
-            let mut file = if self.blk_cfg.direct {
-                OpenOptions::new()
-                    .read(true)
-                    .write(!self.blk_cfg.read_only)
-                    .custom_flags(libc::O_DIRECT)
-                    .open(&self.blk_cfg.path_on_host)
-                    .chain_err(|| {
-                        format!("failed to open the file {}", self.blk_cfg.path_on_host)
-                    })?
-            } else {
-                OpenOptions::new()
-                    .read(true)
-                    .write(!self.blk_cfg.read_only)
-                    .open(&self.blk_cfg.path_on_host)
-                    .chain_err(|| {
-                        format!("failed to open the file {}", self.blk_cfg.path_on_host)
-                    })?
-            };
+            let mut flags = 0;
+            if self.blk_cfg.direct {
+                flags |= libc::O_DIRECT;
+            }
+            // Without write cache, every write is synced before completed.
+            if !self.blk_cfg.writeback {
+                flags |= libc::O_DSYNC;
+            }
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(!self.blk_cfg.read_only)
+                .custom_flags(flags)
+                .open(&self.blk_cfg.path_on_host)
+                .chain_err(|| format!("failed to open the file {}", self.blk_cfg.path_on_host))?;
 
             disk_size = file
                 .seek(SeekFrom::End(0))
@@ -829,6 +1145,9 @@ impl VirtioDevice for Block {
         self.interrupt_cb = Some(cb.clone());
         let (sender, receiver) = channel();
         self.sender = Some(sender);
+        let hold = Arc::new(Mutex::new(AioHold::default()));
+        self.io_hold = Some(hold.clone());
+        self.queue_evt = Some(queue_evts[0].try_clone()?);
 
         let handler = BlockIoHandler {
             queue: queues.remove(0),
@@ -836,15 +1155,17 @@ impl VirtioDevice for Block {
             mem_space,
             disk_image: self.disk_image.take(),
             disk_sectors: self.disk_sectors,
-            direct: self.blk_cfg.direct,
+            io_opts: IoOptions::new(&self.blk_cfg),
             serial_num: self.blk_cfg.serial_num.clone(),
             aio: None,
             driver_features: self.driver_features,
             receiver,
             update_evt: self.update_evt.as_raw_fd(),
             interrupt_cb: cb,
+            pending_unplug: None,
+            hold,
         };
-        handler.add_event_notifiers()?;
+        handler.add_event_notifiers(self.blk_cfg.iothread.as_deref())?;
 
         Ok(())
     }
@@ -857,26 +1178,62 @@ impl VirtioDevice for Block {
         }
 
         self.realize()?;
+        self.notify_config_update(None)
+    }
 
-        if let Some(sender) = &self.sender {
-            sender
-                .send((
-                    self.disk_image.take(),
-                    self.disk_sectors,
-                    self.blk_cfg.serial_num.clone(),
-                    self.blk_cfg.direct,
-                ))
-                .chain_err(|| ErrorKind::ChannelSend("image fd".to_string()))?;
+    /// Remove the image of block device, it's closed in the IO handler once
+    /// the IO submitted to it is complete.
+    fn unplug(&mut self, done: Box<UnplugDone>) -> Result<()> {
+        self.blk_cfg = Default::default();
+        self.realize()?;
+        if self.sender.is_none() {
+            // The device is not activated, so no IO is in flight.
+            self.notify_config_update(None)?;
+            done();
+            return Ok(());
+        }
+        self.notify_config_update(Some(done))
+    }
 
-            self.update_evt
-                .write(1)
-                .chain_err(|| ErrorKind::EventFdWrite)?;
+    /// Stop popping requests, the IO in flight is reaped by the IO handler
+    /// with their completions held until the device is resumed.
+    fn pause_io(&mut self) -> Result<()> {
+        if let Some(hold) = &self.io_hold {
+            hold.lock().unwrap().paused = true;
         }
+        Ok(())
+    }
 
-        if let Some(interrupt_cb) = &self.interrupt_cb {
-            interrupt_cb(VIRTIO_MMIO_INT_CONFIG).chain_err(|| ErrorKind::EventFdWrite)?;
+    fn io_quiesced(&self) -> Result<bool> {
+        match &self.io_hold {
+            Some(hold) => hold.lock().unwrap().quiesced(),
+            None => Ok(true),
+        }
+    }
+
+    /// Publish the completions held while VM is paused, and kick the queue
+    /// to handle the requests made available meanwhile in the event loop of
+    /// the IO handler.
+    fn resume_io(&mut self) -> Result<()> {
+        let completions = match &self.io_hold {
+            Some(hold) => {
+                let mut hold = hold.lock().unwrap();
+                if !hold.paused {
+                    return Ok(());
+                }
+                hold.paused = false;
+                std::mem::replace(&mut hold.completions, Vec::new())
+            }
+            None => return Ok(()),
+        };
+        for (complete_cb, ret) in completions.iter() {
+            publish_aio(complete_cb, *ret);
         }
 
+        // Kicks while paused are consumed without processing the queue.
+        if let Some(queue_evt) = &self.queue_evt {
+            queue_evt.write(1).chain_err(|| ErrorKind::EventFdWrite)?;
+        }
         Ok(())
     }
 }
@@ -885,6 +1242,88 @@ impl VirtioDevice for Block {
 mod tests {
     pub use super::super::*;
     pub use super::*;
+    use address_space::{HostMemMapping, MemAdvice, Region};
+
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+    const DESC_TABLE: u64 = 0x0;
+    const AVAIL_RING: u64 = 0x1000;
+    const USED_RING: u64 = 0x2000;
+    /// Base of the headers of requests, each request takes 0x100 bytes, and
+    /// its status is at offset 0x80.
+    const REQ_BASE: u64 = 0x10000;
+
+    fn address_space_init() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let host_mmap =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1 << 20, MemAdvice::default()).unwrap());
+        sys_space
+            .root()
+            .add_subregion(
+                Region::init_ram_region(host_mmap.clone()),
+                host_mmap.start_address().raw_value(),
+            )
+            .unwrap();
+        sys_space
+    }
+
+    fn status_addr(index: u16) -> GuestAddress {
+        GuestAddress(REQ_BASE + u64::from(index) * 0x100 + 0x80)
+    }
+
+    /// Make request `index` available to the device, which gets the id of
+    /// block device.
+    fn push_request(mem_space: &Arc<AddressSpace>, index: u16) {
+        let header_addr = REQ_BASE + u64::from(index) * 0x100;
+        let header = RequestOutHeader {
+            request_type: VIRTIO_BLK_T_GET_ID,
+            io_prio: 0,
+            sector: 0,
+        };
+        mem_space
+            .write_object(&header, GuestAddress(header_addr))
+            .unwrap();
+        mem_space
+            .write_object(&0xff_u8, status_addr(index))
+            .unwrap();
+
+        let descs = [
+            SplitVringDesc {
+                addr: GuestAddress(header_addr),
+                len: size_of::<RequestOutHeader>() as u32,
+                flags: VIRTQ_DESC_F_NEXT,
+                next: index * 2 + 1,
+            },
+            SplitVringDesc {
+                addr: status_addr(index),
+                len: 1,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            let desc_index = u64::from(index) * 2 + i as u64;
+            mem_space
+                .write_object(desc, GuestAddress(DESC_TABLE + desc_index * 16))
+                .unwrap();
+        }
+        mem_space
+            .write_object(
+                &(index * 2),
+                GuestAddress(AVAIL_RING + 4 + u64::from(index) * 2),
+            )
+            .unwrap();
+        mem_space
+            .write_object(&(index + 1), GuestAddress(AVAIL_RING + 2))
+            .unwrap();
+    }
+
+    fn used_idx(mem_space: &Arc<AddressSpace>) -> u16 {
+        mem_space
+            .read_object::<u16>(GuestAddress(USED_RING + 2))
+            .unwrap()
+    }
 
     #[test]
     fn test_block_init() {
@@ -960,6 +1399,164 @@ mod tests {
         assert_eq!(block.write_config(offset, &mut data).is_ok(), true);
     }
 
+    #[test]
+    fn test_block_snapshot() {
+        let mut block = Block::new();
+        block.realize().unwrap();
+        let write_data: Vec<u8> = vec![7; 4];
+        block.write_config(0x00, &write_data).unwrap();
+
+        // test state saved in snapshot
+        let state = block.get_state_vec().unwrap();
+        let state = BlockState::from_bytes(&state).unwrap();
+        assert_eq!(state.device_features, block.device_features);
+        assert_eq!(state.config_space[..], block.config_space[..]);
+        assert_eq!(state.config_space[..4], write_data[..]);
+    }
+
+    #[test]
+    fn test_unplug_drain() {
+        let record = Arc::new(Mutex::new(Vec::new()));
+        let record_clone = record.clone();
+        let mut unplug = PendingUnplug {
+            disk_image: Some(File::open("/dev/null").unwrap()),
+            done: Some(Box::new(move || {
+                record_clone.lock().unwrap().push("DEVICE_DELETED");
+            })),
+        };
+
+        // The removal waits for the slow IO to be drained.
+        assert!(!unplug.try_complete(2));
+        record.lock().unwrap().push("IO 0 complete");
+        assert!(!unplug.try_complete(1));
+        assert!(unplug.disk_image.is_some());
+        record.lock().unwrap().push("IO 1 complete");
+        assert!(unplug.try_complete(0));
+        assert!(unplug.disk_image.is_none());
+        assert_eq!(
+            *record.lock().unwrap(),
+            vec!["IO 0 complete", "IO 1 complete", "DEVICE_DELETED"]
+        );
+
+        // `done` is never called twice.
+        assert!(unplug.try_complete(0));
+        assert_eq!(record.lock().unwrap().len(), 3);
+
+        // Block device not activated is removed at once.
+        let mut block = Block::new();
+        let record_clone = record.clone();
+        block
+            .unplug(Box::new(move || {
+                record_clone.lock().unwrap().push("DEVICE_DELETED");
+            }))
+            .unwrap();
+        assert_eq!(record.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_pause_io() {
+        let mem_space = address_space_init();
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE_BLK);
+        queue_config.desc_table = GuestAddress(DESC_TABLE);
+        queue_config.avail_ring = GuestAddress(AVAIL_RING);
+        queue_config.used_ring = GuestAddress(USED_RING);
+        queue_config.size = QUEUE_SIZE_BLK;
+        queue_config.ready = true;
+        let queue = Arc::new(Mutex::new(
+            Queue::new(queue_config, QUEUE_TYPE_SPLIT_VRING).unwrap(),
+        ));
+        let interrupts = Arc::new(AtomicU32::new(0));
+        let cloned_interrupts = interrupts.clone();
+        let interrupt_cb = Arc::new(Box::new(move |_: u32| {
+            cloned_interrupts.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }) as VirtioBlockInterrupt);
+        let (_sender, receiver) = channel();
+        let update_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let hold = Arc::new(Mutex::new(AioHold::default()));
+        let queue_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut block = Block::new();
+        block.io_hold = Some(hold.clone());
+        block.queue_evt = Some(queue_evt.try_clone().unwrap());
+        let mut handler = BlockIoHandler {
+            queue: queue.clone(),
+            queue_evt,
+            mem_space: mem_space.clone(),
+            disk_image: None,
+            disk_sectors: 0,
+            serial_num: None,
+            io_opts: IoOptions::new(&DriveConfig::default()),
+            aio: None,
+            driver_features: 0,
+            receiver,
+            update_evt: update_evt.as_raw_fd(),
+            interrupt_cb: interrupt_cb.clone(),
+            pending_unplug: None,
+            hold: hold.clone(),
+        };
+
+        // Requests 0 and 1 are submitted to the busy backend before VM is
+        // paused.
+        let mut inflight = Vec::new();
+        for index in 0..2 {
+            push_request(&mem_space, index);
+            let elem = queue
+                .lock()
+                .unwrap()
+                .vring
+                .pop_avail(&mem_space, 0)
+                .unwrap();
+            inflight.push(AioCompleteCb::new(
+                queue.clone(),
+                mem_space.clone(),
+                elem.index,
+                0,
+                status_addr(index),
+                Some(interrupt_cb.clone()),
+                0,
+            ));
+        }
+        hold.lock().unwrap().inflight = 2;
+        block.pause_io().unwrap();
+        assert!(!block.io_quiesced().unwrap());
+
+        // They are reaped by the IO handler while VM is paused, and guest
+        // kicks request 2.
+        for complete_cb in inflight.iter() {
+            complete_aio(&handler.hold, complete_cb, 0);
+        }
+        handler.complete_unplug();
+        assert!(block.io_quiesced().unwrap());
+        push_request(&mem_space, 2);
+        handler.process_queue().unwrap();
+        assert_eq!(used_idx(&mem_space), 0);
+        assert_eq!(interrupts.load(Ordering::SeqCst), 0);
+        assert_eq!(mem_space.read_object::<u8>(status_addr(0)).unwrap(), 0xff);
+
+        // The held completions are published when VM is resumed, and the
+        // event loop is woken up to handle request 2.
+        block.resume_io().unwrap();
+        assert!(!block.io_quiesced().unwrap());
+        assert_eq!(used_idx(&mem_space), 2);
+        assert!(interrupts.load(Ordering::SeqCst) > 0);
+        assert_eq!(
+            mem_space.read_object::<u8>(status_addr(1)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        assert_eq!(handler.queue_evt.read().unwrap(), 1);
+        handler.process_queue().unwrap();
+        assert_eq!(used_idx(&mem_space), 3);
+
+        // Resuming the device not paused does nothing.
+        block.resume_io().unwrap();
+        assert!(handler.queue_evt.read().is_err());
+
+        // IO in flight can't be waited for on the thread reaping it.
+        hold.lock().unwrap().inflight = 1;
+        block.pause_io().unwrap();
+        assert!(block.io_quiesced().is_err());
+    }
+
     #[test]
     fn test_serial_num_config() {
         // test get_serial_num_config method
@@ -979,4 +1576,33 @@ mod tests {
         let id_bytes = get_serial_num_config(&serial_num);
         assert_eq!(id_bytes.len(), 20);
     }
+
+    #[test]
+    fn test_io_options() {
+        let mut blk_cfg = DriveConfig::default();
+        let io_opts = IoOptions::new(&blk_cfg);
+        assert!(io_opts.uring);
+        assert!(!io_opts.no_flush);
+        assert!(io_opts.detect_zeroes == DetectZeroes::Off);
+
+        blk_cfg.direct = false;
+        assert!(!IoOptions::new(&blk_cfg).uring);
+        blk_cfg.aio = Some(AioEngine::IoUring);
+        blk_cfg.no_flush = true;
+        blk_cfg.detect_zeroes = DetectZeroes::On;
+        let io_opts = IoOptions::new(&blk_cfg);
+        assert!(io_opts.uring);
+        assert!(io_opts.no_flush);
+        assert!(io_opts.detect_zeroes == DetectZeroes::On);
+
+        let zero = vec![0_u8; 512];
+        let mut data = vec![0_u8; 512];
+        data[511] = 1;
+        let iovec = |buf: &Vec<u8>| Iovec {
+            iov_base: buf.as_ptr() as u64,
+            iov_len: buf.len() as u64,
+        };
+        assert!(iovec_is_zero(&[iovec(&zero), iovec(&zero)]));
+        assert!(!iovec_is_zero(&[iovec(&zero), iovec(&data)]));
+    }
 }
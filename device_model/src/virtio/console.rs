@@ -11,36 +11,55 @@
 // See the Mulan PSL v2 for more details.
 
 use std::cmp;
-use std::io::{Read, Write};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
-use machine_manager::config::ConsoleConfig;
+use machine_manager::chardev::Chardev;
+use machine_manager::config::{ChardevConfig, ConsoleConfig};
 use util::byte_code::ByteCode;
 use util::epoll_context::{read_fd, EventNotifier, EventNotifierHelper, NotifierOperation};
 use util::num_ops::{read_u32, write_u32};
-use util::unix::limit_permission;
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::super::micro_vm::main_loop::MainLoop;
 use super::errors::{ErrorKind, Result, ResultExt};
 use super::{
-    Queue, VirtioDevice, VIRTIO_CONSOLE_F_SIZE, VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_VRING,
+    check_restored_features, state_from_bytes, Queue, StateTransfer, VirtioDevice,
+    VIRTIO_CONSOLE_F_MULTIPORT, VIRTIO_CONSOLE_F_SIZE, VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_VRING,
     VIRTIO_TYPE_CONSOLE,
 };
 
-/// Number of virtqueues.
-const QUEUE_NUM_CONSOLE: usize = 2;
+/// Number of virtqueues of each port, and of control.
+const QUEUE_NUM_PER_PORT: usize = 2;
 /// Size of virtqueue.
 const QUEUE_SIZE_CONSOLE: u16 = 256;
 
+/// Driver is ready to handle control messages.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+/// Port is added to driver.
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+/// Port is removed from driver.
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+/// Driver is ready to use the port.
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+/// Port is used as console by driver.
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+/// Port is opened by the other side.
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+/// Name of port follows the control message.
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 pub struct VirtioConsoleConfig {
+    cols: u16,
+    rows: u16,
     max_nr_ports: u32,
     emerg_wr: u32,
 }
@@ -49,14 +68,43 @@ impl ByteCode for VirtioConsoleConfig {}
 
 impl VirtioConsoleConfig {
     /// Create configuration of virtio-console devices.
-    pub fn new() -> Self {
+    ///
+    /// # Arguments
+    ///
+    /// * `max_nr_ports` - Max number of ports, including port 0.
+    pub fn new(max_nr_ports: u32) -> Self {
         VirtioConsoleConfig {
-            max_nr_ports: 1_u32,
+            cols: 0_u16,
+            rows: 0_u16,
+            max_nr_ports,
             emerg_wr: 0_u32,
         }
     }
 }
 
+/// Control message of multiport console, refer to Virtio Spec.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioConsoleControl {
+    /// Number of port.
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+impl ByteCode for VirtioConsoleControl {}
+
+/// Get the index of receiveq and transmitq of port `nr`, the control
+/// virtqueues follow the ones of port 0.
+fn port_queues(nr: u32) -> (usize, usize) {
+    let receiveq = if nr == 0 {
+        0
+    } else {
+        (nr as usize + 1) * QUEUE_NUM_PER_PORT
+    };
+    (receiveq, receiveq + 1)
+}
+
 /// Console device's IO handle context.
 struct ConsoleHandler {
     /// Virtqueue for console input.
@@ -73,20 +121,17 @@ struct ConsoleHandler {
     interrupt_status: Arc<AtomicU32>,
     /// Bit mask of features negotiated by the backend and the frontend.
     driver_features: u64,
-    /// Unix domain socket server.
-    listener: UnixListener,
-    /// Unix stream socket got by the incoming connection.
-    client: Option<UnixStream>,
+    /// Chardev as the backend of port.
+    chardev: Arc<Mutex<Chardev>>,
 }
 
 impl ConsoleHandler {
-    #[allow(clippy::useless_asref)]
     /// Handler for console input.
     ///
     /// # Arguments
     ///
-    /// * `buffer` - where to put the input data.
-    pub fn input_handle(&mut self, buffer: &mut [u8]) -> Result<()> {
+    /// * `buffer` - the input data.
+    pub fn input_handle(&mut self, buffer: &[u8]) -> Result<()> {
         let mut queue_lock = self.input_queue.lock().unwrap();
 
         let count = buffer.len();
@@ -101,10 +146,10 @@ impl ConsoleHandler {
             let mut write_count = 0_usize;
             for elem_iov in elem.in_iovec.iter() {
                 let allow_write_count = cmp::min(write_count + elem_iov.len as usize, count);
-                let source_slice = &mut buffer[write_count..allow_write_count];
+                let source_slice = &buffer[write_count..allow_write_count];
 
                 let write_result = self.mem_space.write(
-                    &mut source_slice.as_ref(),
+                    &mut &source_slice[..],
                     elem_iov.addr,
                     source_slice.len() as u64,
                 );
@@ -113,7 +158,10 @@ impl ConsoleHandler {
                         write_count = allow_write_count;
                     }
                     Err(e) => {
-                        error!("Failed to write slice: {:?}", e);
+                        error!(
+                            "Failed to write slice: {}",
+                            error_chain::ChainedError::display_chain(&e)
+                        );
                         break;
                     }
                 }
@@ -167,16 +215,22 @@ impl ConsoleHandler {
                         read_count = allow_read_count;
                     }
                     Err(e) => {
-                        error!("Failed to read buffer: {:?}", e);
+                        error!(
+                            "Failed to read buffer: {}",
+                            error_chain::ChainedError::display_chain(&e)
+                        );
                         break;
                     }
                 };
             }
 
-            if let Some(mut client) = self.client.as_ref() {
-                if let Err(e) = client.write(&buffer[..read_count as usize]) {
-                    error!("Failed to write console output: {}.", e);
-                };
+            if let Err(e) = self
+                .chardev
+                .lock()
+                .unwrap()
+                .write(&buffer[..read_count as usize])
+            {
+                error!("Failed to write console output: {}.", e);
             }
 
             if let Err(e) = queue_lock.vring.add_used(&self.mem_space, elem.index, 0) {
@@ -187,89 +241,359 @@ impl ConsoleHandler {
 
         Ok(())
     }
+
+    /// Notifiers to remove the handler and its chardev from main loop.
+    fn delete_notifiers(&self) -> Vec<EventNotifier> {
+        let mut notifiers = self.chardev.lock().unwrap().delete_notifiers();
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::Delete,
+            self.output_queue_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            Vec::new(),
+        ));
+        notifiers
+    }
 }
 
 impl EventNotifierHelper for ConsoleHandler {
     fn internal_notifiers(console_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
-        let mut notifiers = Vec::new();
-
-        let cls_outer = console_handler.clone();
-        let handler = Box::new(move |_, _| {
-            let cls = cls_outer.clone();
-            let (stream, _) = cls.lock().unwrap().listener.accept().unwrap();
-            let listener_fd = cls.lock().unwrap().listener.as_raw_fd();
-            let stream_fd = stream.as_raw_fd();
-            cls.lock().unwrap().client = Some(stream);
-            let cls_inner = cls.clone();
-
-            let cls_mid = cls;
-            let handler = Box::new(move |event, _| {
-                if event == EventSet::IN {
-                    let cls_inner = cls_mid.clone();
-                    let mut cls_inner_lk = cls_inner.lock().unwrap();
-
-                    if let Some(client) = &cls_inner_lk.client {
-                        let mut client_inner = client.try_clone().unwrap();
-
-                        let mut buffer = [0_u8; 4096];
-                        if let Ok(nr) = client_inner.read(&mut buffer) {
-                            let _ = cls_inner_lk.input_handle(&mut buffer[..nr]);
-                        }
-                    }
+        // Input of port is passed to driver by the receiver of chardev.
+        let cls = console_handler.clone();
+        let chardev = console_handler.lock().unwrap().chardev.clone();
+        chardev
+            .lock()
+            .unwrap()
+            .set_receiver(Arc::new(move |data: &[u8]| {
+                if let Err(e) = cls.lock().unwrap().input_handle(data) {
+                    error!(
+                        "Failed to handle console input: {}",
+                        error_chain::ChainedError::display_chain(&e)
+                    );
                 }
+            }));
+        let mut notifiers = EventNotifierHelper::internal_notifiers(chardev);
 
-                if event & EventSet::HANG_UP == EventSet::HANG_UP {
-                    cls_inner.lock().unwrap().client = None;
-                    Some(vec![EventNotifier::new(
-                        NotifierOperation::Delete,
-                        stream_fd,
-                        Some(listener_fd),
-                        EventSet::IN | EventSet::HANG_UP,
-                        Vec::new(),
-                    )])
-                } else {
-                    None as Option<Vec<EventNotifier>>
-                }
-            });
-
-            Some(vec![EventNotifier::new(
-                NotifierOperation::AddShared,
-                stream_fd,
-                Some(listener_fd),
-                EventSet::IN | EventSet::HANG_UP,
-                vec![Arc::new(Mutex::new(handler))],
-            )])
+        let cls = console_handler.clone();
+        let handler = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+
+            let _ = cls.clone().lock().unwrap().output_handle();
+
+            None as Option<Vec<EventNotifier>>
         });
 
         notifiers.push(EventNotifier::new(
             NotifierOperation::AddShared,
-            console_handler.lock().unwrap().listener.as_raw_fd(),
+            console_handler.lock().unwrap().output_queue_evt.as_raw_fd(),
             None,
             EventSet::IN,
             vec![Arc::new(Mutex::new(handler))],
         ));
 
-        let cls = console_handler.clone();
-        let handler = Box::new(move |_, fd: RawFd| {
-            read_fd(fd);
+        notifiers
+    }
+}
 
-            let _ = cls.clone().lock().unwrap().output_handle();
+/// Handler of control virtqueues of multiport console.
+struct ControlHandler {
+    /// Virtqueue for control messages to driver.
+    receiveq: Arc<Mutex<Queue>>,
+    /// Eventfd of receiveq, it's kicked when driver adds buffers.
+    receiveq_evt: EventFd,
+    /// Virtqueue for control messages from driver.
+    transmitq: Arc<Mutex<Queue>>,
+    /// Eventfd of transmitq.
+    transmitq_evt: EventFd,
+    /// The address space to which the console device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for triggering interrupts.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Ports of console, indexed by their number.
+    ports: Arc<Mutex<Vec<Option<ConsolePort>>>>,
+    /// Whether driver is ready, ports are added to driver since then.
+    driver_ready: bool,
+    /// Control messages waiting for buffers of receiveq.
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl ControlHandler {
+    /// Send a control message to driver, it's queued until driver adds
+    /// buffers to receiveq.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Number of port.
+    /// * `event` - Event of control message.
+    /// * `value` - Value of control message.
+    /// * `extra` - Data following control message, such as the name of port.
+    fn send(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) -> Result<()> {
+        let mut msg = VirtioConsoleControl { id, event, value }
+            .as_bytes()
+            .to_vec();
+        msg.extend_from_slice(extra);
+        self.pending.push_back(msg);
+        self.flush()
+    }
+
+    /// Write the pending control messages to buffers of receiveq.
+    fn flush(&mut self) -> Result<()> {
+        let mut queue_lock = self.receiveq.lock().unwrap();
+        let mut notify = false;
+
+        while let Some(msg) = self.pending.front() {
+            let elem = match queue_lock
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)
+            {
+                Ok(elem) => elem,
+                Err(_) => break,
+            };
 
+            let mut write_count = 0_usize;
+            for elem_iov in elem.in_iovec.iter() {
+                let len = cmp::min(elem_iov.len as usize, msg.len() - write_count);
+                if len == 0 {
+                    break;
+                }
+                self.mem_space
+                    .write(
+                        &mut &msg[write_count..write_count + len],
+                        elem_iov.addr,
+                        len as u64,
+                    )
+                    .chain_err(|| "Failed to write console control message")?;
+                write_count += len;
+            }
+
+            queue_lock
+                .vring
+                .add_used(&self.mem_space, elem.index, write_count as u32)
+                .chain_err(|| format!("Failed to add used ring {}", elem.index))?;
+            self.pending.pop_front();
+            notify = true;
+        }
+
+        if notify {
+            self.interrupt_status
+                .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+            self.interrupt_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the control messages from driver.
+    fn handle_transmitq(&mut self) -> Result<()> {
+        loop {
+            let elem = match self
+                .transmitq
+                .lock()
+                .unwrap()
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)
+            {
+                Ok(elem) => elem,
+                Err(_) => break,
+            };
+
+            let msg = match elem.out_iovec.first() {
+                Some(elem_iov) if elem_iov.len as usize >= size_of::<VirtioConsoleControl>() => {
+                    Some(
+                        self.mem_space
+                            .read_object::<VirtioConsoleControl>(elem_iov.addr)
+                            .chain_err(|| "Failed to read console control message")?,
+                    )
+                }
+                _ => None,
+            };
+            self.transmitq
+                .lock()
+                .unwrap()
+                .vring
+                .add_used(&self.mem_space, elem.index, 0)
+                .chain_err(|| format!("Failed to add used ring {}", elem.index))?;
+
+            match msg {
+                Some(msg) => self.handle_control(msg)?,
+                None => warn!("Invalid control message of console."),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_control(&mut self, msg: VirtioConsoleControl) -> Result<()> {
+        match msg.event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if msg.value != 1 {
+                    error!("Driver failed to initialize multiport console.");
+                    return Ok(());
+                }
+                self.driver_ready = true;
+                let nrs: Vec<u32> = self
+                    .ports
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, port)| port.is_some())
+                    .map(|(nr, _)| nr as u32)
+                    .collect();
+                for nr in nrs {
+                    self.send(nr, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[])?;
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                if msg.value != 1 {
+                    error!("Driver failed to add port {} of console.", msg.id);
+                    return Ok(());
+                }
+                let (is_console, name) = match self.ports.lock().unwrap().get(msg.id as usize) {
+                    Some(Some(port)) => (port.is_console, port.name.clone()),
+                    _ => {
+                        warn!("Driver is ready for unknown port {} of console.", msg.id);
+                        return Ok(());
+                    }
+                };
+                if is_console {
+                    self.send(msg.id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[])?;
+                }
+                if let Some(name) = name {
+                    self.send(msg.id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes())?;
+                }
+                // Output of port is dropped until a client connects to its
+                // socket, so it's always open to driver.
+                self.send(msg.id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[])?;
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {}
+            event => warn!("Unknown control event {} of console.", event),
+        }
+        Ok(())
+    }
+
+    /// Notify driver that port `nr` is added or removed, it's done when the
+    /// driver gets ready if it's not yet.
+    fn notify_port(&mut self, nr: u32, event: u16) -> Result<()> {
+        if self.driver_ready {
+            self.send(nr, event, 1, &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for ControlHandler {
+    fn internal_notifiers(control_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+
+        let handler = control_handler.clone();
+        let transmitq_handler = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(e) = handler.lock().unwrap().handle_transmitq() {
+                error!(
+                    "Failed to handle console control messages: {}",
+                    error_chain::ChainedError::display_chain(&e)
+                );
+            }
             None as Option<Vec<EventNotifier>>
         });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            control_handler.lock().unwrap().transmitq_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(transmitq_handler))],
+        ));
 
+        let handler = control_handler.clone();
+        let receiveq_handler = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(e) = handler.lock().unwrap().flush() {
+                error!(
+                    "Failed to send console control messages: {}",
+                    error_chain::ChainedError::display_chain(&e)
+                );
+            }
+            None as Option<Vec<EventNotifier>>
+        });
         notifiers.push(EventNotifier::new(
             NotifierOperation::AddShared,
-            console_handler.lock().unwrap().output_queue_evt.as_raw_fd(),
+            control_handler.lock().unwrap().receiveq_evt.as_raw_fd(),
             None,
             EventSet::IN,
-            vec![Arc::new(Mutex::new(handler))],
+            vec![Arc::new(Mutex::new(receiveq_handler))],
         ));
 
         notifiers
     }
 }
 
+/// Port of console, whose backend is a chardev.
+struct ConsolePort {
+    /// Id of port, port 0 has the id of console.
+    id: String,
+    /// Name of port, guest creates `/dev/virtio-ports/<name>` for it.
+    name: Option<String>,
+    /// Whether the port is used as console (hvc) by guest.
+    is_console: bool,
+    /// Chardev as the backend, it's opened with the port.
+    chardev: Arc<Mutex<Chardev>>,
+    /// IO handler of port, it's set once the device is activated.
+    handler: Option<Arc<Mutex<ConsoleHandler>>>,
+}
+
+impl ConsolePort {
+    fn new(
+        id: String,
+        name: Option<String>,
+        is_console: bool,
+        chardev_cfg: &ChardevConfig,
+    ) -> Result<Self> {
+        let mut chardev = Chardev::new(chardev_cfg);
+        chardev
+            .realize()
+            .chain_err(|| format!("Failed to open chardev of port {}", id))?;
+
+        Ok(ConsolePort {
+            id,
+            name,
+            is_console,
+            chardev: Arc::new(Mutex::new(chardev)),
+            handler: None,
+        })
+    }
+
+    /// Remove the handler from main loop, and close the chardev.
+    fn teardown(self) -> Result<()> {
+        if let Some(handler) = self.handler {
+            MainLoop::update_event(handler.lock().unwrap().delete_notifiers())?;
+        }
+        self.chardev.lock().unwrap().cleanup();
+        Ok(())
+    }
+}
+
+/// Context of activated console, it's used to start the handler of port
+/// added after activation.
+struct ActivatedContext {
+    /// The address space to which the console device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for triggering interrupts.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// All virtqueues of device.
+    queues: Vec<Arc<Mutex<Queue>>>,
+    /// Eventfds of all virtqueues.
+    queue_evts: Vec<EventFd>,
+    /// Handler of control virtqueues, `None` if multiport is not negotiated.
+    control: Option<Arc<Mutex<ControlHandler>>>,
+}
+
 /// Virtio console device structure.
 pub struct Console {
     /// Virtio configuration.
@@ -278,8 +602,10 @@ pub struct Console {
     device_features: u64,
     /// Bit mask of features negotiated by the backend and the frontend.
     driver_features: u64,
-    /// UnixListener for virtio-console to communicate in host.
-    listener: UnixListener,
+    /// Ports indexed by their number, port 0 is created with the device.
+    ports: Arc<Mutex<Vec<Option<ConsolePort>>>>,
+    /// Context of device, it's set once the device is activated.
+    activated: Option<ActivatedContext>,
 }
 
 impl Console {
@@ -288,27 +614,224 @@ impl Console {
     /// # Arguments
     ///
     /// * `console_cfg` - Device configuration set by user.
-    pub fn new(console_cfg: ConsoleConfig) -> Self {
-        let path = console_cfg.socket_path;
-        let listener = UnixListener::bind(path.as_str())
-            .unwrap_or_else(|_| panic!("Failed to bind socket {}", path));
-
-        limit_permission(path.as_str())
-            .unwrap_or_else(|_| panic!("Failed to change file permission for {}", path));
+    /// * `chardev_cfg` - Config of chardev as the backend of port 0.
+    pub fn new(console_cfg: ConsoleConfig, chardev_cfg: &ChardevConfig) -> Self {
+        let max_ports = cmp::max(console_cfg.max_ports, 1);
+        let port = ConsolePort::new(console_cfg.console_id, None, true, chardev_cfg)
+            .unwrap_or_else(|e| panic!("{}", error_chain::ChainedError::display_chain(&e)));
+        let mut ports: Vec<Option<ConsolePort>> = (0..max_ports).map(|_| None).collect();
+        ports[0] = Some(port);
 
         Console {
-            config: Arc::new(Mutex::new(VirtioConsoleConfig::new())),
+            config: Arc::new(Mutex::new(VirtioConsoleConfig::new(max_ports))),
             device_features: 0_u64,
             driver_features: 0_u64,
-            listener,
+            ports: Arc::new(Mutex::new(ports)),
+            activated: None,
         }
     }
+
+    /// Whether ports can be added to the console.
+    pub fn is_multiport(&self) -> bool {
+        self.ports.lock().unwrap().len() > 1
+    }
+
+    /// Whether the console has a port with `id`, including port 0.
+    pub fn has_port(&self, id: &str) -> bool {
+        self.ports
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .any(|port| port.id == id)
+    }
+
+    /// Add a port to multiport console, and notify driver if it's ready.
+    /// The number of port is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of port.
+    /// * `name` - Name of port shown in guest.
+    /// * `chardev_cfg` - Config of chardev as the backend of port.
+    /// * `is_console` - Whether the port is used as console by guest.
+    ///
+    /// # Errors
+    ///
+    /// Return error if the console isn't multiport or has no free port, or
+    /// the id, name or chardev is used by another port, or the chardev can't
+    /// be opened.
+    pub fn add_port(
+        &mut self,
+        id: &str,
+        name: Option<String>,
+        chardev_cfg: &ChardevConfig,
+        is_console: bool,
+    ) -> Result<u32> {
+        let nr = {
+            let ports = self.ports.lock().unwrap();
+            if ports.len() <= 1 {
+                bail!("Console doesn't support multiple ports, set max_ports of it");
+            }
+            for port in ports.iter().flatten() {
+                if port.id == id {
+                    bail!("Duplicate ID '{}' for device", id);
+                }
+                if name.is_some() && port.name == name {
+                    bail!("Port name '{}' is already used", name.unwrap());
+                }
+                if port.chardev.lock().unwrap().id == chardev_cfg.id {
+                    bail!("Chardev '{}' is already used", chardev_cfg.id);
+                }
+            }
+            match ports.iter().position(Option::is_none) {
+                Some(nr) => nr as u32,
+                None => bail!("No free port, all {} ports are used", ports.len()),
+            }
+        };
+
+        let port = ConsolePort::new(id.to_string(), name, is_console, chardev_cfg)?;
+        self.ports.lock().unwrap()[nr as usize] = Some(port);
+        let result = self.start_port(nr).and_then(|_| match self.control() {
+            Some(control) => control
+                .lock()
+                .unwrap()
+                .notify_port(nr, VIRTIO_CONSOLE_DEVICE_ADD),
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            let port = self.ports.lock().unwrap()[nr as usize].take();
+            if let Some(port) = port {
+                port.teardown()?;
+            }
+            return Err(e);
+        }
+
+        Ok(nr)
+    }
+
+    /// Remove the port with `id` from console, and notify driver if it's
+    /// ready. The backend of port is closed.
+    ///
+    /// # Errors
+    ///
+    /// Return error if there is no such port, or it's port 0.
+    pub fn del_port(&mut self, id: &str) -> Result<()> {
+        let nr = self
+            .ports
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|port| matches!(port, Some(port) if port.id == id))
+            .ok_or_else(|| format!("Port '{}' not found", id))?;
+        if nr == 0 {
+            bail!("Port 0 of console '{}' can't be removed", id);
+        }
+
+        if let Some(control) = self.control() {
+            control
+                .lock()
+                .unwrap()
+                .notify_port(nr as u32, VIRTIO_CONSOLE_DEVICE_REMOVE)?;
+        }
+        let port = self.ports.lock().unwrap()[nr].take();
+        if let Some(port) = port {
+            port.teardown()?;
+        }
+        Ok(())
+    }
+
+    fn control(&self) -> Option<Arc<Mutex<ControlHandler>>> {
+        self.activated.as_ref().and_then(|ctx| ctx.control.clone())
+    }
+
+    /// Start the IO handler of port `nr` if the device is activated.
+    fn start_port(&self, nr: u32) -> Result<()> {
+        let ctx = match self.activated.as_ref() {
+            Some(ctx) => ctx,
+            None => return Ok(()),
+        };
+        let (receiveq, transmitq) = port_queues(nr);
+        if transmitq >= ctx.queues.len() {
+            bail!("No virtqueue for port {} of console", nr);
+        }
+        let chardev = match self.ports.lock().unwrap()[nr as usize].as_ref() {
+            Some(port) => port.chardev.clone(),
+            None => bail!("Port {} of console not found", nr),
+        };
+
+        let handler = Arc::new(Mutex::new(ConsoleHandler {
+            input_queue: ctx.queues[receiveq].clone(),
+            output_queue: ctx.queues[transmitq].clone(),
+            output_queue_evt: ctx.queue_evts[transmitq].try_clone()?,
+            mem_space: ctx.mem_space.clone(),
+            interrupt_evt: ctx.interrupt_evt.try_clone()?,
+            interrupt_status: ctx.interrupt_status.clone(),
+            driver_features: self.driver_features,
+            chardev,
+        }));
+        MainLoop::update_event(EventNotifierHelper::internal_notifiers(handler.clone()))?;
+
+        if let Some(port) = self.ports.lock().unwrap()[nr as usize].as_mut() {
+            port.handler = Some(handler);
+        }
+        Ok(())
+    }
+}
+
+/// State of console device saved in snapshot.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct ConsoleState {
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Virtio configuration.
+    config: VirtioConsoleConfig,
+    /// Number of ports, including the hot added ones.
+    nr_ports: u32,
+}
+
+impl ByteCode for ConsoleState {}
+
+impl StateTransfer for Console {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        let nr_ports = self.ports.lock().unwrap().iter().flatten().count();
+        let state = ConsoleState {
+            device_features: self.device_features,
+            driver_features: self.driver_features,
+            config: *self.config.lock().unwrap(),
+            nr_ports: nr_ports as u32,
+        };
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        let state: ConsoleState = state_from_bytes(state)?;
+        check_restored_features(state.device_features, self.device_features)?;
+        let nr_ports = self.ports.lock().unwrap().iter().flatten().count();
+        if state.nr_ports as usize != nr_ports {
+            bail!(
+                "Console has {} ports in snapshot, but {} ports are configured",
+                state.nr_ports,
+                nr_ports
+            );
+        }
+        self.device_features = state.device_features;
+        self.driver_features = state.driver_features;
+        *self.config.lock().unwrap() = state.config;
+        Ok(())
+    }
 }
 
 impl VirtioDevice for Console {
     /// Realize vhost virtio network device.
     fn realize(&mut self) -> Result<()> {
         self.device_features = 1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_SIZE;
+        if self.is_multiport() {
+            self.device_features |= 1_u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+        }
 
         Ok(())
     }
@@ -320,7 +843,12 @@ impl VirtioDevice for Console {
 
     /// Get the count of virtio device queues.
     fn queue_num(&self) -> usize {
-        QUEUE_NUM_CONSOLE
+        if self.is_multiport() {
+            // Port 0, control and the other ports.
+            (self.ports.lock().unwrap().len() + 1) * QUEUE_NUM_PER_PORT
+        } else {
+            QUEUE_NUM_PER_PORT
+        }
     }
 
     /// Get the queue size of virtio device.
@@ -372,26 +900,53 @@ impl VirtioDevice for Console {
         mem_space: Arc<AddressSpace>,
         interrupt_evt: EventFd,
         interrupt_status: Arc<AtomicU32>,
-        mut queues: Vec<Arc<Mutex<Queue>>>,
-        mut queue_evts: Vec<EventFd>,
+        queues: Vec<Arc<Mutex<Queue>>>,
+        queue_evts: Vec<EventFd>,
     ) -> Result<()> {
-        queue_evts.remove(0); // input_queue_evt never used
+        let multiport = self.driver_features & (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT) != 0;
+        let control = if multiport && queues.len() >= QUEUE_NUM_PER_PORT * 2 {
+            let (receiveq, transmitq) = (QUEUE_NUM_PER_PORT, QUEUE_NUM_PER_PORT + 1);
+            let handler = Arc::new(Mutex::new(ControlHandler {
+                receiveq: queues[receiveq].clone(),
+                receiveq_evt: queue_evts[receiveq].try_clone()?,
+                transmitq: queues[transmitq].clone(),
+                transmitq_evt: queue_evts[transmitq].try_clone()?,
+                mem_space: mem_space.clone(),
+                interrupt_evt: interrupt_evt.try_clone()?,
+                interrupt_status: interrupt_status.clone(),
+                driver_features: self.driver_features,
+                ports: self.ports.clone(),
+                driver_ready: false,
+                pending: VecDeque::new(),
+            }));
+            MainLoop::update_event(EventNotifierHelper::internal_notifiers(handler.clone()))?;
+            Some(handler)
+        } else {
+            None
+        };
 
-        let handler = ConsoleHandler {
-            input_queue: queues.remove(0),
-            output_queue: queues.remove(0),
-            output_queue_evt: queue_evts.remove(0),
+        self.activated = Some(ActivatedContext {
             mem_space,
-            interrupt_evt: interrupt_evt.try_clone()?,
+            interrupt_evt,
             interrupt_status,
-            driver_features: self.driver_features,
-            listener: self.listener.try_clone()?,
-            client: None,
-        };
+            queues,
+            queue_evts,
+            control,
+        });
 
-        MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
-            Mutex::new(handler),
-        )))?;
+        // Only port 0 works without multiport.
+        let nrs: Vec<u32> = self
+            .ports
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(nr, port)| port.is_some() && (multiport || *nr == 0))
+            .map(|(nr, _)| nr as u32)
+            .collect();
+        for nr in nrs {
+            self.start_port(nr)?;
+        }
 
         Ok(())
     }
@@ -401,16 +956,27 @@ impl VirtioDevice for Console {
 mod tests {
     pub use super::super::*;
     pub use super::*;
+    use machine_manager::config::ChardevType;
     use std::fs::remove_file;
     use std::mem::size_of;
 
+    fn socket_chardev(id: &str, path: &str) -> ChardevConfig {
+        ChardevConfig {
+            id: id.to_string(),
+            backend: ChardevType::Socket {
+                path: path.to_string(),
+            },
+        }
+    }
+
     #[test]
     fn test_set_driver_features() {
         let console_cfg = ConsoleConfig {
             console_id: "console".to_string(),
-            socket_path: "test_console.sock".to_string(),
+            chardev: "chr0".to_string(),
+            max_ports: 1,
         };
-        let mut console = Console::new(console_cfg);
+        let mut console = Console::new(console_cfg, &socket_chardev("chr0", "test_console.sock"));
 
         //If the device feature is 0, all driver features are not supported.
         console.device_features = 0;
@@ -462,9 +1028,10 @@ mod tests {
     fn test_read_config() {
         let console_cfg = ConsoleConfig {
             console_id: "console".to_string(),
-            socket_path: "test_console1.sock".to_string(),
+            chardev: "chr0".to_string(),
+            max_ports: 1,
         };
-        let console = Console::new(console_cfg);
+        let console = Console::new(console_cfg, &socket_chardev("chr0", "test_console1.sock"));
 
         //The offset of configuration that needs to be read exceeds the maximum
         let offset = size_of::<VirtioConsoleConfig>() as u64;
@@ -472,13 +1039,13 @@ mod tests {
         assert_eq!(console.read_config(offset, &mut read_data).is_ok(), false);
 
         //Check the configuration that needs to be read
-        let offset = 0_u64;
+        let offset = 4_u64;
         let mut read_data: Vec<u8> = vec![0; 8];
         let expect_data: Vec<u8> = vec![1, 0, 0, 0, 0, 0, 0, 0];
         assert_eq!(console.read_config(offset, &mut read_data).is_ok(), true);
         assert_eq!(read_data, expect_data);
 
-        let offset = 0_u64;
+        let offset = 4_u64;
         let mut read_data: Vec<u8> = vec![0; 1];
         let expect_data: Vec<u8> = vec![1];
         assert_eq!(console.read_config(offset, &mut read_data).is_ok(), true);
@@ -487,4 +1054,140 @@ mod tests {
         //Clean up the test environment
         remove_file("test_console1.sock").unwrap();
     }
+
+    #[test]
+    fn test_console_ports() {
+        let console_cfg = ConsoleConfig {
+            console_id: "console".to_string(),
+            chardev: "chr0".to_string(),
+            max_ports: 3,
+        };
+        let mut console = Console::new(console_cfg, &socket_chardev("chr0", "test_console2.sock"));
+        console.realize().unwrap();
+        assert!(console.is_multiport());
+        assert_ne!(
+            console.device_features & (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT),
+            0
+        );
+        // Port 0 and 2 ports, plus control.
+        assert_eq!(console.queue_num(), 8);
+        assert_eq!(port_queues(0), (0, 1));
+        assert_eq!(port_queues(1), (4, 5));
+        assert_eq!(port_queues(2), (6, 7));
+
+        // Ports are numbered from 1, ids and names can't be duplicated.
+        assert_eq!(
+            console
+                .add_port(
+                    "agent",
+                    Some("org.qemu.guest_agent.0".to_string()),
+                    &socket_chardev("chr1", "test_port1.sock"),
+                    false
+                )
+                .unwrap(),
+            1
+        );
+        assert!(console.has_port("agent"));
+        assert!(console
+            .add_port(
+                "agent",
+                None,
+                &socket_chardev("chr2", "test_port2.sock"),
+                false
+            )
+            .is_err());
+        assert!(console
+            .add_port(
+                "console",
+                None,
+                &socket_chardev("chr2", "test_port2.sock"),
+                true
+            )
+            .is_err());
+        assert!(console
+            .add_port(
+                "port2",
+                Some("org.qemu.guest_agent.0".to_string()),
+                &socket_chardev("chr2", "test_port2.sock"),
+                false
+            )
+            .is_err());
+        // Chardev can't be used by two ports, and the socket can't be bound
+        // twice.
+        assert!(console
+            .add_port(
+                "port2",
+                None,
+                &socket_chardev("chr1", "test_port2.sock"),
+                false
+            )
+            .is_err());
+        assert!(console
+            .add_port(
+                "port2",
+                None,
+                &socket_chardev("chr2", "test_port1.sock"),
+                false
+            )
+            .is_err());
+        assert_eq!(
+            console
+                .add_port(
+                    "port2",
+                    None,
+                    &socket_chardev("chr2", "test_port2.sock"),
+                    true
+                )
+                .unwrap(),
+            2
+        );
+        assert!(console
+            .add_port(
+                "port3",
+                None,
+                &socket_chardev("chr3", "test_port3.sock"),
+                false
+            )
+            .is_err());
+
+        // The number and the socket of removed port can be reused.
+        console.del_port("agent").unwrap();
+        assert!(!console.has_port("agent"));
+        assert!(console.del_port("agent").is_err());
+        assert!(console.del_port("console").is_err());
+        assert_eq!(
+            console
+                .add_port(
+                    "port3",
+                    None,
+                    &socket_chardev("chr3", "test_port1.sock"),
+                    false
+                )
+                .unwrap(),
+            1
+        );
+
+        console.del_port("port2").unwrap();
+        console.del_port("port3").unwrap();
+        remove_file("test_console2.sock").unwrap();
+
+        // Ports can't be added to the console which isn't multiport.
+        let console_cfg = ConsoleConfig {
+            console_id: "console".to_string(),
+            chardev: "chr0".to_string(),
+            max_ports: 1,
+        };
+        let mut console = Console::new(console_cfg, &socket_chardev("chr0", "test_console3.sock"));
+        assert!(!console.is_multiport());
+        assert_eq!(console.queue_num(), 2);
+        assert!(console
+            .add_port(
+                "port1",
+                None,
+                &socket_chardev("chr1", "test_port1.sock"),
+                false
+            )
+            .is_err());
+        remove_file("test_console3.sock").unwrap();
+    }
 }
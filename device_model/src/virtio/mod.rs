@@ -24,24 +24,34 @@
 //!
 //! - `x86_64`
 //! - `aarch64`
+pub mod balloon;
 pub mod block;
 pub mod console;
 pub mod net;
+pub mod pmem;
 mod queue;
+pub mod rng;
 pub mod vhost;
 
+pub use self::balloon::Balloon;
 pub use self::block::Block;
 pub use self::console::Console;
 pub use self::net::Net;
+pub use self::pmem::Pmem;
 pub use self::queue::*;
+pub use self::rng::Rng;
 
 use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
 use machine_manager::config::ConfigCheck;
+use util::byte_code::ByteCode;
 use vmm_sys_util::eventfd::EventFd;
 
+/// Callback called once a device backend is completely removed.
+pub type UnplugDone = dyn FnOnce() + Send;
+
 /// Check if the bit of features is configured.
 pub fn virtio_has_feature(feature: u64, fbit: u32) -> bool {
     feature & (1 << fbit) != 0
@@ -51,10 +61,11 @@ pub fn virtio_has_feature(feature: u64, fbit: u32) -> bool {
 pub const VIRTIO_TYPE_NET: u32 = 1;
 pub const VIRTIO_TYPE_BLOCK: u32 = 2;
 pub const VIRTIO_TYPE_CONSOLE: u32 = 3;
-pub const _VIRTIO_TYPE_RNG: u32 = 4;
-pub const _VIRTIO_TYPE_BALLOON: u32 = 5;
+pub const VIRTIO_TYPE_RNG: u32 = 4;
+pub const VIRTIO_TYPE_BALLOON: u32 = 5;
 pub const VIRTIO_TYPE_VSOCK: u32 = 19;
 pub const _VIRTIO_TYPE_FS: u32 = 26;
+pub const VIRTIO_TYPE_PMEM: u32 = 27;
 
 /// Feature Bits, refer to Virtio Spec.
 /// Negotiating this feature indicates that the driver can use descriptors
@@ -86,6 +97,8 @@ pub const VIRTIO_NET_F_HOST_TSO4: u32 = 11;
 pub const VIRTIO_NET_F_HOST_UFO: u32 = 14;
 /// Configuration cols and rows are valid.
 pub const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+/// Device has support for multiple ports, max_nr_ports is valid.
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
 /// Maximum size of any single segment is in size_max.
 pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1;
 /// Maximum number of segments in a request is in seg_max.
@@ -94,6 +107,10 @@ pub const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
 pub const VIRTIO_BLK_F_RO: u32 = 5;
 /// Cache flush command support.
 pub const VIRTIO_BLK_F_FLUSH: u32 = 9;
+/// Guest deflates the balloon when it's out of memory.
+pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
+/// Guest reports free pages by the reporting virtqueue.
+pub const VIRTIO_BALLOON_F_REPORTING: u32 = 5;
 
 /// The IO type of virtio block, refer to Virtio Spec.
 /// Read.
@@ -173,8 +190,72 @@ pub mod errors {
 }
 pub use self::errors::*;
 
+/// The trait to get the state of device, which is saved in snapshot.
+pub trait StateTransfer {
+    /// Get the state of device as bytes, which is saved while VM is paused.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the state of device can't be saved, which is the
+    /// default.
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        bail!("Device doesn't support to save state")
+    }
+
+    /// Set the state of device from the bytes saved by `get_state_vec`,
+    /// which is called before the device is activated.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - State of device saved in snapshot.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the state mismatches the device, or the device can't
+    /// be restored, which is the default.
+    fn set_state_mut(&mut self, _state: &[u8]) -> Result<()> {
+        bail!("Device doesn't support to restore state")
+    }
+}
+
+/// Copy the state of device saved in snapshot out of `bytes`.
+///
+/// # Errors
+///
+/// Return Error if the length of `bytes` mismatches the state.
+pub fn state_from_bytes<T: ByteCode>(bytes: &[u8]) -> Result<T> {
+    let mut state = T::default();
+    if bytes.len() != state.as_bytes().len() {
+        bail!(
+            "Invalid length {} of device state, expected {}",
+            bytes.len(),
+            state.as_bytes().len()
+        );
+    }
+    state.as_mut_bytes().copy_from_slice(bytes);
+    Ok(state)
+}
+
+/// Check that the features saved in snapshot are supported by the device
+/// realized with the current configuration.
+///
+/// # Arguments
+///
+/// * `saved` - Features of device saved in snapshot.
+/// * `supported` - Features of the realized device.
+pub fn check_restored_features(saved: u64, supported: u64) -> Result<()> {
+    let unsupported = saved & !supported;
+    if unsupported != 0 {
+        bail!(
+            "Features {:#x} in snapshot are not supported by device",
+            unsupported
+        );
+    }
+    Ok(())
+}
+
 /// The trait for virtio device operations.
-pub trait VirtioDevice: Send {
+pub trait VirtioDevice: Send + StateTransfer {
     /// Realize low level device.
     fn realize(&mut self) -> Result<()>;
 
@@ -232,4 +313,51 @@ pub trait VirtioDevice: Send {
     fn update_config(&mut self, _dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
         bail!("Unsupported to update configuration")
     }
+
+    /// Remove the backend of device. The removal may complete asynchronously
+    /// in the event loop of device, `done` is called once it's complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `done` - Callback called when the removal is complete.
+    fn unplug(&mut self, done: Box<UnplugDone>) -> Result<()> {
+        self.update_config(None)?;
+        done();
+        Ok(())
+    }
+
+    /// Quiesce the backend of activated device while VM is paused, no more
+    /// descriptors are popped and no more used elements are published until
+    /// `resume_io`. Devices whose queues are only processed when guest kicks
+    /// have nothing to quiesce, which is the default.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the backend fails to stop.
+    fn pause_io(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Check whether the backend is quiesced by `pause_io`, some backends
+    /// stop asynchronously, such as the IO in flight is reaped by another
+    /// thread.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the backend can't be quiesced by waiting on the
+    /// current thread.
+    fn io_quiesced(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Restart the backend quiesced by `pause_io`, the requests arrived and
+    /// completed meanwhile are handled then. It does nothing if the device
+    /// isn't paused.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the backend fails to restart.
+    fn resume_io(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
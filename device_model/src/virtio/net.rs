@@ -30,9 +30,10 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 use super::super::micro_vm::main_loop::MainLoop;
 use super::errors::{ErrorKind, Result, ResultExt};
 use super::{
-    Queue, VirtioDevice, VirtioNetHdr, VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_VRING,
-    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
-    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_TYPE_NET,
+    check_restored_features, state_from_bytes, Queue, StateTransfer, VirtioDevice, VirtioNetHdr,
+    VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_VRING, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
+    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
+    VIRTIO_NET_F_MAC, VIRTIO_TYPE_NET,
 };
 
 /// Number of virtqueues.
@@ -148,6 +149,9 @@ pub struct NetIoHandler {
     receiver: Receiver<SenderConfig>,
     /// Eventfd for config space update.
     update_evt: RawFd,
+    /// Whether VM is paused, the queues aren't handled and the tap isn't
+    /// monitored then.
+    paused: bool,
 }
 
 impl NetIoHandler {
@@ -177,7 +181,10 @@ impl NetIoHandler {
                     write_count = allow_write_count;
                 }
                 Err(e) => {
-                    error!("Failed to write slice: err {:?}", e);
+                    error!(
+                        "Failed to write slice: {}",
+                        error_chain::ChainedError::display_chain(&e)
+                    );
                     break;
                 }
             }
@@ -208,6 +215,10 @@ impl NetIoHandler {
     }
 
     fn handle_last_frame_rx(&mut self) -> Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+
         if self.handle_frame_rx().is_ok() {
             self.rx.unfinished_frame = false;
             self.handle_rx()?;
@@ -224,6 +235,10 @@ impl NetIoHandler {
     }
 
     fn handle_rx(&mut self) -> Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+
         while let Some(tap) = self.tap.as_mut() {
             match tap.read(&mut self.rx.frame_buf) {
                 Ok(count) => {
@@ -258,6 +273,10 @@ impl NetIoHandler {
     }
 
     fn handle_tx(&mut self) -> Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+
         let mut queue = self.tx.queue.lock().unwrap();
 
         while let Ok(elem) = queue.vring.pop_avail(&self.mem_space, self.driver_features) {
@@ -325,7 +344,8 @@ impl NetIoHandler {
             NotifierOperation::Delete,
             EventSet::IN,
         ));
-        if old_tap_fd != -1 {
+        // The tap isn't monitored while VM is paused.
+        if old_tap_fd != -1 && !locked_net_io.paused {
             notifiers.push(build_event_notifier(
                 old_tap_fd,
                 None,
@@ -338,6 +358,96 @@ impl NetIoHandler {
         notifiers.append(&mut EventNotifierHelper::internal_notifiers(net_io.clone()));
         Some(notifiers)
     }
+
+    /// Build the edge-triggered event notifier for tap, which receives
+    /// frames to the rx queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `net_io` - The IO handler.
+    /// * `tap_fd` - Fd of the tap.
+    fn tap_notifier(net_io: &Arc<Mutex<Self>>, tap_fd: RawFd) -> EventNotifier {
+        let cloned_net_io = net_io.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, _| {
+            let mut locked_net_io = cloned_net_io.lock().unwrap();
+            if locked_net_io.rx.unfinished_frame {
+                locked_net_io
+                    .handle_last_frame_rx()
+                    .map_err(|e| error!("Failed to handle last frame(rx), {}", e))
+                    .ok();
+            } else {
+                locked_net_io
+                    .handle_rx()
+                    .map_err(|e| error!("Failed to handle rx, {}", e))
+                    .ok();
+            }
+            None
+        });
+        build_event_notifier(
+            tap_fd,
+            Some(handler),
+            NotifierOperation::AddShared,
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
+        )
+    }
+
+    /// Stop handling the queues and park the notifier of tap, return the
+    /// notifiers to update.
+    ///
+    /// # Arguments
+    ///
+    /// * `net_io` - The IO handler.
+    fn pause(net_io: &Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut locked_net_io = net_io.lock().unwrap();
+        if locked_net_io.paused {
+            return Vec::new();
+        }
+        locked_net_io.paused = true;
+
+        let mut notifiers = Vec::new();
+        if locked_net_io.tap_fd != -1 {
+            notifiers.push(build_event_notifier(
+                locked_net_io.tap_fd,
+                None,
+                NotifierOperation::Delete,
+                EventSet::IN,
+            ));
+        }
+        notifiers
+    }
+
+    /// Handle the queues again and monitor the tap, return the notifiers to
+    /// update. The frames arrived while paused are received once the tap is
+    /// monitored.
+    ///
+    /// # Arguments
+    ///
+    /// * `net_io` - The IO handler.
+    fn resume(net_io: &Arc<Mutex<Self>>) -> Result<Vec<EventNotifier>> {
+        let mut locked_net_io = net_io.lock().unwrap();
+        if !locked_net_io.paused {
+            return Ok(Vec::new());
+        }
+        locked_net_io.paused = false;
+
+        // Kicks while paused are consumed without handling the queues.
+        locked_net_io
+            .tx
+            .queue_evt
+            .write(1)
+            .chain_err(|| ErrorKind::EventFdWrite)?;
+        locked_net_io
+            .rx
+            .queue_evt
+            .write(1)
+            .chain_err(|| ErrorKind::EventFdWrite)?;
+
+        let mut notifiers = Vec::new();
+        if locked_net_io.tap_fd != -1 {
+            notifiers.push(NetIoHandler::tap_notifier(net_io, locked_net_io.tap_fd));
+        }
+        Ok(notifiers)
+    }
 }
 
 fn build_event_notifier(
@@ -371,7 +481,8 @@ impl EventNotifierHelper for NetIoHandler {
             EventSet::IN,
         ));
 
-        // Register event notifier for rx.
+        // Register edge-triggered event notifier for rx, the eventfd is
+        // drained before the pending frame is written to the new buffers.
         let cloned_net_io = net_io.clone();
         let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
             let mut locked_net_io = cloned_net_io.lock().unwrap();
@@ -389,10 +500,11 @@ impl EventNotifierHelper for NetIoHandler {
             rx_fd,
             Some(handler),
             NotifierOperation::AddShared,
-            EventSet::IN,
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
         ));
 
-        // Register event notifier for tx.
+        // Register edge-triggered event notifier for tx, the eventfd is
+        // drained before the whole avail ring is transmitted.
         let cloned_net_io = net_io.clone();
         let handler: Box<NotifierCallback> = Box::new(move |_, fd: RawFd| {
             read_fd(fd);
@@ -409,34 +521,15 @@ impl EventNotifierHelper for NetIoHandler {
             tx_fd,
             Some(handler),
             NotifierOperation::AddShared,
-            EventSet::IN,
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
         ));
 
-        // Register event notifier for tap.
-        let cloned_net_io = net_io.clone();
+        // Register event notifier for tap, which is parked while VM is
+        // paused.
         if let Some(tap) = locked_net_io.tap.as_ref() {
-            let handler: Box<NotifierCallback> = Box::new(move |_, _| {
-                let mut locked_net_io = cloned_net_io.lock().unwrap();
-                if locked_net_io.rx.unfinished_frame {
-                    locked_net_io
-                        .handle_last_frame_rx()
-                        .map_err(|e| error!("Failed to handle last frame(rx), {}", e))
-                        .ok();
-                } else {
-                    locked_net_io
-                        .handle_rx()
-                        .map_err(|e| error!("Failed to handle rx, {}", e))
-                        .ok();
-                }
-                None
-            });
-            let tap_fd = tap.as_raw_fd();
-            notifiers.push(build_event_notifier(
-                tap_fd,
-                Some(handler),
-                NotifierOperation::AddShared,
-                EventSet::IN | EventSet::EDGE_TRIGGERED,
-            ));
+            if !locked_net_io.paused {
+                notifiers.push(NetIoHandler::tap_notifier(&net_io, tap.as_raw_fd()));
+            }
         }
 
         notifiers
@@ -459,6 +552,8 @@ pub struct Net {
     sender: Option<Sender<SenderConfig>>,
     /// Eventfd for config space update.
     update_evt: EventFd,
+    /// IO handler of the activated device.
+    io_handler: Option<Arc<Mutex<NetIoHandler>>>,
 }
 
 /// Set Mac address configured into the virtio configuration, and return features mask with
@@ -532,10 +627,45 @@ impl Net {
             device_config: VirtioNetConfig::default(),
             sender: None,
             update_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            io_handler: None,
         }
     }
 }
 
+/// State of network device saved in snapshot.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct NetState {
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Virtio net configurations.
+    device_config: VirtioNetConfig,
+}
+
+impl ByteCode for NetState {}
+
+impl StateTransfer for Net {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        let state = NetState {
+            device_features: self.device_features,
+            driver_features: self.driver_features,
+            device_config: self.device_config,
+        };
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        let state: NetState = state_from_bytes(state)?;
+        check_restored_features(state.device_features, self.device_features)?;
+        self.device_features = state.device_features;
+        self.driver_features = state.driver_features;
+        self.device_config = state.device_config;
+        Ok(())
+    }
+}
+
 impl VirtioDevice for Net {
     /// Realize vhost virtio network device.
     fn realize(&mut self) -> Result<()> {
@@ -670,10 +800,14 @@ impl VirtioDevice for Net {
             driver_features: self.driver_features,
             receiver,
             update_evt: self.update_evt.as_raw_fd(),
+            paused: false,
         };
-        MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
-            Mutex::new(handler),
-        )))?;
+        let handler = Arc::new(Mutex::new(handler));
+        MainLoop::update_event_in(
+            self.net_cfg.iothread.as_deref(),
+            EventNotifierHelper::internal_notifiers(handler.clone()),
+        )?;
+        self.io_handler = Some(handler);
 
         Ok(())
     }
@@ -703,6 +837,24 @@ impl VirtioDevice for Net {
 
         Ok(())
     }
+
+    /// Stop handling the queues and park the notifier of tap, so that no
+    /// frame is received to guest memory while VM is paused.
+    fn pause_io(&mut self) -> Result<()> {
+        if let Some(handler) = &self.io_handler {
+            let notifiers = NetIoHandler::pause(handler);
+            MainLoop::update_event_in(self.net_cfg.iothread.as_deref(), notifiers)?;
+        }
+        Ok(())
+    }
+
+    fn resume_io(&mut self) -> Result<()> {
+        if let Some(handler) = &self.io_handler {
+            let notifiers = NetIoHandler::resume(handler)?;
+            MainLoop::update_event_in(self.net_cfg.iothread.as_deref(), notifiers)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,550 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, FileBackend, GuestAddress, HostMemMapping, MemAdvice, Region};
+use machine_manager::config::PmemConfig;
+use util::byte_code::ByteCode;
+use util::epoll_context::{read_fd, EventNotifier, EventNotifierHelper, NotifierOperation};
+use util::num_ops::{read_u32, write_u32};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::micro_vm::main_loop::MainLoop;
+use super::errors::{ErrorKind, Result, ResultExt};
+use super::{
+    check_restored_features, state_from_bytes, Element, Queue, StateTransfer, VirtioDevice,
+    VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_VRING, VIRTIO_TYPE_PMEM,
+};
+
+/// Number of virtqueues.
+const QUEUE_NUM_PMEM: usize = 1;
+/// Size of virtqueue.
+const QUEUE_SIZE_PMEM: u16 = 256;
+/// Request to write the data of persistent memory back to the file.
+const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+/// Response of request succeeded.
+const VIRTIO_PMEM_RESP_OK: u32 = 0;
+/// Response of request failed or unsupported.
+const VIRTIO_PMEM_RESP_EIO: u32 = 1;
+
+/// Config space of pmem device, where guest finds the persistent memory.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioPmemConfig {
+    /// Guest physical address of persistent memory.
+    start: u64,
+    /// Size of persistent memory.
+    size: u64,
+}
+
+impl ByteCode for VirtioPmemConfig {}
+
+/// Pmem device's IO handle context.
+struct PmemHandler {
+    /// Virtqueue for flush requests.
+    queue: Arc<Mutex<Queue>>,
+    /// Eventfd of the virtqueue.
+    queue_evt: EventFd,
+    /// The address space to which the pmem device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for triggering interrupts.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// File backing the persistent memory.
+    file: Arc<File>,
+}
+
+impl PmemHandler {
+    /// Handle the requests of driver, each is answered once the file is
+    /// synced.
+    fn process_queue(&mut self) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut used = false;
+        while let Ok(elem) = queue.vring.pop_avail(&self.mem_space, self.driver_features) {
+            let resp = self.handle_request(&elem)?;
+            queue
+                .vring
+                .add_used(&self.mem_space, elem.index, resp)
+                .chain_err(|| format!("Failed to add used ring {}", elem.index))?;
+            used = true;
+        }
+
+        if used {
+            self.interrupt_status
+                .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+            self.interrupt_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+        Ok(())
+    }
+
+    /// Handle one request, and return the length of response written.
+    fn handle_request(&self, elem: &Element) -> Result<u32> {
+        // Both request and response are a le32.
+        let len = size_of::<u32>() as u32;
+        let (req_iov, resp_iov) = match (elem.out_iovec.first(), elem.in_iovec.first()) {
+            (Some(req_iov), Some(resp_iov)) if req_iov.len >= len && resp_iov.len >= len => {
+                (req_iov, resp_iov)
+            }
+            _ => {
+                error!("Invalid request {} of pmem", elem.index);
+                return Ok(0);
+            }
+        };
+        let req_type = self
+            .mem_space
+            .read_object::<u32>(req_iov.addr)
+            .chain_err(|| "Failed to read request of pmem")?;
+
+        let resp = match req_type {
+            VIRTIO_PMEM_REQ_TYPE_FLUSH => match self.file.sync_data() {
+                Ok(()) => VIRTIO_PMEM_RESP_OK,
+                Err(e) => {
+                    error!("Failed to flush pmem: {}", e);
+                    VIRTIO_PMEM_RESP_EIO
+                }
+            },
+            _ => {
+                warn!("Unsupported request type {} of pmem", req_type);
+                VIRTIO_PMEM_RESP_EIO
+            }
+        };
+        self.mem_space
+            .write_object(&resp, resp_iov.addr)
+            .chain_err(|| "Failed to write response of pmem")?;
+        Ok(len)
+    }
+}
+
+impl EventNotifierHelper for PmemHandler {
+    fn internal_notifiers(pmem_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let cloned_handler = pmem_handler.clone();
+        let handler = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(e) = cloned_handler.lock().unwrap().process_queue() {
+                error!(
+                    "Failed to handle virtqueue of pmem: {}",
+                    error_chain::ChainedError::display_chain(&e)
+                );
+            }
+            None as Option<Vec<EventNotifier>>
+        });
+
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            pmem_handler.lock().unwrap().queue_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        )]
+    }
+}
+
+/// Virtio pmem device, which maps a file of host into guest physical
+/// address space as persistent memory. Guest accesses it directly, and asks
+/// the device to flush when its data should be durable in the file.
+pub struct Pmem {
+    /// Configuration of the pmem device.
+    pmem_cfg: PmemConfig,
+    /// The address space where persistent memory is mapped.
+    sys_mem: Arc<AddressSpace>,
+    /// Config space of the pmem device.
+    config: VirtioPmemConfig,
+    /// File backing the persistent memory, opened when realized.
+    file: Option<Arc<File>>,
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+}
+
+impl Pmem {
+    /// Create a virtio pmem device.
+    ///
+    /// # Arguments
+    ///
+    /// * `pmem_cfg` - Configuration of the pmem device.
+    /// * `sys_mem` - The address space where persistent memory is mapped.
+    /// * `base` - Guest physical address of persistent memory, which
+    ///   mustn't overlap RAM or MMIO.
+    pub fn new(pmem_cfg: PmemConfig, sys_mem: Arc<AddressSpace>, base: u64) -> Self {
+        let config = VirtioPmemConfig {
+            start: base,
+            size: pmem_cfg.size,
+        };
+        Pmem {
+            pmem_cfg,
+            sys_mem,
+            config,
+            file: None,
+            device_features: 0,
+            driver_features: 0,
+        }
+    }
+
+    /// Open the backing file, which is created if it doesn't exist, and
+    /// extended if it's shorter than persistent memory. Data in the file
+    /// is kept.
+    fn open_file(&self) -> Result<File> {
+        let path = &self.pmem_cfg.path;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .chain_err(|| format!("Failed to open backend file {} of pmem", path))?;
+        let metadata = file
+            .metadata()
+            .chain_err(|| format!("Failed to get metadata of pmem file {}", path))?;
+        if metadata.is_file() && metadata.len() < self.pmem_cfg.size {
+            file.set_len(self.pmem_cfg.size)
+                .chain_err(|| format!("Failed to resize backend file {} of pmem", path))?;
+        }
+        Ok(file)
+    }
+}
+
+/// State of pmem device saved in snapshot.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct PmemState {
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+}
+
+impl ByteCode for PmemState {}
+
+impl StateTransfer for Pmem {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        let state = PmemState {
+            device_features: self.device_features,
+            driver_features: self.driver_features,
+        };
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        let state: PmemState = state_from_bytes(state)?;
+        check_restored_features(state.device_features, self.device_features)?;
+        self.device_features = state.device_features;
+        self.driver_features = state.driver_features;
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Pmem {
+    /// Realize virtio pmem device, the backing file is mapped into guest
+    /// physical address space.
+    fn realize(&mut self) -> Result<()> {
+        let file = Arc::new(self.open_file()?);
+        let backend = FileBackend {
+            file: file.clone(),
+            offset: 0,
+            shared: true,
+        };
+        let mapping = HostMemMapping::with_backend(
+            GuestAddress(self.config.start),
+            self.config.size,
+            Some(backend),
+            MemAdvice::default(),
+        )
+        .chain_err(|| format!("Failed to map backend file {} of pmem", self.pmem_cfg.path))?;
+        self.sys_mem
+            .root()
+            .add_subregion(
+                Region::init_ram_device_region(Arc::new(mapping)),
+                self.config.start,
+            )
+            .chain_err(|| "Failed to add region of pmem")?;
+        self.file = Some(file);
+        self.device_features = 1_u64 << VIRTIO_F_VERSION_1;
+
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_PMEM
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_PMEM
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_PMEM
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        let mut v = write_u32(value, page);
+        let unrequested_features = v & !self.device_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request with unknown feature.");
+            v &= !unrequested_features;
+        }
+        self.driver_features |= v;
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
+        let config_slice = self.config.as_bytes();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            return Err(ErrorKind::DevConfigOverflow(offset, config_len).into());
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write data to config from guest, config space of pmem is read-only.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        bail!("Config space of pmem is read-only")
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_evt: EventFd,
+        interrupt_status: Arc<AtomicU32>,
+        queues: Vec<Arc<Mutex<Queue>>>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        let file = match self.file.as_ref() {
+            Some(file) => file.clone(),
+            None => bail!("Pmem is not realized"),
+        };
+        if queues.is_empty() || queue_evts.is_empty() {
+            bail!("Pmem needs {} virtqueue", QUEUE_NUM_PMEM);
+        }
+
+        let handler = Arc::new(Mutex::new(PmemHandler {
+            queue: queues[0].clone(),
+            queue_evt: queue_evts.remove(0),
+            mem_space,
+            interrupt_evt,
+            interrupt_status,
+            driver_features: self.driver_features,
+            file,
+        }));
+        MainLoop::update_event(EventNotifierHelper::internal_notifiers(handler))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{QueueConfig, SplitVringDesc, QUEUE_TYPE_SPLIT_VRING};
+    use super::*;
+
+    const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+    const DESC_TABLE: u64 = 0x0;
+    const AVAIL_RING: u64 = 0x1000;
+    const USED_RING: u64 = 0x2000;
+    /// Base of requests, each request takes 0x100 bytes, and its response
+    /// is at offset 0x80.
+    const REQ_BASE: u64 = 0x10000;
+    const PMEM_BASE: u64 = 1 << 32;
+
+    fn address_space_init() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let host_mmap =
+            Arc::new(HostMemMapping::new(GuestAddress(0), 1 << 20, MemAdvice::default()).unwrap());
+        sys_space
+            .root()
+            .add_subregion(
+                Region::init_ram_region(host_mmap.clone()),
+                host_mmap.start_address().raw_value(),
+            )
+            .unwrap();
+        sys_space
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("stratovirt-test-{}-{}", std::process::id(), name))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn resp_addr(index: u16) -> GuestAddress {
+        GuestAddress(REQ_BASE + u64::from(index) * 0x100 + 0x80)
+    }
+
+    /// Make request `index` of `req_type` available to the device.
+    fn push_request(mem_space: &Arc<AddressSpace>, index: u16, req_type: u32) {
+        let req_addr = GuestAddress(REQ_BASE + u64::from(index) * 0x100);
+        mem_space.write_object(&req_type, req_addr).unwrap();
+        mem_space.write_object(&0xff_u32, resp_addr(index)).unwrap();
+
+        let descs = [
+            SplitVringDesc {
+                addr: req_addr,
+                len: 4,
+                flags: VIRTQ_DESC_F_NEXT,
+                next: index * 2 + 1,
+            },
+            SplitVringDesc {
+                addr: resp_addr(index),
+                len: 4,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            },
+        ];
+        for (i, desc) in descs.iter().enumerate() {
+            let desc_index = u64::from(index) * 2 + i as u64;
+            mem_space
+                .write_object(desc, GuestAddress(DESC_TABLE + desc_index * 16))
+                .unwrap();
+        }
+        mem_space
+            .write_object(
+                &(index * 2),
+                GuestAddress(AVAIL_RING + 4 + u64::from(index) * 2),
+            )
+            .unwrap();
+        mem_space
+            .write_object(&(index + 1), GuestAddress(AVAIL_RING + 2))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pmem_realize() {
+        let path = temp_path("pmem-realize");
+        let sys_mem = address_space_init();
+        let pmem_cfg = PmemConfig {
+            path: path.clone(),
+            size: 2 << 20,
+        };
+        let mut pmem = Pmem::new(pmem_cfg, sys_mem.clone(), PMEM_BASE);
+        pmem.realize().unwrap();
+        assert_eq!(pmem.device_type(), VIRTIO_TYPE_PMEM);
+        assert_eq!(pmem.queue_num(), 1);
+        assert_eq!(pmem.get_device_features(1), 1);
+        // The file is created with the size of persistent memory.
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 << 20);
+
+        // Guest finds the address and size in config space.
+        let mut data = [0_u8; 16];
+        pmem.read_config(0, &mut data).unwrap();
+        assert_eq!(data[..8], PMEM_BASE.to_le_bytes());
+        assert_eq!(data[8..], (2_u64 << 20).to_le_bytes());
+        assert!(pmem.read_config(16, &mut data).is_err());
+        assert!(pmem.write_config(0, &data).is_err());
+
+        // Guest writes reach the file, and the region isn't taken as RAM.
+        sys_mem
+            .write_object(&0x1234_u32, GuestAddress(PMEM_BASE + 0x1000))
+            .unwrap();
+        assert!(!sys_mem.address_in_memory(GuestAddress(PMEM_BASE), 0));
+        assert_eq!(sys_mem.memory_end_address(), GuestAddress(1 << 20));
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(content[0x1000..0x1004], 0x1234_u32.to_le_bytes());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pmem_flush() {
+        let path = temp_path("pmem-flush");
+        let mem_space = address_space_init();
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE_PMEM);
+        queue_config.desc_table = GuestAddress(DESC_TABLE);
+        queue_config.avail_ring = GuestAddress(AVAIL_RING);
+        queue_config.used_ring = GuestAddress(USED_RING);
+        queue_config.size = QUEUE_SIZE_PMEM;
+        queue_config.ready = true;
+        let queue = Arc::new(Mutex::new(
+            Queue::new(queue_config, QUEUE_TYPE_SPLIT_VRING).unwrap(),
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        let interrupt_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut handler = PmemHandler {
+            queue,
+            queue_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            mem_space: mem_space.clone(),
+            interrupt_evt: interrupt_evt.try_clone().unwrap(),
+            interrupt_status: Arc::new(AtomicU32::new(0)),
+            driver_features: 0,
+            file: Arc::new(file),
+        };
+
+        // A flush and an unsupported request are answered in order.
+        push_request(&mem_space, 0, VIRTIO_PMEM_REQ_TYPE_FLUSH);
+        push_request(&mem_space, 1, 1);
+        handler.process_queue().unwrap();
+        assert_eq!(
+            mem_space
+                .read_object::<u16>(GuestAddress(USED_RING + 2))
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            mem_space.read_object::<u32>(resp_addr(0)).unwrap(),
+            VIRTIO_PMEM_RESP_OK
+        );
+        assert_eq!(
+            mem_space.read_object::<u32>(resp_addr(1)).unwrap(),
+            VIRTIO_PMEM_RESP_EIO
+        );
+        // Length of response is in the used element.
+        assert_eq!(
+            mem_space
+                .read_object::<u32>(GuestAddress(USED_RING + 8))
+                .unwrap(),
+            4
+        );
+        assert_eq!(interrupt_evt.read().unwrap(), 1);
+        assert_eq!(
+            handler.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -124,6 +124,18 @@ impl Element {
     }
 }
 
+/// Indices of vring kept by device, which are saved in snapshot along
+/// with the configuration of vring.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct VringIndices {
+    /// The next index which can be popped in the available vring.
+    pub next_avail: u16,
+    /// The next index which can be pushed in the used vring.
+    pub next_used: u16,
+    /// The index of last descriptor used which has triggered interrupt.
+    pub last_signal_used: u16,
+}
+
 /// Vring operations.
 pub trait VringOps {
     /// Return true if the configuration of vring is valid.
@@ -163,6 +175,17 @@ pub trait VringOps {
 
     /// Get the configuration of the vring.
     fn get_queue_config(&self) -> QueueConfig;
+
+    /// Get the indices of the vring kept by device.
+    fn get_indices(&self) -> VringIndices;
+
+    /// Set the indices of the vring kept by device, which are restored from
+    /// snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `indices` - Indices saved by `get_indices`.
+    fn set_indices(&mut self, indices: VringIndices);
 }
 
 /// Virtio used element.
@@ -701,6 +724,20 @@ impl VringOps for SplitVring {
             size: self.size,
         }
     }
+
+    fn get_indices(&self) -> VringIndices {
+        VringIndices {
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+            last_signal_used: self.last_signal_used.0,
+        }
+    }
+
+    fn set_indices(&mut self, indices: VringIndices) {
+        self.next_avail = Wrapping(indices.next_avail);
+        self.next_used = Wrapping(indices.next_used);
+        self.last_signal_used = Wrapping(indices.last_signal_used);
+    }
 }
 
 /// Virtio queue.
@@ -740,13 +777,14 @@ impl Queue {
 #[cfg(test)]
 mod tests {
     pub use super::*;
-    use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+    use address_space::{AddressSpace, GuestAddress, HostMemMapping, MemAdvice, Region};
 
     fn address_space_init() -> Arc<AddressSpace> {
         let root = Region::init_container_region(1 << 36);
         let sys_space = AddressSpace::new(root).unwrap();
-        let host_mmap =
-            Arc::new(HostMemMapping::new(GuestAddress(0), SYSTEM_SPACE_SIZE, false).unwrap());
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), SYSTEM_SPACE_SIZE, MemAdvice::default()).unwrap(),
+        );
         sys_space
             .root()
             .add_subregion(
@@ -1473,6 +1511,45 @@ mod tests {
         assert_eq!(vring.get_used_ring_idx(&sys_space).unwrap(), 1);
     }
 
+    #[test]
+    fn test_vring_indices_snapshot() {
+        let sys_space = address_space_init();
+
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.avail_ring = GuestAddress((QUEUE_SIZE as u64) * DESCRIPTOR_LEN);
+        queue_config.used_ring = GuestAddress(align(
+            (QUEUE_SIZE as u64) * DESCRIPTOR_LEN
+                + VRING_AVAIL_LEN_EXCEPT_AVAILELEM
+                + AVAILELEM_LEN * (QUEUE_SIZE as u64),
+            4096,
+        ));
+        queue_config.ready = true;
+        queue_config.size = QUEUE_SIZE;
+        let mut vring = SplitVring::new(queue_config);
+        assert_eq!(vring.is_valid(&sys_space), true);
+
+        // the indices saved in snapshot follow the used ring
+        assert!(vring.add_used(&sys_space, 10, 100).is_ok());
+        assert_eq!(
+            vring.get_indices(),
+            VringIndices {
+                next_avail: 0,
+                next_used: 1,
+                last_signal_used: 0,
+            }
+        );
+
+        // the indices restored from snapshot are kept by the vring
+        let indices = VringIndices {
+            next_avail: 3,
+            next_used: 2,
+            last_signal_used: 1,
+        };
+        vring.set_indices(indices);
+        assert_eq!(vring.get_indices(), indices);
+    }
+
     #[test]
     fn test_should_notify() {
         let sys_space = address_space_init();
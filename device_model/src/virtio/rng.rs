@@ -0,0 +1,348 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use address_space::AddressSpace;
+use machine_manager::config::RngConfig;
+use util::byte_code::ByteCode;
+use util::epoll_context::{read_fd, EventNotifier, EventNotifierHelper, NotifierOperation};
+use util::num_ops::{read_u32, write_u32};
+use util::timer::{TimerHandle, TimerMode};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::super::micro_vm::main_loop::MainLoop;
+use super::errors::{ErrorKind, Result, ResultExt};
+use super::{
+    check_restored_features, state_from_bytes, Queue, StateTransfer, VirtioDevice,
+    VIRTIO_F_VERSION_1, VIRTIO_MMIO_INT_VRING, VIRTIO_TYPE_RNG,
+};
+
+/// Number of virtqueues.
+const QUEUE_NUM_RNG: usize = 1;
+/// Size of virtqueue.
+const QUEUE_SIZE_RNG: u16 = 256;
+/// Max bytes given to one request, larger buffers of driver are partly
+/// filled.
+const MAX_REQUEST_SIZE: u64 = 1 << 16;
+/// Period to refill the bytes allowed by `bytes_per_sec`.
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Rng device's IO handle context.
+struct RngHandler {
+    /// Virtqueue for requests of random bytes.
+    queue: Arc<Mutex<Queue>>,
+    /// Eventfd of the virtqueue.
+    queue_evt: EventFd,
+    /// The address space to which the rng device belongs.
+    mem_space: Arc<AddressSpace>,
+    /// Eventfd for triggering interrupts.
+    interrupt_evt: EventFd,
+    /// State of the interrupt in the device/function.
+    interrupt_status: Arc<AtomicU32>,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// File where random bytes are read.
+    random_file: File,
+    /// Bytes allowed to give until the next period, unlimited if not set.
+    quota: Option<u64>,
+}
+
+impl RngHandler {
+    /// Fill the requests of driver with random bytes until the quota of
+    /// this period is used up, the rest are handled in the next period.
+    fn process_queue(&mut self) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut used = false;
+        while self.quota != Some(0) {
+            let elem = match queue.vring.pop_avail(&self.mem_space, self.driver_features) {
+                Ok(elem) => elem,
+                Err(_) => break,
+            };
+
+            let mut size: u64 = elem.in_iovec.iter().map(|iov| u64::from(iov.len)).sum();
+            size = cmp::min(size, MAX_REQUEST_SIZE);
+            if let Some(quota) = self.quota {
+                size = cmp::min(size, quota);
+            }
+            let mut buffer = vec![0_u8; size as usize];
+            self.random_file
+                .read_exact(&mut buffer)
+                .chain_err(|| "Failed to read random file of rng")?;
+
+            let mut written = 0_u64;
+            for iov in elem.in_iovec.iter() {
+                if written >= size {
+                    break;
+                }
+                let len = cmp::min(u64::from(iov.len), size - written);
+                let mut slice = &buffer[written as usize..(written + len) as usize];
+                self.mem_space
+                    .write(&mut slice, iov.addr, len)
+                    .chain_err(|| "Failed to write random bytes to guest")?;
+                written += len;
+            }
+
+            if let Some(quota) = self.quota.as_mut() {
+                *quota -= size;
+            }
+            queue
+                .vring
+                .add_used(&self.mem_space, elem.index, size as u32)
+                .chain_err(|| format!("Failed to add used ring {}", elem.index))?;
+            used = true;
+        }
+
+        if used {
+            self.interrupt_status
+                .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+            self.interrupt_evt
+                .write(1)
+                .chain_err(|| ErrorKind::EventFdWrite)?;
+        }
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for RngHandler {
+    fn internal_notifiers(rng_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let cloned_handler = rng_handler.clone();
+        let handler = Box::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(e) = cloned_handler.lock().unwrap().process_queue() {
+                error!(
+                    "Failed to handle virtqueue of rng: {}",
+                    error_chain::ChainedError::display_chain(&e)
+                );
+            }
+            None as Option<Vec<EventNotifier>>
+        });
+
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            rng_handler.lock().unwrap().queue_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        )]
+    }
+}
+
+/// Virtio rng device, which gives random bytes of host to guest.
+pub struct Rng {
+    /// Configuration of the rng device.
+    rng_cfg: RngConfig,
+    /// File where random bytes are read, opened when realized.
+    random_file: Option<File>,
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Timer refilling the quota of rate limit, replaced when the device is
+    /// activated again.
+    rate_timer: Option<TimerHandle>,
+}
+
+impl Rng {
+    /// Create a virtio rng device.
+    ///
+    /// # Arguments
+    ///
+    /// * `rng_cfg` - Configuration of the rng device.
+    pub fn new(rng_cfg: RngConfig) -> Self {
+        Rng {
+            rng_cfg,
+            random_file: None,
+            device_features: 0,
+            driver_features: 0,
+            rate_timer: None,
+        }
+    }
+}
+
+/// State of rng device saved in snapshot.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct RngState {
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+}
+
+impl ByteCode for RngState {}
+
+impl StateTransfer for Rng {
+    fn get_state_vec(&self) -> Result<Vec<u8>> {
+        let state = RngState {
+            device_features: self.device_features,
+            driver_features: self.driver_features,
+        };
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> Result<()> {
+        let state: RngState = state_from_bytes(state)?;
+        check_restored_features(state.device_features, self.device_features)?;
+        self.device_features = state.device_features;
+        self.driver_features = state.driver_features;
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Rng {
+    /// Realize virtio rng device.
+    fn realize(&mut self) -> Result<()> {
+        let file = File::open(&self.rng_cfg.random_file).chain_err(|| {
+            format!(
+                "Failed to open random file {} of rng",
+                self.rng_cfg.random_file
+            )
+        })?;
+        self.random_file = Some(file);
+        self.device_features = 1_u64 << VIRTIO_F_VERSION_1;
+
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_RNG
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        QUEUE_NUM_RNG
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        QUEUE_SIZE_RNG
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        let mut v = write_u32(value, page);
+        let unrequested_features = v & !self.device_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request with unknown feature.");
+            v &= !unrequested_features;
+        }
+        self.driver_features |= v;
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, _data: &mut [u8]) -> Result<()> {
+        Err(ErrorKind::DevConfigOverflow(offset, 0).into())
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        bail!("No device config space")
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_evt: EventFd,
+        interrupt_status: Arc<AtomicU32>,
+        queues: Vec<Arc<Mutex<Queue>>>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        let random_file = match self.random_file.as_ref() {
+            Some(file) => file.try_clone()?,
+            None => bail!("Rng is not realized"),
+        };
+        if queues.is_empty() || queue_evts.is_empty() {
+            bail!("Rng needs {} virtqueue", QUEUE_NUM_RNG);
+        }
+
+        let bytes_per_sec = self.rng_cfg.bytes_per_sec;
+        let handler = Arc::new(Mutex::new(RngHandler {
+            queue: queues[0].clone(),
+            queue_evt: queue_evts.remove(0),
+            mem_space,
+            interrupt_evt,
+            interrupt_status,
+            driver_features: self.driver_features,
+            random_file,
+            quota: bytes_per_sec,
+        }));
+        MainLoop::update_event(EventNotifierHelper::internal_notifiers(handler.clone()))?;
+
+        // Requests held by the rate limit are handled when the quota is refilled.
+        if let Some(timer) = self.rate_timer.take() {
+            timer.cancel();
+        }
+        if let Some(rate) = bytes_per_sec {
+            let timer = MainLoop::add_timer(
+                RATE_PERIOD,
+                TimerMode::Periodic,
+                Box::new(move |_| {
+                    let mut locked_handler = handler.lock().unwrap();
+                    locked_handler.quota = Some(rate);
+                    if let Err(e) = locked_handler.process_queue() {
+                        error!(
+                            "Failed to handle virtqueue of rng: {}",
+                            error_chain::ChainedError::display_chain(&e)
+                        );
+                    }
+                }),
+            )
+            .chain_err(|| "Failed to add timer of rng")?;
+            self.rate_timer = Some(timer);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_realize() {
+        let mut rng = Rng::new(RngConfig::default());
+        rng.realize().unwrap();
+        assert!(rng.random_file.is_some());
+        assert_eq!(rng.device_type(), VIRTIO_TYPE_RNG);
+        assert_eq!(rng.queue_num(), 1);
+        assert_eq!(rng.get_device_features(0), 0);
+        assert_eq!(rng.get_device_features(1), 1);
+        let mut data = [0_u8; 4];
+        assert!(rng.read_config(0, &mut data).is_err());
+
+        let mut rng = Rng::new(RngConfig {
+            random_file: "/path/to/nothing".to_string(),
+            bytes_per_sec: None,
+        });
+        assert_eq!(
+            rng.realize().unwrap_err().to_string(),
+            "Failed to open random file /path/to/nothing of rng"
+        );
+    }
+}
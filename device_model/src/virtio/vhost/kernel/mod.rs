@@ -23,7 +23,7 @@ use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
 use address_space::{
-    AddressSpace, FlatRange, GuestAddress, Listener, ListenerReqType, RegionIoEventFd, RegionType,
+    AddressSpace, FlatRange, GuestAddress, HostMemRef, Listener, ListenerReqType, RegionIoEventFd,
 };
 use util::byte_code::ByteCode;
 use util::epoll_context::{read_fd, EventNotifier, EventNotifierHelper, NotifierOperation};
@@ -122,7 +122,9 @@ impl ByteCode for VhostMemory {}
 
 #[derive(Clone)]
 pub struct VhostMemInfo {
-    regions: Arc<Mutex<Vec<VhostMemoryRegion>>>,
+    /// Memory regions told to vhost, along with the host memory backing them,
+    /// which is kept mapped as long as vhost may access it.
+    regions: Arc<Mutex<Vec<(VhostMemoryRegion, HostMemRef)>>>,
 }
 
 impl VhostMemInfo {
@@ -134,7 +136,7 @@ impl VhostMemInfo {
 
     pub fn addr_to_host(&self, addr: GuestAddress) -> Option<u64> {
         let addr = addr.raw_value();
-        for region in self.regions.lock().unwrap().iter() {
+        for (region, _) in self.regions.lock().unwrap().iter() {
             if addr >= region.guest_phys_addr && addr < region.guest_phys_addr + region.memory_size
             {
                 let offset = addr - region.guest_phys_addr;
@@ -144,32 +146,25 @@ impl VhostMemInfo {
         None
     }
 
-    fn check_vhost_mem_range(fr: &FlatRange) -> bool {
-        fr.owner.region_type() == RegionType::Ram
-    }
-
-    fn add_mem_range(&self, fr: &FlatRange) {
-        let guest_phys_addr = fr.addr_range.base.raw_value();
-        let memory_size = fr.addr_range.size;
-        let userspace_addr = fr.owner.get_host_address().unwrap() + fr.offset_in_region;
-
-        self.regions.lock().unwrap().push(VhostMemoryRegion {
-            guest_phys_addr,
-            memory_size,
-            userspace_addr,
+    fn add_mem_range(&self, fr: &FlatRange, host_mem: HostMemRef) {
+        let region = VhostMemoryRegion {
+            guest_phys_addr: fr.addr_range.base.raw_value(),
+            memory_size: host_mem.len(),
+            userspace_addr: host_mem.host_address(),
             flags_padding: 0_u64,
-        });
+        };
+        self.regions.lock().unwrap().push((region, host_mem));
     }
 
-    fn delete_mem_range(&self, fr: &FlatRange) {
+    fn delete_mem_range(&self, fr: &FlatRange, host_mem: HostMemRef) {
         let mut mem_regions = self.regions.lock().unwrap();
         let target = VhostMemoryRegion {
             guest_phys_addr: fr.addr_range.base.raw_value(),
-            memory_size: fr.addr_range.size,
-            userspace_addr: fr.owner.get_host_address().unwrap() + fr.offset_in_region,
+            memory_size: host_mem.len(),
+            userspace_addr: host_mem.host_address(),
             flags_padding: 0_u64,
         };
-        for (index, mr) in mem_regions.iter().enumerate() {
+        for (index, (mr, _)) in mem_regions.iter().enumerate() {
             if mr.guest_phys_addr == target.guest_phys_addr
                 && mr.memory_size == target.memory_size
                 && mr.userspace_addr == target.userspace_addr
@@ -196,14 +191,15 @@ impl Listener for VhostMemInfo {
     ) -> std::result::Result<(), address_space::errors::Error> {
         match req_type {
             ListenerReqType::AddRegion => {
-                if Self::check_vhost_mem_range(&range.unwrap()) {
-                    self.add_mem_range(range.unwrap());
+                let fr = range.unwrap();
+                if let Some(host_mem) = fr.host_mem() {
+                    self.add_mem_range(fr, host_mem);
                 }
             }
             ListenerReqType::DeleteRegion => {
                 let fr = range.unwrap();
-                if fr.owner.region_type() == RegionType::Ram {
-                    self.delete_mem_range(&fr);
+                if let Some(host_mem) = fr.host_mem() {
+                    self.delete_mem_range(fr, host_mem);
                 }
             }
             _ => {}
@@ -218,13 +214,28 @@ pub struct VhostBackend {
 }
 
 impl VhostBackend {
+    /// Create vhost backend with the vhost device at `path`, or with the
+    /// opened one given by `rawfd`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_space` - The address space shared with the vhost device.
+    /// * `path` - Path of the vhost device, such as `/dev/vhost-net`.
+    /// * `rawfd` - Fd of the opened vhost device, such as the one given by
+    ///   `vhostfd=`.
+    ///
+    /// # Notes
+    ///
+    /// The backend owns a duplicate of `rawfd`, which is closed when the
+    /// backend is dropped. `rawfd` itself is never closed, so that it can be
+    /// used again when the device is realized again.
     pub fn new(
         mem_space: &Arc<AddressSpace>,
         path: &str,
         rawfd: Option<RawFd>,
     ) -> Result<VhostBackend> {
         let fd = match rawfd {
-            Some(rawfd) => unsafe { File::from_raw_fd(rawfd) },
+            Some(rawfd) => dup_vhost_fd(rawfd)?,
             None => OpenOptions::new()
                 .read(true)
                 .write(true)
@@ -239,6 +250,30 @@ impl VhostBackend {
     }
 }
 
+/// Duplicate the fd of an opened vhost device, and check that it's really a
+/// vhost device by `VHOST_GET_FEATURES`, which doesn't change its state.
+///
+/// # Arguments
+///
+/// * `rawfd` - Fd of the opened vhost device.
+fn dup_vhost_fd(rawfd: RawFd) -> Result<File> {
+    let fd = unsafe { libc::fcntl(rawfd, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+            .chain_err(|| format!("Failed to duplicate vhost fd {}", rawfd));
+    }
+    // The duplicate is closed on error when it's dropped.
+    let file = unsafe { File::from_raw_fd(fd) };
+
+    let mut features: u64 = 0;
+    let ret = unsafe { ioctl_with_mut_ref(&file, VHOST_GET_FEATURES(), &mut features) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error())
+            .chain_err(|| format!("Fd {} is not a vhost device", rawfd));
+    }
+    Ok(file)
+}
+
 impl AsRawFd for VhostBackend {
     fn as_raw_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
@@ -285,7 +320,7 @@ impl VhostOps for VhostBackend {
             .as_bytes(),
         );
 
-        for (index, region) in self.mem_info.regions.lock().unwrap().iter().enumerate() {
+        for (index, (region, _)) in self.mem_info.regions.lock().unwrap().iter().enumerate() {
             bytes[(vm_size + index * vmr_size)..(vm_size + (index + 1) * vmr_size)]
                 .copy_from_slice(region.as_bytes());
         }
@@ -433,3 +468,37 @@ impl EventNotifierHelper for VhostIoHandler {
         notifiers
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dup_vhost_fd() {
+        // A dummy fd is refused, and it's kept open.
+        let dummy = File::open("/dev/null").unwrap();
+        let rawfd = dummy.as_raw_fd();
+        assert_eq!(
+            dup_vhost_fd(rawfd).unwrap_err().to_string(),
+            format!("Fd {} is not a vhost device", rawfd)
+        );
+        assert!(unsafe { libc::fcntl(rawfd, libc::F_GETFD) } >= 0);
+
+        assert_eq!(
+            dup_vhost_fd(-1).unwrap_err().to_string(),
+            "Failed to duplicate vhost fd -1"
+        );
+
+        // The given fd is still open after the duplicate is closed.
+        if let Ok(vhost) = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/vhost-vsock")
+        {
+            let file = dup_vhost_fd(vhost.as_raw_fd()).unwrap();
+            assert_ne!(file.as_raw_fd(), vhost.as_raw_fd());
+            drop(file);
+            assert!(unsafe { libc::fcntl(vhost.as_raw_fd(), libc::F_GETFD) } >= 0);
+        }
+    }
+}
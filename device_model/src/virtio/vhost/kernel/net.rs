@@ -11,9 +11,8 @@
 // See the Mulan PSL v2 for more details.
 
 use std::cmp;
-use std::fs::File;
 use std::io::Write;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
 use std::sync::atomic::AtomicU32;
 use std::sync::{Arc, Mutex};
 
@@ -30,8 +29,8 @@ use super::super::super::super::micro_vm::main_loop::MainLoop;
 use super::super::super::errors::{ErrorKind, Result, ResultExt};
 use super::super::super::{
     net::{build_device_config_space, create_tap, VirtioNetConfig},
-    Queue, VirtioDevice, VIRTIO_F_ACCESS_PLATFORM, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM,
-    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    Queue, StateTransfer, VirtioDevice, VIRTIO_F_ACCESS_PLATFORM, VIRTIO_F_VERSION_1,
+    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
     VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_TYPE_NET,
 };
 use super::super::{VhostNotify, VhostOps};
@@ -52,16 +51,16 @@ trait VhostNetBackend {
     ///
     /// # Arguments
     /// * `queue_index` - Index of the queue to modify.
-    /// * `fd` - EventFd that will be signaled from guest.
-    fn set_backend(&self, queue_index: usize, tap_file: &File) -> Result<()>;
+    /// * `fd` - Fd of the tap device, or -1 to unbind.
+    fn set_backend(&self, queue_index: usize, fd: RawFd) -> Result<()>;
 }
 
 impl VhostNetBackend for VhostBackend {
     /// Attach virtio net ring to a raw socket, or tap device.
-    fn set_backend(&self, queue_index: usize, tap_file: &File) -> Result<()> {
+    fn set_backend(&self, queue_index: usize, fd: RawFd) -> Result<()> {
         let vring_file = VhostVringFile {
             index: queue_index as u32,
-            fd: tap_file.as_raw_fd(),
+            fd,
         };
 
         let ret = unsafe { ioctl_with_ref(self, VHOST_NET_SET_BACKEND(), &vring_file) };
@@ -90,6 +89,10 @@ pub struct Net {
     device_config: VirtioNetConfig,
     /// System address space.
     mem_space: Arc<AddressSpace>,
+    /// Number of rings attached to the tap, once the device is activated.
+    activated_queues: usize,
+    /// Whether the rings are unbound from the tap while VM is paused.
+    paused: bool,
 }
 
 impl Net {
@@ -103,10 +106,15 @@ impl Net {
             vhost_features: 0_u64,
             device_config: VirtioNetConfig::default(),
             mem_space,
+            activated_queues: 0,
+            paused: false,
         }
     }
 }
 
+/// Rings of vhost-net are handled in kernel, whose state can't be saved.
+impl StateTransfer for Net {}
+
 impl VirtioDevice for Net {
     /// Realize vhost virtio network device.
     fn realize(&mut self) -> Result<()> {
@@ -249,8 +257,9 @@ impl VirtioDevice for Net {
                 None => bail!("Failed to get tap"),
                 Some(tap_) => tap_,
             };
-            backend.set_backend(queue_index, &tap.file)?;
+            backend.set_backend(queue_index, tap.as_raw_fd())?;
         }
+        self.activated_queues = queues.len();
 
         let handler = VhostIoHandler {
             interrupt_evt: interrupt_evt.try_clone()?,
@@ -258,10 +267,39 @@ impl VirtioDevice for Net {
             host_notifies,
         };
 
-        MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
-            Mutex::new(handler),
-        )))?;
+        MainLoop::update_event_in(
+            self.net_cfg.iothread.as_deref(),
+            EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler))),
+        )?;
+
+        Ok(())
+    }
 
+    /// Unbind the rings from the tap, the vhost worker finishes the frames
+    /// being handled and stops using the rings.
+    fn pause_io(&mut self) -> Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+        if let Some(backend) = &self.backend {
+            for queue_index in 0..self.activated_queues {
+                backend.set_backend(queue_index, -1)?;
+            }
+        }
+        self.paused = true;
+        Ok(())
+    }
+
+    fn resume_io(&mut self) -> Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        if let (Some(backend), Some(tap)) = (&self.backend, &self.tap) {
+            for queue_index in 0..self.activated_queues {
+                backend.set_backend(queue_index, tap.as_raw_fd())?;
+            }
+        }
+        self.paused = false;
         Ok(())
     }
 }
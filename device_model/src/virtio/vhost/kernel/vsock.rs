@@ -24,7 +24,7 @@ use vmm_sys_util::ioctl::ioctl_with_ref;
 
 use super::super::super::super::micro_vm::main_loop::MainLoop;
 use super::super::super::errors::{ErrorKind, Result, ResultExt};
-use super::super::super::{Queue, VirtioDevice, VIRTIO_TYPE_VSOCK};
+use super::super::super::{Queue, StateTransfer, VirtioDevice, VIRTIO_TYPE_VSOCK};
 use super::super::{VhostNotify, VhostOps};
 use super::{VhostBackend, VhostIoHandler, VHOST_VSOCK_SET_GUEST_CID, VHOST_VSOCK_SET_RUNNING};
 
@@ -75,6 +75,10 @@ pub struct Vsock {
     config_space: Vec<u8>,
     /// System address space.
     mem_space: Arc<AddressSpace>,
+    /// Whether the device is activated, the backend is running then.
+    activated: bool,
+    /// Whether the backend is stopped while VM is paused.
+    paused: bool,
 }
 
 impl Vsock {
@@ -86,10 +90,15 @@ impl Vsock {
             driver_features: 0_u64,
             config_space: Vec::new(),
             mem_space,
+            activated: false,
+            paused: false,
         }
     }
 }
 
+/// Rings of vhost-vsock are handled in kernel, whose state can't be saved.
+impl StateTransfer for Vsock {}
+
 impl VirtioDevice for Vsock {
     /// Realize vhost virtio vsock device.
     fn realize(&mut self) -> Result<()> {
@@ -220,7 +229,32 @@ impl VirtioDevice for Vsock {
         MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
             Mutex::new(handler),
         )))?;
+        self.activated = true;
+
+        Ok(())
+    }
 
+    /// Stop the backend, the vhost worker finishes the packets being
+    /// handled and stops using the rings.
+    fn pause_io(&mut self) -> Result<()> {
+        if !self.activated || self.paused {
+            return Ok(());
+        }
+        if let Some(backend) = &self.backend {
+            backend.set_running(false)?;
+        }
+        self.paused = true;
+        Ok(())
+    }
+
+    fn resume_io(&mut self) -> Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        if let Some(backend) = &self.backend {
+            backend.set_running(true)?;
+        }
+        self.paused = false;
         Ok(())
     }
 }
@@ -0,0 +1,829 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Character devices shared by the frontends talking to host as a byte
+//! stream, such as serial and virtio-console.
+//!
+//! A backend implements `CharDevice`, and is wrapped by `Chardev`, which
+//! registers it to main loop and passes its input to the receiver set by
+//! frontend:
+//!
+//! ```text
+//!   frontend ---- write ----> Chardev ----> backend (stdio, socket, pty...)
+//!   frontend <--- receiver -- Chardev <---- backend
+//! ```
+//!
+//! Server backends, the unix socket and tcp socket, accept one client at a
+//! time. Their listener is parked while the client is connected, and the
+//! output is dropped while no client is connected.
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+
+use util::epoll_context::{
+    EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::unix::limit_permission;
+use vmm_sys_util::epoll::EventSet;
+
+use crate::config::{ChardevConfig, ChardevType};
+use crate::errors::{Result, ResultExt};
+
+/// Size of buffer to read input of backend.
+const INPUT_BUFFER_SIZE: usize = 4096;
+
+/// Receiver of the input of chardev, it's set by frontend.
+pub type InputReceiver = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// Backend of character device.
+pub trait CharDevice: Send {
+    /// Open the backend, such as binding the socket server.
+    fn open(&mut self) -> Result<()>;
+
+    /// Get the fd of listener which accepts clients, `None` if the backend
+    /// isn't a server.
+    fn listener_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Accept a client on listener, it's called when the listener is
+    /// readable.
+    fn accept(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Close the connected client, the listener is kept.
+    fn disconnect(&mut self) {}
+
+    /// Get the fd to read input from, `None` if there is no input, or no
+    /// client is connected to server.
+    fn input_fd(&self) -> Option<RawFd>;
+
+    /// Read input to `buf`, 0 is returned if the input is closed.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write output of frontend, it's dropped if no one can receive it.
+    fn write(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Close the backend and remove what it created on host.
+    fn cleanup(&mut self) {}
+}
+
+/// Write all of `buf` to stream, the output is dropped if the stream would
+/// block, so that frontend is never blocked by a slow reader.
+fn write_nonblocking<W: Write>(stream: &mut W, buf: &[u8]) -> io::Result<()> {
+    match stream.write_all(buf) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        result => result,
+    }
+}
+
+/// Backend of stdin and stdout of StratoVirt.
+#[derive(Default)]
+pub struct StdioChardev;
+
+impl CharDevice for StdioChardev {
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        Some(libc::STDIN_FILENO)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Stdin is read directly, the buffered `Stdin` may keep the input
+        // which is never notified again.
+        let ret = unsafe {
+            libc::read(
+                libc::STDIN_FILENO,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(buf)?;
+        stdout.flush()
+    }
+}
+
+/// Backend of unix domain socket server.
+pub struct UnixSocketChardev {
+    /// Path of socket.
+    path: String,
+    /// Socket server, it's bound when it's opened.
+    listener: Option<UnixListener>,
+    /// Connected client.
+    client: Option<UnixStream>,
+}
+
+impl UnixSocketChardev {
+    pub fn new(path: &str) -> Self {
+        UnixSocketChardev {
+            path: path.to_string(),
+            listener: None,
+            client: None,
+        }
+    }
+}
+
+impl CharDevice for UnixSocketChardev {
+    fn open(&mut self) -> Result<()> {
+        let listener = UnixListener::bind(&self.path)
+            .chain_err(|| format!("Failed to bind socket {}", self.path))?;
+        limit_permission(&self.path)
+            .chain_err(|| format!("Failed to change file permission for {}", self.path))?;
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    fn listener_fd(&self) -> Option<RawFd> {
+        self.listener.as_ref().map(|listener| listener.as_raw_fd())
+    }
+
+    fn accept(&mut self) -> Result<()> {
+        if let Some(listener) = self.listener.as_ref() {
+            let (client, _) = listener.accept()?;
+            client.set_nonblocking(true)?;
+            self.client = Some(client);
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        self.client.as_ref().map(|client| client.as_raw_fd())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.client.as_mut() {
+            Some(client) => client.read(buf),
+            None => Ok(0),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.client.as_mut() {
+            Some(client) => write_nonblocking(client, buf),
+            None => Ok(()),
+        }
+    }
+
+    fn cleanup(&mut self) {
+        self.client = None;
+        if self.listener.take().is_some() {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!("Failed to remove socket {}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// Backend of tcp socket server.
+pub struct TcpSocketChardev {
+    /// Address of server, such as `127.0.0.1:4444`.
+    addr: String,
+    /// Socket server, it's bound when it's opened.
+    listener: Option<TcpListener>,
+    /// Connected client.
+    client: Option<TcpStream>,
+}
+
+impl TcpSocketChardev {
+    pub fn new(addr: &str) -> Self {
+        TcpSocketChardev {
+            addr: addr.to_string(),
+            listener: None,
+            client: None,
+        }
+    }
+}
+
+impl CharDevice for TcpSocketChardev {
+    fn open(&mut self) -> Result<()> {
+        let listener = TcpListener::bind(&self.addr)
+            .chain_err(|| format!("Failed to bind tcp address {}", self.addr))?;
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    fn listener_fd(&self) -> Option<RawFd> {
+        self.listener.as_ref().map(|listener| listener.as_raw_fd())
+    }
+
+    fn accept(&mut self) -> Result<()> {
+        if let Some(listener) = self.listener.as_ref() {
+            let (client, _) = listener.accept()?;
+            client.set_nonblocking(true)?;
+            self.client = Some(client);
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        self.client.as_ref().map(|client| client.as_raw_fd())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.client.as_mut() {
+            Some(client) => client.read(buf),
+            None => Ok(0),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.client.as_mut() {
+            Some(client) => write_nonblocking(client, buf),
+            None => Ok(()),
+        }
+    }
+
+    fn cleanup(&mut self) {
+        self.client = None;
+        self.listener = None;
+    }
+}
+
+/// Backend of pseudo terminal, the path of its slave is logged when it's
+/// opened, so that it can be opened by terminal tools like `screen`.
+#[derive(Default)]
+pub struct PtyChardev {
+    /// Master of pty.
+    master: Option<File>,
+    /// Slave of pty, it's kept open so that master never hangs up while no
+    /// one opens the slave.
+    slave: Option<File>,
+    /// Path of slave, such as `/dev/pts/3`.
+    path: Option<String>,
+}
+
+impl PtyChardev {
+    /// Get the path of slave, `None` if it's not opened.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+}
+
+impl CharDevice for PtyChardev {
+    fn open(&mut self) -> Result<()> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "Failed to open pty master");
+        }
+        // It's safe because fd is just opened, and owned by `master` only.
+        let master = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "Failed to unlock pty slave");
+        }
+        let mut name = [0 as libc::c_char; 64];
+        let ret = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret))
+                .chain_err(|| "Failed to get the path of pty slave");
+        }
+        // It's safe because `ptsname_r` writes a nul-terminated string.
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)
+            .chain_err(|| format!("Failed to open pty slave {}", path))?;
+        // Guest output is passed through as it is, instead of being
+        // translated by line discipline.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        let slave_fd = slave.as_raw_fd();
+        if unsafe { libc::tcgetattr(slave_fd, &mut termios) } < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "Failed to get pty attributes");
+        }
+        unsafe { libc::cfmakeraw(&mut termios) };
+        if unsafe { libc::tcsetattr(slave_fd, libc::TCSANOW, &termios) } < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "Failed to set pty attributes");
+        }
+
+        info!("Pty slave of chardev is {}", path);
+        self.master = Some(master);
+        self.slave = Some(slave);
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        self.master.as_ref().map(|master| master.as_raw_fd())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.master.as_mut() {
+            Some(master) => master.read(buf),
+            None => Ok(0),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.master.as_mut() {
+            Some(master) => write_nonblocking(master, buf),
+            None => Ok(()),
+        }
+    }
+
+    fn cleanup(&mut self) {
+        self.master = None;
+        self.slave = None;
+        self.path = None;
+    }
+}
+
+/// Backend of file, it's output only and the output is appended to it.
+pub struct FileChardev {
+    /// Path of file, it's created if it doesn't exist.
+    path: String,
+    /// Opened file.
+    file: Option<File>,
+}
+
+impl FileChardev {
+    pub fn new(path: &str) -> Self {
+        FileChardev {
+            path: path.to_string(),
+            file: None,
+        }
+    }
+}
+
+impl CharDevice for FileChardev {
+    fn open(&mut self) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .chain_err(|| format!("Failed to open file {}", self.path))?;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn input_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.write_all(buf),
+            None => Ok(()),
+        }
+    }
+
+    fn cleanup(&mut self) {
+        self.file = None;
+    }
+}
+
+/// Character device with its backend, which is created by config.
+pub struct Chardev {
+    /// Id of chardev.
+    pub id: String,
+    /// Backend of chardev.
+    backend: Box<dyn CharDevice>,
+    /// Receiver of input, the input is dropped if it's not set.
+    receiver: Option<InputReceiver>,
+    /// Whether the input of backend which isn't a server is closed, its fd
+    /// is removed from main loop then.
+    input_closed: bool,
+}
+
+impl Chardev {
+    /// Create a chardev, its backend is not opened until `realize`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Config of chardev.
+    pub fn new(config: &ChardevConfig) -> Self {
+        let backend: Box<dyn CharDevice> = match &config.backend {
+            ChardevType::Stdio => Box::new(StdioChardev),
+            ChardevType::Socket { path } => Box::new(UnixSocketChardev::new(path)),
+            ChardevType::Tcp { host, port } => {
+                // IPv6 address is bracketed before port.
+                let addr = if host.contains(':') {
+                    format!("[{}]:{}", host, port)
+                } else {
+                    format!("{}:{}", host, port)
+                };
+                Box::new(TcpSocketChardev::new(&addr))
+            }
+            ChardevType::Pty => Box::new(PtyChardev::default()),
+            ChardevType::File { path } => Box::new(FileChardev::new(path)),
+        };
+        Chardev::with_backend(&config.id, backend)
+    }
+
+    /// Create a chardev with `backend`.
+    pub fn with_backend(id: &str, backend: Box<dyn CharDevice>) -> Self {
+        Chardev {
+            id: id.to_string(),
+            backend,
+            receiver: None,
+            input_closed: false,
+        }
+    }
+
+    /// Open the backend of chardev.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the backend fails to be opened, such as the socket
+    /// can't be bound.
+    pub fn realize(&mut self) -> Result<()> {
+        self.backend
+            .open()
+            .chain_err(|| format!("Failed to open chardev '{}'", self.id))
+    }
+
+    /// Set the receiver of input.
+    pub fn set_receiver(&mut self, receiver: InputReceiver) {
+        self.receiver = Some(receiver);
+    }
+
+    /// Write the output of frontend to backend.
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.backend.write(buf)
+    }
+
+    /// Notifiers to remove the chardev from main loop.
+    pub fn delete_notifiers(&self) -> Vec<EventNotifier> {
+        // The client is removed first, so that the parked listener is alive
+        // when it's removed.
+        let listener_fd = self.backend.listener_fd();
+        let mut fds = Vec::new();
+        if let Some(fd) = self.backend.input_fd() {
+            if listener_fd.is_some() || !self.input_closed {
+                fds.push(fd);
+            }
+        }
+        if let Some(fd) = listener_fd {
+            fds.push(fd);
+        }
+
+        fds.into_iter()
+            .map(|fd| {
+                EventNotifier::new(
+                    NotifierOperation::Delete,
+                    fd,
+                    None,
+                    EventSet::IN,
+                    Vec::new(),
+                )
+            })
+            .collect()
+    }
+
+    /// Close the backend, and drop the receiver which may refer to
+    /// frontend.
+    pub fn cleanup(&mut self) {
+        self.receiver = None;
+        self.backend.cleanup();
+    }
+}
+
+/// Handler of the input fd of chardev, the input fd of server is parked
+/// on `listener_fd`.
+fn input_handler(chardev: Arc<Mutex<Chardev>>, listener_fd: Option<RawFd>) -> EventNotifier {
+    let input_fd = chardev.lock().unwrap().backend.input_fd().unwrap_or(-1);
+    let handler: Box<NotifierCallback> = Box::new(move |event, fd| {
+        let mut buffer = [0_u8; INPUT_BUFFER_SIZE];
+        let mut closed = event & EventSet::HANG_UP == EventSet::HANG_UP;
+        let mut count = 0;
+
+        let mut locked_chardev = chardev.lock().unwrap();
+        if event & EventSet::IN == EventSet::IN {
+            match locked_chardev.backend.read(&mut buffer) {
+                Ok(0) => closed = true,
+                Ok(nr) => count = nr,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    error!("Failed to read chardev '{}': {}", locked_chardev.id, e);
+                    closed = true;
+                }
+            }
+        }
+        // The receiver is called without the lock of chardev, since it may
+        // lock frontend, which locks chardev to write output.
+        let receiver = locked_chardev.receiver.clone();
+        let notifiers = if closed {
+            if listener_fd.is_some() {
+                info!("Client of chardev '{}' is disconnected", locked_chardev.id);
+                locked_chardev.backend.disconnect();
+            } else {
+                info!("Input of chardev '{}' is closed", locked_chardev.id);
+                locked_chardev.input_closed = true;
+            }
+            Some(vec![EventNotifier::new(
+                NotifierOperation::Delete,
+                fd,
+                listener_fd,
+                EventSet::IN | EventSet::HANG_UP,
+                Vec::new(),
+            )])
+        } else {
+            None
+        };
+        drop(locked_chardev);
+
+        if let Some(receiver) = receiver {
+            if count > 0 {
+                receiver(&buffer[..count]);
+            }
+        }
+        notifiers
+    });
+
+    EventNotifier::new(
+        NotifierOperation::AddShared,
+        input_fd,
+        listener_fd,
+        EventSet::IN | EventSet::HANG_UP,
+        vec![Arc::new(Mutex::new(handler))],
+    )
+}
+
+impl EventNotifierHelper for Chardev {
+    fn internal_notifiers(chardev: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let (listener_fd, input_fd) = {
+            let locked_chardev = chardev.lock().unwrap();
+            (
+                locked_chardev.backend.listener_fd(),
+                locked_chardev.backend.input_fd(),
+            )
+        };
+
+        match (listener_fd, input_fd) {
+            (Some(listener_fd), _) => {
+                let cloned_chardev = chardev.clone();
+                let handler: Box<NotifierCallback> = Box::new(move |_, _| {
+                    let mut locked_chardev = cloned_chardev.lock().unwrap();
+                    if let Err(e) = locked_chardev.backend.accept() {
+                        error!(
+                            "Failed to accept client of chardev '{}': {}",
+                            locked_chardev.id,
+                            error_chain::ChainedError::display_chain(&e)
+                        );
+                        return None;
+                    }
+                    info!("Client of chardev '{}' is connected", locked_chardev.id);
+                    drop(locked_chardev);
+                    Some(vec![input_handler(
+                        cloned_chardev.clone(),
+                        Some(listener_fd),
+                    )])
+                });
+                vec![EventNotifier::new(
+                    NotifierOperation::AddShared,
+                    listener_fd,
+                    None,
+                    EventSet::IN,
+                    vec![Arc::new(Mutex::new(handler))],
+                )]
+            }
+            (None, Some(_)) => vec![input_handler(chardev, None)],
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+/// Writer of chardev, which is used as the output of frontend.
+pub struct ChardevWriter(Arc<Mutex<Chardev>>);
+
+impl ChardevWriter {
+    pub fn new(chardev: Arc<Mutex<Chardev>>) -> Self {
+        ChardevWriter(chardev)
+    }
+}
+
+impl Write for ChardevWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Read from `stream` until `len` bytes are got, the stream must have
+    /// read timeout or be blocking.
+    fn read_exact_bytes<R: Read>(stream: &mut R, len: usize) -> Vec<u8> {
+        let mut data = vec![0_u8; len];
+        stream.read_exact(&mut data).unwrap();
+        data
+    }
+
+    /// Read from nonblocking backend until some input is got.
+    fn backend_read(backend: &mut dyn CharDevice) -> Vec<u8> {
+        let mut buf = [0_u8; 64];
+        for _ in 0..100 {
+            match backend.read(&mut buf) {
+                Ok(nr) => return buf[..nr].to_vec(),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        panic!("No input of chardev");
+    }
+
+    #[test]
+    fn test_stdio_chardev() {
+        let mut backend = StdioChardev;
+        backend.open().unwrap();
+        assert_eq!(backend.listener_fd(), None);
+        assert_eq!(backend.input_fd(), Some(libc::STDIN_FILENO));
+        backend.write(b"").unwrap();
+    }
+
+    #[test]
+    fn test_unix_socket_chardev() {
+        let path = "test_chardev.sock";
+        let mut backend = UnixSocketChardev::new(path);
+        backend.open().unwrap();
+        assert!(backend.listener_fd().is_some());
+        assert_eq!(backend.input_fd(), None);
+        // Output is dropped without client.
+        backend.write(b"dropped").unwrap();
+
+        let mut client = UnixStream::connect(path).unwrap();
+        backend.accept().unwrap();
+        assert!(backend.input_fd().is_some());
+        backend.write(b"output").unwrap();
+        assert_eq!(read_exact_bytes(&mut client, 6), b"output");
+        client.write_all(b"input").unwrap();
+        assert_eq!(backend_read(&mut backend), b"input");
+
+        drop(client);
+        assert_eq!(backend_read(&mut backend), b"");
+        backend.disconnect();
+        assert_eq!(backend.input_fd(), None);
+
+        // The socket file is removed by cleanup.
+        backend.cleanup();
+        assert!(!std::path::Path::new(path).exists());
+    }
+
+    #[test]
+    fn test_tcp_socket_chardev() {
+        let mut backend = TcpSocketChardev::new("127.0.0.1:0");
+        backend.open().unwrap();
+        let addr = backend.listener.as_ref().unwrap().local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        backend.accept().unwrap();
+        backend.write(b"output").unwrap();
+        assert_eq!(read_exact_bytes(&mut client, 6), b"output");
+        client.write_all(b"input").unwrap();
+        assert_eq!(backend_read(&mut backend), b"input");
+
+        backend.cleanup();
+        assert_eq!(backend.listener_fd(), None);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_pty_chardev() {
+        let mut backend = PtyChardev::default();
+        backend.open().unwrap();
+        let path = backend.path().unwrap().to_string();
+        assert!(path.starts_with("/dev/pts/"));
+        assert!(backend.input_fd().is_some());
+
+        let mut slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)
+            .unwrap();
+        backend.write(b"output\n").unwrap();
+        // Raw mode, so that the output isn't translated to "\r\n".
+        assert_eq!(read_exact_bytes(&mut slave, 7), b"output\n");
+        slave.write_all(b"input").unwrap();
+        assert_eq!(backend_read(&mut backend), b"input");
+
+        backend.cleanup();
+        assert_eq!(backend.path(), None);
+    }
+
+    #[test]
+    fn test_file_chardev() {
+        let path = "test_chardev.log";
+        let _ = std::fs::remove_file(path);
+        let mut backend = FileChardev::new(path);
+        backend.open().unwrap();
+        assert_eq!(backend.input_fd(), None);
+        backend.write(b"line 1\n").unwrap();
+        backend.cleanup();
+
+        // The output is appended when it's opened again.
+        backend.open().unwrap();
+        backend.write(b"line 2\n").unwrap();
+        backend.cleanup();
+        assert_eq!(std::fs::read(path).unwrap(), b"line 1\nline 2\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_chardev_notifiers() {
+        let path = "test_chardev_notifiers.sock";
+        let config = ChardevConfig {
+            id: "chr0".to_string(),
+            backend: ChardevType::Socket {
+                path: path.to_string(),
+            },
+        };
+        let chardev = Arc::new(Mutex::new(Chardev::new(&config)));
+        chardev.lock().unwrap().realize().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let cloned_received = received.clone();
+        chardev
+            .lock()
+            .unwrap()
+            .set_receiver(Arc::new(move |data: &[u8]| {
+                cloned_received.lock().unwrap().extend_from_slice(data)
+            }));
+
+        // Only the listener is registered before client connects.
+        let notifiers = EventNotifierHelper::internal_notifiers(chardev.clone());
+        assert_eq!(notifiers.len(), 1);
+        let listener_fd = notifiers[0].raw_fd;
+        assert_eq!(chardev.lock().unwrap().delete_notifiers().len(), 1);
+
+        // The client is added with the listener parked.
+        let mut client = UnixStream::connect(path).unwrap();
+        let accept = notifiers[0].handlers[0].lock().unwrap();
+        let client_notifiers = accept(EventSet::IN, listener_fd).unwrap();
+        assert_eq!(client_notifiers.len(), 1);
+        let client_fd = client_notifiers[0].raw_fd;
+        assert_eq!(client_notifiers[0].parked_fd, Some(listener_fd));
+        assert_eq!(chardev.lock().unwrap().delete_notifiers().len(), 2);
+
+        let mut writer = ChardevWriter::new(chardev.clone());
+        writer.write_all(b"output").unwrap();
+        assert_eq!(read_exact_bytes(&mut client, 6), b"output");
+
+        client.write_all(b"input").unwrap();
+        let input = client_notifiers[0].handlers[0].lock().unwrap();
+        assert!(input(EventSet::IN, client_fd).is_none());
+        assert_eq!(*received.lock().unwrap(), b"input");
+
+        // The client is removed once it's closed, and the listener is
+        // reactivated.
+        drop(client);
+        let notifiers = input(EventSet::IN | EventSet::HANG_UP, client_fd).unwrap();
+        assert_eq!(notifiers[0].raw_fd, client_fd);
+        assert_eq!(notifiers[0].parked_fd, Some(listener_fd));
+        assert_eq!(chardev.lock().unwrap().delete_notifiers().len(), 1);
+
+        chardev.lock().unwrap().cleanup();
+        assert!(!std::path::Path::new(path).exists());
+    }
+}
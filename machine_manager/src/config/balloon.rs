@@ -0,0 +1,136 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+extern crate serde;
+extern crate serde_json;
+
+use serde::{Deserialize, Serialize};
+
+use super::errors::Result;
+use crate::config::{CmdParams, ConfigCheck, ParamOperation, VmConfig};
+
+/// Config structure for virtio-balloon.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonConfig {
+    /// Whether guest deflates the balloon when it's out of memory.
+    #[serde(default)]
+    pub deflate_on_oom: bool,
+    /// Whether guest reports its free pages, which are returned to host.
+    #[serde(default)]
+    pub free_page_reporting: bool,
+}
+
+impl ConfigCheck for BalloonConfig {
+    fn check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    /// Update '-balloon ...' config to `VmConfig`.
+    ///
+    /// # Notes
+    ///
+    /// Panic if the options can't be parsed, or a balloon device is already
+    /// configured, only one is supported.
+    pub fn update_balloon(&mut self, balloon_config: String) {
+        if self.balloon.is_some() {
+            panic!("Only one balloon device can be configured");
+        }
+        let balloon = parse_balloon(&balloon_config)
+            .unwrap_or_else(|e| panic!("Invalid balloon '{}': {}", balloon_config, e));
+        self.balloon = Some(balloon);
+    }
+}
+
+/// Parse the options of `-balloon`, such as
+/// `deflate-on-oom=true,free-page-reporting=false`.
+///
+/// # Errors
+///
+/// An option is unknown or not a boolean.
+fn parse_balloon(balloon_config: &str) -> Result<BalloonConfig> {
+    let cmd_params: CmdParams = CmdParams::from_str(balloon_config.to_string());
+    let mut balloon = BalloonConfig::default();
+    for param in cmd_params.params.iter() {
+        match param.param_type.as_str() {
+            "deflate-on-oom" => balloon.deflate_on_oom = param.parse_bool()?,
+            "free-page-reporting" => balloon.free_page_reporting = param.parse_bool()?,
+            "" if param.value.is_empty() => (),
+            _ => bail!("Unknown option '{}'", param),
+        }
+    }
+    Ok(balloon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_balloon() {
+        let cases = [
+            ("", false, false),
+            ("deflate-on-oom=true", true, false),
+            ("free-page-reporting=on", false, true),
+            ("deflate-on-oom=off,free-page-reporting=yes", false, true),
+            ("deflate-on-oom=true,free-page-reporting=true", true, true),
+        ];
+        for (cmdline, deflate_on_oom, free_page_reporting) in cases.iter() {
+            let mut vm_config = VmConfig::default();
+            vm_config.update_balloon(cmdline.to_string());
+            let balloon = vm_config.balloon.unwrap();
+            assert_eq!(balloon.deflate_on_oom, *deflate_on_oom, "{}", cmdline);
+            assert_eq!(
+                balloon.free_page_reporting, *free_page_reporting,
+                "{}",
+                cmdline
+            );
+            assert!(balloon.check().is_ok());
+        }
+
+        // The same options are given by `-device`.
+        let mut vm_config = VmConfig::default();
+        vm_config.update_device("virtio-balloon,deflate-on-oom=on".to_string());
+        assert_eq!(
+            vm_config.balloon,
+            Some(BalloonConfig {
+                deflate_on_oom: true,
+                free_page_reporting: false,
+            })
+        );
+
+        let errors = [
+            (
+                "deflate-on-oom=1",
+                "Can only give `yes`,`on`,`true`,`no`,`off`,`false` for boolean.",
+            ),
+            (
+                "stats-polling-interval=2",
+                "Unknown option 'stats-polling-interval=2'",
+            ),
+            ("deflate-on-oom", "Unknown option 'deflate-on-oom'"),
+        ];
+        for (cmdline, error) in errors.iter() {
+            assert_eq!(parse_balloon(cmdline).unwrap_err().to_string(), *error);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Only one balloon device can be configured")]
+    fn test_update_balloon_twice() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_balloon("deflate-on-oom=true".to_string());
+        vm_config.update_device("virtio-balloon".to_string());
+    }
+}
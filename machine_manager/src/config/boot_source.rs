@@ -24,9 +24,12 @@ use crate::config::{ConfigCheck, Param, ParamOperation, VmConfig};
 
 const MAX_STRING_LENGTH: usize = 255;
 const MAX_PATH_LENGTH: usize = 4096;
+/// Kernel params which StratoVirt may append several times with different
+/// values, only the same param is dropped as a duplicate.
+const REPEATABLE_VMM_PARAMS: [&str; 1] = ["virtio_mmio.device"];
 
 /// Config struct for boot-source.
-/// Contains `kernel_file`, `kernel_cmdline` and `initrd`.
+/// Contains `kernel_file`, `kernel_cmdline`, `initrd`, `dtb` and `bios`.
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct BootSource {
     /// Path of the kernel image.
@@ -35,30 +38,20 @@ pub struct BootSource {
     pub kernel_cmdline: KernelParams,
     /// Config of initrd.
     pub initrd: Option<InitrdConfig>,
+    /// Path of the user-provided device tree blob, which replaces the
+    /// generated one on aarch64.
+    #[serde(default)]
+    pub dtb: Option<PathBuf>,
+    /// Path of the firmware image, which is mapped read-only and executed
+    /// from the reset state of cpu. Kernel is optional when it's given.
+    #[serde(default)]
+    pub bios: Option<PathBuf>,
 }
 
 impl BootSource {
-    /// Create `BootSource` from `Value` structure.
-    ///
-    /// # Arguments
-    ///
-    /// * `Value` - structure can be gotten by `json_file`.
-    pub fn from_value(value: &serde_json::Value) -> Self {
-        let mut boot_source = BootSource::default();
-        if value.get("kernel_image_path") != None {
-            boot_source.kernel_file =
-                PathBuf::from(&(value["kernel_image_path"].to_string().replace("\"", "")));
-        }
-        if value.get("boot_args") != None {
-            boot_source.kernel_cmdline =
-                KernelParams::from_str((value["boot_args"]).to_string().replace("\"", ""))
-        }
-        if value.get("initrd_fs_path") != None {
-            boot_source.initrd = Some(InitrdConfig::new(
-                &(value["initrd_fs_path"].to_string().replace("\"", "")),
-            ))
-        }
-        boot_source
+    /// Whether a kernel is given, it's optional when booting from bios.
+    pub fn has_kernel(&self) -> bool {
+        !self.kernel_file.as_os_str().is_empty()
     }
 
     /// Move all the elements of `other` into `Self.kernel_cmdline`.
@@ -69,16 +62,35 @@ impl BootSource {
 
 impl ConfigCheck for BootSource {
     fn check(&self) -> Result<()> {
-        if self.kernel_file.to_str().unwrap().len() > MAX_PATH_LENGTH {
-            return Err(ErrorKind::StringLengthTooLong(
-                "kernel_file path".to_string(),
-                MAX_PATH_LENGTH,
-            )
-            .into());
+        if let Some(bios) = &self.bios {
+            if bios.to_str().unwrap().len() > MAX_PATH_LENGTH {
+                return Err(ErrorKind::StringLengthTooLong(
+                    "bios path".to_string(),
+                    MAX_PATH_LENGTH,
+                )
+                .into());
+            }
+            if !bios.is_file() {
+                return Err(ErrorKind::UnRegularFile("Input bios".to_string()).into());
+            }
         }
 
-        if !self.kernel_file.is_file() {
-            return Err(ErrorKind::UnRegularFile("Input kernel_file".to_string()).into());
+        if self.has_kernel() {
+            if self.kernel_file.to_str().unwrap().len() > MAX_PATH_LENGTH {
+                return Err(ErrorKind::StringLengthTooLong(
+                    "kernel_file path".to_string(),
+                    MAX_PATH_LENGTH,
+                )
+                .into());
+            }
+
+            if !self.kernel_file.is_file() {
+                return Err(ErrorKind::UnRegularFile("Input kernel_file".to_string()).into());
+            }
+        } else if self.bios.is_none() {
+            bail!("Either kernel or bios should be set to boot VM.");
+        } else if self.initrd.is_some() {
+            bail!("Initrd can't be set without kernel.");
         }
 
         self.kernel_cmdline.check()?;
@@ -86,6 +98,22 @@ impl ConfigCheck for BootSource {
             self.initrd.as_ref().unwrap().check()?;
         }
 
+        if let Some(dtb) = &self.dtb {
+            if cfg!(not(target_arch = "aarch64")) {
+                bail!("Device tree blob is only supported on aarch64.");
+            }
+            if dtb.to_str().unwrap().len() > MAX_PATH_LENGTH {
+                return Err(ErrorKind::StringLengthTooLong(
+                    "dtb path".to_string(),
+                    MAX_PATH_LENGTH,
+                )
+                .into());
+            }
+            if !dtb.is_file() {
+                return Err(ErrorKind::UnRegularFile("Input dtb".to_string()).into());
+            }
+        }
+
         Ok(())
     }
 }
@@ -157,16 +185,16 @@ impl ParamOperation for KernelParams {
         KernelParams { params, length }
     }
 
-    /// Created `Kernel` from `String`.
+    /// Created `Kernel` from `String`, params are separated by whitespaces
+    /// and empty ones are dropped, so that `to_string()` is canonical.
+    /// Same as linux kernel, whitespaces between double quotes don't
+    /// separate params, and the quotes are kept verbatim.
     fn from_str(kernel_cmdline: String) -> Self {
-        let split = kernel_cmdline.split(' ');
-        let vec = split.collect::<Vec<&str>>();
-        let mut params: Vec<Param> = Vec::new();
-        let mut length: usize = 0;
-        for item in vec {
-            params.push(Param::from_str(item));
-            length += 1;
-        }
+        let params = split_kernel_cmdline(&kernel_cmdline)
+            .into_iter()
+            .map(Param::from_str)
+            .collect::<Vec<Param>>();
+        let length = params.len();
         KernelParams { params, length }
     }
 }
@@ -174,6 +202,10 @@ impl ParamOperation for KernelParams {
 impl ConfigCheck for KernelParams {
     fn check(&self) -> Result<()> {
         for param in self.params.clone() {
+            // Params appended by StratoVirt would be swallowed by the quote.
+            if param.to_string().matches('"').count() % 2 != 0 {
+                bail!("Unterminated quote in kernel params: {}", param);
+            }
             if param.value.len() > MAX_STRING_LENGTH {
                 return Err(ErrorKind::StringLengthTooLong(
                     "kernel params".to_string(),
@@ -206,6 +238,28 @@ impl KernelParams {
         self.params.append(items);
     }
 
+    /// Push `Param` generated by StratoVirt to `KernelParams`, unless a param
+    /// of the same name is already given, e.g. `console=` set by user is
+    /// never given twice. For params in `REPEATABLE_VMM_PARAMS`, only the
+    /// same param is dropped. Return whether `item` is pushed.
+    ///
+    /// # Arguments
+    ///
+    /// * `item` - The param generated by StratoVirt.
+    pub fn push_vmm_param(&mut self, item: Param) -> bool {
+        let name = param_name(&item);
+        let given = if REPEATABLE_VMM_PARAMS.contains(&name) {
+            self.params.contains(&item)
+        } else {
+            self.params.iter().any(|param| param_name(param) == name)
+        };
+        if given {
+            return false;
+        }
+        self.push(item);
+        true
+    }
+
     /// Check `KernelParam` whether contains `item` or not.
     pub fn contains(&self, item: &str) -> bool {
         for i in 0..self.length {
@@ -227,13 +281,49 @@ impl fmt::Display for KernelParams {
     }
 }
 
+/// Get the name of kernel param, which is the whole param if it has no `=`.
+fn param_name(param: &Param) -> &str {
+    if param.param_type.is_empty() {
+        &param.value
+    } else {
+        &param.param_type
+    }
+}
+
+/// Split kernel cmdline into params by whitespaces out of double quotes.
+/// An unterminated quote extends to the end of cmdline.
+fn split_kernel_cmdline(cmdline: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut start = None;
+    let mut in_quote = false;
+    for (i, c) in cmdline.char_indices() {
+        if c.is_whitespace() && !in_quote {
+            if let Some(begin) = start.take() {
+                params.push(&cmdline[begin..i]);
+            }
+            continue;
+        }
+        if start.is_none() {
+            start = Some(i);
+        }
+        if c == '"' {
+            in_quote = !in_quote;
+        }
+    }
+    if let Some(begin) = start {
+        params.push(&cmdline[begin..]);
+    }
+    params
+}
+
 impl VmConfig {
     /// Update `-kernel kernel_file` config to `VmConfig`
     pub fn update_kernel(&mut self, kernel_image: String) {
         self.boot_source.kernel_file = PathBuf::from(kernel_image);
     }
 
-    /// Update  `-append kernel_cmdline` config to `VmConfig`
+    /// Update  `-append kernel_cmdline` config to `VmConfig`. `-append` can
+    /// be given more than once, the fragments are concatenated in order.
     pub fn update_kernel_cmdline(&mut self, cmdline: &[String]) {
         let cmdline: String = cmdline.join(" ");
         self.boot_source.kernel_cmdline = KernelParams::from_str(cmdline);
@@ -243,12 +333,22 @@ impl VmConfig {
     pub fn update_initrd(&mut self, initrd: String) {
         self.boot_source.initrd = Some(InitrdConfig::new(&initrd));
     }
+
+    /// Update `-dtb dtb_path` config to `VmConfig`
+    pub fn update_dtb(&mut self, dtb: String) {
+        self.boot_source.dtb = Some(PathBuf::from(dtb));
+    }
+
+    /// Update `-bios bios_path` config to `VmConfig`
+    pub fn update_bios(&mut self, bios: String) {
+        self.boot_source.bios = Some(PathBuf::from(bios));
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::{Param, ParamOperation};
-    use super::KernelParams;
+    use super::super::{ConfigCheck, Param, ParamOperation, VmConfig};
+    use super::{BootSource, KernelParams};
 
     #[test]
     fn test_kernel_params() {
@@ -266,4 +366,125 @@ mod tests {
             "reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0 maxcpus=8"
         );
     }
+
+    #[test]
+    fn test_kernel_params_assembly() {
+        // redundant whitespaces are dropped
+        let mut params = KernelParams::from_str("  console=ttyS0   quiet ".to_string());
+        assert_eq!(params.length, 2);
+        assert_eq!(params.to_string(), "console=ttyS0 quiet");
+
+        // value with `=` is kept as a whole
+        let root = KernelParams::from_str("root=PARTUUID=1234-01 ro".to_string());
+        assert_eq!(root.params[0].param_type, "root");
+        assert_eq!(root.params[0].value, "PARTUUID=1234-01");
+        assert_eq!(root.to_string(), "root=PARTUUID=1234-01 ro");
+
+        // params appended by VMM follow user's params in order
+        let mut empty = KernelParams::from_str(String::new());
+        assert_eq!(empty.length, 0);
+        empty.push(Param::from_str("virtio_mmio.device=512@0xd0000000:5"));
+        assert_eq!(empty.to_string(), "virtio_mmio.device=512@0xd0000000:5");
+        params.append(&mut vec![
+            Param::from_str("earlycon"),
+            Param::from_str("panic=1"),
+        ]);
+        assert_eq!(params.to_string(), "console=ttyS0 quiet earlycon panic=1");
+    }
+
+    #[test]
+    fn test_kernel_params_quoting() {
+        // whitespaces in double quotes are kept with the quotes
+        let cmdline = r#"root=/dev/vda dyndbg="file virtio.c +p" "quoted param" a="b"c"#;
+        let params = KernelParams::from_str(cmdline.to_string());
+        assert_eq!(params.length, 4);
+        assert_eq!(params.params[1].param_type, "dyndbg");
+        assert_eq!(params.params[1].value, r#""file virtio.c +p""#);
+        assert_eq!(params.params[2].value, r#""quoted param""#);
+        assert_eq!(params.params[3].value, r#""b"c"#);
+        assert_eq!(params.to_string(), cmdline);
+        assert!(params.check().is_ok());
+
+        // unterminated quote extends to the end
+        let params = KernelParams::from_str(r#"quiet init="/bin/sh -x  "#.to_string());
+        assert_eq!(params.length, 2);
+        assert_eq!(params.params[1].value, r#""/bin/sh -x  "#);
+        assert_eq!(
+            params.check().unwrap_err().to_string(),
+            r#"Unterminated quote in kernel params: init="/bin/sh -x  "#
+        );
+    }
+
+    #[test]
+    fn test_update_kernel_cmdline() {
+        // repeated `-append` are concatenated in order with single spaces
+        let mut vm_config = VmConfig::default();
+        vm_config.update_kernel_cmdline(&[
+            "console=ttyS0  reboot=k".to_string(),
+            r#"dyndbg="module virtio_blk +p""#.to_string(),
+            "panic=1".to_string(),
+        ]);
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            r#"console=ttyS0 reboot=k dyndbg="module virtio_blk +p" panic=1"#
+        );
+    }
+
+    #[test]
+    fn test_push_vmm_param() {
+        let mut params =
+            KernelParams::from_str("console=hvc0 earlycon=pl011,0x9000000".to_string());
+        // params given by user are kept
+        assert!(!params.push_vmm_param(Param::from_str("console=ttyS0")));
+        assert!(!params.push_vmm_param(Param::from_str("earlycon")));
+        assert!(params.push_vmm_param(Param::from_str("panic=1")));
+        assert!(!params.push_vmm_param(Param::from_str("panic=1")));
+
+        // repeatable params are only dropped if the same
+        let mmio = "virtio_mmio.device=512@0xd0000000:5";
+        assert!(params.push_vmm_param(Param::from_str(mmio)));
+        assert!(!params.push_vmm_param(Param::from_str(mmio)));
+        assert!(params.push_vmm_param(Param::from_str("virtio_mmio.device=512@0xd0000200:6")));
+        assert_eq!(params.length, 5);
+        assert_eq!(
+            params.to_string(),
+            "console=hvc0 earlycon=pl011,0x9000000 panic=1 \
+             virtio_mmio.device=512@0xd0000000:5 virtio_mmio.device=512@0xd0000200:6"
+        );
+
+        // bare param is the same as the one with value
+        let mut params = KernelParams::from_str("quiet".to_string());
+        assert!(!params.push_vmm_param(Param::from_str("quiet=1")));
+    }
+
+    #[test]
+    fn test_boot_source_bios() {
+        let json = serde_json::json!({ "boot-source": { "bios_path": "/path/to/bios" } });
+        let boot_source = VmConfig::create_from_value(json).unwrap().boot_source;
+        assert!(!boot_source.has_kernel());
+        assert_eq!(
+            boot_source.bios.as_ref().unwrap().to_str(),
+            Some("/path/to/bios")
+        );
+        // bios file doesn't exist
+        assert!(boot_source.check().is_err());
+
+        // firmware-only boot
+        let bios = std::env::current_exe().unwrap();
+        let mut boot_source = BootSource {
+            bios: Some(bios.clone()),
+            ..Default::default()
+        };
+        assert!(boot_source.check().is_ok());
+
+        // neither kernel nor bios
+        boot_source.bios = None;
+        assert!(boot_source.check().is_err());
+
+        // firmware with kernel
+        boot_source.bios = Some(bios.clone());
+        boot_source.kernel_file = bios;
+        assert!(boot_source.has_kernel());
+        assert!(boot_source.check().is_ok());
+    }
 }
@@ -22,12 +22,295 @@ const MAX_STRING_LENGTH: usize = 255;
 const MAX_PATH_LENGTH: usize = 4096;
 const MAX_GUEST_CID: u64 = 4_294_967_295;
 const MIN_GUEST_CID: u64 = 3;
+/// Max number of ports of virtio-console, including port 0.
+pub const MAX_CONSOLE_PORTS: u32 = 32;
+/// Id of the implicit chardev of `-serial stdio`.
+pub const SERIAL_CHARDEV_ID: &str = "charserial0";
+
+/// Get the id of implicit chardev created for `owner` by the shorthand
+/// which embeds the backend, such as `-serial stdio`.
+pub fn implicit_chardev_id(owner: &str) -> String {
+    format!("char{}", owner)
+}
+
+/// Backend of chardev.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum ChardevType {
+    /// Stdin and stdout of StratoVirt.
+    Stdio,
+    /// Unix domain socket server.
+    Socket { path: String },
+    /// Tcp socket server.
+    Tcp { host: String, port: u16 },
+    /// Pseudo terminal, the path of its slave is logged.
+    Pty,
+    /// File which the output is appended to, it has no input.
+    File { path: String },
+}
+
+/// Config structure for chardev, which is the host side backend of serial,
+/// virtio-console and so on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChardevConfig {
+    pub id: String,
+    #[serde(flatten)]
+    pub backend: ChardevType,
+}
+
+impl ChardevConfig {
+    /// Create `ChardevConfig` from `Value` structure.
+    ///
+    /// # Arguments
+    ///
+    /// * `Value` - structure can be gotten by `json_file`.
+    pub fn from_value(value: &serde_json::Value) -> Option<Vec<Self>> {
+        serde_json::from_value(value.clone()).ok()
+    }
+
+    /// Create `ChardevConfig` from the arguments of `-chardev`, such as
+    /// `socket,id=chr0,path=/path/to/socket`.
+    ///
+    /// # Notes
+    ///
+    /// Unknown backend panics as the other invalid values of cmdline do, the
+    /// missing options are reported by `check`.
+    fn from_cmdline(cmd_params: &CmdParams, backend: &str) -> Self {
+        let path = cmd_params.get_value_str("path").unwrap_or_default();
+        let backend = match backend {
+            "stdio" => ChardevType::Stdio,
+            "socket" => match cmd_params.get_value_str("host") {
+                Some(host) => ChardevType::Tcp {
+                    host,
+                    // Port 0 is reported by `check`.
+                    port: cmd_params
+                        .get_value_u64("port")
+                        .filter(|port| *port <= u64::from(u16::MAX))
+                        .unwrap_or(0) as u16,
+                },
+                None => ChardevType::Socket { path },
+            },
+            "pty" => ChardevType::Pty,
+            "file" => ChardevType::File { path },
+            _ => panic!(
+                "Unknown chardev backend {}, only `stdio`,`socket`,`pty`,`file` are supported.",
+                backend
+            ),
+        };
+        ChardevConfig {
+            id: cmd_params.get_value_str("id").unwrap_or_default(),
+            backend,
+        }
+    }
+}
+
+impl ConfigCheck for ChardevConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.is_empty() {
+            bail!("Chardev id is missing");
+        }
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "chardev id".to_string(),
+                MAX_STRING_LENGTH,
+            )
+            .into());
+        }
+
+        match &self.backend {
+            ChardevType::Socket { path } | ChardevType::File { path } => {
+                if path.is_empty() {
+                    bail!("Path of chardev '{}' is missing", self.id);
+                }
+                if path.len() > MAX_PATH_LENGTH {
+                    return Err(ErrorKind::StringLengthTooLong(
+                        "chardev path".to_string(),
+                        MAX_PATH_LENGTH,
+                    )
+                    .into());
+                }
+            }
+            ChardevType::Tcp { host, port } => {
+                if host.is_empty() || *port == 0 {
+                    bail!(
+                        "Chardev '{}' needs host and port, port should be in 1..65535",
+                        self.id
+                    );
+                }
+            }
+            ChardevType::Stdio | ChardevType::Pty => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    /// Add new chardev to `VmConfig`.
+    fn add_chardev(&mut self, chardev: ChardevConfig) {
+        if let Some(mut chardevs) = self.chardevs.clone() {
+            chardevs.push(chardev);
+            self.chardevs = Some(chardevs);
+        } else {
+            let mut chardevs: Vec<ChardevConfig> = Vec::new();
+            chardevs.push(chardev);
+            self.chardevs = Some(chardevs);
+        }
+    }
+
+    /// Update '-chardev ...' config to `VmConfig`.
+    ///
+    /// # Notes
+    ///
+    /// The shorthand without backend, `id=console_id,path=socket_path`,
+    /// adds a virtio-console with an implicit unix socket chardev.
+    pub fn update_chardev(&mut self, chardev_config: String) {
+        let cmd_params: CmdParams = CmdParams::from_str(chardev_config);
+        match cmd_params.get_value_str("") {
+            Some(backend) => {
+                let chardev = ChardevConfig::from_cmdline(&cmd_params, &backend);
+                self.add_chardev(chardev);
+            }
+            None => {
+                let mut console = ConsoleConfig::default();
+                if let Some(console_id) = cmd_params.get_value_str("id") {
+                    console.console_id = console_id;
+                }
+                console.chardev = implicit_chardev_id(&console.console_id);
+                if let Some(max_ports) = cmd_params.get_value_u64("max_ports") {
+                    // Out of range is reported by `check`.
+                    console.max_ports = max_ports.min(u64::from(u32::MAX)) as u32;
+                }
+                self.add_chardev(ChardevConfig {
+                    id: console.chardev.clone(),
+                    backend: ChardevType::Socket {
+                        path: cmd_params.get_value_str("path").unwrap_or_default(),
+                    },
+                });
+                self.add_console(console);
+            }
+        }
+    }
+
+    /// Get the config of chardev with `id`.
+    pub fn get_chardev(&self, id: &str) -> Option<&ChardevConfig> {
+        self.chardevs
+            .as_ref()
+            .and_then(|chardevs| chardevs.iter().find(|chardev| chardev.id == id))
+    }
+
+    /// Check that ids of chardevs are unique, and every chardev referred by
+    /// devices exists and is used only once.
+    pub(crate) fn check_chardevs(&self, is_daemonize: bool) -> Result<()> {
+        let chardevs = self.chardevs.as_deref().unwrap_or(&[]);
+        for (index, chardev) in chardevs.iter().enumerate() {
+            chardev.check()?;
+            if chardevs[..index].iter().any(|other| other.id == chardev.id) {
+                bail!("Duplicate ID '{}' for chardev", chardev.id);
+            }
+            if chardev.backend == ChardevType::Stdio && is_daemonize {
+                bail!(
+                    "Chardev '{}' with stdio backend can't be used with -daemonize, use socket, pty or file instead",
+                    chardev.id
+                );
+            }
+        }
+
+        let mut used: Vec<&str> = Vec::new();
+        let serial = self
+            .serial
+            .as_ref()
+            .and_then(|serial| serial.chardev.as_deref());
+        let consoles = self
+            .consoles
+            .iter()
+            .flatten()
+            .map(|console| console.chardev.as_str());
+        for id in serial.into_iter().chain(consoles) {
+            if self.get_chardev(id).is_none() {
+                bail!("Chardev '{}' not found", id);
+            }
+            if used.contains(&id) {
+                bail!("Chardev '{}' is used by more than one device", id);
+            }
+            used.push(id);
+        }
+        Ok(())
+    }
+}
+
+/// Convert the legacy configs of json file, which embed the backend, to
+/// references to implicit chardevs, that is `socket_path` of console and
+/// `stdio` of serial.
+///
+/// # Arguments
+///
+/// * `value` - The whole config of json file.
+pub(crate) fn convert_legacy_chardevs(value: &mut serde_json::Value) {
+    let mut implicit = Vec::new();
+
+    if let Some(consoles) = value.get_mut("console").and_then(|v| v.as_array_mut()) {
+        for console in consoles.iter_mut().filter_map(|v| v.as_object_mut()) {
+            let path = match console.remove("socket_path") {
+                Some(path) if !console.contains_key("chardev") => path,
+                _ => continue,
+            };
+            let console_id = console
+                .get("console_id")
+                .and_then(|id| id.as_str())
+                .unwrap_or_default();
+            let id = implicit_chardev_id(console_id);
+            implicit.push(serde_json::json!({ "id": id, "backend": "socket", "path": path }));
+            console.insert("chardev".to_string(), id.into());
+        }
+    }
+
+    if let Some(serial) = value.get_mut("serial").and_then(|v| v.as_object_mut()) {
+        if let Some(stdio) = serial.remove("stdio") {
+            if stdio.as_bool() == Some(true) && !serial.contains_key("chardev") {
+                implicit.push(serde_json::json!({ "id": SERIAL_CHARDEV_ID, "backend": "stdio" }));
+                serial.insert("chardev".to_string(), SERIAL_CHARDEV_ID.into());
+            }
+        }
+    }
+
+    if implicit.is_empty() {
+        return;
+    }
+    if let Some(map) = value.as_object_mut() {
+        match map.get_mut("chardev").and_then(|v| v.as_array_mut()) {
+            Some(chardevs) => chardevs.extend(implicit),
+            None => {
+                map.insert("chardev".to_string(), implicit.into());
+            }
+        }
+    }
+}
 
 /// Config structure for virtio-console.
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConsoleConfig {
     pub console_id: String,
-    pub socket_path: String,
+    /// Id of chardev as the backend of port 0.
+    pub chardev: String,
+    /// Max number of ports including port 0, the console is multiport if
+    /// it's more than 1, so that ports can be hot added.
+    #[serde(default = "default_max_ports")]
+    pub max_ports: u32,
+}
+
+fn default_max_ports() -> u32 {
+    1
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        ConsoleConfig {
+            console_id: String::new(),
+            chardev: String::new(),
+            max_ports: default_max_ports(),
+        }
+    }
 }
 
 impl ConsoleConfig {
@@ -51,10 +334,16 @@ impl ConfigCheck for ConsoleConfig {
             .into());
         }
 
-        if self.socket_path.len() > MAX_PATH_LENGTH {
-            return Err(
-                ErrorKind::StringLengthTooLong("socket path".to_string(), MAX_PATH_LENGTH).into(),
-            );
+        if self.chardev.len() > MAX_STRING_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "chardev id".to_string(),
+                MAX_STRING_LENGTH,
+            )
+            .into());
+        }
+
+        if self.max_ports == 0 || self.max_ports > MAX_CONSOLE_PORTS {
+            return Err(ErrorKind::ConsolePortsError.into());
         }
 
         Ok(())
@@ -74,15 +363,19 @@ impl VmConfig {
         }
     }
 
-    /// Update '-console ...' network config to `VmConfig`.
+    /// Update '-device virtio-console,...' config to `VmConfig`.
     pub fn update_console(&mut self, console_config: String) {
         let cmd_params: CmdParams = CmdParams::from_str(console_config);
         let mut console = ConsoleConfig::default();
         if let Some(console_id) = cmd_params.get("id") {
             console.console_id = console_id.value;
         }
-        if let Some(console_path) = cmd_params.get("path") {
-            console.socket_path = console_path.value;
+        if let Some(chardev) = cmd_params.get("chardev") {
+            console.chardev = chardev.value;
+        }
+        if let Some(max_ports) = cmd_params.get_value_u64("max_ports") {
+            // Out of range is reported by `check`.
+            console.max_ports = max_ports.min(u64::from(u32::MAX)) as u32;
         }
         self.add_console(console);
     }
@@ -99,9 +392,13 @@ impl VmConfig {
     }
 }
 
+/// Config structure for serial.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SerialConfig {
-    pub stdio: bool,
+    /// Id of chardev as the backend, the output is written to stdout
+    /// without input if it's not set.
+    #[serde(default)]
+    pub chardev: Option<String>,
 }
 
 impl SerialConfig {
@@ -116,15 +413,25 @@ impl SerialConfig {
 }
 
 impl VmConfig {
+    /// Update '-serial ...' config to `VmConfig`, the backend is given as
+    /// `chardev:id`, or `stdio` for an implicit stdio chardev.
     pub fn update_serial(&mut self, serial_config: String) {
         let cmd_params: CmdParams = CmdParams::from_str(serial_config);
 
         if let Some(serial_type) = cmd_params.get("") {
-            if serial_type.to_string() == "stdio" {
-                self.serial = Some(SerialConfig { stdio: true });
+            let serial_type = serial_type.to_string();
+            let chardev = if serial_type == "stdio" {
+                self.add_chardev(ChardevConfig {
+                    id: SERIAL_CHARDEV_ID.to_string(),
+                    backend: ChardevType::Stdio,
+                });
+                Some(SERIAL_CHARDEV_ID.to_string())
+            } else if serial_type.starts_with("chardev:") {
+                Some(serial_type["chardev:".len()..].to_string())
             } else {
-                self.serial = Some(SerialConfig { stdio: false });
-            }
+                None
+            };
+            self.serial = Some(SerialConfig { chardev });
         }
     }
 }
@@ -134,6 +441,9 @@ impl VmConfig {
 pub struct VsockConfig {
     pub vsock_id: String,
     pub guest_cid: u64,
+    /// Opened fd of vhost-vsock device, which is used instead of opening
+    /// `/dev/vhost-vsock`. StratoVirt uses a duplicate of it, the fd itself
+    /// is never closed.
     pub vhost_fd: Option<i32>,
 }
 
@@ -157,11 +467,33 @@ impl ConfigCheck for VsockConfig {
             return Err(ErrorKind::GuestCidError.into());
         }
 
+        if let Some(vhost_fd) = self.vhost_fd {
+            if vhost_fd < 0 {
+                bail!("Vhost fd {} is illegal", vhost_fd);
+            }
+        }
+
         Ok(())
     }
 }
 
 impl VmConfig {
+    /// Update '-device ...' config to `VmConfig` by the type of device.
+    pub fn update_device(&mut self, device_config: String) {
+        let device_type = device_config.split(',').next().unwrap_or_default();
+        if device_type == "virtio-console" {
+            self.update_console(device_config);
+        } else if device_type == "virtio-balloon" {
+            let options = device_config[device_type.len()..].trim_start_matches(',');
+            self.update_balloon(options.to_string());
+        } else if device_type == "virtio-pmem" {
+            let options = device_config[device_type.len()..].trim_start_matches(',');
+            self.update_pmem(options.to_string());
+        } else {
+            self.update_vsock(device_config);
+        }
+    }
+
     pub fn update_vsock(&mut self, vsock_config: String) {
         let cmd_params: CmdParams = CmdParams::from_str(vsock_config);
 
@@ -177,3 +509,182 @@ impl VmConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_config() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_chardev("socket,id=chr0,path=/tmp/console0.sock".to_string());
+        vm_config.update_device("virtio-console,id=console0,chardev=chr0".to_string());
+        // The shorthand creates an implicit chardev.
+        vm_config.update_chardev("id=console1,path=/tmp/console1.sock,max_ports=8".to_string());
+        let consoles = vm_config.get_virtio_console();
+        assert_eq!(consoles[0].chardev, "chr0");
+        assert_eq!(consoles[0].max_ports, 1);
+        assert_eq!(consoles[1].chardev, "charconsole1");
+        assert_eq!(consoles[1].max_ports, 8);
+        assert!(consoles.iter().all(|console| console.check().is_ok()));
+        assert_eq!(
+            vm_config.get_chardev("charconsole1").unwrap().backend,
+            ChardevType::Socket {
+                path: "/tmp/console1.sock".to_string()
+            }
+        );
+        assert!(vm_config.check_chardevs(false).is_ok());
+
+        let json = serde_json::json!([{ "console_id": "console0", "chardev": "chr0" }]);
+        assert_eq!(ConsoleConfig::from_value(&json).unwrap()[0].max_ports, 1);
+
+        for max_ports in ["0", "33", "4294967296"].iter() {
+            let mut vm_config = VmConfig::default();
+            vm_config.update_chardev(format!(
+                "id=console0,path=/tmp/c.sock,max_ports={}",
+                max_ports
+            ));
+            assert!(vm_config.get_virtio_console()[0].check().is_err());
+        }
+    }
+
+    #[test]
+    fn test_chardev_config() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_chardev("stdio,id=chr0".to_string());
+        vm_config.update_chardev("socket,id=chr1,path=/tmp/chr1.sock,server,nowait".to_string());
+        vm_config.update_chardev("socket,id=chr2,host=127.0.0.1,port=4444".to_string());
+        vm_config.update_chardev("pty,id=chr3".to_string());
+        vm_config.update_chardev("file,id=chr4,path=/tmp/chr4.log".to_string());
+        let backends: Vec<ChardevType> = vm_config
+            .chardevs
+            .iter()
+            .flatten()
+            .map(|chardev| chardev.backend.clone())
+            .collect();
+        assert_eq!(
+            backends,
+            vec![
+                ChardevType::Stdio,
+                ChardevType::Socket {
+                    path: "/tmp/chr1.sock".to_string()
+                },
+                ChardevType::Tcp {
+                    host: "127.0.0.1".to_string(),
+                    port: 4444
+                },
+                ChardevType::Pty,
+                ChardevType::File {
+                    path: "/tmp/chr4.log".to_string()
+                },
+            ]
+        );
+        assert!(vm_config.check_chardevs(false).is_ok());
+        // Stdio can't be used by daemon.
+        assert!(vm_config.check_chardevs(true).is_err());
+
+        // Serial refers to chardev, and `stdio` is the shorthand.
+        vm_config.update_serial("chardev:chr3".to_string());
+        assert_eq!(
+            vm_config.serial.as_ref().unwrap().chardev.as_deref(),
+            Some("chr3")
+        );
+        assert!(vm_config.check_chardevs(false).is_ok());
+        vm_config.update_serial("stdio".to_string());
+        assert_eq!(
+            vm_config.serial.as_ref().unwrap().chardev.as_deref(),
+            Some(SERIAL_CHARDEV_ID)
+        );
+        assert_eq!(
+            vm_config.get_chardev(SERIAL_CHARDEV_ID).unwrap().backend,
+            ChardevType::Stdio
+        );
+        vm_config.update_serial("".to_string());
+        assert_eq!(vm_config.serial.as_ref().unwrap().chardev, None);
+
+        // Missing, duplicated and shared chardevs.
+        let mut vm_config = VmConfig::default();
+        vm_config.update_serial("chardev:chr0".to_string());
+        assert!(vm_config.check_chardevs(false).is_err());
+        vm_config.update_chardev("pty,id=chr0".to_string());
+        assert!(vm_config.check_chardevs(false).is_ok());
+        vm_config.update_device("virtio-console,id=console0,chardev=chr0".to_string());
+        assert!(vm_config.check_chardevs(false).is_err());
+        let mut vm_config = VmConfig::default();
+        vm_config.update_chardev("pty,id=chr0".to_string());
+        vm_config.update_chardev("stdio,id=chr0".to_string());
+        assert!(vm_config.check_chardevs(false).is_err());
+
+        for args in [
+            "socket,path=/tmp/chr.sock",
+            "socket,id=chr0",
+            "socket,id=chr0,host=127.0.0.1",
+            "socket,id=chr0,host=127.0.0.1,port=65536",
+            "file,id=chr0",
+        ]
+        .iter()
+        {
+            let mut vm_config = VmConfig::default();
+            vm_config.update_chardev(args.to_string());
+            assert!(vm_config.check_chardevs(false).is_err(), "{}", args);
+        }
+    }
+
+    #[test]
+    fn test_legacy_chardev_json() {
+        let json = serde_json::json!({
+            "chardev": [{ "id": "chr0", "backend": "tcp", "host": "127.0.0.1", "port": 4444 }],
+            "console": [
+                { "console_id": "console0", "socket_path": "/tmp/console0.sock" },
+                { "console_id": "console1", "chardev": "chr0" }
+            ],
+            "serial": { "stdio": true }
+        });
+        let vm_config = VmConfig::create_from_value(json).unwrap();
+        let consoles = vm_config.get_virtio_console();
+        assert_eq!(consoles[0].chardev, "charconsole0");
+        assert_eq!(consoles[1].chardev, "chr0");
+        assert_eq!(
+            vm_config.serial.as_ref().unwrap().chardev.as_deref(),
+            Some(SERIAL_CHARDEV_ID)
+        );
+        assert_eq!(vm_config.chardevs.as_ref().unwrap().len(), 3);
+        assert_eq!(
+            vm_config.get_chardev("charconsole0").unwrap().backend,
+            ChardevType::Socket {
+                path: "/tmp/console0.sock".to_string()
+            }
+        );
+        assert!(vm_config.check_chardevs(false).is_ok());
+
+        // Serial without stdio writes to stdout only.
+        let json = serde_json::json!({ "serial": { "stdio": false } });
+        let vm_config = VmConfig::create_from_value(json).unwrap();
+        assert_eq!(vm_config.serial.unwrap().chardev, None);
+        assert_eq!(vm_config.chardevs, None);
+    }
+
+    #[test]
+    fn test_vsock_vhost_fd() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_device("vsock,id=vsock0,guest-cid=3".to_string());
+        let vsock = vm_config.vsock.as_ref().unwrap();
+        assert_eq!(vsock.vhost_fd, None);
+        assert!(vsock.check().is_ok());
+
+        vm_config.update_device("vsock,id=vsock0,guest-cid=3,vhostfd=4".to_string());
+        let vsock = vm_config.vsock.as_ref().unwrap();
+        assert_eq!(vsock.vhost_fd, Some(4));
+        assert!(vsock.check().is_ok());
+
+        let vsock = VsockConfig {
+            vsock_id: "vsock0".to_string(),
+            guest_cid: 3,
+            vhost_fd: Some(-1),
+        };
+        assert_eq!(
+            vsock.check().unwrap_err().to_string(),
+            "Vhost fd -1 is illegal"
+        );
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+extern crate serde;
+extern crate serde_json;
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::errors::{ErrorKind, Result, ResultExt};
+use crate::config::{
+    convert_legacy_chardevs, BalloonConfig, BootSource, ChardevConfig, ConsoleConfig, DriveConfig,
+    InitrdConfig, IothreadConfig, KernelParams, MachineConfig, NetworkInterfaceConfig,
+    ParamOperation, PmemConfig, RngConfig, SerialConfig, VmConfig, VsockConfig,
+};
+use crate::json_error::describe;
+
+/// Model of json config file given by `-config`, which is mapped onto
+/// `VmConfig`. The sections of devices are the same as their config
+/// structures.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(rename = "machine-config", default)]
+    machine_config: MachineConfigFile,
+    #[serde(rename = "boot-source", default)]
+    boot_source: BootSourceFile,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iothread: Option<Vec<IothreadConfig>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drive: Option<Vec<DriveConfig>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    net: Option<Vec<NetworkInterfaceConfig>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chardev: Option<Vec<ChardevConfig>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    console: Option<Vec<ConsoleConfig>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    serial: Option<SerialConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vsock: Option<VsockConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    balloon: Option<BalloonConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rng: Option<RngConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pmem: Option<PmemConfig>,
+}
+
+/// Transparent huge page policy, the same as `thp` of `-machine`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ThpPolicy {
+    On,
+    Off,
+    Auto,
+}
+
+/// Whether to append `earlycon`, the same as `earlycon` of `-machine`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EarlyconPolicy {
+    Auto,
+    Off,
+}
+
+/// Section `machine-config`, the fields not given are default.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MachineConfigFile {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    name: String,
+    vcpu_count: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_vcpu_count: Option<u8>,
+    mem_size: u64,
+    omit_vm_memory: bool,
+    mem_merge: bool,
+    thp: ThpPolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_slot_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mem_path: Option<String>,
+    earlycon: EarlyconPolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    uuid_on_cmdline: bool,
+    boot_marker: bool,
+}
+
+impl Default for MachineConfigFile {
+    fn default() -> Self {
+        MachineConfigFile::from(&MachineConfig::default())
+    }
+}
+
+impl From<&MachineConfig> for MachineConfigFile {
+    fn from(config: &MachineConfig) -> Self {
+        MachineConfigFile {
+            name: config.name.clone(),
+            vcpu_count: config.nr_cpus,
+            max_vcpu_count: config.max_cpus,
+            mem_size: config.mem_size,
+            omit_vm_memory: config.omit_vm_memory,
+            mem_merge: config.mem_merge,
+            thp: match config.thp {
+                Some(true) => ThpPolicy::On,
+                Some(false) => ThpPolicy::Off,
+                None => ThpPolicy::Auto,
+            },
+            max_slot_size: config.max_slot_size,
+            mem_path: config.mem_path.clone(),
+            earlycon: if config.earlycon {
+                EarlyconPolicy::Auto
+            } else {
+                EarlyconPolicy::Off
+            },
+            uuid: config.uuid.clone(),
+            uuid_on_cmdline: config.uuid_on_cmdline,
+            boot_marker: config.boot_marker,
+        }
+    }
+}
+
+impl From<MachineConfigFile> for MachineConfig {
+    fn from(file: MachineConfigFile) -> Self {
+        MachineConfig {
+            name: file.name,
+            nr_cpus: file.vcpu_count,
+            max_cpus: file.max_vcpu_count,
+            mem_size: file.mem_size,
+            omit_vm_memory: file.omit_vm_memory,
+            mem_merge: file.mem_merge,
+            thp: match file.thp {
+                ThpPolicy::On => Some(true),
+                ThpPolicy::Off => Some(false),
+                ThpPolicy::Auto => None,
+            },
+            max_slot_size: file.max_slot_size,
+            mem_path: file.mem_path,
+            earlycon: match file.earlycon {
+                EarlyconPolicy::Auto => true,
+                EarlyconPolicy::Off => false,
+            },
+            uuid: file.uuid,
+            uuid_on_cmdline: file.uuid_on_cmdline,
+            boot_marker: file.boot_marker,
+        }
+    }
+}
+
+/// Section `boot-source`.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BootSourceFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kernel_image_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    boot_args: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initrd_fs_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dtb_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bios_path: Option<PathBuf>,
+}
+
+impl From<&BootSource> for BootSourceFile {
+    fn from(boot_source: &BootSource) -> Self {
+        BootSourceFile {
+            kernel_image_path: if boot_source.has_kernel() {
+                Some(boot_source.kernel_file.clone())
+            } else {
+                None
+            },
+            boot_args: boot_source.kernel_cmdline.to_string(),
+            initrd_fs_path: boot_source
+                .initrd
+                .as_ref()
+                .map(|initrd| initrd.initrd_file.to_string_lossy().to_string()),
+            dtb_path: boot_source.dtb.clone(),
+            bios_path: boot_source.bios.clone(),
+        }
+    }
+}
+
+impl From<BootSourceFile> for BootSource {
+    fn from(file: BootSourceFile) -> Self {
+        BootSource {
+            kernel_file: file.kernel_image_path.unwrap_or_default(),
+            kernel_cmdline: KernelParams::from_str(file.boot_args),
+            initrd: file.initrd_fs_path.map(|initrd| InitrdConfig::new(&initrd)),
+            dtb: file.dtb_path,
+            bios: file.bios_path,
+        }
+    }
+}
+
+impl From<&VmConfig> for ConfigFile {
+    fn from(config: &VmConfig) -> Self {
+        ConfigFile {
+            machine_config: MachineConfigFile::from(&config.machine_config),
+            boot_source: BootSourceFile::from(&config.boot_source),
+            iothread: config.iothreads.clone(),
+            drive: config.drives.clone(),
+            net: config.nets.clone(),
+            chardev: config.chardevs.clone(),
+            console: config.consoles.clone(),
+            serial: config.serial.clone(),
+            vsock: config.vsock.clone(),
+            balloon: config.balloon.clone(),
+            rng: config.rng.clone(),
+            pmem: config.pmem.clone(),
+        }
+    }
+}
+
+impl From<ConfigFile> for VmConfig {
+    fn from(file: ConfigFile) -> Self {
+        VmConfig {
+            machine_config: file.machine_config.into(),
+            boot_source: file.boot_source.into(),
+            drives: file.drive,
+            nets: file.net,
+            chardevs: file.chardev,
+            consoles: file.console,
+            vsock: file.vsock,
+            serial: file.serial,
+            iothreads: file.iothread,
+            balloon: file.balloon,
+            rng: file.rng,
+            pmem: file.pmem,
+        }
+    }
+}
+
+impl VmConfig {
+    /// Create the `VmConfig` from `Value`.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The whole config of json file.
+    ///
+    /// # Errors
+    ///
+    /// * `ConfigFileError` - A field is unexpected, missing or of wrong type,
+    ///   it's cited by its path such as `drive[0].read_only`.
+    pub fn create_from_value(mut value: Value) -> Result<VmConfig> {
+        if !value.is_object() {
+            bail!("Config file should be a json object.");
+        }
+        convert_legacy_chardevs(&mut value);
+
+        // Parse from pretty printed json, so that the field in error can be
+        // found by the line of error.
+        let text =
+            serde_json::to_string_pretty(&value).chain_err(|| "Failed to format config file")?;
+        match serde_json::from_str::<ConfigFile>(&text) {
+            Ok(file) => Ok(file.into()),
+            Err(e) => match describe(&e, &text) {
+                Some((path, problem)) => Err(ErrorKind::ConfigFileError(path, problem).into()),
+                None => bail!("Invalid config file: {}.", e),
+            },
+        }
+    }
+
+    /// Get the json of `VmConfig` in the format of config file, which
+    /// `create_from_value` creates the same `VmConfig` from.
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(ConfigFile::from(self)).unwrap()
+    }
+
+    /// Get the paths of fields given by config file, whose value is
+    /// different in `VmConfig`, that is, overridden by cmdline.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The whole config of json file.
+    pub fn overridden_fields(&self, value: &Value) -> Vec<String> {
+        let mut value = value.clone();
+        convert_legacy_chardevs(&mut value);
+        let mut fields = Vec::new();
+        diff_fields(&value, &self.to_value(), String::new(), &mut fields);
+        fields
+    }
+}
+
+/// Push the path of every field of `given` to `fields`, if it's not the
+/// same in `current`.
+fn diff_fields(given: &Value, current: &Value, path: String, fields: &mut Vec<String>) {
+    match given {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let current = current.get(key).unwrap_or(&Value::Null);
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_fields(value, current, path, fields);
+            }
+        }
+        Value::Array(array) => {
+            for (index, value) in array.iter().enumerate() {
+                let current = current.get(index).unwrap_or(&Value::Null);
+                diff_fields(value, current, format!("{}[{}]", path, index), fields);
+            }
+        }
+        _ => {
+            if given != current {
+                fields.push(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChardevType;
+
+    fn load(text: &str) -> Result<VmConfig> {
+        VmConfig::create_from_value(serde_json::from_str(text).unwrap())
+    }
+
+    #[test]
+    fn test_config_file_full() {
+        let vm_config = load(include_str!("fixtures/full.json")).unwrap();
+
+        let machine = &vm_config.machine_config;
+        assert_eq!(machine.vm_name(), Some("vm-full"));
+        assert_eq!(machine.nr_cpus, 4);
+        assert_eq!(machine.max_cpus, Some(8));
+        assert_eq!(machine.mem_size, 2 * 1024 * 1024 * 1024);
+        assert_eq!(machine.thp, Some(true));
+        assert_eq!(machine.max_slot_size, Some(1024 * 1024 * 1024));
+        assert_eq!(machine.mem_path.as_deref(), Some("/dev/hugepages/vm-full"));
+        assert!(machine.mem_merge && machine.earlycon && machine.uuid_on_cmdline);
+        assert!(machine.boot_marker);
+        assert_eq!(
+            vm_config.boot_source.kernel_cmdline.to_string(),
+            "console=ttyS0 reboot=k panic=1 root=/dev/vda rw"
+        );
+        assert_eq!(vm_config.iothreads.as_ref().unwrap().len(), 1);
+        let drives = vm_config.drives.as_ref().unwrap();
+        assert_eq!(drives.len(), 2);
+        assert_eq!(drives[1].iothread.as_deref(), Some("iothread0"));
+        assert_eq!(vm_config.nets.as_ref().unwrap()[0].host_dev_name, "tap0");
+        assert_eq!(
+            vm_config.get_chardev("pty0").unwrap().backend,
+            ChardevType::Pty
+        );
+        assert_eq!(vm_config.consoles.as_ref().unwrap()[0].max_ports, 4);
+        assert_eq!(
+            vm_config.serial.as_ref().unwrap().chardev.as_deref(),
+            Some("serial0")
+        );
+        assert_eq!(vm_config.vsock.as_ref().unwrap().guest_cid, 3);
+        assert!(vm_config.balloon.as_ref().unwrap().deflate_on_oom);
+        assert_eq!(vm_config.rng.as_ref().unwrap().bytes_per_sec, Some(1024));
+        assert_eq!(vm_config.pmem.as_ref().unwrap().size, 1024 * 1024 * 1024);
+
+        // What `-dump-config` prints is loaded to the same config.
+        let value = vm_config.to_value();
+        assert_eq!(load(&value.to_string()).unwrap().to_value(), value);
+        assert!(vm_config
+            .overridden_fields(&serde_json::from_str(include_str!("fixtures/full.json")).unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_config_file_invalid() {
+        let cases = [
+            (
+                include_str!("fixtures/invalid_type.json"),
+                "Field 'drive[1].read_only' of config file expects a boolean.",
+            ),
+            (
+                include_str!("fixtures/unknown_field.json"),
+                "Field 'machine-config.vcpus' of config file is unexpected.",
+            ),
+            (
+                include_str!("fixtures/unknown_section.json"),
+                "Field 'machine_config' of config file is unexpected.",
+            ),
+            (
+                include_str!("fixtures/missing_field.json"),
+                "Field 'net[0].host_dev_name' of config file is missing.",
+            ),
+            (
+                include_str!("fixtures/unknown_variant.json"),
+                "Field 'machine-config.thp' of config file expects one of `on`, `off`, `auto`.",
+            ),
+            ("[]", "Config file should be a json object."),
+        ];
+        for (text, error) in cases.iter() {
+            assert_eq!(load(text).unwrap_err().to_string(), *error, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_overridden_fields() {
+        let value: Value = serde_json::from_str(include_str!("fixtures/full.json")).unwrap();
+        let mut vm_config = VmConfig::create_from_value(value.clone()).unwrap();
+
+        vm_config.update_cpu("2".to_string());
+        vm_config.update_kernel_cmdline(&["console=ttyS0".to_string()]);
+        // Same as config file, or not given by it.
+        vm_config.update_name("vm-full".to_string());
+        vm_config.update_iothread("id=iothread1".to_string());
+        assert_eq!(
+            vm_config.overridden_fields(&value),
+            vec!["boot-source.boot_args", "machine-config.vcpu_count"]
+        );
+
+        // Legacy `stdio` of serial is compared as its implicit chardev.
+        let legacy = serde_json::json!({ "serial": { "stdio": true } });
+        let vm_config = VmConfig::create_from_value(legacy.clone()).unwrap();
+        assert!(vm_config.overridden_fields(&legacy).is_empty());
+    }
+}
@@ -16,12 +16,95 @@ extern crate serde_json;
 use serde::{Deserialize, Serialize};
 
 use super::errors::{ErrorKind, Result};
-use crate::config::{CmdParams, ConfigCheck, ParamOperation, VmConfig};
+use crate::config::{CmdParams, ConfigCheck, Param, ParamOperation, VmConfig};
+#[cfg(feature = "qmp")]
+use crate::qmp::qmp_schema::{
+    blockdev_add, BlockdevAioOptions, BlockdevDetectZeroesOptions, BlockdevDiscardOptions,
+};
 
 const MAX_STRING_LENGTH: usize = 255;
 const MAX_PATH_LENGTH: usize = 4096;
 const MAX_SERIAL_NUM: usize = 20;
 
+/// Engine to submit IO of block device.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AioEngine {
+    /// IO is done synchronously in the thread handling virtqueue.
+    #[serde(rename = "threads")]
+    Threads,
+    /// Linux native aio.
+    #[serde(rename = "native")]
+    Native,
+    /// IO is submitted by io_uring.
+    #[serde(rename = "io_uring")]
+    IoUring,
+}
+
+/// How to handle writes of zeroes to block device.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DetectZeroes {
+    /// Written as normal data.
+    #[serde(rename = "off")]
+    Off,
+    /// Zero the range by fallocate instead of writing.
+    #[serde(rename = "on")]
+    On,
+    /// Punch a hole in the range, it requires `discard`.
+    #[serde(rename = "unmap")]
+    Unmap,
+}
+
+/// Format of the image of block device.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DiskFormat {
+    #[serde(rename = "raw")]
+    Raw,
+    /// Not supported by virtio-blk for now, it's rejected by `check`.
+    #[serde(rename = "qcow2")]
+    Qcow2,
+}
+
+impl Default for DiskFormat {
+    fn default() -> Self {
+        DiskFormat::Raw
+    }
+}
+
+/// Cache mode of `-drive`, which is a shorthand of `direct`, `writeback`
+/// and `no_flush`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheMode {
+    /// Direct IO with write cache, flush requests sync the image.
+    None,
+    /// Buffered IO with write cache, flush requests sync the image.
+    Writeback,
+    /// Buffered IO with write cache, flush requests are ignored.
+    Unsafe,
+}
+
+impl CacheMode {
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "none" => Ok(CacheMode::None),
+            "writeback" => Ok(CacheMode::Writeback),
+            "unsafe" => Ok(CacheMode::Unsafe),
+            _ => Err(ErrorKind::DriveOptionError(format!(
+                "cache={} is unknown, it should be `none`, `writeback` or `unsafe`",
+                mode
+            ))
+            .into()),
+        }
+    }
+}
+
+fn default_writeback() -> bool {
+    true
+}
+
+fn default_detect_zeroes() -> DetectZeroes {
+    DetectZeroes::Off
+}
+
 /// Config struct for `drive`.
 /// Contains block device's attr.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +115,25 @@ pub struct DriveConfig {
     pub read_only: bool,
     pub direct: bool,
     pub serial_num: Option<String>,
+    pub iothread: Option<String>,
+    /// Whether guest sees a volatile write cache, otherwise every write is
+    /// synced to the image before completed.
+    #[serde(default = "default_writeback")]
+    pub writeback: bool,
+    /// Complete flush requests of guest without syncing the image.
+    #[serde(default)]
+    pub no_flush: bool,
+    /// Engine to submit IO. If not set, io_uring is used for direct IO and
+    /// threads for the others.
+    #[serde(default)]
+    pub aio: Option<AioEngine>,
+    /// Whether blocks of the image may be released.
+    #[serde(default)]
+    pub discard: bool,
+    #[serde(default = "default_detect_zeroes")]
+    pub detect_zeroes: DetectZeroes,
+    #[serde(default)]
+    pub format: DiskFormat,
 }
 
 impl DriveConfig {
@@ -43,6 +145,70 @@ impl DriveConfig {
     pub fn from_value(value: &serde_json::Value) -> Option<Vec<Self>> {
         serde_json::from_value(value.clone()).ok()
     }
+
+    /// Create `DriveConfig` from the arguments of `blockdev-add`, the options
+    /// not set are the same as `-drive`.
+    ///
+    /// # Errors
+    ///
+    /// The driver of file is not `file`, or the options are not compatible.
+    #[cfg(feature = "qmp")]
+    pub fn from_blockdev_add(args: blockdev_add) -> Result<Self> {
+        if args.file.driver != "file" {
+            bail!("Driver '{}' of file is not supported", args.file.driver);
+        }
+
+        let mut drive = DriveConfig {
+            drive_id: args.node_name,
+            path_on_host: args.file.filename,
+            read_only: args.read_only.unwrap_or(false),
+            ..Default::default()
+        };
+        if let Some(cache) = args.cache {
+            drive.direct = cache.direct.unwrap_or(drive.direct);
+            drive.writeback = cache.writeback.unwrap_or(drive.writeback);
+            drive.no_flush = cache.no_flush.unwrap_or(drive.no_flush);
+        }
+        drive.aio = args.aio.map(|aio| match aio {
+            BlockdevAioOptions::threads => AioEngine::Threads,
+            BlockdevAioOptions::native => AioEngine::Native,
+            BlockdevAioOptions::io_uring => AioEngine::IoUring,
+        });
+        drive.discard = args.discard == Some(BlockdevDiscardOptions::unmap);
+        if let Some(detect_zeroes) = args.detect_zeroes {
+            drive.detect_zeroes = match detect_zeroes {
+                BlockdevDetectZeroesOptions::off => DetectZeroes::Off,
+                BlockdevDetectZeroesOptions::on => DetectZeroes::On,
+                BlockdevDetectZeroesOptions::unmap => DetectZeroes::Unmap,
+            };
+        }
+
+        drive.check()?;
+        Ok(drive)
+    }
+
+    /// Set `direct`, `writeback` and `no_flush` by cache mode, the same as
+    /// the `cache` options of `blockdev-add` with them.
+    pub fn set_cache(&mut self, mode: CacheMode) {
+        let (direct, writeback, no_flush) = match mode {
+            CacheMode::None => (true, true, false),
+            CacheMode::Writeback => (false, true, false),
+            CacheMode::Unsafe => (false, true, true),
+        };
+        self.direct = direct;
+        self.writeback = writeback;
+        self.no_flush = no_flush;
+    }
+
+    /// Get the engine to submit IO, which is chosen by `direct` if `aio` is
+    /// not set.
+    pub fn aio_engine(&self) -> AioEngine {
+        match self.aio {
+            Some(aio) => aio,
+            None if self.direct => AioEngine::IoUring,
+            None => AioEngine::Threads,
+        }
+    }
 }
 
 impl Default for DriveConfig {
@@ -53,6 +219,13 @@ impl Default for DriveConfig {
             read_only: false,
             direct: true,
             serial_num: None,
+            iothread: None,
+            writeback: true,
+            no_flush: false,
+            aio: None,
+            discard: false,
+            detect_zeroes: DetectZeroes::Off,
+            format: DiskFormat::Raw,
         }
     }
 }
@@ -83,6 +256,27 @@ impl ConfigCheck for DriveConfig {
             .into());
         }
 
+        if self.aio_engine() == AioEngine::Native {
+            return Err(ErrorKind::DriveOptionError(
+                "aio=native is not supported, use aio=io_uring instead".to_string(),
+            )
+            .into());
+        }
+
+        if self.detect_zeroes == DetectZeroes::Unmap && !self.discard {
+            return Err(ErrorKind::DriveOptionError(
+                "detect-zeroes=unmap requires discard=unmap".to_string(),
+            )
+            .into());
+        }
+
+        if self.format != DiskFormat::Raw {
+            return Err(ErrorKind::DriveOptionError(
+                "format=qcow2 is not supported, only raw image can be used".to_string(),
+            )
+            .into());
+        }
+
         Ok(())
     }
 }
@@ -101,23 +295,326 @@ impl VmConfig {
     }
 
     /// Update '-drive ...' drive config to `VmConfig`.
+    ///
+    /// # Notes
+    ///
+    /// Panic with the drive string if any option can't be parsed, the
+    /// options are checked with the other drives by `check_vmconfig`.
     pub fn update_drive(&mut self, drive_config: String) {
-        let cmd_params: CmdParams = CmdParams::from_str(drive_config);
-        let mut drive = DriveConfig::default();
-        if let Some(drive_path) = cmd_params.get("file") {
-            drive.path_on_host = drive_path.value;
+        let drive = parse_drive(&drive_config)
+            .unwrap_or_else(|e| panic!("Invalid drive '{}': {}", drive_config, e));
+        self.add_drive(drive);
+    }
+
+    /// Check every drive, ids of them are checked with the other devices
+    /// by `check_device_ids`.
+    pub(crate) fn check_drives(&self) -> Result<()> {
+        for drive in self.drives.iter().flatten() {
+            drive.check()?;
         }
-        if let Some(drive_id) = cmd_params.get("id") {
-            drive.drive_id = drive_id.value;
+        Ok(())
+    }
+}
+
+/// Parse the options of `-drive`, such as
+/// `id=rootfs,file=/path/to/rootfs,cache=none,aio=io_uring`.
+///
+/// # Errors
+///
+/// The value of any option is unknown, or `cache` is set with `direct`,
+/// whose meaning is included in `cache`.
+fn parse_drive(drive_config: &str) -> Result<DriveConfig> {
+    let cmd_params: CmdParams = CmdParams::from_str(drive_config.to_string());
+    let mut drive = DriveConfig::default();
+    if let Some(drive_path) = cmd_params.get("file") {
+        drive.path_on_host = drive_path.value;
+    }
+    if let Some(drive_id) = cmd_params.get("id") {
+        drive.drive_id = drive_id.value;
+    }
+    if let Some(read_only) = cmd_params.get("readonly") {
+        drive.read_only = parse_option(&read_only)?;
+    }
+    if let Some(direct) = cmd_params.get("direct") {
+        drive.direct = parse_option(&direct)?;
+    }
+    if let Some(cache) = cmd_params.get("cache") {
+        if cmd_params.get("direct").is_some() {
+            return Err(ErrorKind::DriveOptionError(
+                "cache and direct can't be set together".to_string(),
+            )
+            .into());
         }
-        if let Some(read_only) = cmd_params.get("readonly") {
-            drive.read_only = read_only.to_bool();
+        drive.set_cache(CacheMode::from_str(&cache.value)?);
+    }
+    if let Some(aio) = cmd_params.get("aio") {
+        drive.aio = Some(match aio.value.as_str() {
+            "threads" => AioEngine::Threads,
+            "native" => AioEngine::Native,
+            "io_uring" => AioEngine::IoUring,
+            _ => {
+                return Err(ErrorKind::DriveOptionError(format!(
+                    "aio={} is unknown, it should be `threads` or `io_uring`",
+                    aio.value
+                ))
+                .into())
+            }
+        });
+    }
+    if let Some(format) = cmd_params.get("format") {
+        drive.format = match format.value.as_str() {
+            "raw" => DiskFormat::Raw,
+            "qcow2" => DiskFormat::Qcow2,
+            _ => {
+                return Err(ErrorKind::DriveOptionError(format!(
+                    "format={} is unknown, it should be `raw` or `qcow2`",
+                    format.value
+                ))
+                .into())
+            }
+        };
+    }
+    drive.serial_num = cmd_params.get_value_str("serial");
+    drive.iothread = cmd_params.get_value_str("iothread");
+
+    Ok(drive)
+}
+
+/// Parse the boolean option of `-drive`, the error tells which one it is.
+fn parse_option(param: &Param) -> Result<bool> {
+    param.parse_bool().map_err(|_| {
+        ErrorKind::DriveOptionError(format!(
+            "{}={} is not a boolean, it should be `on` or `off`",
+            param.param_type, param.value
+        ))
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "qmp")]
+    #[test]
+    fn test_drive_from_blockdev_add() {
+        let blockdev = |args: serde_json::Value| -> Result<DriveConfig> {
+            DriveConfig::from_blockdev_add(serde_json::from_value(args).unwrap())
+        };
+
+        // Options not set are the same as `-drive`.
+        let drive = blockdev(serde_json::json!({
+            "node-name": "drive-0",
+            "file": { "driver": "file", "filename": "/path/to/block" }
+        }))
+        .unwrap();
+        assert_eq!(drive.drive_id, "drive-0");
+        assert_eq!(drive.path_on_host, "/path/to/block");
+        assert!(!drive.read_only && drive.direct && drive.writeback && !drive.no_flush);
+        assert_eq!(drive.aio, None);
+        assert_eq!(drive.aio_engine(), AioEngine::IoUring);
+        assert!(!drive.discard);
+        assert_eq!(drive.detect_zeroes, DetectZeroes::Off);
+
+        let drive = blockdev(serde_json::json!({
+            "node-name": "drive-0",
+            "file": { "driver": "file", "filename": "/path/to/block" },
+            "cache": { "direct": false, "writeback": false, "no-flush": true },
+            "read-only": true,
+            "aio": "threads",
+            "discard": "unmap",
+            "detect-zeroes": "unmap"
+        }))
+        .unwrap();
+        assert!(drive.read_only && !drive.direct && !drive.writeback && drive.no_flush);
+        assert_eq!(drive.aio_engine(), AioEngine::Threads);
+        assert!(drive.discard);
+        assert_eq!(drive.detect_zeroes, DetectZeroes::Unmap);
+
+        // Direct IO doesn't imply the engine if it's set.
+        let drive = blockdev(serde_json::json!({
+            "node-name": "drive-0",
+            "file": { "driver": "file", "filename": "/path/to/block" },
+            "cache": { "direct": false },
+            "aio": "io_uring",
+            "detect-zeroes": "on"
+        }))
+        .unwrap();
+        assert_eq!(drive.aio_engine(), AioEngine::IoUring);
+        assert_eq!(drive.detect_zeroes, DetectZeroes::On);
+
+        let errors = [
+            (
+                serde_json::json!({
+                    "node-name": "drive-0",
+                    "file": { "driver": "host_device", "filename": "/dev/sdb" }
+                }),
+                "Driver 'host_device' of file is not supported",
+            ),
+            (
+                serde_json::json!({
+                    "node-name": "drive-0",
+                    "file": { "driver": "file", "filename": "/path/to/block" },
+                    "aio": "native"
+                }),
+                "Drive option is illegal: aio=native is not supported, use aio=io_uring instead.",
+            ),
+            (
+                serde_json::json!({
+                    "node-name": "drive-0",
+                    "file": { "driver": "file", "filename": "/path/to/block" },
+                    "discard": "ignore",
+                    "detect-zeroes": "unmap"
+                }),
+                "Drive option is illegal: detect-zeroes=unmap requires discard=unmap.",
+            ),
+        ];
+        for (args, error) in errors.iter() {
+            assert_eq!(blockdev(args.clone()).unwrap_err().to_string(), *error);
         }
-        if let Some(direct) = cmd_params.get("direct") {
-            drive.direct = direct.to_bool();
+    }
+
+    #[test]
+    fn test_update_drive() {
+        let caches = [
+            ("", (true, true, false)),
+            (",cache=none", (true, true, false)),
+            (",cache=writeback", (false, true, false)),
+            (",cache=unsafe", (false, true, true)),
+            (",direct=off", (false, true, false)),
+        ];
+        let aios = [
+            ("", None),
+            (",aio=threads", Some(AioEngine::Threads)),
+            (",aio=io_uring", Some(AioEngine::IoUring)),
+        ];
+        let formats = [
+            ("", DiskFormat::Raw),
+            (",format=raw", DiskFormat::Raw),
+            (",format=qcow2", DiskFormat::Qcow2),
+        ];
+        for (cache, (direct, writeback, no_flush)) in caches.iter() {
+            for (aio, engine) in aios.iter() {
+                for (format, disk_format) in formats.iter() {
+                    let cmdline =
+                        format!("id=rootfs,file=/path/to/rootfs{}{}{}", cache, aio, format);
+                    let mut vm_config = VmConfig::default();
+                    vm_config.update_drive(cmdline.clone());
+                    let drive = vm_config.drives.unwrap().remove(0);
+                    assert_eq!(
+                        (drive.direct, drive.writeback, drive.no_flush),
+                        (*direct, *writeback, *no_flush),
+                        "{}",
+                        cmdline
+                    );
+                    assert_eq!(drive.aio, *engine, "{}", cmdline);
+                    assert_eq!(drive.format, *disk_format, "{}", cmdline);
+                    assert_eq!(
+                        drive.check().is_ok(),
+                        *disk_format == DiskFormat::Raw,
+                        "{}",
+                        cmdline
+                    );
+
+                    // Json of the drive is parsed back to the same options.
+                    let value = serde_json::to_value([&drive]).unwrap();
+                    let parsed = DriveConfig::from_value(&value).unwrap().remove(0);
+                    assert_eq!(serde_json::to_value([&parsed]).unwrap(), value);
+                }
+            }
         }
-        drive.serial_num = cmd_params.get_value_str("serial");
 
-        self.add_drive(drive);
+        let errors = [
+            (
+                "id=rootfs,file=/img,cache=directsync",
+                "Drive option is illegal: cache=directsync is unknown, it should be `none`, `writeback` or `unsafe`.",
+            ),
+            (
+                "id=rootfs,file=/img,cache=none,direct=off",
+                "Drive option is illegal: cache and direct can't be set together.",
+            ),
+            (
+                "id=rootfs,file=/img,aio=posix",
+                "Drive option is illegal: aio=posix is unknown, it should be `threads` or `io_uring`.",
+            ),
+            (
+                "id=rootfs,file=/img,format=vmdk",
+                "Drive option is illegal: format=vmdk is unknown, it should be `raw` or `qcow2`.",
+            ),
+            (
+                "id=rootfs,file=/img,readonly=1",
+                "Drive option is illegal: readonly=1 is not a boolean, it should be `on` or `off`.",
+            ),
+        ];
+        for (cmdline, error) in errors.iter() {
+            assert_eq!(parse_drive(cmdline).unwrap_err().to_string(), *error);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid drive 'id=rootfs,file=/img,cache=directsync'")]
+    fn test_update_drive_invalid() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_drive("id=rootfs,file=/img,cache=directsync".to_string());
+    }
+
+    #[test]
+    fn test_check_drives() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_drive("id=rootfs,file=/path/to/rootfs".to_string());
+        vm_config.update_drive("id=data,file=/path/to/data,aio=native".to_string());
+        assert_eq!(
+            vm_config.check_drives().unwrap_err().to_string(),
+            "Drive option is illegal: aio=native is not supported, use aio=io_uring instead."
+        );
+
+        let mut vm_config = VmConfig::default();
+        vm_config.update_drive("id=rootfs,file=/path/to/rootfs".to_string());
+        vm_config.update_drive("id=data,file=/path/to/data".to_string());
+        assert!(vm_config.check_drives().is_ok());
+        vm_config.update_drive("id=rootfs,file=/path/to/other".to_string());
+        assert_eq!(
+            vm_config.check_device_ids().unwrap_err().to_string(),
+            "Duplicate ID 'rootfs' for drive"
+        );
+    }
+
+    #[test]
+    fn test_drive_config_json() {
+        // The options added later are optional in json.
+        let value = serde_json::json!([{
+            "drive_id": "rootfs",
+            "path_on_host": "/path/to/rootfs",
+            "direct": false,
+            "read_only": false,
+            "serial_num": null,
+            "iothread": null
+        }]);
+        let drives = DriveConfig::from_value(&value).unwrap();
+        assert!(drives[0].writeback && !drives[0].no_flush && !drives[0].discard);
+        assert_eq!(drives[0].aio_engine(), AioEngine::Threads);
+
+        let value = serde_json::json!([{
+            "drive_id": "rootfs",
+            "path_on_host": "/path/to/rootfs",
+            "direct": true,
+            "read_only": false,
+            "serial_num": null,
+            "iothread": null,
+            "writeback": false,
+            "no_flush": true,
+            "aio": "threads",
+            "discard": true,
+            "detect_zeroes": "unmap"
+        }]);
+        let drive = DriveConfig::from_value(&value).unwrap().remove(0);
+        assert!(!drive.writeback && drive.no_flush && drive.discard);
+        assert_eq!(drive.aio_engine(), AioEngine::Threads);
+        assert_eq!(drive.detect_zeroes, DetectZeroes::Unmap);
+        let value = serde_json::to_value(&[drive]).unwrap();
+        assert_eq!(
+            DriveConfig::from_value(&value).unwrap()[0].aio,
+            Some(AioEngine::Threads)
+        );
     }
 }
@@ -0,0 +1,149 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+extern crate serde;
+extern crate serde_json;
+
+use serde::{Deserialize, Serialize};
+
+use super::errors::{ErrorKind, Result};
+use crate::config::{CmdParams, ConfigCheck, ParamOperation, VmConfig};
+
+const MAX_STRING_LENGTH: usize = 255;
+
+/// Config structure for iothread, devices referring to its `id` handle their
+/// events in the iothread instead of the main loop.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IothreadConfig {
+    pub id: String,
+}
+
+impl IothreadConfig {
+    /// Create `IothreadConfig` from `Value` structure.
+    ///
+    /// # Arguments
+    ///
+    /// * `Value` - structure can be gotten by `json_file`.
+    pub fn from_value(value: &serde_json::Value) -> Option<Vec<Self>> {
+        serde_json::from_value(value.clone()).ok()
+    }
+}
+
+impl ConfigCheck for IothreadConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.is_empty() {
+            bail!("Iothread id must be set.");
+        }
+
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "iothread id".to_string(),
+                MAX_STRING_LENGTH,
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    /// Add new iothread to `VmConfig`.
+    fn add_iothread(&mut self, iothread: IothreadConfig) {
+        if let Some(mut iothreads) = self.iothreads.clone() {
+            iothreads.push(iothread);
+            self.iothreads = Some(iothreads);
+        } else {
+            let mut iothreads: Vec<IothreadConfig> = Vec::new();
+            iothreads.push(iothread);
+            self.iothreads = Some(iothreads);
+        }
+    }
+
+    /// Update '-iothread ...' iothread config to `VmConfig`.
+    pub fn update_iothread(&mut self, iothread_config: String) {
+        let cmd_params: CmdParams = CmdParams::from_str(iothread_config);
+        let mut iothread = IothreadConfig::default();
+        if let Some(id) = cmd_params.get("id") {
+            iothread.id = id.value;
+        }
+
+        self.add_iothread(iothread);
+    }
+
+    /// Check iothreads are unique, and every iothread referred by devices is
+    /// defined.
+    pub(crate) fn check_iothreads(&self) -> Result<()> {
+        let iothreads = self.iothreads.clone().unwrap_or_default();
+        for (index, iothread) in iothreads.iter().enumerate() {
+            iothread.check()?;
+            if iothreads[..index].iter().any(|i| i.id == iothread.id) {
+                bail!("Iothread {} is defined more than once.", iothread.id);
+            }
+        }
+
+        let drive_refs = self
+            .drives
+            .iter()
+            .flatten()
+            .map(|drive| drive.iothread.as_ref());
+        let net_refs = self.nets.iter().flatten().map(|net| net.iothread.as_ref());
+        for id in drive_refs.chain(net_refs).flatten() {
+            if !iothreads.iter().any(|i| &i.id == id) {
+                bail!("Iothread {} is not defined.", id);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iothread_config() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_iothread("id=iothread0".to_string());
+        vm_config.update_iothread("id=iothread1".to_string());
+        vm_config.update_drive("file=/path/to/rootfs,id=rootfs,iothread=iothread0".to_string());
+        vm_config.update_net("id=eth0,netdev=tap0,iothread=iothread1".to_string());
+        let iothreads = vm_config.iothreads.as_ref().unwrap();
+        assert_eq!(iothreads.len(), 2);
+        assert_eq!(iothreads[1].id, "iothread1");
+        assert_eq!(
+            vm_config.drives.as_ref().unwrap()[0].iothread,
+            Some("iothread0".to_string())
+        );
+        assert!(vm_config.check_iothreads().is_ok());
+
+        // Referred iothread must be defined.
+        vm_config.update_drive("file=/path/to/data,id=data,iothread=iothread2".to_string());
+        assert!(vm_config.check_iothreads().is_err());
+
+        // Iothread id must be unique.
+        let mut vm_config = VmConfig::default();
+        vm_config.update_iothread("id=iothread0".to_string());
+        vm_config.update_iothread("id=iothread0".to_string());
+        assert!(vm_config.check_iothreads().is_err());
+
+        let mut vm_config = VmConfig::default();
+        vm_config.update_iothread("iothread0".to_string());
+        assert!(vm_config.check_iothreads().is_err());
+
+        let json = serde_json::json!([{ "id": "iothread0" }]);
+        let iothreads = IothreadConfig::from_value(&json).unwrap();
+        assert_eq!(iothreads[0].id, "iothread0");
+    }
+}
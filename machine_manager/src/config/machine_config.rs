@@ -15,63 +15,266 @@ extern crate serde_json;
 
 use serde::{Deserialize, Serialize};
 
-use super::errors::{ErrorKind, Result};
-use crate::config::{CmdParams, ConfigCheck, ParamOperation, VmConfig};
+use super::errors::{ErrorKind, Result, ResultExt};
+use crate::config::{CmdParams, ConfigCheck, Param, ParamOperation, VmConfig};
 
 const DEFAULT_CPUS: u8 = 1;
 const DEFAULT_MEMSIZE: u64 = 128;
-const MAX_NR_CPUS: u8 = 254;
+/// Max number of vcpus supported by the machine.
+pub const MAX_NR_CPUS: u8 = 254;
 const MIN_NR_CPUS: u8 = 1;
 const MAX_MEMSIZE: u64 = 549_755_813_888;
 const MIN_MEMSIZE: u64 = 134_217_728;
 const MAX_STRING_LENGTH: usize = 255;
+/// Guest memory is aligned to huge page, so that it can be backed by THP.
+const MEMSIZE_ALIGN: u64 = 2 * M;
 const M: u64 = 1024 * 1024;
 const G: u64 = 1024 * 1024 * 1024;
+/// Machine types supported by `-machine`.
+const MACHINE_TYPES: [&str; 1] = ["microvm"];
+
+/// Type of the value of a `-machine` property.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachinePropType {
+    /// `yes`,`on`,`true`,`no`,`off` or `false`.
+    Bool,
+    /// Bytes with optional suffix `K`, `M`, `G`, `T`, `P` or `E`.
+    Size,
+    /// One of the given words.
+    Enum(&'static [&'static str]),
+}
+
+impl MachinePropType {
+    /// Get the name of type, such as `bool`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MachinePropType::Bool => "bool",
+            MachinePropType::Size => "size",
+            MachinePropType::Enum(_) => "enum",
+        }
+    }
+}
+
+/// A property of `-machine`, such as `dump-guest-core=off`.
+#[derive(Debug, Clone, Copy)]
+pub struct MachineProp {
+    /// Name of property.
+    pub name: &'static str,
+    /// Type of its value.
+    pub prop_type: MachinePropType,
+    /// Default value, `None` if it's decided by the machine.
+    pub default: Option<&'static str>,
+}
+
+/// All the properties of `-machine`, other keys are rejected.
+pub const MACHINE_PROPS: [MachineProp; 6] = [
+    MachineProp {
+        name: "dump-guest-core",
+        prop_type: MachinePropType::Bool,
+        default: Some("on"),
+    },
+    MachineProp {
+        name: "mem-merge",
+        prop_type: MachinePropType::Bool,
+        default: Some("off"),
+    },
+    MachineProp {
+        name: "thp",
+        prop_type: MachinePropType::Enum(&["on", "off", "auto"]),
+        default: Some("auto"),
+    },
+    MachineProp {
+        name: "max-slot-size",
+        prop_type: MachinePropType::Size,
+        default: None,
+    },
+    MachineProp {
+        name: "earlycon",
+        prop_type: MachinePropType::Enum(&["auto", "off"]),
+        default: Some("off"),
+    },
+    MachineProp {
+        name: "boot-marker",
+        prop_type: MachinePropType::Bool,
+        default: Some("off"),
+    },
+];
 
 /// Config struct for machine-config.
 /// Contains some basic Vm config about cpu, memory, name.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MachineConfig {
+    /// Name of VM, empty means it's not set.
     pub name: String,
     pub nr_cpus: u8,
+    /// Max number of vcpus including the ones not online at boot, `None`
+    /// means the same as `nr_cpus`.
+    #[serde(default)]
+    pub max_cpus: Option<u8>,
     pub mem_size: u64,
     pub omit_vm_memory: bool,
+    #[serde(default)]
+    pub mem_merge: bool,
+    /// Transparent huge page policy of guest memory, `None` means `auto`.
+    #[serde(default)]
+    pub thp: Option<bool>,
+    /// Max size of one guest ram mapping (and kvm memory slot), `None` means default.
+    #[serde(default)]
+    pub max_slot_size: Option<u64>,
+    /// File backing guest memory, which is mapped shared. Guest memory is
+    /// anonymous if it's `None`.
+    #[serde(default)]
+    pub mem_path: Option<String>,
+    /// Append `earlycon` to kernel cmdline, so that guest finds early console
+    /// by `stdout-path` of device tree.
+    #[serde(default)]
+    pub earlycon: bool,
+    /// UUID of VM, a random one is generated if it's not set.
+    #[serde(default)]
+    pub uuid: Option<String>,
+    /// Expose UUID to guest by kernel cmdline.
+    #[serde(default)]
+    pub uuid_on_cmdline: bool,
+    /// Add the IO port which guest writes to mark that it has booted, the
+    /// time is reported by `query-boot-times`.
+    #[serde(default)]
+    pub boot_marker: bool,
 }
 
 impl Default for MachineConfig {
     /// Set default config for `machine-config`.
     fn default() -> Self {
         MachineConfig {
-            name: "StratoVirt".to_string(),
+            name: String::new(),
             nr_cpus: DEFAULT_CPUS,
+            max_cpus: None,
             mem_size: DEFAULT_MEMSIZE * M,
             omit_vm_memory: false,
+            mem_merge: false,
+            thp: None,
+            max_slot_size: None,
+            mem_path: None,
+            earlycon: false,
+            uuid: None,
+            uuid_on_cmdline: false,
+            boot_marker: false,
         }
     }
 }
 
 impl MachineConfig {
-    /// Create `MachineConfig` from `Value` structure.
+    /// Get the name of VM, `None` if it's not set.
+    pub fn vm_name(&self) -> Option<&str> {
+        if self.name.is_empty() {
+            None
+        } else {
+            Some(&self.name)
+        }
+    }
+
+    /// Get the max number of vcpus, which is `nr_cpus` if it's not set.
+    pub fn vm_max_cpus(&self) -> u8 {
+        self.max_cpus.unwrap_or(self.nr_cpus)
+    }
+
+    /// Set the `-machine` property `name` to `value`.
     ///
     /// # Arguments
     ///
-    /// * `Value` - structure can be gotten by `json_file`.
-    pub fn from_value(value: &serde_json::Value) -> Self {
-        let mut machine_config = MachineConfig::default();
-        if value.get("name") != None {
-            machine_config.name = value["name"].to_string();
-        }
-        if value.get("vcpu_count") != None {
-            machine_config.nr_cpus = value["vcpu_count"].to_string().parse::<u8>().unwrap();
+    /// * `name` - Name of property in `MACHINE_PROPS`.
+    /// * `value` - Value of property in the syntax of cmdline.
+    ///
+    /// # Errors
+    ///
+    /// The property is unknown, or the value isn't of its type. Nothing is
+    /// changed then.
+    pub fn set_machine_prop(&mut self, name: &str, value: &str) -> Result<()> {
+        let prop = match MACHINE_PROPS.iter().find(|prop| prop.name == name) {
+            Some(prop) => prop,
+            None => {
+                let names: Vec<&str> = MACHINE_PROPS.iter().map(|prop| prop.name).collect();
+                bail!(
+                    "Unknown machine property '{}', valid ones are: {}",
+                    name,
+                    names.join(", ")
+                );
+            }
+        };
+        let param = Param {
+            param_type: name.to_string(),
+            value: value.to_string(),
+        };
+        match prop.prop_type {
+            MachinePropType::Bool => {
+                let on = param
+                    .parse_bool()
+                    .chain_err(|| format!("Invalid value '{}' of {}", value, name))?;
+                match name {
+                    "dump-guest-core" => self.omit_vm_memory = !on,
+                    "mem-merge" => self.mem_merge = on,
+                    "boot-marker" => self.boot_marker = on,
+                    _ => unreachable!(),
+                }
+            }
+            MachinePropType::Size => {
+                let size = str_to_size(value, 1).map_err(|reason| {
+                    format!("Invalid size '{}' of {}: {}.", value, name, reason)
+                })?;
+                self.max_slot_size = Some(size);
+            }
+            MachinePropType::Enum(words) => {
+                if !words.contains(&value) {
+                    let words: Vec<String> =
+                        words.iter().map(|word| format!("`{}`", word)).collect();
+                    bail!("Can only give {} for {}.", words.join(","), name);
+                }
+                match name {
+                    "thp" => {
+                        self.thp = match value {
+                            "on" => Some(true),
+                            "off" => Some(false),
+                            _ => None,
+                        }
+                    }
+                    "earlycon" => self.earlycon = value == "auto",
+                    _ => unreachable!(),
+                }
+            }
         }
-        if value.get("mem_size") != None {
-            machine_config.mem_size = value["mem_size"].to_string().parse::<u64>().unwrap();
+        Ok(())
+    }
+
+    /// Get the value of `-machine` property `name` in the syntax of
+    /// cmdline, `None` if it's unknown or decided by the machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of property in `MACHINE_PROPS`.
+    pub fn machine_prop(&self, name: &str) -> Option<String> {
+        let on_off = |on: bool| if on { "on" } else { "off" }.to_string();
+        match name {
+            "dump-guest-core" => Some(on_off(!self.omit_vm_memory)),
+            "mem-merge" => Some(on_off(self.mem_merge)),
+            "thp" => Some(self.thp.map_or("auto".to_string(), on_off)),
+            "max-slot-size" => self.max_slot_size.map(|size| size.to_string()),
+            "earlycon" => Some(if self.earlycon { "auto" } else { "off" }.to_string()),
+            "boot-marker" => Some(on_off(self.boot_marker)),
+            _ => None,
         }
-        if value.get("omit_vm_memory") != None {
-            machine_config.omit_vm_memory =
-                value["omit_vm_memory"].to_string().parse::<bool>().unwrap();
+    }
+
+    /// Get the UUID of VM. If it's not set, a random one is generated and
+    /// kept, so that the same UUID is returned in one run.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to generate UUID.
+    pub fn vm_uuid(&mut self) -> Result<String> {
+        if self.uuid.is_none() {
+            let uuid = util::uuid::uuid_v4().chain_err(|| "Failed to generate uuid")?;
+            self.uuid = Some(uuid);
         }
-        machine_config
+        Ok(self.uuid.clone().unwrap())
     }
 }
 
@@ -83,12 +286,48 @@ impl ConfigCheck for MachineConfig {
             );
         }
 
+        if self.name.chars().any(char::is_control) {
+            bail!("Name of VM can't contain control characters.");
+        }
+
+        if let Some(uuid) = &self.uuid {
+            if !util::uuid::is_valid_uuid(uuid) {
+                return Err(ErrorKind::UuidFormatError(uuid.to_string()).into());
+            }
+        }
+
         if self.nr_cpus < MIN_NR_CPUS || self.nr_cpus > MAX_NR_CPUS {
-            return Err(ErrorKind::NrcpusError.into());
+            return Err(ErrorKind::NrcpusError(u64::from(self.nr_cpus), MAX_NR_CPUS).into());
+        }
+
+        if let Some(max_cpus) = self.max_cpus {
+            if max_cpus < self.nr_cpus || max_cpus > MAX_NR_CPUS {
+                bail!(
+                    "Max cpus {} is out of range, it should be no less than number of vcpus {} and no more than {}.",
+                    max_cpus,
+                    self.nr_cpus,
+                    MAX_NR_CPUS
+                );
+            }
+        }
+
+        check_mem_size(self.mem_size)?;
+
+        if self.max_slot_size == Some(0) {
+            bail!("Max slot size of guest memory should be more than 0.");
+        }
+
+        if let Some(mem_path) = &self.mem_path {
+            if mem_path.is_empty() {
+                bail!("Path of memory backend file can't be empty.");
+            }
         }
 
-        if self.mem_size < MIN_MEMSIZE || self.mem_size > MAX_MEMSIZE {
-            return Err(ErrorKind::MemsizeError.into());
+        #[cfg(target_arch = "aarch64")]
+        {
+            if self.boot_marker {
+                bail!("Boot marker is only supported on x86_64.");
+            }
         }
 
         Ok(())
@@ -97,47 +336,466 @@ impl ConfigCheck for MachineConfig {
 
 impl VmConfig {
     /// Update '-m' memory config to `VmConfig`.
+    ///
+    /// # Notes
+    ///
+    /// The size is parsed by `parse_mem_size`, and it panics with the error
+    /// as the other invalid values of cmdline do.
     pub fn update_memory(&mut self, mem_config: String) {
         let cmd_params: CmdParams = CmdParams::from_str(mem_config);
-        if let Some(mut mem_size) = cmd_params.get("") {
-            if mem_size.value_replace_blank("M") || mem_size.value_replace_blank("m") {
-                self.machine_config.mem_size = get_inner(mem_size.value_to_u64().checked_mul(M));
-            } else if mem_size.value_replace_blank("G") || mem_size.value_replace_blank("g") {
-                self.machine_config.mem_size = get_inner(mem_size.value_to_u64().checked_mul(G));
-            } else {
-                self.machine_config.mem_size = mem_size.value_to_u64();
-            }
-        } else if let Some(mut mem_size) = cmd_params.get("size") {
-            if mem_size.value_replace_blank("M") || mem_size.value_replace_blank("m") {
-                self.machine_config.mem_size = get_inner(mem_size.value_to_u64().checked_mul(M));
-            } else if mem_size.value_replace_blank("G") || mem_size.value_replace_blank("g") {
-                self.machine_config.mem_size = get_inner(mem_size.value_to_u64().checked_mul(G));
-            } else {
-                self.machine_config.mem_size = mem_size.value_to_u64();
-            }
+        if let Some(mem_size) = cmd_params.get("").or_else(|| cmd_params.get("size")) {
+            self.machine_config.mem_size =
+                parse_mem_size(&mem_size.value).unwrap_or_else(|e| panic!("{}", e));
         }
     }
 
-    /// Update '-smp' cpu config to `VmConfig`.
+    /// Update '-smp' cpu config to `VmConfig`, such as `2,maxcpus=8`.
+    ///
+    /// # Notes
+    ///
+    /// The number more than `MAX_NR_CPUS` panics with both of them, the
+    /// limit of KVM is checked when vcpus are created. `maxcpus` less than
+    /// the number of vcpus is reported by `check`.
     pub fn update_cpu(&mut self, cpu_config: String) {
         let cmd_params: CmdParams = CmdParams::from_str(cpu_config);
-        if let Some(cpu_num) = cmd_params.get("") {
-            self.machine_config.nr_cpus = cpu_num.value_to_u8();
-        } else if let Some(cpu_num) = cmd_params.get("cpus") {
-            self.machine_config.nr_cpus = cpu_num.value_to_u8();
+        if let Some(cpu_num) = cmd_params.get("").or_else(|| cmd_params.get("cpus")) {
+            let nr_cpus = cpu_num.value_to_u64();
+            if nr_cpus > u64::from(MAX_NR_CPUS) {
+                panic!("{}", ErrorKind::NrcpusError(nr_cpus, MAX_NR_CPUS));
+            }
+            self.machine_config.nr_cpus = nr_cpus as u8;
+        }
+        if let Some(max_cpus) = cmd_params.get_value_u64("maxcpus") {
+            if max_cpus > u64::from(MAX_NR_CPUS) {
+                panic!(
+                    "Max cpus {} is out of range, it should be no more than {}.",
+                    max_cpus, MAX_NR_CPUS
+                );
+            }
+            self.machine_config.max_cpus = Some(max_cpus as u8);
         }
     }
 
+    /// Update '-uuid' config to `VmConfig`.
+    pub fn update_uuid(&mut self, uuid_config: String) {
+        let cmd_params: CmdParams = CmdParams::from_str(uuid_config);
+        if let Some(uuid) = cmd_params.get_value_str("") {
+            self.machine_config.uuid = Some(uuid);
+        }
+        if let Some(expose) = cmd_params.get_value_str("expose") {
+            self.machine_config.uuid_on_cmdline = parse_uuid_expose(&expose);
+        }
+    }
+
+    /// Update '-mem-path' config to `VmConfig`.
+    pub fn update_mem_path(&mut self, mem_path: String) {
+        self.machine_config.mem_path = Some(mem_path);
+    }
+
     /// Update '-omit_vm_memory' config to 'VmConfig'.
+    ///
+    /// # Notes
+    ///
+    /// It's deprecated, and the same as `-machine dump-guest-core=off`.
     pub fn update_omit_vm_memory(&mut self) {
+        warn!("-omit_vm_memory is deprecated, use -machine dump-guest-core=off instead");
         self.machine_config.omit_vm_memory = true;
     }
+
+    /// Update '-machine' config to 'VmConfig', such as
+    /// `microvm,dump-guest-core=off,thp=on`.
+    ///
+    /// # Notes
+    ///
+    /// Panic with the machine string if the machine type is unknown, or any
+    /// property can't be parsed.
+    pub fn update_machine(&mut self, machine_config: String) {
+        if let Err(e) = parse_machine(&machine_config, &mut self.machine_config) {
+            panic!("Invalid machine '{}': {}", machine_config, e);
+        }
+    }
+}
+
+/// Parse the options of `-machine` to `machine_config`, the type of
+/// machine is optional, the others are properties in `MACHINE_PROPS`.
+///
+/// # Errors
+///
+/// The machine type is unknown, or a property is unknown or of wrong type.
+fn parse_machine(machine: &str, machine_config: &mut MachineConfig) -> Result<()> {
+    let cmd_params: CmdParams = CmdParams::from_str(machine.to_string());
+    for (index, param) in cmd_params.params.iter().enumerate() {
+        let machine_type = match param.param_type.as_str() {
+            "" if param.value.is_empty() => continue,
+            "" if index == 0 => &param.value,
+            "type" => &param.value,
+            "" => bail!("Unknown machine property '{}'", param.value),
+            name => {
+                machine_config.set_machine_prop(name, &param.value)?;
+                continue;
+            }
+        };
+        if !MACHINE_TYPES.contains(&machine_type.as_str()) {
+            bail!(
+                "Unknown machine type '{}', supported ones are: {}",
+                machine_type,
+                MACHINE_TYPES.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parse the size of memory given by `-m`, such as `512M` or `4G`, the
+/// unit is `M` if there is no suffix.
+///
+/// # Arguments
+///
+/// * `size` - The size with optional suffix `K`, `M`, `G`, `T`, `P` or `E`.
+///
+/// # Errors
+///
+/// The size is not a number, overflows, or is out of range or not aligned to
+/// 2M, the error cites `size`.
+pub fn parse_mem_size(size: &str) -> Result<u64> {
+    let bytes = match str_to_size(size, M) {
+        Ok(bytes) => bytes,
+        Err(reason) => bail!("Invalid memory size '{}': {}.", size, reason),
+    };
+    if let Err(e) = check_mem_size(bytes) {
+        bail!("Invalid memory size '{}': {}", size, e);
+    }
+    Ok(bytes)
+}
+
+fn check_mem_size(bytes: u64) -> Result<()> {
+    if bytes < MIN_MEMSIZE || bytes > MAX_MEMSIZE {
+        return Err(ErrorKind::MemsizeError.into());
+    }
+    if bytes % MEMSIZE_ALIGN != 0 {
+        bail!("Size of memory should be aligned to 2M.");
+    }
+    Ok(())
+}
+
+/// Converts a size with optional binary suffix to bytes, `default_unit` is
+/// used if there is no suffix. The reason is returned if it fails.
+pub(crate) fn str_to_size(size: &str, default_unit: u64) -> std::result::Result<u64, &'static str> {
+    let (number, unit) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') => (&size[..size.len() - 1], 1 << 30),
+        Some('T') => (&size[..size.len() - 1], 1 << 40),
+        Some('P') => (&size[..size.len() - 1], 1 << 50),
+        Some('E') => (&size[..size.len() - 1], 1 << 60),
+        _ => (size, default_unit),
+    };
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return Err("it should be a number with optional suffix K, M, G, T, P or E");
+    }
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or("it's too large")
 }
 
-fn get_inner<T>(outer: Option<T>) -> T {
-    if let Some(x) = outer {
-        x
-    } else {
-        panic!("Integer overflow occurred!");
+/// Converts `cmdline` to whether to expose UUID by kernel cmdline.
+fn parse_uuid_expose(expose: &str) -> bool {
+    match expose {
+        "cmdline" => true,
+        _ => panic!("Can only give `cmdline` for expose of uuid."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_machine() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_machine("microvm".to_string());
+        assert_eq!(vm_config.machine_config.omit_vm_memory, false);
+        assert_eq!(vm_config.machine_config.mem_merge, false);
+        assert_eq!(vm_config.machine_config.thp, None);
+
+        vm_config.update_machine("microvm,dump-guest-core=off,mem-merge=on,thp=on".to_string());
+        assert_eq!(vm_config.machine_config.omit_vm_memory, true);
+        assert_eq!(vm_config.machine_config.mem_merge, true);
+        assert_eq!(vm_config.machine_config.thp, Some(true));
+
+        vm_config.update_machine("dump-guest-core=on,thp=off".to_string());
+        assert_eq!(vm_config.machine_config.omit_vm_memory, false);
+        assert_eq!(vm_config.machine_config.thp, Some(false));
+
+        vm_config.update_machine("thp=auto".to_string());
+        assert_eq!(vm_config.machine_config.thp, None);
+
+        assert_eq!(vm_config.machine_config.max_slot_size, None);
+        vm_config.update_machine("max-slot-size=64G".to_string());
+        assert_eq!(vm_config.machine_config.max_slot_size, Some(64 * G));
+        vm_config.update_machine("max-slot-size=512M".to_string());
+        assert_eq!(vm_config.machine_config.max_slot_size, Some(512 * M));
+
+        assert_eq!(vm_config.machine_config.earlycon, false);
+        vm_config.update_machine("earlycon=auto".to_string());
+        assert_eq!(vm_config.machine_config.earlycon, true);
+        vm_config.update_machine("earlycon=off".to_string());
+        assert_eq!(vm_config.machine_config.earlycon, false);
+
+        vm_config.update_omit_vm_memory();
+        assert_eq!(vm_config.machine_config.omit_vm_memory, true);
+    }
+
+    #[test]
+    fn test_machine_props() {
+        // Defaults of all properties.
+        let machine_config = MachineConfig::default();
+        for prop in MACHINE_PROPS.iter() {
+            assert_eq!(
+                machine_config.machine_prop(prop.name).as_deref(),
+                prop.default,
+                "{}",
+                prop.name
+            );
+        }
+
+        // Every property is set and got in the syntax of cmdline.
+        let cases = [
+            ("dump-guest-core", "off", "off"),
+            ("dump-guest-core", "yes", "on"),
+            ("mem-merge", "true", "on"),
+            ("mem-merge", "no", "off"),
+            ("thp", "on", "on"),
+            ("thp", "off", "off"),
+            ("thp", "auto", "auto"),
+            ("max-slot-size", "1G", "1073741824"),
+            ("max-slot-size", "4096", "4096"),
+            ("earlycon", "auto", "auto"),
+            ("earlycon", "off", "off"),
+            ("boot-marker", "on", "on"),
+            ("boot-marker", "false", "off"),
+        ];
+        let mut machine_config = MachineConfig::default();
+        for (name, value, effective) in cases.iter() {
+            machine_config.set_machine_prop(name, value).unwrap();
+            assert_eq!(
+                machine_config.machine_prop(name).as_deref(),
+                Some(*effective),
+                "{}={}",
+                name,
+                value
+            );
+        }
+        assert_eq!(machine_config.machine_prop("type"), None);
+
+        let errors = [
+            ("dump-guest-core", "1", "Invalid value '1' of dump-guest-core"),
+            ("mem-merge", "", "Invalid value '' of mem-merge"),
+            ("thp", "always", "Can only give `on`,`off`,`auto` for thp."),
+            ("earlycon", "on", "Can only give `auto`,`off` for earlycon."),
+            (
+                "max-slot-size",
+                "1GB",
+                "Invalid size '1GB' of max-slot-size: it should be a number with optional suffix K, M, G, T, P or E.",
+            ),
+            (
+                "max-slot-size",
+                "64E",
+                "Invalid size '64E' of max-slot-size: it's too large.",
+            ),
+            (
+                "mem-share",
+                "on",
+                "Unknown machine property 'mem-share', valid ones are: dump-guest-core, mem-merge, thp, max-slot-size, earlycon, boot-marker",
+            ),
+        ];
+        for (name, value, error) in errors.iter() {
+            let mut machine_config = MachineConfig::default();
+            let err = machine_config.set_machine_prop(name, value).unwrap_err();
+            assert_eq!(err.to_string(), *error);
+            // Nothing is changed by invalid value.
+            assert_eq!(
+                machine_config.machine_prop(name),
+                MachineConfig::default().machine_prop(name)
+            );
+        }
+
+        // Machine type is optional, and only the first may omit `type=`.
+        let mut machine_config = MachineConfig::default();
+        for machine in ["microvm", "type=microvm,thp=on", "mem-merge=on,", ""].iter() {
+            assert!(parse_machine(machine, &mut machine_config).is_ok());
+        }
+        let errors = [
+            (
+                "q35",
+                "Unknown machine type 'q35', supported ones are: microvm",
+            ),
+            (
+                "microvm,type=virt",
+                "Unknown machine type 'virt', supported ones are: microvm",
+            ),
+            ("microvm,accel", "Unknown machine property 'accel'"),
+        ];
+        for (machine, error) in errors.iter() {
+            assert_eq!(
+                parse_machine(machine, &mut machine_config)
+                    .unwrap_err()
+                    .to_string(),
+                *error
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid machine 'microvm,dump-guest-core=maybe'")]
+    fn test_update_machine_invalid() {
+        VmConfig::default().update_machine("microvm,dump-guest-core=maybe".to_string());
+    }
+
+    #[test]
+    fn test_update_name() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.vm_name(), None);
+        assert!(vm_config.machine_config.check().is_ok());
+
+        vm_config.update_name("guest=foo".to_string());
+        assert_eq!(vm_config.machine_config.vm_name(), Some("foo"));
+        assert!(vm_config.machine_config.check().is_ok());
+
+        vm_config.update_name("bar".to_string());
+        assert_eq!(vm_config.machine_config.vm_name(), Some("bar"));
+
+        vm_config.update_name("guest=foo\tbar".to_string());
+        assert!(vm_config.machine_config.check().is_err());
+
+        vm_config.update_name(format!("guest={}", "a".repeat(MAX_STRING_LENGTH + 1)));
+        assert!(vm_config.machine_config.check().is_err());
+
+        let json = serde_json::json!({ "machine-config": { "name": "foo", "vcpu_count": 1 } });
+        let machine_config = VmConfig::create_from_value(json).unwrap().machine_config;
+        assert_eq!(machine_config.vm_name(), Some("foo"));
+    }
+
+    #[test]
+    fn test_update_uuid() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.uuid, None);
+        // Generated uuid is kept in one run.
+        let uuid = vm_config.machine_config.vm_uuid().unwrap();
+        assert!(util::uuid::is_valid_uuid(&uuid));
+        assert_eq!(vm_config.machine_config.vm_uuid().unwrap(), uuid);
+        assert!(vm_config.machine_config.check().is_ok());
+
+        let mut vm_config = VmConfig::default();
+        vm_config.update_uuid("6ba7b810-9dad-11d1-80b4-00c04fd430c8".to_string());
+        assert_eq!(
+            vm_config.machine_config.vm_uuid().unwrap(),
+            "6ba7b810-9dad-11d1-80b4-00c04fd430c8"
+        );
+        assert!(!vm_config.machine_config.uuid_on_cmdline);
+        assert!(vm_config.machine_config.check().is_ok());
+
+        vm_config.update_uuid("6ba7b810-9dad-11d1-80b4-00c04fd430c8,expose=cmdline".to_string());
+        assert!(vm_config.machine_config.uuid_on_cmdline);
+
+        // The error names the illegal uuid.
+        vm_config.update_uuid("6ba7b810-9dad-11d1".to_string());
+        let err = vm_config.machine_config.check().err().unwrap();
+        assert!(err.to_string().contains("6ba7b810-9dad-11d1"));
+    }
+
+    #[test]
+    fn test_parse_mem_size() {
+        // Plain number is in M.
+        assert_eq!(parse_mem_size("512").unwrap(), 512 * M);
+        assert_eq!(parse_mem_size("131072K").unwrap(), 128 * M);
+        assert_eq!(parse_mem_size("256m").unwrap(), 256 * M);
+        assert_eq!(parse_mem_size("256M").unwrap(), 256 * M);
+        assert_eq!(parse_mem_size("4G").unwrap(), 4 * G);
+
+        // Boundaries.
+        assert_eq!(parse_mem_size("128M").unwrap(), MIN_MEMSIZE);
+        assert_eq!(parse_mem_size("512G").unwrap(), MAX_MEMSIZE);
+        assert!(parse_mem_size("126M").is_err());
+        assert!(parse_mem_size("0").is_err());
+        assert!(parse_mem_size("524290M").is_err());
+        assert!(parse_mem_size("1T").is_err());
+        assert!(parse_mem_size("1P").is_err());
+        // Not aligned to 2M.
+        assert!(parse_mem_size("129M").is_err());
+        assert!(parse_mem_size("262145K").is_err());
+
+        // The error cites the original string.
+        for size in ["20E", "1.5G", "", "G", "-1", "512MB", "0x100"].iter() {
+            let err = parse_mem_size(size).unwrap_err().to_string();
+            assert!(err.contains(&format!("'{}'", size)), "{}", err);
+        }
+        assert!(parse_mem_size("20E")
+            .unwrap_err()
+            .to_string()
+            .contains("too large"));
+
+        let mut vm_config = VmConfig::default();
+        vm_config.update_memory("size=1G".to_string());
+        assert_eq!(vm_config.machine_config.mem_size, G);
+        vm_config.update_memory("768".to_string());
+        assert_eq!(vm_config.machine_config.mem_size, 768 * M);
+        assert!(vm_config.machine_config.check().is_ok());
+
+        // Size of memory in json is checked as well.
+        vm_config.machine_config.mem_size = 768 * M + 4096;
+        assert!(vm_config.machine_config.check().is_err());
+    }
+
+    #[test]
+    fn test_update_cpu() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_cpu("4".to_string());
+        assert_eq!(vm_config.machine_config.nr_cpus, 4);
+        vm_config.update_cpu(format!("cpus={}", MAX_NR_CPUS));
+        assert_eq!(vm_config.machine_config.nr_cpus, MAX_NR_CPUS);
+        assert!(vm_config.machine_config.check().is_ok());
+
+        vm_config.machine_config.nr_cpus = 0;
+        let err = vm_config.machine_config.check().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Number of vcpus 0 is out of range, it should be more than 0 and no more than 254."
+        );
+    }
+
+    #[test]
+    fn test_update_max_cpus() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.vm_max_cpus(), 1);
+        vm_config.update_cpu("2,maxcpus=8".to_string());
+        assert_eq!(vm_config.machine_config.nr_cpus, 2);
+        assert_eq!(vm_config.machine_config.max_cpus, Some(8));
+        assert_eq!(vm_config.machine_config.vm_max_cpus(), 8);
+        assert!(vm_config.machine_config.check().is_ok());
+        vm_config.update_cpu("cpus=8,maxcpus=8".to_string());
+        assert!(vm_config.machine_config.check().is_ok());
+
+        vm_config.update_cpu("cpus=9".to_string());
+        assert_eq!(
+            vm_config.machine_config.check().unwrap_err().to_string(),
+            "Max cpus 8 is out of range, it should be no less than number of vcpus 9 and no more than 254."
+        );
+        vm_config.machine_config.max_cpus = Some(255);
+        assert!(vm_config.machine_config.check().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "Max cpus 255 is out of range, it should be no more than 254.")]
+    fn test_update_max_cpus_too_many() {
+        VmConfig::default().update_cpu("1,maxcpus=255".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Number of vcpus 300 is out of range")]
+    fn test_update_cpu_too_many() {
+        VmConfig::default().update_cpu("300".to_string());
     }
 }
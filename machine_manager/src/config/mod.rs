@@ -13,11 +13,16 @@
 extern crate serde;
 extern crate serde_json;
 
+mod balloon;
 mod boot_source;
 mod chardev;
+mod config_file;
 mod fs;
+mod iothread;
 mod machine_config;
 mod network;
+mod pmem;
+mod rng;
 
 use std::any::Any;
 use std::fmt;
@@ -27,12 +32,17 @@ use serde::{Deserialize, Serialize};
 #[cfg(target_arch = "aarch64")]
 use util::device_tree;
 
+use self::errors::ErrorKind;
 pub use self::errors::Result;
+pub use balloon::*;
 pub use boot_source::*;
 pub use chardev::*;
 pub use fs::*;
+pub use iothread::*;
 pub use machine_config::*;
 pub use network::*;
+pub use pmem::*;
+pub use rng::*;
 
 pub mod errors {
     error_chain! {
@@ -41,9 +51,9 @@ pub mod errors {
                 description("Limit the length of String.")
                 display("Input {} string's length must be no more than {}.", t, len)
             }
-            NrcpusError {
+            NrcpusError(requested: u64, max: u8) {
                 description("Limit the number of vcpu in StratoVirt.")
-                display("Number of vcpu should be more than 0 and less than 255.")
+                display("Number of vcpus {} is out of range, it should be more than 0 and no more than {}.", requested, max)
             }
             MemsizeError {
                 description("Limit the size of memory in StratoVirt.")
@@ -65,28 +75,38 @@ pub mod errors {
                 description("Check legality of file.")
                 display("{} is not a regular File.", t)
             }
+            DriveOptionError(t: String) {
+                description("Check compatibility of drive options.")
+                display("Drive option is illegal: {}.", t)
+            }
+            ConsolePortsError {
+                description("Limit the number of ports of virtio-console.")
+                display("Number of console ports should be more than 0 and no more than 32.")
+            }
+            UuidFormatError(t: String) {
+                description("Check legality of uuid.")
+                display("Uuid {} is illegal, it should be like xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx.", t)
+            }
+            ConfigFileError(path: String, problem: String) {
+                description("Check fields of config file.")
+                display("Field '{}' of config file {}.", path, problem)
+            }
+            RngRateError(rate: u64) {
+                description("Limit the rate of virtio-rng.")
+                display("Rate of rng {} bytes/sec is too low, it should be no less than 64.", rate)
+            }
+            IdFormatError(t: String) {
+                description("Check legality of device id.")
+                display("Id '{}' is illegal, it should start with a letter or '_', followed by no more than 63 letters, digits, '_', '.' or '-'.", t)
+            }
         }
     }
 }
 
 /// `MAX_VCPUS`: the most cpu number Vm support.
 pub static MAX_VCPUS: u8 = 128_u8;
-
-/// Macro: From serde_json: Value $y to get member $z, use $s's from_value
-/// function to convert.
-///
-/// # Example
-///
-/// ```text
-/// config_parse!(machine_config, value, "machine-config", MachineConfig);
-/// ```
-macro_rules! config_parse {
-    ( $x:expr, $y:expr, $z:expr, $s:tt ) => {
-        if let Some(tmp_value) = $y.get($z) {
-            $x = $s::from_value(tmp_value);
-        }
-    };
-}
+/// Max length of the id of device or backend.
+const MAX_ID_LENGTH: usize = 64;
 
 /// This main config structure for Vm, contains Vm's basic configuration and devices.
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
@@ -95,56 +115,170 @@ pub struct VmConfig {
     pub boot_source: BootSource,
     pub drives: Option<Vec<DriveConfig>>,
     pub nets: Option<Vec<NetworkInterfaceConfig>>,
+    pub chardevs: Option<Vec<ChardevConfig>>,
     pub consoles: Option<Vec<ConsoleConfig>>,
     pub vsock: Option<VsockConfig>,
     pub serial: Option<SerialConfig>,
+    pub iothreads: Option<Vec<IothreadConfig>>,
+    pub balloon: Option<BalloonConfig>,
+    pub rng: Option<RngConfig>,
+    pub pmem: Option<PmemConfig>,
 }
 
 impl VmConfig {
-    /// Create the `VmConfig` from `Value`.
+    /// Healthy check for `VmConfig`
+    pub fn check_vmconfig(&self, is_daemonize: bool) -> Result<()> {
+        self.boot_source.check()?;
+        self.check_machine_and_devices(is_daemonize)?;
+
+        if self.boot_source.initrd.is_none() && self.drives.is_none() {
+            bail!("Before Vm start, set a initrd or drive_file as rootfs");
+        }
+
+        Ok(())
+    }
+
+    /// Check that `VmConfig` is able to restore the VM saved in snapshot
+    /// with config `saved`. Backends of devices, such as image files, taps
+    /// and chardevs, may differ, but guest must see the same machine, whose
+    /// devices are in the same order, so that their MMIO addresses are the
+    /// same. Boot source is not checked, as guest is not booted again.
     ///
     /// # Arguments
     ///
-    /// * `Value` - structure can be gotten by `json_file`.
-    pub fn create_from_value(value: serde_json::Value) -> Result<VmConfig> {
-        let mut machine_config = MachineConfig::default();
-        let mut boot_source = BootSource::default();
-        let mut drives = None;
-        let mut nets = None;
-        let mut consoles = None;
-        let mut vsock = None;
-        let mut serial = None;
-
-        // Use macro to use from_value function for every member
-        config_parse!(machine_config, value, "machine-config", MachineConfig);
-        config_parse!(boot_source, value, "boot-source", BootSource);
-        config_parse!(drives, value, "drive", DriveConfig);
-        config_parse!(nets, value, "net", NetworkInterfaceConfig);
-        config_parse!(consoles, value, "console", ConsoleConfig);
-        config_parse!(vsock, value, "vsock", VsockConfig);
-        config_parse!(serial, value, "serial", SerialConfig);
-
-        Ok(VmConfig {
-            machine_config,
-            boot_source,
-            drives,
-            nets,
-            consoles,
-            vsock,
-            serial,
-        })
+    /// * `saved` - The config of VM saved in snapshot.
+    /// * `is_daemonize` - Whether StratoVirt runs as a daemon.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if `VmConfig` is unhealthy, or it names the machine
+    /// option or the device which differs from snapshot.
+    pub fn check_restore(&self, saved: &VmConfig, is_daemonize: bool) -> Result<()> {
+        self.check_machine_and_devices(is_daemonize)?;
+        self.check_same_machine(saved, "snapshot")
     }
 
-    /// Healthy check for `VmConfig`
-    pub fn check_vmconfig(&self, is_daemonize: bool) -> Result<()> {
-        self.boot_source.check()?;
-        self.machine_config.check()?;
+    /// Check `VmConfig` of VM migrated in, which isn't booted. It's checked
+    /// against the config of source by `check_migration` when the source
+    /// connects.
+    ///
+    /// # Arguments
+    ///
+    /// * `is_daemonize` - Whether StratoVirt runs as a daemon.
+    pub fn check_incoming(&self, is_daemonize: bool) -> Result<()> {
+        self.check_machine_and_devices(is_daemonize)
+    }
 
-        if self.drives.is_some() {
-            for drive in self.drives.as_ref().unwrap() {
-                drive.check()?;
-            }
+    /// Check that `VmConfig` checked by `check_incoming` is able to take VM
+    /// migrated from the source with config `source`, as `check_restore`
+    /// does.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the machine option or the device which differs
+    /// from the source.
+    pub fn check_migration(&self, source: &VmConfig) -> Result<()> {
+        self.check_same_machine(source, "source")
+    }
+
+    /// Check that guest sees the same machine with `VmConfig` and `saved`,
+    /// which is the config of `origin`.
+    fn check_same_machine(&self, saved: &VmConfig, origin: &str) -> Result<()> {
+        let machine = &self.machine_config;
+        let saved_machine = &saved.machine_config;
+        if machine.mem_size != saved_machine.mem_size {
+            bail!(
+                "Memory size {} mismatches {} in {}",
+                machine.mem_size,
+                saved_machine.mem_size,
+                origin
+            );
         }
+        if machine.nr_cpus != saved_machine.nr_cpus {
+            bail!(
+                "Number of vcpus {} mismatches {} in {}",
+                machine.nr_cpus,
+                saved_machine.nr_cpus,
+                origin
+            );
+        }
+        if machine.max_cpus != saved_machine.max_cpus {
+            bail!(
+                "Max number of vcpus {:?} mismatches {:?} in {}",
+                machine.max_cpus,
+                saved_machine.max_cpus,
+                origin
+            );
+        }
+
+        let devices = self.device_list();
+        let saved_devices = saved.device_list();
+        if let Some(device) = saved_devices.iter().find(|d| !devices.contains(d)) {
+            bail!("Device {} in {} is not configured", device, origin);
+        }
+        if let Some(device) = devices.iter().find(|d| !saved_devices.contains(d)) {
+            bail!("Device {} is not in {}", device, origin);
+        }
+        if devices != saved_devices {
+            bail!(
+                "Devices are configured in the order {:?}, but {:?} in {}",
+                devices,
+                saved_devices,
+                origin
+            );
+        }
+        Ok(())
+    }
+
+    /// Let the devices, chardevs and iothreads given more than once with
+    /// the same id override the former ones in place, so that backends of
+    /// a config can be changed without changing the order of devices.
+    pub fn override_devices(&mut self) {
+        override_by_id(&mut self.drives, |drive| &drive.drive_id);
+        override_by_id(&mut self.nets, |net| &net.iface_id);
+        override_by_id(&mut self.chardevs, |chardev| &chardev.id);
+        override_by_id(&mut self.consoles, |console| &console.console_id);
+        override_by_id(&mut self.iothreads, |iothread| &iothread.id);
+    }
+
+    /// Get the devices seen by guest, in the order they're attached to bus.
+    fn device_list(&self) -> Vec<String> {
+        let mut devices = Vec::new();
+        if self.serial.is_some() {
+            devices.push("serial".to_string());
+        }
+        if let Some(vsock) = self.vsock.as_ref() {
+            devices.push(format!("vsock '{}'", vsock.vsock_id));
+        }
+        for drive in self.drives.iter().flatten() {
+            devices.push(format!("drive '{}'", drive.drive_id));
+        }
+        for net in self.nets.iter().flatten() {
+            devices.push(format!("net '{}'", net.iface_id));
+        }
+        if self.balloon.is_some() {
+            devices.push("balloon".to_string());
+        }
+        if self.rng.is_some() {
+            devices.push("rng".to_string());
+        }
+        if let Some(pmem) = self.pmem.as_ref() {
+            devices.push(format!("pmem of size {:#x}", pmem.size));
+        }
+        for console in self.consoles.iter().flatten() {
+            devices.push(format!("console '{}'", console.console_id));
+        }
+        devices
+    }
+
+    /// Check the machine and every device, which is all of `check_vmconfig`
+    /// but boot source.
+    fn check_machine_and_devices(&self, is_daemonize: bool) -> Result<()> {
+        self.machine_config.check()?;
+
+        self.check_drives()?;
+
+        self.check_device_ids()?;
 
         if self.nets.is_some() {
             for net in self.nets.as_ref().unwrap() {
@@ -162,24 +296,73 @@ impl VmConfig {
             self.vsock.as_ref().unwrap().check()?;
         }
 
-        if self.boot_source.initrd.is_none() && self.drives.is_none() {
-            bail!("Before Vm start, set a initrd or drive_file as rootfs");
+        if let Some(balloon) = self.balloon.as_ref() {
+            balloon.check()?;
+        }
+
+        if let Some(rng) = self.rng.as_ref() {
+            rng.check()?;
         }
 
-        if self.serial.is_some() && self.serial.as_ref().unwrap().stdio && is_daemonize {
-            bail!("Serial with stdio and daemonize can't be set together");
+        if let Some(pmem) = self.pmem.as_ref() {
+            pmem.check()?;
         }
 
+        self.check_chardevs(is_daemonize)?;
+
+        self.check_iothreads()?;
+
+        Ok(())
+    }
+
+    /// Check that ids of devices are legal and unique. Drives, network
+    /// interfaces, consoles and vsock share one namespace, which is the
+    /// same one as the devices and backends added by QMP.
+    pub(crate) fn check_device_ids(&self) -> Result<()> {
+        let drives = self
+            .drives
+            .iter()
+            .flatten()
+            .map(|drive| ("drive", drive.drive_id.as_str()));
+        let nets = self
+            .nets
+            .iter()
+            .flatten()
+            .map(|net| ("net", net.iface_id.as_str()));
+        let consoles = self
+            .consoles
+            .iter()
+            .flatten()
+            .map(|console| ("console", console.console_id.as_str()));
+        let vsock = self
+            .vsock
+            .iter()
+            .map(|vsock| ("vsock", vsock.vsock_id.as_str()));
+
+        let mut used: Vec<&str> = Vec::new();
+        for (kind, id) in drives.chain(nets).chain(consoles).chain(vsock) {
+            check_id(id)?;
+            if used.contains(&id) {
+                bail!("Duplicate ID '{}' for {}", id, kind);
+            }
+            used.push(id);
+        }
         Ok(())
     }
 
-    /// Update argument `name` to `VmConfig`.
+    /// Update '-name' config to `VmConfig`, the name can be given as
+    /// `guest=name` or `name`.
     ///
     /// # Arguments
     ///
     /// * `name` - The name `String` updated to `VmConfig`.
     pub fn update_name(&mut self, name: String) {
-        self.machine_config.name = name;
+        let cmd_params: CmdParams = CmdParams::from_str(name);
+        if let Some(name) = cmd_params.get_value_str("guest") {
+            self.machine_config.name = name;
+        } else if let Some(name) = cmd_params.get_value_str("") {
+            self.machine_config.name = name;
+        }
     }
 }
 
@@ -190,6 +373,45 @@ impl device_tree::CompileFDT for VmConfig {
     }
 }
 
+/// Replace the former item of `list` with the later one of the same id.
+fn override_by_id<T>(list: &mut Option<Vec<T>>, id: fn(&T) -> &str) {
+    if let Some(items) = list.as_mut() {
+        let mut index = 0;
+        while index < items.len() {
+            let former = items[..index]
+                .iter()
+                .position(|item| id(item) == id(&items[index]));
+            match former {
+                Some(former) => {
+                    let item = items.remove(index);
+                    items[former] = item;
+                }
+                None => index += 1,
+            }
+        }
+    }
+}
+
+/// Check that `id` of device or backend is like `[A-Za-z_][A-Za-z0-9_.-]{0,63}`.
+///
+/// # Errors
+///
+/// `IdFormatError` if it's not.
+pub fn check_id(id: &str) -> Result<()> {
+    let mut chars = id.chars();
+    let legal = match chars.next() {
+        Some(first) => {
+            (first.is_ascii_alphabetic() || first == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+        }
+        None => false,
+    };
+    if !legal || id.len() > MAX_ID_LENGTH {
+        return Err(ErrorKind::IdFormatError(id.to_string()).into());
+    }
+    Ok(())
+}
+
 /// This trait is to cast trait object to struct.
 pub trait AsAny {
     fn as_any(&self) -> &dyn Any;
@@ -213,6 +435,7 @@ pub trait ConfigCheck: AsAny + Send + Sync {
     /// * `GuestCidError` - Vsock guest-cid is illegel.
     /// * `MacFormatError` - Mac address is illegel.
     /// * `UnRegularFile` - File is illegel.
+    /// * `RngRateError` - Rate of virtio-rng is too low.
     fn check(&self) -> Result<()>;
 }
 
@@ -222,11 +445,11 @@ pub trait ConfigCheck: AsAny + Send + Sync {
 ///
 /// The attr format such as `param_type=value` can be treated as a `Param`
 /// Single attr such as `quiet` can also be treated as Param
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Param {
-    /// The item on the left of `=`, if no `=`, param_type is ""
+    /// The item on the left of the first `=`, if no `=`, param_type is ""
     pub param_type: String,
-    /// The item on the right of `=`, if no `=`, the whole is value
+    /// The item on the right of the first `=`, if no `=`, the whole is value
     pub value: String,
 }
 
@@ -237,7 +460,7 @@ impl Param {
     ///
     /// * `item` - The `str` transformed to `Param`.
     fn from_str(item: &str) -> Self {
-        let split = item.split('=');
+        let split = item.splitn(2, '=');
         let vec = split.collect::<Vec<&str>>();
         if vec.len() == 1 {
             Param {
@@ -289,10 +512,19 @@ impl Param {
 
     /// Converts `yes`,`on`,`true`,`no`,`off`,`false` in `value` to `bool`.
     pub fn to_bool(&self) -> bool {
+        self.parse_bool().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Converts `yes`,`on`,`true`,`no`,`off`,`false` in `value` to `bool`.
+    ///
+    /// # Errors
+    ///
+    /// `value` is none of them.
+    pub fn parse_bool(&self) -> Result<bool> {
         match self.value.as_ref() {
-            "yes" | "on" | "true" => true,
-            "no" | "off" | "false" => false,
-            _ => panic!("Can only give `yes`,`on`,`true`,`no`,`off`,`false` for boolean."),
+            "yes" | "on" | "true" => Ok(true),
+            "no" | "off" | "false" => Ok(false),
+            _ => bail!("Can only give `yes`,`on`,`true`,`no`,`off`,`false` for boolean."),
         }
     }
 }
@@ -441,6 +673,149 @@ mod tests {
         assert_eq!(test_param.value_to_u64(), 8u64);
     }
 
+    #[test]
+    fn test_check_id() {
+        let long_id = format!("a{}", "0".repeat(63));
+        for id in ["rootfs", "_drive-0", "net.1", "A_b-C.9", long_id.as_str()].iter() {
+            assert!(check_id(id).is_ok(), "{}", id);
+        }
+        let too_long = format!("{}0", long_id);
+        for id in [
+            "",
+            "0drive",
+            "-net",
+            ".a",
+            "a b",
+            "a/b",
+            "a,b",
+            "ä",
+            too_long.as_str(),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                check_id(id).unwrap_err().to_string(),
+                format!(
+                    "Id '{}' is illegal, it should start with a letter or '_', followed by no more than 63 letters, digits, '_', '.' or '-'.",
+                    id
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_device_ids() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_drive("id=rootfs,file=/path/to/rootfs".to_string());
+        vm_config.update_net("id=net0,netdev=tap0".to_string());
+        vm_config.update_device("virtio-console,id=console0,chardev=chr0".to_string());
+        vm_config.update_device("vsock,id=vsock0,guest-cid=3".to_string());
+        assert!(vm_config.check_device_ids().is_ok());
+
+        // Devices of different types share one namespace.
+        let mut other = vm_config.clone();
+        other.update_net("id=rootfs,netdev=tap1".to_string());
+        assert_eq!(
+            other.check_device_ids().unwrap_err().to_string(),
+            "Duplicate ID 'rootfs' for net"
+        );
+        let mut other = vm_config.clone();
+        other.update_device("virtio-console,id=net0,chardev=chr1".to_string());
+        assert_eq!(
+            other.check_device_ids().unwrap_err().to_string(),
+            "Duplicate ID 'net0' for console"
+        );
+        let mut other = vm_config.clone();
+        other.update_device("vsock,id=console0,guest-cid=3".to_string());
+        assert_eq!(
+            other.check_device_ids().unwrap_err().to_string(),
+            "Duplicate ID 'console0' for vsock"
+        );
+
+        // Chardevs and iothreads are not devices, their ids are apart.
+        let mut other = vm_config.clone();
+        other.update_chardev("socket,id=rootfs,path=/tmp/rootfs.sock".to_string());
+        other.update_iothread("id=net0".to_string());
+        assert!(other.check_device_ids().is_ok());
+
+        vm_config.update_drive("id=data disk,file=/path/to/data".to_string());
+        assert!(vm_config
+            .check_device_ids()
+            .unwrap_err()
+            .to_string()
+            .starts_with("Id 'data disk' is illegal"));
+    }
+
+    #[test]
+    fn test_check_restore() {
+        let mut saved = VmConfig::default();
+        saved.update_memory("256M".to_string());
+        saved.update_drive("id=rootfs,file=/path/to/rootfs".to_string());
+        saved.update_net("id=net0,netdev=tap0".to_string());
+        saved.update_rng("random_file=/dev/urandom".to_string());
+
+        // Backends of devices are changed in place.
+        let mut vm_config = saved.clone();
+        vm_config.update_drive("id=rootfs,file=/path/to/other".to_string());
+        vm_config.update_net("id=net0,netdev=tap1".to_string());
+        vm_config.override_devices();
+        let drives = vm_config.drives.as_ref().unwrap();
+        assert_eq!(drives.len(), 1);
+        assert_eq!(drives[0].path_on_host, "/path/to/other");
+        assert_eq!(vm_config.nets.as_ref().unwrap()[0].host_dev_name, "tap1");
+        assert!(vm_config.check_restore(&saved, false).is_ok());
+
+        let mut other = vm_config.clone();
+        other.update_memory("512M".to_string());
+        assert_eq!(
+            other.check_restore(&saved, false).unwrap_err().to_string(),
+            "Memory size 536870912 mismatches 268435456 in snapshot"
+        );
+        let mut other = vm_config.clone();
+        other.update_cpu("2".to_string());
+        assert_eq!(
+            other.check_restore(&saved, false).unwrap_err().to_string(),
+            "Number of vcpus 2 mismatches 1 in snapshot"
+        );
+        let mut other = vm_config.clone();
+        other.rng = None;
+        assert_eq!(
+            other.check_restore(&saved, false).unwrap_err().to_string(),
+            "Device rng in snapshot is not configured"
+        );
+        // Guest sees the size of pmem.
+        let mut other = vm_config.clone();
+        other.update_pmem("path=/path/to/pmem,size=4M".to_string());
+        assert_eq!(
+            other.check_restore(&saved, false).unwrap_err().to_string(),
+            "Device pmem of size 0x400000 is not in snapshot"
+        );
+        let mut other = vm_config.clone();
+        other.update_drive("id=data,file=/path/to/data".to_string());
+        assert_eq!(
+            other.check_restore(&saved, false).unwrap_err().to_string(),
+            "Device drive 'data' is not in snapshot"
+        );
+        assert_eq!(
+            other.check_migration(&saved).unwrap_err().to_string(),
+            "Device drive 'data' is not in source"
+        );
+
+        let mut saved = saved;
+        saved.update_drive("id=data,file=/path/to/data".to_string());
+        let mut other = vm_config;
+        other
+            .drives
+            .as_mut()
+            .unwrap()
+            .insert(0, saved.drives.as_ref().unwrap()[1].clone());
+        assert!(other
+            .check_restore(&saved, false)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Devices are configured in the order"));
+    }
+
     #[test]
     fn test_cmd_param() {
         let test_cmdline = "socket,id=charconsole0,path=/tmp/console.sock";
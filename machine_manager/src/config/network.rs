@@ -31,7 +31,11 @@ pub struct NetworkInterfaceConfig {
     pub mac: Option<String>,
     pub tap_fd: Option<i32>,
     pub vhost_type: Option<String>,
+    /// Opened fd of vhost-net device, which is used instead of opening
+    /// `/dev/vhost-net`. StratoVirt uses a duplicate of it, the fd itself is
+    /// never closed.
     pub vhost_fd: Option<i32>,
+    pub iothread: Option<String>,
 }
 
 impl NetworkInterfaceConfig {
@@ -55,6 +59,7 @@ impl Default for NetworkInterfaceConfig {
             tap_fd: None,
             vhost_type: None,
             vhost_fd: None,
+            iothread: None,
         }
     }
 }
@@ -85,6 +90,15 @@ impl ConfigCheck for NetworkInterfaceConfig {
             }
         }
 
+        if let Some(vhost_fd) = self.vhost_fd {
+            if self.vhost_type.is_none() {
+                bail!("Vhost fd {} can't be used with vhost off", vhost_fd);
+            }
+            if vhost_fd < 0 {
+                bail!("Vhost fd {} is illegal", vhost_fd);
+            }
+        }
+
         Ok(())
     }
 }
@@ -120,14 +134,20 @@ impl VmConfig {
         if let Some(tap_fd) = cmd_params.get("fds") {
             net.tap_fd = Some(tap_fd.value_to_u32() as i32);
         }
-        if let Some(vhost) = cmd_params.get("vhost") {
-            if vhost.to_bool() {
-                net.vhost_type = Some("vhost-kernel".to_string());
-            }
-        }
-        if let Some(vhostfd) = cmd_params.get("vhostfds") {
-            net.vhost_fd = Some(vhostfd.value_to_u32() as i32);
+        // `vhostfds` is the former name of `vhostfd`.
+        net.vhost_fd = cmd_params
+            .get_value_i32("vhostfd")
+            .or_else(|| cmd_params.get_value_i32("vhostfds"));
+        // Vhost is on if vhost fd is given, unless it's turned off, which is
+        // refused by check.
+        let vhost = match cmd_params.get("vhost") {
+            Some(vhost) => vhost.to_bool(),
+            None => net.vhost_fd.is_some(),
+        };
+        if vhost {
+            net.vhost_type = Some("vhost-kernel".to_string());
         }
+        net.iothread = cmd_params.get_value_str("iothread");
 
         self.add_netdev(net);
     }
@@ -161,3 +181,53 @@ fn check_mac_address(mac: &str) -> bool {
 
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_net_vhost_fd() {
+        let cases = [
+            ("id=net0,netdev=tap0", None, None),
+            ("id=net0,netdev=tap0,vhost=on", Some("vhost-kernel"), None),
+            (
+                "id=net0,netdev=tap0,vhostfd=5",
+                Some("vhost-kernel"),
+                Some(5),
+            ),
+            (
+                "id=net0,netdev=tap0,vhost=on,vhostfds=6",
+                Some("vhost-kernel"),
+                Some(6),
+            ),
+            ("id=net0,netdev=tap0,vhost=off,vhostfd=5", None, Some(5)),
+        ];
+        for (cmdline, vhost_type, vhost_fd) in cases.iter() {
+            let mut vm_config = VmConfig::default();
+            vm_config.update_net(cmdline.to_string());
+            let net = &vm_config.nets.unwrap()[0];
+            assert_eq!(net.vhost_type.as_deref(), *vhost_type, "{}", cmdline);
+            assert_eq!(net.vhost_fd, *vhost_fd, "{}", cmdline);
+        }
+
+        // vhost fd with vhost off is refused
+        let mut vm_config = VmConfig::default();
+        vm_config.update_net("id=net0,netdev=tap0,vhost=off,vhostfd=5".to_string());
+        let net = &vm_config.nets.as_ref().unwrap()[0];
+        assert_eq!(
+            net.check().unwrap_err().to_string(),
+            "Vhost fd 5 can't be used with vhost off"
+        );
+
+        let net = NetworkInterfaceConfig {
+            vhost_type: Some("vhost-kernel".to_string()),
+            vhost_fd: Some(-1),
+            ..Default::default()
+        };
+        assert_eq!(
+            net.check().unwrap_err().to_string(),
+            "Vhost fd -1 is illegal"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+extern crate serde;
+extern crate serde_json;
+
+use serde::{Deserialize, Serialize};
+
+use super::errors::{ErrorKind, Result};
+use super::machine_config::str_to_size;
+use crate::config::{CmdParams, ConfigCheck, ParamOperation, VmConfig};
+
+const MAX_PATH_LENGTH: usize = 4096;
+/// Size of pmem is aligned to huge pages, so that guest can map it with them.
+pub const PMEM_ALIGN: u64 = 2 << 20;
+
+/// Config structure for virtio-pmem.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PmemConfig {
+    /// File on host backing the persistent memory, it's created if it
+    /// doesn't exist, and extended if it's shorter than `size`.
+    pub path: String,
+    /// Size of the persistent memory seen by guest.
+    pub size: u64,
+}
+
+impl PmemConfig {
+    /// Create `PmemConfig` from the options of `-pmem`, such as
+    /// `path=/path/to/pmem,size=1G`. Size without suffix is in bytes.
+    ///
+    /// # Errors
+    ///
+    /// An option is unknown or missing, or `size` is not a size. The config
+    /// is not checked.
+    pub fn from_cmdline(pmem_config: &str) -> Result<Self> {
+        let cmd_params: CmdParams = CmdParams::from_str(pmem_config.to_string());
+        let mut path = None;
+        let mut size = None;
+        for param in cmd_params.params.iter() {
+            match param.param_type.as_str() {
+                "path" => path = Some(param.value.clone()),
+                "size" => {
+                    let bytes = str_to_size(&param.value, 1).map_err(|reason| {
+                        format!("Invalid size '{}' of pmem: {}.", param.value, reason)
+                    })?;
+                    size = Some(bytes);
+                }
+                "" if param.value.is_empty() => (),
+                _ => bail!("Unknown option '{}'", param),
+            }
+        }
+        match (path, size) {
+            (Some(path), Some(size)) => Ok(PmemConfig { path, size }),
+            (None, _) => bail!("Option 'path' of pmem is missing"),
+            (_, None) => bail!("Option 'size' of pmem is missing"),
+        }
+    }
+}
+
+impl ConfigCheck for PmemConfig {
+    fn check(&self) -> Result<()> {
+        if self.path.is_empty() {
+            bail!("Path of pmem is empty");
+        }
+        if self.path.len() > MAX_PATH_LENGTH {
+            return Err(
+                ErrorKind::StringLengthTooLong("pmem path".to_string(), MAX_PATH_LENGTH).into(),
+            );
+        }
+        if self.size == 0 || self.size % PMEM_ALIGN != 0 {
+            bail!(
+                "Size of pmem {:#x} should be a non-zero multiple of 2M",
+                self.size
+            );
+        }
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    /// Update '-pmem ...' config to `VmConfig`.
+    ///
+    /// # Notes
+    ///
+    /// Panic if the options can't be parsed, or a pmem device is already
+    /// configured, only one is supported.
+    pub fn update_pmem(&mut self, pmem_config: String) {
+        if self.pmem.is_some() {
+            panic!("Only one pmem device can be configured");
+        }
+        let pmem = PmemConfig::from_cmdline(&pmem_config)
+            .unwrap_or_else(|e| panic!("Invalid pmem '{}': {}", pmem_config, e));
+        self.pmem = Some(pmem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pmem_config() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_pmem("path=/path/to/pmem,size=1G".to_string());
+        let pmem = vm_config.pmem.unwrap();
+        assert_eq!(pmem.path, "/path/to/pmem");
+        assert_eq!(pmem.size, 1 << 30);
+        assert!(pmem.check().is_ok());
+
+        // The same options are given by `-device`.
+        let mut vm_config = VmConfig::default();
+        vm_config.update_device("virtio-pmem,size=2097152,path=/path/to/pmem".to_string());
+        assert_eq!(
+            vm_config.pmem,
+            Some(PmemConfig {
+                path: "/path/to/pmem".to_string(),
+                size: 2 << 20,
+            })
+        );
+
+        let errors = [
+            ("size=1G", "Option 'path' of pmem is missing"),
+            ("path=/path/to/pmem", "Option 'size' of pmem is missing"),
+            (
+                "path=/path/to/pmem,size=1X",
+                "Invalid size '1X' of pmem: it should be a number with optional suffix K, M, G, T, P or E.",
+            ),
+            (
+                "path=/path/to/pmem,size=1G,share=on",
+                "Unknown option 'share=on'",
+            ),
+        ];
+        for (cmdline, error) in errors.iter() {
+            assert_eq!(
+                PmemConfig::from_cmdline(cmdline).unwrap_err().to_string(),
+                *error
+            );
+        }
+
+        // Size is aligned to 2M.
+        for size in [0, 1 << 20, (2 << 20) + 4096].iter() {
+            let pmem = PmemConfig {
+                path: "/path/to/pmem".to_string(),
+                size: *size,
+            };
+            assert_eq!(
+                pmem.check().unwrap_err().to_string(),
+                format!(
+                    "Size of pmem {:#x} should be a non-zero multiple of 2M",
+                    size
+                )
+            );
+        }
+        let pmem = PmemConfig {
+            path: String::new(),
+            size: 2 << 20,
+        };
+        assert_eq!(
+            pmem.check().unwrap_err().to_string(),
+            "Path of pmem is empty"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only one pmem device can be configured")]
+    fn test_update_pmem_twice() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_pmem("path=/path/to/pmem,size=2M".to_string());
+        vm_config.update_device("virtio-pmem,path=/path/to/other,size=2M".to_string());
+    }
+}
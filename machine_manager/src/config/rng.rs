@@ -0,0 +1,175 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+extern crate serde;
+extern crate serde_json;
+
+use std::fs::File;
+
+use serde::{Deserialize, Serialize};
+
+use super::errors::{ErrorKind, Result, ResultExt};
+use crate::config::{CmdParams, ConfigCheck, ParamOperation, VmConfig};
+
+const MAX_PATH_LENGTH: usize = 4096;
+/// Minimum rate of rng, a lower one hardly feeds the entropy pool of guest.
+const MIN_BYTES_PER_SEC: u64 = 64;
+
+fn default_random_file() -> String {
+    "/dev/urandom".to_string()
+}
+
+/// Config structure for virtio-rng.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RngConfig {
+    /// File on host where random bytes are read.
+    #[serde(default = "default_random_file")]
+    pub random_file: String,
+    /// Max bytes given to guest per second, unlimited if not set.
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl Default for RngConfig {
+    fn default() -> Self {
+        RngConfig {
+            random_file: default_random_file(),
+            bytes_per_sec: None,
+        }
+    }
+}
+
+impl RngConfig {
+    /// Create `RngConfig` from the options of `-rng`, such as
+    /// `random_file=/dev/urandom,bytes_per_sec=1234`. The options not set
+    /// are default.
+    ///
+    /// # Errors
+    ///
+    /// An option is unknown or `bytes_per_sec` is not an integer. The
+    /// config is not checked.
+    pub fn from_cmdline(rng_config: &str) -> Result<Self> {
+        let cmd_params: CmdParams = CmdParams::from_str(rng_config.to_string());
+        let mut rng = RngConfig::default();
+        for param in cmd_params.params.iter() {
+            match param.param_type.as_str() {
+                "random_file" => rng.random_file = param.value.clone(),
+                "bytes_per_sec" => {
+                    let rate = param.value.parse::<u64>().chain_err(|| {
+                        format!("bytes_per_sec '{}' is not an integer", param.value)
+                    })?;
+                    rng.bytes_per_sec = Some(rate);
+                }
+                "" if param.value.is_empty() => (),
+                _ => bail!("Unknown option '{}'", param),
+            }
+        }
+        Ok(rng)
+    }
+}
+
+impl ConfigCheck for RngConfig {
+    fn check(&self) -> Result<()> {
+        if self.random_file.len() > MAX_PATH_LENGTH {
+            return Err(ErrorKind::StringLengthTooLong(
+                "rng random file".to_string(),
+                MAX_PATH_LENGTH,
+            )
+            .into());
+        }
+
+        File::open(&self.random_file)
+            .chain_err(|| format!("Failed to open random file {} of rng", self.random_file))?;
+
+        if let Some(rate) = self.bytes_per_sec {
+            if rate < MIN_BYTES_PER_SEC {
+                return Err(ErrorKind::RngRateError(rate).into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    /// Update '-rng ...' config to `VmConfig`.
+    ///
+    /// # Notes
+    ///
+    /// Panic with the rng string if any option can't be parsed.
+    pub fn update_rng(&mut self, rng_config: String) {
+        let rng = RngConfig::from_cmdline(&rng_config)
+            .unwrap_or_else(|e| panic!("Invalid rng '{}': {}", rng_config, e));
+        self.rng = Some(rng);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_config() {
+        // Defaults.
+        let rng = RngConfig::from_cmdline("").unwrap();
+        assert_eq!(rng, RngConfig::default());
+        assert_eq!(rng.random_file, "/dev/urandom");
+        assert_eq!(rng.bytes_per_sec, None);
+        assert!(rng.check().is_ok());
+        let rng: RngConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(rng, RngConfig::default());
+
+        let mut vm_config = VmConfig::default();
+        vm_config.update_rng("random_file=/dev/random,bytes_per_sec=1234".to_string());
+        let rng = vm_config.rng.unwrap();
+        assert_eq!(rng.random_file, "/dev/random");
+        assert_eq!(rng.bytes_per_sec, Some(1234));
+        assert!(rng.check().is_ok());
+
+        // Missing file.
+        let rng = RngConfig::from_cmdline("random_file=/path/to/nothing").unwrap();
+        assert_eq!(
+            rng.check().unwrap_err().to_string(),
+            "Failed to open random file /path/to/nothing of rng"
+        );
+
+        // Bad rate.
+        let rng = RngConfig::from_cmdline("bytes_per_sec=63").unwrap();
+        assert_eq!(
+            rng.check().unwrap_err().to_string(),
+            "Rate of rng 63 bytes/sec is too low, it should be no less than 64."
+        );
+        assert!(RngConfig::from_cmdline("bytes_per_sec=64")
+            .unwrap()
+            .check()
+            .is_ok());
+        let errors = [
+            ("bytes_per_sec=-1", "bytes_per_sec '-1' is not an integer"),
+            ("bytes_per_sec=1k", "bytes_per_sec '1k' is not an integer"),
+            ("period=1000", "Unknown option 'period=1000'"),
+        ];
+        for (cmdline, error) in errors.iter() {
+            assert_eq!(
+                RngConfig::from_cmdline(cmdline).unwrap_err().to_string(),
+                *error
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid rng 'bytes_per_sec=fast'")]
+    fn test_update_rng_invalid() {
+        let mut vm_config = VmConfig::default();
+        vm_config.update_rng("bytes_per_sec=fast".to_string());
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Locate the field of json which fails to be deserialized.
+//!
+//! The error of serde has the line of field in error but not its name. The
+//! json is deserialized from its pretty printed text, where every field and
+//! element is on its own line, so that the path of field is found by the
+//! line of error, such as `drive[1].read_only`.
+
+/// Get the path of field in `error` raised by deserializing pretty printed
+/// `text`, and what's wrong with it, such as `expects a boolean`.
+///
+/// # Notes
+///
+/// Return `None` if the error is not caused by one field, such as invalid
+/// json, so that the caller reports the original error.
+pub(crate) fn describe(error: &serde_json::Error, text: &str) -> Option<(String, String)> {
+    let msg = error.to_string();
+    if msg.starts_with("unknown field `") {
+        let path = field_path(text, error.line(), false)?;
+        Some((path, "is unexpected".to_string()))
+    } else if msg.starts_with("missing field `") {
+        let field = msg.split('`').nth(1)?;
+        let mut path = field_path(text, error.line(), true)?;
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(field);
+        Some((path, "is missing".to_string()))
+    } else if msg.starts_with("unknown variant `") {
+        let expected = msg.split(", expected ").nth(1)?;
+        let expected = expected.split(" at line ").next()?;
+        let path = field_path(text, error.line(), false)?;
+        Some((path, format!("expects {}", expected)))
+    } else if msg.starts_with("invalid type: ") || msg.starts_with("invalid value: ") {
+        let expected = msg.split(", expected ").nth(1)?;
+        let expected = expected.split(" at line ").next()?;
+        let path = field_path(text, error.line(), false)?;
+        Some((path, format!("expects {}", type_name(expected))))
+    } else {
+        None
+    }
+}
+
+/// Opened object or array of pretty printed json.
+struct Opened {
+    indent: usize,
+    /// Segment of path, `.key` or `[index]`.
+    segment: String,
+    /// Index of the next element if it's an array.
+    next_index: Option<usize>,
+}
+
+/// Get the path of field at `line` of pretty printed `text`. If it's
+/// `closing`, the path of object closed at `line` is returned instead, which
+/// is where serde reports a missing field.
+fn field_path(text: &str, line: usize, closing: bool) -> Option<String> {
+    let mut opened: Vec<Opened> = Vec::new();
+    for (index, text) in text.lines().enumerate() {
+        let content = text.trim_start();
+        let indent = text.len() - content.len();
+        let is_closing = content.starts_with('}') || content.starts_with(']');
+
+        if index + 1 == line && closing && is_closing {
+            opened.retain(|o| o.indent <= indent);
+            return Some(join_path(&opened));
+        }
+
+        opened.retain(|o| o.indent < indent);
+        let segment = if is_closing {
+            None
+        } else if let Some(array) = opened.last_mut().filter(|o| o.next_index.is_some()) {
+            let element = array.next_index.unwrap();
+            array.next_index = Some(element + 1);
+            Some(format!("[{}]", element))
+        } else {
+            serde_json::Deserializer::from_str(content)
+                .into_iter::<String>()
+                .next()
+                .and_then(|key| key.ok())
+                .filter(|_| content.starts_with('"'))
+                .map(|key| format!(".{}", key))
+        };
+
+        if let Some(segment) = segment {
+            let next_index = if content.ends_with('[') {
+                Some(0)
+            } else {
+                None
+            };
+            opened.push(Opened {
+                indent,
+                segment,
+                next_index,
+            });
+        }
+        if index + 1 == line {
+            return Some(join_path(&opened));
+        }
+        if !content.ends_with('{') && !content.ends_with('[') {
+            opened.retain(|o| o.indent < indent);
+        }
+    }
+    None
+}
+
+fn join_path(opened: &[Opened]) -> String {
+    let path: String = opened.iter().map(|o| o.segment.as_str()).collect();
+    path.trim_start_matches('.').to_string()
+}
+
+/// Translate the type expected by serde to the type of json value.
+fn type_name(expected: &str) -> &str {
+    match expected {
+        "u8" | "u16" | "u32" | "u64" | "usize" | "i8" | "i16" | "i32" | "i64" | "isize" => {
+            "an integer"
+        }
+        "f32" | "f64" => "a number",
+        "a sequence" => "an array",
+        "a map" => "an object",
+        _ if expected.starts_with("struct ") => "an object",
+        _ => expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_path() {
+        let text = serde_json::to_string_pretty(&serde_json::json!({
+            "boot-source": { "kernel_image_path": "/kernel" },
+            "drive": [
+                { "drive_id": "rootfs", "read_only": false },
+                { "drive_id": "data", "read_only": "yes" }
+            ],
+            "tags": ["a", "b"]
+        }))
+        .unwrap();
+        let line_of = |needle: &str| text.lines().position(|l| l.contains(needle)).unwrap() + 1;
+
+        assert_eq!(
+            field_path(&text, line_of("/kernel"), false).as_deref(),
+            Some("boot-source.kernel_image_path")
+        );
+        assert_eq!(
+            field_path(&text, line_of("\"yes\""), false).as_deref(),
+            Some("drive[1].read_only")
+        );
+        assert_eq!(
+            field_path(&text, line_of("\"b\""), false).as_deref(),
+            Some("tags[1]")
+        );
+        // The first drive is closed two lines before "data".
+        assert_eq!(
+            field_path(&text, line_of("\"data\"") - 2, true).as_deref(),
+            Some("drive[0]")
+        );
+        assert_eq!(field_path(&text, 1, false).as_deref(), Some(""));
+    }
+}
@@ -25,9 +25,13 @@
 extern crate log;
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
+extern crate lazy_static;
 extern crate serde_json;
 
+pub mod chardev;
 pub mod config;
+mod json_error;
 pub mod machine;
 #[cfg(feature = "qmp")]
 pub mod qmp;
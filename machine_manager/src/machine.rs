@@ -18,7 +18,7 @@ use std::os::unix::io::RawFd;
 use crate::qmp::Response;
 
 #[cfg(feature = "qmp")]
-use crate::qmp::qmp_schema::{CacheOptions, FileOptions};
+use crate::qmp::qmp_schema::{blockdev_add, device_add, migrate, RunState, StatusInfo};
 
 /// State for KVM VM.
 #[derive(PartialEq, Copy, Clone)]
@@ -31,6 +31,162 @@ pub enum KvmVmState {
     Shutdown = 6,
 }
 
+impl KvmVmState {
+    /// Name of state, the same as `RunState` of Qemu.
+    pub fn name(self) -> &'static str {
+        match self {
+            KvmVmState::Created => "prelaunch",
+            KvmVmState::Running => "running",
+            KvmVmState::InMigrating => "inmigrate",
+            KvmVmState::Migrated => "postmigrate",
+            KvmVmState::Paused => "paused",
+            KvmVmState::Shutdown => "shutdown",
+        }
+    }
+
+    /// Status of VM reported by `query-status`, VM is running only in
+    /// `Running` state.
+    #[cfg(feature = "qmp")]
+    pub fn status_info(self) -> StatusInfo {
+        let status = match self {
+            KvmVmState::Created => RunState::prelaunch,
+            KvmVmState::Running => RunState::running,
+            KvmVmState::InMigrating => RunState::inmigrate,
+            KvmVmState::Migrated => RunState::postmigrate,
+            KvmVmState::Paused => RunState::paused,
+            KvmVmState::Shutdown => RunState::shutdown,
+        };
+        StatusInfo {
+            singlestep: false,
+            running: self == KvmVmState::Running,
+            status,
+        }
+    }
+}
+
+/// Check whether `command` can transform VM from `state` to `target`.
+///
+/// # Notes
+///
+/// `stop` transforms VM to `Paused` and `cont` to `Running`:
+///
+/// | state         | `stop`   | `cont`   |
+/// |---------------|----------|----------|
+/// | `prelaunch`   | error    | error    |
+/// | `running`     | pause    | no-op    |
+/// | `paused`      | no-op    | resume   |
+/// | `inmigrate`   | error    | error    |
+/// | `postmigrate` | error    | error    |
+/// | `shutdown`    | error    | error    |
+///
+/// Return `Ok(false)` if VM is in `target` already, which is a no-op
+/// success as Qemu does, and `Ok(true)` if the transformation is needed.
+///
+/// # Arguments
+///
+/// * `command` - The name of QMP command requesting the transformation.
+/// * `state` - The current `KvmVmState`.
+/// * `target` - The `KvmVmState` expected to transform to.
+///
+/// # Errors
+///
+/// Return the message naming the current state and the transformation if
+/// it's illegal.
+pub fn check_transition(
+    command: &str,
+    state: KvmVmState,
+    target: KvmVmState,
+) -> std::result::Result<bool, String> {
+    match (state, target) {
+        (KvmVmState::Running, KvmVmState::Running) | (KvmVmState::Paused, KvmVmState::Paused) => {
+            Ok(false)
+        }
+        (KvmVmState::Running, KvmVmState::Paused) | (KvmVmState::Paused, KvmVmState::Running) => {
+            Ok(true)
+        }
+        // VM whose vcpus are frozen at startup by `-S` is started by `cont`,
+        // and `stop` does nothing before it.
+        (KvmVmState::Created, KvmVmState::Running) => Ok(true),
+        (KvmVmState::Created, KvmVmState::Paused) => Ok(false),
+        _ => Err(format!(
+            "Cannot execute '{}' in state '{}': transformation to '{}' is illegal",
+            command,
+            state.name(),
+            target.name()
+        )),
+    }
+}
+
+/// Check whether `migrate-incoming` can start listening for the source of
+/// VM in `state`, which is only allowed once for VM started with
+/// `-incoming defer`.
+///
+/// # Arguments
+///
+/// * `state` - The current `KvmVmState`.
+/// * `uri` - Uri listened by the former `migrate-incoming`, if any.
+///
+/// # Errors
+///
+/// Return the message telling why VM isn't waiting for `migrate-incoming`.
+pub fn check_migrate_incoming(
+    state: KvmVmState,
+    uri: Option<&str>,
+) -> std::result::Result<(), String> {
+    match (state, uri) {
+        (_, Some(uri)) => Err(format!(
+            "'migrate-incoming' has already been executed, VM is migrated in from {}",
+            uri
+        )),
+        (KvmVmState::InMigrating, None) => Ok(()),
+        _ => Err(format!(
+            "Cannot execute 'migrate-incoming' in state '{}': VM is not started with '-incoming defer'",
+            state.name()
+        )),
+    }
+}
+
+/// Action taken when guest requests to reboot, such as it writes the reset
+/// port or a vcpu triple-faults.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum RebootAction {
+    /// Reset VM, so that guest boots again. It's only taken by machines
+    /// which support `reset`.
+    Reset,
+    /// Shut down VM, as `-no-reboot` does.
+    Shutdown,
+}
+
+impl Default for RebootAction {
+    fn default() -> Self {
+        RebootAction::Shutdown
+    }
+}
+
+/// Dispatch reboot request of guest by `action`.
+///
+/// # Arguments
+///
+/// * `action` - Action set by `-action reboot=` or `-no-reboot`.
+/// * `reset` - Reset VM, it returns false if VM can't be reset.
+///
+/// # Notes
+///
+/// Return true if VM needs to be shut down, which is the case of action
+/// `Shutdown`, or VM fails to reset.
+pub fn reboot_dispatch<F: FnOnce() -> bool>(action: RebootAction, reset: F) -> bool {
+    match action {
+        RebootAction::Reset => {
+            if reset() {
+                return false;
+            }
+            warn!("VM can't be reset on request of guest, shut it down instead");
+            true
+        }
+        RebootAction::Shutdown => true,
+    }
+}
+
 /// Event over StratoVirt lifetime.
 pub enum VmEvent {
     ShutdownCauseGuestReset,
@@ -50,14 +206,16 @@ unsafe impl Send for VmEvent {}
 ///
 /// `None` --`(new)`--> `Created`
 /// `Created` --`(start)`--> `Running`
+/// `Created` --`(resume)`--> `Running`, if vcpus are frozen at startup by `-S`
 /// `Running` --`(pause)`--> `Paused`
 /// `Paused` --`(resume)`--> `Running`
+/// `Created` --`(defer)`--> `InMigrating`, if VM is started with `-incoming defer`
+/// `InMigrating` --`(migrate-incoming)`--> `Paused`, once VM is migrated in
 /// `KVM_VMSTATE_*` --`(destroy)`--> `None`
 ///
 /// **Notice**:
-///    1. Migrate state(`Migrated` and `InMigrating`),
-///    not include in Life cycle, both migrate state should deal like `PAUSED`
-///    state.
+///    1. Migrate state(`Migrated` and `InMigrating`), both migrate state
+///    should deal like `PAUSED` state, but can't be resumed by `cont`.
 ///
 ///    2. Snapshot state deal with `PAUSED` state.
 ///
@@ -85,6 +243,31 @@ pub trait MachineLifecycle {
         self.notify_lifecycle(KvmVmState::Running, KvmVmState::Shutdown)
     }
 
+    /// Shut down VM or Device on request of guest, such as a vcpu exits for
+    /// shutdown. It may be requested more than once, by every vcpu, so that
+    /// only the first request counts.
+    ///
+    /// Return false if the request is ignored.
+    fn request_guest_shutdown(&self) -> bool {
+        self.destroy()
+    }
+
+    /// Reset VM or Device, so that it runs as if it's powered on again.
+    ///
+    /// Return false if it's not supported.
+    fn reset(&self) -> bool {
+        false
+    }
+
+    /// Reboot VM or Device on request of guest, such as guest writes the
+    /// reset port or a vcpu triple-faults. Like `request_guest_shutdown`,
+    /// only the first request counts if VM is shut down for it.
+    ///
+    /// Return false if the request is ignored.
+    fn request_guest_reset(&self) -> bool {
+        self.request_guest_shutdown()
+    }
+
     /// When VM or Device life state changed, notify concerned entry.
     ///
     /// # Arguments
@@ -130,6 +313,34 @@ pub trait DeviceInterface {
     #[cfg(feature = "qmp")]
     fn query_status(&self) -> Response;
 
+    /// Pause VM, it's a no-op if VM is paused already.
+    ///
+    /// # Errors
+    ///
+    /// Return the message if VM can't be paused, see `check_transition`.
+    #[cfg(feature = "qmp")]
+    fn stop(&self) -> std::result::Result<(), String>;
+
+    /// Resume VM, it's a no-op if VM is running already.
+    ///
+    /// # Errors
+    ///
+    /// Return the message if VM can't be resumed, see `check_transition`.
+    #[cfg(feature = "qmp")]
+    fn cont(&self) -> std::result::Result<(), String>;
+
+    /// Press the power button of guest, the guest is asked to shut down.
+    #[cfg(feature = "qmp")]
+    fn system_powerdown(&self) -> Response;
+
+    /// Query the name of VM.
+    #[cfg(feature = "qmp")]
+    fn query_name(&self) -> Response;
+
+    /// Query the UUID of VM.
+    #[cfg(feature = "qmp")]
+    fn query_uuid(&self) -> Response;
+
     /// Query each cpu's the topology info.
     #[cfg(feature = "qmp")]
     fn query_cpus(&self) -> Response;
@@ -138,33 +349,124 @@ pub trait DeviceInterface {
     #[cfg(feature = "qmp")]
     fn query_hotpluggable_cpus(&self) -> Response;
 
-    /// Add a device with configuration.
-    fn device_add(
+    /// Query memory advice which takes effect on each guest ram mapping.
+    #[cfg(feature = "qmp")]
+    fn query_memory_advice(&self) -> Response;
+
+    /// Query the machine types supported.
+    #[cfg(feature = "qmp")]
+    fn query_machines(&self) -> Response;
+
+    /// Query the effective value of each property of `-machine`.
+    #[cfg(feature = "qmp")]
+    fn query_machine_properties(&self) -> Response;
+
+    /// Query statistics of the main loop and iothreads.
+    #[cfg(feature = "qmp")]
+    fn query_eventloop_stats(&self, reset: Option<bool>) -> Response;
+
+    /// Query the time of boot milestones of VM.
+    #[cfg(feature = "qmp")]
+    fn query_boot_times(&self) -> Response;
+
+    /// Query the capabilities of host KVM.
+    #[cfg(feature = "qmp")]
+    fn query_kvm_caps(&self) -> Response;
+
+    /// Query the block devices and their backends.
+    #[cfg(feature = "qmp")]
+    fn query_block(&self) -> Response;
+
+    /// Query the network devices and their backends.
+    #[cfg(feature = "qmp")]
+    fn query_network(&self) -> Response;
+
+    /// Query the memory regions of guest ram and devices.
+    #[cfg(feature = "qmp")]
+    fn query_mtree(&self) -> Response;
+
+    /// List the properties of QOM object at `path`.
+    #[cfg(feature = "qmp")]
+    fn qom_list(&self, path: String) -> Response;
+
+    /// Get the value of `property` of QOM object at `path`.
+    #[cfg(feature = "qmp")]
+    fn qom_get(&self, path: String, property: String) -> Response;
+
+    /// Dump guest memory and vcpu registers as an ELF core file.
+    #[cfg(feature = "qmp")]
+    fn dump_guest_memory(
         &self,
-        device_id: String,
-        driver: String,
-        addr: Option<String>,
-        lun: Option<usize>,
-    ) -> bool;
+        protocol: String,
+        paging: bool,
+        detach: Option<bool>,
+        begin: Option<u64>,
+        length: Option<u64>,
+    ) -> Response;
+
+    /// Save the state of VM to a snapshot file at `path`, VM is left paused
+    /// after saved if `paused` is true.
+    #[cfg(feature = "qmp")]
+    fn x_snapshot_save(&self, path: String, paused: Option<bool>) -> Response;
+
+    /// Start migrating VM out to the destination at `uri` in background,
+    /// its status changes are emitted as `MIGRATION` events.
+    #[cfg(feature = "qmp")]
+    fn migrate(&self, args: migrate) -> Response;
+
+    /// Cancel the running migration, VM keeps running if it was.
+    #[cfg(feature = "qmp")]
+    fn migrate_cancel(&self) -> Response;
 
-    /// Delete a device with device id.
-    fn device_del(&self, device_id: String) -> bool;
+    /// Listen on `uri` for the source of VM started with `-incoming defer`,
+    /// VM is left paused once it's migrated in.
+    #[cfg(feature = "qmp")]
+    fn migrate_incoming(&self, uri: String) -> Response;
+
+    /// Query the status and progress of the last migration.
+    #[cfg(feature = "qmp")]
+    fn query_migrate(&self) -> Response;
 
-    /// Creates a new block device.
-    fn blockdev_add(
+    /// Set the parameters of migration, which are kept if not given.
+    #[cfg(feature = "qmp")]
+    fn migrate_set_parameters(
         &self,
-        node_name: String,
-        file: FileOptions,
-        cache: Option<CacheOptions>,
-        read_only: Option<bool>,
-    ) -> bool;
+        max_bandwidth: Option<u64>,
+        downtime_limit: Option<u64>,
+    ) -> Response;
 
-    /// Create a new network device.
-    fn netdev_add(&self, id: String, if_name: Option<String>, fds: Option<String>) -> bool;
+    /// Add a device with configuration, which is a block or network device
+    /// realized from the replaceable slot, or a port of multiport console.
+    #[cfg(feature = "qmp")]
+    fn device_add(&self, args: device_add) -> Response;
+
+    /// Delete a device with device id, `DEVICE_DELETED` is emitted once the
+    /// removal is complete.
+    #[cfg(feature = "qmp")]
+    fn device_del(&self, device_id: String) -> Response;
 
-    /// Receive a file descriptor via SCM rights and assign it a name.
+    /// Creates a new block device, which is realized by the next
+    /// `device_add` with the same id.
     #[cfg(feature = "qmp")]
-    fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response;
+    fn blockdev_add(&self, args: blockdev_add) -> Response;
+
+    /// Create a new network device, which is realized by the next
+    /// `device_add` with the same id.
+    ///
+    /// # Errors
+    ///
+    /// The fds can't be resolved, or the id is illegal or already used.
+    fn netdev_add(
+        &self,
+        id: String,
+        if_name: Option<String>,
+        fds: Option<String>,
+    ) -> std::result::Result<(), String>;
+
+    /// Receive file descriptors sent in one message via SCM rights and
+    /// assign them names, the ones not restored must be closed.
+    #[cfg(feature = "qmp")]
+    fn getfd(&self, fd_name: String, fds: Vec<RawFd>) -> Response;
 }
 
 /// Machine interface which is exposed to inner hypervisor.
@@ -172,3 +474,106 @@ pub trait MachineInterface: MachineLifecycle + MachineAddressInterface {}
 
 /// Machine interface which is exposed to outer hypervisor.
 pub trait MachineExternalInterface: MachineLifecycle + DeviceInterface {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_transition() {
+        use KvmVmState::*;
+
+        // (state, result of `stop`, result of `cont`)
+        let matrix = [
+            (Created, Some(false), Some(true)),
+            (Running, Some(true), Some(false)),
+            (Paused, Some(false), Some(true)),
+            (InMigrating, None, None),
+            (Migrated, None, None),
+            (Shutdown, None, None),
+        ];
+        for (state, stop, cont) in matrix.iter() {
+            assert_eq!(check_transition("stop", *state, Paused).ok(), *stop);
+            assert_eq!(check_transition("cont", *state, Running).ok(), *cont);
+        }
+
+        assert_eq!(
+            check_transition("stop", Shutdown, Paused),
+            Err(
+                "Cannot execute 'stop' in state 'shutdown': transformation to 'paused' is illegal"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check_transition("cont", Migrated, Running),
+            Err(
+                "Cannot execute 'cont' in state 'postmigrate': transformation to 'running' is illegal"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_check_migrate_incoming() {
+        use KvmVmState::*;
+
+        // VM deferred by `-incoming defer` waits in `inmigrate` state.
+        assert_eq!(check_migrate_incoming(InMigrating, None), Ok(()));
+        assert_eq!(
+            check_migrate_incoming(InMigrating, Some("tcp:0.0.0.0:4444")),
+            Err(
+                "'migrate-incoming' has already been executed, VM is migrated in from tcp:0.0.0.0:4444"
+                    .to_string()
+            )
+        );
+        // Once migrated in, it's paused until `cont`.
+        assert!(check_migrate_incoming(Paused, Some("tcp:0.0.0.0:4444")).is_err());
+        assert_eq!(check_transition("cont", Paused, Running), Ok(true));
+
+        for state in [Created, Running, Migrated, Paused, Shutdown].iter() {
+            assert_eq!(
+                check_migrate_incoming(*state, None),
+                Err(format!(
+                    "Cannot execute 'migrate-incoming' in state '{}': VM is not started with '-incoming defer'",
+                    state.name()
+                ))
+            );
+        }
+    }
+
+    #[cfg(feature = "qmp")]
+    #[test]
+    fn test_status_info() {
+        use KvmVmState::*;
+
+        for state in [Created, Running, InMigrating, Migrated, Paused, Shutdown].iter() {
+            let info = serde_json::to_value(state.status_info()).unwrap();
+            assert_eq!(info["status"], serde_json::Value::from(state.name()));
+            assert_eq!(info["running"], serde_json::Value::from(*state == Running));
+            assert_eq!(info["singlestep"], serde_json::Value::from(false));
+        }
+    }
+
+    #[test]
+    fn test_reboot_dispatch() {
+        use std::cell::Cell;
+
+        // (action, result of reset, VM is reset, VM is shut down)
+        let matrix = [
+            (RebootAction::Reset, true, true, false),
+            (RebootAction::Reset, false, true, true),
+            (RebootAction::Shutdown, true, false, true),
+            (RebootAction::Shutdown, false, false, true),
+        ];
+        for (action, result, reset, shutdown) in matrix.iter() {
+            let called = Cell::new(false);
+            let ret = reboot_dispatch(*action, || {
+                called.set(true);
+                *result
+            });
+            assert_eq!(called.get(), *reset);
+            assert_eq!(ret, *shutdown);
+        }
+        assert_eq!(RebootAction::default(), RebootAction::Shutdown);
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Diagnose the arguments of a command which fails to be parsed.
+//!
+//! `QmpCommand` is tagged by `execute`, serde buffers the whole request
+//! before parsing arguments, so its error has neither field name nor useful
+//! position. Here the arguments are parsed again as the argument struct of
+//! command from pretty printed json, so the field can be found by the line
+//! of error.
+
+use serde_json::Value;
+
+use super::qmp_schema as schema;
+use super::Command;
+use crate::json_error::describe;
+
+/// Generate `check_command`, which parses the arguments as the argument
+/// struct of command, a new command only needs an entry here.
+macro_rules! check_commands {
+    ($($cmd:ident),*) => {
+        fn check_command(name: &str, args: &str) -> Option<serde_json::Error> {
+            $(
+                if name == <schema::$cmd as Command>::NAME {
+                    return serde_json::from_str::<schema::$cmd>(args).err();
+                }
+            )*
+            None
+        }
+    };
+}
+
+check_commands!(
+    qmp_capabilities,
+    quit,
+    system_powerdown,
+    stop,
+    cont,
+    device_add,
+    device_del,
+    netdev_add,
+    netdev_del,
+    query_hotpluggable_cpus,
+    query_cpus,
+    query_memory_advice,
+    query_machines,
+    query_machine_properties,
+    query_eventloop_stats,
+    query_boot_times,
+    query_kvm_caps,
+    query_event_buffer,
+    query_version,
+    query_commands,
+    query_events,
+    query_status,
+    query_name,
+    query_uuid,
+    getfd,
+    closefd,
+    logfile_reopen,
+    human_monitor_command,
+    dump_guest_memory,
+    x_snapshot_save,
+    migrate,
+    migrate_cancel,
+    migrate_incoming,
+    query_migrate,
+    migrate_set_parameters,
+    qom_list,
+    qom_get,
+    blockdev_add,
+    blockdev_del
+);
+
+/// Get the message describing which argument of `request` is wrong, such
+/// as "Parameter 'lun' of command 'device_add' expects an integer".
+///
+/// # Notes
+///
+/// Return `None` if `request` is not a json object, or its command is
+/// unknown, or its arguments are all right, so that the caller reports the
+/// original error.
+///
+/// # Arguments
+///
+/// * `request` - The raw request which fails to be parsed as `QmpCommand`.
+pub fn argument_error(request: &str) -> Option<String> {
+    let request = serde_json::from_str::<Value>(request).ok()?;
+    let name = request.get("execute")?.as_str()?;
+    let args = match request.get("arguments") {
+        Some(Value::Object(args)) => Value::Object(args.clone()),
+        Some(_) => {
+            return Some(format!(
+                "Parameter 'arguments' of command '{}' expects an object",
+                name
+            ))
+        }
+        None => Value::Object(Default::default()),
+    };
+    let args = serde_json::to_string_pretty(&args).ok()?;
+    let error = check_command(name, &args)?;
+    let (path, problem) = describe(&error, &args)?;
+    Some(format!(
+        "Parameter '{}' of command '{}' {}",
+        path, name, problem
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argument_error() {
+        let cases = [
+            // device_add
+            (
+                r#"{"execute":"device_add","arguments":{"id":"net-0","driver":"virtio-net-mmio","romfile":""}}"#,
+                "Parameter 'romfile' of command 'device_add' is unexpected",
+            ),
+            (
+                r#"{"execute":"device_add","arguments":{"id":"disk-0","driver":"virtio-blk-mmio","lun":"0"}}"#,
+                "Parameter 'lun' of command 'device_add' expects an integer",
+            ),
+            (
+                r#"{"execute":"device_add","arguments":{"id":"disk-0","driver":"virtio-blk-mmio","lun":-1}}"#,
+                "Parameter 'lun' of command 'device_add' expects an integer",
+            ),
+            (
+                r#"{"execute":"device_add","arguments":{"id":0,"driver":"virtio-blk-mmio"}}"#,
+                "Parameter 'id' of command 'device_add' expects a string",
+            ),
+            (
+                r#"{"execute":"device_add","arguments":{"id":"net-0"}}"#,
+                "Parameter 'driver' of command 'device_add' is missing",
+            ),
+            (
+                r#"{"execute":"device_add"}"#,
+                "Parameter 'id' of command 'device_add' is missing",
+            ),
+            (
+                r#"{"execute":"device_add","arguments":"net-0"}"#,
+                "Parameter 'arguments' of command 'device_add' expects an object",
+            ),
+            // blockdev-add
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/img"},"format":"raw"}}"#,
+                "Parameter 'format' of command 'blockdev-add' is unexpected",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/img","locking":"off"}}}"#,
+                "Parameter 'file.locking' of command 'blockdev-add' is unexpected",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/img"},"read-only":"yes"}}"#,
+                "Parameter 'read-only' of command 'blockdev-add' expects a boolean",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/img"},"cache":{"direct":1}}}"#,
+                "Parameter 'cache.direct' of command 'blockdev-add' expects a boolean",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":"/img"}}"#,
+                "Parameter 'file' of command 'blockdev-add' expects an object",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0"}}"#,
+                "Parameter 'file' of command 'blockdev-add' is missing",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file"}}}"#,
+                "Parameter 'file.filename' of command 'blockdev-add' is missing",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{}}}"#,
+                "Parameter 'file.driver' of command 'blockdev-add' is missing",
+            ),
+            (
+                r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/img"},"aio":"posix"}}"#,
+                "Parameter 'aio' of command 'blockdev-add' expects one of `threads`, `native`, `io_uring`",
+            ),
+            // Commands without arguments.
+            (
+                r#"{"execute":"query-status","arguments":{"verbose":true}}"#,
+                "Parameter 'verbose' of command 'query-status' is unexpected",
+            ),
+        ];
+        for (request, error) in cases.iter() {
+            assert_eq!(
+                argument_error(request).as_deref(),
+                Some(*error),
+                "{}",
+                request
+            );
+        }
+
+        // Errors not caused by arguments are left to the caller.
+        assert_eq!(argument_error(r#"{"execute":"device_add""#), None);
+        assert_eq!(argument_error(r#"{"execute":"no-such-command"}"#), None);
+        assert_eq!(argument_error(r#"{"arguments":{}}"#), None);
+        assert_eq!(
+            argument_error(r#"{"execute":"device_del","arguments":{"id":"net-0"}}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn test_all_commands_checked() {
+        for name in schema::QMP_COMMANDS {
+            let request = format!(r#"{{"execute":"{}","arguments":{{"x-unknown":0}}}}"#, name);
+            assert_eq!(
+                argument_error(&request),
+                Some(format!(
+                    "Parameter 'x-unknown' of command '{}' is unexpected",
+                    name
+                ))
+            );
+        }
+    }
+}
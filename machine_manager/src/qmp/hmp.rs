@@ -0,0 +1,295 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! A subset of human monitor commands, executed by `human-monitor-command`.
+//!
+//! Every `info` command runs the same query as the QMP command does, and
+//! formats its result as human-readable text. Lines are ended by `\r\n` as
+//! Qemu's human monitor does.
+
+use serde_json::Value;
+
+use super::Response;
+use crate::machine::MachineExternalInterface;
+
+/// `info` command of human monitor.
+struct InfoCommand {
+    /// Name of command, such as `status` for `info status`.
+    name: &'static str,
+    /// Help message listed by `info`.
+    help: &'static str,
+    /// Query the result of command.
+    query: fn(&dyn MachineExternalInterface) -> Response,
+    /// Format the result of query as text.
+    format: fn(&Value) -> String,
+}
+
+/// All supported `info` commands, a new one only needs an entry here.
+const INFO_COMMANDS: &[InfoCommand] = &[
+    InfoCommand {
+        name: "status",
+        help: "show the current VM status (running|paused)",
+        query: |controller| controller.query_status(),
+        format: format_status,
+    },
+    InfoCommand {
+        name: "cpus",
+        help: "show infos for each CPU",
+        query: |controller| controller.query_cpus(),
+        format: format_cpus,
+    },
+    InfoCommand {
+        name: "block",
+        help: "show info of block devices",
+        query: |controller| controller.query_block(),
+        format: format_block,
+    },
+    InfoCommand {
+        name: "network",
+        help: "show the network state",
+        query: |controller| controller.query_network(),
+        format: format_network,
+    },
+    InfoCommand {
+        name: "mtree",
+        help: "show memory tree",
+        query: |controller| controller.query_mtree(),
+        format: format_mtree,
+    },
+];
+
+/// Execute a command line of human monitor, and return its output.
+///
+/// # Notes
+///
+/// Error of command is returned as output, just as Qemu does, so that it's
+/// never reported as a QMP error.
+///
+/// # Arguments
+///
+/// * `controller` - The machine which the command is executed on.
+/// * `command_line` - The command line of human monitor.
+pub fn execute(controller: &dyn MachineExternalInterface, command_line: &str) -> String {
+    let mut args = command_line.split_whitespace();
+    match args.next() {
+        None => String::new(),
+        Some("info") => match args.next() {
+            None => info_help(),
+            Some(name) => match INFO_COMMANDS.iter().find(|cmd| cmd.name == name) {
+                Some(cmd) => run_info(cmd, controller),
+                None => format!("unknown command: 'info {}'\r\n", name),
+            },
+        },
+        Some("help") | Some("?") => {
+            "info [subcommand] -- show various information about the system state\r\n".to_string()
+        }
+        Some(cmd) => format!("unknown command: '{}'\r\n", cmd),
+    }
+}
+
+fn run_info(cmd: &InfoCommand, controller: &dyn MachineExternalInterface) -> String {
+    let response = (cmd.query)(controller);
+    if let Some(error) = response.error {
+        return format!("Error: {}\r\n", error.desc);
+    }
+    (cmd.format)(response.return_.as_ref().unwrap_or(&Value::Null))
+}
+
+fn info_help() -> String {
+    INFO_COMMANDS
+        .iter()
+        .map(|cmd| format!("info {} -- {}\r\n", cmd.name, cmd.help))
+        .collect()
+}
+
+/// Get the elements of result in array, empty if it's not an array.
+fn entries(value: &Value) -> &[Value] {
+    value.as_array().map_or(&[][..], Vec::as_slice)
+}
+
+fn attached_to(value: &Value) -> String {
+    let mut attached = value["attached-to"].as_str().unwrap_or("-").to_string();
+    if value["removing"].as_bool().unwrap_or(false) {
+        attached += " (removing)";
+    }
+    attached
+}
+
+fn format_status(value: &Value) -> String {
+    format!(
+        "VM status: {}\r\n",
+        value["status"].as_str().unwrap_or("unknown")
+    )
+}
+
+fn format_cpus(value: &Value) -> String {
+    entries(value)
+        .iter()
+        .map(|cpu| {
+            let current = if cpu["current"].as_bool().unwrap_or(false) {
+                '*'
+            } else {
+                ' '
+            };
+            format!(
+                "{} CPU #{}: thread_id={}\r\n",
+                current, cpu["CPU"], cpu["thread_id"]
+            )
+        })
+        .collect()
+}
+
+fn format_block(value: &Value) -> String {
+    entries(value)
+        .iter()
+        .map(|block| {
+            let read_only = if block["read-only"].as_bool().unwrap_or(false) {
+                ", read-only"
+            } else {
+                ""
+            };
+            let cache = if block["direct"].as_bool().unwrap_or(false) {
+                "writeback, direct"
+            } else {
+                "writeback"
+            };
+            format!(
+                "{}: {} (raw{})\r\n    Attached to:      {}\r\n    Cache mode:       {}\r\n",
+                block["device"].as_str().unwrap_or_default(),
+                block["file"].as_str().unwrap_or_default(),
+                read_only,
+                attached_to(block),
+                cache
+            )
+        })
+        .collect()
+}
+
+fn format_network(value: &Value) -> String {
+    entries(value)
+        .iter()
+        .map(|net| {
+            let mut backend = String::from("type=tap");
+            if let Some(ifname) = net["ifname"].as_str() {
+                backend += &format!(",ifname={}", ifname);
+            }
+            if let Some(fd) = net["fd"].as_i64() {
+                backend += &format!(",fd={}", fd);
+            }
+            if net["vhost"].as_bool().unwrap_or(false) {
+                backend += ",vhost=on";
+            }
+            if let Some(mac) = net["mac"].as_str() {
+                backend += &format!(",macaddr={}", mac);
+            }
+            format!(
+                "{}: {}\r\n    Attached to:      {}\r\n",
+                net["id"].as_str().unwrap_or_default(),
+                backend,
+                attached_to(net)
+            )
+        })
+        .collect()
+}
+
+fn format_mtree(value: &Value) -> String {
+    let mut output = String::new();
+    let mut address_space = None;
+    for region in entries(value) {
+        let space = region["address-space"].as_str().unwrap_or_default();
+        if address_space != Some(space) {
+            if address_space.is_some() {
+                output += "\r\n";
+            }
+            output += &format!("address-space: {}\r\n", space);
+            address_space = Some(space);
+        }
+        let addr = region["addr"].as_u64().unwrap_or(0);
+        let size = region["size"].as_u64().unwrap_or(0);
+        let region_type = if region["ram"].as_bool().unwrap_or(false) {
+            "ram"
+        } else {
+            "i/o"
+        };
+        output += &format!(
+            "  {:016x}-{:016x} (prio 0, {}): {}\r\n",
+            addr,
+            addr + size.saturating_sub(1),
+            region_type,
+            region["name"].as_str().unwrap_or_default()
+        );
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_hmp_format() {
+        let cpus = json!([
+            { "CPU": 0, "current": true, "thread_id": 1000 },
+            { "CPU": 1, "current": false, "thread_id": 1001 }
+        ]);
+        assert_eq!(
+            format_cpus(&cpus),
+            "* CPU #0: thread_id=1000\r\n  CPU #1: thread_id=1001\r\n"
+        );
+
+        let blocks = json!([{
+            "device": "drive-0",
+            "file": "/path/to/rootfs",
+            "read-only": true,
+            "direct": false,
+            "attached-to": "virtio-mmio@0x0a000000",
+            "removing": false
+        }]);
+        assert_eq!(
+            format_block(&blocks),
+            "drive-0: /path/to/rootfs (raw, read-only)\r\n    \
+             Attached to:      virtio-mmio@0x0a000000\r\n    \
+             Cache mode:       writeback\r\n"
+        );
+
+        let nets = json!([
+            { "id": "net-0", "ifname": "tap0", "vhost": true, "removing": true,
+              "attached-to": "virtio-mmio@0x0a006000" },
+            { "id": "net-1", "fd": 12, "vhost": false, "removing": false }
+        ]);
+        assert_eq!(
+            format_network(&nets),
+            "net-0: type=tap,ifname=tap0,vhost=on\r\n    \
+             Attached to:      virtio-mmio@0x0a006000 (removing)\r\n\
+             net-1: type=tap,fd=12\r\n    Attached to:      -\r\n"
+        );
+
+        let regions = json!([
+            { "address-space": "memory", "name": "ram", "addr": 0, "size": 0x4000_0000_u64, "ram": true },
+            { "address-space": "memory", "name": "virtio-mmio", "addr": 0x0a00_0000_u64, "size": 0x1000, "ram": false },
+            { "address-space": "I/O", "name": "serial", "addr": 0x3f8, "size": 8, "ram": false }
+        ]);
+        assert_eq!(
+            format_mtree(&regions),
+            "address-space: memory\r\n  \
+             0000000000000000-000000003fffffff (prio 0, ram): ram\r\n  \
+             000000000a000000-000000000a000fff (prio 0, i/o): virtio-mmio\r\n\
+             \r\naddress-space: I/O\r\n  \
+             00000000000003f8-00000000000003ff (prio 0, i/o): serial\r\n"
+        );
+
+        // Result which is not an array is formatted as empty.
+        assert_eq!(format_block(&json!({})), "");
+    }
+}
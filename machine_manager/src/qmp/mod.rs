@@ -33,24 +33,43 @@ extern crate serde_json;
 #[allow(non_snake_case)]
 pub mod qmp_schema;
 
-use std::collections::BTreeMap;
+mod args;
+mod hmp;
+
+use std::collections::{BTreeMap, VecDeque};
 use std::io::Write;
-use std::os::unix::io::RawFd;
-use std::sync::{Arc, RwLock};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use vmm_sys_util::terminal::Terminal;
+use vmm_sys_util::eventfd::EventFd;
 
-use crate::errors::Result;
+use crate::errors::{ErrorKind, Result};
 use crate::machine::MachineExternalInterface;
-use crate::socket::SocketRWHandler;
+use crate::socket::{SocketHandler, SocketRWHandler, SocketType};
 use qmp_schema as schema;
 use schema::QmpCommand;
 
-static mut QMP_CHANNEL: Option<Arc<QmpChannel>> = None;
+lazy_static! {
+    /// The `QmpMonitor` of api-channel, constructed by the first access.
+    pub(crate) static ref QMP_CHANNEL: Arc<QmpMonitor> = Arc::new(QmpMonitor::new());
+}
+
+/// Version of QEMU which StratoVirt pretends to be, reported in greeting and
+/// `query-version`.
+pub const QEMU_VERSION_MICRO: u8 = 1;
+pub const QEMU_VERSION_MINOR: u8 = 0;
+pub const QEMU_VERSION_MAJOR: u8 = 4;
+/// Version of StratoVirt.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Default number of events buffered while no client is connected.
+pub const DEFAULT_EVENT_BUFFER_DEPTH: usize = 128;
+/// Max number of events emitted but not handled by main loop yet.
+pub const EVENT_QUEUE_DEPTH: usize = 256;
 
 /// Macro `event!`: send event to qmp-client.
 ///
@@ -107,7 +126,8 @@ macro_rules! create_command_matches {
     };
 }
 
-/// Macro: to execute handle func $y/$a with every arguments $y/$tail.
+/// Macro: to execute handle func $y/$a with every arguments $y/$tail. With
+/// `Result`, the error returned by handle func is set to response $z.
 macro_rules! qmp_command_match {
     ( $x:tt;$y:expr ) => {
         {
@@ -119,6 +139,14 @@ macro_rules! qmp_command_match {
             $z = $y.$x();
         }
     };
+    ( $x:tt;$y:expr;$z:expr;Result ) => {
+        {
+            if let Err(e) = $y.$x() {
+                let err_resp = $crate::qmp::qmp_schema::QmpErrorClass::GenericError(e);
+                $z = $crate::qmp::Response::create_error_response(err_resp, None).unwrap();
+            }
+        }
+    };
     ( $x:tt;$y:expr;$a:expr;$($tail:tt),*) => {
         {
             $y.$x(
@@ -199,7 +227,7 @@ pub struct Response {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     error: Option<ErrorMessage>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    id: Option<u32>,
+    id: Option<Value>,
 }
 
 impl Response {
@@ -209,8 +237,8 @@ impl Response {
     ///
     /// * `v` - The `Value` of qmp `return` field.
     /// * `id` - The `id` for qmp `Response`, it must be equal to `Request`'s
-    ///          `id`.
-    pub fn create_response(v: Value, id: Option<u32>) -> Self {
+    ///          `id`, which can be any JSON value.
+    pub fn create_response(v: Value, id: Option<Value>) -> Self {
         Response {
             return_: Some(v),
             error: None,
@@ -232,10 +260,10 @@ impl Response {
     ///
     /// * `err_class` - The `QmpErrorClass` of qmp `error` field.
     /// * `id` - The `id` for qmp `Response`, it must be equal to `Request`'s
-    ///          `id`.
+    ///          `id`, which can be any JSON value.
     pub fn create_error_response(
         err_class: schema::QmpErrorClass,
-        id: Option<u32>,
+        id: Option<Value>,
     ) -> Result<Self> {
         Ok(Response {
             return_: None,
@@ -244,7 +272,7 @@ impl Response {
         })
     }
 
-    fn change_id(&mut self, id: Option<u32>) {
+    fn change_id(&mut self, id: Option<Value>) {
         self.id = id;
     }
 }
@@ -312,22 +340,67 @@ pub fn create_timestamp() -> TimeStamp {
 ///
 /// # Arguments
 ///
-/// * `stream_fd` - The input stream file description.
+/// * `qmp_service` - The handler of the input stream, which keeps the state
+///   between reads.
+/// * `sock_type` - The transport of stream.
+/// * `channel` - The monitor which the stream is bound to.
 /// * `controller` - The controller which execute actual qmp command.
 ///
+/// # Notes
+///
+/// File descriptors are restored by `controller`, in global `QMP_CHANNEL`.
+/// All the complete commands received are handled in order, each with the
+/// file descriptors sent along with it, and their responses are queued in
+/// the same order. A partial command is kept for the next read.
+///
 /// # Errors
 ///
 /// This function will fail when json parser failed or socket file description broke.
-pub fn handle_qmp(stream_fd: RawFd, controller: &Arc<dyn MachineExternalInterface>) -> Result<()> {
-    let mut qmp_service = crate::socket::SocketHandler::new(stream_fd);
+pub fn handle_qmp(
+    qmp_service: &mut SocketHandler,
+    sock_type: SocketType,
+    channel: &QmpMonitor,
+    controller: &Arc<dyn MachineExternalInterface>,
+) -> Result<()> {
+    // Commands sent at once are handled in order, a partial one is left
+    // for the next read.
+    loop {
+        handle_message(qmp_service, sock_type, channel, controller)?;
+        if !qmp_service.has_message() {
+            return Ok(());
+        }
+    }
+}
+
+/// Decode the next message from `qmp_service` and handle it.
+fn handle_message(
+    qmp_service: &mut SocketHandler,
+    sock_type: SocketType,
+    channel: &QmpMonitor,
+    controller: &Arc<dyn MachineExternalInterface>,
+) -> Result<()> {
     match qmp_service.decode_line() {
-        (Ok(None), _) => Ok(()),
-        (Ok(buffer), if_fd) => {
+        (Ok(None), fds) => {
+            close_fds(&fds);
+            Ok(())
+        }
+        (Ok(buffer), fds) => {
             info!("QMP: <-- {:?}", buffer);
             let qmp_command: schema::QmpCommand = buffer.unwrap();
-            let (return_msg, shutdown_flag) = qmp_command_exec(qmp_command, controller, if_fd);
+            let request = qmp_service.get_buffer();
+            let rejected = negotiate(channel, &qmp_command, request)
+                .or_else(|| check_transport(&qmp_command, sock_type, request));
+            let negotiated =
+                rejected.is_none() && matches!(qmp_command, QmpCommand::qmp_capabilities { .. });
+            let (return_msg, shutdown_flag) = match rejected {
+                Some(err_resp) => {
+                    close_fds(&fds);
+                    (err_resp, false)
+                }
+                None => qmp_command_exec(qmp_command, channel, controller, fds),
+            };
             info!("QMP: --> {:?}", return_msg);
-            qmp_service.send_str(&return_msg)?;
+            channel.send_response(qmp_service, &return_msg, negotiated)?;
 
             // handle shutdown command
             if shutdown_flag {
@@ -337,61 +410,286 @@ pub fn handle_qmp(stream_fd: RawFd, controller: &Arc<dyn MachineExternalInterfac
                 };
                 event!(SHUTDOWN; shutdown_msg);
 
-                std::io::stdin()
-                    .lock()
-                    .set_canon_mode()
-                    .expect("Failed to set terminal to canon mode.");
+                util::cleanup::run_cleanup_hooks();
                 std::process::exit(1);
             }
 
             Ok(())
         }
-        (Err(e), _) => {
-            let err_resp = schema::QmpErrorClass::GenericError(format!("{}", &e));
+        (Err(e), fds) => {
+            close_fds(&fds);
+            // The stream is broken, no response can be sent.
+            if let ErrorKind::Io(_) = e.kind() {
+                return Err(e);
+            }
             warn!("Qmp json parser made an error:{}", e);
-            qmp_service.send_str(&serde_json::to_string(&Response::create_error_response(
-                err_resp, None,
-            )?)?)?;
+            let request = qmp_service.get_buffer();
+            let err_msg = args::argument_error(request).unwrap_or_else(|| format!("{}", &e));
+            let err_resp = schema::QmpErrorClass::GenericError(err_msg);
+            let id = recover_id(request);
+            let err_msg = serde_json::to_string(&Response::create_error_response(err_resp, id)?)?;
+            channel.send_response(qmp_service, &err_msg, false)?;
             Ok(())
         }
     }
 }
 
+/// Check the capabilities negotiation of connection, return the error
+/// response if `qmp_command` is not allowed in current state. The
+/// negotiation completes after the response of `qmp_capabilities` is sent.
+///
+/// # Arguments
+///
+/// * `channel` - The monitor which the connection is bound to.
+/// * `qmp_command` - The command received.
+/// * `request` - The raw request, used to recover `id` of error response.
+fn negotiate(channel: &QmpMonitor, qmp_command: &QmpCommand, request: &str) -> Option<String> {
+    let negotiated = channel.is_negotiated();
+    let err_msg = match qmp_command {
+        QmpCommand::qmp_capabilities { .. } if !negotiated => return None,
+        QmpCommand::qmp_capabilities { .. } => {
+            "Capabilities negotiation is already complete, command ignored"
+        }
+        _ if negotiated => return None,
+        _ => "Expecting capabilities negotiation with 'qmp_capabilities'",
+    };
+
+    let err_resp = schema::QmpErrorClass::CommandNotFound(err_msg.to_string());
+    let resp = Response::create_error_response(err_resp, recover_id(request)).unwrap();
+    Some(serde_json::to_string(&resp).unwrap())
+}
+
+/// Check whether `qmp_command` is supported by the transport of connection,
+/// return the error response if not.
+///
+/// # Arguments
+///
+/// * `qmp_command` - The command received.
+/// * `sock_type` - The transport of connection.
+/// * `request` - The raw request, used to recover `id` of error response.
+fn check_transport(
+    qmp_command: &QmpCommand,
+    sock_type: SocketType,
+    request: &str,
+) -> Option<String> {
+    match (qmp_command, sock_type) {
+        // File descriptors are passed with SCM_RIGHTS, which only works on
+        // unix socket.
+        (QmpCommand::getfd { .. }, SocketType::Tcp) => {
+            let err_resp = schema::QmpErrorClass::GenericError(
+                "getfd is not supported on tcp socket, pass fd over unix socket".to_string(),
+            );
+            let resp = Response::create_error_response(err_resp, recover_id(request)).unwrap();
+            Some(serde_json::to_string(&resp).unwrap())
+        }
+        _ => None,
+    }
+}
+
+/// Commands answered to `query-commands`, it needs no machine state.
+fn commands() -> Vec<schema::CommandInfo> {
+    schema::QMP_COMMANDS
+        .iter()
+        .map(|name| schema::CommandInfo {
+            name: name.to_string(),
+        })
+        .collect()
+}
+
+/// Events answered to `query-events`, it needs no machine state.
+fn events() -> Vec<schema::EventInfo> {
+    schema::QMP_EVENTS
+        .iter()
+        .map(|name| schema::EventInfo {
+            name: name.to_string(),
+        })
+        .collect()
+}
+
+/// Version info answered to `query-version`, it needs no machine state.
+fn version_info() -> schema::VersionInfo {
+    schema::VersionInfo {
+        qemu: schema::VersionTriple {
+            micro: QEMU_VERSION_MICRO,
+            minor: QEMU_VERSION_MINOR,
+            major: QEMU_VERSION_MAJOR,
+        },
+        package: format!("StratoVirt-{}", VERSION),
+    }
+}
+
+/// Recover `id` from a request which fails to be parsed as `QmpCommand`, so
+/// that the error response can still be correlated with the request. It's
+/// `None` if the request is not a JSON object.
+fn recover_id(request: &str) -> Option<Value> {
+    match serde_json::from_str::<Value>(request) {
+        Ok(Value::Object(mut request)) => request.remove("id"),
+        _ => None,
+    }
+}
+
+/// Close the file descriptors received from client but not claimed.
+fn close_fds(fds: &[RawFd]) {
+    for fd in fds {
+        // It's safe because the fd is received from client and owned by us.
+        unsafe { libc::close(*fd) };
+    }
+    if !fds.is_empty() {
+        warn!("Close {} unclaimed fds received from client", fds.len());
+    }
+}
+
 /// Create a match , where `qmp_command` and its arguments matching by handle
-/// function, and exec this qmp command.
+/// function, and exec this qmp command. The file descriptors received with
+/// it are passed to `getfd`, or closed for other commands.
 fn qmp_command_exec(
     qmp_command: QmpCommand,
+    channel: &QmpMonitor,
     controller: &Arc<dyn MachineExternalInterface>,
-    if_fd: Option<RawFd>,
+    fds: Vec<RawFd>,
 ) -> (String, bool) {
+    if !matches!(qmp_command, QmpCommand::getfd { .. }) {
+        close_fds(&fds);
+    }
+
     let mut qmp_response = Response::create_empty_response();
     let mut shutdown_flag = false;
 
     // Use macro create match to cover most Qmp command
     let mut id = create_command_matches!(
         qmp_command.clone();
-        (stop, qmp_command_match!(pause; controller)),
-        (cont, qmp_command_match!(resume; controller)),
+        (stop, qmp_command_match!(stop; controller; qmp_response; Result)),
+        (cont, qmp_command_match!(cont; controller; qmp_response; Result)),
         (query_status, qmp_command_match!(query_status; controller; qmp_response)),
+        (query_name, qmp_command_match!(query_name; controller; qmp_response)),
+        (system_powerdown, qmp_command_match!(system_powerdown; controller; qmp_response)),
+        (query_uuid, qmp_command_match!(query_uuid; controller; qmp_response)),
         (query_cpus, qmp_command_match!(query_cpus; controller; qmp_response)),
         (query_hotpluggable_cpus,
-            qmp_command_match!(query_hotpluggable_cpus; controller; qmp_response));
-        (device_add, device_add, controller, id, driver, addr, lun),
-        (device_del, device_del, controller, id),
-        (blockdev_add, blockdev_add, controller, node_name, file, cache, read_only),
-        (netdev_add, netdev_add, controller, id, if_name, fds)
+            qmp_command_match!(query_hotpluggable_cpus; controller; qmp_response)),
+        (query_memory_advice,
+            qmp_command_match!(query_memory_advice; controller; qmp_response)),
+        (query_machines, qmp_command_match!(query_machines; controller; qmp_response)),
+        (query_machine_properties,
+            qmp_command_match!(query_machine_properties; controller; qmp_response)),
+        (query_boot_times, qmp_command_match!(query_boot_times; controller; qmp_response)),
+        (query_kvm_caps, qmp_command_match!(query_kvm_caps; controller; qmp_response)),
+        (migrate_cancel, qmp_command_match!(migrate_cancel; controller; qmp_response)),
+        (query_migrate, qmp_command_match!(query_migrate; controller; qmp_response));
     );
 
     // Handle the Qmp command which macro can't cover
     if id.is_none() {
         id = match qmp_command {
+            QmpCommand::qmp_capabilities { id, .. } => id,
+            QmpCommand::query_commands { id, .. } => {
+                qmp_response =
+                    Response::create_response(serde_json::to_value(commands()).unwrap(), None);
+                id
+            }
+            QmpCommand::query_events { id, .. } => {
+                qmp_response =
+                    Response::create_response(serde_json::to_value(events()).unwrap(), None);
+                id
+            }
+            QmpCommand::query_event_buffer { id, .. } => {
+                qmp_response = Response::create_response(
+                    serde_json::to_value(channel.event_buffer_info()).unwrap(),
+                    None,
+                );
+                id
+            }
+            QmpCommand::query_version { id, .. } => {
+                qmp_response =
+                    Response::create_response(serde_json::to_value(version_info()).unwrap(), None);
+                id
+            }
             QmpCommand::quit { id, .. } => {
                 controller.destroy();
                 shutdown_flag = true;
                 id
             }
+            QmpCommand::device_add { arguments, id } => {
+                qmp_response = controller.device_add(arguments);
+                id
+            }
+            QmpCommand::blockdev_add { arguments, id } => {
+                qmp_response = controller.blockdev_add(arguments);
+                id
+            }
+            QmpCommand::netdev_add { arguments, id } => {
+                if let Err(e) =
+                    controller.netdev_add(arguments.id, arguments.if_name, arguments.fds)
+                {
+                    let err_resp = schema::QmpErrorClass::GenericError(e);
+                    qmp_response = Response::create_error_response(err_resp, None).unwrap();
+                }
+                id
+            }
+            QmpCommand::device_del { arguments, id } => {
+                qmp_response = controller.device_del(arguments.id);
+                id
+            }
             QmpCommand::getfd { arguments, id } => {
-                qmp_response = controller.getfd(arguments.fd_name, if_fd);
+                qmp_response = controller.getfd(arguments.fd_name, fds);
+                id
+            }
+            QmpCommand::closefd { arguments, id } => {
+                if let Err(e) = QmpChannel::close_fd(&arguments.fd_name) {
+                    let err_resp = schema::QmpErrorClass::GenericError(e.to_string());
+                    qmp_response = Response::create_error_response(err_resp, None).unwrap();
+                }
+                id
+            }
+            QmpCommand::logfile_reopen { id, .. } => {
+                if let Err(e) = util::logger::reopen_log_file() {
+                    let err_resp = schema::QmpErrorClass::GenericError(e.to_string());
+                    qmp_response = Response::create_error_response(err_resp, None).unwrap();
+                }
+                id
+            }
+            QmpCommand::query_eventloop_stats { arguments, id } => {
+                qmp_response = controller.query_eventloop_stats(arguments.reset);
+                id
+            }
+            QmpCommand::qom_list { arguments, id } => {
+                qmp_response = controller.qom_list(arguments.path);
+                id
+            }
+            QmpCommand::qom_get { arguments, id } => {
+                qmp_response = controller.qom_get(arguments.path, arguments.property);
+                id
+            }
+            QmpCommand::dump_guest_memory { arguments, id } => {
+                qmp_response = controller.dump_guest_memory(
+                    arguments.protocol,
+                    arguments.paging,
+                    arguments.detach,
+                    arguments.begin,
+                    arguments.length,
+                );
+                id
+            }
+            QmpCommand::x_snapshot_save { arguments, id } => {
+                qmp_response = controller.x_snapshot_save(arguments.path, arguments.paused);
+                id
+            }
+            QmpCommand::migrate { arguments, id } => {
+                qmp_response = controller.migrate(arguments);
+                id
+            }
+            QmpCommand::migrate_incoming { arguments, id } => {
+                qmp_response = controller.migrate_incoming(arguments.uri);
+                id
+            }
+            QmpCommand::migrate_set_parameters { arguments, id } => {
+                qmp_response = controller
+                    .migrate_set_parameters(arguments.max_bandwidth, arguments.downtime_limit);
+                id
+            }
+            QmpCommand::human_monitor_command { arguments, id } => {
+                let output = hmp::execute(controller.as_ref(), &arguments.command_line);
+                qmp_response = Response::create_response(Value::String(output), None);
                 id
             }
             _ => None,
@@ -403,204 +701,1047 @@ fn qmp_command_exec(
     (serde_json::to_string(&qmp_response).unwrap(), shutdown_flag)
 }
 
-/// The struct `QmpChannel` is the only struct can handle Global variable
-/// `QMP_CHANNEL`.
-/// It is used to send event to qmp client and restore some file descriptor
-/// which was sended by client.
-pub struct QmpChannel {
+/// Ring buffer of serialized events, the oldest is dropped on overflow. It
+/// buffers events emitted while no client has negotiated capabilities, and
+/// queues events emitted but not sent by main loop yet.
+struct EventBuffer {
+    events: VecDeque<String>,
+    depth: usize,
+    dropped: u64,
+}
+
+impl EventBuffer {
+    fn new(depth: usize) -> Self {
+        EventBuffer {
+            events: VecDeque::new(),
+            depth,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, event: String) {
+        if self.depth == 0 {
+            self.dropped += 1;
+            return;
+        }
+        while self.events.len() >= self.depth {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back(event);
+    }
+
+    fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.events.len() > depth {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+    }
+}
+
+/// Write one line to `writer` at once, so that it's never interleaved with
+/// other messages.
+fn write_line(writer: &mut SocketRWHandler, line: &str) -> std::io::Result<()> {
+    writer.flush()?;
+    writer.write_all(format!("{}\n", line).as_bytes())
+}
+
+/// File descriptor restored by `getfd`.
+struct StashedFd {
+    fd: RawFd,
+    /// The device which the fd is handed off to, it closes the fd.
+    owner: Option<String>,
+}
+
+/// State of a QMP monitor: the writer of the client bound, the negotiation
+/// state, events queued for main loop, events buffered before negotiation
+/// and file descriptors restored by `getfd`.
+///
+/// The global one is accessed by `QmpChannel`, others can be owned and
+/// injected into `Socket` and `handle_qmp`, such as by tests or a second
+/// monitor.
+pub struct QmpMonitor {
     /// The `writer` to send `QmpEvent`.
     event_writer: RwLock<Option<SocketRWHandler>>,
+    /// Whether the client bound has negotiated capabilities.
+    negotiated: AtomicBool,
+    /// Events emitted before capabilities are negotiated, replayed after
+    /// negotiation. It's locked after `event_writer`.
+    event_buffer: Mutex<EventBuffer>,
+    /// Events emitted by any thread, sent by main loop. It's locked after
+    /// `event_writer`, and alone by emitters.
+    event_queue: Mutex<EventBuffer>,
+    /// Signaled when an event is queued, monitored by main loop.
+    event_evt: EventFd,
     /// Restore file descriptor received from client.
-    fds: Arc<RwLock<BTreeMap<String, RawFd>>>,
+    fds: RwLock<BTreeMap<String, StashedFd>>,
 }
 
-impl QmpChannel {
-    /// Constructs a `QmpChannel` in global `QMP_CHANNEL`.
-    pub fn object_init() {
-        unsafe {
-            if QMP_CHANNEL.is_none() {
-                QMP_CHANNEL = Some(Arc::new(QmpChannel {
-                    event_writer: RwLock::new(None),
-                    fds: Arc::new(RwLock::new(BTreeMap::new())),
-                }));
-            }
+impl Default for QmpMonitor {
+    fn default() -> Self {
+        QmpMonitor::new()
+    }
+}
+
+impl QmpMonitor {
+    /// Constructs a `QmpMonitor` without client bound.
+    pub fn new() -> Self {
+        QmpMonitor {
+            event_writer: RwLock::new(None),
+            negotiated: AtomicBool::new(false),
+            event_buffer: Mutex::new(EventBuffer::new(DEFAULT_EVENT_BUFFER_DEPTH)),
+            event_queue: Mutex::new(EventBuffer::new(EVENT_QUEUE_DEPTH)),
+            event_evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            fds: RwLock::new(BTreeMap::new()),
         }
     }
 
-    /// Bind a `SocketRWHanler` to `QMP_CHANNEL`.
+    /// Bind a `SocketRWHanler` to the monitor.
     ///
     /// # Arguments
     ///
     /// * `writer` - The `SocketRWHandler` used to communicate with client.
-    pub fn bind_writer(writer: SocketRWHandler) {
-        *Self::inner().event_writer.write().unwrap() = Some(writer);
+    pub fn bind_writer(&self, writer: SocketRWHandler) {
+        *self.event_writer.write().unwrap() = Some(writer);
+        self.negotiated.store(false, Ordering::SeqCst);
     }
 
-    /// Unbind `SocketRWHandler` from `QMP_CHANNEL`.
-    pub fn unbind() {
-        *Self::inner().event_writer.write().unwrap() = None;
+    /// Unbind `SocketRWHandler` from the monitor.
+    pub fn unbind(&self) {
+        *self.event_writer.write().unwrap() = None;
+        self.negotiated.store(false, Ordering::SeqCst);
     }
 
-    /// Check whether a `SocketRWHandler` bind with `QMP_CHANNEL` or not.
-    pub fn is_connected() -> bool {
-        Self::inner().event_writer.read().unwrap().is_some()
+    /// Check whether the client bound has negotiated capabilities, commands
+    /// other than `qmp_capabilities` are refused before negotiation.
+    pub fn is_negotiated(&self) -> bool {
+        self.negotiated.load(Ordering::SeqCst)
     }
 
-    /// Restore extern file descriptor in `QMP_CHANNEL`.
+    /// Set the capabilities negotiation state of the client bound.
     ///
     /// # Arguments
     ///
-    /// * `name` - Name of file descriptor.
-    /// * `fd` - File descriptor sent by client.
-    pub fn set_fd(name: String, fd: RawFd) {
-        Self::inner().fds.write().unwrap().insert(name, fd);
+    /// * `negotiated` - Whether capabilities are negotiated.
+    pub fn set_negotiated(&self, negotiated: bool) {
+        self.negotiated.store(negotiated, Ordering::SeqCst);
     }
 
-    /// Get extern file descriptor restored in `QMP_CHANNEL`.
+    /// Set the max number of events buffered while no client is connected,
+    /// the oldest ones beyond it are dropped.
     ///
     /// # Arguments
     ///
-    /// * `name` - Name of file descriptor.
-    pub fn get_fd(name: &str) -> Option<RawFd> {
-        match Self::inner().fds.read().unwrap().get(name) {
-            Some(fd) => Some(*fd),
-            None => None,
+    /// * `depth` - Max number of buffered events.
+    pub fn set_event_buffer_depth(&self, depth: usize) {
+        self.event_buffer.lock().unwrap().set_depth(depth);
+    }
+
+    /// Get the state of the buffer of events.
+    pub fn event_buffer_info(&self) -> schema::EventBufferInfo {
+        let buffer = self.event_buffer.lock().unwrap();
+        schema::EventBufferInfo {
+            depth: buffer.depth as u64,
+            buffered: buffer.events.len() as u64,
+            dropped: buffer.dropped,
+            queue_dropped: self.event_queue.lock().unwrap().dropped,
         }
     }
 
-    /// Send a `QmpEvent` to client.
+    /// Send the response of command to client.
     ///
     /// # Arguments
     ///
-    /// * `event` - The `QmpEvent` sent to client.
-    #[allow(clippy::unused_io_amount)]
-    pub fn send_event(event: &schema::QmpEvent) {
-        if Self::is_connected() {
-            let event_str = serde_json::to_string(&event).unwrap();
-            let mut writer_unlocked = Self::inner().event_writer.write().unwrap();
-            let writer = writer_unlocked.as_mut().unwrap();
-            writer.flush().unwrap();
-            writer.write(event_str.as_bytes()).unwrap();
-            writer.write(&[b'\n']).unwrap();
-            info!("EVENT: --> {:?}", event);
+    /// * `service` - The `SocketHandler` of the stream.
+    /// * `resp` - The response.
+    /// * `negotiated` - Whether it's the response completing capabilities
+    ///   negotiation, then buffered events are replayed after it.
+    ///
+    /// # Notes
+    ///
+    /// The response is sent with the writer locked, so it's never
+    /// interleaved with events emitted by other threads, and events emitted
+    /// during replay are sent after the replayed ones. It's queued after the
+    /// messages left by short writes of the writer, and the events queued
+    /// for main loop, so the order is kept.
+    ///
+    /// # Errors
+    ///
+    /// The socket is broken.
+    pub fn send_response(
+        &self,
+        service: &mut SocketHandler,
+        resp: &str,
+        negotiated: bool,
+    ) -> std::io::Result<()> {
+        let mut writer_locked = self.event_writer.write().unwrap();
+        self.deliver_events(&mut writer_locked);
+        match writer_locked.as_mut() {
+            Some(writer) => write_line(writer, resp)?,
+            None => service.send_str(resp)?,
+        }
+        if !negotiated {
+            return Ok(());
         }
-    }
 
-    fn inner() -> &'static std::sync::Arc<QmpChannel> {
-        unsafe {
-            match &QMP_CHANNEL {
-                Some(channel) => channel,
-                None => {
-                    panic!("Qmp channel not initialized");
-                }
+        if let Some(writer) = writer_locked.as_mut() {
+            let mut buffer = self.event_buffer.lock().unwrap();
+            while let Some(event) = buffer.events.front() {
+                write_line(writer, event)?;
+                info!("EVENT: --> {} (replayed)", event);
+                buffer.events.pop_front();
             }
         }
+        self.negotiated.store(true, Ordering::SeqCst);
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    extern crate serde_json;
-    use super::*;
-    use std::os::unix::net::{UnixListener, UnixStream};
-
-    #[test]
-    fn test_qmp_greeting_msg() {
-        let greeting_msg = QmpGreeting::create_greeting(1, 0, 4);
 
-        let json_msg = r#"
-            {
-                "QMP":{
-                    "version":{
-                        "qemu":{
-                            "micro": 1,
-                            "minor": 0,
-                            "major": 4
-                        },
-                        "package": ""
-                    },
-                    "capabilities": []
-                }
+    /// Send the messages left by short writes of the writer, called when the
+    /// stream is writable again.
+    ///
+    /// # Errors
+    ///
+    /// The socket is broken, then the writer is unbound.
+    pub fn flush_writer(&self) -> std::io::Result<()> {
+        let mut writer_locked = self.event_writer.write().unwrap();
+        if let Some(writer) = writer_locked.as_mut() {
+            if let Err(e) = writer.write_pending() {
+                *writer_locked = None;
+                self.negotiated.store(false, Ordering::SeqCst);
+                return Err(e);
             }
-        "#;
-        let greeting_from_json: QmpGreeting = serde_json::from_str(json_msg).unwrap();
-
-        assert_eq!(greeting_from_json, greeting_msg);
+        }
+        Ok(())
     }
 
-    #[test]
-    fn test_qmp_resp() {
-        // 1.Empty response and ID change;
-        let mut resp = Response::create_empty_response();
-        resp.change_id(Some(0));
-
-        let json_msg = r#"{"return":{},"id":0}"#;
-        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
-
-        resp.change_id(Some(1));
-        let json_msg = r#"{"return":{},"id":1}"#;
-        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
-
-        // 2.Normal response
-        let resp_value = schema::StatusInfo {
-            singlestep: false,
-            running: true,
-            status: schema::RunState::running,
-        };
-        let resp = Response::create_response(serde_json::to_value(&resp_value).unwrap(), None);
-
-        let json_msg = r#"{"return":{"running":true,"singlestep":false,"status":"running"}}"#;
-        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
-
-        // 3.Error response
-        let qmp_err =
-            schema::QmpErrorClass::GenericError("Invalid Qmp command arguments!".to_string());
-        let resp = Response::create_error_response(qmp_err, None).unwrap();
+    /// Check whether some messages are left by short writes of the writer,
+    /// the stream should be monitored for writable until they're sent.
+    pub fn has_pending_output(&self) -> bool {
+        matches!(
+            self.event_writer.read().unwrap().as_ref(),
+            Some(writer) if writer.pending_len() > 0
+        )
+    }
 
-        let json_msg =
-            r#"{"error":{"class":"GenericError","desc":"Invalid Qmp command arguments!"}}"#;
-        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+    /// Check whether a `SocketRWHandler` bind with the monitor or not.
+    pub fn is_connected(&self) -> bool {
+        self.event_writer.read().unwrap().is_some()
     }
 
-    #[test]
-    fn test_qmp_event_msg() {
-        let event_json =
-            r#"{"event":"STOP","data":{},"timestamp":{"seconds":1575531524,"microseconds":91519}}"#;
-        let qmp_event: schema::QmpEvent = serde_json::from_str(&event_json).unwrap();
-        match qmp_event {
-            schema::QmpEvent::STOP {
-                data: _,
-                timestamp: _,
-            } => {
-                assert!(true);
+    /// Restore extern file descriptor in the monitor, the one restored with
+    /// the same name before is closed unless it's handed off to device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of file descriptor.
+    /// * `fd` - File descriptor sent by client.
+    pub fn set_fd(&self, name: String, fd: RawFd) {
+        let stashed = StashedFd { fd, owner: None };
+        if let Some(old) = self.fds.write().unwrap().insert(name, stashed) {
+            if old.fd != fd && old.owner.is_none() {
+                // It's safe because the fd is owned by the monitor.
+                unsafe { libc::close(old.fd) };
             }
-            _ => assert!(false),
         }
     }
 
-    // Environment Preparation for UnixSocket
-    fn prepare_unix_socket_environment(socket_id: &str) -> (UnixListener, UnixStream, UnixStream) {
-        let socket_name: String = format!("test_{}.sock", socket_id);
-        let _ = std::fs::remove_file(&socket_name);
+    /// Restore a group of extern file descriptors sent in one message, they
+    /// are named `name`, `name#1`, `name#2`... in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the group.
+    /// * `fds` - File descriptors sent by client.
+    pub fn set_fds(&self, name: &str, fds: Vec<RawFd>) -> Vec<String> {
+        let mut names = Vec::new();
+        for (index, fd) in fds.into_iter().enumerate() {
+            let fd_name = if index == 0 {
+                name.to_string()
+            } else {
+                format!("{}#{}", name, index)
+            };
+            self.set_fd(fd_name.clone(), fd);
+            names.push(fd_name);
+        }
+        names
+    }
 
-        let listener = UnixListener::bind(&socket_name).unwrap();
-        let client = UnixStream::connect(&socket_name).unwrap();
-        let (server, _) = listener.accept().unwrap();
-        (listener, client, server)
+    /// Get extern file descriptor restored in the monitor.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of file descriptor.
+    pub fn get_fd(&self, name: &str) -> Option<RawFd> {
+        match self.fds.read().unwrap().get(name) {
+            Some(stashed) => Some(stashed.fd),
+            None => None,
+        }
     }
 
-    // Environment Recovery for UnixSocket
-    fn recover_unix_socket_environment(socket_id: &str) {
-        let socket_name: String = format!("test_{}.sock", socket_id);
-        std::fs::remove_file(&socket_name).unwrap();
+    /// Mark the extern file descriptor handed off to a device, which closes
+    /// it, so that it can't be closed by `closefd`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of file descriptor, it's ignored if not restored.
+    /// * `owner` - Id of the device.
+    pub fn claim_fd(&self, name: &str, owner: &str) {
+        if let Some(stashed) = self.fds.write().unwrap().get_mut(name) {
+            stashed.owner = Some(owner.to_string());
+        }
+    }
+
+    /// Remove the extern file descriptor from the monitor and close it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of file descriptor.
+    ///
+    /// # Errors
+    ///
+    /// The file descriptor is not found, or it's handed off to a device.
+    pub fn close_fd(&self, name: &str) -> Result<()> {
+        let mut fds = self.fds.write().unwrap();
+        match fds.get(name) {
+            None => bail!("File descriptor named '{}' not found", name),
+            Some(StashedFd {
+                owner: Some(owner), ..
+            }) => bail!(
+                "File descriptor named '{}' is in use by device {}",
+                name,
+                owner
+            ),
+            Some(_) => {
+                let stashed = fds.remove(name).unwrap();
+                // It's safe because the fd is owned by the monitor.
+                unsafe { libc::close(stashed.fd) };
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolve colon separated file descriptors, such as `fds` of
+    /// `netdev_add`. Each of them is a name restored by `getfd`, or a fd
+    /// number.
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - Colon separated names or numbers of file descriptors.
+    ///
+    /// # Errors
+    ///
+    /// Any of them is neither restored nor a number.
+    pub fn get_fds(&self, names: &str) -> Result<Vec<RawFd>> {
+        let mut fds = Vec::new();
+        for name in names.split(':') {
+            let fd = match self.get_fd(name) {
+                Some(fd) => fd,
+                None => name
+                    .parse::<RawFd>()
+                    .map_err(|_| format!("Failed to convert {} to RawFd", name))?,
+            };
+            fds.push(fd);
+        }
+        Ok(fds)
+    }
+
+    /// Send a `QmpEvent` to client.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The `QmpEvent` sent to client.
+    ///
+    /// # Notes
+    ///
+    /// The event is queued and sent by main loop, so the emitting thread,
+    /// such as a vcpu thread, is never stalled by the client. If the queue
+    /// is full, the oldest event in it is dropped.
+    pub fn send_event(&self, event: &schema::QmpEvent) {
+        let event_str = serde_json::to_string(&event).unwrap();
+        self.event_queue.lock().unwrap().push(event_str);
+        if let Err(e) = self.event_evt.write(1) {
+            error!("Failed to notify event {:?}: {}", event, e);
+        }
+    }
+
+    /// Get the eventfd signaled when an event is queued, main loop calls
+    /// `flush_events` on it.
+    pub fn event_fd(&self) -> RawFd {
+        self.event_evt.as_raw_fd()
+    }
+
+    /// Send the events queued by `send_event`, called by main loop.
+    ///
+    /// # Notes
+    ///
+    /// If no client has negotiated capabilities, the events are buffered and
+    /// replayed after negotiation. If the writer is broken, it's unbound and
+    /// the events are buffered.
+    pub fn flush_events(&self) {
+        // The counter is reset, the queue is drained anyway.
+        let _ = self.event_evt.read();
+        let mut writer_locked = self.event_writer.write().unwrap();
+        self.deliver_events(&mut writer_locked);
+    }
+
+    fn deliver_events(&self, writer_locked: &mut Option<SocketRWHandler>) {
+        let events: Vec<String> = self.event_queue.lock().unwrap().events.drain(..).collect();
+        for event in events {
+            if self.negotiated.load(Ordering::SeqCst) {
+                if let Some(writer) = writer_locked.as_mut() {
+                    match write_line(writer, &event) {
+                        Ok(_) => {
+                            info!("EVENT: --> {}", event);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("Failed to send event {}: {}, unbind the writer", event, e);
+                            *writer_locked = None;
+                            self.negotiated.store(false, Ordering::SeqCst);
+                        }
+                    }
+                }
+            }
+            self.event_buffer.lock().unwrap().push(event);
+        }
+    }
+}
+
+/// The struct `QmpChannel` is the only struct can handle Global variable
+/// `QMP_CHANNEL`, the `QmpMonitor` of api-channel.
+/// It is used to send event to qmp client and restore some file descriptor
+/// which was sended by client.
+pub struct QmpChannel;
+
+impl QmpChannel {
+    /// Constructs the `QmpMonitor` in global `QMP_CHANNEL`, it's also
+    /// constructed by the first access.
+    pub fn object_init() {
+        lazy_static::initialize(&QMP_CHANNEL);
+    }
+
+    /// Bind a `SocketRWHanler` to `QMP_CHANNEL`.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The `SocketRWHandler` used to communicate with client.
+    pub fn bind_writer(writer: SocketRWHandler) {
+        QMP_CHANNEL.bind_writer(writer);
+    }
+
+    /// Unbind `SocketRWHandler` from `QMP_CHANNEL`.
+    pub fn unbind() {
+        QMP_CHANNEL.unbind();
+    }
+
+    /// Check whether the client bound has negotiated capabilities.
+    pub fn is_negotiated() -> bool {
+        QMP_CHANNEL.is_negotiated()
+    }
+
+    /// Set the capabilities negotiation state of the client bound.
+    pub fn set_negotiated(negotiated: bool) {
+        QMP_CHANNEL.set_negotiated(negotiated);
+    }
+
+    /// Set the max number of events buffered while no client is connected.
+    pub fn set_event_buffer_depth(depth: usize) {
+        QMP_CHANNEL.set_event_buffer_depth(depth);
+    }
+
+    /// Get the state of the buffer of events.
+    pub fn event_buffer_info() -> schema::EventBufferInfo {
+        QMP_CHANNEL.event_buffer_info()
+    }
+
+    /// Send the response of command to client, see
+    /// `QmpMonitor::send_response`.
+    pub fn send_response(
+        service: &mut SocketHandler,
+        resp: &str,
+        negotiated: bool,
+    ) -> std::io::Result<()> {
+        QMP_CHANNEL.send_response(service, resp, negotiated)
+    }
+
+    /// Send the messages left by short writes of the writer.
+    pub fn flush_writer() -> std::io::Result<()> {
+        QMP_CHANNEL.flush_writer()
+    }
+
+    /// Check whether some messages are left by short writes of the writer.
+    pub fn has_pending_output() -> bool {
+        QMP_CHANNEL.has_pending_output()
+    }
+
+    /// Check whether a `SocketRWHandler` bind with `QMP_CHANNEL` or not.
+    pub fn is_connected() -> bool {
+        QMP_CHANNEL.is_connected()
+    }
+
+    /// Restore extern file descriptor in `QMP_CHANNEL`.
+    pub fn set_fd(name: String, fd: RawFd) {
+        QMP_CHANNEL.set_fd(name, fd);
+    }
+
+    /// Restore a group of extern file descriptors sent in one message.
+    pub fn set_fds(name: &str, fds: Vec<RawFd>) -> Vec<String> {
+        QMP_CHANNEL.set_fds(name, fds)
+    }
+
+    /// Get extern file descriptor restored in `QMP_CHANNEL`.
+    pub fn get_fd(name: &str) -> Option<RawFd> {
+        QMP_CHANNEL.get_fd(name)
+    }
+
+    /// Mark the extern file descriptor handed off to a device.
+    pub fn claim_fd(name: &str, owner: &str) {
+        QMP_CHANNEL.claim_fd(name, owner);
+    }
+
+    /// Remove the extern file descriptor from `QMP_CHANNEL` and close it.
+    pub fn close_fd(name: &str) -> Result<()> {
+        QMP_CHANNEL.close_fd(name)
+    }
+
+    /// Resolve colon separated file descriptors restored in `QMP_CHANNEL`.
+    pub fn get_fds(names: &str) -> Result<Vec<RawFd>> {
+        QMP_CHANNEL.get_fds(names)
+    }
+
+    /// Send a `QmpEvent` to the client of `QMP_CHANNEL`.
+    pub fn send_event(event: &schema::QmpEvent) {
+        QMP_CHANNEL.send_event(event);
+    }
+
+    /// Send the events queued in `QMP_CHANNEL`.
+    pub fn flush_events() {
+        QMP_CHANNEL.flush_events();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+    use super::*;
+    use crate::machine::KvmVmState;
+    use crate::socket::Socket;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    #[test]
+    fn test_qmp_greeting_msg() {
+        let greeting_msg = QmpGreeting::create_greeting(1, 0, 4);
+
+        let json_msg = r#"
+            {
+                "QMP":{
+                    "version":{
+                        "qemu":{
+                            "micro": 1,
+                            "minor": 0,
+                            "major": 4
+                        },
+                        "package": ""
+                    },
+                    "capabilities": []
+                }
+            }
+        "#;
+        let greeting_from_json: QmpGreeting = serde_json::from_str(json_msg).unwrap();
+
+        assert_eq!(greeting_from_json, greeting_msg);
+    }
+
+    #[test]
+    fn test_qmp_resp() {
+        // 1.Empty response and ID change;
+        let mut resp = Response::create_empty_response();
+        resp.change_id(Some(Value::from(0)));
+
+        let json_msg = r#"{"return":{},"id":0}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        resp.change_id(Some(Value::from(1)));
+        let json_msg = r#"{"return":{},"id":1}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        // String and object id are echoed back verbatim.
+        resp.change_id(Some(Value::from("req-1")));
+        let json_msg = r#"{"return":{},"id":"req-1"}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        resp.change_id(Some(serde_json::json!({"seq": 2, "tag": ["a"]})));
+        let json_msg = r#"{"return":{},"id":{"seq":2,"tag":["a"]}}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        // 2.Normal response
+        let resp_value = schema::StatusInfo {
+            singlestep: false,
+            running: true,
+            status: schema::RunState::running,
+        };
+        let resp = Response::create_response(serde_json::to_value(&resp_value).unwrap(), None);
+
+        let json_msg = r#"{"return":{"running":true,"singlestep":false,"status":"running"}}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        let resp_value = KvmVmState::Paused.status_info();
+        let resp = Response::create_response(serde_json::to_value(&resp_value).unwrap(), None);
+        let json_msg = r#"{"return":{"running":false,"singlestep":false,"status":"paused"}}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        // 3.Error response
+        let qmp_err =
+            schema::QmpErrorClass::GenericError("Invalid Qmp command arguments!".to_string());
+        let resp = Response::create_error_response(qmp_err, None).unwrap();
+
+        let json_msg =
+            r#"{"error":{"class":"GenericError","desc":"Invalid Qmp command arguments!"}}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        // 4.Error response with id recovered from an invalid request
+        let request = r#"{"execute":"unknown-command","id":{"seq":3}}"#;
+        assert!(serde_json::from_str::<QmpCommand>(request).is_err());
+        let qmp_err = schema::QmpErrorClass::GenericError("Invalid command".to_string());
+        let resp = Response::create_error_response(qmp_err, recover_id(request)).unwrap();
+        let json_msg =
+            r#"{"error":{"class":"GenericError","desc":"Invalid command"},"id":{"seq":3}}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+        assert_eq!(recover_id(r#"{"execute":"stop""#), None);
+        assert_eq!(recover_id(r#"["id"]"#), None);
+    }
+
+    /// Get the variant names listed in serde's unknown variant error.
+    fn expected_variants(err: &str) -> Vec<&str> {
+        err.split("expected one of ")
+            .nth(1)
+            .unwrap()
+            .split(", ")
+            .map(|name| name.trim_matches(|c: char| c == '`' || c.is_whitespace()))
+            .map(|name| name.split('`').next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_blockdev_add_round_trip() {
+        let requests = [
+            r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/path/to/block"}}}"#,
+            r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/path/to/block"},"cache":{"direct":false,"writeback":false,"no-flush":true},"read-only":true,"aio":"threads","discard":"unmap","detect-zeroes":"unmap"}}"#,
+            r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/path/to/block"},"cache":{},"aio":"io_uring","discard":"ignore","detect-zeroes":"on"}}"#,
+        ];
+        for request in requests.iter() {
+            let command: QmpCommand = serde_json::from_str(request).unwrap();
+            let value = serde_json::to_value(&command).unwrap();
+            let parsed: QmpCommand = serde_json::from_value(value.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+
+            // Options set are serialized as they're sent.
+            let request: Value = serde_json::from_str(request).unwrap();
+            for key in ["aio", "discard", "detect-zeroes", "read-only"].iter() {
+                if let Some(arg) = request["arguments"].get(key) {
+                    assert_eq!(value["arguments"][key], *arg);
+                }
+            }
+        }
+
+        let request = r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/path/to/block"},"cache":{"writeback":false},"aio":"threads"}}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::blockdev_add { arguments, .. } => {
+                let cache = arguments.cache.unwrap();
+                assert_eq!(cache.writeback, Some(false));
+                assert_eq!(cache.direct, None);
+                assert_eq!(arguments.aio, Some(schema::BlockdevAioOptions::threads));
+                assert_eq!(arguments.detect_zeroes, None);
+            }
+            _ => panic!("blockdev-add is parsed as other command"),
+        }
+    }
+
+    #[test]
+    fn test_qmp_commands() {
+        // The error of unknown command lists all the variants of `QmpCommand`.
+        let err = serde_json::from_str::<QmpCommand>(r#"{"execute":"unknown"}"#)
+            .err()
+            .unwrap()
+            .to_string();
+        let mut variants = expected_variants(&err);
+        let mut names = schema::QMP_COMMANDS.to_vec();
+        variants.sort();
+        names.sort();
+        assert_eq!(variants, names);
+
+        let resp = Response::create_response(serde_json::to_value(commands()).unwrap(), None);
+        let json_msg = serde_json::to_string(&resp).unwrap();
+        assert!(json_msg.starts_with(r#"{"return":[{"name":"qmp_capabilities"},{"name":"quit"},"#));
+        assert!(json_msg.contains(r#"{"name":"query-commands"}"#));
+    }
+
+    #[test]
+    fn test_qmp_events() {
+        // The error of unknown event lists all the variants of `QmpEvent`.
+        let err = serde_json::from_str::<schema::QmpEvent>(r#"{"event":"UNKNOWN"}"#)
+            .err()
+            .unwrap()
+            .to_string();
+        let resp = Response::create_response(serde_json::to_value(events()).unwrap(), None);
+        let resp: Value = serde_json::from_str(&serde_json::to_string(&resp).unwrap()).unwrap();
+        let names: Vec<&str> = resp["return"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|info| info["name"].as_str().unwrap())
+            .collect();
+        let variants = expected_variants(&err);
+        for variant in variants.iter() {
+            assert_eq!(names.iter().filter(|name| *name == variant).count(), 1);
+        }
+        assert_eq!(names.len(), variants.len());
+    }
+
+    #[test]
+    fn test_qmp_name() {
+        let name_info = schema::NameInfo {
+            name: Some("guest-1".to_string()),
+        };
+        let resp = Response::create_response(serde_json::to_value(&name_info).unwrap(), None);
+        let json_msg = r#"{"return":{"name":"guest-1"}}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        // Unset name is answered with empty object.
+        let name_info = schema::NameInfo::default();
+        let resp = Response::create_response(serde_json::to_value(&name_info).unwrap(), None);
+        let json_msg = r#"{"return":{}}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+    }
+
+    #[test]
+    fn test_qmp_uuid() {
+        let uuid_info = schema::UuidInfo {
+            uuid: "550e8400-e29b-41d4-a716-446655440000".to_string(),
+        };
+        let mut resp = Response::create_response(serde_json::to_value(&uuid_info).unwrap(), None);
+        resp.change_id(Some(Value::from(3)));
+        let json_msg = r#"{"return":{"UUID":"550e8400-e29b-41d4-a716-446655440000"},"id":3}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+    }
+
+    #[test]
+    fn test_qmp_machines() {
+        let machine_info = schema::MachineInfo {
+            name: "microvm".to_string(),
+            alias: None,
+            is_default: Some(true),
+            cpu_max: 254,
+            hotpluggable_cpus: false,
+        };
+        let resp =
+            Response::create_response(serde_json::to_value(vec![machine_info]).unwrap(), None);
+
+        let json_msg = r#"{"return":[{"cpu-max":254,"hotpluggable-cpus":false,"is-default":true,"name":"microvm"}]}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        let request = r#"{"execute":"query-machines","id":"probe"}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::query_machines { id, .. } => assert_eq!(id, Some(Value::from("probe"))),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_qmp_machine_properties() {
+        let props = vec![
+            schema::MachinePropertyInfo {
+                name: "dump-guest-core".to_string(),
+                prop_type: "bool".to_string(),
+                value: "off".to_string(),
+                default: Some("on".to_string()),
+            },
+            schema::MachinePropertyInfo {
+                name: "max-slot-size".to_string(),
+                prop_type: "size".to_string(),
+                value: "1073741824".to_string(),
+                default: None,
+            },
+        ];
+        let resp = Response::create_response(serde_json::to_value(props).unwrap(), None);
+        let json_msg = r#"{"return":[{"default":"on","name":"dump-guest-core","type":"bool","value":"off"},{"name":"max-slot-size","type":"size","value":"1073741824"}]}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        let request = r#"{"execute":"query-machine-properties"}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::query_machine_properties { .. } => (),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_qmp_boot_times() {
+        let boot_times = vec![
+            schema::BootTimeInfo {
+                name: "kvm-vm-created".to_string(),
+                time_ms: 0.412,
+                delta_ms: 0.412,
+            },
+            schema::BootTimeInfo {
+                name: "guest-ready".to_string(),
+                time_ms: 125.5,
+                delta_ms: 125.088,
+            },
+        ];
+        let resp = Response::create_response(serde_json::to_value(boot_times).unwrap(), None);
+        let json_msg = r#"{"return":[{"delta-ms":0.412,"name":"kvm-vm-created","time-ms":0.412},{"delta-ms":125.088,"name":"guest-ready","time-ms":125.5}]}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        let request = r#"{"execute":"query-boot-times","id":1}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::query_boot_times { id, .. } => assert_eq!(id, Some(Value::from(1))),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_qmp_kvm_caps() {
+        let caps = vec![
+            schema::KvmCapInfo {
+                name: "KVM_CAP_IRQFD".to_string(),
+                required: true,
+                supported: true,
+            },
+            schema::KvmCapInfo {
+                name: "KVM_CAP_READONLY_MEM".to_string(),
+                required: false,
+                supported: false,
+            },
+        ];
+        let resp = Response::create_response(serde_json::to_value(caps).unwrap(), None);
+        let json_msg = r#"{"return":[{"name":"KVM_CAP_IRQFD","required":true,"supported":true},{"name":"KVM_CAP_READONLY_MEM","required":false,"supported":false}]}"#;
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+
+        let request = r#"{"execute":"query-kvm-caps"}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::query_kvm_caps { .. } => (),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_qmp_version() {
+        let version = serde_json::to_string(&version_info()).unwrap();
+        let json_msg = format!(
+            r#"{{"qemu":{{"micro":1,"minor":0,"major":4}},"package":"StratoVirt-{}"}}"#,
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(version, json_msg);
+
+        let mut resp =
+            Response::create_response(serde_json::to_value(version_info()).unwrap(), None);
+        resp.change_id(Some(Value::from(5)));
+        let json_msg = format!(
+            r#"{{"return":{{"package":"StratoVirt-{}","qemu":{{"major":4,"micro":1,"minor":0}}}},"id":5}}"#,
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(serde_json::to_string(&resp).unwrap(), json_msg);
+    }
+
+    #[test]
+    fn test_qmp_request_id() {
+        let request = r#"{"execute":"stop","id":"req-1"}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::stop { id, .. } => assert_eq!(id, Some(Value::from("req-1"))),
+            _ => assert!(false),
+        }
+
+        let request = r#"{"execute":"query-status","id":{"seq":1,"client":"kata"}}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::query_status { id, .. } => {
+                assert_eq!(id, Some(serde_json::json!({"seq": 1, "client": "kata"})))
+            }
+            _ => assert!(false),
+        }
+
+        let request = r#"{"execute":"qmp_capabilities","id":7}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::qmp_capabilities { id, .. } => assert_eq!(id, Some(Value::from(7))),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_qmp_powerdown_event() {
+        let event = schema::QmpEvent::POWERDOWN {
+            data: Default::default(),
+            timestamp: create_timestamp(),
+        };
+        let event_json = serde_json::to_string(&event).unwrap();
+        assert!(event_json.starts_with(r#"{"event":"POWERDOWN","data":{},"timestamp":"#));
+
+        let request = r#"{"execute":"system_powerdown","id":"p1"}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::system_powerdown { id, .. } => assert_eq!(id, Some(Value::from("p1"))),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_qmp_lifecycle_events() {
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        let events = vec![
+            schema::QmpEvent::POWERDOWN {
+                data: Default::default(),
+                timestamp: create_timestamp(),
+            },
+            schema::QmpEvent::RESET {
+                data: schema::RESET { guest: true },
+                timestamp: create_timestamp(),
+            },
+        ];
+        for (event, name) in events.iter().zip(["POWERDOWN", "RESET"].iter()) {
+            let event: Value = serde_json::to_value(event).unwrap();
+            assert_eq!(event["event"], Value::from(*name));
+            assert!(event["data"].is_object());
+            assert!(event["timestamp"]["seconds"].as_u64().unwrap() > 0);
+            assert!(event["timestamp"]["microseconds"].is_u64());
+        }
+
+        // Sending events without client connected is harmless.
+        event!(POWERDOWN);
+        event!(RESET; schema::RESET { guest: false });
+        QmpChannel::flush_events();
+    }
+
+    #[test]
+    fn test_qmp_rtc_change_event() {
+        let event = schema::QmpEvent::RTC_CHANGE {
+            data: schema::RTC_CHANGE { offset: -3600 },
+            timestamp: create_timestamp(),
+        };
+        let event_json = serde_json::to_string(&event).unwrap();
+        assert!(
+            event_json.starts_with(r#"{"event":"RTC_CHANGE","data":{"offset":-3600},"timestamp":"#)
+        );
+    }
+
+    #[test]
+    fn test_qmp_migration() {
+        use crate::machine::DeviceInterface;
+
+        let event = schema::QmpEvent::MIGRATION {
+            data: schema::MIGRATION {
+                status: "active".to_string(),
+            },
+            timestamp: create_timestamp(),
+        };
+        let event_json = serde_json::to_string(&event).unwrap();
+        assert!(event_json
+            .starts_with(r#"{"event":"MIGRATION","data":{"status":"active"},"timestamp":"#));
+
+        // Fields not given are omitted, as Qemu does.
+        let resp = serde_json::to_value(TestMachine.query_migrate()).unwrap();
+        assert_eq!(
+            resp,
+            serde_json::json!({
+                "return": { "status": "completed", "total-time": 1250, "downtime": 40 }
+            })
+        );
+        let info = schema::MigrationInfo {
+            status: Some("active".to_string()),
+            ram: Some(schema::MigrationStats {
+                transferred: 4096,
+                remaining: 8192,
+                total: 1 << 30,
+                dirty_pages_rate: 20,
+                dirty_sync_count: 2,
+            }),
+            total_time: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(info).unwrap(),
+            serde_json::json!({
+                "status": "active",
+                "ram": {
+                    "transferred": 4096,
+                    "remaining": 8192,
+                    "total": 1 << 30,
+                    "dirty-pages-rate": 20,
+                    "dirty-sync-count": 2
+                },
+                "total-time": 10
+            })
+        );
+        let info = schema::MigrationInfo::default();
+        assert_eq!(serde_json::to_string(&info).unwrap(), "{}");
+
+        // Arguments sent by libvirt are accepted.
+        let request = r#"{"execute":"migrate","arguments":{"detach":true,"blk":false,"inc":false,"uri":"tcp:10.0.0.2:4444"}}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::migrate { arguments, .. } => {
+                assert_eq!(arguments.uri, "tcp:10.0.0.2:4444");
+                assert_eq!(arguments.blk, Some(false));
+            }
+            _ => panic!("Failed to parse migrate"),
+        }
+        let request = r#"{"execute":"migrate-set-parameters","arguments":{"max-bandwidth":1024,"downtime-limit":500}}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::migrate_set_parameters { arguments, .. } => {
+                assert_eq!(arguments.max_bandwidth, Some(1024));
+                assert_eq!(arguments.downtime_limit, Some(500));
+            }
+            _ => panic!("Failed to parse migrate-set-parameters"),
+        }
+        let request = r#"{"execute":"migrate-incoming","arguments":{"uri":"tcp:0.0.0.0:4444"}}"#;
+        match serde_json::from_str::<QmpCommand>(request).unwrap() {
+            QmpCommand::migrate_incoming { arguments, .. } => {
+                assert_eq!(arguments.uri, "tcp:0.0.0.0:4444");
+            }
+            _ => panic!("Failed to parse migrate-incoming"),
+        }
+    }
+
+    #[test]
+    fn test_qmp_event_msg() {
+        let event_json =
+            r#"{"event":"STOP","data":{},"timestamp":{"seconds":1575531524,"microseconds":91519}}"#;
+        let qmp_event: schema::QmpEvent = serde_json::from_str(&event_json).unwrap();
+        match qmp_event {
+            schema::QmpEvent::STOP {
+                data: _,
+                timestamp: _,
+            } => {
+                assert!(true);
+            }
+            _ => assert!(false),
+        }
+    }
+
+    // Tests binding writer to the global `QMP_CHANNEL` run one by one.
+    static QMP_CHANNEL_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    // Environment Preparation for UnixSocket
+    fn prepare_unix_socket_environment(socket_id: &str) -> (UnixListener, UnixStream, UnixStream) {
+        let socket_name: String = format!("test_{}.sock", socket_id);
+        let _ = std::fs::remove_file(&socket_name);
+
+        let listener = UnixListener::bind(&socket_name).unwrap();
+        let client = UnixStream::connect(&socket_name).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (listener, client, server)
+    }
+
+    // Environment Recovery for UnixSocket
+    fn recover_unix_socket_environment(socket_id: &str) {
+        let socket_name: String = format!("test_{}.sock", socket_id);
+        std::fs::remove_file(&socket_name).unwrap();
     }
 
     #[test]
     fn test_qmp_event_macro() {
-        use crate::socket::{Socket, SocketRWHandler};
+        use crate::socket::SocketRWHandler;
         use std::io::Read;
 
         // Pre test. Environment preparation
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
         QmpChannel::object_init();
         let mut buffer = [0u8; 200];
         let (listener, mut client, server) = prepare_unix_socket_environment("06");
@@ -609,9 +1750,11 @@ mod tests {
         let socket = Socket::from_unix_listener(listener, None);
         socket.bind_unix_stream(server);
         QmpChannel::bind_writer(SocketRWHandler::new(socket.get_stream_fd()));
+        QmpChannel::set_negotiated(true);
 
         // 1.send no-content event
         event!(STOP);
+        QmpChannel::flush_events();
         let length = client.read(&mut buffer).unwrap();
         let qmp_event: schema::QmpEvent =
             serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
@@ -631,6 +1774,7 @@ mod tests {
             reason: "guest-shutdown".to_string(),
         };
         event!(SHUTDOWN; shutdown_event);
+        QmpChannel::flush_events();
         let length = client.read(&mut buffer).unwrap();
         let qmp_event: schema::QmpEvent =
             serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
@@ -643,15 +1787,147 @@ mod tests {
         }
 
         // After test. Environment Recover
+        QmpChannel::unbind();
         recover_unix_socket_environment("06");
     }
 
+    #[test]
+    fn test_qmp_monitor_concurrency() {
+        use crate::socket::SocketRWHandler;
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+        const THREADS: usize = 8;
+        const ITERATIONS: usize = 100;
+
+        // The global one is constructed once by racing threads.
+        let globals: Vec<usize> = (0..THREADS)
+            .map(|_| std::thread::spawn(|| QMP_CHANNEL.as_ref() as *const QmpMonitor as usize))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert!(globals.iter().all(|ptr| *ptr == globals[0]));
+
+        // Owned monitors are independent of the global one.
+        let monitor = Arc::new(QmpMonitor::new());
+        let (client, server) = UnixStream::pair().unwrap();
+        monitor.bind_writer(SocketRWHandler::new(server.as_raw_fd()));
+        monitor.set_negotiated(true);
+        let reader = std::thread::spawn(move || {
+            let mut lines = BufReader::new(client).lines();
+            for _ in 0..THREADS * ITERATIONS {
+                let line = lines.next().unwrap().unwrap();
+                let event: Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(event["event"], Value::from("STOP"));
+            }
+        });
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|index| {
+                let monitor = monitor.clone();
+                std::thread::spawn(move || {
+                    for i in 0..ITERATIONS {
+                        let name = format!("fd-{}-{}", index, i % 4);
+                        let fd = std::fs::File::open("/dev/null").unwrap().into_raw_fd();
+                        monitor.set_fd(name.clone(), fd);
+                        assert_eq!(monitor.get_fd(&name), Some(fd));
+                        monitor.send_event(&schema::QmpEvent::STOP {
+                            data: Default::default(),
+                            timestamp: create_timestamp(),
+                        });
+                        monitor.flush_events();
+                    }
+                    for i in 0..4 {
+                        monitor.close_fd(&format!("fd-{}-{}", index, i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        while monitor.has_pending_output() {
+            monitor.flush_writer().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // Every event is sent as a whole line, and none is buffered.
+        reader.join().unwrap();
+        assert_eq!(monitor.event_buffer_info().buffered, 0);
+        assert!(monitor.fds.read().unwrap().is_empty());
+        assert!(monitor.get_fd("fd-0-0").is_none());
+    }
+
+    #[test]
+    fn test_qmp_event_queue() {
+        use crate::socket::SocketRWHandler;
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::io::AsRawFd;
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        // No lock is needed since the global one is not touched.
+        let monitor = Arc::new(QmpMonitor::new());
+        let (client, server) = UnixStream::pair().unwrap();
+        monitor.bind_writer(SocketRWHandler::new(server.as_raw_fd()));
+        monitor.set_negotiated(true);
+
+        // The client doesn't read, and main loop is stuck with the writer
+        // locked, the emitting thread is not stalled by them.
+        let writer_locked = monitor.event_writer.write().unwrap();
+        let (done_tx, done_rx) = channel();
+        let emitter = {
+            let monitor = monitor.clone();
+            std::thread::spawn(move || {
+                for _ in 0..EVENT_QUEUE_DEPTH + 2 {
+                    monitor.send_event(&schema::QmpEvent::STOP {
+                        data: Default::default(),
+                        timestamp: create_timestamp(),
+                    });
+                }
+                done_tx.send(()).unwrap();
+            })
+        };
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        emitter.join().unwrap();
+        drop(writer_locked);
+
+        // The oldest events are dropped once the queue is full.
+        monitor.send_event(&schema::QmpEvent::RESUME {
+            data: Default::default(),
+            timestamp: create_timestamp(),
+        });
+        assert_eq!(monitor.event_buffer_info().queue_dropped, 3);
+        assert_eq!(
+            monitor.event_evt.read().unwrap(),
+            EVENT_QUEUE_DEPTH as u64 + 3
+        );
+
+        // Main loop sends the queued events in order.
+        monitor.flush_events();
+        assert_eq!(monitor.event_queue.lock().unwrap().events.len(), 0);
+        let mut lines = BufReader::new(client).lines();
+        for index in 0..EVENT_QUEUE_DEPTH {
+            let event: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            let name = if index + 1 == EVENT_QUEUE_DEPTH {
+                "RESUME"
+            } else {
+                "STOP"
+            };
+            assert_eq!(event["event"], Value::from(name));
+        }
+        assert_eq!(monitor.event_buffer_info().buffered, 0);
+    }
+
     #[test]
     fn test_qmp_send_response() {
-        use crate::socket::Socket;
         use std::io::Read;
 
         // Pre test. Environment preparation
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QmpChannel::unbind();
         let mut buffer = [0u8; 300];
         let (listener, mut client, server) = prepare_unix_socket_environment("07");
 
@@ -680,6 +1956,859 @@ mod tests {
         drop(socket);
     }
 
+    #[test]
+    fn test_qmp_output_order() {
+        use crate::socket::{SocketHandler, SocketRWHandler};
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        let (mut client, server) = UnixStream::pair().unwrap();
+        // Shrink the buffers, so that the socket is full soon.
+        let size: libc::c_int = 4096;
+        for (fd, option) in [
+            (server.as_raw_fd(), libc::SO_SNDBUF),
+            (client.as_raw_fd(), libc::SO_RCVBUF),
+        ]
+        .iter()
+        {
+            let ret = unsafe {
+                libc::setsockopt(
+                    *fd,
+                    libc::SOL_SOCKET,
+                    *option,
+                    &size as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            assert_eq!(ret, 0);
+        }
+        QmpChannel::bind_writer(SocketRWHandler::new(server.as_raw_fd()));
+        QmpChannel::set_negotiated(true);
+
+        // Events are queued once the socket is full, the response is queued
+        // after them.
+        let mut events = 0;
+        while !QmpChannel::has_pending_output() {
+            event!(STOP);
+            QmpChannel::flush_events();
+            events += 1;
+        }
+        event!(RESUME);
+        QmpChannel::flush_events();
+        let mut service = SocketHandler::new(server.as_raw_fd());
+        QmpChannel::send_response(&mut service, r#"{"return":{}}"#, false).unwrap();
+
+        // The queue is sent as the client reads, without any line broken.
+        client.set_nonblocking(true).unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            QmpChannel::flush_writer().unwrap();
+            match client.read(&mut buffer) {
+                Ok(length) => received.extend_from_slice(&buffer[..length]),
+                Err(_) if !QmpChannel::has_pending_output() => break,
+                Err(_) => {}
+            }
+        }
+        let received = String::from_utf8(received).unwrap();
+        let lines: Vec<&str> = received.lines().collect();
+        assert_eq!(lines.len(), events + 2);
+        for line in lines[..events].iter() {
+            assert!(line.starts_with(r#"{"event":"STOP","#));
+        }
+        assert!(lines[events].starts_with(r#"{"event":"RESUME","#));
+        assert_eq!(lines[events + 1], r#"{"return":{}}"#);
+
+        // The closed client is reported as broken pipe.
+        drop(client);
+        let err = QmpChannel::send_response(&mut service, r#"{"return":{}}"#, false).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+        QmpChannel::unbind();
+    }
+
+    struct TestMachine;
+
+    impl crate::machine::MachineLifecycle for TestMachine {
+        fn notify_lifecycle(&self, _old: KvmVmState, _new: KvmVmState) -> bool {
+            true
+        }
+    }
+
+    impl crate::machine::DeviceInterface for TestMachine {
+        fn query_status(&self) -> Response {
+            let status = KvmVmState::Running.status_info();
+            Response::create_response(serde_json::to_value(&status).unwrap(), None)
+        }
+
+        fn stop(&self) -> std::result::Result<(), String> {
+            crate::machine::check_transition("stop", KvmVmState::Running, KvmVmState::Paused)
+                .map(|_| ())
+        }
+
+        fn cont(&self) -> std::result::Result<(), String> {
+            crate::machine::check_transition("cont", KvmVmState::Running, KvmVmState::Running)
+                .map(|_| ())
+        }
+
+        fn system_powerdown(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_name(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_uuid(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_cpus(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_hotpluggable_cpus(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_memory_advice(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_machines(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_machine_properties(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_eventloop_stats(&self, _reset: Option<bool>) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_boot_times(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_kvm_caps(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_block(&self) -> Response {
+            let block = schema::BlockInfo {
+                device: "drive-0".to_string(),
+                file: "/path/to/rootfs".to_string(),
+                ..Default::default()
+            };
+            Response::create_response(serde_json::to_value(vec![block]).unwrap(), None)
+        }
+
+        fn query_network(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_mtree(&self) -> Response {
+            let err_resp = schema::QmpErrorClass::GenericError("no memory".to_string());
+            Response::create_error_response(err_resp, None).unwrap()
+        }
+
+        fn qom_list(&self, _path: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn qom_get(&self, _path: String, _property: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn dump_guest_memory(
+            &self,
+            _protocol: String,
+            _paging: bool,
+            _detach: Option<bool>,
+            _begin: Option<u64>,
+            _length: Option<u64>,
+        ) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn x_snapshot_save(&self, _path: String, _paused: Option<bool>) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn migrate(&self, _args: schema::migrate) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn migrate_cancel(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn migrate_incoming(&self, _uri: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_migrate(&self) -> Response {
+            let info = schema::MigrationInfo {
+                status: Some("completed".to_string()),
+                total_time: Some(1250),
+                downtime: Some(40),
+                ..Default::default()
+            };
+            Response::create_response(serde_json::to_value(info).unwrap(), None)
+        }
+
+        fn migrate_set_parameters(
+            &self,
+            _max_bandwidth: Option<u64>,
+            _downtime_limit: Option<u64>,
+        ) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn device_add(&self, _args: schema::device_add) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn device_del(&self, _device_id: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn blockdev_add(&self, _args: schema::blockdev_add) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn netdev_add(
+            &self,
+            _id: String,
+            _if_name: Option<String>,
+            _fds: Option<String>,
+        ) -> std::result::Result<(), String> {
+            Ok(())
+        }
+
+        fn getfd(&self, fd_name: String, fds: Vec<RawFd>) -> Response {
+            QmpChannel::set_fds(&fd_name, fds);
+            Response::create_empty_response()
+        }
+    }
+
+    impl MachineExternalInterface for TestMachine {}
+
+    // Read the lines sent to client, which are all sent when it's called.
+    fn client_read_lines<T: std::io::Read>(client: &mut T) -> Vec<Value> {
+        let mut buffer = [0u8; 1024];
+        let length = client.read(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..length])
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn client_request<T: std::io::Read + Write>(
+        client: &mut T,
+        socket: &Socket,
+        request: &str,
+    ) -> Value {
+        use vmm_sys_util::epoll::EventSet;
+
+        client.write_all(request.as_bytes()).unwrap();
+        assert!(socket.handle_stream_event(EventSet::IN).is_none());
+        client_read_lines(client).remove(0)
+    }
+
+    #[test]
+    fn test_qmp_reconnect() {
+        use std::io::Read;
+        use vmm_sys_util::epoll::EventSet;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QMP_CHANNEL.event_buffer.lock().unwrap().events.clear();
+        let socket_name = "test_10.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let listener = UnixListener::bind(socket_name).unwrap();
+        let socket = Socket::from_unix_listener(listener, Some(Arc::new(TestMachine)));
+
+        for _ in 0..3 {
+            let mut client = UnixStream::connect(socket_name).unwrap();
+            socket.accept();
+            assert!(QmpChannel::is_connected());
+
+            // Every connection is greeted.
+            let mut buffer = [0u8; 1024];
+            let length = client.read(&mut buffer).unwrap();
+            assert!(String::from_utf8_lossy(&buffer[..length]).starts_with(r#"{"QMP":"#));
+
+            // Capabilities must be negotiated again.
+            let request = r#"{"execute":"query-status","id":1}"#;
+            let resp = client_request(&mut client, &socket, request);
+            assert_eq!(resp["error"]["class"], Value::from("CommandNotFound"));
+            assert_eq!(resp["id"], Value::from(1));
+            let resp = client_request(&mut client, &socket, r#"{"execute":"qmp_capabilities"}"#);
+            assert!(resp["return"].is_object());
+            let resp = client_request(&mut client, &socket, request);
+            assert_eq!(resp["return"]["status"], Value::from("running"));
+
+            // The client is gone uncleanly, the connection is cleaned up and
+            // the listener is re-armed.
+            drop(client);
+            let notifiers = socket.handle_stream_event(EventSet::IN).unwrap();
+            assert_eq!(notifiers.len(), 1);
+            assert!(!socket.is_connected());
+            assert!(!QmpChannel::is_connected());
+            assert!(!QmpChannel::is_negotiated());
+
+            // Events are buffered without panic.
+            event!(STOP);
+            QmpChannel::flush_events();
+        }
+
+        QMP_CHANNEL.event_buffer.lock().unwrap().events.clear();
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    #[test]
+    fn test_qmp_event_after_peer_closed() {
+        use vmm_sys_util::epoll::EventSet;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QMP_CHANNEL.event_buffer.lock().unwrap().events.clear();
+        let socket_name = "test_15.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let listener = UnixListener::bind(socket_name).unwrap();
+        let socket = Socket::from_unix_listener(listener, Some(Arc::new(TestMachine)));
+        let mut client = UnixStream::connect(socket_name).unwrap();
+        socket.accept();
+        client_read_lines(&mut client);
+        let resp = client_request(&mut client, &socket, r#"{"execute":"qmp_capabilities"}"#);
+        assert!(resp["return"].is_object());
+
+        // The event hits EPIPE before the stream notifier sees the close, the
+        // writer is unbound at once and the event is buffered.
+        drop(client);
+        event!(STOP);
+        QmpChannel::flush_events();
+        assert!(!QmpChannel::is_connected());
+        assert!(socket.is_connected());
+        assert_eq!(QmpChannel::event_buffer_info().buffered, 1);
+
+        // The stream notifier removes the stream and re-arms the listener.
+        let notifiers = socket
+            .handle_stream_event(EventSet::IN | EventSet::HANG_UP)
+            .unwrap();
+        assert_eq!(notifiers.len(), 1);
+        assert!(!socket.is_connected());
+
+        // The next client gets the buffered event after negotiation.
+        let mut client = UnixStream::connect(socket_name).unwrap();
+        socket.accept();
+        client_read_lines(&mut client);
+        client
+            .write_all(r#"{"execute":"qmp_capabilities"}"#.as_bytes())
+            .unwrap();
+        assert!(socket.handle_stream_event(EventSet::IN).is_none());
+        let lines = client_read_lines(&mut client);
+        assert!(lines[0]["return"].is_object());
+        assert_eq!(lines[1]["event"], Value::from("STOP"));
+        let resp = client_request(&mut client, &socket, r#"{"execute":"query-status"}"#);
+        assert_eq!(resp["return"]["status"], Value::from("running"));
+
+        QmpChannel::unbind();
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    // Send `data` with `fds` in one SCM_RIGHTS message.
+    fn send_with_fds(stream: &UnixStream, data: &str, fds: &[RawFd]) {
+        use libc::{
+            c_void, iovec, msghdr, sendmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_LEN, CMSG_SPACE,
+            SCM_RIGHTS, SOL_SOCKET,
+        };
+        use std::os::unix::io::AsRawFd;
+
+        let fds_len = std::mem::size_of_val(fds) as u32;
+        let cmsg_space = unsafe { CMSG_SPACE(fds_len) } as usize;
+        // Use u64 to align the control message.
+        let mut cmsg_buf = vec![0_u64; cmsg_space / 8 + 1];
+        let mut iov = iovec {
+            iov_base: data.as_ptr() as *mut c_void,
+            iov_len: data.len(),
+        };
+        let mut mhdr: msghdr = unsafe { std::mem::zeroed() };
+        mhdr.msg_iov = &mut iov as *mut iovec;
+        mhdr.msg_iovlen = 1;
+        mhdr.msg_control = cmsg_buf.as_mut_ptr() as *mut c_void;
+        mhdr.msg_controllen = cmsg_space as _;
+        unsafe {
+            let cmsg = CMSG_FIRSTHDR(&mhdr as *const msghdr);
+            (*cmsg).cmsg_level = SOL_SOCKET;
+            (*cmsg).cmsg_type = SCM_RIGHTS;
+            (*cmsg).cmsg_len = CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                CMSG_DATA(cmsg),
+                fds_len as usize,
+            );
+            assert_eq!(
+                sendmsg(stream.as_raw_fd(), &mhdr, 0),
+                data.len() as libc::ssize_t
+            );
+        }
+    }
+
+    #[test]
+    fn test_qmp_getfd_multiple() {
+        use std::os::unix::io::AsRawFd;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QmpChannel::set_negotiated(true);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut service = SocketHandler::new(server.as_raw_fd());
+        let controller: Arc<dyn MachineExternalInterface> = Arc::new(TestMachine);
+
+        // Three fds sent at once are restored with sequential names.
+        let files: Vec<std::fs::File> = (0..3)
+            .map(|_| std::fs::File::open("/dev/null").unwrap())
+            .collect();
+        let fds: Vec<RawFd> = files.iter().map(|file| file.as_raw_fd()).collect();
+        let request = r#"{"execute":"getfd","arguments":{"fdname":"tap"}}"#;
+        send_with_fds(&client, request, &fds);
+        handle_qmp(&mut service, SocketType::Unix, &QMP_CHANNEL, &controller).unwrap();
+        assert!(client_read_lines(&mut client)[0]["return"].is_object());
+        let received = QmpChannel::get_fds("tap:tap#1:tap#2").unwrap();
+        assert_eq!(received.len(), 3);
+        for fd in received.iter() {
+            assert!(!fds.contains(fd));
+            assert!(unsafe { libc::fcntl(*fd, libc::F_GETFD) } >= 0);
+        }
+        assert!(QmpChannel::get_fds("tap:tap#3").is_err());
+        assert_eq!(QmpChannel::get_fds("tap:10").unwrap()[1], 10);
+
+        // Fds sent with other commands are closed, so the write end of pipe
+        // is closed and EOF is read.
+        let mut pipe = [0; 2];
+        assert_eq!(
+            unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_NONBLOCK) },
+            0
+        );
+        send_with_fds(&client, r#"{"execute":"query-status"}"#, &pipe[1..]);
+        unsafe { libc::close(pipe[1]) };
+        handle_qmp(&mut service, SocketType::Unix, &QMP_CHANNEL, &controller).unwrap();
+        let resp = client_read_lines(&mut client);
+        assert_eq!(resp[0]["return"]["status"], Value::from("running"));
+        let mut byte = 0_u8;
+        let ret = unsafe { libc::read(pipe[0], &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        assert_eq!(ret, 0);
+        unsafe { libc::close(pipe[0]) };
+
+        for fd in received {
+            unsafe { libc::close(fd) };
+        }
+        QMP_CHANNEL.fds.write().unwrap().clear();
+        QmpChannel::unbind();
+    }
+
+    #[test]
+    fn test_qmp_pipelined() {
+        use std::os::unix::io::AsRawFd;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QmpChannel::set_negotiated(true);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut service = SocketHandler::new(server.as_raw_fd());
+        let controller: Arc<dyn MachineExternalInterface> = Arc::new(TestMachine);
+        let handle = |service: &mut SocketHandler| {
+            handle_qmp(service, SocketType::Unix, &QMP_CHANNEL, &controller).unwrap()
+        };
+
+        // Three commands in one write get three responses in order.
+        client
+            .write_all(
+                concat!(
+                    r#"{"execute":"query-status","id":1}"#,
+                    "\n",
+                    r#"{"execute":"query-name","id":2}"#,
+                    "\n",
+                    r#"{"execute":"query-status","id":3}"#,
+                    "\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        handle(&mut service);
+        let lines = client_read_lines(&mut client);
+        assert_eq!(lines.len(), 3);
+        for (index, line) in lines.iter().enumerate() {
+            assert_eq!(line["id"], Value::from(index + 1));
+            assert!(line["return"].is_object());
+        }
+        assert_eq!(lines[0]["return"]["status"], Value::from("running"));
+
+        // The partial command is kept until the rest of it is received.
+        client
+            .write_all(br#"{"execute":"query-status","id":4}{"execute":"query-st"#)
+            .unwrap();
+        handle(&mut service);
+        let lines = client_read_lines(&mut client);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["id"], Value::from(4));
+        client.write_all(br#"atus","id":5}"#).unwrap();
+        handle(&mut service);
+        let lines = client_read_lines(&mut client);
+        assert_eq!(lines[0]["id"], Value::from(5));
+        assert_eq!(lines[0]["return"]["status"], Value::from("running"));
+
+        // An invalid command in the batch doesn't affect the others.
+        client
+            .write_all(b"{\"execute\"]\n{\"execute\":\"query-status\",\"id\":7}")
+            .unwrap();
+        handle(&mut service);
+        let lines = client_read_lines(&mut client);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["error"]["class"], Value::from("GenericError"));
+        assert_eq!(lines[1]["id"], Value::from(7));
+
+        // The fd stays with the command it's sent with, not the first one
+        // of the batch.
+        let file = std::fs::File::open("/dev/null").unwrap();
+        client
+            .write_all(br#"{"execute":"query-status","id":8}"#)
+            .unwrap();
+        send_with_fds(
+            &client,
+            r#"{"execute":"getfd","arguments":{"fdname":"fd-batch"},"id":9}"#,
+            &[file.as_raw_fd()],
+        );
+        handle(&mut service);
+        let lines = client_read_lines(&mut client);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["return"]["status"], Value::from("running"));
+        assert!(lines[1]["return"].is_object());
+        assert_eq!(lines[1]["id"], Value::from(9));
+        QmpChannel::close_fd("fd-batch").unwrap();
+
+        QmpChannel::unbind();
+    }
+
+    #[test]
+    fn test_qmp_closefd() {
+        use std::os::unix::io::AsRawFd;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QmpChannel::set_negotiated(true);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut service = SocketHandler::new(server.as_raw_fd());
+        let controller: Arc<dyn MachineExternalInterface> = Arc::new(TestMachine);
+
+        // Close after getfd, the write end of pipe is closed and EOF is read.
+        let mut pipe = [0; 2];
+        assert_eq!(
+            unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_NONBLOCK) },
+            0
+        );
+        let request = r#"{"execute":"getfd","arguments":{"fdname":"fd1"}}"#;
+        send_with_fds(&client, request, &pipe[1..]);
+        unsafe { libc::close(pipe[1]) };
+        handle_qmp(&mut service, SocketType::Unix, &QMP_CHANNEL, &controller).unwrap();
+        assert!(client_read_lines(&mut client)[0]["return"].is_object());
+
+        let request = r#"{"execute":"closefd","arguments":{"fdname":"fd1"},"id":1}"#;
+        client.write_all(request.as_bytes()).unwrap();
+        handle_qmp(&mut service, SocketType::Unix, &QMP_CHANNEL, &controller).unwrap();
+        let resp = client_read_lines(&mut client);
+        assert!(resp[0]["return"].is_object());
+        assert_eq!(resp[0]["id"], Value::from(1));
+        let mut byte = 0_u8;
+        let ret = unsafe { libc::read(pipe[0], &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        assert_eq!(ret, 0);
+        unsafe { libc::close(pipe[0]) };
+        assert!(QmpChannel::get_fd("fd1").is_none());
+
+        // Unknown name.
+        client.write_all(request.as_bytes()).unwrap();
+        handle_qmp(&mut service, SocketType::Unix, &QMP_CHANNEL, &controller).unwrap();
+        let resp = client_read_lines(&mut client);
+        assert_eq!(resp[0]["error"]["class"], Value::from("GenericError"));
+        assert!(resp[0]["error"]["desc"].as_str().unwrap().contains("'fd1'"));
+
+        // Close after consumed by device.
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fd = unsafe { libc::dup(file.as_raw_fd()) };
+        QmpChannel::set_fd("fd2".to_string(), fd);
+        QmpChannel::claim_fd("fd2", "net-0");
+        let err = QmpChannel::close_fd("fd2").err().unwrap().to_string();
+        assert!(err.contains("in use by device net-0"));
+        assert_eq!(QmpChannel::get_fd("fd2"), Some(fd));
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+
+        unsafe { libc::close(fd) };
+        QMP_CHANNEL.fds.write().unwrap().clear();
+        QmpChannel::unbind();
+    }
+
+    #[test]
+    fn test_qmp_human_monitor_command() {
+        use std::os::unix::io::AsRawFd;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QmpChannel::set_negotiated(true);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut service = SocketHandler::new(server.as_raw_fd());
+        let controller: Arc<dyn MachineExternalInterface> = Arc::new(TestMachine);
+        let mut hmp = |command_line: &str| {
+            let request = serde_json::json!({
+                "execute": "human-monitor-command",
+                "arguments": { "command-line": command_line },
+                "id": "hmp"
+            });
+            client.write_all(request.to_string().as_bytes()).unwrap();
+            handle_qmp(&mut service, SocketType::Unix, &QMP_CHANNEL, &controller).unwrap();
+            let resp = client_read_lines(&mut client).remove(0);
+            assert_eq!(resp["id"], Value::from("hmp"));
+            resp["return"].as_str().unwrap().to_string()
+        };
+
+        assert_eq!(hmp("info status"), "VM status: running\r\n");
+        assert!(hmp("info block").starts_with("drive-0: /path/to/rootfs (raw)\r\n"));
+        assert_eq!(hmp("info network"), "");
+        assert_eq!(hmp("info mtree"), "Error: no memory\r\n");
+        assert!(hmp("info").contains("info cpus -- "));
+        // Unknown command is reported in output rather than QMP error.
+        assert_eq!(hmp("info foo"), "unknown command: 'info foo'\r\n");
+        assert_eq!(hmp("savevm"), "unknown command: 'savevm'\r\n");
+
+        QmpChannel::unbind();
+    }
+
+    #[test]
+    fn test_qmp_event_buffer() {
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QMP_CHANNEL.event_buffer.lock().unwrap().events.clear();
+        QmpChannel::set_event_buffer_depth(3);
+        let dropped = QmpChannel::event_buffer_info().dropped;
+
+        // The oldest event is dropped on overflow.
+        event!(STOP);
+        event!(RESUME);
+        event!(POWERDOWN);
+        event!(RESET; schema::RESET { guest: true });
+        QmpChannel::flush_events();
+        let info = QmpChannel::event_buffer_info();
+        assert_eq!(info.depth, 3);
+        assert_eq!(info.buffered, 3);
+        assert_eq!(info.dropped, dropped + 1);
+
+        let socket_name = "test_11.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let listener = UnixListener::bind(socket_name).unwrap();
+        let socket = Socket::from_unix_listener(listener, Some(Arc::new(TestMachine)));
+        let mut client = UnixStream::connect(socket_name).unwrap();
+        socket.accept();
+        let greeting = client_read_lines(&mut client);
+        assert!(greeting[0]["QMP"].is_object());
+
+        // Events are still buffered before negotiation.
+        event!(STOP);
+        QmpChannel::flush_events();
+        assert_eq!(QmpChannel::event_buffer_info().dropped, dropped + 2);
+
+        // Buffered events are replayed oldest first, right after the response
+        // of `qmp_capabilities`.
+        client
+            .write_all(r#"{"execute":"qmp_capabilities"}"#.as_bytes())
+            .unwrap();
+        assert!(socket
+            .handle_stream_event(vmm_sys_util::epoll::EventSet::IN)
+            .is_none());
+        let lines = client_read_lines(&mut client);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0]["return"].is_object());
+        let names: Vec<&str> = lines[1..]
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["POWERDOWN", "RESET", "STOP"]);
+        assert!(lines[2]["timestamp"]["seconds"].as_u64().unwrap() > 0);
+
+        // New events are sent at once after negotiation.
+        event!(RESUME);
+        QmpChannel::flush_events();
+        let lines = client_read_lines(&mut client);
+        assert_eq!(lines[0]["event"], Value::from("RESUME"));
+
+        let resp = client_request(&mut client, &socket, r#"{"execute":"query-event-buffer"}"#);
+        assert_eq!(resp["return"]["depth"], Value::from(3));
+        assert_eq!(resp["return"]["buffered"], Value::from(0));
+        assert_eq!(resp["return"]["dropped"], Value::from(dropped + 2));
+
+        QmpChannel::unbind();
+        QmpChannel::set_event_buffer_depth(DEFAULT_EVENT_BUFFER_DEPTH);
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    #[test]
+    fn test_qmp_max_msg_len() {
+        use vmm_sys_util::epoll::EventSet;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        let socket_name = "test_12.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let listener = UnixListener::bind(socket_name).unwrap();
+        let mut socket = Socket::from_unix_listener(listener, Some(Arc::new(TestMachine)));
+        socket.set_max_msg_len(64);
+        let mut client = UnixStream::connect(socket_name).unwrap();
+        socket.accept();
+        client_read_lines(&mut client);
+        let resp = client_request(&mut client, &socket, r#"{"execute":"qmp_capabilities"}"#);
+        assert!(resp["return"].is_object());
+
+        // The oversized message is rejected before its end is received.
+        let request = format!(r#"{{"execute":"query-status","id":"{}"}}"#, "x".repeat(64));
+        let resp = client_request(&mut client, &socket, &request);
+        assert_eq!(resp["error"]["class"], Value::from("GenericError"));
+
+        // Its remains are discarded until the next newline, at most 65 bytes
+        // are read at once, so the next message is sent after it.
+        client.write_all(b"\n").unwrap();
+        assert!(socket.handle_stream_event(EventSet::IN).is_none());
+        let request = r#"{"execute":"query-status","id":1}"#;
+        let resp = client_request(&mut client, &socket, request);
+        assert_eq!(resp["return"]["status"], Value::from("running"));
+        assert_eq!(resp["id"], Value::from(1));
+
+        QmpChannel::unbind();
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    #[test]
+    fn test_qmp_access_policy() {
+        use crate::socket::AccessPolicy;
+        use std::io::Read;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QmpChannel::unbind();
+        let socket_name = "test_13.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let listener = UnixListener::bind(socket_name).unwrap();
+        let mut socket = Socket::from_unix_listener(listener, Some(Arc::new(TestMachine)));
+        let uid = unsafe { libc::getuid() };
+
+        // The rejected peer gets an error instead of greeting, and is closed
+        // before the writer of events is bound.
+        socket.set_access_policy(AccessPolicy {
+            allow_uids: vec![uid + 1],
+            allow_gids: Vec::new(),
+        });
+        let mut client = UnixStream::connect(socket_name).unwrap();
+        assert!(!socket.accept());
+        assert!(!socket.is_connected());
+        assert!(!QmpChannel::is_connected());
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        let resp: Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(resp["error"]["class"], Value::from("GenericError"));
+        assert_eq!(resp["error"]["desc"], Value::from("Permission denied"));
+
+        socket.set_access_policy(AccessPolicy {
+            allow_uids: vec![uid + 1, uid],
+            allow_gids: Vec::new(),
+        });
+        let mut client = UnixStream::connect(socket_name).unwrap();
+        assert!(socket.accept());
+        assert!(QmpChannel::is_connected());
+        let greeting = client_read_lines(&mut client);
+        assert!(greeting[0]["QMP"].is_object());
+
+        QmpChannel::unbind();
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    #[test]
+    fn test_qmp_owned_monitor() {
+        use vmm_sys_util::epoll::EventSet;
+
+        // No lock is needed since the global one is not touched.
+        let monitor = Arc::new(QmpMonitor::new());
+        let socket_name = "test_14.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let listener = UnixListener::bind(socket_name).unwrap();
+        let mut socket = Socket::from_unix_listener(listener, Some(Arc::new(TestMachine)));
+        socket.set_qmp_channel(monitor.clone());
+        let mut client = UnixStream::connect(socket_name).unwrap();
+        assert!(socket.accept());
+        assert!(monitor.is_connected());
+        let greeting = client_read_lines(&mut client);
+        assert!(greeting[0]["QMP"].is_object());
+
+        // Events emitted before negotiation are replayed by the monitor.
+        monitor.send_event(&schema::QmpEvent::STOP {
+            data: Default::default(),
+            timestamp: create_timestamp(),
+        });
+        client
+            .write_all(r#"{"execute":"qmp_capabilities"}"#.as_bytes())
+            .unwrap();
+        assert!(socket.handle_stream_event(EventSet::IN).is_none());
+        let lines = client_read_lines(&mut client);
+        assert!(lines[0]["return"].is_object());
+        assert_eq!(lines[1]["event"], Value::from("STOP"));
+        assert!(monitor.is_negotiated());
+
+        drop(client);
+        assert!(socket.handle_stream_event(EventSet::IN).is_some());
+        assert!(!monitor.is_connected());
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    #[test]
+    fn test_qmp_tcp() {
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+        use vmm_sys_util::epoll::EventSet;
+
+        let _lock = QMP_CHANNEL_LOCK.lock().unwrap();
+        QmpChannel::object_init();
+        QMP_CHANNEL.event_buffer.lock().unwrap().events.clear();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = Socket::from_tcp_listener(listener, Some(Arc::new(TestMachine)));
+        assert_eq!(socket.get_socket_type(), SocketType::Tcp);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        socket.accept();
+        assert!(QmpChannel::is_connected());
+
+        let mut buffer = [0u8; 1024];
+        let length = client.read(&mut buffer).unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..length]).starts_with(r#"{"QMP":"#));
+
+        let resp = client_request(&mut client, &socket, r#"{"execute":"qmp_capabilities"}"#);
+        assert!(resp["return"].is_object());
+        let request = r#"{"execute":"query-status","id":"tcp"}"#;
+        let resp = client_request(&mut client, &socket, request);
+        assert_eq!(resp["return"]["status"], Value::from("running"));
+        assert_eq!(resp["id"], Value::from("tcp"));
+
+        // Fd can't be passed over tcp.
+        let request = r#"{"execute":"getfd","arguments":{"fdname":"fd1"},"id":2}"#;
+        let resp = client_request(&mut client, &socket, request);
+        assert_eq!(resp["error"]["class"], Value::from("GenericError"));
+        assert_eq!(resp["id"], Value::from(2));
+
+        drop(client);
+        assert!(socket.handle_stream_event(EventSet::IN).is_some());
+        assert!(!QmpChannel::is_connected());
+    }
+
     #[derive(Clone)]
     struct TestQmpHandler {
         content: usize,
@@ -705,12 +2834,18 @@ mod tests {
         fn handle_qmp_type_03(&mut self, _arguments: String) {
             self.content = 3;
         }
+
+        // Result with no args
+        fn handle_qmp_type_04(&mut self) -> std::result::Result<(), String> {
+            self.content = 4;
+            Err("It's type 4 handler".to_string())
+        }
     }
 
     fn test_handle_qmp(
         qmp_command: QmpCommand,
         mut handler: TestQmpHandler,
-    ) -> (Option<u32>, String, usize) {
+    ) -> (Option<Value>, String, usize) {
         let mut resp_str = String::new();
         (
             create_command_matches!(
@@ -731,21 +2866,21 @@ mod tests {
         // 1.Build a qmp command with id and no args, no response
         let qmp_command = schema::QmpCommand::stop {
             arguments: Default::default(),
-            id: Some(0),
+            id: Some(Value::from(0)),
         };
         assert_eq!(
             test_handle_qmp(qmp_command, qmp_handler.clone()),
-            (Some(0), String::new(), 1)
+            (Some(Value::from(0)), String::new(), 1)
         );
 
         // 2.Build a qmp command with id and no args, with response
         let qmp_command = schema::QmpCommand::query_cpus {
             arguments: Default::default(),
-            id: Some(0),
+            id: Some(Value::from(0)),
         };
         assert_eq!(
             test_handle_qmp(qmp_command, qmp_handler.clone()),
-            (Some(0), "It's type 2 handler".to_string(), 2)
+            (Some(Value::from(0)), "It's type 2 handler".to_string(), 2)
         );
 
         // 3.Build a qmp command with id and with args, no response
@@ -753,11 +2888,28 @@ mod tests {
             arguments: schema::device_del {
                 id: "cpu_0".to_string(),
             },
-            id: Some(0),
+            id: Some(Value::from(0)),
         };
         assert_eq!(
             test_handle_qmp(qmp_command, qmp_handler.clone()),
-            (Some(0), String::new(), 3)
+            (Some(Value::from(0)), String::new(), 3)
+        );
+
+        // 4.Build a qmp command with id and no args, its error is response
+        let mut handler = qmp_handler;
+        let mut resp = Response::create_empty_response();
+        let qmp_command = schema::QmpCommand::cont {
+            arguments: Default::default(),
+            id: Some(Value::from(0)),
+        };
+        let id = create_command_matches!(
+            qmp_command;
+            (cont, qmp_command_match!(handle_qmp_type_04; handler; resp; Result));
         );
+        assert_eq!(id, Some(Value::from(0)));
+        assert_eq!(handler.get_content(), 4);
+        let resp = serde_json::to_value(&resp).unwrap();
+        assert_eq!(resp["error"]["class"], Value::from("GenericError"));
+        assert_eq!(resp["error"]["desc"], Value::from("It's type 4 handler"));
     }
 }
@@ -45,6 +45,63 @@ impl QmpErrorClass {
     }
 }
 
+/// Names of all commands in `QmpCommand`, reported by `query-commands`.
+/// It must be updated when a command is added, which is checked by test.
+pub const QMP_COMMANDS: &[&str] = &[
+    "qmp_capabilities",
+    "quit",
+    "system_powerdown",
+    "stop",
+    "cont",
+    "device_add",
+    "device_del",
+    "netdev_add",
+    "netdev_del",
+    "query-hotpluggable-cpus",
+    "query-cpus",
+    "query-memory-advice",
+    "query-machines",
+    "query-machine-properties",
+    "query-eventloop-stats",
+    "query-boot-times",
+    "query-kvm-caps",
+    "query-event-buffer",
+    "query-version",
+    "query-commands",
+    "query-events",
+    "query-status",
+    "query-name",
+    "query-uuid",
+    "getfd",
+    "closefd",
+    "logfile-reopen",
+    "human-monitor-command",
+    "dump-guest-memory",
+    "x-snapshot-save",
+    "migrate",
+    "migrate_cancel",
+    "migrate-incoming",
+    "query-migrate",
+    "migrate-set-parameters",
+    "qom-list",
+    "qom-get",
+    "blockdev-add",
+    "blockdev-del",
+];
+
+/// Names of all events in `QmpEvent`, reported by `query-events`.
+/// It must be updated when an event is added, which is checked by test.
+pub const QMP_EVENTS: &[&str] = &[
+    "SHUTDOWN",
+    "RESET",
+    "STOP",
+    "RESUME",
+    "DEVICE_DELETED",
+    "POWERDOWN",
+    "RTC_CHANGE",
+    "MIGRATION",
+];
+
 /// A enum to store all command struct
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "execute")]
@@ -53,82 +110,246 @@ pub enum QmpCommand {
     qmp_capabilities {
         #[serde(default)]
         arguments: qmp_capabilities,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
     },
     quit {
         #[serde(default)]
         arguments: quit,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
+    },
+    system_powerdown {
+        #[serde(default)]
+        arguments: system_powerdown,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
     },
     stop {
         #[serde(default)]
         arguments: stop,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
     cont {
         #[serde(default)]
         arguments: cont,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
     device_add {
         arguments: device_add,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
     device_del {
         arguments: device_del,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
     netdev_add {
         arguments: netdev_add,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
     netdev_del {
         arguments: netdev_del,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
     #[serde(rename = "query-hotpluggable-cpus")]
     query_hotpluggable_cpus {
         #[serde(default)]
         arguments: query_hotpluggable_cpus,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-name")]
+    query_name {
+        #[serde(default)]
+        arguments: query_name,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-uuid")]
+    query_uuid {
+        #[serde(default)]
+        arguments: query_uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
     },
     #[serde(rename = "query-cpus")]
     query_cpus {
         #[serde(default)]
         arguments: query_cpus,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-machines")]
+    query_machines {
+        #[serde(default)]
+        arguments: query_machines,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-machine-properties")]
+    query_machine_properties {
+        #[serde(default)]
+        arguments: query_machine_properties,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-memory-advice")]
+    query_memory_advice {
+        #[serde(default)]
+        arguments: query_memory_advice,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-eventloop-stats")]
+    query_eventloop_stats {
+        #[serde(default)]
+        arguments: query_eventloop_stats,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-boot-times")]
+    query_boot_times {
+        #[serde(default)]
+        arguments: query_boot_times,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-kvm-caps")]
+    query_kvm_caps {
+        #[serde(default)]
+        arguments: query_kvm_caps,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-event-buffer")]
+    query_event_buffer {
+        #[serde(default)]
+        arguments: query_event_buffer,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-version")]
+    query_version {
+        #[serde(default)]
+        arguments: query_version,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-commands")]
+    query_commands {
+        #[serde(default)]
+        arguments: query_commands,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-events")]
+    query_events {
+        #[serde(default)]
+        arguments: query_events,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
     },
     #[serde(rename = "query-status")]
     query_status {
         #[serde(default)]
         arguments: query_status,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
     getfd {
         arguments: getfd,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
+    },
+    closefd {
+        arguments: closefd,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "logfile-reopen")]
+    logfile_reopen {
+        #[serde(default)]
+        arguments: logfile_reopen,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "human-monitor-command")]
+    human_monitor_command {
+        arguments: human_monitor_command,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "qom-list")]
+    qom_list {
+        arguments: qom_list,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "qom-get")]
+    qom_get {
+        arguments: qom_get,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "dump-guest-memory")]
+    dump_guest_memory {
+        arguments: dump_guest_memory,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "x-snapshot-save")]
+    x_snapshot_save {
+        arguments: x_snapshot_save,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    migrate {
+        arguments: migrate,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    migrate_cancel {
+        #[serde(default)]
+        arguments: migrate_cancel,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "migrate-incoming")]
+    migrate_incoming {
+        arguments: migrate_incoming,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "query-migrate")]
+    query_migrate {
+        #[serde(default)]
+        arguments: query_migrate,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
+    },
+    #[serde(rename = "migrate-set-parameters")]
+    migrate_set_parameters {
+        arguments: migrate_set_parameters,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<Any>,
     },
     #[serde(rename = "blockdev-add")]
     blockdev_add {
         arguments: blockdev_add,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
     #[serde(rename = "blockdev-del")]
     blockdev_del {
         arguments: blockdev_del,
         #[serde(default, skip_serializing_if = "Option::is_none")]
-        id: Option<u32>,
+        id: Option<Any>,
     },
 }
 
@@ -143,6 +364,7 @@ pub enum QmpCommand {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct qmp_capabilities {}
 
 impl Command for qmp_capabilities {
@@ -168,6 +390,7 @@ impl Command for qmp_capabilities {
 /// <- { "return": {}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct quit {}
 
 impl Command for quit {
@@ -179,6 +402,31 @@ impl Command for quit {
     }
 }
 
+/// system_powerdown
+///
+/// Press the power button of guest, so that guest shuts down gracefully if
+/// it handles the button. The command returns immediately, a `POWERDOWN`
+/// event is emitted, and a `SHUTDOWN` event follows when guest shuts down.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "system_powerdown" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct system_powerdown {}
+
+impl Command for system_powerdown {
+    const NAME: &'static str = "system_powerdown";
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// stop
 ///
 /// Stop all guest VCPU execution
@@ -190,6 +438,7 @@ impl Command for quit {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct stop {}
 
 impl Command for stop {
@@ -212,6 +461,7 @@ impl Command for stop {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct cont {}
 
 impl Command for cont {
@@ -241,6 +491,7 @@ impl Command for cont {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct device_add {
     #[serde(rename = "id")]
     pub id: String,
@@ -250,6 +501,15 @@ pub struct device_add {
     pub addr: Option<String>,
     #[serde(rename = "lun")]
     pub lun: Option<usize>,
+    /// Id of multiport console which the port is added to.
+    #[serde(rename = "bus")]
+    pub bus: Option<String>,
+    /// Path of unix domain socket as the backend of port.
+    #[serde(rename = "chardev")]
+    pub chardev: Option<String>,
+    /// Name of port shown in guest.
+    #[serde(rename = "name")]
+    pub name: Option<String>,
 }
 
 impl Command for device_add {
@@ -262,16 +522,47 @@ impl Command for device_add {
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FileOptions {
     pub driver: String,
     pub filename: String,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CacheOptions {
     #[serde(rename = "no-flush")]
     pub no_flush: Option<bool>,
     pub direct: Option<bool>,
+    pub writeback: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BlockdevAioOptions {
+    #[serde(rename = "threads")]
+    threads,
+    #[serde(rename = "native")]
+    native,
+    #[serde(rename = "io_uring")]
+    io_uring,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BlockdevDiscardOptions {
+    #[serde(rename = "ignore")]
+    ignore,
+    #[serde(rename = "unmap")]
+    unmap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BlockdevDetectZeroesOptions {
+    #[serde(rename = "off")]
+    off,
+    #[serde(rename = "on")]
+    on,
+    #[serde(rename = "unmap")]
+    unmap,
 }
 
 /// blockdev_add
@@ -280,8 +571,11 @@ pub struct CacheOptions {
 ///
 /// * `node_name` - the device's ID, must be unique.
 /// * `file` - the backend file information.
-/// * `cache` - if use direct io.
+/// * `cache` - if use direct io, write cache, and if ignore flush requests.
 /// * `read_only` - if readonly.
+/// * `aio` - the engine to submit IO.
+/// * `discard` - if blocks of the image may be released.
+/// * `detect_zeroes` - how to handle writes of zeroes.
 ///
 /// Additional arguments depend on the type.
 ///
@@ -291,10 +585,13 @@ pub struct CacheOptions {
 /// -> { "execute": "blockdev_add",
 ///      "arguments":  {"node-name": "drive-0",
 ///                     "file": {"driver": "file", "filename": "/path/to/block"},
-///                     "cache": {"direct": true}, "read-only": false }}
+///                     "cache": {"direct": true, "writeback": true, "no-flush": false},
+///                     "read-only": false, "aio": "io_uring",
+///                     "discard": "unmap", "detect-zeroes": "unmap" }}
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct blockdev_add {
     #[serde(rename = "node-name")]
     pub node_name: String,
@@ -302,6 +599,10 @@ pub struct blockdev_add {
     pub cache: Option<CacheOptions>,
     #[serde(rename = "read-only")]
     pub read_only: Option<bool>,
+    pub aio: Option<BlockdevAioOptions>,
+    pub discard: Option<BlockdevDiscardOptions>,
+    #[serde(rename = "detect-zeroes")]
+    pub detect_zeroes: Option<BlockdevDetectZeroesOptions>,
 }
 
 impl Command for blockdev_add {
@@ -331,6 +632,7 @@ impl Command for blockdev_add {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct netdev_add {
     pub id: String,
     #[serde(rename = "ifname")]
@@ -358,15 +660,14 @@ impl Command for netdev_add {
 /// # Errors
 ///
 /// If `id` is not a valid device, DeviceNotFound.
+/// If the device is already being removed, GenericError.
 ///
 /// # Notes
 ///
 /// When this command completes, the device may not be removed from the
-/// guest. Hot removal is an operation that requires guest cooperation.
-/// This command merely requests that the guest begin the hot removal
-/// process. Completion of the device removal process is signaled with a
-/// DEVICE_DELETED event. Guest reset will automatically complete removal
-/// for all devices.
+/// guest. This command merely starts the removal, the IO in flight is
+/// drained and the backend is closed asynchronously. Completion of the
+/// device removal process is signaled with a DEVICE_DELETED event.
 ///
 /// # Examples
 ///
@@ -376,6 +677,7 @@ impl Command for netdev_add {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct device_del {
     pub id: String,
 }
@@ -390,6 +692,7 @@ impl Command for device_del {
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct blockdev_del {
     #[serde(rename = "node-name")]
     pub node_name: String,
@@ -423,6 +726,7 @@ impl Command for blockdev_del {
 /// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct netdev_del {
     pub id: String,
 }
@@ -460,6 +764,7 @@ impl Command for netdev_del {
 ///    ]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct query_hotpluggable_cpus {}
 
 impl Command for query_hotpluggable_cpus {
@@ -495,6 +800,72 @@ pub struct CpuInstanceProperties {
     pub core_id: Option<isize>,
 }
 
+/// query-name
+///
+/// Query the name of VM set by `-name`.
+///
+/// # Returns
+///
+/// `NameInfo` of VM, whose `name` is absent if it's not set.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-name" }
+/// <- { "return": { "name": "guest-1" } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_name {}
+
+impl Command for query_name {
+    const NAME: &'static str = "query-name";
+    type Res = NameInfo;
+
+    fn back(self) -> NameInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NameInfo {
+    #[serde(rename = "name", default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// query-uuid
+///
+/// Query the UUID of VM.
+///
+/// # Returns
+///
+/// `UuidInfo` of VM.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-uuid" }
+/// <- { "return": { "UUID": "550e8400-e29b-41d4-a716-446655440000" } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_uuid {}
+
+impl Command for query_uuid {
+    const NAME: &'static str = "query-uuid";
+    type Res = UuidInfo;
+
+    fn back(self) -> UuidInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct UuidInfo {
+    #[serde(rename = "UUID")]
+    pub uuid: String,
+}
+
 /// query-cpus:
 ///
 /// This command causes vCPU threads to exit to userspace, which causes
@@ -532,6 +903,7 @@ pub struct CpuInstanceProperties {
 ///    }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct query_cpus {}
 
 impl Command for query_cpus {
@@ -590,147 +962,1194 @@ pub struct CpuInfoX86 {}
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct CpuInfoArm {}
 
-/// query-status
+/// query-memory-advice
 ///
-/// Query the run status of all VCPUs.
+/// Query memory advice which takes effect on each host mapping of guest ram.
 ///
 /// # Returns
 ///
-/// `StatusInfo` reflecting all VCPUs.
+/// A list of `MemoryAdviceInfo` for each guest ram mapping.
 ///
 /// # Examples
 ///
 /// ```text
-/// -> { "execute": "query-status" }
-/// <- { "return": { "running": true,
-///                  "singlestep": false,
-///                  "status": "running" } }
+/// -> { "execute": "query-memory-advice" }
+/// <- { "return": [
+///          {
+///             "base": 0,
+///             "size": 1073741824,
+///             "dump-guest-core": false,
+///             "mem-merge": true,
+///             "thp": "auto"
+///          }
+///       ]
+///    }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_status {}
+#[serde(deny_unknown_fields)]
+pub struct query_memory_advice {}
 
-impl Command for query_status {
-    const NAME: &'static str = "query-status";
-    type Res = StatusInfo;
+impl Command for query_memory_advice {
+    const NAME: &'static str = "query-memory-advice";
+    type Res = Vec<MemoryAdviceInfo>;
 
-    fn back(self) -> StatusInfo {
+    fn back(self) -> Vec<MemoryAdviceInfo> {
         Default::default()
     }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct StatusInfo {
-    #[serde(rename = "singlestep")]
-    pub singlestep: bool,
-    #[serde(rename = "running")]
-    pub running: bool,
-    #[serde(rename = "status")]
-    pub status: RunState,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum RunState {
-    #[serde(rename = "debug")]
-    debug,
-    #[serde(rename = "inmigrate")]
-    inmigrate,
-    #[serde(rename = "internal-error")]
-    internal_error,
-    #[serde(rename = "io-error")]
-    io_error,
-    #[serde(rename = "paused")]
-    paused,
-    #[serde(rename = "postmigrate")]
-    postmigrate,
-    #[serde(rename = "prelaunch")]
-    prelaunch,
-    #[serde(rename = "finish-migrate")]
-    finish_migrate,
-    #[serde(rename = "restore-vm")]
-    restore_vm,
-    #[serde(rename = "running")]
-    running,
-    #[serde(rename = "save-vm")]
-    save_vm,
-    #[serde(rename = "shutdown")]
-    shutdown,
-    #[serde(rename = "suspended")]
-    suspended,
-    #[serde(rename = "watchdog")]
-    watchdog,
-    #[serde(rename = "guest-panicked")]
-    guest_panicked,
-    #[serde(rename = "colo")]
-    colo,
-    #[serde(rename = "preconfig")]
-    preconfig,
-}
-
-impl Default for RunState {
-    fn default() -> Self {
-        RunState::debug
-    }
+pub struct MemoryAdviceInfo {
+    #[serde(rename = "base")]
+    pub base: u64,
+    #[serde(rename = "size")]
+    pub size: u64,
+    #[serde(rename = "dump-guest-core")]
+    pub dump_guest_core: bool,
+    #[serde(rename = "mem-merge")]
+    pub mem_merge: bool,
+    #[serde(rename = "thp")]
+    pub thp: String,
 }
 
-/// getfd
+/// query-machines
 ///
-/// Receive a file descriptor via SCM rights and assign it a name
+/// Query the machine types supported by StratoVirt.
 ///
-/// # Arguments
+/// # Returns
 ///
-/// * `fdname` - File descriptor name.
+/// A list of `MachineInfo` for each machine type.
 ///
 /// # Examples
 ///
 /// ```text
-/// -> { "execute": "getfd", "arguments": { "fdname": "fd1" } }
-/// <- { "return": {} }
+/// -> { "execute": "query-machines" }
+/// <- { "return": [
+///          {
+///             "name": "microvm",
+///             "is-default": true,
+///             "cpu-max": 254,
+///             "hotpluggable-cpus": false
+///          }
+///       ]
+///    }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct getfd {
-    #[serde(rename = "fdname")]
-    pub fd_name: String,
-}
-
-impl Command for getfd {
-    const NAME: &'static str = "getfd";
+#[serde(deny_unknown_fields)]
+pub struct query_machines {}
 
-    type Res = Empty;
+impl Command for query_machines {
+    const NAME: &'static str = "query-machines";
+    type Res = Vec<MachineInfo>;
 
-    fn back(self) -> Empty {
+    fn back(self) -> Vec<MachineInfo> {
         Default::default()
     }
 }
 
-/// SHUTDOWN
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MachineInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "alias", default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(
+        rename = "is-default",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub is_default: Option<bool>,
+    #[serde(rename = "cpu-max")]
+    pub cpu_max: isize,
+    #[serde(rename = "hotpluggable-cpus")]
+    pub hotpluggable_cpus: bool,
+}
+
+/// query-machine-properties
 ///
-/// Emitted when the virtual machine has shut down, indicating that StratoVirt is
-/// about to exit.
+/// Query the effective value of each property of `-machine`.
 ///
-/// # Notes
+/// # Returns
 ///
-/// If the command-line option "-no-shutdown" has been specified, StratoVirt
-/// will not exit, and a STOP event will eventually follow the SHUTDOWN event
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SHUTDOWN {
-    /// If true, the shutdown was triggered by a guest request (such as
-    /// a guest-initiated ACPI shutdown request or other hardware-specific
-    /// action) rather than a host request (such as sending StratoVirt a SIGINT).
-    #[serde(rename = "guest")]
-    pub guest: bool,
-    pub reason: String,
+/// A list of `MachinePropertyInfo` for each property, the values are in the
+/// syntax of cmdline.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-machine-properties" }
+/// <- { "return": [
+///          { "name": "dump-guest-core", "type": "bool", "value": "off", "default": "on" },
+///          { "name": "max-slot-size", "type": "size", "value": "549755813888" }
+///       ]
+///    }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_machine_properties {}
+
+impl Command for query_machine_properties {
+    const NAME: &'static str = "query-machine-properties";
+    type Res = Vec<MachinePropertyInfo>;
+
+    fn back(self) -> Vec<MachinePropertyInfo> {
+        Default::default()
+    }
 }
 
-impl Event for SHUTDOWN {
-    const NAME: &'static str = "SHUTDOWN";
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MachinePropertyInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "type")]
+    pub prop_type: String,
+    #[serde(rename = "value")]
+    pub value: String,
+    #[serde(rename = "default", default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
 }
 
-/// RESET
+/// query-eventloop-stats
 ///
-/// Emitted when the virtual machine is reset
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RESET {
-    /// If true, the reset was triggered by a guest request (such as
+/// Query statistics of the main loop and iothreads.
+///
+/// # Arguments
+///
+/// * `reset` - Zero the counters after they are reported.
+///
+/// # Returns
+///
+/// A list of `EventLoopStats` for each event loop. `callback-time` is absent
+/// until the first query, since callbacks are not timed before it.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-eventloop-stats", "arguments": { "reset": true } }
+/// <- { "return": [
+///          {
+///             "name": "main",
+///             "iterations": 1024,
+///             "wakeups": 1000,
+///             "events": 1200,
+///             "max-events": 3,
+///             "notifiers": [ { "fd": 12, "count": 1000 } ],
+///             "callback-time": [
+///                 { "upper-bound-us": 10, "count": 900 },
+///                 { "count": 0 }
+///             ]
+///          }
+///       ]
+///    }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_eventloop_stats {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset: Option<bool>,
+}
+
+impl Command for query_eventloop_stats {
+    const NAME: &'static str = "query-eventloop-stats";
+    type Res = Vec<EventLoopStats>;
+
+    fn back(self) -> Vec<EventLoopStats> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct EventLoopStats {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "iterations")]
+    pub iterations: u64,
+    #[serde(rename = "wakeups")]
+    pub wakeups: u64,
+    #[serde(rename = "events")]
+    pub events: u64,
+    #[serde(rename = "max-events")]
+    pub max_events: u64,
+    #[serde(rename = "notifiers")]
+    pub notifiers: Vec<NotifierStats>,
+    #[serde(
+        rename = "callback-time",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub callback_time: Option<Vec<HistogramBucket>>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NotifierStats {
+    #[serde(rename = "fd")]
+    pub fd: i32,
+    #[serde(rename = "count")]
+    pub count: u64,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    #[serde(
+        rename = "upper-bound-us",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub upper_bound_us: Option<u64>,
+    #[serde(rename = "count")]
+    pub count: u64,
+}
+
+/// query-boot-times
+///
+/// Query the time of boot milestones of VM, such as the first vcpu entry
+/// and `guest-ready` marked by guest with `-machine boot-marker=on`.
+///
+/// # Returns
+///
+/// A list of `BootTimeInfo` for each milestone reached, in the order they
+/// are reached. Time is in milliseconds from the creation of VM, and delta
+/// is from the previous milestone.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-boot-times" }
+/// <- { "return": [
+///          { "name": "kvm-vm-created", "time-ms": 0.412, "delta-ms": 0.412 },
+///          { "name": "memory-ready", "time-ms": 1.05, "delta-ms": 0.638 }
+///       ]
+///    }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_boot_times {}
+
+impl Command for query_boot_times {
+    const NAME: &'static str = "query-boot-times";
+    type Res = Vec<BootTimeInfo>;
+
+    fn back(self) -> Vec<BootTimeInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BootTimeInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "time-ms")]
+    pub time_ms: f64,
+    #[serde(rename = "delta-ms")]
+    pub delta_ms: f64,
+}
+
+/// query-kvm-caps
+///
+/// Query the capabilities of host KVM probed when VM is created.
+///
+/// # Returns
+///
+/// A list of `KvmCapInfo`, the required capabilities are listed first, which
+/// are always supported since VM can't be created otherwise.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-kvm-caps" }
+/// <- { "return": [
+///          { "name": "KVM_CAP_USER_MEMORY", "required": true, "supported": true },
+///          { "name": "KVM_CAP_SIGNAL_MSI", "required": false, "supported": false }
+///       ]
+///    }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_kvm_caps {}
+
+impl Command for query_kvm_caps {
+    const NAME: &'static str = "query-kvm-caps";
+    type Res = Vec<KvmCapInfo>;
+
+    fn back(self) -> Vec<KvmCapInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct KvmCapInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "required")]
+    pub required: bool,
+    #[serde(rename = "supported")]
+    pub supported: bool,
+}
+
+/// query-event-buffer
+///
+/// Query the buffer of events emitted while no client is connected, which
+/// are replayed after capabilities negotiation.
+///
+/// # Returns
+///
+/// `EventBufferInfo` of the buffer.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-event-buffer" }
+/// <- { "return": { "depth": 128, "buffered": 0, "dropped": 3, "queue-dropped": 0 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_event_buffer {}
+
+impl Command for query_event_buffer {
+    const NAME: &'static str = "query-event-buffer";
+    type Res = EventBufferInfo;
+
+    fn back(self) -> EventBufferInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct EventBufferInfo {
+    /// Max number of buffered events.
+    #[serde(rename = "depth")]
+    pub depth: u64,
+    /// Number of events in buffer.
+    #[serde(rename = "buffered")]
+    pub buffered: u64,
+    /// Number of events dropped for overflow.
+    #[serde(rename = "dropped")]
+    pub dropped: u64,
+    /// Number of events dropped for overflow of the queue to main loop.
+    #[serde(rename = "queue-dropped")]
+    pub queue_dropped: u64,
+}
+
+/// query-version
+///
+/// Query the version of QEMU which StratoVirt is compatible with, and the
+/// version of StratoVirt itself in `package`.
+///
+/// # Returns
+///
+/// `VersionInfo` of StratoVirt.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-version" }
+/// <- { "return": {
+///          "qemu": { "micro": 1, "minor": 0, "major": 4 },
+///          "package": "StratoVirt-0.1.0"
+///       }
+///    }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_version {}
+
+impl Command for query_version {
+    const NAME: &'static str = "query-version";
+    type Res = VersionInfo;
+
+    fn back(self) -> VersionInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct VersionInfo {
+    #[serde(rename = "qemu")]
+    pub qemu: VersionTriple,
+    #[serde(rename = "package")]
+    pub package: String,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct VersionTriple {
+    #[serde(rename = "micro")]
+    pub micro: u8,
+    #[serde(rename = "minor")]
+    pub minor: u8,
+    #[serde(rename = "major")]
+    pub major: u8,
+}
+
+/// query-commands
+///
+/// Query the commands supported by StratoVirt.
+///
+/// # Returns
+///
+/// A list of `CommandInfo` for each command.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-commands" }
+/// <- { "return": [ { "name": "qmp_capabilities" }, { "name": "quit" }, ... ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_commands {}
+
+impl Command for query_commands {
+    const NAME: &'static str = "query-commands";
+    type Res = Vec<CommandInfo>;
+
+    fn back(self) -> Vec<CommandInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct CommandInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+}
+
+/// query-events
+///
+/// Query the events which StratoVirt can emit.
+///
+/// # Returns
+///
+/// A list of `EventInfo` for each event.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-events" }
+/// <- { "return": [ { "name": "SHUTDOWN" }, { "name": "RESET" }, ... ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_events {}
+
+impl Command for query_events {
+    const NAME: &'static str = "query-events";
+    type Res = Vec<EventInfo>;
+
+    fn back(self) -> Vec<EventInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct EventInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+}
+
+/// query-status
+///
+/// Query the run status of all VCPUs.
+///
+/// # Returns
+///
+/// `StatusInfo` reflecting all VCPUs.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-status" }
+/// <- { "return": { "running": true,
+///                  "singlestep": false,
+///                  "status": "running" } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_status {}
+
+impl Command for query_status {
+    const NAME: &'static str = "query-status";
+    type Res = StatusInfo;
+
+    fn back(self) -> StatusInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct StatusInfo {
+    #[serde(rename = "singlestep")]
+    pub singlestep: bool,
+    #[serde(rename = "running")]
+    pub running: bool,
+    #[serde(rename = "status")]
+    pub status: RunState,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RunState {
+    #[serde(rename = "debug")]
+    debug,
+    #[serde(rename = "inmigrate")]
+    inmigrate,
+    #[serde(rename = "internal-error")]
+    internal_error,
+    #[serde(rename = "io-error")]
+    io_error,
+    #[serde(rename = "paused")]
+    paused,
+    #[serde(rename = "postmigrate")]
+    postmigrate,
+    #[serde(rename = "prelaunch")]
+    prelaunch,
+    #[serde(rename = "finish-migrate")]
+    finish_migrate,
+    #[serde(rename = "restore-vm")]
+    restore_vm,
+    #[serde(rename = "running")]
+    running,
+    #[serde(rename = "save-vm")]
+    save_vm,
+    #[serde(rename = "shutdown")]
+    shutdown,
+    #[serde(rename = "suspended")]
+    suspended,
+    #[serde(rename = "watchdog")]
+    watchdog,
+    #[serde(rename = "guest-panicked")]
+    guest_panicked,
+    #[serde(rename = "colo")]
+    colo,
+    #[serde(rename = "preconfig")]
+    preconfig,
+}
+
+impl Default for RunState {
+    fn default() -> Self {
+        RunState::debug
+    }
+}
+
+/// getfd
+///
+/// Receive a file descriptor via SCM rights and assign it a name. If several
+/// file descriptors are sent in one message, they're named `fdname`,
+/// `fdname#1`, `fdname#2`... in order.
+///
+/// # Arguments
+///
+/// * `fdname` - File descriptor name.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "getfd", "arguments": { "fdname": "fd1" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct getfd {
+    #[serde(rename = "fdname")]
+    pub fd_name: String,
+}
+
+impl Command for getfd {
+    const NAME: &'static str = "getfd";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// closefd
+///
+/// Close a file descriptor received by `getfd`. The file descriptor handed off
+/// to a device can't be closed.
+///
+/// # Arguments
+///
+/// * `fdname` - File descriptor name.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "closefd", "arguments": { "fdname": "fd1" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct closefd {
+    #[serde(rename = "fdname")]
+    pub fd_name: String,
+}
+
+impl Command for closefd {
+    const NAME: &'static str = "closefd";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// logfile-reopen
+///
+/// Reopen the log file given by `-D`, so that log is written to a new file
+/// after the old one is renamed by log rotation.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "logfile-reopen" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct logfile_reopen {}
+
+impl Command for logfile_reopen {
+    const NAME: &'static str = "logfile-reopen";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// human-monitor-command
+///
+/// Execute a command of human monitor, only a subset of `info` commands is
+/// supported.
+///
+/// # Arguments
+///
+/// * `command-line` - The command line of human monitor.
+///
+/// # Returns
+///
+/// The output of the command as a string. Error of the command, such as
+/// unknown command, is reported in the output instead of a QMP error.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "human-monitor-command",
+///      "arguments": { "command-line": "info status" } }
+/// <- { "return": "VM status: running\r\n" }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct human_monitor_command {
+    #[serde(rename = "command-line")]
+    pub command_line: String,
+}
+
+impl Command for human_monitor_command {
+    const NAME: &'static str = "human-monitor-command";
+
+    type Res = String;
+
+    fn back(self) -> String {
+        Default::default()
+    }
+}
+
+/// dump-guest-memory
+///
+/// Dump guest memory as an ELF core file, which can be analyzed by `crash` or
+/// `gdb`. The registers of each vcpu are saved in `NT_PRSTATUS` notes. VM is
+/// paused while dumping if it's running, and resumed after that.
+///
+/// # Arguments
+///
+/// * `paging` - Whether to dump guest virtual memory by paging, only `false`
+///   is supported.
+/// * `protocol` - Destination of dump, `file:PATH` creates a new file, and
+///   `fd:NAME` writes to the file descriptor received by `getfd`.
+/// * `detach` - Whether to dump in background, only `false` is supported.
+/// * `begin` - Start guest physical address of memory to dump.
+/// * `length` - Size of memory to dump, it must be given along with `begin`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "dump-guest-memory",
+///      "arguments": { "paging": false, "protocol": "file:/tmp/vmcore" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct dump_guest_memory {
+    #[serde(rename = "paging")]
+    pub paging: bool,
+    #[serde(rename = "protocol")]
+    pub protocol: String,
+    #[serde(rename = "detach", default, skip_serializing_if = "Option::is_none")]
+    pub detach: Option<bool>,
+    #[serde(rename = "begin", default, skip_serializing_if = "Option::is_none")]
+    pub begin: Option<u64>,
+    #[serde(rename = "length", default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+}
+
+impl Command for dump_guest_memory {
+    const NAME: &'static str = "dump-guest-memory";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// x-snapshot-save
+///
+/// Save the state of vcpus, devices and guest memory to a snapshot file. VM
+/// is paused while saving if it's running, and resumed after that unless
+/// `paused` is true. If saving fails, the incomplete file is removed and VM
+/// is resumed.
+///
+/// # Arguments
+///
+/// * `path` - Path of snapshot file, which must not exist.
+/// * `paused` - Whether to leave VM paused after saved, default false.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "x-snapshot-save",
+///      "arguments": { "path": "/tmp/vm.snap", "paused": true } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct x_snapshot_save {
+    #[serde(rename = "path")]
+    pub path: String,
+    #[serde(rename = "paused", default, skip_serializing_if = "Option::is_none")]
+    pub paused: Option<bool>,
+}
+
+impl Command for x_snapshot_save {
+    const NAME: &'static str = "x-snapshot-save";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// migrate
+///
+/// Migrate VM out to the destination started with `-incoming`. It returns
+/// once migration is started, whose progress is reported by `query-migrate`
+/// and `MIGRATION` events. VM is left paused in `postmigrate` status after
+/// it's handed off, or keeps running if migration fails or is cancelled.
+///
+/// # Arguments
+///
+/// * `uri` - `tcp:IP:PORT` of the destination.
+/// * `blk` - Whether to migrate images of drives, which is not supported.
+/// * `inc` - Whether to migrate images incrementally, which is not supported.
+/// * `detach` - Ignored, migration always runs in background.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "migrate", "arguments": { "uri": "tcp:192.168.0.2:4444" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct migrate {
+    #[serde(rename = "uri")]
+    pub uri: String,
+    #[serde(rename = "blk", default, skip_serializing_if = "Option::is_none")]
+    pub blk: Option<bool>,
+    #[serde(rename = "inc", default, skip_serializing_if = "Option::is_none")]
+    pub inc: Option<bool>,
+    #[serde(rename = "detach", default, skip_serializing_if = "Option::is_none")]
+    pub detach: Option<bool>,
+}
+
+impl Command for migrate {
+    const NAME: &'static str = "migrate";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// migrate_cancel
+///
+/// Cancel the running migration, VM keeps running if it was. It's a no-op
+/// if no migration is running.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "migrate_cancel" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct migrate_cancel {}
+
+impl Command for migrate_cancel {
+    const NAME: &'static str = "migrate_cancel";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// migrate-incoming
+///
+/// Listen for the source of VM started with `-incoming defer`. It returns
+/// once the listener is created, VM is left paused in `paused` status after
+/// it's migrated in, and started by `cont`.
+///
+/// # Arguments
+///
+/// * `uri` - `tcp:IP:PORT` listened for the source.
+///
+/// # Errors
+///
+/// VM isn't started with `-incoming defer`, or `migrate-incoming` has
+/// already been executed.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "migrate-incoming", "arguments": { "uri": "tcp:0.0.0.0:4444" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct migrate_incoming {
+    #[serde(rename = "uri")]
+    pub uri: String,
+}
+
+impl Command for migrate_incoming {
+    const NAME: &'static str = "migrate-incoming";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-migrate
+///
+/// Query the status and progress of the last migration.
+///
+/// # Returns
+///
+/// `MigrationInfo`, which is empty if no migration is started.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-migrate" }
+/// <- { "return": { "status": "active",
+///                  "ram": { "transferred": 123456, "remaining": 8192,
+///                           "total": 1073741824, "dirty-pages-rate": 20,
+///                           "dirty-sync-count": 2 },
+///                  "total-time": 1250 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_migrate {}
+
+impl Command for query_migrate {
+    const NAME: &'static str = "query-migrate";
+
+    type Res = MigrationInfo;
+
+    fn back(self) -> MigrationInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationInfo {
+    /// One of `setup`, `active`, `cancelling`, `completed`, `failed` and
+    /// `cancelled`.
+    #[serde(rename = "status", default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Progress of guest memory, given once memory is being sent.
+    #[serde(rename = "ram", default, skip_serializing_if = "Option::is_none")]
+    pub ram: Option<MigrationStats>,
+    /// Milliseconds since migration started, until it stopped.
+    #[serde(
+        rename = "total-time",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub total_time: Option<u64>,
+    /// Milliseconds VM is paused for, given once migration is completed.
+    #[serde(rename = "downtime", default, skip_serializing_if = "Option::is_none")]
+    pub downtime: Option<u64>,
+    /// Reason of failure, given if migration failed.
+    #[serde(
+        rename = "error-desc",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub error_desc: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationStats {
+    /// Bytes sent to the destination.
+    #[serde(rename = "transferred")]
+    pub transferred: u64,
+    /// Bytes of dirty pages left to send.
+    #[serde(rename = "remaining")]
+    pub remaining: u64,
+    /// Bytes of guest memory.
+    #[serde(rename = "total")]
+    pub total: u64,
+    /// Pages dirtied by guest per second in the last pass.
+    #[serde(rename = "dirty-pages-rate")]
+    pub dirty_pages_rate: u64,
+    /// Number of times dirty pages are synced.
+    #[serde(rename = "dirty-sync-count")]
+    pub dirty_sync_count: u64,
+}
+
+/// migrate-set-parameters
+///
+/// Set the parameters of migration, which take effect on the next migration.
+///
+/// # Arguments
+///
+/// * `max-bandwidth` - Max bytes sent per second before VM is paused for the
+///   last pass, 0 for unlimited. Default 32 MiB/s.
+/// * `downtime-limit` - Max milliseconds VM is allowed to be paused for the
+///   last pass, up to 2000000. Default 300 ms.
+///
+/// # Errors
+///
+/// Parameters can't be changed while migration is running.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "migrate-set-parameters",
+///      "arguments": { "max-bandwidth": 104857600, "downtime-limit": 500 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct migrate_set_parameters {
+    #[serde(
+        rename = "max-bandwidth",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_bandwidth: Option<u64>,
+    #[serde(
+        rename = "downtime-limit",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub downtime_limit: Option<u64>,
+}
+
+impl Command for migrate_set_parameters {
+    const NAME: &'static str = "migrate-set-parameters";
+
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// qom-list
+///
+/// List the properties of an object in QOM tree, including its children.
+///
+/// # Arguments
+///
+/// * `path` - Absolute path of object, such as `/machine/unattached/device[0]`.
+///
+/// # Returns
+///
+/// A list of `ObjectPropertyInfo` for each property, the type of child is
+/// `child<TYPE>`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "qom-list", "arguments": { "path": "/machine" } }
+/// <- { "return": [ { "name": "type", "type": "string" },
+///                  { "name": "unattached", "type": "child<container>" } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct qom_list {
+    #[serde(rename = "path")]
+    pub path: String,
+}
+
+impl Command for qom_list {
+    const NAME: &'static str = "qom-list";
+
+    type Res = Vec<ObjectPropertyInfo>;
+
+    fn back(self) -> Vec<ObjectPropertyInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectPropertyInfo {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "type")]
+    pub prop_type: String,
+}
+
+/// qom-get
+///
+/// Get the value of a property of an object in QOM tree.
+///
+/// # Arguments
+///
+/// * `path` - Absolute path of object.
+/// * `property` - Name of property.
+///
+/// # Returns
+///
+/// The value of property, the value of child is its path.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "qom-get",
+///      "arguments": { "path": "/machine/unattached/device[0]",
+///                     "property": "thread-id" } }
+/// <- { "return": 3134 }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct qom_get {
+    #[serde(rename = "path")]
+    pub path: String,
+    #[serde(rename = "property")]
+    pub property: String,
+}
+
+impl Command for qom_get {
+    const NAME: &'static str = "qom-get";
+
+    type Res = Any;
+
+    fn back(self) -> Any {
+        Default::default()
+    }
+}
+
+/// Information of block device, reported by human monitor `info block`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockInfo {
+    #[serde(rename = "device")]
+    pub device: String,
+    #[serde(rename = "file")]
+    pub file: String,
+    #[serde(rename = "read-only")]
+    pub read_only: bool,
+    #[serde(rename = "direct")]
+    pub direct: bool,
+    #[serde(
+        rename = "attached-to",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub attached_to: Option<String>,
+    #[serde(rename = "removing")]
+    pub removing: bool,
+}
+
+/// Information of network device, reported by human monitor `info network`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NetInfo {
+    #[serde(rename = "id")]
+    pub id: String,
+    #[serde(rename = "ifname", default, skip_serializing_if = "Option::is_none")]
+    pub ifname: Option<String>,
+    #[serde(rename = "fd", default, skip_serializing_if = "Option::is_none")]
+    pub fd: Option<i32>,
+    #[serde(rename = "mac", default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(rename = "vhost")]
+    pub vhost: bool,
+    #[serde(
+        rename = "attached-to",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub attached_to: Option<String>,
+    #[serde(rename = "removing")]
+    pub removing: bool,
+}
+
+/// Information of memory region, reported by human monitor `info mtree`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryRegionInfo {
+    #[serde(rename = "address-space")]
+    pub address_space: String,
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "addr")]
+    pub addr: u64,
+    #[serde(rename = "size")]
+    pub size: u64,
+    #[serde(rename = "ram")]
+    pub ram: bool,
+}
+
+/// SHUTDOWN
+///
+/// Emitted when the virtual machine has shut down, indicating that StratoVirt is
+/// about to exit.
+///
+/// # Notes
+///
+/// If the command-line option "-no-shutdown" has been specified, StratoVirt
+/// will not exit, and a STOP event will eventually follow the SHUTDOWN event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SHUTDOWN {
+    /// If true, the shutdown was triggered by a guest request (such as
+    /// a guest-initiated ACPI shutdown request or other hardware-specific
+    /// action) rather than a host request (such as sending StratoVirt a SIGINT).
+    #[serde(rename = "guest")]
+    pub guest: bool,
+    pub reason: String,
+}
+
+impl Event for SHUTDOWN {
+    const NAME: &'static str = "SHUTDOWN";
+}
+
+/// RESET
+///
+/// Emitted when the virtual machine is reset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RESET {
+    /// If true, the reset was triggered by a guest request (such as
     /// a guest-initiated ACPI reboot request or other hardware-specific action
     /// ) rather than a host request (such as the QMP command system_reset).
     #[serde(rename = "guest")]
@@ -763,9 +2182,9 @@ impl Event for RESUME {
 
 /// DEVICE_DELETED
 ///
-/// Emitted whenever the device removal completion is acknowledged by the guest.
-/// At this point, it's safe to reuse the specified device ID. Device removal can
-/// be initiated by the guest or by HMP/QMP commands.
+/// Emitted whenever the device removal is complete, that is, the IO in flight
+/// is drained and the backend is closed. At this point, it's safe to reuse the
+/// specified device ID.
 ///
 /// # Examples
 ///
@@ -789,6 +2208,70 @@ impl Event for DEVICE_DELETED {
     const NAME: &'static str = "DEVICE_DELETED";
 }
 
+/// POWERDOWN
+///
+/// Emitted when the virtual machine is powered down through the power control
+/// system, such as via ACPI.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "POWERDOWN",
+///      "data": {},
+///      "timestamp": { "seconds": 1267040730, "microseconds": 682951 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct POWERDOWN {}
+
+impl Event for POWERDOWN {
+    const NAME: &'static str = "POWERDOWN";
+}
+
+/// RTC_CHANGE
+///
+/// Emitted when the guest changes the RTC time. The events are rate-limited,
+/// at most one is emitted per second with the latest offset.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "RTC_CHANGE",
+///      "data": { "offset": 78 },
+///      "timestamp": { "seconds": 1267020223, "microseconds": 435656 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RTC_CHANGE {
+    /// Offset in seconds between guest RTC and host clock.
+    #[serde(rename = "offset")]
+    pub offset: i64,
+}
+
+impl Event for RTC_CHANGE {
+    const NAME: &'static str = "RTC_CHANGE";
+}
+
+/// MIGRATION
+///
+/// Emitted when the status of migration changes, see `query-migrate`.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "MIGRATION",
+///      "data": { "status": "completed" },
+///      "timestamp": { "seconds": 1267020223, "microseconds": 435656 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct MIGRATION {
+    /// Status of migration.
+    #[serde(rename = "status")]
+    pub status: String,
+}
+
+impl Event for MIGRATION {
+    const NAME: &'static str = "MIGRATION";
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "event")]
 pub enum QmpEvent {
@@ -816,4 +2299,20 @@ pub enum QmpEvent {
         data: DEVICE_DELETED,
         timestamp: TimeStamp,
     },
+    #[serde(rename = "POWERDOWN")]
+    POWERDOWN {
+        #[serde(default)]
+        data: POWERDOWN,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "RTC_CHANGE")]
+    RTC_CHANGE {
+        data: RTC_CHANGE,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "MIGRATION")]
+    MIGRATION {
+        data: MIGRATION,
+        timestamp: TimeStamp,
+    },
 }
@@ -13,24 +13,99 @@
 use serde::Deserialize;
 use std::io;
 use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 
 use util::epoll_context::{EventNotifier, EventNotifierHelper, NotifierOperation};
 use vmm_sys_util::epoll::EventSet;
 
-use super::errors::Result;
+use super::errors::{Error, ErrorKind, Result};
 use crate::machine::MachineExternalInterface;
 #[cfg(feature = "qmp")]
 use crate::{
     qmp::qmp_schema::QmpEvent,
-    qmp::{QmpChannel, QmpGreeting, Response},
+    qmp::{
+        QmpGreeting, QmpMonitor, Response, QEMU_VERSION_MAJOR, QEMU_VERSION_MICRO,
+        QEMU_VERSION_MINOR, QMP_CHANNEL,
+    },
 };
 
-const MAX_SOCKET_MSG_LENGTH: usize = 8192;
+/// Default max length of message received, and of the bytes pending to be
+/// sent to client.
+pub const DEFAULT_MAX_MSG_LENGTH: usize = 1 << 20;
+/// Max number of file descriptors received in one SCM_RIGHTS message, the
+/// excess ones are closed by kernel.
+pub const MAX_SCM_FDS: usize = 16;
+
+/// Credentials of the peer process of unix socket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCred {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Get the credentials of the peer process of unix socket `fd`, which are
+/// taken when the peer connects.
+///
+/// # Errors
+///
+/// `fd` is not a connected unix socket.
+pub fn get_peer_cred(fd: RawFd) -> io::Result<PeerCred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // Safe because `cred` and `len` are valid for writing, and the length of
+    // `cred` is checked by kernel.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+/// Which peers of unix socket are allowed to connect, by their uid or gid.
+/// Everyone is allowed if both lists are empty.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AccessPolicy {
+    pub allow_uids: Vec<u32>,
+    pub allow_gids: Vec<u32>,
+}
+
+impl AccessPolicy {
+    /// Whether any allow list is set.
+    pub fn is_restricted(&self) -> bool {
+        !self.allow_uids.is_empty() || !self.allow_gids.is_empty()
+    }
 
-/// The wrapper over Unix socket and socket handler.
+    /// Whether the peer with `cred` is allowed, its uid or gid is in the
+    /// allow lists.
+    pub fn allows(&self, cred: &PeerCred) -> bool {
+        !self.is_restricted()
+            || self.allow_uids.contains(&cred.uid)
+            || self.allow_gids.contains(&cred.gid)
+    }
+}
+
+/// The wrapper over Unix or TCP socket and socket handler.
 ///
 /// # Example
 ///
@@ -57,11 +132,21 @@ pub struct Socket {
     /// Type for Socket
     sock_type: SocketType,
     /// Socket listener tuple
-    listener: UnixListener,
+    listener: SocketListener,
     /// Socket stream with RwLock
     stream: RwLock<Option<SocketStream>>,
     /// Perform socket command
     performer: Option<Arc<dyn MachineExternalInterface>>,
+    /// Whether the stream is monitored for writable, while messages are
+    /// left by short writes.
+    writable_monitored: AtomicBool,
+    /// Max length of message received, and of the bytes pending to be sent.
+    max_msg_len: usize,
+    /// Peers allowed to connect to unix socket.
+    access: AccessPolicy,
+    /// The monitor which accepted stream is bound to.
+    #[cfg(feature = "qmp")]
+    channel: Arc<QmpMonitor>,
 }
 
 impl Socket {
@@ -77,37 +162,170 @@ impl Socket {
     ) -> Self {
         Socket {
             sock_type: SocketType::Unix,
-            listener,
+            listener: SocketListener::Unix(listener),
             stream: RwLock::new(None),
             performer,
+            writable_monitored: AtomicBool::new(false),
+            max_msg_len: DEFAULT_MAX_MSG_LENGTH,
+            access: AccessPolicy::default(),
+            #[cfg(feature = "qmp")]
+            channel: QMP_CHANNEL.clone(),
         }
     }
 
+    /// Allocates a new `Socket` with `TcpListener`.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - The `TcpListener` bind to `Socket`.
+    /// * `performer` - The `VM` to perform socket command.
+    pub fn from_tcp_listener(
+        listener: TcpListener,
+        performer: Option<Arc<dyn MachineExternalInterface>>,
+    ) -> Self {
+        Socket {
+            sock_type: SocketType::Tcp,
+            listener: SocketListener::Tcp(listener),
+            stream: RwLock::new(None),
+            performer,
+            writable_monitored: AtomicBool::new(false),
+            max_msg_len: DEFAULT_MAX_MSG_LENGTH,
+            access: AccessPolicy::default(),
+            #[cfg(feature = "qmp")]
+            channel: QMP_CHANNEL.clone(),
+        }
+    }
+
+    /// Set the max length of message received from client, which also
+    /// limits the bytes pending to be sent to client. It takes effect from
+    /// the next accepted stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_msg_len` - Max length in bytes.
+    pub fn set_max_msg_len(&mut self, max_msg_len: usize) {
+        self.max_msg_len = max_msg_len;
+    }
+
+    /// Set the peers allowed to connect to unix socket, the others are
+    /// rejected on accept.
+    ///
+    /// # Arguments
+    ///
+    /// * `access` - The allow lists of uid and gid.
+    pub fn set_access_policy(&mut self, access: AccessPolicy) {
+        self.access = access;
+    }
+
+    /// Set the monitor which accepted stream is bound to, instead of global
+    /// `QMP_CHANNEL`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The monitor.
+    #[cfg(feature = "qmp")]
+    pub fn set_qmp_channel(&mut self, channel: Arc<QmpMonitor>) {
+        self.channel = channel;
+    }
+
     /// Get listener's fd from `Socket`.
     pub fn get_listener_fd(&self) -> RawFd {
-        self.listener.as_raw_fd()
+        match &self.listener {
+            SocketListener::Unix(listener) => listener.as_raw_fd(),
+            SocketListener::Tcp(listener) => listener.as_raw_fd(),
+        }
     }
 
     /// Accept stream and bind to Socket.
-    pub fn accept(&self) {
+    ///
+    /// # Notes
+    ///
+    /// Return false if the peer of unix stream is rejected by the access
+    /// policy, then it's closed without being bound.
+    pub fn accept(&self) -> bool {
         match self.sock_type {
             SocketType::Unix => {
                 let stream = self.accept_unix_stream();
+                if !self.check_peer(&stream) {
+                    return false;
+                }
                 self.bind_unix_stream(stream);
             }
+            SocketType::Tcp => {
+                let stream = self.accept_tcp_stream();
+                self.bind_tcp_stream(stream);
+            }
         }
 
         #[cfg(feature = "qmp")]
         {
-            QmpChannel::bind_writer(SocketRWHandler::new(self.get_stream_fd()));
+            let mut writer = SocketRWHandler::new(self.get_stream_fd());
+            writer.set_max_pending(self.max_msg_len);
+            self.channel.bind_writer(writer);
             self.send_response(true);
         }
+        true
+    }
+
+    /// Check the credentials of the peer of `stream` against the access
+    /// policy. The rejected peer is sent an error before being closed.
+    fn check_peer(&self, stream: &UnixStream) -> bool {
+        let cred = match get_peer_cred(stream.as_raw_fd()) {
+            Ok(cred) => cred,
+            Err(e) => {
+                error!("Failed to get credentials of api peer: {}", e);
+                return !self.access.is_restricted();
+            }
+        };
+        if self.access.allows(&cred) {
+            info!(
+                "Accept api connection from pid {} uid {} gid {}",
+                cred.pid, cred.uid, cred.gid
+            );
+            return true;
+        }
+
+        warn!(
+            "Reject api connection from pid {} uid {} gid {}",
+            cred.pid, cred.uid, cred.gid
+        );
+        #[cfg(feature = "qmp")]
+        {
+            let err = crate::qmp::qmp_schema::QmpErrorClass::GenericError(
+                "Permission denied".to_string(),
+            );
+            if let Ok(resp) = Response::create_error_response(err, None) {
+                let mut stream = stream;
+                if let Err(e) = writeln!(stream, "{}", serde_json::to_string(&resp).unwrap()) {
+                    error!("Failed to send error to rejected api peer: {}", e);
+                }
+            }
+        }
+        false
     }
 
     /// Accept a new incoming connection unix stream from unix listener.
     pub fn accept_unix_stream(&self) -> UnixStream {
-        let (stream, _) = self.listener.accept().unwrap();
-        stream
+        match &self.listener {
+            SocketListener::Unix(listener) => listener.accept().unwrap().0,
+            SocketListener::Tcp(_) => panic!("Failed to accept unix stream from tcp listener!"),
+        }
+    }
+
+    /// Accept a new incoming connection tcp stream from tcp listener.
+    pub fn accept_tcp_stream(&self) -> TcpStream {
+        match &self.listener {
+            SocketListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().unwrap();
+                info!("Accept tcp connection from {}", addr);
+                // Responses and events are small messages, send them at once.
+                if let Err(e) = stream.set_nodelay(true) {
+                    error!("Failed to set nodelay for tcp stream: {}", e);
+                }
+                stream
+            }
+            SocketListener::Unix(_) => panic!("Failed to accept tcp stream from unix listener!"),
+        }
     }
 
     /// Get socket type from `Socket`.
@@ -121,7 +339,17 @@ impl Socket {
     ///
     /// * `unix_stream` - The `UnixStream` bind to `Socket`.
     pub fn bind_unix_stream(&self, unix_stream: UnixStream) {
-        let stream = SocketStream::from_unix_stream(unix_stream);
+        let stream = SocketStream::from_unix_stream(unix_stream, self.max_msg_len);
+        *self.stream.write().unwrap() = Some(stream);
+    }
+
+    /// Bind `Socket` with a `TcpStream`.
+    ///
+    /// # Arguments
+    ///
+    /// * `tcp_stream` - The `TcpStream` bind to `Socket`.
+    pub fn bind_tcp_stream(&self, tcp_stream: TcpStream) {
+        let stream = SocketStream::from_tcp_stream(tcp_stream, self.max_msg_len);
         *self.stream.write().unwrap() = Some(stream);
     }
 
@@ -159,12 +387,15 @@ impl Socket {
         if self.is_connected() {
             let mut handler = self.get_socket_handler();
             let event_str = serde_json::to_string(&event).unwrap();
-            handler.send_str(&event_str).unwrap();
-            info!("EVENT: --> {:?}", event);
+            match handler.send_str(&event_str) {
+                Ok(_) => info!("EVENT: --> {:?}", event),
+                Err(e) => error!("Failed to send event {:?}: {}", event, e),
+            }
         }
     }
 
-    /// In qmp feature, send empty or greeting response to client.
+    /// In qmp feature, send empty or greeting response to client, in the
+    /// same queue as events if the writer is bound.
     ///
     /// # Arguments
     ///
@@ -174,13 +405,145 @@ impl Socket {
         if self.is_connected() {
             let mut handler = self.get_socket_handler();
             let resp = if is_greeting {
-                serde_json::to_string(&QmpGreeting::create_greeting(1, 0, 4)).unwrap()
+                serde_json::to_string(&QmpGreeting::create_greeting(
+                    QEMU_VERSION_MICRO,
+                    QEMU_VERSION_MINOR,
+                    QEMU_VERSION_MAJOR,
+                ))
+                .unwrap()
             } else {
                 serde_json::to_string(&Response::create_empty_response()).unwrap()
             };
-            handler.send_str(&resp).unwrap();
-            info!("QMP: --> {:?}", resp);
+            match self.channel.send_response(&mut handler, &resp, false) {
+                Ok(_) => info!("QMP: --> {:?}", resp),
+                Err(e) => error!("Failed to send {:?}: {}", resp, e),
+            }
+        }
+    }
+
+    /// Handle the event of accepted stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event set of stream.
+    ///
+    /// # Notes
+    ///
+    /// If the peer hangs up, closes the stream or the stream is broken, the
+    /// connection is cleaned up, and the notifier which removes the stream
+    /// and re-arms the listener is returned. Otherwise the notifier which
+    /// monitors the stream for writable is returned if messages are left by
+    /// short writes, or which stops monitoring once they're all sent.
+    pub fn handle_stream_event(&self, event: EventSet) -> Option<Vec<EventNotifier>> {
+        let mut disconnected = event & EventSet::HANG_UP == EventSet::HANG_UP;
+
+        #[cfg(feature = "qmp")]
+        {
+            if !disconnected && event & EventSet::OUT == EventSet::OUT {
+                if let Err(e) = self.channel.flush_writer() {
+                    error!("Failed to send pending messages: {}", e);
+                    disconnected = true;
+                }
+            }
+        }
+
+        if !disconnected && event & EventSet::IN == EventSet::IN {
+            let stream = self.stream.read().unwrap();
+            let mut handler = stream.as_ref().unwrap().handler.lock().unwrap();
+
+            #[cfg(feature = "qmp")]
+            let ret = crate::qmp::handle_qmp(
+                &mut handler,
+                self.sock_type,
+                &self.channel,
+                self.performer.as_ref().unwrap(),
+            );
+
+            #[cfg(not(feature = "qmp"))]
+            let ret = {
+                handler.stream.clear();
+                handler
+                    .stream
+                    .read_fd(self.max_msg_len)
+                    .map_err(Error::from)
+            };
+
+            drop(handler);
+            drop(stream);
+
+            if let Err(e) = ret {
+                disconnected = is_disconnected(&e);
+                error!("{}", e);
+            }
+        }
+
+        if disconnected {
+            return Some(self.disconnect());
+        }
+        self.update_writable_monitored()
+    }
+
+    /// Send the events queued by emitters, called by main loop when the
+    /// eventfd of monitor is signaled.
+    ///
+    /// # Notes
+    ///
+    /// The notifier which monitors the stream for writable is returned if
+    /// events are left by short writes.
+    #[cfg(feature = "qmp")]
+    pub fn handle_queued_events(&self) -> Option<Vec<EventNotifier>> {
+        self.channel.flush_events();
+        if !self.is_connected() {
+            return None;
+        }
+        self.update_writable_monitored()
+    }
+
+    /// Return the notifier which monitors the stream for writable if
+    /// messages are left by short writes, or which stops monitoring once
+    /// they're all sent, `None` if nothing changes.
+    fn update_writable_monitored(&self) -> Option<Vec<EventNotifier>> {
+        let pending = self.has_pending_output();
+        if self.writable_monitored.swap(pending, Ordering::SeqCst) == pending {
+            return None;
+        }
+        Some(vec![EventNotifier::new(
+            NotifierOperation::Modify,
+            self.get_stream_fd(),
+            None,
+            stream_event_set(pending),
+            Vec::new(),
+        )])
+    }
+
+    /// Whether some messages to client are left by short writes.
+    fn has_pending_output(&self) -> bool {
+        #[cfg(feature = "qmp")]
+        let pending = self.channel.has_pending_output();
+        #[cfg(not(feature = "qmp"))]
+        let pending = false;
+        pending
+    }
+
+    /// Clean up the state of connection, return the notifier which removes
+    /// the stream from event loop and re-arms the listener for next accept.
+    fn disconnect(&self) -> Vec<EventNotifier> {
+        let stream_fd = self.get_stream_fd();
+
+        #[cfg(feature = "qmp")]
+        {
+            self.channel.unbind();
         }
+        self.drop_stream();
+        info!("Client of socket {} is disconnected", stream_fd);
+
+        vec![EventNotifier::new(
+            NotifierOperation::Delete,
+            stream_fd,
+            Some(self.get_listener_fd()),
+            EventSet::IN | EventSet::HANG_UP,
+            Vec::new(),
+        )]
     }
 
     /// Create socket's accepted stream to `event_notifier`.
@@ -189,59 +552,24 @@ impl Socket {
         shared_socket: Arc<Mutex<Self>>,
     ) -> Option<Vec<EventNotifier>> {
         let mut notifiers = Vec::new();
-        self.accept();
+        // The listener keeps monitored for the next peer.
+        if !self.accept() {
+            return None;
+        }
+        // The greeting may be left by short write already.
+        let pending = self.has_pending_output();
+        self.writable_monitored.store(pending, Ordering::SeqCst);
 
         let mut handlers = Vec::new();
         let handler: Box<dyn Fn(EventSet, RawFd) -> Option<Vec<EventNotifier>>> =
-            Box::new(move |event, _| {
-                if event == EventSet::IN {
-                    let socket_mutexed = shared_socket.lock().unwrap();
-                    let stream_fd = socket_mutexed.get_stream_fd();
-
-                    #[cfg(feature = "qmp")]
-                    {
-                        let performer = &socket_mutexed.performer.as_ref().unwrap();
-
-                        if let Err(e) = crate::qmp::handle_qmp(stream_fd, performer) {
-                            error!("{}", e);
-                        }
-                    }
-
-                    #[cfg(not(feature = "qmp"))]
-                    {
-                        if let Err(e) = SocketRWHandler::new(stream_fd).read_fd() {
-                            error!("{}", e);
-                        }
-                    }
-                }
-                if event & EventSet::HANG_UP == EventSet::HANG_UP {
-                    let socket_mutexed = shared_socket.lock().unwrap();
-                    let stream_fd = socket_mutexed.get_stream_fd();
-                    let listener_fd = socket_mutexed.get_listener_fd();
-
-                    #[cfg(feature = "qmp")]
-                    {
-                        QmpChannel::unbind();
-                    }
-
-                    Some(vec![EventNotifier::new(
-                        NotifierOperation::Delete,
-                        stream_fd,
-                        Some(listener_fd),
-                        EventSet::IN | EventSet::HANG_UP,
-                        Vec::new(),
-                    )])
-                } else {
-                    None
-                }
-            });
+            Box::new(move |event, _| shared_socket.lock().unwrap().handle_stream_event(event));
         handlers.push(Arc::new(Mutex::new(handler)));
 
         let notifier = EventNotifier::new(
             NotifierOperation::AddShared,
             self.get_stream_fd(),
             Some(self.get_listener_fd()),
-            EventSet::IN | EventSet::HANG_UP,
+            stream_event_set(pending),
             handlers,
         );
 
@@ -271,30 +599,87 @@ impl EventNotifierHelper for Socket {
 
         notifiers.push(notifier);
 
+        // Events emitted by other threads are sent in main loop.
+        #[cfg(feature = "qmp")]
+        {
+            let socket = shared_socket.clone();
+            let handler: Box<dyn Fn(EventSet, RawFd) -> Option<Vec<EventNotifier>>> =
+                Box::new(move |_, _| socket.lock().unwrap().handle_queued_events());
+            notifiers.push(EventNotifier::new(
+                NotifierOperation::AddShared,
+                shared_socket.lock().unwrap().channel.event_fd(),
+                None,
+                EventSet::IN,
+                vec![Arc::new(Mutex::new(handler))],
+            ));
+        }
+
         notifiers
     }
 }
 
+/// Events monitored on accepted stream, and writable if `pending` messages
+/// are left by short writes.
+fn stream_event_set(pending: bool) -> EventSet {
+    if pending {
+        EventSet::IN | EventSet::OUT | EventSet::HANG_UP
+    } else {
+        EventSet::IN | EventSet::HANG_UP
+    }
+}
+
+/// Whether the error of handling stream means the connection is broken,
+/// such as the peer is closed or the pipe is broken.
+fn is_disconnected(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::Io(_))
+}
+
 /// Type for api socket.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum SocketType {
     Unix = 1,
+    Tcp = 2,
 }
 
-/// Wrapper over UnixSteam.
+/// Listener of api socket.
+enum SocketListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// Accepted stream of api socket, kept to close it on `drop`.
 #[derive(Debug)]
+enum PersistentStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+/// Wrapper over UnixSteam or TcpStream.
 struct SocketStream {
     /// `RawFd` for socket
     socket_fd: RawFd,
-    /// Make `UnixStream` persistent without `drop`
-    persistent: Option<UnixStream>,
+    /// Make stream persistent without `drop`
+    persistent: Option<PersistentStream>,
+    /// Handler of messages received, it keeps the state between reads.
+    handler: Mutex<SocketHandler>,
 }
 
 impl SocketStream {
-    fn from_unix_stream(stream: UnixStream) -> Self {
+    fn from_unix_stream(stream: UnixStream, max_msg_len: usize) -> Self {
+        let socket_fd = stream.as_raw_fd();
         SocketStream {
-            socket_fd: stream.as_raw_fd(),
-            persistent: Some(stream),
+            socket_fd,
+            persistent: Some(PersistentStream::Unix(stream)),
+            handler: Mutex::new(SocketHandler::with_max_len(socket_fd, max_msg_len)),
+        }
+    }
+
+    fn from_tcp_stream(stream: TcpStream, max_msg_len: usize) -> Self {
+        let socket_fd = stream.as_raw_fd();
+        SocketStream {
+            socket_fd,
+            persistent: Some(PersistentStream::Tcp(stream)),
+            handler: Mutex::new(SocketHandler::with_max_len(socket_fd, max_msg_len)),
         }
     }
 }
@@ -327,8 +712,13 @@ pub struct SocketRWHandler {
     buf: Vec<u8>,
     /// Pos to buffer when read and write with fd
     pos: usize,
-    /// Fds when read from fd's scm right
-    scm_fd: Vec<RawFd>,
+    /// Fds when read from fd's scm right, with the index in `buf` of the
+    /// byte which carries them
+    scm_fd: Vec<(usize, RawFd)>,
+    /// Bytes written but not sent yet, because the socket is full
+    pending: Vec<u8>,
+    /// Max number of bytes pending, the socket is shut down beyond it
+    max_pending: usize,
 }
 
 impl SocketRWHandler {
@@ -343,25 +733,45 @@ impl SocketRWHandler {
             buf: Vec::new(),
             pos: 0,
             scm_fd: Vec::new(),
+            pending: Vec::new(),
+            max_pending: DEFAULT_MAX_MSG_LENGTH,
         }
     }
 
     /// Get inner buf as a `String`.
     pub fn get_buf_string(&mut self) -> Result<String> {
-        if self.buf.len() > MAX_SOCKET_MSG_LENGTH {
-            bail!("The socket messege is too long.");
-        }
-
         Ok(String::from_utf8_lossy(&self.buf).trim().to_string())
     }
 
-    /// Get the last file descriptor read from `scm_fd`.
-    pub fn getfd(&mut self) -> Option<RawFd> {
-        if self.scm_fd.is_empty() {
-            None
-        } else {
-            Some(self.scm_fd[self.scm_fd.len() - 1])
-        }
+    /// Set the max number of bytes pending to be sent. If the peer doesn't
+    /// read, and more bytes are left by short writes, the socket is shut
+    /// down so that the connection is closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_pending` - Max number of bytes pending.
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+    }
+
+    /// Send the bytes left by short writes, call it when the socket is
+    /// writable again.
+    ///
+    /// # Errors
+    /// The socket file descriptor is broken.
+    pub fn write_pending(&mut self) -> std::io::Result<()> {
+        self.write_fd()
+    }
+
+    /// Get the number of bytes written but not sent yet.
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Take all the file descriptors read from `scm_fd`, the caller owns
+    /// them and must close the ones not used.
+    pub fn getfds(&mut self) -> Vec<RawFd> {
+        self.scm_fd.drain(..).map(|(_, fd)| fd).collect()
     }
 
     /// Receive bytes and scm_fd from socket file descriptor.
@@ -373,15 +783,25 @@ impl SocketRWHandler {
     /// in a single Control Message.
     /// This function can read both buffer[u8] and fd.
     ///
+    /// # Arguments
+    ///
+    /// * `limit` - Max number of bytes to read, the rest are left in socket.
+    ///
     /// # Errors
-    /// The socket file descriptor is broken.
-    fn read_fd(&mut self) -> std::io::Result<()> {
+    /// The socket file descriptor is broken, or the peer is closed before
+    /// any byte is read.
+    fn read_fd(&mut self, limit: usize) -> std::io::Result<()> {
         use libc::{
-            c_uint, c_void, cmsghdr, iovec, msghdr, recvmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_SPACE,
-            MSG_DONTWAIT, SCM_RIGHTS, SOL_SOCKET,
+            c_uint, c_void, cmsghdr, iovec, msghdr, recvmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_LEN,
+            CMSG_NXTHDR, CMSG_SPACE, MSG_CTRUNC, MSG_DONTWAIT, SCM_RIGHTS, SOL_SOCKET,
         };
 
+        let start = self.pos;
         'read: loop {
+            if self.pos - start >= limit {
+                break 'read;
+            }
+
             let tmp_buf = [0_u8; 1];
             let mut iov = iovec {
                 iov_base: tmp_buf.as_ptr() as *mut c_void,
@@ -391,7 +811,8 @@ impl SocketRWHandler {
             let mut cmsg_space = {
                 let mut space = 0;
                 space +=
-                    unsafe { CMSG_SPACE(std::mem::size_of::<[RawFd; 2]>() as c_uint) } as usize;
+                    unsafe { CMSG_SPACE(std::mem::size_of::<[RawFd; MAX_SCM_FDS]>() as c_uint) }
+                        as usize;
                 Some(Vec::<u8>::with_capacity(space))
             };
 
@@ -424,7 +845,26 @@ impl SocketRWHandler {
                 }
             }
 
-            let cmsg_hdr: Option<&cmsghdr> = unsafe {
+            if ret == 0 {
+                // The peer is closed, the bytes already read are handled
+                // first, and EOF is reported by the next read.
+                if self.pos == start {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The socket peer is closed!",
+                    ));
+                }
+                break 'read;
+            }
+
+            if mhdr.msg_flags & MSG_CTRUNC != 0 {
+                warn!(
+                    "More than {} fds are sent in one message, the excess are dropped",
+                    MAX_SCM_FDS
+                );
+            }
+
+            let mut cmsg_hdr: *const cmsghdr = unsafe {
                 if mhdr.msg_controllen > 0 {
                     cmsg_space
                         .as_mut()
@@ -434,24 +874,22 @@ impl SocketRWHandler {
                 } else {
                     std::ptr::null()
                 }
-                .as_ref()
             };
 
-            if let Some(scm) = cmsg_hdr {
+            // It's safe because the control messages are bounded by
+            // `msg_controllen`, which is checked by `CMSG_NXTHDR`.
+            while let Some(scm) = unsafe { cmsg_hdr.as_ref() } {
                 if scm.cmsg_level == SOL_SOCKET && scm.cmsg_type == SCM_RIGHTS {
-                    let scm_cmsg_header = unsafe {
-                        std::slice::from_raw_parts(
-                            CMSG_DATA(scm),
-                            std::mem::size_of::<[RawFd; 2]>() as usize,
-                        )
-                    };
-                    for fd in scm_cmsg_header.iter() {
-                        if *fd != 0 {
-                            self.scm_fd.push(i32::from(*fd));
-                        }
+                    let data_len = scm.cmsg_len as usize - unsafe { CMSG_LEN(0) } as usize;
+                    let fds = unsafe { CMSG_DATA(scm) } as *const RawFd;
+                    for index in 0..data_len / std::mem::size_of::<RawFd>() {
+                        // The data of control message may be unaligned.
+                        let fd = unsafe { std::ptr::read_unaligned(fds.add(index)) };
+                        self.scm_fd.push((self.buf.len(), fd));
                     }
                 }
-            };
+                cmsg_hdr = unsafe { CMSG_NXTHDR(&mhdr as *const msghdr, scm) };
+            }
 
             self.buf.push(tmp_buf[0]);
             if let Some(pos) = self.pos.checked_add(1) {
@@ -463,47 +901,49 @@ impl SocketRWHandler {
         Ok(())
     }
 
-    /// Send bytes message with socket file descriptor.
+    /// Send the bytes queued in `self::pending` with socket file descriptor.
     ///
     /// # Notes
     /// Use [sendmsg(2)](https://linux.die.net/man/2/sendmsg) to send messages
-    /// to `socket_fd`.
-    /// Message is `self::buf`: Vec<u8> with `self::pos` and length.
-    ///
-    /// # Arguments
-    ///
-    /// * `length` - Length of the buf to write.
+    /// to `socket_fd` without blocking. It loops on short writes, and the
+    /// bytes not sent are kept in order if the socket is full.
     ///
     /// # Errors
-    /// The socket file descriptor is broken.
-    fn write_fd(&mut self, length: usize) -> std::io::Result<()> {
-        use libc::{c_void, iovec, msghdr, sendmsg};
+    /// The socket file descriptor is broken, such as `EPIPE` if the peer is
+    /// closed, or `ECONNRESET` if the peer resets the connection.
+    fn write_fd(&mut self) -> std::io::Result<()> {
+        use libc::{c_void, iovec, msghdr, sendmsg, MSG_DONTWAIT, MSG_NOSIGNAL};
 
-        let mut iov = iovec {
-            iov_base: self.buf.as_slice()[(self.pos - length)..(self.pos - 1)].as_ptr()
-                as *mut c_void,
-            iov_len: length,
-        };
+        while !self.pending.is_empty() {
+            let mut iov = iovec {
+                iov_base: self.pending.as_ptr() as *mut c_void,
+                iov_len: self.pending.len(),
+            };
 
-        // In `musl` toolchain, msghdr has private member `__pad0` and `__pad1`, it can't be
-        // initialized in normal way.
-        let mut mhdr: msghdr = unsafe { std::mem::zeroed() };
-        mhdr.msg_name = std::ptr::null_mut();
-        mhdr.msg_namelen = 0;
-        mhdr.msg_iov = &mut iov as *mut iovec;
-        mhdr.msg_iovlen = 1;
-        mhdr.msg_control = std::ptr::null_mut();
-        mhdr.msg_controllen = 0;
-        mhdr.msg_flags = 0;
-
-        if unsafe { sendmsg(self.socket_fd, &mhdr, 0) } == -1 {
-            Err(io::Error::new(
-                io::ErrorKind::BrokenPipe,
-                "The socket pipe is broken!",
-            ))
-        } else {
-            Ok(())
+            // In `musl` toolchain, msghdr has private member `__pad0` and `__pad1`, it can't be
+            // initialized in normal way.
+            let mut mhdr: msghdr = unsafe { std::mem::zeroed() };
+            mhdr.msg_name = std::ptr::null_mut();
+            mhdr.msg_namelen = 0;
+            mhdr.msg_iov = &mut iov as *mut iovec;
+            mhdr.msg_iovlen = 1;
+            mhdr.msg_control = std::ptr::null_mut();
+            mhdr.msg_controllen = 0;
+            mhdr.msg_flags = 0;
+
+            // MSG_NOSIGNAL: Return EPIPE instead of raising SIGPIPE if the peer is closed.
+            let ret = unsafe { sendmsg(self.socket_fd, &mhdr, MSG_DONTWAIT | MSG_NOSIGNAL) };
+            if ret == -1 {
+                let sock_err = io::Error::last_os_error();
+                match sock_err.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(sock_err),
+                }
+            }
+            self.pending.drain(..ret as usize);
         }
+        Ok(())
     }
 
     /// Reset `SocketRWHandler` buffer and pos.
@@ -517,7 +957,10 @@ impl SocketRWHandler {
 impl Read for SocketRWHandler {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let start = self.pos;
-        self.read_fd()?;
+        match self.read_fd(buf.len()) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+            ret => ret?,
+        }
 
         buf[0..self.pos - start].copy_from_slice(&self.buf[start..self.pos]);
         Ok(self.pos - start)
@@ -533,13 +976,27 @@ impl Write for SocketRWHandler {
             return Err(io::ErrorKind::InvalidInput.into());
         }
 
-        self.write_fd(buf.len())?;
+        // The whole `buf` is accepted, what's not sent is queued.
+        self.pending.extend_from_slice(buf);
+        self.write_fd()?;
+        if self.pending.len() > self.max_pending {
+            // It's safe because the socket fd is valid, and it's closed by
+            // its owner later.
+            unsafe { libc::shutdown(self.socket_fd, libc::SHUT_RDWR) };
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "More than {} bytes are pending to be sent, the peer doesn't read",
+                    self.max_pending
+                ),
+            ));
+        }
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         self.clear();
-        Ok(())
+        self.write_fd()
     }
 }
 
@@ -568,8 +1025,33 @@ impl Write for SocketRWHandler {
 pub struct SocketHandler {
     /// Handler `Read` and `Write` for socket stream
     stream: SocketRWHandler,
+    /// Bytes received but not decoded yet, which may end with a partial
+    /// message
+    input: Vec<u8>,
+    /// File descriptors received, with the index in `input` of the byte
+    /// which carries them
+    input_fds: Vec<(usize, RawFd)>,
     /// Buffer to leave with read result
     buffer: String,
+    /// Max length of message received
+    max_len: usize,
+    /// Whether the rest of a message which is too long is being discarded,
+    /// until the next newline
+    discarding: bool,
+}
+
+/// Where the first message in the input of `SocketHandler` ends.
+#[derive(Debug, PartialEq)]
+enum Framing {
+    /// Only whitespace before the index.
+    Empty(usize),
+    /// The message is not complete yet.
+    Partial,
+    /// A message, or an invalid one, ends before the index.
+    Message(usize),
+    /// The message is longer than the limit, it ends before the index, and
+    /// it's complete or not.
+    TooLong(usize, bool),
 }
 
 impl SocketHandler {
@@ -579,39 +1061,183 @@ impl SocketHandler {
     ///
     /// * `r` - The file descriptor for socket.
     pub fn new(r: RawFd) -> Self {
+        Self::with_max_len(r, DEFAULT_MAX_MSG_LENGTH)
+    }
+
+    /// Allocates a new `SocketHandler` with `socket_fd`, which receives
+    /// messages no longer than `max_len`.
+    ///
+    /// # Arguments
+    ///
+    /// * `r` - The file descriptor for socket.
+    /// * `max_len` - Max length of message received.
+    pub fn with_max_len(r: RawFd, max_len: usize) -> Self {
         SocketHandler {
             stream: SocketRWHandler::new(r),
+            input: Vec::new(),
+            input_fds: Vec::new(),
             buffer: String::new(),
+            max_len,
+            discarding: false,
         }
     }
 
-    /// Parse the bytes received by `SocketHandler`.
+    /// Parse the next message received by `SocketHandler`, and take the
+    /// file descriptors received with it.
     ///
     /// # Notes
-    /// If the bytes ended with '\n', this function will remove it. And then
-    /// parse to Deserialize object.
-    pub fn decode_line<'de, D: Deserialize<'de>>(
-        &'de mut self,
-    ) -> (Result<Option<D>>, Option<RawFd>) {
+    /// Bytes are read from socket only if no complete message is left by
+    /// the last read, so several messages sent at once are decoded one by
+    /// one in order, and a partial message is kept until the rest of it is
+    /// received. Messages are delimited by json itself, whitespace such as
+    /// '\n' between them is skipped.
+    /// The file descriptors are returned even if it fails to parse, the
+    /// caller owns them and must close the ones not used.
+    /// A message longer than `max_len` is discarded with an error, and so
+    /// are the bytes following it until the next newline, where the next
+    /// message starts.
+    pub fn decode_line<'de, D: Deserialize<'de>>(&'de mut self) -> (Result<Option<D>>, Vec<RawFd>) {
         self.buffer.clear();
-        self.stream.clear();
-        self.stream.read_fd().unwrap();
-        match self.stream.get_buf_string() {
-            Ok(buffer) => {
-                self.buffer = buffer;
-                if self.stream.pos == 0 {
-                    (Ok(None), None)
-                } else {
-                    (
-                        serde_json::from_str(&self.buffer)
-                            .map(Some)
-                            .map_err(From::from),
-                        self.stream.getfd(),
+        let mut framing = self.frame();
+        if let Framing::Empty(_) | Framing::Partial = framing {
+            if let Err(e) = self.read_input() {
+                let end = self.input.len();
+                return (Err(e.into()), self.consume(end));
+            }
+            framing = self.frame();
+        }
+
+        match framing {
+            Framing::Empty(end) => (Ok(None), self.consume(end)),
+            Framing::Partial => (Ok(None), Vec::new()),
+            Framing::TooLong(end, complete) => {
+                self.discarding = !complete;
+                (
+                    Err(format!(
+                        "Message is longer than {} bytes, it's discarded until the next newline",
+                        self.max_len
                     )
+                    .into()),
+                    self.consume(end),
+                )
+            }
+            Framing::Message(end) => {
+                self.buffer = String::from_utf8_lossy(&self.input[..end])
+                    .trim()
+                    .to_string();
+                let fds = self.consume(end);
+                (
+                    serde_json::from_str(&self.buffer)
+                        .map(Some)
+                        .map_err(From::from),
+                    fds,
+                )
+            }
+        }
+    }
+
+    /// Whether a complete message, or an invalid one, is left to be decoded
+    /// without reading socket.
+    pub fn has_message(&self) -> bool {
+        match self.frame() {
+            Framing::Empty(_) | Framing::Partial => false,
+            Framing::Message(_) | Framing::TooLong(..) => true,
+        }
+    }
+
+    /// Find where the first message in `input` ends.
+    fn frame(&self) -> Framing {
+        let start = match self
+            .input
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+        {
+            Some(start) => start,
+            None => return Framing::Empty(self.input.len()),
+        };
+
+        let mut values = serde_json::Deserializer::from_slice(&self.input[start..])
+            .into_iter::<serde::de::IgnoredAny>();
+        // Whether the message is complete, and whether its end is found so
+        // that nothing following it needs to be discarded.
+        let (end, complete, delimited) = match values.next() {
+            Some(Ok(_)) => (start + values.byte_offset(), true, true),
+            Some(Err(e)) if e.is_eof() => (self.input.len(), false, false),
+            // The invalid message is dropped until the next newline, where
+            // the next message may start.
+            _ => match self.input[start..].iter().position(|byte| *byte == b'\n') {
+                Some(pos) => (start + pos + 1, true, true),
+                None => (self.input.len(), true, false),
+            },
+        };
+
+        if end - start > self.max_len {
+            Framing::TooLong(end, delimited)
+        } else if complete {
+            Framing::Message(end)
+        } else {
+            Framing::Partial
+        }
+    }
+
+    /// Read at most `max_len + 1` bytes into `input`, so that the message
+    /// longer than `max_len` is found without reading all of it. Bytes being
+    /// discarded are dropped, and not counted, the file descriptors received
+    /// with them are closed.
+    fn read_input(&mut self) -> std::io::Result<()> {
+        self.stream.clear();
+        let limit = self.max_len.saturating_add(1);
+        let ret = self.stream.read_fd(limit);
+        let mut bytes = std::mem::replace(&mut self.stream.buf, Vec::new());
+        let mut fds = std::mem::replace(&mut self.stream.scm_fd, Vec::new());
+        self.stream.clear();
+
+        if self.discarding {
+            let end = match bytes.iter().position(|byte| *byte == b'\n') {
+                Some(pos) => {
+                    self.discarding = false;
+                    pos + 1
                 }
+                None => bytes.len(),
+            };
+            bytes.drain(..end);
+            for (_, fd) in fds.iter().filter(|(pos, _)| *pos < end) {
+                // It's safe because the fd is received and owned here.
+                unsafe { libc::close(*fd) };
+            }
+            fds.retain(|(pos, _)| *pos >= end);
+            for (pos, _) in fds.iter_mut() {
+                *pos -= end;
+            }
+        }
+
+        let base = self.input.len();
+        self.input.extend(bytes);
+        self.input_fds
+            .extend(fds.into_iter().map(|(pos, fd)| (base + pos, fd)));
+        ret
+    }
+
+    /// Remove the first `end` bytes from `input`, and take the file
+    /// descriptors received with them.
+    fn consume(&mut self, end: usize) -> Vec<RawFd> {
+        self.input.drain(..end);
+        let mut fds = Vec::new();
+        let mut rest = Vec::new();
+        for (pos, fd) in self.input_fds.drain(..) {
+            if pos < end {
+                fds.push(fd);
+            } else {
+                rest.push((pos - end, fd));
             }
-            Err(e) => (Err(e), None),
         }
+        self.input_fds = rest;
+        fds
+    }
+
+    /// Get the string last read by `decode_line`.
+    pub fn get_buffer(&self) -> &str {
+        &self.buffer
     }
 
     /// Send String to `socket_fd`.
@@ -623,17 +1249,8 @@ impl SocketHandler {
     /// # Errors
     /// The socket file descriptor is broken.
     pub fn send_str(&mut self, s: &str) -> std::io::Result<()> {
-        self.stream.flush().unwrap();
-        match self.stream.write(s.as_bytes()) {
-            Ok(_) => {
-                let _ = self.stream.write(&[b'\n'])?;
-                Ok(())
-            }
-            Err(_) => Err(io::Error::new(
-                io::ErrorKind::BrokenPipe,
-                "The socket pipe is broken!",
-            )),
-        }
+        self.stream.flush()?;
+        self.stream.write_all(format!("{}\n", s).as_bytes())
     }
 }
 
@@ -646,7 +1263,9 @@ mod tests {
 
     use serde::{Deserialize, Serialize};
 
-    use super::{Socket, SocketHandler, SocketRWHandler, SocketType};
+    use super::{
+        get_peer_cred, AccessPolicy, PeerCred, Socket, SocketHandler, SocketRWHandler, SocketType,
+    };
 
     // Environment Preparation for UnixSocket
     fn prepare_unix_socket_environment(socket_id: &str) -> (UnixListener, UnixStream, UnixStream) {
@@ -667,6 +1286,21 @@ mod tests {
         std::fs::remove_file(&socket_name).unwrap();
     }
 
+    // Shrink the send or receive buffer of socket, so that it's full soon.
+    fn shrink_socket_buffer(fd: RawFd, option: libc::c_int) {
+        let size: libc::c_int = 4096;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &size as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+
     fn socket_basic_rw(client_fd: RawFd, server_fd: RawFd) -> bool {
         // Create `write_handler` and `read_handler` from `client_fd` and `server_fd`
         let mut write_handler = SocketRWHandler::new(client_fd);
@@ -731,6 +1365,36 @@ mod tests {
         recover_unix_socket_environment("01");
     }
 
+    #[test]
+    fn test_socket_short_write() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        shrink_socket_buffer(server.as_raw_fd(), libc::SO_SNDBUF);
+        shrink_socket_buffer(client.as_raw_fd(), libc::SO_RCVBUF);
+        let mut handler = SocketRWHandler::new(server.as_raw_fd());
+
+        // 1.The socket is full, the rest is queued instead of blocking.
+        let message: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        assert_eq!(handler.write(&message).unwrap(), message.len());
+        assert!(handler.pending_len() > 0);
+        assert!(handler.pending_len() < message.len());
+
+        // 2.The queue is sent in order as the peer reads.
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 8192];
+        while received.len() < message.len() {
+            let length = client.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..length]);
+            handler.write_pending().unwrap();
+        }
+        assert_eq!(handler.pending_len(), 0);
+        assert!(received == message);
+
+        // 3.Writing to the closed peer fails with EPIPE, without SIGPIPE.
+        drop(client);
+        let err = handler.write(b"closed").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
     #[test]
     fn test_socket_handler_sendstr() {
         // Pre test. Environment Preparation
@@ -819,6 +1483,99 @@ mod tests {
         recover_unix_socket_environment("03");
     }
 
+    #[test]
+    fn test_socket_handler_max_len() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut handler = SocketHandler::with_max_len(server.as_raw_fd(), 64);
+        let data = r#"{"name":"Lucky Dog","age":18,"phones":[]}"#;
+        let expected = JsonTestStruct {
+            name: "Lucky Dog".to_string(),
+            age: 18u8,
+            phones: Vec::new(),
+        };
+
+        // 1.The message too long is discarded.
+        client.write_all(&[b'x'; 100]).unwrap();
+        match handler.decode_line::<JsonTestStruct>() {
+            (Err(e), _) => assert!(e.to_string().contains("longer than 64 bytes")),
+            _ => panic!("Message too long is decoded!"),
+        }
+
+        // 2.The rest of it is discarded until the next newline, and the
+        // message following it is decoded.
+        client.write_all(&[b'x'; 100]).unwrap();
+        client.write_all(b"\n").unwrap();
+        client.write_all(data.as_bytes()).unwrap();
+        let resp_json = loop {
+            match handler.decode_line::<JsonTestStruct>() {
+                (Ok(Some(resp_json)), _) => break resp_json,
+                // Discarded in pieces no longer than the limit.
+                (Ok(None), _) => continue,
+                _ => panic!("Failed to decode line!"),
+            }
+        };
+        assert_eq!(resp_json, expected);
+
+        // 3.Message no longer than the limit is decoded as usual.
+        client.write_all(data.as_bytes()).unwrap();
+        match handler.decode_line::<JsonTestStruct>() {
+            (Ok(Some(resp_json)), _) => assert_eq!(resp_json, expected),
+            _ => panic!("Failed to decode line!"),
+        }
+    }
+
+    #[test]
+    fn test_peer_cred() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let cred = get_peer_cred(server.as_raw_fd()).unwrap();
+        assert_eq!(cred.pid, std::process::id() as i32);
+        assert_eq!(cred.uid, unsafe { libc::getuid() });
+        assert_eq!(cred.gid, unsafe { libc::getgid() });
+        assert_eq!(get_peer_cred(client.as_raw_fd()).unwrap(), cred);
+
+        // Not a socket.
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(get_peer_cred(file.as_raw_fd()).is_err());
+
+        let cred = PeerCred {
+            pid: 1,
+            uid: 107,
+            gid: 991,
+        };
+        assert!(AccessPolicy::default().allows(&cred));
+        let policy = AccessPolicy {
+            allow_uids: vec![0, 107],
+            allow_gids: Vec::new(),
+        };
+        assert!(policy.allows(&cred));
+        let policy = AccessPolicy {
+            allow_uids: vec![0],
+            allow_gids: vec![991],
+        };
+        assert!(policy.allows(&cred));
+        let policy = AccessPolicy {
+            allow_uids: vec![0],
+            allow_gids: vec![0],
+        };
+        assert!(policy.is_restricted());
+        assert!(!policy.allows(&cred));
+    }
+
+    #[test]
+    fn test_socket_max_pending() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        shrink_socket_buffer(server.as_raw_fd(), libc::SO_SNDBUF);
+        shrink_socket_buffer(client.as_raw_fd(), libc::SO_RCVBUF);
+        let mut handler = SocketRWHandler::new(server.as_raw_fd());
+        handler.set_max_pending(1024);
+
+        // The peer doesn't read, the socket is shut down once too many bytes
+        // are pending, and the peer reads EOF after the bytes sent.
+        assert!(handler.write(&[0u8; 64 * 1024]).is_err());
+        let mut buffer = [0u8; 8192];
+        while client.read(&mut buffer).unwrap() > 0 {}
+    }
+
     #[test]
     fn test_socket_lifecycle() {
         // Pre test. Environment Preparation
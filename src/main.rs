@@ -12,24 +12,35 @@
 
 #[macro_use]
 extern crate error_chain;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate vmm_sys_util;
 
-use std::os::unix::fs::OpenOptionsExt;
+use std::net::TcpListener;
 use std::os::unix::net::UnixListener;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use vmm_sys_util::terminal::Terminal;
 
-use device_model::cmdline::{check_api_channel, create_args_parser, create_vmconfig};
-use device_model::{register_seccomp, LightMachine, MainLoop};
+use device_model::cmdline::{
+    check_api_channel, create_args_parser, create_vmconfig, dump_vmconfig, parse_incoming,
+    parse_reboot_action, Incoming,
+};
+use device_model::{
+    register_seccomp, LightMachine, MainLoop, SeccompFragment, SeccompMode, SeccompPolicy,
+    ThreadRole,
+};
 use machine_manager::config::VmConfig;
 #[cfg(feature = "qmp")]
 use machine_manager::qmp::QmpChannel;
-use machine_manager::socket::Socket;
+use machine_manager::socket::{Socket, SocketType};
+use util::cleanup::{register_cleanup_hook, run_cleanup_hooks};
 use util::epoll_context::EventNotifierHelper;
-use util::unix::limit_permission;
+use util::pidfile::PidFile;
+use util::signal::{ignore_sigpipe, SignalFd};
+use util::unix::{limit_permission, set_thread_name};
 use util::{arg_parser, daemonize::daemonize, logger};
 
 error_chain! {
@@ -45,27 +56,46 @@ error_chain! {
 
 quick_main!(run);
 
+/// Default timeout of graceful shutdown after receiving SIGTERM or SIGINT.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
 fn run() -> Result<()> {
     let cmd_args = create_args_parser().get_matches()?;
 
+    let log_level = match cmd_args.value_of("log-level") {
+        Some(level) => Some(
+            level
+                .parse::<log::Level>()
+                .chain_err(|| format!("Invalid log level {}", level))?,
+        ),
+        None => None,
+    };
     if let Some(logfile_path) = cmd_args.value_of("display log") {
         if logfile_path.is_empty() {
-            logger::init_logger_with_env(Some(Box::new(std::io::stdout())))
-                .chain_err(|| "Failed to init logger.")?;
+            if cmd_args.is_present("daemonize") {
+                bail!("Log can't be output to stdio with -daemonize, give a log file instead");
+            }
+            logger::init_logger(log_level, None).chain_err(|| "Failed to init logger.")?;
         } else {
-            let logfile = std::fs::OpenOptions::new()
-                .read(false)
-                .write(true)
-                .append(true)
-                .create(true)
-                .mode(0o640)
-                .open(logfile_path)
-                .chain_err(|| "Failed to open log file")?;
-            logger::init_logger_with_env(Some(Box::new(logfile)))
+            logger::init_logger(log_level, Some(&logfile_path))
                 .chain_err(|| "Failed to init logger.")?;
         }
     }
 
+    if cmd_args.is_present("dump-config") {
+        println!("{}", dump_vmconfig(&cmd_args)?);
+        return Ok(());
+    }
+    if cmd_args.value_of("seccomp").as_deref() == Some("dump") {
+        let vm_config = create_vmconfig(&cmd_args)?;
+        let fragments = SeccompFragment::from_config(&vm_config);
+        print!(
+            "{}",
+            SeccompPolicy::new(SeccompMode::Enforce, fragments).dump()
+        );
+        return Ok(());
+    }
+
     std::panic::set_hook(Box::new(|panic_msg| {
         std::io::stdin()
             .lock()
@@ -84,10 +114,7 @@ fn run() -> Result<()> {
     match real_main(&cmd_args) {
         Ok(()) => info!("MainLoop over, Vm exit"),
         Err(ref e) => {
-            std::io::stdin()
-                .lock()
-                .set_canon_mode()
-                .expect("Failed to set terminal to canon mode.");
+            run_cleanup_hooks();
             error!("{}", error_chain::ChainedError::display_chain(e));
         }
     }
@@ -97,49 +124,165 @@ fn run() -> Result<()> {
 
 fn real_main(cmd_args: &arg_parser::ArgMatches) -> Result<()> {
     let vm_config: VmConfig = create_vmconfig(cmd_args)?;
+    if let Some(name) = vm_config.machine_config.vm_name() {
+        logger::set_log_name(name);
+        set_thread_name(name);
+    }
     info!("VmConfig is {:?}", vm_config);
 
-    if cmd_args.is_present("daemonize") {
-        match daemonize(cmd_args.value_of("pidfile")) {
-            Ok(()) => info!("Daemonize mode start!"),
-            Err(e) => error!("Daemonize start failed: {}", e),
-        }
+    let daemon_notifier = if cmd_args.is_present("daemonize") {
+        let notifier = daemonize().chain_err(|| "Failed to daemonize")?;
+        info!("Daemonize mode start!");
+        Some(notifier)
     } else {
         std::io::stdin()
             .lock()
             .set_raw_mode()
             .chain_err(|| "Failed to set terminal to raw mode.")?;
+        register_cleanup_hook(
+            "terminal",
+            Box::new(|| {
+                if let Err(e) = std::io::stdin().lock().set_canon_mode() {
+                    error!("Failed to reset stdin to canonical mode: {}", e);
+                }
+            }),
+        );
+        None
+    };
+
+    // The pid is final after daemonizing, and no guest runs if the pid file
+    // can't be created.
+    if let Some(path) = cmd_args.value_of("pidfile") {
+        let pid_file =
+            PidFile::create(&path).chain_err(|| format!("Failed to create pid file {}", path))?;
+        register_cleanup_hook("pid file", Box::new(move || pid_file.remove()));
     }
 
+    // Block the signals before any thread is spawned, so that they are only
+    // received from signalfd in main loop.
+    ignore_sigpipe()?;
+    let signal_fd = SignalFd::new(&[libc::SIGTERM, libc::SIGINT, libc::SIGHUP])?;
+    let shutdown_timeout = match cmd_args.value_of("shutdown-timeout") {
+        Some(timeout) => timeout
+            .parse::<u64>()
+            .chain_err(|| format!("Invalid shutdown timeout {}", timeout))?,
+        None => DEFAULT_SHUTDOWN_TIMEOUT,
+    };
+
     #[cfg(feature = "qmp")]
-    QmpChannel::object_init();
+    {
+        QmpChannel::object_init();
+        if let Some(depth) = cmd_args.value_of("qmp-event-buffer") {
+            let depth = depth
+                .parse::<usize>()
+                .chain_err(|| format!("Invalid QMP event buffer depth {}", depth))?;
+            QmpChannel::set_event_buffer_depth(depth);
+        }
+    }
     MainLoop::object_init();
 
-    let vm = LightMachine::new(vm_config)?;
+    let seccomp = if cmd_args.is_present("disable-seccomp") {
+        SeccompMode::Off
+    } else {
+        match cmd_args.value_of("seccomp") {
+            Some(mode) => mode.parse::<SeccompMode>()?,
+            None => SeccompMode::Enforce,
+        }
+    };
+    match seccomp {
+        SeccompMode::Off => warn!(
+            "Seccomp is off, all syscalls are allowed. Don't run StratoVirt like this in production!"
+        ),
+        SeccompMode::Log => warn!("Seccomp is in log mode, syscalls not allowed are only logged"),
+        SeccompMode::Enforce => (),
+    }
+
+    // Chardev of api-channel is looked up before `vm_config` is consumed.
+    let api_channel = check_api_channel(&cmd_args, &vm_config)?;
+    let vm = LightMachine::new(vm_config, parse_reboot_action(cmd_args)?)?;
     MainLoop::set_manager(vm.clone());
 
-    let api_socket = {
-        let (api_path, _) = check_api_channel(&cmd_args)?;
-        let listener = UnixListener::bind(&api_path)?;
-        limit_permission(&api_path)?;
-        Socket::from_unix_listener(listener, Some(vm.clone()))
+    let mut api_socket = {
+        let (api_path, api_type, access) = api_channel;
+        match api_type {
+            SocketType::Unix => {
+                let listener = UnixListener::bind(&api_path)?;
+                // Working directory is changed when the daemon is ready.
+                let socket_path = std::env::current_dir()?.join(&api_path);
+                register_cleanup_hook(
+                    "api socket",
+                    Box::new(move || {
+                        if let Err(e) = std::fs::remove_file(&socket_path) {
+                            error!(
+                                "Failed to remove api socket {}: {}",
+                                socket_path.display(),
+                                e
+                            );
+                        }
+                    }),
+                );
+                limit_permission(&api_path)?;
+                let mut socket = Socket::from_unix_listener(listener, Some(vm.clone()));
+                socket.set_access_policy(access);
+                socket
+            }
+            SocketType::Tcp => {
+                // SO_REUSEADDR is set by `TcpListener::bind`, so that the
+                // address can be bound again right after the last VM exits.
+                let listener = TcpListener::bind(&api_path)?;
+                info!("Api channel is listening on tcp {}", api_path);
+                Socket::from_tcp_listener(listener, Some(vm.clone()))
+            }
+        }
     };
+    if let Some(len) = cmd_args.value_of("qmp-max-msg-len") {
+        let max_msg_len = len
+            .parse::<usize>()
+            .chain_err(|| format!("Invalid QMP max message length {}", len))?;
+        if max_msg_len == 0 {
+            bail!("QMP max message length must be greater than 0");
+        }
+        api_socket.set_max_msg_len(max_msg_len);
+    }
 
     MainLoop::update_event(EventNotifierHelper::internal_notifiers(Arc::new(
         Mutex::new(api_socket),
     )))
     .chain_err(|| "Failed to add api event to MainLoop")?;
 
-    vm.realize()?;
+    // io_uring is probed before seccomp is enabled, it's checked by drives
+    // hot-plugged later.
+    if !util::aio::uring_supported() {
+        warn!("io_uring is not available, aio=io_uring of drives is rejected");
+    }
+
+    // VM restored from snapshot or migrated in is paused until `cont`.
+    let incoming = parse_incoming(cmd_args)?;
+    match &incoming {
+        Some(Incoming::Snapshot(path)) => vm.restore(path)?,
+        Some(Incoming::Tcp(addr)) => vm.migrate_incoming(addr)?,
+        Some(Incoming::Defer) => LightMachine::defer_incoming(&vm)?,
+        None => vm.realize()?,
+    }
+    let seccomp = vm.seccomp_policy(seccomp);
     vm.vm_start(
-        cmd_args.is_present("freeze_cpu"),
-        !cmd_args.is_present("disable-seccomp"),
+        cmd_args.is_present("freeze_cpu") || incoming.is_some(),
+        &seccomp,
     )?;
+    LightMachine::register_signal_event(&vm, signal_fd, Duration::from_secs(shutdown_timeout))?;
+    #[cfg(feature = "qmp")]
+    LightMachine::start_migration_thread(&vm, &seccomp)?;
 
-    if !cmd_args.is_present("disable-seccomp") {
-        register_seccomp()?;
+    // The daemon is ready before seccomp is enabled, which forbids changing
+    // working directory.
+    if let Some(notifier) = daemon_notifier {
+        notifier.notify_ready()?;
     }
 
+    // All setup of main thread is done, such as creating VM and binding api
+    // socket, seccomp is enabled before the first epoll_wait of main loop.
+    register_seccomp(ThreadRole::Main, &seccomp)?;
+
     loop {
         if !MainLoop::run().chain_err(|| "MainLoop exits unexpectedly: error occurs")? {
             break;
@@ -28,7 +28,10 @@ use super::errors::Result;
 use super::link_list::{List, Node};
 pub use libaio::*;
 pub use raw::*;
-pub use uring::{UringCmd, UringCb, UringContext, SampleContext};
+pub use uring::{
+    uring_supported, SampleContext, UringCb, UringCmd, UringContext, __NR_IO_URING_ENTER,
+    __NR_IO_URING_REGISTER, __NR_IO_URING_SETUP,
+};
 
 type CbList<T> = List<AioCb<T>>;
 type CbNode<T> = Node<AioCb<T>>;
@@ -178,10 +181,29 @@ impl<T: Clone + 'static> Aio<T> {
                 r
             }
             UringCmd::IORING_OP_FSYNC => raw_datasync(cb.file_fd)?,
+            UringCmd::IORING_OP_NOP => 0,
             _ => -1,
         };
         (self.complete_func)(&cb, ret);
 
         Ok(())
     }
+
+    /// Complete a write of zeroes by zeroing the range, fall back to writing
+    /// if the file system doesn't support it.
+    ///
+    /// # Arguments
+    ///
+    /// * `cb` - The write request, whose buffer is all zero.
+    /// * `unmap` - Whether the blocks of range can be released.
+    pub fn write_zeroes(&mut self, cb: AioCb<T>, unmap: bool) -> Result<()> {
+        let size = cb.iovec.iter().map(|iov| iov.iov_len).sum();
+        match raw_write_zeroes(cb.file_fd, cb.offset, size, unmap) {
+            Ok(ret) => {
+                (self.complete_func)(&cb, ret);
+                Ok(())
+            }
+            Err(_) => self.rw_sync(cb),
+        }
+    }
 }
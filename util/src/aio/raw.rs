@@ -11,7 +11,7 @@
 // See the Mulan PSL v2 for more details.
 
 use super::Result;
-use libc::{c_void, fdatasync, pread, pwrite};
+use libc::{c_void, fallocate, fdatasync, pread, pwrite};
 use std::os::unix::io::RawFd;
 
 pub fn raw_read(fd: RawFd, buf: u64, size: usize, offset: usize) -> Result<i64> {
@@ -40,3 +40,19 @@ pub fn raw_datasync(fd: RawFd) -> Result<i64> {
 
     Ok(ret)
 }
+
+/// Zero the range of file instead of writing zeroes, the blocks are released
+/// if `unmap` is set.
+pub fn raw_write_zeroes(fd: RawFd, offset: usize, size: u64, unmap: bool) -> Result<i64> {
+    let mode = if unmap {
+        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE
+    } else {
+        libc::FALLOC_FL_ZERO_RANGE
+    };
+    let ret = unsafe { i64::from(fallocate(fd, mode, offset as i64, size as i64)) };
+    if ret < 0 {
+        bail!("Failed to fallocate for {}, return {}.", fd, ret);
+    }
+
+    Ok(ret)
+}
@@ -9,6 +9,8 @@ use vmm_sys_util::eventfd::EventFd;
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
 
 pub const __NR_IO_URING_SETUP: i64 = 425;
 pub const __NR_IO_URING_ENTER: i64 = 426;
@@ -159,6 +161,24 @@ pub struct UringContext {
     pub cqes: *mut IoUringCqe,
 }
 
+/// Check whether io_uring is available, it may be disabled by kernel or
+/// container. It's probed only once, so it must be called before seccomp
+/// is enabled.
+pub fn uring_supported() -> bool {
+    static PROBE: Once = Once::new();
+    static SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+    PROBE.call_once(|| {
+        let mut p: IoUringParams = Default::default();
+        let ret = unsafe { syscall(__NR_IO_URING_SETUP, 1, &mut p) };
+        if ret >= 0 {
+            unsafe { close(ret as i32) };
+            SUPPORTED.store(true, Ordering::SeqCst);
+        }
+    });
+    SUPPORTED.load(Ordering::SeqCst)
+}
+
 impl UringContext {
     pub fn new(max_size: i32, fd: &EventFd) -> Result<Self> {
         let mut p: IoUringParams = Default::default();
@@ -0,0 +1,186 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Milestones of VM boot, in the order they are usually reached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootMilestone {
+    /// KVM VM is created.
+    VmCreated = 0,
+    /// Guest memory is mapped and registered to KVM.
+    MemoryReady = 1,
+    /// Devices are realized.
+    DevicesRealized = 2,
+    /// Kernel and initrd are loaded into guest memory.
+    KernelLoaded = 3,
+    /// The first vcpu enters guest.
+    FirstVcpuEntry = 4,
+    /// Guest writes the boot marker, usually when it reaches userspace.
+    GuestReady = 5,
+}
+
+const MILESTONE_NUM: usize = 6;
+
+impl BootMilestone {
+    /// Get the name of milestone, such as `kvm-vm-created`.
+    pub fn name(self) -> &'static str {
+        match self {
+            BootMilestone::VmCreated => "kvm-vm-created",
+            BootMilestone::MemoryReady => "memory-ready",
+            BootMilestone::DevicesRealized => "devices-realized",
+            BootMilestone::KernelLoaded => "kernel-loaded",
+            BootMilestone::FirstVcpuEntry => "first-vcpu-entry",
+            BootMilestone::GuestReady => "guest-ready",
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            0 => BootMilestone::VmCreated,
+            1 => BootMilestone::MemoryReady,
+            2 => BootMilestone::DevicesRealized,
+            3 => BootMilestone::KernelLoaded,
+            4 => BootMilestone::FirstVcpuEntry,
+            _ => BootMilestone::GuestReady,
+        }
+    }
+}
+
+/// Time of a boot milestone reached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BootTime {
+    /// The milestone.
+    pub milestone: BootMilestone,
+    /// Time from the creation of VM to the milestone.
+    pub time: Duration,
+    /// Time from the previous milestone reached, or from the creation of VM
+    /// for the first one.
+    pub delta: Duration,
+}
+
+/// Monotonic time of the boot milestones, relative to the creation of VM.
+/// The time is atomic, so that it's recorded by vcpu threads without locking,
+/// and recording costs no more than reading the clock.
+pub struct BootTimes {
+    /// When VM starts to be created.
+    start: Instant,
+    /// Nanoseconds from `start` to each milestone plus one, 0 means the
+    /// milestone isn't reached.
+    reached: [AtomicU64; MILESTONE_NUM],
+}
+
+impl Default for BootTimes {
+    fn default() -> Self {
+        BootTimes::new()
+    }
+}
+
+impl BootTimes {
+    /// Create `BootTimes` starting from now.
+    pub fn new() -> Self {
+        BootTimes {
+            start: Instant::now(),
+            reached: Default::default(),
+        }
+    }
+
+    /// Record that `milestone` is reached now, only the first time counts.
+    pub fn record(&self, milestone: BootMilestone) {
+        let slot = &self.reached[milestone as usize];
+        if slot.load(Ordering::Relaxed) != 0 {
+            return;
+        }
+        let nanos = self.start.elapsed().as_nanos() as u64 + 1;
+        let _ = slot.compare_exchange(0, nanos, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Get the milestones reached, in the order they are reached.
+    pub fn snapshot(&self) -> Vec<BootTime> {
+        let mut reached: Vec<(BootMilestone, Duration)> = self
+            .reached
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| match slot.load(Ordering::Relaxed) {
+                0 => None,
+                nanos => Some((
+                    BootMilestone::from_index(index),
+                    Duration::from_nanos(nanos - 1),
+                )),
+            })
+            .collect();
+        reached.sort_by_key(|(_, time)| *time);
+
+        let mut previous = Duration::default();
+        reached
+            .into_iter()
+            .map(|(milestone, time)| {
+                let delta = time - previous;
+                previous = time;
+                BootTime {
+                    milestone,
+                    time,
+                    delta,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_times() {
+        let boot_times = BootTimes::new();
+        assert!(boot_times.snapshot().is_empty());
+
+        boot_times.record(BootMilestone::VmCreated);
+        boot_times.record(BootMilestone::MemoryReady);
+        std::thread::sleep(Duration::from_millis(2));
+        boot_times.record(BootMilestone::FirstVcpuEntry);
+        boot_times.record(BootMilestone::DevicesRealized);
+        let first = boot_times.snapshot();
+
+        // Milestones are sorted by time, and the ones not reached are absent.
+        let names: Vec<&str> = first.iter().map(|t| t.milestone.name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "kvm-vm-created",
+                "memory-ready",
+                "first-vcpu-entry",
+                "devices-realized"
+            ]
+        );
+        assert!(first.windows(2).all(|pair| pair[0].time <= pair[1].time));
+        assert!(first[2].delta >= Duration::from_millis(2));
+        // Deltas add up to the time of each milestone.
+        let mut sum = Duration::default();
+        for boot_time in first.iter() {
+            sum += boot_time.delta;
+            assert_eq!(sum, boot_time.time);
+        }
+
+        // Only the first time is kept.
+        boot_times.record(BootMilestone::VmCreated);
+        assert_eq!(boot_times.snapshot(), first);
+
+        boot_times.record(BootMilestone::GuestReady);
+        let last = boot_times.snapshot();
+        assert_eq!(last.len(), 5);
+        assert_eq!(last[4].milestone, BootMilestone::GuestReady);
+        assert_eq!(last[4].delta, last[4].time - last[3].time);
+    }
+}
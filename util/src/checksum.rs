@@ -33,3 +33,82 @@ pub fn obj_checksum<T: ByteCode>(t: &T) -> u8 {
 
     (sum & 0xff) as u8
 }
+
+/// Reversed polynomial of CRC-32/ISO-HDLC, which is used by zlib and gzip.
+const CRC32_POLY: u32 = 0xedb8_8320;
+
+/// Incremental CRC-32 (CRC-32/ISO-HDLC), so that data written in pieces is
+/// checked without being buffered.
+#[derive(Clone)]
+pub struct Crc32 {
+    /// Lookup table of one byte.
+    table: [u32; 256],
+    /// Current value, inverted.
+    value: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        let mut table = [0_u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ CRC32_POLY
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        Crc32 { table, value: !0 }
+    }
+
+    /// Add `data` to the checksum.
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data.iter() {
+            let index = (self.value ^ u32::from(*byte)) & 0xff;
+            self.value = (self.value >> 8) ^ self.table[index as usize];
+        }
+    }
+
+    /// Get the checksum of all data added.
+    pub fn value(&self) -> u32 {
+        !self.value
+    }
+}
+
+/// Get CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+
+        // Data added in pieces has the same checksum.
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xcbf4_3926);
+    }
+}
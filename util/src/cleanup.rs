@@ -0,0 +1,134 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Mutex, Once};
+
+/// Hook releasing external resources, such as resetting terminal or removing
+/// socket file, when the process exits.
+pub type CleanupHook = dyn FnOnce() + Send;
+
+static INIT_CLEANUP_HOOKS: Once = Once::new();
+static mut CLEANUP_HOOKS: Option<Mutex<CleanupHooks>> = None;
+
+/// Registry of cleanup hooks, every hook runs exactly once, in the reverse
+/// order of registration.
+#[derive(Default)]
+pub struct CleanupHooks {
+    hooks: Vec<(String, Box<CleanupHook>)>,
+}
+
+impl CleanupHooks {
+    /// Register a cleanup hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of hook, used in log.
+    /// * `hook` - The hook to run when cleaning up.
+    pub fn register(&mut self, name: &str, hook: Box<CleanupHook>) {
+        self.hooks.push((name.to_string(), hook));
+    }
+
+    /// Take all the registered hooks out in the order they should run, so
+    /// that they are not run again.
+    fn take(&mut self) -> Vec<(String, Box<CleanupHook>)> {
+        let mut hooks = std::mem::replace(&mut self.hooks, Vec::new());
+        hooks.reverse();
+        hooks
+    }
+
+    /// Run all the registered hooks in the reverse order of registration.
+    pub fn run(&mut self) {
+        run_hooks(self.take());
+    }
+}
+
+fn run_hooks(hooks: Vec<(String, Box<CleanupHook>)>) {
+    for (name, hook) in hooks {
+        info!("Run cleanup hook {}", name);
+        hook();
+    }
+}
+
+fn cleanup_hooks() -> &'static Mutex<CleanupHooks> {
+    // It's safe because `CLEANUP_HOOKS` is only written once under `Once`.
+    unsafe {
+        INIT_CLEANUP_HOOKS.call_once(|| {
+            CLEANUP_HOOKS = Some(Mutex::new(CleanupHooks::default()));
+        });
+        CLEANUP_HOOKS.as_ref().unwrap()
+    }
+}
+
+/// Register a cleanup hook of the process, which runs on main loop exit or
+/// on the error-exit path.
+///
+/// # Arguments
+///
+/// * `name` - Name of hook, used in log.
+/// * `hook` - The hook to run when cleaning up.
+pub fn register_cleanup_hook(name: &str, hook: Box<CleanupHook>) {
+    cleanup_hooks().lock().unwrap().register(name, hook);
+}
+
+/// Run all the cleanup hooks of the process in the reverse order of
+/// registration. Hooks which have run are dropped, so calling it more than
+/// once is harmless.
+pub fn run_cleanup_hooks() {
+    // Release the lock before running hooks, so that a hook can register
+    // another one without deadlock, which is run by the next call.
+    let hooks = cleanup_hooks().lock().unwrap().take();
+    run_hooks(hooks);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_cleanup_hooks() {
+        let record = Arc::new(Mutex::new(Vec::new()));
+        let mut hooks = CleanupHooks::default();
+        for i in 0..3 {
+            let record = record.clone();
+            hooks.register(
+                &format!("hook{}", i),
+                Box::new(move || record.lock().unwrap().push(i)),
+            );
+        }
+
+        hooks.run();
+        assert_eq!(*record.lock().unwrap(), vec![2, 1, 0]);
+
+        // Hooks never run twice.
+        hooks.run();
+        assert_eq!(*record.lock().unwrap(), vec![2, 1, 0]);
+
+        let record_clone = record.clone();
+        hooks.register(
+            "hook3",
+            Box::new(move || record_clone.lock().unwrap().push(3)),
+        );
+        hooks.run();
+        assert_eq!(*record.lock().unwrap(), vec![2, 1, 0, 3]);
+
+        let record_clone = record.clone();
+        register_cleanup_hook(
+            "global",
+            Box::new(move || record_clone.lock().unwrap().push(4)),
+        );
+        run_cleanup_hooks();
+        run_cleanup_hooks();
+        assert_eq!(*record.lock().unwrap(), vec![2, 1, 0, 3, 4]);
+    }
+}
@@ -30,35 +30,71 @@
 //! 7. Disassociate from its process group, to insulate itself from signals
 //! sent to the process group.
 //! 8. Handle any `SIGCLD` signals.
+//!
+//! The process which runs StratoVirt at first doesn't exit until the daemon
+//! notifies it by a pipe that StratoVirt is fully initialized, so that its
+//! exit code tells whether StratoVirt starts successfully.
 
 extern crate libc;
 
 use std::cmp::Ordering;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::io::prelude::*;
-use std::os::unix::fs::OpenOptionsExt;
-use std::os::unix::io::RawFd;
-use std::path::Path;
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::process::exit;
 
-use crate::errors::{ErrorKind, Result};
+use crate::errors::{ErrorKind, Result, ResultExt};
+
+/// Byte sent through the pipe when the daemon is ready.
+const DAEMON_READY: u8 = 0;
 
-/// Write process id to pid file.
-fn create_pid_file(path: &str) -> Result<()> {
-    let pid: u32 = std::process::id();
+/// Notifier of the daemon, which tells the waiting process whether the
+/// daemon starts successfully.
+pub struct DaemonNotifier {
+    /// Write end of the pipe to the waiting process.
+    pipe: File,
+}
 
-    if Path::new(path).exists() {
-        return Err(ErrorKind::PidFileExist.into());
+impl DaemonNotifier {
+    /// Notify the waiting process that the daemon is ready, which exits with
+    /// 0 then. The working directory is changed to `/` so that no mounted
+    /// filesystem is kept busy, relative paths given at startup have been
+    /// opened by now.
+    ///
+    /// # Notes
+    ///
+    /// Dropping the notifier without calling it, such as on error, closes the
+    /// pipe, and the waiting process exits with 1.
+    pub fn notify_ready(mut self) -> Result<()> {
+        std::env::set_current_dir("/").chain_err(|| "Failed to change directory to /")?;
+        self.pipe
+            .write_all(&[DAEMON_READY])
+            .chain_err(|| "Failed to notify that daemon is ready")
     }
+}
 
-    let mut pid_file: File = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .mode(0o600)
-        .open(path)?;
-    write!(pid_file, "{}", pid)?;
+/// Create a pipe, both ends are closed on exec.
+fn pipe() -> Result<(File, File)> {
+    let mut fds: [libc::c_int; 2] = [-1; 2];
+    let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error()).chain_err(|| "Failed to create pipe");
+    }
+    // It's safe because the fds are just created and owned by nobody else.
+    unsafe { Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))) }
+}
 
-    Ok(())
+/// Wait for the daemon to be ready, and get the exit code of the waiting
+/// process. The daemon fails if the pipe is closed before it's ready.
+fn wait_for_daemon(mut pipe: File) -> i32 {
+    let mut status = [0_u8; 1];
+    match pipe.read(&mut status) {
+        Ok(1) if status[0] == DAEMON_READY => 0,
+        _ => {
+            eprintln!("StratoVirt failed to start as a daemon, see its log for details.");
+            1
+        }
+    }
 }
 
 /// [fork(2)](https://man7.org/linux/man-pages/man2/fork.2.html)
@@ -67,18 +103,19 @@ fn create_pid_file(path: &str) -> Result<()> {
 /// to as the parent process.
 /// **libc::fork()** may have three kinds ret:
 /// if ret > 0 : current process is parent process, it's not expected, so exit
+/// with the code got by `parent_exit`
 /// if ret < 0 : error occurred in fork()
 /// if ret = 0 : current process is child process, it's expected
 ///
 /// # Errors
 ///
 /// `DaemonFork` Error, the ret of `libc::fork()` is less than zero.
-fn fork() -> Result<()> {
+fn fork<F: FnOnce() -> i32>(parent_exit: F) -> Result<()> {
     let ret = unsafe { libc::fork() };
 
     match ret.cmp(&0) {
         Ordering::Less => Err(ErrorKind::DaemonFork.into()),
-        Ordering::Greater => exit(0),
+        Ordering::Greater => exit(parent_exit()),
         Ordering::Equal => Ok(()),
     }
 }
@@ -138,39 +175,95 @@ fn redirect_stdio(fd: RawFd) -> Result<()> {
 
 /// Daemonize a process.
 ///
-/// # Arguments
-///
-/// * `pid_file` - Path where will create pid file.
-///
 /// # Notes
-/// This function do five things to daemonize a process:
+/// This function do four things to daemonize a process:
 /// 1. Reset its umask value.
 /// 2. Run in the background use fork.
 /// 3. Ignore all terminal I/O signals.
 /// 4. Disassociate from the control terminal.
-/// 5. Write pid to pidfile.
-pub fn daemonize(pid_file: Option<String>) -> Result<()> {
+///
+/// It must be called before any thread is spawned, and the pid is changed
+/// by fork, so pid file is written after it. The process calling it exits
+/// after the returned notifier is notified or dropped by the daemon.
+pub fn daemonize() -> Result<DaemonNotifier> {
+    let (reader, writer) = pipe()?;
+    let mut writer = Some(writer);
     // The first fork make parent process quit, child process inherit parent's
     // session ID and have a new process ID. It can guarantee child
-    // process will not be the first process in a session.
-    fork()?;
+    // process will not be the first process in a session. The parent process
+    // waits for the daemon to be ready before quitting, it closes the write
+    // end of pipe so that it's notified when the daemon exits.
+    fork(|| {
+        writer.take();
+        wait_for_daemon(reader)
+    })?;
     // Create a new session for process. Now parent process quit will not
     // influence stratovirt process. But stratovirt becomes the first process in
     // new section.
     set_sid()?;
     // The second fork make stratovirt run as daemonize process. It won't be the
     // first process in this session and never get terminal control.
-    fork()?;
+    fork(|| 0)?;
     // Redirect stdio to `/dev/null`.
     redirect_stdio(libc::STDIN_FILENO)?;
     redirect_stdio(libc::STDOUT_FILENO)?;
     redirect_stdio(libc::STDERR_FILENO)?;
 
-    // Now can record PID to file. It won't be changed again in stratovirt's
-    // lifetime.
-    if let Some(path) = pid_file {
-        create_pid_file(&path)?;
+    // Only the parent process takes the write end of pipe.
+    Ok(DaemonNotifier {
+        pipe: writer.unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pidfile::PidFile;
+
+    /// Fork a child process of test, which is daemonized and runs `daemon`
+    /// in the daemon. Return the exit code of the child process, which waits
+    /// for the daemon.
+    fn run_daemon<F: FnOnce(DaemonNotifier)>(daemon: F) -> i32 {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // The forked processes never return to the test harness.
+            match daemonize() {
+                Ok(notifier) => daemon(notifier),
+                Err(_) => unsafe { libc::_exit(2) },
+            }
+            unsafe { libc::_exit(0) };
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        libc::WEXITSTATUS(status)
     }
 
-    Ok(())
+    #[test]
+    fn test_daemonize() {
+        let path = std::env::temp_dir()
+            .join(format!("stratovirt-test-daemon-{}.pid", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+
+        // The waiting process exits with 0 after the daemon is ready, and the
+        // pid file records the pid of daemon.
+        let daemon_path = path.clone();
+        let code = run_daemon(move |notifier| {
+            let pid_file = PidFile::create(&daemon_path).unwrap();
+            notifier.notify_ready().unwrap();
+            assert_eq!(std::env::current_dir().unwrap(), std::path::Path::new("/"));
+            std::mem::forget(pid_file);
+        });
+        assert_eq!(code, 0);
+        let daemon_pid = std::fs::read_to_string(&path).unwrap();
+        let daemon_pid = daemon_pid.trim().parse::<u32>().unwrap();
+        assert_ne!(daemon_pid, std::process::id());
+        std::fs::remove_file(&path).unwrap();
+
+        // The daemon fails before it's ready.
+        assert_eq!(run_daemon(drop), 1);
+    }
 }
@@ -0,0 +1,885 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+mod parser;
+
+pub use parser::{Fdt, FdtHeader, FdtNode, FdtProperty};
+
+use std::ffi::CString;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::errors::{Result, ResultExt};
+use libc::{c_char, c_int, c_void};
+
+pub const CLK_PHANDLE: u32 = 1;
+pub const GIC_PHANDLE: u32 = 2;
+pub const GIC_ITS_PHANDLE: u32 = 3;
+pub const GPIO_PHANDLE: u32 = 4;
+pub const CPU_PHANDLE_START: u32 = 10;
+
+pub const GIC_FDT_IRQ_TYPE_SPI: u32 = 0;
+pub const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
+pub const IRQ_TYPE_EDGE_RISING: u32 = 1;
+pub const IRQ_TYPE_EDGE_FALLING: u32 = 2;
+pub const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+pub const IRQ_TYPE_LEVEL_LOW: u32 = 8;
+// Number of SPIs and PPIs defined by GIC architecture, SPI ranges from
+// INTID 32 to 1019 and PPI from INTID 16 to 31.
+const GIC_SPI_NUM: u32 = 988;
+const GIC_PPI_NUM: u32 = 16;
+// Default `#address-cells` and `#size-cells` from devicetree specification.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// Default hard cap of fdt size, which is also the max size of dtb accepted
+/// by arm64 Linux.
+pub const FDT_MAX_SIZE: u32 = 0x20_0000;
+/// Alignment of fdt in guest memory required by Linux.
+pub const FDT_ALIGN: u64 = 8;
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+/// Size of one entry in memory reservation block: address and size in u64.
+pub const FDT_MEM_RSV_ENTRY_SIZE: u64 = 16;
+// Size of `magic` and `totalsize` at the beginning of fdt header.
+const FDT_HEADER_PREFIX_SIZE: usize = 8;
+// Initial size of fdt buffer, it's doubled each time the space runs out.
+const FDT_INIT_SIZE: usize = 0x1000;
+// Error code of libfdt when there is no enough space in buffer.
+const FDT_ERR_NOSPACE: c_int = 3;
+
+static FDT_SIZE_LIMIT: AtomicU32 = AtomicU32::new(FDT_MAX_SIZE);
+
+extern "C" {
+    fn fdt_create(buf: *mut c_void, bufsize: c_int) -> c_int;
+    fn fdt_finish_reservemap(fdt: *mut c_void) -> c_int;
+    fn fdt_begin_node(fdt: *mut c_void, name: *const c_char) -> c_int;
+    fn fdt_end_node(fdt: *mut c_void) -> c_int;
+    fn fdt_finish(fdt: *const c_void) -> c_int;
+    fn fdt_open_into(fdt: *const c_void, buf: *mut c_void, size: c_int) -> c_int;
+    fn fdt_pack(fdt: *mut c_void) -> c_int;
+    fn fdt_add_mem_rsv(fdt: *mut c_void, address: u64, size: u64) -> c_int;
+
+    fn fdt_path_offset(fdt: *const c_void, path: *const c_char) -> c_int;
+    fn fdt_add_subnode(fdt: *mut c_void, offset: c_int, name: *const c_char) -> c_int;
+    fn fdt_del_node(fdt: *mut c_void, offset: c_int) -> c_int;
+    fn fdt_node_offset_by_prop_value(
+        fdt: *const c_void,
+        startoffset: c_int,
+        name: *const c_char,
+        val: *const c_void,
+        len: c_int,
+    ) -> c_int;
+    fn fdt_getprop(
+        fdt: *const c_void,
+        offset: c_int,
+        name: *const c_char,
+        lenp: *mut c_int,
+    ) -> *const c_void;
+    fn fdt_setprop(
+        fdt: *mut c_void,
+        offset: c_int,
+        name: *const c_char,
+        val: *const c_void,
+        len: c_int,
+    ) -> c_int;
+}
+
+/// Set the hard cap of fdt size, building or loading a device tree larger
+/// than it fails.
+///
+/// # Arguments
+///
+/// * `limit` - Max size of fdt in bytes.
+pub fn set_size_limit(limit: u32) {
+    FDT_SIZE_LIMIT.store(limit, Ordering::SeqCst);
+}
+
+/// Get the hard cap of fdt size, which is the space reserved for fdt in
+/// guest memory.
+pub fn size_limit() -> u32 {
+    FDT_SIZE_LIMIT.load(Ordering::SeqCst)
+}
+
+/// Read `totalsize` in fdt header.
+fn totalsize(fdt: &[u8]) -> u32 {
+    let mut size = [0_u8; 4];
+    size.copy_from_slice(&fdt[4..FDT_HEADER_PREFIX_SIZE]);
+    u32::from_be_bytes(size)
+}
+
+/// Double the fdt buffer, the memory reservation block, structure block and
+/// strings block are laid out again by libfdt with the free space after them.
+///
+/// # Errors
+///
+/// Return Error if the buffer already reaches `limit`.
+fn grow(fdt: &mut Vec<u8>, limit: usize) -> Result<()> {
+    if fdt.len() >= limit {
+        bail!("Device tree exceeds the size limit of {} bytes.", limit);
+    }
+    let size = std::cmp::min(std::cmp::max(fdt.len() * 2, FDT_INIT_SIZE), limit);
+    fdt.resize(size, 0);
+    let ret = unsafe {
+        fdt_open_into(
+            fdt.as_ptr() as *const c_void,
+            fdt.as_mut_ptr() as *mut c_void,
+            size as c_int,
+        )
+    };
+    if ret < 0 {
+        bail!("Failed to fdt_open_into, return {}.", ret);
+    }
+    Ok(())
+}
+
+/// Run a libfdt operation on the buffer, and retry it after growing the
+/// buffer if it runs out of space.
+fn with_space<F>(fdt: &mut Vec<u8>, limit: usize, mut op: F) -> Result<c_int>
+where
+    F: FnMut(*mut c_void) -> c_int,
+{
+    loop {
+        let ret = op(fdt.as_mut_ptr() as *mut c_void);
+        if ret != -FDT_ERR_NOSPACE {
+            return Ok(ret);
+        }
+        grow(fdt, limit)?;
+    }
+}
+
+/// Create an empty device tree with only the root node, the buffer grows on
+/// demand when nodes and properties are added.
+pub fn create_device_tree(fdt: &mut Vec<u8>) -> Result<()> {
+    fdt.clear();
+    fdt.resize(FDT_INIT_SIZE, 0);
+    let mut ret = unsafe { fdt_create(fdt.as_mut_ptr() as *mut c_void, FDT_INIT_SIZE as c_int) };
+    if ret < 0 {
+        bail!("Failed to fdt_create, return {}.", ret);
+    }
+
+    ret = unsafe { fdt_finish_reservemap(fdt.as_mut_ptr() as *mut c_void) };
+    if ret < 0 {
+        bail!("Failed to fdt_finish_reservemap, return {}.", ret);
+    }
+
+    let c_str = CString::new("").unwrap();
+    ret = unsafe { fdt_begin_node(fdt.as_mut_ptr() as *mut c_void, c_str.as_ptr()) };
+    if ret < 0 {
+        bail!("Failed to fdt_begin_node, return {}.", ret);
+    }
+
+    ret = unsafe { fdt_end_node(fdt.as_mut_ptr() as *mut c_void) };
+    if ret < 0 {
+        bail!("Failed to fdt_end_node, return {}.", ret);
+    }
+
+    ret = unsafe { fdt_finish(fdt.as_mut_ptr() as *mut c_void) };
+    if ret < 0 {
+        bail!("Failed to fdt_finish, return {}.", ret);
+    }
+
+    ret = unsafe {
+        fdt_open_into(
+            fdt.as_ptr() as *mut c_void,
+            fdt.as_mut_ptr() as *mut c_void,
+            FDT_INIT_SIZE as c_int,
+        )
+    };
+    if ret < 0 {
+        bail!("Failed to fdt_open_into, return {}.", ret);
+    }
+
+    Ok(())
+}
+
+/// Pack the device tree and shrink the buffer to its `totalsize`, so that
+/// only the used bytes are loaded into guest memory. The tree can still be
+/// modified afterwards.
+pub fn finish_device_tree(fdt: &mut Vec<u8>) -> Result<()> {
+    let ret = unsafe { fdt_pack(fdt.as_mut_ptr() as *mut c_void) };
+    if ret < 0 {
+        bail!("Failed to fdt_pack, return {}.", ret);
+    }
+    let size = totalsize(fdt) as usize;
+    fdt.truncate(size);
+
+    Ok(())
+}
+
+/// Load a flattened device tree blob provided by user, the blob is opened into
+/// a growable buffer so that it can be modified afterwards.
+///
+/// # Arguments
+///
+/// * `blob` - The content of dtb file.
+///
+/// # Errors
+///
+/// Return Error if the blob fails the validation of `Fdt::parse`, or it's
+/// larger than the size limit.
+pub fn load_device_tree(blob: &[u8]) -> Result<Vec<u8>> {
+    let totalsize = Fdt::parse(blob)?.header.totalsize;
+    if totalsize > size_limit() {
+        bail!(
+            "Device tree totalsize {} exceeds max size {}.",
+            totalsize,
+            size_limit()
+        );
+    }
+
+    let mut fdt = vec![0; totalsize as usize];
+    let ret = unsafe {
+        fdt_open_into(
+            blob.as_ptr() as *const c_void,
+            fdt.as_mut_ptr() as *mut c_void,
+            totalsize as c_int,
+        )
+    };
+    if ret < 0 {
+        bail!("Failed to fdt_open_into, return {}.", ret);
+    }
+
+    Ok(fdt)
+}
+
+/// Append an entry to the memory reservation block, guest kernel never uses
+/// the range as normal memory.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt.
+/// * `addr` - Start address of reserved range.
+/// * `size` - Size of reserved range.
+pub fn add_mem_reserve(fdt: &mut Vec<u8>, addr: u64, size: u64) -> Result<()> {
+    let ret = with_space(fdt, size_limit() as usize, |buf| unsafe {
+        fdt_add_mem_rsv(buf, addr, size)
+    })
+    .chain_err(|| format!("Failed to reserve memory 0x{:x}+0x{:x}", addr, size))?;
+    if ret < 0 {
+        bail!("Failed to fdt_add_mem_rsv, return {}.", ret);
+    }
+
+    Ok(())
+}
+
+/// Add a child of `/reserved-memory` node covering the range, the parent
+/// node is created if absent, with the same cell sizes as root node.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt.
+/// * `name` - Node name of reserved range, the unit address is appended.
+/// * `addr` - Start address of reserved range.
+/// * `size` - Size of reserved range.
+/// * `no_map` - Whether guest kernel can map the range or not.
+///
+/// # Errors
+///
+/// Return Error if fail to add the node or the range doesn't fit in cells.
+pub fn add_reserved_memory(
+    fdt: &mut Vec<u8>,
+    name: &str,
+    addr: u64,
+    size: u64,
+    no_map: bool,
+) -> Result<String> {
+    let parent = "/reserved-memory";
+    if !node_exists(fdt, parent) {
+        let cells = CellSizes::of_node(fdt, "/")?;
+        add_sub_node(fdt, parent)?;
+        set_property_u32(fdt, parent, "#address-cells", cells.address)?;
+        set_property_u32(fdt, parent, "#size-cells", cells.size)?;
+        set_property(fdt, parent, "ranges", None)?;
+    }
+
+    let node = format!("{}/{}@{:x}", parent, name, addr);
+    add_sub_node(fdt, &node)?;
+    set_reg(fdt, &node, &[(addr, size)])?;
+    if no_map {
+        set_property(fdt, &node, "no-map", None)?;
+    }
+
+    Ok(node)
+}
+
+/// Check whether the node exists in fdt.
+pub fn node_exists(fdt: &[u8], node_path: &str) -> bool {
+    let c_str = CString::new(node_path).unwrap();
+    unsafe { fdt_path_offset(fdt.as_ptr() as *const c_void, c_str.as_ptr()) >= 0 }
+}
+
+/// Delete all nodes whose string property `prop` equals to `val`, return the
+/// number of deleted nodes.
+pub fn delete_nodes_by_prop_string(fdt: &mut Vec<u8>, prop: &str, val: &str) -> Result<usize> {
+    let c_prop = CString::new(prop).unwrap();
+    let c_val = CString::new(val).unwrap();
+    let len = c_val.as_bytes_with_nul().len() as c_int;
+    let mut count = 0;
+    loop {
+        let offset = unsafe {
+            fdt_node_offset_by_prop_value(
+                fdt.as_ptr() as *const c_void,
+                -1,
+                c_prop.as_ptr(),
+                c_val.as_ptr() as *const c_void,
+                len,
+            )
+        };
+        if offset < 0 {
+            break;
+        }
+        let ret = unsafe { fdt_del_node(fdt.as_mut_ptr() as *mut c_void, offset) };
+        if ret < 0 {
+            bail!("Failed to fdt_del_node, return {}.", ret);
+        }
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Get the value of property, `None` if the node or property doesn't exist.
+pub fn get_property(fdt: &[u8], node_path: &str, prop: &str) -> Option<Vec<u8>> {
+    let c_str = CString::new(node_path).unwrap();
+    let offset = unsafe { fdt_path_offset(fdt.as_ptr() as *const c_void, c_str.as_ptr()) };
+    if offset < 0 {
+        return None;
+    }
+
+    let c_str = CString::new(prop).unwrap();
+    let mut len: c_int = 0;
+    let ptr = unsafe {
+        fdt_getprop(
+            fdt.as_ptr() as *const c_void,
+            offset,
+            c_str.as_ptr(),
+            &mut len,
+        )
+    };
+    if ptr.is_null() || len < 0 {
+        return None;
+    }
+    // Safe because libfdt guarantees `len` bytes at `ptr` are inside `fdt`.
+    Some(unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) }.to_vec())
+}
+
+pub fn add_sub_node(fdt: &mut Vec<u8>, node_path: &str) -> Result<()> {
+    let names: Vec<&str> = node_path.split('/').collect();
+    if names.len() < 2 {
+        bail!("Failed to add sub node, node_path: {} invalid.", node_path);
+    }
+
+    let node_name = names[names.len() - 1];
+    let pare_name = names[0..names.len() - 1].join("/");
+
+    let c_str = if pare_name.is_empty() {
+        CString::new("/").unwrap()
+    } else {
+        CString::new(pare_name).unwrap()
+    };
+
+    let offset = unsafe { fdt_path_offset(fdt.as_ptr() as *const c_void, c_str.as_ptr()) };
+    if offset < 0 {
+        bail!("Failed to fdt_path_offset, return {}.", offset);
+    }
+
+    let c_str = CString::new(node_name).unwrap();
+    let ret = with_space(fdt, size_limit() as usize, |buf| unsafe {
+        fdt_add_subnode(buf, offset, c_str.as_ptr())
+    })
+    .chain_err(|| format!("Failed to add node {}", node_path))?;
+    if ret < 0 {
+        bail!("Failed to fdt_add_subnode, return {}.", ret);
+    }
+
+    Ok(())
+}
+
+pub fn set_property(
+    fdt: &mut Vec<u8>,
+    node_path: &str,
+    prop: &str,
+    val: Option<&[u8]>,
+) -> Result<()> {
+    set_property_limited(fdt, node_path, prop, val, size_limit() as usize)
+}
+
+fn set_property_limited(
+    fdt: &mut Vec<u8>,
+    node_path: &str,
+    prop: &str,
+    val: Option<&[u8]>,
+    limit: usize,
+) -> Result<()> {
+    let c_str = CString::new(node_path).unwrap();
+    let offset = unsafe { fdt_path_offset(fdt.as_ptr() as *const c_void, c_str.as_ptr()) };
+    if offset < 0 {
+        bail!("Failed to fdt_path_offset, return {}.", offset);
+    }
+
+    let (ptr, len) = if let Some(val) = val {
+        (val.as_ptr() as *const c_void, val.len() as i32)
+    } else {
+        (std::ptr::null::<c_void>(), 0)
+    };
+
+    let c_str = CString::new(prop).unwrap();
+    let ret = with_space(fdt, limit, |buf| unsafe {
+        fdt_setprop(buf, offset, c_str.as_ptr(), ptr, len)
+    })
+    .chain_err(|| format!("Failed to set property {} of node {}", prop, node_path))?;
+    if ret < 0 {
+        bail!("Failed to fdt_setprop, return {}.", ret);
+    }
+
+    Ok(())
+}
+
+pub fn set_property_string(
+    fdt: &mut Vec<u8>,
+    node_path: &str,
+    prop: &str,
+    val: &str,
+) -> Result<()> {
+    set_property(
+        fdt,
+        node_path,
+        prop,
+        Some(&([val.as_bytes(), &[0_u8]].concat())),
+    )
+}
+
+pub fn set_property_u32(fdt: &mut Vec<u8>, node_path: &str, prop: &str, val: u32) -> Result<()> {
+    set_property(fdt, node_path, prop, Some(&val.to_be_bytes()))
+}
+
+pub fn set_property_u64(fdt: &mut Vec<u8>, node_path: &str, prop: &str, val: u64) -> Result<()> {
+    set_property(fdt, node_path, prop, Some(&val.to_be_bytes()))
+}
+
+pub fn set_property_array_u32(
+    fdt: &mut Vec<u8>,
+    node_path: &str,
+    prop: &str,
+    array: &[u32],
+) -> Result<()> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for &val in array {
+        bytes.append(&mut val.to_be_bytes().to_vec());
+    }
+    set_property(fdt, node_path, prop, Some(&bytes))
+}
+
+pub fn set_property_array_u64(
+    fdt: &mut Vec<u8>,
+    node_path: &str,
+    prop: &str,
+    array: &[u64],
+) -> Result<()> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for &val in array {
+        bytes.append(&mut val.to_be_bytes().to_vec());
+    }
+    set_property(fdt, node_path, prop, Some(&bytes))
+}
+
+/// Number of cells used to encode address and size in `reg` property, which
+/// is given by `#address-cells` and `#size-cells` of the parent node.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CellSizes {
+    pub address: u32,
+    pub size: u32,
+}
+
+impl CellSizes {
+    /// Get the cell sizes for children of the node, the default values of
+    /// devicetree specification are used if the properties are absent.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdt` - The fdt.
+    /// * `node_path` - Path of parent node.
+    pub fn of_node(fdt: &[u8], node_path: &str) -> Result<Self> {
+        let read_cells = |prop: &str, default: u32| -> Result<u32> {
+            match get_property(fdt, node_path, prop) {
+                Some(val) if val.len() == 4 => {
+                    let mut cells = [0_u8; 4];
+                    cells.copy_from_slice(&val);
+                    Ok(u32::from_be_bytes(cells))
+                }
+                Some(val) => bail!(
+                    "Invalid {} of node {}: {} bytes.",
+                    prop,
+                    node_path,
+                    val.len()
+                ),
+                None => Ok(default),
+            }
+        };
+        Ok(CellSizes {
+            address: read_cells("#address-cells", DEFAULT_ADDRESS_CELLS)?,
+            size: read_cells("#size-cells", DEFAULT_SIZE_CELLS)?,
+        })
+    }
+}
+
+/// Append `val` as `cells` big-endian 32-bit cells.
+fn append_cells(bytes: &mut Vec<u8>, val: u64, cells: u32, name: &str) -> Result<()> {
+    match cells {
+        1 if val <= u64::from(u32::MAX) => bytes.extend_from_slice(&(val as u32).to_be_bytes()),
+        2 => bytes.extend_from_slice(&val.to_be_bytes()),
+        1 => bail!("{} 0x{:x} doesn't fit in 1 cell.", name, val),
+        _ => bail!("Unsupported number of cells {} for {}.", cells, name),
+    }
+    Ok(())
+}
+
+/// Get the path of parent node.
+fn parent_path(node_path: &str) -> &str {
+    match node_path.rfind('/') {
+        Some(0) | None => "/",
+        Some(pos) => &node_path[..pos],
+    }
+}
+
+/// Set `reg` property of node with the cell sizes of its parent node.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt.
+/// * `node_path` - Path of node.
+/// * `regions` - (address, size) of every region.
+///
+/// # Errors
+///
+/// Return Error if the cell sizes of parent are unsupported, or the address
+/// or size doesn't fit in them.
+pub fn set_reg(fdt: &mut Vec<u8>, node_path: &str, regions: &[(u64, u64)]) -> Result<()> {
+    let cells = CellSizes::of_node(fdt, parent_path(node_path))?;
+    set_reg_with_cells(fdt, node_path, regions, cells)
+}
+
+/// Set `reg` property of node with given cell sizes.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt.
+/// * `node_path` - Path of node.
+/// * `regions` - (address, size) of every region.
+/// * `cells` - `#address-cells` and `#size-cells` of parent node.
+pub fn set_reg_with_cells(
+    fdt: &mut Vec<u8>,
+    node_path: &str,
+    regions: &[(u64, u64)],
+    cells: CellSizes,
+) -> Result<()> {
+    let mut bytes = Vec::new();
+    for &(addr, size) in regions {
+        append_cells(&mut bytes, addr, cells.address, "address")?;
+        append_cells(&mut bytes, size, cells.size, "size")?;
+    }
+    set_property(fdt, node_path, "reg", Some(&bytes))
+}
+
+fn check_trigger(trigger: u32) -> Result<()> {
+    match trigger {
+        IRQ_TYPE_EDGE_RISING | IRQ_TYPE_EDGE_FALLING | IRQ_TYPE_LEVEL_HIGH | IRQ_TYPE_LEVEL_LOW => {
+            Ok(())
+        }
+        _ => bail!("Invalid interrupt trigger type {}.", trigger),
+    }
+}
+
+/// Set `interrupts` property of node with a GIC SPI, which is encoded in
+/// three cells: type, number and trigger type.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt.
+/// * `node_path` - Path of node.
+/// * `irq` - SPI number, counted from INTID 32.
+/// * `trigger` - Trigger type, one of `IRQ_TYPE_*`.
+///
+/// # Errors
+///
+/// Return Error if SPI number or trigger type is invalid.
+pub fn set_interrupts_spi(
+    fdt: &mut Vec<u8>,
+    node_path: &str,
+    irq: u32,
+    trigger: u32,
+) -> Result<()> {
+    if irq >= GIC_SPI_NUM {
+        bail!("SPI number {} exceeds max value {}.", irq, GIC_SPI_NUM - 1);
+    }
+    check_trigger(trigger)?;
+    set_property_array_u32(
+        fdt,
+        node_path,
+        "interrupts",
+        &[GIC_FDT_IRQ_TYPE_SPI, irq, trigger],
+    )
+}
+
+/// Set `interrupts` property of node with a GIC PPI.
+///
+/// # Arguments
+///
+/// * `fdt` - The fdt.
+/// * `node_path` - Path of node.
+/// * `irq` - PPI number, counted from INTID 16.
+/// * `trigger` - Trigger type, one of `IRQ_TYPE_*`.
+///
+/// # Errors
+///
+/// Return Error if PPI number or trigger type is invalid.
+pub fn set_interrupts_ppi(
+    fdt: &mut Vec<u8>,
+    node_path: &str,
+    irq: u32,
+    trigger: u32,
+) -> Result<()> {
+    if irq >= GIC_PPI_NUM {
+        bail!("PPI number {} exceeds max value {}.", irq, GIC_PPI_NUM - 1);
+    }
+    check_trigger(trigger)?;
+    set_property_array_u32(
+        fdt,
+        node_path,
+        "interrupts",
+        &[GIC_FDT_IRQ_TYPE_PPI, irq, trigger],
+    )
+}
+
+pub fn dump_dtb(fdt: &[u8], file_path: &str) {
+    use std::fs::File;
+    use std::io::Write;
+
+    let mut f = File::create(file_path).unwrap();
+    for i in fdt.iter() {
+        f.write_all(&[*i]).expect("Unable to write data");
+    }
+}
+
+/// Trait for devices to be added to the Flattened Device Tree.
+pub trait CompileFDT {
+    /// function to generate fdt node
+    ///
+    /// # Arguments
+    ///
+    /// * `fdt` - the fdt slice to be expended.
+    fn generate_fdt_node(&self, fdt: &mut Vec<u8>) -> Result<()>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_device_tree_grows() {
+        let mut fdt = Vec::new();
+        create_device_tree(&mut fdt).unwrap();
+        assert_eq!(fdt.len(), FDT_INIT_SIZE);
+
+        // Many cpu and virtio nodes, which exceed the old 64K buffer.
+        for cpu in 0..255 {
+            let node = format!("/cpu@{:x}", cpu);
+            add_sub_node(&mut fdt, &node).unwrap();
+            set_property_string(&mut fdt, &node, "compatible", "arm,arm-v8").unwrap();
+            set_property_string(&mut fdt, &node, "enable-method", "psci").unwrap();
+            set_property_u64(&mut fdt, &node, "reg", cpu).unwrap();
+        }
+        for dev in 0..128_u64 {
+            let addr = 0x0a00_0000 + dev * 0x200;
+            let node = format!("/virtio_mmio@{:x}", addr);
+            add_sub_node(&mut fdt, &node).unwrap();
+            set_property_string(&mut fdt, &node, "compatible", "virtio,mmio").unwrap();
+            set_property_array_u64(&mut fdt, &node, "reg", &[addr, 0x200]).unwrap();
+            set_property_array_u32(&mut fdt, &node, "interrupts", &[0, dev as u32, 1]).unwrap();
+        }
+        finish_device_tree(&mut fdt).unwrap();
+        assert!(fdt.len() > 0x1_0000);
+        assert_eq!(totalsize(&fdt) as usize, fdt.len());
+
+        // nothing is truncated
+        assert_eq!(
+            get_property(&fdt, "/cpu@fe", "reg").unwrap(),
+            0xfe_u64.to_be_bytes().to_vec()
+        );
+        assert_eq!(
+            get_property(&fdt, "/virtio_mmio@a00fe00", "interrupts").unwrap(),
+            [0_u32, 127, 1]
+                .iter()
+                .flat_map(|v| v.to_be_bytes().to_vec())
+                .collect::<Vec<u8>>()
+        );
+
+        // finished tree can still be modified and reloaded
+        set_property_string(&mut fdt, "/", "model", "linux,dummy-virt").unwrap();
+        finish_device_tree(&mut fdt).unwrap();
+        let fdt = load_device_tree(&fdt).unwrap();
+        assert_eq!(
+            get_property(&fdt, "/", "model").unwrap(),
+            b"linux,dummy-virt\0".to_vec()
+        );
+        assert!(node_exists(&fdt, "/cpu@0"));
+    }
+
+    #[test]
+    fn test_reg_and_interrupts() {
+        let mut fdt = Vec::new();
+        create_device_tree(&mut fdt).unwrap();
+        set_property_u32(&mut fdt, "/", "#address-cells", 2).unwrap();
+        set_property_u32(&mut fdt, "/", "#size-cells", 2).unwrap();
+        add_sub_node(&mut fdt, "/uart@9000000").unwrap();
+        add_sub_node(&mut fdt, "/soc").unwrap();
+        set_property_u32(&mut fdt, "/soc", "#address-cells", 1).unwrap();
+        set_property_u32(&mut fdt, "/soc", "#size-cells", 1).unwrap();
+        add_sub_node(&mut fdt, "/soc/pl031@9010000").unwrap();
+        add_sub_node(&mut fdt, "/bus").unwrap();
+        add_sub_node(&mut fdt, "/bus/dev@0").unwrap();
+
+        // Reference bytes are from dtc, compiling:
+        //   reg = <0x0 0x9000000 0x0 0x1000>;
+        //   interrupts = <0x0 0x1 0x1>;
+        set_reg(&mut fdt, "/uart@9000000", &[(0x900_0000, 0x1000)]).unwrap();
+        assert_eq!(
+            get_property(&fdt, "/uart@9000000", "reg").unwrap(),
+            vec![0, 0, 0, 0, 0x09, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0]
+        );
+        set_interrupts_spi(&mut fdt, "/uart@9000000", 1, IRQ_TYPE_EDGE_RISING).unwrap();
+        assert_eq!(
+            get_property(&fdt, "/uart@9000000", "interrupts").unwrap(),
+            vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1]
+        );
+
+        //   reg = <0x9010000 0x1000 0x9020000 0x2000>;
+        //   interrupts = <0x1 0x9 0x4>;
+        set_reg(
+            &mut fdt,
+            "/soc/pl031@9010000",
+            &[(0x901_0000, 0x1000), (0x902_0000, 0x2000)],
+        )
+        .unwrap();
+        assert_eq!(
+            get_property(&fdt, "/soc/pl031@9010000", "reg").unwrap(),
+            vec![0x09, 0x01, 0, 0, 0, 0, 0x10, 0, 0x09, 0x02, 0, 0, 0, 0, 0x20, 0]
+        );
+        set_interrupts_ppi(&mut fdt, "/soc/pl031@9010000", 9, IRQ_TYPE_LEVEL_HIGH).unwrap();
+        assert_eq!(
+            get_property(&fdt, "/soc/pl031@9010000", "interrupts").unwrap(),
+            vec![0, 0, 0, 1, 0, 0, 0, 9, 0, 0, 0, 4]
+        );
+
+        // default cells of devicetree specification: <2 1>
+        //   reg = <0x1 0x0 0x1000>;
+        set_reg(&mut fdt, "/bus/dev@0", &[(0x1_0000_0000, 0x1000)]).unwrap();
+        assert_eq!(
+            get_property(&fdt, "/bus/dev@0", "reg").unwrap(),
+            vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x10, 0]
+        );
+
+        // values out of range
+        assert!(set_reg(&mut fdt, "/soc/pl031@9010000", &[(0x1_0000_0000, 0x1000)]).is_err());
+        assert!(set_reg(&mut fdt, "/bus/dev@0", &[(0, 0x1_0000_0000)]).is_err());
+        assert!(set_interrupts_spi(&mut fdt, "/uart@9000000", 988, IRQ_TYPE_LEVEL_HIGH).is_err());
+        assert!(set_interrupts_spi(&mut fdt, "/uart@9000000", 987, IRQ_TYPE_LEVEL_HIGH).is_ok());
+        assert!(set_interrupts_spi(&mut fdt, "/uart@9000000", 1, 3).is_err());
+        assert!(set_interrupts_ppi(&mut fdt, "/uart@9000000", 16, IRQ_TYPE_LEVEL_HIGH).is_err());
+        assert!(set_reg_with_cells(
+            &mut fdt,
+            "/uart@9000000",
+            &[(0, 0)],
+            CellSizes {
+                address: 3,
+                size: 2
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_mem_reserve() {
+        let mut fdt = Vec::new();
+        create_device_tree(&mut fdt).unwrap();
+        set_property_u32(&mut fdt, "/", "#address-cells", 2).unwrap();
+        set_property_u32(&mut fdt, "/", "#size-cells", 2).unwrap();
+        add_mem_reserve(&mut fdt, 0x8800_0000, 0x10_0000).unwrap();
+        add_mem_reserve(&mut fdt, 0x1_2345_6000, 0x2000).unwrap();
+        finish_device_tree(&mut fdt).unwrap();
+
+        // Reservation block follows the 40 bytes header, terminated by an
+        // entry of zeros.
+        let mut off = [0_u8; 4];
+        off.copy_from_slice(&fdt[16..20]);
+        let off = u32::from_be_bytes(off) as usize;
+        assert_eq!(off, 40);
+        let expected: Vec<u8> = vec![
+            0, 0, 0, 0, 0x88, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, //
+            0, 0, 0, 1, 0x23, 0x45, 0x60, 0, 0, 0, 0, 0, 0, 0, 0x20, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(&fdt[off..off + expected.len()], expected.as_slice());
+        // structure block starts right after the reservation block
+        let mut off_struct = [0_u8; 4];
+        off_struct.copy_from_slice(&fdt[8..12]);
+        assert_eq!(
+            u32::from_be_bytes(off_struct) as usize,
+            off + expected.len()
+        );
+
+        // one more entry takes exactly one entry size
+        let size = fdt.len();
+        add_mem_reserve(&mut fdt, 0x9000_0000, 0x1000).unwrap();
+        finish_device_tree(&mut fdt).unwrap();
+        assert_eq!(fdt.len() as u64, size as u64 + FDT_MEM_RSV_ENTRY_SIZE);
+
+        // reserved-memory node
+        let node = add_reserved_memory(&mut fdt, "its", 0x8000_0000, 0x1_0000, true).unwrap();
+        assert_eq!(node, "/reserved-memory/its@80000000");
+        assert_eq!(
+            get_property(&fdt, "/reserved-memory", "#size-cells").unwrap(),
+            vec![0, 0, 0, 2]
+        );
+        assert_eq!(
+            get_property(&fdt, "/reserved-memory", "ranges").unwrap(),
+            Vec::<u8>::new()
+        );
+        assert_eq!(
+            get_property(&fdt, &node, "reg").unwrap(),
+            vec![0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]
+        );
+        assert!(get_property(&fdt, &node, "no-map").is_some());
+        let node = add_reserved_memory(&mut fdt, "fw", 0x8001_0000, 0x1000, false).unwrap();
+        assert!(get_property(&fdt, &node, "no-map").is_none());
+    }
+
+    #[test]
+    fn test_device_tree_size_limit() {
+        let mut fdt = Vec::new();
+        create_device_tree(&mut fdt).unwrap();
+
+        let limit = FDT_INIT_SIZE * 2;
+        let big = vec![0xa5_u8; FDT_INIT_SIZE];
+        set_property_limited(&mut fdt, "/", "small", Some(&big), limit).unwrap();
+        assert_eq!(fdt.len(), limit);
+        let err = set_property_limited(&mut fdt, "/", "big", Some(&big), limit)
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("Failed to set property big of node /"));
+
+        // the tree is kept intact after the failure
+        assert_eq!(get_property(&fdt, "/", "small").unwrap(), big);
+        assert!(get_property(&fdt, "/", "big").is_none());
+    }
+}
@@ -0,0 +1,571 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use super::FDT_MAGIC;
+use crate::errors::Result;
+
+// Tokens of structure block, sourced from devicetree specification 5.4.1.
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_VERSION: u32 = 17;
+// Max depth of nodes, to stop parsing malformed blob early.
+const FDT_MAX_DEPTH: usize = 64;
+
+fn read_be_u32(blob: &[u8], offset: usize) -> Result<u32> {
+    match blob.get(offset..offset + 4) {
+        Some(bytes) => {
+            let mut val = [0_u8; 4];
+            val.copy_from_slice(bytes);
+            Ok(u32::from_be_bytes(val))
+        }
+        None => bail!("Offset {} is out of device tree block.", offset),
+    }
+}
+
+fn read_be_u64(blob: &[u8], offset: usize) -> Result<u64> {
+    match blob.get(offset..offset + 8) {
+        Some(bytes) => {
+            let mut val = [0_u8; 8];
+            val.copy_from_slice(bytes);
+            Ok(u64::from_be_bytes(val))
+        }
+        None => bail!("Offset {} is out of device tree block.", offset),
+    }
+}
+
+/// Read a NUL-terminated string, return the string and its length without
+/// NUL.
+fn read_str(blob: &[u8], offset: usize) -> Result<(String, usize)> {
+    let bytes = match blob.get(offset..) {
+        Some(bytes) => bytes,
+        None => bail!("Offset {} is out of device tree block.", offset),
+    };
+    match bytes.iter().position(|&b| b == 0) {
+        Some(len) => {
+            let s = std::str::from_utf8(&bytes[..len])
+                .map_err(|_| format!("Invalid string at offset {}.", offset))?;
+            Ok((s.to_string(), len))
+        }
+        None => bail!("Unterminated string at offset {}.", offset),
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Header of flattened device tree.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct FdtHeader {
+    pub magic: u32,
+    pub totalsize: u32,
+    pub off_dt_struct: u32,
+    pub off_dt_strings: u32,
+    pub off_mem_rsvmap: u32,
+    pub version: u32,
+    pub last_comp_version: u32,
+    pub boot_cpuid_phys: u32,
+    pub size_dt_strings: u32,
+    pub size_dt_struct: u32,
+}
+
+impl FdtHeader {
+    /// Parse the header and check that all blocks are inside `totalsize`.
+    ///
+    /// # Arguments
+    ///
+    /// * `blob` - The flattened device tree.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if magic or version is unsupported, or any block is
+    /// misaligned or out of `totalsize`.
+    pub fn parse(blob: &[u8]) -> Result<Self> {
+        if blob.len() < FDT_HEADER_SIZE {
+            bail!("Device tree blob is too small: {} bytes.", blob.len());
+        }
+        let field = |index: usize| read_be_u32(blob, index * 4);
+        let header = FdtHeader {
+            magic: field(0)?,
+            totalsize: field(1)?,
+            off_dt_struct: field(2)?,
+            off_dt_strings: field(3)?,
+            off_mem_rsvmap: field(4)?,
+            version: field(5)?,
+            last_comp_version: field(6)?,
+            boot_cpuid_phys: field(7)?,
+            size_dt_strings: field(8)?,
+            size_dt_struct: field(9)?,
+        };
+
+        if header.magic != FDT_MAGIC {
+            bail!("Invalid device tree magic 0x{:x}.", header.magic);
+        }
+        if (header.totalsize as usize) < FDT_HEADER_SIZE || header.totalsize as usize > blob.len() {
+            bail!(
+                "Invalid device tree totalsize {}, blob size {}.",
+                header.totalsize,
+                blob.len()
+            );
+        }
+        if header.version < FDT_VERSION || header.last_comp_version > FDT_VERSION {
+            bail!(
+                "Unsupported device tree version {}, last compatible version {}.",
+                header.version,
+                header.last_comp_version
+            );
+        }
+        header.check_block("memory reservation", header.off_mem_rsvmap, 0, 8)?;
+        header.check_block("structure", header.off_dt_struct, header.size_dt_struct, 4)?;
+        header.check_block("strings", header.off_dt_strings, header.size_dt_strings, 1)?;
+
+        Ok(header)
+    }
+
+    fn check_block(&self, name: &str, offset: u32, size: u32, align: u32) -> Result<()> {
+        if offset % align != 0 {
+            bail!(
+                "Device tree {} block at offset {} isn't aligned to {}.",
+                name,
+                offset,
+                align
+            );
+        }
+        let end = u64::from(offset) + u64::from(size);
+        if (offset as usize) < FDT_HEADER_SIZE || end > u64::from(self.totalsize) {
+            bail!(
+                "Device tree {} block [{}, {}) is out of totalsize {}.",
+                name,
+                offset,
+                end,
+                self.totalsize
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Property of device tree node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdtProperty {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Node of device tree, with its properties and subnodes in the order of
+/// structure block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdtNode {
+    /// Node name with unit address, empty for root node.
+    pub name: String,
+    pub properties: Vec<FdtProperty>,
+    pub children: Vec<FdtNode>,
+}
+
+impl FdtNode {
+    /// Get the value of property.
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|prop| prop.name == name)
+            .map(|prop| prop.value.as_slice())
+    }
+
+    /// Get the value of property as one u32 cell.
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        match self.property(name) {
+            Some(val) if val.len() == 4 => read_be_u32(val, 0).ok(),
+            _ => None,
+        }
+    }
+
+    /// Get the value of property as two u32 cells.
+    pub fn property_u64(&self, name: &str) -> Option<u64> {
+        match self.property(name) {
+            Some(val) if val.len() == 8 => read_be_u64(val, 0).ok(),
+            _ => None,
+        }
+    }
+
+    /// Get the value of property as u32 cells.
+    pub fn property_cells(&self, name: &str) -> Option<Vec<u32>> {
+        match self.property(name) {
+            Some(val) if val.len() % 4 == 0 => (0..val.len())
+                .step_by(4)
+                .map(|offset| read_be_u32(val, offset).ok())
+                .collect(),
+            _ => None,
+        }
+    }
+
+    /// Get the value of property as a NUL-terminated string.
+    pub fn property_str(&self, name: &str) -> Option<&str> {
+        match self.property(name) {
+            Some(val) if val.last() == Some(&0) => std::str::from_utf8(&val[..val.len() - 1]).ok(),
+            _ => None,
+        }
+    }
+
+    /// Get the subnode by its name.
+    pub fn child(&self, name: &str) -> Option<&FdtNode> {
+        self.children.iter().find(|node| node.name == name)
+    }
+}
+
+/// Reader of structure block.
+struct StructReader<'a> {
+    block: &'a [u8],
+    strings: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StructReader<'a> {
+    fn token(&mut self) -> Result<u32> {
+        let token = read_be_u32(self.block, self.pos)?;
+        self.pos += 4;
+        Ok(token)
+    }
+
+    fn node_name(&mut self) -> Result<String> {
+        let (name, len) = read_str(self.block, self.pos)?;
+        self.pos = align4(self.pos + len + 1);
+        Ok(name)
+    }
+
+    fn property(&mut self) -> Result<FdtProperty> {
+        let len = read_be_u32(self.block, self.pos)? as usize;
+        let nameoff = read_be_u32(self.block, self.pos + 4)? as usize;
+        let start = self.pos + 8;
+        let value = match self.block.get(start..start + len) {
+            Some(value) => value.to_vec(),
+            None => bail!(
+                "Property value of {} bytes at offset {} is out of structure block.",
+                len,
+                start
+            ),
+        };
+        if nameoff >= self.strings.len() {
+            bail!(
+                "Property name offset {} is out of strings block of {} bytes.",
+                nameoff,
+                self.strings.len()
+            );
+        }
+        let (name, _) = read_str(self.strings, nameoff)?;
+        self.pos = align4(start + len);
+        Ok(FdtProperty { name, value })
+    }
+
+    fn node(&mut self, name: String, depth: usize) -> Result<FdtNode> {
+        if depth > FDT_MAX_DEPTH {
+            bail!(
+                "Device tree nodes are nested deeper than {}.",
+                FDT_MAX_DEPTH
+            );
+        }
+        let mut node = FdtNode {
+            name,
+            properties: Vec::new(),
+            children: Vec::new(),
+        };
+        loop {
+            let offset = self.pos;
+            match self.token()? {
+                FDT_PROP => {
+                    if !node.children.is_empty() {
+                        bail!(
+                            "Property at offset {} follows subnodes of node {:?}.",
+                            offset,
+                            node.name
+                        );
+                    }
+                    let prop = self.property()?;
+                    if node.property(&prop.name).is_some() {
+                        bail!("Duplicate property {} in node {:?}.", prop.name, node.name);
+                    }
+                    node.properties.push(prop);
+                }
+                FDT_BEGIN_NODE => {
+                    let name = self.node_name()?;
+                    if name.is_empty() || node.child(&name).is_some() {
+                        bail!(
+                            "Invalid or duplicate node name {:?} at offset {}.",
+                            name,
+                            offset
+                        );
+                    }
+                    let child = self.node(name, depth + 1)?;
+                    node.children.push(child);
+                }
+                FDT_END_NODE => return Ok(node),
+                FDT_NOP => {}
+                token => bail!("Unexpected token 0x{:x} at offset {}.", token, offset),
+            }
+        }
+    }
+}
+
+/// Flattened device tree parsed into nodes, used to validate generated or
+/// user provided device tree.
+#[derive(Debug, Clone)]
+pub struct Fdt {
+    pub header: FdtHeader,
+    /// Entries of memory reservation block, (address, size).
+    pub mem_reserves: Vec<(u64, u64)>,
+    pub root: FdtNode,
+}
+
+impl Fdt {
+    /// Parse and validate a flattened device tree.
+    ///
+    /// # Arguments
+    ///
+    /// * `blob` - The flattened device tree.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the header is invalid, the tokens are misordered,
+    /// property names are out of strings block, or any data is out of its
+    /// block.
+    pub fn parse(blob: &[u8]) -> Result<Self> {
+        let header = FdtHeader::parse(blob)?;
+        let blob = &blob[..header.totalsize as usize];
+
+        let mut mem_reserves = Vec::new();
+        let mut offset = header.off_mem_rsvmap as usize;
+        loop {
+            let addr = read_be_u64(blob, offset)?;
+            let size = read_be_u64(blob, offset + 8)?;
+            offset += 16;
+            if addr == 0 && size == 0 {
+                break;
+            }
+            mem_reserves.push((addr, size));
+        }
+
+        let struct_start = header.off_dt_struct as usize;
+        let strings_start = header.off_dt_strings as usize;
+        let mut reader = StructReader {
+            block: &blob[struct_start..struct_start + header.size_dt_struct as usize],
+            strings: &blob[strings_start..strings_start + header.size_dt_strings as usize],
+            pos: 0,
+        };
+
+        let mut token = reader.token()?;
+        while token == FDT_NOP {
+            token = reader.token()?;
+        }
+        if token != FDT_BEGIN_NODE {
+            bail!("Structure block starts with token 0x{:x}.", token);
+        }
+        let name = reader.node_name()?;
+        if !name.is_empty() {
+            bail!("Root node has name {:?}.", name);
+        }
+        let root = reader.node(name, 0)?;
+
+        let mut token = reader.token()?;
+        while token == FDT_NOP {
+            token = reader.token()?;
+        }
+        if token != FDT_END {
+            bail!("Structure block ends with token 0x{:x}.", token);
+        }
+        if reader.pos != reader.block.len() {
+            bail!(
+                "Structure block has {} bytes after FDT_END.",
+                reader.block.len() - reader.pos
+            );
+        }
+
+        Ok(Fdt {
+            header,
+            mem_reserves,
+            root,
+        })
+    }
+
+    /// Get the node by path, `None` if it doesn't exist.
+    pub fn node(&self, path: &str) -> Option<&FdtNode> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&self.root, |node, name| node.child(name))
+    }
+
+    /// Get the value of property, `None` if the node or property doesn't
+    /// exist.
+    pub fn property(&self, path: &str, name: &str) -> Option<&[u8]> {
+        self.node(path).and_then(|node| node.property(name))
+    }
+
+    /// Visit all nodes in depth-first order with their paths.
+    pub fn walk<F: FnMut(&str, &FdtNode)>(&self, mut f: F) {
+        fn visit<F: FnMut(&str, &FdtNode)>(path: &str, node: &FdtNode, f: &mut F) {
+            f(path, node);
+            for child in node.children.iter() {
+                let child_path = if path == "/" {
+                    format!("/{}", child.name)
+                } else {
+                    format!("{}/{}", path, child.name)
+                };
+                visit(&child_path, child, f);
+            }
+        }
+        visit("/", &self.root, &mut f);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        add_mem_reserve, add_sub_node, create_device_tree, finish_device_tree, set_property,
+        set_property_string, set_property_u32,
+    };
+    use super::*;
+
+    /// Build a blob from raw structure and strings blocks.
+    fn build_blob(structs: &[u32], strings: &[u8]) -> Vec<u8> {
+        let rsvmap = FDT_HEADER_SIZE as u32;
+        let off_struct = rsvmap + 16;
+        let size_struct = structs.len() as u32 * 4;
+        let off_strings = off_struct + size_struct;
+        let totalsize = off_strings + strings.len() as u32;
+        let header = [
+            FDT_MAGIC,
+            totalsize,
+            off_struct,
+            off_strings,
+            rsvmap,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            size_struct,
+        ];
+        let mut blob = Vec::new();
+        for val in header.iter() {
+            blob.extend_from_slice(&val.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0_u8; 16]);
+        for val in structs.iter() {
+            blob.extend_from_slice(&val.to_be_bytes());
+        }
+        blob.extend_from_slice(strings);
+        blob
+    }
+
+    #[test]
+    fn test_parse_generated_tree() {
+        let mut fdt = Vec::new();
+        create_device_tree(&mut fdt).unwrap();
+        set_property_u32(&mut fdt, "/", "#address-cells", 2).unwrap();
+        add_sub_node(&mut fdt, "/cpus").unwrap();
+        add_sub_node(&mut fdt, "/cpus/cpu@0").unwrap();
+        set_property_string(&mut fdt, "/cpus/cpu@0", "enable-method", "psci").unwrap();
+        add_sub_node(&mut fdt, "/intc").unwrap();
+        set_property(&mut fdt, "/intc", "interrupt-controller", None).unwrap();
+        add_mem_reserve(&mut fdt, 0x8800_0000, 0x1000).unwrap();
+        finish_device_tree(&mut fdt).unwrap();
+
+        let tree = Fdt::parse(&fdt).unwrap();
+        assert_eq!(tree.header.totalsize as usize, fdt.len());
+        assert_eq!(tree.mem_reserves, vec![(0x8800_0000, 0x1000)]);
+        assert_eq!(tree.root.property_u32("#address-cells"), Some(2));
+        assert_eq!(
+            tree.node("/cpus/cpu@0")
+                .unwrap()
+                .property_str("enable-method"),
+            Some("psci")
+        );
+        assert_eq!(
+            tree.property("/intc", "interrupt-controller"),
+            Some(&[][..])
+        );
+        assert!(tree.node("/cpus/cpu@1").is_none());
+        assert!(tree.property("/intc", "phandle").is_none());
+
+        let mut paths = Vec::new();
+        tree.walk(|path, _| paths.push(path.to_string()));
+        assert_eq!(paths, vec!["/", "/cpus", "/cpus/cpu@0", "/intc"]);
+    }
+
+    #[test]
+    fn test_parse_invalid_tree() {
+        // "a\0" is the only string
+        let strings = b"a\0";
+        let valid = [
+            FDT_BEGIN_NODE,
+            0,
+            FDT_PROP,
+            4,
+            0,
+            0x1234,
+            FDT_BEGIN_NODE,
+            u32::from_be_bytes(*b"n\0\0\0"),
+            FDT_END_NODE,
+            FDT_END_NODE,
+            FDT_END,
+        ];
+        let tree = Fdt::parse(&build_blob(&valid, strings)).unwrap();
+        assert_eq!(tree.root.property_u32("a"), Some(0x1234));
+        assert!(tree.node("/n").is_some());
+
+        // bad magic, version and totalsize
+        let mut blob = build_blob(&valid, strings);
+        blob[0] = 0;
+        assert!(Fdt::parse(&blob).is_err());
+        let mut blob = build_blob(&valid, strings);
+        blob[23] = 16;
+        assert!(Fdt::parse(&blob).is_err());
+        let blob = build_blob(&valid, strings);
+        assert!(Fdt::parse(&blob[..blob.len() - 1]).is_err());
+
+        // property name out of strings block
+        let mut tokens = valid;
+        tokens[4] = 2;
+        assert!(Fdt::parse(&build_blob(&tokens, strings)).is_err());
+        // property value out of structure block
+        let mut tokens = valid;
+        tokens[3] = 0x100;
+        assert!(Fdt::parse(&build_blob(&tokens, strings)).is_err());
+        // property after subnode
+        let tokens = [
+            FDT_BEGIN_NODE,
+            0,
+            FDT_BEGIN_NODE,
+            u32::from_be_bytes(*b"n\0\0\0"),
+            FDT_END_NODE,
+            FDT_PROP,
+            4,
+            0,
+            0x1234,
+            FDT_END_NODE,
+            FDT_END,
+        ];
+        assert!(Fdt::parse(&build_blob(&tokens, strings)).is_err());
+        // unbalanced nodes and missing FDT_END
+        assert!(Fdt::parse(&build_blob(&valid[..9], strings)).is_err());
+        assert!(Fdt::parse(&build_blob(&valid[..10], strings)).is_err());
+        // invalid token and trailing data
+        let mut tokens = valid;
+        tokens[8] = 0x5;
+        assert!(Fdt::parse(&build_blob(&tokens, strings)).is_err());
+        let mut tokens = valid.to_vec();
+        tokens.push(FDT_NOP);
+        tokens.push(0);
+        assert!(Fdt::parse(&build_blob(&tokens, strings)).is_err());
+    }
+}
@@ -13,15 +13,21 @@
 extern crate vmm_sys_util;
 
 use std::collections::BTreeMap;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use libc::{c_void, read};
 use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 
 use crate::errors::{ErrorKind, Result};
+use crate::loop_stats::{LoopCounters, LoopStatsHandle};
+use crate::timer::{TimerCallback, TimerHandle, TimerMode};
 
 const READY_EVENT_MAX: usize = 256;
+/// Priority of `EventNotifier` if not specified.
+pub const DEFAULT_PRIORITY: i32 = 0;
 
 #[derive(Debug)]
 pub enum NotifierOperation {
@@ -31,7 +37,8 @@ pub enum NotifierOperation {
     /// Try to add a notifier to a file descriptor, when some event
     /// also notice me, the file descriptor must be read.
     AddShared = 2,
-    /// Change the settings associated with a file descriptor.
+    /// Change the event set of a registered file descriptor, the handlers
+    /// of the notifier are ignored.
     Modify = 4,
     /// Delete a file descriptor from the event table, if has one more notifiers,
     /// file descriptor not closed.
@@ -43,9 +50,16 @@ enum EventStatus {
     Alive = 0,
     /// Event is parked, temporarily not monitored.
     Parked = 1,
-    /// Event is removed.
+    /// Event is removed, it's skipped by the dispatching and freed after
+    /// the current dispatch batch finishes.
     Removed = 2,
 }
+/// Callback of `EventNotifier`, called with the ready events and the fd.
+///
+/// It can register or remove events by returning notifiers, or by updating
+/// the `MainLoopContext` directly. Either way, the notifiers are applied in
+/// order after the current dispatch batch finishes, except that removed
+/// events are skipped at once.
 pub type NotifierCallback = dyn Fn(EventSet, RawFd) -> Option<Vec<EventNotifier>>;
 /// Epoll Event Notifier Entry.
 pub struct EventNotifier {
@@ -55,12 +69,26 @@ pub struct EventNotifier {
     pub op: NotifierOperation,
     /// Parked fd, temporarily removed from epoll
     pub parked_fd: Option<i32>,
-    /// The types of events for which we use this fd
+    /// The types of events for which we use this fd, level-triggered by
+    /// default.
+    ///
+    /// With `EventSet::EDGE_TRIGGERED`, the handler is called only when the
+    /// fd becomes ready again, so it must drain the fd before doing its work
+    /// and finish all the work before returning, e.g. read the queue eventfd
+    /// first and then pop the avail ring until it's empty. A notification
+    /// arriving after the drain makes the fd ready again and is never lost.
+    /// Keep level-triggered for fds which can't be drained in one call, like
+    /// sockets.
     pub event: EventSet,
     /// Event Handler List, one fd event may have many handlers
     pub handlers: Vec<Arc<Mutex<Box<NotifierCallback>>>>,
+    /// Dispatch priority, the ready notifier with higher priority is handled
+    /// earlier in one wake-up of `MainLoop`.
+    pub priority: i32,
     /// Event status
     status: EventStatus,
+    /// Number of times the event is dispatched.
+    pub(crate) dispatch_count: AtomicU64,
 }
 
 impl EventNotifier {
@@ -78,9 +106,46 @@ impl EventNotifier {
             parked_fd,
             event,
             handlers,
+            priority: DEFAULT_PRIORITY,
             status: EventStatus::Alive,
+            dispatch_count: AtomicU64::new(0),
         }
     }
+
+    /// Set the dispatch priority of `EventNotifier`. It only takes effect
+    /// when the fd is added to `MainLoop` for the first time, handlers added
+    /// to the fd later share the existing priority.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - Higher priority is dispatched earlier, notifiers with
+    ///   equal priority are dispatched in the order reported by epoll.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Callback called when `MainLoop` is idle, which means no event is ready
+/// before the wait timeout.
+pub type IdleCallback = dyn Fn();
+
+/// Idle callback registered to `MainLoop`.
+struct IdleNotifier {
+    /// Minimum interval between two calls.
+    interval: Duration,
+    /// Time of the last call, or the registration.
+    last_run: Instant,
+    callback: Arc<IdleCallback>,
+}
+
+impl IdleNotifier {
+    /// Time left before the callback can be called again.
+    fn time_to_due(&self, now: Instant) -> Duration {
+        (self.last_run + self.interval)
+            .checked_duration_since(now)
+            .unwrap_or_default()
+    }
 }
 
 /// `EventNotifier` Factory
@@ -112,6 +177,18 @@ pub struct MainLoopContext {
     gc: Arc<RwLock<Vec<Box<EventNotifier>>>>,
     /// Temp events vector, store wait returned events.
     ready_events: Vec<EpollEvent>,
+    /// Timers added to the `MainLoop`.
+    timers: Vec<TimerHandle>,
+    /// Notifiers updated while dispatching, it's `None` out of dispatching.
+    pending: Mutex<Option<Vec<EventNotifier>>>,
+    /// Max time to wait for events, `None` means waiting forever.
+    wait_timeout: Option<Duration>,
+    /// Idle callbacks indexed by id.
+    idle_notifiers: BTreeMap<u64, IdleNotifier>,
+    /// Id of the next idle callback.
+    next_idle_id: u64,
+    /// Statistics of the loop.
+    counters: Arc<LoopCounters>,
 }
 
 impl MainLoopContext {
@@ -123,6 +200,21 @@ impl MainLoopContext {
             events: Arc::new(RwLock::new(BTreeMap::new())),
             gc: Arc::new(RwLock::new(Vec::new())),
             ready_events: vec![EpollEvent::default(); READY_EVENT_MAX],
+            timers: Vec::new(),
+            pending: Mutex::new(None),
+            wait_timeout: None,
+            idle_notifiers: BTreeMap::new(),
+            next_idle_id: 0,
+            counters: Arc::new(LoopCounters::default()),
+        }
+    }
+
+    /// Get the handle to read statistics of the loop, it can be used in
+    /// other threads.
+    pub fn stats_handle(&self) -> LoopStatsHandle {
+        LoopStatsHandle {
+            counters: self.counters.clone(),
+            events: self.events.clone(),
         }
     }
 
@@ -130,6 +222,87 @@ impl MainLoopContext {
         self.manager = Some(manager);
     }
 
+    /// Set the max time to wait for events in each iteration, `None` means
+    /// waiting forever unless there are idle callbacks.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The max time to wait for events.
+    pub fn set_wait_timeout(&mut self, timeout: Option<Duration>) {
+        self.wait_timeout = timeout;
+    }
+
+    /// Add a callback called when no event is ready before the wait timeout,
+    /// return its id used to remove it.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - Minimum interval between two calls, the first call is
+    ///   not earlier than `interval` after adding.
+    /// * `callback` - Called when `MainLoop` is idle.
+    pub fn add_idle(&mut self, interval: Duration, callback: Box<IdleCallback>) -> u64 {
+        let id = self.next_idle_id;
+        self.next_idle_id += 1;
+        self.idle_notifiers.insert(
+            id,
+            IdleNotifier {
+                interval,
+                last_run: Instant::now(),
+                callback: Arc::from(callback),
+            },
+        );
+        id
+    }
+
+    /// Remove the idle callback, return `false` if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id returned by `add_idle`.
+    pub fn rm_idle(&mut self, id: u64) -> bool {
+        self.idle_notifiers.remove(&id).is_some()
+    }
+
+    /// Timeout of `epoll.wait()` in milliseconds, -1 means waiting forever.
+    fn epoll_timeout(&self) -> i32 {
+        let now = Instant::now();
+        let idle_timeout = self
+            .idle_notifiers
+            .values()
+            .map(|idle| idle.time_to_due(now))
+            .min();
+        let timeout = match (self.wait_timeout, idle_timeout) {
+            (Some(wait), Some(idle)) => Some(std::cmp::min(wait, idle)),
+            (wait, idle) => wait.or(idle),
+        };
+
+        match timeout {
+            // Round up, so that idle callbacks are due when waking up.
+            Some(timeout) => {
+                let ms = (timeout.as_nanos() + 999_999) / 1_000_000;
+                std::cmp::min(ms, i32::max_value() as u128) as i32
+            }
+            None => -1,
+        }
+    }
+
+    /// Call idle callbacks which are due.
+    fn run_idle(&mut self) {
+        let now = Instant::now();
+        let ids: Vec<u64> = self.idle_notifiers.keys().copied().collect();
+        for id in ids {
+            // The callback may be removed by the previous one.
+            let callback = match self.idle_notifiers.get_mut(&id) {
+                Some(idle) if idle.time_to_due(now) == Duration::from_secs(0) => {
+                    idle.last_run = now;
+                    idle.callback.clone()
+                }
+                _ => continue,
+            };
+            callback();
+        }
+    }
+
     fn clear_gc(&mut self) {
         let mut gc = self.gc.write().unwrap();
         gc.clear();
@@ -139,6 +312,8 @@ impl MainLoopContext {
         // If there is one same alive event monitored, update the handlers.
         // If there is one same parked event, update the handlers but warn.
         // If there is no event in the map, insert the event and park the related.
+        // Removed event of the same fd is kept in gc instead of the map, so
+        // a reused fd never gets the handlers of the removed one.
         let mut events_map = self.events.write().unwrap();
         if let Some(notifier) = events_map.get_mut(&event.raw_fd) {
             if let NotifierOperation::AddExclusion = event.op {
@@ -177,9 +352,21 @@ impl MainLoopContext {
 
     fn rm_event(&mut self, event: &EventNotifier) -> Result<()> {
         // If there is one same parked event, return Error.
+        // If the event is already removed in the current dispatch batch, do nothing.
         // If there is no event in the map, return Error.
         // If there is one same alive event monitored, put the event in gc and reactivate the parked event.
         let mut events_map = self.events.write().unwrap();
+        if !events_map.contains_key(&event.raw_fd)
+            && self
+                .gc
+                .read()
+                .unwrap()
+                .iter()
+                .any(|removed| removed.raw_fd == event.raw_fd)
+        {
+            return Ok(());
+        }
+
         match events_map.get_mut(&event.raw_fd) {
             Some(notifier) => {
                 if let EventStatus::Parked = notifier.status {
@@ -222,12 +409,54 @@ impl MainLoopContext {
         Ok(())
     }
 
+    fn modify_event(&mut self, event: &EventNotifier) -> Result<()> {
+        // Parked event is monitored with the new event set when it's
+        // reactivated, and removed event is left as it is.
+        let mut events_map = self.events.write().unwrap();
+        match events_map.get_mut(&event.raw_fd) {
+            Some(notifier) => {
+                notifier.event = event.event;
+                if let EventStatus::Alive = notifier.status {
+                    self.epoll.ctl(
+                        ControlOperation::Modify,
+                        notifier.raw_fd,
+                        EpollEvent::new(notifier.event, &**notifier as *const _ as u64),
+                    )?;
+                }
+            }
+            None => {
+                return Err(ErrorKind::NoRegisterFd(event.raw_fd).into());
+            }
+        }
+
+        Ok(())
+    }
+
     /// update fds registered to `MainLoop` according to the operation type.
     ///
+    /// It's safe to be called from `NotifierCallback`, the notifiers are
+    /// queued and applied after the current dispatch batch then. Events
+    /// to be removed are not dispatched any more in this batch.
+    ///
     /// # Arguments
     ///
     /// * `notifiers` - event notifiers wanted to add to or remove from `MainLoop`.
     pub fn update_events(&mut self, notifiers: Vec<EventNotifier>) -> Result<()> {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            let mut events_map = self.events.write().unwrap();
+            for en in notifiers {
+                if let NotifierOperation::Delete = en.op {
+                    if let Some(notifier) = events_map.get_mut(&en.raw_fd) {
+                        if let EventStatus::Alive = notifier.status {
+                            notifier.status = EventStatus::Removed;
+                        }
+                    }
+                }
+                pending.push(en);
+            }
+            return Ok(());
+        }
+
         for en in notifiers {
             match en.op {
                 NotifierOperation::AddExclusion | NotifierOperation::AddShared => {
@@ -236,8 +465,8 @@ impl MainLoopContext {
                 NotifierOperation::Delete => {
                     self.rm_event(&en)?;
                 }
-                _ => {
-                    return Err(ErrorKind::UnExpectedOperationType.into());
+                NotifierOperation::Modify => {
+                    self.modify_event(&en)?;
                 }
             }
         }
@@ -245,6 +474,101 @@ impl MainLoopContext {
         Ok(())
     }
 
+    /// Add a timer whose callback is called in the thread running this
+    /// `MainLoop`. One-shot timer which isn't re-armed in its callback is
+    /// removed after firing.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - Duration before the timer fires, and the interval of
+    ///   periodic timer.
+    /// * `mode` - One-shot or periodic.
+    /// * `callback` - Called when the timer fires.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to create timerfd or add it to epoll.
+    pub fn add_timer(
+        &mut self,
+        duration: Duration,
+        mode: TimerMode,
+        callback: Box<TimerCallback>,
+    ) -> Result<TimerHandle> {
+        let timer = TimerHandle::new()?;
+        let handle = timer.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, _| {
+            if handle.expirations() == 0 || handle.is_cancelled() {
+                return None;
+            }
+            callback(&handle);
+            if !handle.is_armed() {
+                handle.cancel();
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddExclusion,
+            timer.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        );
+        self.update_events(vec![notifier])?;
+        self.timers.push(timer.clone());
+
+        if let Err(e) = timer.rearm(duration, mode) {
+            timer.cancel();
+            self.reap_timers()?;
+            return Err(e);
+        }
+        Ok(timer)
+    }
+
+    /// Remove cancelled timers from epoll.
+    fn reap_timers(&mut self) -> Result<()> {
+        let (cancelled, alive): (Vec<TimerHandle>, Vec<TimerHandle>) = self
+            .timers
+            .drain(..)
+            .partition(|timer| timer.is_cancelled());
+        self.timers = alive;
+
+        let notifiers = cancelled
+            .iter()
+            .map(|timer| {
+                EventNotifier::new(
+                    NotifierOperation::Delete,
+                    timer.as_raw_fd(),
+                    None,
+                    EventSet::IN,
+                    Vec::new(),
+                )
+            })
+            .collect();
+        self.update_events(notifiers)
+    }
+
+    /// Get the order to dispatch ready events by priority, `None` means all
+    /// the ready events share the same priority and are dispatched in the
+    /// order of `ready_events`, which saves sorting in the common case.
+    fn dispatch_order(&self, ev_count: usize) -> Option<Vec<usize>> {
+        // It`s safe because elements in self.events_map never get released in other functions
+        let priority =
+            |i: usize| unsafe { (*(self.ready_events[i].data() as *const EventNotifier)).priority };
+
+        if ev_count < 2 {
+            return None;
+        }
+        let first = priority(0);
+        if (1..ev_count).all(|i| priority(i) == first) {
+            return None;
+        }
+
+        let mut order: Vec<usize> = (0..ev_count).collect();
+        // Stable sort keeps the epoll order of events with equal priority.
+        order.sort_by_key(|&i| std::cmp::Reverse(priority(i)));
+        Some(order)
+    }
+
     /// Executes `epoll.wait()` to wait for events, and call the responding callbacks.
     pub fn run(&mut self) -> Result<bool> {
         match &self.manager {
@@ -257,36 +581,61 @@ impl MainLoopContext {
             None => {}
         }
 
+        self.reap_timers()?;
+        // Notifiers of the reaped timers hold the timerfd.
+        self.clear_gc();
+
+        let timeout = self.epoll_timeout();
         let ev_count = match self
             .epoll
-            .wait(READY_EVENT_MAX, -1, &mut self.ready_events[..])
+            .wait(READY_EVENT_MAX, timeout, &mut self.ready_events[..])
         {
             Ok(ev_count) => ev_count,
             Err(e) if e.raw_os_error() == Some(libc::EINTR) => 0,
             Err(e) => return Err(ErrorKind::EpollWait(e).into()),
         };
+        self.counters.record_iteration(ev_count);
+        if ev_count == 0 {
+            self.run_idle();
+        }
 
-        for i in 0..ev_count {
+        *self.pending.lock().unwrap() = Some(Vec::new());
+
+        let order = self.dispatch_order(ev_count);
+        for k in 0..ev_count {
+            let i = order.as_ref().map_or(k, |order| order[k]);
             // It`s safe because elements in self.events_map never get released in other functions
             let event = unsafe {
                 let event_ptr = self.ready_events[i].data() as *const EventNotifier;
                 &*event_ptr as &EventNotifier
             };
-            if let EventStatus::Alive = event.status {
-                let mut notifiers = Vec::new();
-                for i in 0..event.handlers.len() {
-                    let handle = event.handlers[i].lock().unwrap();
-                    match handle(self.ready_events[i].event_set(), event.raw_fd) {
-                        None => {}
-                        Some(mut notifier) => {
-                            notifiers.append(&mut notifier);
-                        }
-                    }
+            let event_set = self.ready_events[i].event_set();
+            event.dispatch_count.fetch_add(1, Ordering::Relaxed);
+            let timing = self.counters.timing();
+            for j in 0..event.handlers.len() {
+                // The event may be removed by the previous handler, or by the
+                // previous event in this batch.
+                match event.status {
+                    EventStatus::Alive => {}
+                    _ => break,
+                }
+
+                let handle = event.handlers[j].lock().unwrap();
+                let start = if timing { Some(Instant::now()) } else { None };
+                let notifiers = handle(event_set, event.raw_fd);
+                if let Some(start) = start {
+                    self.counters.record_callback(start.elapsed());
+                }
+                drop(handle);
+                if let Some(notifiers) = notifiers {
+                    self.update_events(notifiers)?;
                 }
-                self.update_events(notifiers)?;
             }
         }
 
+        let pending = self.pending.lock().unwrap().take().unwrap_or_default();
+        self.update_events(pending)?;
+        // Removed events are not referred by `ready_events` any more.
         self.clear_gc();
 
         Ok(true)
@@ -341,6 +690,12 @@ mod test {
             }
         }
 
+        fn assert_reaped(&mut self, timer: &TimerHandle) {
+            self.reap_timers().unwrap();
+            assert!(self.timers.is_empty());
+            assert_eq!(self.check_existence(timer.as_raw_fd()), None);
+        }
+
         fn create_event(&mut self) -> i32 {
             let fd = EventFd::new(EFD_NONBLOCK).unwrap();
             let result = fd.as_raw_fd();
@@ -557,6 +912,61 @@ mod test {
         assert!(mainloop.update_events(vec![event1]).is_err());
     }
 
+    #[test]
+    fn modify_event_test() {
+        let mut mainloop = MainLoopContext::new();
+        mainloop.set_wait_timeout(Some(Duration::from_millis(10)));
+        let fd1 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let calls = Arc::new(Mutex::new(0));
+
+        let calls_clone = calls.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, _| {
+            *calls_clone.lock().unwrap() += 1;
+            None
+        });
+        let event1 = EventNotifier::new(
+            NotifierOperation::AddShared,
+            fd1.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        );
+        mainloop.update_events(vec![event1]).unwrap();
+        mainloop.run().unwrap();
+        assert_eq!(*calls.lock().unwrap(), 0);
+
+        // Eventfd is always writable, the handler is called once OUT is
+        // monitored, and not called any more after it's cleared.
+        let modify = |event| {
+            EventNotifier::new(
+                NotifierOperation::Modify,
+                fd1.as_raw_fd(),
+                None,
+                event,
+                Vec::new(),
+            )
+        };
+        mainloop
+            .update_events(vec![modify(EventSet::IN | EventSet::OUT)])
+            .unwrap();
+        mainloop.run().unwrap();
+        assert_eq!(*calls.lock().unwrap(), 1);
+        mainloop.update_events(vec![modify(EventSet::IN)]).unwrap();
+        mainloop.run().unwrap();
+        assert_eq!(*calls.lock().unwrap(), 1);
+
+        // Modify unexist event
+        let fd2 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let event2 = EventNotifier::new(
+            NotifierOperation::Modify,
+            fd2.as_raw_fd(),
+            None,
+            EventSet::OUT,
+            Vec::new(),
+        );
+        assert!(mainloop.update_events(vec![event2]).is_err());
+    }
+
     #[test]
     fn fd_released_test() {
         let mut mainloop = MainLoopContext::new();
@@ -573,4 +983,544 @@ mod test {
 
         assert!(mainloop.update_events(vec![event]).is_ok());
     }
+
+    #[test]
+    fn delete_dispatching_test() {
+        let mut mainloop = MainLoopContext::new();
+        let fd1 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let fd2 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let count = Arc::new(Mutex::new(0));
+
+        // Every handler deletes both events, and fd1 is deleted twice.
+        let raw_fds = [fd1.as_raw_fd(), fd2.as_raw_fd(), fd1.as_raw_fd()];
+        let generate_delete_handler = || -> Box<NotifierCallback> {
+            let count = count.clone();
+            Box::new(move |_, _| {
+                *count.lock().unwrap() += 1;
+                Some(
+                    raw_fds
+                        .iter()
+                        .map(|fd| {
+                            EventNotifier::new(
+                                NotifierOperation::Delete,
+                                *fd,
+                                None,
+                                EventSet::IN,
+                                Vec::new(),
+                            )
+                        })
+                        .collect(),
+                )
+            })
+        };
+        for fd in [&fd1, &fd2].iter() {
+            fd.write(1).unwrap();
+            let event = EventNotifier::new(
+                NotifierOperation::AddShared,
+                fd.as_raw_fd(),
+                None,
+                EventSet::IN,
+                vec![
+                    Arc::new(Mutex::new(generate_delete_handler())),
+                    Arc::new(Mutex::new(generate_delete_handler())),
+                ],
+            );
+            mainloop.update_events(vec![event]).unwrap();
+        }
+
+        // Both events are ready, but only the first handler is called.
+        mainloop.run().unwrap();
+        assert_eq!(*count.lock().unwrap(), 1);
+        assert!(mainloop.check_existence(fd1.as_raw_fd()).is_none());
+        assert!(mainloop.check_existence(fd2.as_raw_fd()).is_none());
+        assert!(mainloop.gc.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn register_in_callback_test() {
+        let mut mainloop = MainLoopContext::new();
+        let fd1 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let fd2 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let fd3 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let generate_record_handler = || -> Box<NotifierCallback> {
+            let fired = fired.clone();
+            Box::new(move |_, fd| {
+                read_fd(fd);
+                fired.lock().unwrap().push(fd);
+                None
+            })
+        };
+        let fd2_notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            fd2.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(generate_record_handler()))],
+        );
+        let fd3_notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            fd3.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(generate_record_handler()))],
+        );
+
+        // fd2 is registered by returning notifier, fd3 is registered by updating
+        // the context directly, like `MainLoop::update_event` does.
+        let ctx: *mut MainLoopContext = &mut mainloop;
+        let notifiers = Mutex::new(Some((fd2_notifier, fd3_notifier)));
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd| {
+            read_fd(fd);
+            let (fd2_notifier, fd3_notifier) = notifiers.lock().unwrap().take()?;
+            unsafe { (*ctx).update_events(vec![fd3_notifier]).unwrap() };
+            Some(vec![
+                EventNotifier::new(
+                    NotifierOperation::Delete,
+                    fd,
+                    None,
+                    EventSet::IN,
+                    Vec::new(),
+                ),
+                fd2_notifier,
+            ])
+        });
+        let event = EventNotifier::new(
+            NotifierOperation::AddShared,
+            fd1.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        );
+        mainloop.update_events(vec![event]).unwrap();
+
+        fd1.write(1).unwrap();
+        fd2.write(1).unwrap();
+        fd3.write(1).unwrap();
+        mainloop.run().unwrap();
+        assert!(mainloop.check_existence(fd1.as_raw_fd()).is_none());
+        assert!(mainloop.check_existence(fd2.as_raw_fd()).unwrap());
+        assert!(mainloop.check_existence(fd3.as_raw_fd()).unwrap());
+        assert!(fired.lock().unwrap().is_empty());
+
+        // New events fire in the next dispatch batch.
+        mainloop.run().unwrap();
+        let mut fired = fired.lock().unwrap().clone();
+        fired.sort();
+        let mut expected = vec![fd2.as_raw_fd(), fd3.as_raw_fd()];
+        expected.sort();
+        assert_eq!(fired, expected);
+    }
+
+    struct ChurnState {
+        evts: Mutex<BTreeMap<RawFd, EventFd>>,
+        count: Mutex<usize>,
+    }
+
+    fn generate_churn_handler(state: Arc<ChurnState>) -> Box<NotifierCallback> {
+        Box::new(move |_, fd| {
+            *state.count.lock().unwrap() += 1;
+
+            // Close the fd before deleting its event, so that the fd number
+            // is likely reused by the new one.
+            state.evts.lock().unwrap().remove(&fd);
+            let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+            evt.write(1).unwrap();
+            let new_fd = evt.as_raw_fd();
+            state.evts.lock().unwrap().insert(new_fd, evt);
+
+            Some(vec![
+                EventNotifier::new(
+                    NotifierOperation::Delete,
+                    fd,
+                    None,
+                    EventSet::IN,
+                    Vec::new(),
+                ),
+                EventNotifier::new(
+                    NotifierOperation::AddShared,
+                    new_fd,
+                    None,
+                    EventSet::IN,
+                    vec![Arc::new(Mutex::new(generate_churn_handler(state.clone())))],
+                ),
+            ])
+        })
+    }
+
+    #[test]
+    fn churn_in_callback_test() {
+        const EVENT_NUM: usize = 16;
+        let mut mainloop = MainLoopContext::new();
+        let state = Arc::new(ChurnState {
+            evts: Mutex::new(BTreeMap::new()),
+            count: Mutex::new(0),
+        });
+
+        for _ in 0..EVENT_NUM {
+            let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+            evt.write(1).unwrap();
+            let event = EventNotifier::new(
+                NotifierOperation::AddShared,
+                evt.as_raw_fd(),
+                None,
+                EventSet::IN,
+                vec![Arc::new(Mutex::new(generate_churn_handler(state.clone())))],
+            );
+            state.evts.lock().unwrap().insert(evt.as_raw_fd(), evt);
+            mainloop.update_events(vec![event]).unwrap();
+        }
+
+        for round in 1..=100 {
+            mainloop.run().unwrap();
+            assert_eq!(*state.count.lock().unwrap(), round * EVENT_NUM);
+        }
+
+        // Re-added events never get the handlers of the deleted ones.
+        let events = mainloop.events.read().unwrap();
+        assert_eq!(events.len(), EVENT_NUM);
+        assert!(events.values().all(|event| event.handlers.len() == 1));
+        assert!(mainloop.gc.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn idle_callback_test() {
+        let mut mainloop = MainLoopContext::new();
+        assert_eq!(mainloop.epoll_timeout(), -1);
+
+        let fast_count = Arc::new(Mutex::new(0));
+        let slow_count = Arc::new(Mutex::new(0));
+        let fast_count_clone = fast_count.clone();
+        let fast = mainloop.add_idle(
+            Duration::from_millis(5),
+            Box::new(move || *fast_count_clone.lock().unwrap() += 1),
+        );
+        let slow_count_clone = slow_count.clone();
+        let slow = mainloop.add_idle(
+            Duration::from_secs(100),
+            Box::new(move || *slow_count_clone.lock().unwrap() += 1),
+        );
+        assert!(mainloop.epoll_timeout() >= 0);
+        assert!(mainloop.epoll_timeout() <= 5);
+
+        // Idle callbacks are called no more than once in the interval.
+        for _ in 0..5 {
+            mainloop.run().unwrap();
+        }
+        assert!(*fast_count.lock().unwrap() >= 1);
+        assert_eq!(*slow_count.lock().unwrap(), 0);
+
+        // Removed idle callbacks are not called any more.
+        assert!(mainloop.rm_idle(fast));
+        assert!(!mainloop.rm_idle(fast));
+        let count = *fast_count.lock().unwrap();
+        mainloop.set_wait_timeout(Some(Duration::from_millis(5)));
+        assert_eq!(mainloop.epoll_timeout(), 5);
+        for _ in 0..3 {
+            mainloop.run().unwrap();
+        }
+        assert_eq!(*fast_count.lock().unwrap(), count);
+
+        assert!(mainloop.rm_idle(slow));
+        mainloop.set_wait_timeout(None);
+        assert_eq!(mainloop.epoll_timeout(), -1);
+    }
+
+    fn run_until(mainloop: &mut MainLoopContext, done: &dyn Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            mainloop.run().unwrap();
+        }
+        panic!("Timers never fired");
+    }
+
+    #[test]
+    fn timer_order_test() {
+        let mut mainloop = MainLoopContext::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let fired_clone = fired.clone();
+        mainloop
+            .add_timer(
+                Duration::from_millis(30),
+                TimerMode::OneShot,
+                Box::new(move |_| fired_clone.lock().unwrap().push(30)),
+            )
+            .unwrap();
+        let fired_clone = fired.clone();
+        let timer = mainloop
+            .add_timer(
+                Duration::from_millis(10),
+                TimerMode::OneShot,
+                Box::new(move |_| fired_clone.lock().unwrap().push(10)),
+            )
+            .unwrap();
+
+        run_until(&mut mainloop, &|| fired.lock().unwrap().len() == 2);
+        assert_eq!(*fired.lock().unwrap(), vec![10, 30]);
+
+        // Finished one-shot timers are removed from epoll.
+        assert!(timer.is_cancelled());
+        mainloop.assert_reaped(&timer);
+    }
+
+    #[test]
+    fn timer_cancel_test() {
+        let mut mainloop = MainLoopContext::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let fired_clone = fired.clone();
+        let cancelled = mainloop
+            .add_timer(
+                Duration::from_millis(10),
+                TimerMode::OneShot,
+                Box::new(move |_| fired_clone.lock().unwrap().push(10)),
+            )
+            .unwrap();
+        let fired_clone = fired.clone();
+        mainloop
+            .add_timer(
+                Duration::from_millis(30),
+                TimerMode::OneShot,
+                Box::new(move |_| fired_clone.lock().unwrap().push(30)),
+            )
+            .unwrap();
+        cancelled.cancel();
+        assert!(!cancelled.is_armed());
+        assert!(cancelled
+            .rearm(Duration::from_millis(10), TimerMode::OneShot)
+            .is_err());
+
+        run_until(&mut mainloop, &|| !fired.lock().unwrap().is_empty());
+        assert_eq!(*fired.lock().unwrap(), vec![30]);
+        assert_eq!(mainloop.check_existence(cancelled.as_raw_fd()), None);
+    }
+
+    #[test]
+    fn timer_periodic_test() {
+        let mut mainloop = MainLoopContext::new();
+        let count = Arc::new(Mutex::new(0));
+
+        let count_clone = count.clone();
+        let timer = mainloop
+            .add_timer(
+                Duration::from_millis(5),
+                TimerMode::Periodic,
+                Box::new(move |handle| {
+                    let mut count = count_clone.lock().unwrap();
+                    *count += 1;
+                    if *count == 3 {
+                        handle.cancel();
+                    }
+                }),
+            )
+            .unwrap();
+
+        run_until(&mut mainloop, &|| timer.is_cancelled());
+        assert_eq!(*count.lock().unwrap(), 3);
+        mainloop.assert_reaped(&timer);
+    }
+
+    #[test]
+    fn timer_rearm_test() {
+        let mut mainloop = MainLoopContext::new();
+        let count = Arc::new(Mutex::new(0));
+
+        let count_clone = count.clone();
+        let timer = mainloop
+            .add_timer(
+                Duration::from_millis(5),
+                TimerMode::OneShot,
+                Box::new(move |handle| {
+                    let mut count = count_clone.lock().unwrap();
+                    *count += 1;
+                    if *count < 3 {
+                        handle
+                            .rearm(Duration::from_millis(5), TimerMode::OneShot)
+                            .unwrap();
+                    }
+                }),
+            )
+            .unwrap();
+
+        run_until(&mut mainloop, &|| timer.is_cancelled());
+        assert_eq!(*count.lock().unwrap(), 3);
+        mainloop.assert_reaped(&timer);
+    }
+
+    #[test]
+    fn edge_triggered_test() {
+        let mut mainloop = MainLoopContext::new();
+        mainloop.set_wait_timeout(Some(Duration::from_millis(10)));
+        let queue_evt = Arc::new(EventFd::new(EFD_NONBLOCK).unwrap());
+        // Number of requests in the avail ring, and the handled ones.
+        let avail = Arc::new(Mutex::new(1));
+        let handled = Arc::new(Mutex::new(0));
+        let calls = Arc::new(Mutex::new(0));
+
+        let (avail_clone, handled_clone, calls_clone) =
+            (avail.clone(), handled.clone(), calls.clone());
+        let guest_evt = queue_evt.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, fd| {
+            read_fd(fd);
+            let mut calls = calls_clone.lock().unwrap();
+            *calls += 1;
+            while *avail_clone.lock().unwrap() > 0 {
+                *avail_clone.lock().unwrap() -= 1;
+                *handled_clone.lock().unwrap() += 1;
+                // Guest kicks while the first request is being handled, the
+                // request is handled in this call since the ring is drained.
+                if *calls == 1 && *handled_clone.lock().unwrap() == 1 {
+                    *avail_clone.lock().unwrap() += 1;
+                    guest_evt.write(1).unwrap();
+                }
+            }
+            // Guest kicks after the ring is found empty, before returning.
+            if *calls == 1 {
+                *avail_clone.lock().unwrap() += 1;
+                guest_evt.write(1).unwrap();
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            queue_evt.as_raw_fd(),
+            None,
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
+            vec![Arc::new(Mutex::new(handler))],
+        );
+        mainloop.update_events(vec![notifier]).unwrap();
+
+        queue_evt.write(1).unwrap();
+        mainloop.run().unwrap();
+        assert_eq!(*calls.lock().unwrap(), 1);
+        assert_eq!(*handled.lock().unwrap(), 2);
+        assert_eq!(*avail.lock().unwrap(), 1);
+
+        // The late kick is not lost.
+        mainloop.run().unwrap();
+        assert_eq!(*calls.lock().unwrap(), 2);
+        assert_eq!(*handled.lock().unwrap(), 3);
+        assert_eq!(*avail.lock().unwrap(), 0);
+
+        // No redundant wake-up once the eventfd and the ring are drained.
+        mainloop.run().unwrap();
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn stats_test() {
+        let mut mainloop = MainLoopContext::new();
+        let stats = mainloop.stats_handle();
+        let fd1 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let fd2 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let notifiers = [&fd1, &fd2]
+            .iter()
+            .map(|fd| {
+                let handler: Box<NotifierCallback> = Box::new(|_, fd| {
+                    read_fd(fd);
+                    None
+                });
+                EventNotifier::new(
+                    NotifierOperation::AddShared,
+                    fd.as_raw_fd(),
+                    None,
+                    EventSet::IN,
+                    vec![Arc::new(Mutex::new(handler))],
+                )
+            })
+            .collect();
+        mainloop.update_events(notifiers).unwrap();
+
+        fd1.write(1).unwrap();
+        fd2.write(1).unwrap();
+        mainloop.run().unwrap();
+        fd1.write(1).unwrap();
+        mainloop.run().unwrap();
+
+        // Callbacks are not timed until the first query.
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.iterations, 2);
+        assert_eq!(snapshot.wakeups, 2);
+        assert_eq!(snapshot.events, 3);
+        assert_eq!(snapshot.max_events, 2);
+        assert!(snapshot.notifiers.contains(&(fd1.as_raw_fd(), 2)));
+        assert!(snapshot.notifiers.contains(&(fd2.as_raw_fd(), 1)));
+        assert_eq!(snapshot.callback_time, None);
+
+        fd2.write(1).unwrap();
+        mainloop.run().unwrap();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.events, 4);
+        assert_eq!(snapshot.callback_time.unwrap().iter().sum::<u64>(), 1);
+
+        stats.reset();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.iterations, 0);
+        assert_eq!(snapshot.max_events, 0);
+        assert!(snapshot.notifiers.iter().all(|(_, count)| *count == 0));
+        assert_eq!(snapshot.callback_time.unwrap().iter().sum::<u64>(), 0);
+    }
+
+    #[test]
+    fn priority_test() {
+        let mut mainloop = MainLoopContext::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let fds: Vec<EventFd> = (0..3)
+            .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+            .collect();
+
+        let mut notifiers = Vec::new();
+        for (index, priority) in [-1, 5, DEFAULT_PRIORITY].iter().enumerate() {
+            let order_clone = order.clone();
+            let handler: Box<NotifierCallback> = Box::new(move |_, fd| {
+                read_fd(fd);
+                order_clone.lock().unwrap().push(index);
+                None
+            });
+            let notifier = EventNotifier::new(
+                NotifierOperation::AddShared,
+                fds[index].as_raw_fd(),
+                None,
+                EventSet::IN,
+                vec![Arc::new(Mutex::new(handler))],
+            )
+            .with_priority(*priority);
+            notifiers.push(notifier);
+        }
+        mainloop.update_events(notifiers).unwrap();
+
+        // All the three fds are ready in one wake-up.
+        for fd in fds.iter() {
+            fd.write(1).unwrap();
+        }
+        mainloop.run().unwrap();
+        assert_eq!(*order.lock().unwrap(), vec![1, 2, 0]);
+
+        // Handlers added later share the priority of the fd.
+        let order_clone = order.clone();
+        let handler: Box<NotifierCallback> = Box::new(move |_, _| {
+            order_clone.lock().unwrap().push(3);
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            fds[0].as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![Arc::new(Mutex::new(handler))],
+        )
+        .with_priority(10);
+        mainloop.update_events(vec![notifier]).unwrap();
+
+        order.lock().unwrap().clear();
+        for fd in fds.iter() {
+            fd.write(1).unwrap();
+        }
+        mainloop.run().unwrap();
+        assert_eq!(*order.lock().unwrap(), vec![1, 2, 0, 3]);
+    }
 }
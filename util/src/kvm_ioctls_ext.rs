@@ -10,10 +10,12 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use kvm_bindings::{kvm_device_attr, KVMIO};
-use kvm_ioctls::DeviceFd;
+use std::os::unix::io::AsRawFd;
+
+use kvm_bindings::{kvm_create_device, kvm_device_attr, KVMIO, KVM_CREATE_DEVICE_TEST};
+use kvm_ioctls::{DeviceFd, KvmRunWrapper, VmFd};
 use vmm_sys_util::errno;
-use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
 
 pub type Result<T> = std::result::Result<T, errno::Error>;
 
@@ -52,5 +54,54 @@ pub fn check_device_attr(device_fd: &DeviceFd, device_attr: &kvm_device_attr) ->
     Ok(ret as u32)
 }
 
+/// Check whether an extension of KVM is supported.
+///
+/// See the documentation for `KVM_CHECK_EXTENSION`.
+///
+/// # Arguments
+///
+/// * `fd` - File descriptor of `/dev/kvm` or VM.
+/// * `cap` - The capability to be checked, one of `KVM_CAP_*`.
+pub fn check_extension<F: AsRawFd>(fd: &F, cap: u32) -> bool {
+    // Safe because the ioctl doesn't touch memory of the process.
+    let ret = unsafe { ioctl_with_val(fd, KVM_CHECK_EXTENSION(), libc::c_ulong::from(cap)) };
+    ret > 0
+}
+
+/// Check whether a device of `device_type` can be created in VM, nothing
+/// is created.
+///
+/// See the documentation for `KVM_CREATE_DEVICE` with `KVM_CREATE_DEVICE_TEST`.
+///
+/// # Arguments
+///
+/// * `vm_fd` - File descriptor of VM.
+/// * `device_type` - The device type to be checked, one of `KVM_DEV_TYPE_*`.
+pub fn check_device_type(vm_fd: &VmFd, device_type: u32) -> bool {
+    let mut device = kvm_create_device {
+        type_: device_type,
+        fd: 0,
+        flags: KVM_CREATE_DEVICE_TEST,
+    };
+    let ret = unsafe {
+        // Here we trust the kernel not to read past the end of the kvm_create_device struct.
+        ioctl_with_mut_ref(vm_fd, KVM_CREATE_DEVICE(), &mut device)
+    };
+    ret == 0
+}
+
+/// Get the type of the system event which the vcpu exits for at last, one
+/// of `KVM_SYSTEM_EVENT_*`, since `VcpuExit::SystemEvent` doesn't carry it.
+///
+/// # Arguments
+///
+/// * `kvm_run` - Mapping of `kvm_run` of the vcpu exiting with `KVM_EXIT_SYSTEM_EVENT`.
+pub fn get_system_event_type(kvm_run: &KvmRunWrapper) -> u32 {
+    // Safe because the union is filled as `system_event` on `KVM_EXIT_SYSTEM_EVENT`.
+    unsafe { kvm_run.as_mut_ref().__bindgen_anon_1.system_event.type_ }
+}
+
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_iowr_nr!(KVM_CREATE_DEVICE, KVMIO, 0xe0, kvm_create_device);
 ioctl_iow_nr!(KVM_GET_DEVICE_ATTR, KVMIO, 0xe2, kvm_device_attr);
 ioctl_iow_nr!(KVM_HAS_DEVICE_ATTR, KVMIO, 0xe3, kvm_device_attr);
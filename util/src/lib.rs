@@ -22,17 +22,25 @@ extern crate kvm_ioctls;
 
 pub mod aio;
 pub mod arg_parser;
+pub mod boot_time;
 pub mod byte_code;
 pub mod checksum;
+pub mod cleanup;
 pub mod daemonize;
 pub mod device_tree;
 pub mod epoll_context;
 pub mod kvm_ioctls_ext;
 mod link_list;
+pub mod loop_stats;
 pub mod num_ops;
+pub mod pidfile;
 pub mod seccomp;
+pub mod signal;
+pub mod state;
 pub mod tap;
+pub mod timer;
 pub mod unix;
+pub mod uuid;
 #[macro_use]
 pub mod logger;
 #[macro_use]
@@ -88,9 +96,10 @@ pub mod errors {
                 description("Unable to redirect standard streams to /dev/null.")
                 display("Unable to redirect standard streams to /dev/null.")
             }
-            PidFileExist {
-                description("Pidfile path is existed yet.")
-                display("Pidfile path is existed yet.")
+            // pidfile error
+            PidFileInUse(path: String, pid: String) {
+                description("Pid file is in use by a running process.")
+                display("Pid file {} is in use, StratoVirt is already running (pid {})", path, pid)
             }
             // epoll_context error
             BadSyscall(err: std::io::Error) {
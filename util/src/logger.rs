@@ -13,11 +13,39 @@
 extern crate libc;
 extern crate log;
 
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::sync::Mutex;
+use std::io::BufWriter;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex, Once};
 
+use crate::errors::{Result, ResultExt};
 use crate::unix::gettid;
-use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+static INIT_LOG_NAME: Once = Once::new();
+static mut LOG_NAME: Option<String> = None;
+
+/// Set the name of VM shown in every log line, so that logs of VMs on the
+/// same host can be told apart. Only the first call takes effect.
+///
+/// # Arguments
+///
+/// * `name` - Name of VM.
+pub fn set_log_name(name: &str) {
+    // It's safe because `LOG_NAME` is only written once under `Once`.
+    INIT_LOG_NAME.call_once(|| unsafe {
+        LOG_NAME = Some(name.to_string());
+    });
+}
+
+fn log_name() -> Option<&'static str> {
+    if !INIT_LOG_NAME.is_completed() {
+        return None;
+    }
+    // It's safe because `LOG_NAME` is never written after `Once` completes.
+    unsafe { LOG_NAME.as_deref() }
+}
 
 fn format_now() -> String {
     let mut ts = libc::timespec {
@@ -31,79 +59,142 @@ fn format_now() -> String {
         libc::localtime_r(&ts.tv_sec, &mut ti);
     }
 
+    let offset_sign = if ti.tm_gmtoff < 0 { '-' } else { '+' };
+    let offset = ti.tm_gmtoff.abs();
     format!(
-        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}",
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}{}{:02}:{:02}",
         ti.tm_year + 1900,
         ti.tm_mon + 1,
         ti.tm_mday,
         ti.tm_hour,
         ti.tm_min,
         ti.tm_sec,
-        ts.tv_nsec
+        ts.tv_nsec / 1000,
+        offset_sign,
+        offset / 3600,
+        offset % 3600 / 60
     )
 }
 
-/// Format like "%year-%mon-%dayT%hour:%min:%sec.%nsec
+/// Format like "%year-%mon-%dayT%hour:%min:%sec.%usec%offset: [%pid][%tid][%name][%module]:%level: %msg",
+/// the time is RFC3339 with microseconds, and the file and line of error is
+/// put after module.
+fn format_record(record: &Record) -> String {
+    let pid = unsafe { libc::getpid() };
+    let tid = gettid();
+    let name = log_name().map_or(String::new(), |name| format!("[{}]", name));
+    let location = match record.level() {
+        Level::Error => format!(
+            "[{}: {}]",
+            record.file().unwrap_or(""),
+            record.line().unwrap_or(0)
+        ),
+        _ => String::new(),
+    };
+
+    format!(
+        "{}: [{}][{}]{}[{}]{}:{}: {}\n",
+        format_now(),
+        pid,
+        tid,
+        name,
+        record.module_path().unwrap_or(""),
+        location,
+        record.level(),
+        record.args()
+    )
+}
+
+fn open_log_file(path: &str) -> Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(path)
+        .chain_err(|| format!("Failed to open log file {}", path))
+}
+
+/// Writer of log, which is stdout or a log file.
+struct LogWriter {
+    writer: Box<dyn Write + Send>,
+    /// Path of log file, which is reopened for rotation. `None` if log is
+    /// written to stdout.
+    path: Option<String>,
+}
+
+impl LogWriter {
+    fn new(path: Option<&str>) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(BufWriter::new(open_log_file(path)?)),
+            None => Box::new(std::io::stdout()),
+        };
+        Ok(LogWriter {
+            writer,
+            path: path.map(String::from),
+        })
+    }
+
+    /// Write one line of log, it's flushed at once so that the log is
+    /// complete if the process is killed, and the buffer makes the line
+    /// written by one syscall.
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()
+    }
+
+    /// Open the log file again, so that the log is written to a new file
+    /// after the old one is renamed by log rotation.
+    fn reopen(&mut self) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => bail!("Log is not written to a file"),
+        };
+        let file = open_log_file(path)?;
+        // Nothing is left in the buffer as every line is flushed.
+        self.writer = Box::new(BufWriter::new(file));
+        Ok(())
+    }
+}
+
+static INIT_LOG_WRITER: Once = Once::new();
+static mut LOG_WRITER: Option<Arc<Mutex<LogWriter>>> = None;
+
+fn log_writer() -> Option<&'static Arc<Mutex<LogWriter>>> {
+    if !INIT_LOG_WRITER.is_completed() {
+        return None;
+    }
+    // It's safe because `LOG_WRITER` is never written after `Once` completes.
+    unsafe { LOG_WRITER.as_ref() }
+}
+
 struct VmLogger {
-    handler: Option<Mutex<Box<dyn Write + Send>>>,
+    writer: Arc<Mutex<LogWriter>>,
     level: Level,
 }
 
 impl Log for VmLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        self.handler.is_some() && metadata.level() <= self.level
+        metadata.level() <= self.level
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let pid = unsafe { libc::getpid() };
-            let tid = gettid();
-
-            self.handler.as_ref().map(|writer| match record.level() {
-                Level::Error => writer.lock().unwrap().write_fmt(format_args!(
-                    "{:<5}: [{}][{}][{}: {}]:{}: {}\n",
-                    format_now(),
-                    pid,
-                    tid,
-                    record.file().unwrap_or(""),
-                    record.line().unwrap_or(0),
-                    record.level(),
-                    record.args()
-                )),
-                _ => writer.lock().unwrap().write_fmt(format_args!(
-                    "{:<5}: [{}][{}]:{}: {}\n",
-                    format_now(),
-                    pid,
-                    tid,
-                    record.level(),
-                    record.args()
-                )),
-            });
+            let line = format_record(record);
+            // Failing to write log is ignored, as there is nowhere to report
+            // it, and it must not break VM.
+            if let Ok(mut writer) = self.writer.lock() {
+                let _ = writer.write_line(&line);
+            }
         }
     }
 
     fn flush(&self) {}
 }
 
-pub fn init_vm_logger(
-    level: Option<Level>,
-    logfile: Option<Box<dyn Write + Send>>,
-) -> Result<(), log::SetLoggerError> {
-    let buffer = match logfile {
-        Some(x) => Some(Mutex::new(x)),
-        None => None,
-    };
-
-    let logger = VmLogger {
-        level: level.unwrap_or(Level::Info),
-        handler: buffer,
-    };
-
-    log::set_boxed_logger(Box::new(logger)).map(|()| log::set_max_level(LevelFilter::Trace))
-}
-
-pub fn init_logger_with_env(logfile: Option<Box<dyn Write + Send>>) -> Result<(), SetLoggerError> {
-    let level = match std::env::var("STRATOVIRT_LOG_LEVEL") {
+/// Get the level of log set by env `STRATOVIRT_LOG_LEVEL`, which is `error`
+/// if it's not set or unknown.
+fn env_level() -> Level {
+    match std::env::var("STRATOVIRT_LOG_LEVEL") {
         Ok(l) => match l.to_lowercase().as_str() {
             "trace" => Level::Trace,
             "debug" => Level::Debug,
@@ -112,9 +203,119 @@ pub fn init_logger_with_env(logfile: Option<Box<dyn Write + Send>>) -> Result<()
             _ => Level::Error,
         },
         _ => Level::Error,
-    };
+    }
+}
 
-    init_vm_logger(Some(level), logfile)?;
+/// Init the logger of process, it must be called before VM is created so
+/// that all the errors are logged.
+///
+/// # Arguments
+///
+/// * `level` - Level of log, the one set by env `STRATOVIRT_LOG_LEVEL` is
+///   used if it's `None`.
+/// * `path` - Path of log file, log is written to stdout if it's `None`.
+///
+/// # Errors
+///
+/// Return Error if fail to open log file, or logger is already initialized.
+pub fn init_logger(level: Option<Level>, path: Option<&str>) -> Result<()> {
+    let writer = Arc::new(Mutex::new(LogWriter::new(path)?));
+    let logger = VmLogger {
+        writer: writer.clone(),
+        level: level.unwrap_or_else(env_level),
+    };
+    log::set_boxed_logger(Box::new(logger))
+        .map(|()| log::set_max_level(LevelFilter::Trace))
+        .map_err(|e| format!("Failed to set logger: {}", e))?;
 
+    // It's safe because `LOG_WRITER` is only written once under `Once`.
+    INIT_LOG_WRITER.call_once(|| unsafe {
+        LOG_WRITER = Some(writer);
+    });
     Ok(())
 }
+
+/// Reopen the log file for log rotation, which is requested by SIGHUP or
+/// QMP command `logfile-reopen`.
+///
+/// # Errors
+///
+/// Return Error if log is not written to a file, or fail to open it.
+pub fn reopen_log_file() -> Result<()> {
+    match log_writer() {
+        Some(writer) => writer.lock().unwrap().reopen(),
+        None => bail!("Logger is not initialized"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_record() {
+        let line = format_record(
+            &Record::builder()
+                .args(format_args!("device {} is ready", "net0"))
+                .level(Level::Warn)
+                .module_path(Some("device_model::virtio::net"))
+                .file(Some("device_model/src/virtio/net.rs"))
+                .line(Some(42))
+                .build(),
+        );
+        // Such as "2020-09-30T10:08:05.123456+08:00".
+        let (time, rest) = line.split_at(32);
+        let time = time.as_bytes();
+        assert_eq!(time[10], b'T');
+        assert_eq!(time[19], b'.');
+        assert!(time[26] == b'+' || time[26] == b'-');
+        assert_eq!(time[29], b':');
+        assert_eq!(
+            rest,
+            format!(
+                ": [{}][{}][device_model::virtio::net]:WARN: device net0 is ready\n",
+                unsafe { libc::getpid() },
+                gettid()
+            )
+        );
+
+        let line = format_record(
+            &Record::builder()
+                .args(format_args!("failed"))
+                .level(Level::Error)
+                .module_path(Some("util::aio"))
+                .file(Some("util/src/aio/mod.rs"))
+                .line(Some(7))
+                .build(),
+        );
+        assert!(line.ends_with("[util::aio][util/src/aio/mod.rs: 7]:ERROR: failed\n"));
+    }
+
+    #[test]
+    fn test_reopen_log_file() {
+        let path = std::env::temp_dir()
+            .join(format!("stratovirt-test-{}.log", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let rotated = format!("{}.1", path);
+
+        let mut writer = LogWriter::new(Some(&path)).unwrap();
+        writer.write_line("line 1\n").unwrap();
+        // Log is written to the renamed file until it's reopened.
+        std::fs::rename(&path, &rotated).unwrap();
+        writer.write_line("line 2\n").unwrap();
+        writer.reopen().unwrap();
+        writer.write_line("line 3\n").unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&rotated).unwrap(),
+            "line 1\nline 2\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "line 3\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+
+        // Stdout can't be reopened.
+        assert!(LogWriter::new(None).unwrap().reopen().is_err());
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeMap;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::epoll_context::EventNotifier;
+
+/// Upper bounds in microseconds of the buckets of callback execution time,
+/// the last bucket collects the callbacks slower than all of them.
+pub const CALLBACK_TIME_BUCKETS_US: [u64; 6] = [10, 100, 1_000, 10_000, 100_000, 1_000_000];
+const CALLBACK_TIME_BUCKET_NUM: usize = CALLBACK_TIME_BUCKETS_US.len() + 1;
+
+/// Counters updated by the thread running the event loop, they're atomic so
+/// that they can be read from other threads without locking.
+#[derive(Default)]
+pub(crate) struct LoopCounters {
+    /// Number of `epoll_wait` returned.
+    iterations: AtomicU64,
+    /// Number of `epoll_wait` returned with ready events.
+    wakeups: AtomicU64,
+    /// Number of ready events dispatched.
+    events: AtomicU64,
+    /// Max number of ready events in one wake-up.
+    max_events: AtomicU64,
+    /// Whether to time callbacks, it's enabled by the first query.
+    timing: AtomicBool,
+    /// Histogram of callback execution time.
+    callback_time: [AtomicU64; CALLBACK_TIME_BUCKET_NUM],
+}
+
+impl LoopCounters {
+    /// Record one iteration of event loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `ev_count` - Number of ready events in this iteration.
+    pub(crate) fn record_iteration(&self, ev_count: usize) {
+        self.iterations.fetch_add(1, Ordering::Relaxed);
+        if ev_count == 0 {
+            return;
+        }
+        let ev_count = ev_count as u64;
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+        self.events.fetch_add(ev_count, Ordering::Relaxed);
+        // Only the loop thread updates it, so no compare-exchange is needed.
+        if ev_count > self.max_events.load(Ordering::Relaxed) {
+            self.max_events.store(ev_count, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether callbacks should be timed.
+    pub(crate) fn timing(&self) -> bool {
+        self.timing.load(Ordering::Relaxed)
+    }
+
+    /// Record the execution time of one callback.
+    pub(crate) fn record_callback(&self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        let bucket = CALLBACK_TIME_BUCKETS_US
+            .iter()
+            .position(|bound| us < *bound)
+            .unwrap_or(CALLBACK_TIME_BUCKETS_US.len());
+        self.callback_time[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the statistics of an event loop.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LoopStats {
+    /// Number of `epoll_wait` returned.
+    pub iterations: u64,
+    /// Number of `epoll_wait` returned with ready events.
+    pub wakeups: u64,
+    /// Number of ready events dispatched.
+    pub events: u64,
+    /// Max number of ready events in one wake-up.
+    pub max_events: u64,
+    /// Dispatch count of each registered fd.
+    pub notifiers: Vec<(RawFd, u64)>,
+    /// Histogram of callback execution time, bucketed by
+    /// `CALLBACK_TIME_BUCKETS_US`. It's `None` before the first query, since
+    /// callbacks are not timed until statistics are queried.
+    pub callback_time: Option<Vec<u64>>,
+}
+
+/// Handle to read the statistics of an event loop from any thread.
+#[derive(Clone)]
+pub struct LoopStatsHandle {
+    pub(crate) counters: Arc<LoopCounters>,
+    pub(crate) events: Arc<RwLock<BTreeMap<i32, Box<EventNotifier>>>>,
+}
+
+impl LoopStatsHandle {
+    /// Get the statistics, and start timing callbacks if it's the first
+    /// query.
+    pub fn snapshot(&self) -> LoopStats {
+        let counters = &self.counters;
+        let callback_time = if counters.timing.swap(true, Ordering::Relaxed) {
+            Some(
+                counters
+                    .callback_time
+                    .iter()
+                    .map(|count| count.load(Ordering::Relaxed))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let notifiers = self
+            .events
+            .read()
+            .unwrap()
+            .values()
+            .map(|event| (event.raw_fd, event.dispatch_count.load(Ordering::Relaxed)))
+            .collect();
+
+        LoopStats {
+            iterations: counters.iterations.load(Ordering::Relaxed),
+            wakeups: counters.wakeups.load(Ordering::Relaxed),
+            events: counters.events.load(Ordering::Relaxed),
+            max_events: counters.max_events.load(Ordering::Relaxed),
+            notifiers,
+            callback_time,
+        }
+    }
+
+    /// Zero all the counters, timing of callbacks keeps enabled.
+    pub fn reset(&self) {
+        let counters = &self.counters;
+        counters.iterations.store(0, Ordering::Relaxed);
+        counters.wakeups.store(0, Ordering::Relaxed);
+        counters.events.store(0, Ordering::Relaxed);
+        counters.max_events.store(0, Ordering::Relaxed);
+        for count in counters.callback_time.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+        for event in self.events.read().unwrap().values() {
+            event.dispatch_count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_counters() {
+        let counters = LoopCounters::default();
+        counters.record_iteration(0);
+        counters.record_iteration(3);
+        counters.record_iteration(1);
+        assert_eq!(counters.iterations.load(Ordering::Relaxed), 3);
+        assert_eq!(counters.wakeups.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.events.load(Ordering::Relaxed), 4);
+        assert_eq!(counters.max_events.load(Ordering::Relaxed), 3);
+
+        counters.record_callback(Duration::from_micros(5));
+        counters.record_callback(Duration::from_micros(10));
+        counters.record_callback(Duration::from_secs(2));
+        let histogram: Vec<u64> = counters
+            .callback_time
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        assert_eq!(histogram, vec![1, 1, 0, 0, 0, 0, 1]);
+    }
+}
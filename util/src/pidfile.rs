@@ -0,0 +1,193 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Pid file of StratoVirt process.
+//!
+//! The pid file is locked by [flock(2)](https://man7.org/linux/man-pages/man2/flock.2.html)
+//! as long as the process is running, so that another process given the same
+//! pid file fails at once. A pid file left by a process which has exited is
+//! stale, and is replaced.
+
+extern crate libc;
+
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use crate::errors::{ErrorKind, Result, ResultExt};
+
+/// Pid file locked by the current process.
+pub struct PidFile {
+    /// Absolute path of pid file, which is removed after working directory
+    /// is changed.
+    path: std::path::PathBuf,
+    /// The opened pid file holding the lock.
+    file: File,
+}
+
+impl PidFile {
+    /// Write pid of the current process to the pid file at `path`. The pid
+    /// is written to a temporary file, which is renamed to `path`, so that
+    /// the pid file is never seen half written.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of pid file.
+    ///
+    /// # Errors
+    ///
+    /// `PidFileInUse` Error, the pid file is locked by another process, or
+    /// the pid in it is still running.
+    pub fn create(path: &str) -> Result<Self> {
+        let abs_path = std::env::current_dir()?.join(path);
+        let pid = std::process::id();
+        let tmp_path = format!("{}.{}.tmp", path, pid);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .chain_err(|| format!("Failed to create temporary pid file {}", tmp_path))?;
+
+        let ret = lock_file(&file)
+            .and_then(|_| {
+                writeln!(file, "{}", pid)?;
+                file.sync_all()?;
+                Ok(())
+            })
+            .chain_err(|| format!("Failed to write temporary pid file {}", tmp_path))
+            .and_then(|_| check_existing(path))
+            .and_then(|_| {
+                std::fs::rename(&tmp_path, path)
+                    .chain_err(|| format!("Failed to rename {} to {}", tmp_path, path))
+            });
+        if let Err(e) = ret {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        Ok(PidFile {
+            path: abs_path,
+            file,
+        })
+    }
+
+    /// Remove the pid file, the lock is released after it's removed.
+    pub fn remove(self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("Failed to remove pid file {}: {}", self.path.display(), e);
+        }
+        drop(self.file);
+    }
+}
+
+/// Take the exclusive lock of `file` without blocking.
+fn lock_file(file: &File) -> std::io::Result<()> {
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Check whether the existing pid file at `path` is in use.
+///
+/// # Errors
+///
+/// `PidFileInUse` Error, the pid file is locked, or the pid in it is still
+/// running.
+fn check_existing(path: &str) -> Result<()> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).chain_err(|| format!("Failed to open pid file {}", path)),
+    };
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .chain_err(|| format!("Failed to read pid file {}", path))?;
+    let old_pid = content.trim().to_string();
+
+    let in_use = match lock_file(&file) {
+        Ok(()) => old_pid
+            .parse::<libc::pid_t>()
+            .map(pid_is_running)
+            .unwrap_or(false),
+        Err(ref e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => true,
+        Err(e) => return Err(e).chain_err(|| format!("Failed to lock pid file {}", path)),
+    };
+    if in_use {
+        return Err(ErrorKind::PidFileInUse(path.to_string(), old_pid).into());
+    }
+
+    warn!("Replace stale pid file {} of pid '{}'", path, old_pid);
+    Ok(())
+}
+
+/// Check whether the process of `pid` exists.
+fn pid_is_running(pid: libc::pid_t) -> bool {
+    // Signal 0 only checks the existence of process, and pid 0 or a negative
+    // one means a process group.
+    if pid <= 0 {
+        return false;
+    }
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cleanup::CleanupHooks;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir()
+            .join(format!("stratovirt-test-{}.pid", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let pid = format!("{}\n", std::process::id());
+
+        // Stale pid files are replaced, pid_max is never a valid pid.
+        let pid_max = std::fs::read_to_string("/proc/sys/kernel/pid_max").unwrap();
+        for stale in [pid_max.trim(), "", "garbage"].iter() {
+            std::fs::write(&path, stale).unwrap();
+            let pid_file = PidFile::create(&path).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), pid);
+            pid_file.remove();
+        }
+
+        // The pid file is locked until it's removed on exit.
+        let pid_file = PidFile::create(&path).unwrap();
+        match PidFile::create(&path) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                format!(
+                    "Pid file {} is in use, StratoVirt is already running (pid {})",
+                    path,
+                    std::process::id()
+                )
+            ),
+            Ok(_) => panic!("Pid file in use is replaced"),
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), pid);
+        let tmp_path = format!("{}.{}.tmp", path, std::process::id());
+        assert!(!std::path::Path::new(&tmp_path).exists());
+
+        let mut hooks = CleanupHooks::default();
+        hooks.register("pid file", Box::new(move || pid_file.remove()));
+        hooks.run();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
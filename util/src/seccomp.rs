@@ -113,9 +113,12 @@ const SECCOMP_RET_KILL: u32 = 0x0000_0000;
 const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
 const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
 const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
 const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
 /// See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/linux/seccomp.h#L45
 const SECCOMP_RET_MASK: u32 = 0x0000_ffff;
+/// See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/linux/seccomp.h#L17
+const SECCOMP_GET_ACTION_AVAIL: u32 = 2;
 
 /// System call convention as an AUDIT_ARCH_* value
 #[cfg(target_arch = "x86_64")]
@@ -131,6 +134,10 @@ const AUDIT_ARCH_X86_64: u32 = EM_X86_64 | __AUDIT_ATCH_64BIT | __AUDIT_ARCH_LE;
 /// See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/linux/audit.h#L376
 const AUDIT_ARCH_AARCH64: u32 = EM_AARCH64 | __AUDIT_ATCH_64BIT | __AUDIT_ARCH_LE;
 
+// Syscalls of an unknown architecture would be all killed.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("Seccomp is only supported on x86_64 and aarch64");
+
 /// Compared operator in bpf filter rule.
 #[derive(Copy, Clone, PartialEq)]
 pub enum SeccompCmpOpt {
@@ -166,6 +173,26 @@ pub enum SeccompOpt {
     Trace(u32),
     /// Allow.
     Allow,
+    /// Allow after the syscall is logged by kernel.
+    Log,
+}
+
+impl SeccompOpt {
+    /// Check whether the operation is supported by kernel, `Log` is
+    /// supported since Linux 4.14.
+    pub fn is_available(self) -> bool {
+        // Data of `Errno` and `Trace` is not a part of the action.
+        let action: u32 = Into::<u32>::into(self) & !SECCOMP_RET_MASK;
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_GET_ACTION_AVAIL,
+                0,
+                &action as *const u32,
+            )
+        };
+        ret == 0
+    }
 }
 
 impl Into<u32> for SeccompOpt {
@@ -176,6 +203,7 @@ impl Into<u32> for SeccompOpt {
             SeccompOpt::Errno(x) => SECCOMP_RET_ERRNO | (x & SECCOMP_RET_MASK),
             SeccompOpt::Trace(x) => SECCOMP_RET_TRACE | (x & SECCOMP_RET_MASK),
             SeccompOpt::Allow => SECCOMP_RET_ALLOW,
+            SeccompOpt::Log => SECCOMP_RET_LOG,
         }
     }
 }
@@ -300,6 +328,11 @@ impl BpfRule {
         }
     }
 
+    /// Get the number of system call allowed by this rule.
+    pub fn syscall_num(&self) -> i64 {
+        i64::from(self.header_rule.k)
+    }
+
     /// Allow a syscall with arguments limitation in bpf-filter.
     ///
     /// # Arguments
@@ -580,4 +613,12 @@ mod tests {
 
         assert_eq!(seccomp_filter.sock_filters, bpf_vec);
     }
+
+    #[test]
+    fn test_opt_available() {
+        assert!(SeccompOpt::Allow.is_available());
+        assert!(SeccompOpt::Trap.is_available());
+        assert!(SeccompOpt::Errno(libc::EPERM as u32).is_available());
+        assert_eq!(Into::<u32>::into(SeccompOpt::Log), 0x7ffc_0000);
+    }
 }
@@ -0,0 +1,196 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use libc::{c_int, c_void, signalfd_siginfo, sigset_t};
+
+use crate::errors::Result;
+
+/// Signalfd receiving the blocked signals, so that signals are handled
+/// synchronously in main loop instead of signal handler.
+pub struct SignalFd {
+    fd: RawFd,
+}
+
+impl SignalFd {
+    /// Block `signals` in the calling thread and create a non-blocking
+    /// signalfd for them. Threads created later inherit the signal mask, so
+    /// it must be called before any other thread is spawned to make sure the
+    /// signals are never delivered asynchronously.
+    ///
+    /// # Arguments
+    ///
+    /// * `signals` - Signals to be received from signalfd.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to block signals or create signalfd.
+    pub fn new(signals: &[c_int]) -> Result<Self> {
+        // It's safe because `mask` is initialized by `sigemptyset`.
+        let mut mask: sigset_t = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut mask) };
+        for signal in signals {
+            if unsafe { libc::sigaddset(&mut mask, *signal) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+
+        let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut()) };
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret).into());
+        }
+
+        let fd = unsafe { libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(SignalFd { fd })
+    }
+
+    /// Read one pending signal, return `None` if there is no pending one.
+    pub fn read(&self) -> Option<c_int> {
+        // It's safe because `signalfd_siginfo` is plain data.
+        let mut info: signalfd_siginfo = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<signalfd_siginfo>();
+        let ret = unsafe { libc::read(self.fd, &mut info as *mut _ as *mut c_void, size) };
+        if ret as usize == size {
+            Some(info.ssi_signo as c_int)
+        } else {
+            None
+        }
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        // Safe because the fd is owned by `SignalFd`.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Ignore SIGPIPE in the whole process, so that writing to a closed socket
+/// returns EPIPE instead of killing the process.
+///
+/// # Errors
+///
+/// Return Error if fail to set signal disposition.
+pub fn ignore_sigpipe() -> Result<()> {
+    if unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN) } == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::thread::JoinHandleExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+
+    use vmm_sys_util::epoll::EventSet;
+
+    use super::*;
+    use crate::epoll_context::{
+        EventNotifier, MainLoopContext, MainLoopManager, NotifierCallback, NotifierOperation,
+    };
+
+    struct TestManager {
+        exit: AtomicBool,
+        record: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MainLoopManager for TestManager {
+        fn main_loop_should_exit(&self) -> bool {
+            self.exit.load(Ordering::SeqCst)
+        }
+
+        fn main_loop_cleanup(&self) -> Result<()> {
+            self.record.lock().unwrap().push("cleanup".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_signal_exit() {
+        let record = Arc::new(Mutex::new(Vec::new()));
+        let record_clone = record.clone();
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        let handle = std::thread::spawn(move || {
+            let signal_fd = SignalFd::new(&[libc::SIGTERM]).unwrap();
+            let fd = signal_fd.as_raw_fd();
+            let manager = Arc::new(TestManager {
+                exit: AtomicBool::new(false),
+                record: record_clone.clone(),
+            });
+            let mut mainloop = MainLoopContext::new();
+            mainloop.set_manager(manager.clone());
+
+            let handler: Box<NotifierCallback> = Box::new(move |_, _| {
+                if let Some(signal) = signal_fd.read() {
+                    record_clone
+                        .lock()
+                        .unwrap()
+                        .push(format!("signal {}", signal));
+                    manager.exit.store(true, Ordering::SeqCst);
+                }
+                None
+            });
+            let notifier = EventNotifier::new(
+                NotifierOperation::AddShared,
+                fd,
+                None,
+                EventSet::IN,
+                vec![Arc::new(Mutex::new(handler))],
+            );
+            mainloop.update_events(vec![notifier]).unwrap();
+
+            ready_tx.send(()).unwrap();
+            while mainloop.run().unwrap() {}
+        });
+
+        // SIGTERM is blocked in the loop thread once it's ready, and it's
+        // sent to that thread only, so the test process is not killed.
+        ready_rx.recv().unwrap();
+        let ret = unsafe { libc::pthread_kill(handle.as_pthread_t(), libc::SIGTERM) };
+        assert_eq!(ret, 0);
+        handle.join().unwrap();
+
+        assert_eq!(
+            *record.lock().unwrap(),
+            vec![format!("signal {}", libc::SIGTERM), "cleanup".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_ignore_sigpipe() {
+        assert!(ignore_sigpipe().is_ok());
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { libc::close(fds[0]) };
+        let buf = [0_u8; 1];
+        let ret = unsafe { libc::write(fds[1], buf.as_ptr() as *const c_void, 1) };
+        assert_eq!(ret, -1);
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::EPIPE)
+        );
+        unsafe { libc::close(fds[1]) };
+    }
+}
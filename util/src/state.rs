@@ -0,0 +1,628 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Versioned state of VM, which is saved in sections by snapshot and live
+//! migration.
+//!
+//! Each section has a name, such as `cpu0`, and the version of its state,
+//! so that a state saved by an older StratoVirt can be loaded by a newer
+//! one. A state of plain old data, which implements `ByteCode`, follows the
+//! rules when it's changed:
+//! * New fields are only appended to the end of state, and the current
+//!   version is increased. Fields are never removed or reordered.
+//! * The state of an older version is loaded with the new fields set to
+//!   their default value.
+//! * The minimum version is increased if the default of new fields can't
+//!   work with the older state.
+//!
+//! A section is mandatory unless it's registered as optional. Loading fails
+//! if a mandatory section is unknown or missing, while an unknown optional
+//! section is skipped, which suits the states only needed by newer
+//! StratoVirt.
+
+use crate::byte_code::ByteCode;
+use crate::errors::{Result, ResultExt};
+
+/// Versions of the state of a section.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StateVersion {
+    /// Version of the state saved.
+    pub current: u16,
+    /// Oldest version of the state which can be loaded.
+    pub minimum: u16,
+}
+
+impl StateVersion {
+    /// Create versions of a state.
+    ///
+    /// # Arguments
+    ///
+    /// * `current` - Version of the state saved.
+    /// * `minimum` - Oldest version of the state which can be loaded.
+    pub const fn new(current: u16, minimum: u16) -> Self {
+        StateVersion { current, minimum }
+    }
+
+    /// Check that the state of `version` can be loaded.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if `version` is out of the range from `minimum` to
+    /// `current`.
+    pub fn check(&self, version: u16) -> Result<()> {
+        if version > self.current {
+            bail!(
+                "Version {} of state is newer than the supported version {}",
+                version,
+                self.current
+            );
+        }
+        if version < self.minimum {
+            bail!(
+                "Version {} of state is older than the minimum supported version {}",
+                version,
+                self.minimum
+            );
+        }
+        Ok(())
+    }
+}
+
+/// State of plain old data whose older versions are the prefixes of it,
+/// which is implemented by `versioned_state!`.
+pub trait VersionedState: ByteCode {
+    /// Versions of the state.
+    const VERSION: StateVersion;
+
+    /// Get the size of the state in `version`, which is the offset of the
+    /// first field added after it.
+    fn size_of_version(version: u16) -> usize;
+
+    /// Create a state from the bytes saved in `version`, the fields added
+    /// after it are set to their default value.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - State saved.
+    /// * `version` - Version of the state saved.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the version isn't supported, or the length of
+    /// `bytes` mismatches the state of the version.
+    fn from_versioned_bytes(bytes: &[u8], version: u16) -> Result<Self> {
+        Self::VERSION.check(version)?;
+        let size = Self::size_of_version(version);
+        if bytes.len() != size {
+            bail!(
+                "Invalid length {} of state in version {}, expected {}",
+                bytes.len(),
+                version,
+                size
+            );
+        }
+        let mut state = Self::default();
+        state.as_mut_bytes()[..size].copy_from_slice(bytes);
+        Ok(state)
+    }
+}
+
+/// Macro: Implement `VersionedState` for a state of `ByteCode`.
+///
+/// # Arguments
+///
+/// The type of state, its current and minimum version, followed by the
+/// first field added in each version after the first one, in the order of
+/// version. The fields before the first field added in a version must not
+/// leave padding at their end, as the older state has no padding there.
+///
+/// # Examples
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate util;
+///
+/// use util::byte_code::ByteCode;
+///
+/// #[repr(C)]
+/// #[derive(Copy, Clone, Default)]
+/// struct DevState {
+///     features: u64,
+///     // Added in version 2.
+///     config: u64,
+/// }
+///
+/// impl ByteCode for DevState {}
+///
+/// versioned_state!(DevState, 2, 1, 2 => config);
+///
+/// fn main() {}
+/// ```
+#[macro_export]
+macro_rules! versioned_state {
+    ($type_name:ty, $current:expr, $minimum:expr $(, $version:expr => $field:ident)*) => {
+        impl $crate::state::VersionedState for $type_name {
+            const VERSION: $crate::state::StateVersion =
+                $crate::state::StateVersion::new($current, $minimum);
+
+            fn size_of_version(_version: u16) -> usize {
+                $(
+                    if _version < $version {
+                        return $crate::__offset_of!($type_name, $field);
+                    }
+                )*
+                std::mem::size_of::<$type_name>()
+            }
+        }
+    };
+}
+
+/// Object whose state is saved as a section.
+pub trait StateSection {
+    /// Get the versions of the state.
+    fn state_version(&self) -> StateVersion;
+
+    /// Get the state to save, in the current version.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the state can't be got.
+    fn save_state(&self) -> Result<Vec<u8>>;
+
+    /// Load the state saved in `version`, which is checked to be supported.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - Version of the state saved.
+    /// * `state` - State saved.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the state mismatches the object.
+    fn load_state(&mut self, version: u16, state: &[u8]) -> Result<()>;
+}
+
+type SaveFn<'a> = Box<dyn Fn() -> Result<Vec<u8>> + 'a>;
+type LoadFn<'a> = Box<dyn FnMut(u16, &[u8]) -> Result<()> + 'a>;
+
+/// Section of state which is saved and loaded by closures, for the objects
+/// which can't implement `StateSection` themselves.
+pub struct StateFn<'a> {
+    version: StateVersion,
+    save: SaveFn<'a>,
+    load: LoadFn<'a>,
+}
+
+impl<'a> StateFn<'a> {
+    /// Create a section of state.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - Versions of the state.
+    /// * `save` - Get the state to save.
+    /// * `load` - Load the state saved in a version.
+    pub fn new<S, L>(version: StateVersion, save: S, load: L) -> Self
+    where
+        S: Fn() -> Result<Vec<u8>> + 'a,
+        L: FnMut(u16, &[u8]) -> Result<()> + 'a,
+    {
+        StateFn {
+            version,
+            save: Box::new(save),
+            load: Box::new(load),
+        }
+    }
+}
+
+impl<'a> StateSection for StateFn<'a> {
+    fn state_version(&self) -> StateVersion {
+        self.version
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>> {
+        (self.save)()
+    }
+
+    fn load_state(&mut self, version: u16, state: &[u8]) -> Result<()> {
+        (self.load)(version, state)
+    }
+}
+
+/// State of a section saved, or read to load.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedState {
+    /// Name of section.
+    pub name: String,
+    /// Version of the state.
+    pub version: u16,
+    /// Whether the section can be skipped by loader who doesn't know it.
+    pub optional: bool,
+    /// State of section.
+    pub data: Vec<u8>,
+}
+
+struct RegisteredState<'a> {
+    name: String,
+    optional: bool,
+    section: Box<dyn StateSection + 'a>,
+}
+
+/// Registry of the sections of state, which are saved and loaded in the
+/// order of registering.
+#[derive(Default)]
+pub struct StateRegistry<'a> {
+    sections: Vec<RegisteredState<'a>>,
+}
+
+impl<'a> StateRegistry<'a> {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        StateRegistry {
+            sections: Vec::new(),
+        }
+    }
+
+    /// Register a mandatory section, which must be known and present when
+    /// it's loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of section, such as `cpu0`.
+    /// * `section` - Object whose state is saved as the section.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the name is registered.
+    pub fn register(&mut self, name: &str, section: Box<dyn StateSection + 'a>) -> Result<()> {
+        self.register_section(name, false, section)
+    }
+
+    /// Register an optional section, which is skipped by the loader who
+    /// doesn't know it, and left as it is if it's not saved.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of section.
+    /// * `section` - Object whose state is saved as the section.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the name is registered.
+    pub fn register_optional(
+        &mut self,
+        name: &str,
+        section: Box<dyn StateSection + 'a>,
+    ) -> Result<()> {
+        self.register_section(name, true, section)
+    }
+
+    fn register_section(
+        &mut self,
+        name: &str,
+        optional: bool,
+        section: Box<dyn StateSection + 'a>,
+    ) -> Result<()> {
+        if self.sections.iter().any(|s| s.name == name) {
+            bail!("Section '{}' is registered twice", name);
+        }
+        self.sections.push(RegisteredState {
+            name: name.to_string(),
+            optional,
+            section,
+        });
+        Ok(())
+    }
+
+    /// Get the states of all sections in their current versions.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the section whose state can't be got.
+    pub fn save(&self) -> Result<Vec<SavedState>> {
+        let mut states = Vec::new();
+        for s in self.sections.iter() {
+            let data = s
+                .section
+                .save_state()
+                .chain_err(|| format!("Failed to save section '{}'", s.name))?;
+            states.push(SavedState {
+                name: s.name.clone(),
+                version: s.section.state_version().current,
+                optional: s.optional,
+                data,
+            });
+        }
+        Ok(states)
+    }
+
+    /// Load `states` to the sections registered. Unknown optional sections
+    /// are skipped with a warning.
+    ///
+    /// # Arguments
+    ///
+    /// * `states` - States read, in any order.
+    ///
+    /// # Errors
+    ///
+    /// Return Error naming the section which is unknown and mandatory,
+    /// missing, of unsupported version, or fails to be loaded. Nothing is
+    /// loaded if any section is unknown, missing or of unsupported version.
+    pub fn load(&mut self, states: &[SavedState]) -> Result<()> {
+        for state in states.iter() {
+            match self.sections.iter().find(|s| s.name == state.name) {
+                Some(s) => s
+                    .section
+                    .state_version()
+                    .check(state.version)
+                    .chain_err(|| format!("Section '{}' can't be loaded", state.name))?,
+                None if state.optional => warn!(
+                    "Skip unknown optional section '{}' of version {}",
+                    state.name, state.version
+                ),
+                None => bail!("Unknown section '{}' is mandatory", state.name),
+            }
+        }
+        for s in self.sections.iter().filter(|s| !s.optional) {
+            if !states.iter().any(|state| state.name == s.name) {
+                bail!("Section '{}' is not found", s.name);
+            }
+        }
+
+        for s in self.sections.iter_mut() {
+            if let Some(state) = states.iter().find(|state| state.name == s.name) {
+                s.section
+                    .load_state(state.version, &state.data)
+                    .chain_err(|| format!("Failed to restore section '{}'", s.name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Default, Debug, PartialEq)]
+    struct StateV1 {
+        features: u64,
+        status: u32,
+        queue_num: u32,
+    }
+
+    impl ByteCode for StateV1 {}
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct StateV2 {
+        features: u64,
+        status: u32,
+        queue_num: u32,
+        // Added in version 2.
+        config_gen: u32,
+        queue_size: u16,
+        // Added in version 3.
+        flags: u16,
+    }
+
+    impl Default for StateV2 {
+        fn default() -> Self {
+            StateV2 {
+                features: 0,
+                status: 0,
+                queue_num: 0,
+                config_gen: 0,
+                queue_size: 256,
+                flags: 0,
+            }
+        }
+    }
+
+    impl ByteCode for StateV2 {}
+
+    versioned_state!(StateV1, 1, 1);
+    versioned_state!(StateV2, 3, 1, 2 => config_gen, 3 => flags);
+
+    #[test]
+    fn test_versioned_state() {
+        assert_eq!(StateV1::size_of_version(1), 16);
+        assert_eq!(StateV2::size_of_version(1), 16);
+        assert_eq!(StateV2::size_of_version(2), 22);
+        assert_eq!(StateV2::size_of_version(3), 24);
+
+        // Restore the state saved by an older binary with version 1.
+        let old = StateV1 {
+            features: 1 << 32,
+            status: 0xf,
+            queue_num: 2,
+        };
+        let bytes = old.as_bytes().to_vec();
+        assert_eq!(StateV1::from_versioned_bytes(&bytes, 1).unwrap(), old);
+        let state = StateV2::from_versioned_bytes(&bytes, 1).unwrap();
+        assert_eq!(
+            state,
+            StateV2 {
+                features: 1 << 32,
+                status: 0xf,
+                queue_num: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(state.queue_size, 256);
+
+        let new = StateV2 { flags: 1, ..state };
+        let bytes = new.as_bytes().to_vec();
+        assert_eq!(StateV2::from_versioned_bytes(&bytes, 3).unwrap(), new);
+        let bytes_v2 = &bytes[..22];
+        assert_eq!(
+            StateV2::from_versioned_bytes(bytes_v2, 2).unwrap(),
+            StateV2 { flags: 0, ..new }
+        );
+
+        let errors = [
+            (
+                &bytes[..],
+                1,
+                "Invalid length 24 of state in version 1, expected 16",
+            ),
+            (
+                &bytes[..],
+                4,
+                "Version 4 of state is newer than the supported version 3",
+            ),
+            (
+                &bytes[..],
+                0,
+                "Version 0 of state is older than the minimum supported version 1",
+            ),
+        ];
+        for (bytes, version, err) in errors.iter() {
+            assert_eq!(
+                StateV2::from_versioned_bytes(bytes, *version)
+                    .unwrap_err()
+                    .to_string(),
+                *err
+            );
+        }
+    }
+
+    fn registry<'a>(
+        v1: &'a RefCell<StateV1>,
+        v2: &'a RefCell<StateV2>,
+        version: StateVersion,
+    ) -> StateRegistry<'a> {
+        let mut registry = StateRegistry::new();
+        registry
+            .register(
+                "dev0",
+                Box::new(StateFn::new(
+                    StateV1::VERSION,
+                    move || Ok(v1.borrow().as_bytes().to_vec()),
+                    move |version, data| {
+                        *v1.borrow_mut() = StateV1::from_versioned_bytes(data, version)?;
+                        Ok(())
+                    },
+                )),
+            )
+            .unwrap();
+        registry
+            .register_optional(
+                "dev1",
+                Box::new(StateFn::new(
+                    version,
+                    move || Ok(v2.borrow().as_bytes().to_vec()),
+                    move |version, data| {
+                        *v2.borrow_mut() = StateV2::from_versioned_bytes(data, version)?;
+                        Ok(())
+                    },
+                )),
+            )
+            .unwrap();
+        registry
+    }
+
+    fn error_msg(e: crate::errors::Error) -> String {
+        e.iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join(": ")
+    }
+
+    #[test]
+    fn test_state_registry() {
+        let v1 = RefCell::new(StateV1 {
+            features: 3,
+            status: 1,
+            queue_num: 1,
+        });
+        let v2 = RefCell::new(StateV2 {
+            flags: 1,
+            ..Default::default()
+        });
+        let mut source = registry(&v1, &v2, StateV2::VERSION);
+        let states = source.save().unwrap();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].name, "dev0");
+        assert_eq!((states[0].version, states[0].optional), (1, false));
+        assert_eq!(states[1].name, "dev1");
+        assert_eq!((states[1].version, states[1].optional), (3, true));
+        let dup = StateFn::new(StateV1::VERSION, || Ok(Vec::new()), |_, _| Ok(()));
+        assert_eq!(
+            source
+                .register_optional("dev0", Box::new(dup))
+                .unwrap_err()
+                .to_string(),
+            "Section 'dev0' is registered twice"
+        );
+
+        let dst_v1 = RefCell::new(StateV1::default());
+        let dst_v2 = RefCell::new(StateV2::default());
+        let mut dst = registry(&dst_v1, &dst_v2, StateV2::VERSION);
+        dst.load(&states).unwrap();
+        assert_eq!(*dst_v1.borrow(), *v1.borrow());
+        assert_eq!(*dst_v2.borrow(), *v2.borrow());
+
+        // Optional sections are skipped if unknown, or left if missing.
+        let mut unknown = states.clone();
+        unknown.push(SavedState {
+            name: "dev2".to_string(),
+            version: 1,
+            optional: true,
+            data: Vec::new(),
+        });
+        unknown.remove(1);
+        let dst_v1 = RefCell::new(StateV1::default());
+        let dst_v2 = RefCell::new(StateV2::default());
+        let mut dst = registry(&dst_v1, &dst_v2, StateV2::VERSION);
+        dst.load(&unknown).unwrap();
+        assert_eq!(*dst_v1.borrow(), *v1.borrow());
+        assert_eq!(*dst_v2.borrow(), StateV2::default());
+
+        let mut mandatory = states.clone();
+        mandatory[1].name = "dev2".to_string();
+        mandatory[1].optional = false;
+        let mut missing = states.clone();
+        missing.remove(0);
+        let mut corrupted = states.clone();
+        corrupted[0].data.pop();
+        let errors = [
+            (mandatory, "Unknown section 'dev2' is mandatory"),
+            (missing, "Section 'dev0' is not found"),
+            (
+                states.clone(),
+                "Section 'dev1' can't be loaded: Version 3 of state is newer than the supported version 2",
+            ),
+            (
+                corrupted,
+                "Failed to restore section 'dev0': Invalid length 15 of state in version 1, expected 16",
+            ),
+        ];
+        for (index, (states, err)) in errors.iter().enumerate() {
+            let dst_v1 = RefCell::new(StateV1::default());
+            let dst_v2 = RefCell::new(StateV2::default());
+            // The older loader only supports version 2 of `dev1`.
+            let version = if index == 2 {
+                StateVersion::new(2, 1)
+            } else {
+                StateV2::VERSION
+            };
+            let mut dst = registry(&dst_v1, &dst_v2, version);
+            assert_eq!(error_msg(dst.load(states).unwrap_err()), *err);
+            if index < 3 {
+                assert_eq!(*dst_v1.borrow(), StateV1::default());
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use libc::{c_void, itimerspec, timespec};
+
+use crate::errors::Result;
+
+/// Callback of timer, it's called in the thread of the main loop which the
+/// timer is added to.
+pub type TimerCallback = dyn Fn(&TimerHandle);
+
+/// Mode of timer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Fire once after the duration.
+    OneShot,
+    /// Fire every duration.
+    Periodic,
+}
+
+/// Timer backed by a non-blocking timerfd on `CLOCK_MONOTONIC`.
+struct Timer {
+    fd: RawFd,
+    cancelled: AtomicBool,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // Safe because the fd is owned by the timer.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn to_timespec(duration: Duration) -> timespec {
+    timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
+}
+
+/// Handle of timer added to main loop, it can be cloned and used to re-arm
+/// or cancel the timer from anywhere, including the callback of timer.
+#[derive(Clone)]
+pub struct TimerHandle {
+    timer: Arc<Timer>,
+}
+
+impl TimerHandle {
+    /// Create a disarmed timer.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if fail to create timerfd.
+    pub(crate) fn new() -> Result<Self> {
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(TimerHandle {
+            timer: Arc::new(Timer {
+                fd,
+                cancelled: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    /// Arm the timer again, the previous expiration is overridden.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - Duration before the timer fires, and the interval of
+    ///   periodic timer.
+    /// * `mode` - One-shot or periodic.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if the timer is cancelled, or fail to set timerfd.
+    pub fn rearm(&self, duration: Duration, mode: TimerMode) -> Result<()> {
+        if self.is_cancelled() {
+            bail!("Timer {} is cancelled.", self.timer.fd);
+        }
+        // Zero `it_value` disarms the timer, so the shortest expiration is used.
+        let duration = std::cmp::max(duration, Duration::from_nanos(1));
+        let interval = match mode {
+            TimerMode::OneShot => Duration::from_secs(0),
+            TimerMode::Periodic => duration,
+        };
+        let spec = itimerspec {
+            it_interval: to_timespec(interval),
+            it_value: to_timespec(duration),
+        };
+        self.settime(&spec)
+    }
+
+    /// Cancel the timer, it never fires again and is removed from the main
+    /// loop at its next iteration.
+    pub fn cancel(&self) {
+        self.timer.cancelled.store(true, Ordering::SeqCst);
+        let spec = itimerspec {
+            it_interval: to_timespec(Duration::from_secs(0)),
+            it_value: to_timespec(Duration::from_secs(0)),
+        };
+        if let Err(e) = self.settime(&spec) {
+            error!("Failed to disarm timer {}: {}", self.timer.fd, e);
+        }
+    }
+
+    /// Whether the timer is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.timer.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Whether the timer is going to fire.
+    pub fn is_armed(&self) -> bool {
+        let mut spec = itimerspec {
+            it_interval: to_timespec(Duration::from_secs(0)),
+            it_value: to_timespec(Duration::from_secs(0)),
+        };
+        let ret = unsafe { libc::timerfd_gettime(self.timer.fd, &mut spec) };
+        ret == 0 && (spec.it_value.tv_sec != 0 || spec.it_value.tv_nsec != 0)
+    }
+
+    /// Consume the expirations of timerfd, return the number of them.
+    pub(crate) fn expirations(&self) -> u64 {
+        let mut count: u64 = 0;
+        let ret = unsafe {
+            libc::read(
+                self.timer.fd,
+                &mut count as *mut u64 as *mut c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if ret < 0 {
+            0
+        } else {
+            count
+        }
+    }
+
+    fn settime(&self, spec: &itimerspec) -> Result<()> {
+        let ret = unsafe { libc::timerfd_settime(self.timer.fd, 0, spec, std::ptr::null_mut()) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for TimerHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.fd
+    }
+}
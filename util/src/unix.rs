@@ -19,6 +19,17 @@ pub fn gettid() -> u64 {
     unsafe { libc::syscall(libc::SYS_gettid) as u64 }
 }
 
+/// Set the name of the calling thread, the kernel truncates it to 15 bytes.
+///
+/// # Arguments
+///
+/// * `name` - Name of thread.
+pub fn set_thread_name(name: &str) {
+    if let Ok(name) = std::ffi::CString::new(name) {
+        unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr()) };
+    }
+}
+
 /// This function used to remove group and others permission using libc::chmod.
 pub fn limit_permission(path: &str) -> Result<()> {
     let file_path = path.as_bytes().to_vec();
@@ -0,0 +1,79 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::Read;
+
+use crate::errors::Result;
+
+/// Length of each hyphen-separated group of UUID string.
+const UUID_GROUP_LENS: [usize; 5] = [8, 4, 4, 4, 12];
+
+/// Check whether `uuid` is in the RFC 4122 string format, i.e.
+/// `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` where `x` is a hex digit.
+///
+/// # Arguments
+///
+/// * `uuid` - The string to be checked.
+pub fn is_valid_uuid(uuid: &str) -> bool {
+    let groups: Vec<&str> = uuid.split('-').collect();
+    groups.len() == UUID_GROUP_LENS.len()
+        && groups
+            .iter()
+            .zip(UUID_GROUP_LENS.iter())
+            .all(|(group, len)| group.len() == *len && group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Generate a random (version 4) UUID in lowercase string format.
+///
+/// # Errors
+///
+/// Return Error if fail to read `/dev/urandom`.
+pub fn uuid_v4() -> Result<String> {
+    let mut bytes = [0_u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    // Version 4 in the high nibble of byte 6, variant 10b in byte 8.
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid() {
+        assert!(is_valid_uuid("6ba7b810-9dad-11d1-80b4-00c04fd430c8"));
+        assert!(is_valid_uuid("6BA7B810-9DAD-11D1-80B4-00C04FD430C8"));
+        assert!(!is_valid_uuid(""));
+        assert!(!is_valid_uuid("6ba7b8109dad11d180b400c04fd430c8"));
+        assert!(!is_valid_uuid("6ba7b810-9dad-11d1-80b4-00c04fd430c"));
+        assert!(!is_valid_uuid("6ba7b810-9dad-11d1-80b4-00c04fd430c8-"));
+        assert!(!is_valid_uuid("6ba7b810-9dad-11d1-80b4-00c04fd430cg"));
+
+        let uuid = uuid_v4().unwrap();
+        assert!(is_valid_uuid(&uuid));
+        assert_eq!(&uuid[14..15], "4");
+        assert!(["8", "9", "a", "b"].contains(&&uuid[19..20]));
+        assert_ne!(uuid, uuid_v4().unwrap());
+    }
+}